@@ -0,0 +1,83 @@
+// Imports a 50,000 row file through the CLI, the size of a large supplier
+// catalog, to measure the batched inserts and the events recorded for them.
+//
+// Needs a MongoDB to write to, and is skipped without one:
+//
+//     BENCH_MONGODB_URI=mongodb://localhost:27017 cargo bench --bench import_batches
+//
+// Every run starts from an empty products_import_bench database.
+
+use std::{
+    env,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use mongodb::Client;
+
+const ROWS: usize = 50_000;
+const DATABASE: &str = "products_import_bench";
+
+fn write_catalog(path: &Path) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "name,price,category,has_active_sale,sku,stock_quantity")?;
+    for row in 0..ROWS {
+        let category = ["Electronics", "Books", "Clothing", "Food"][row % 4];
+        writeln!(file, "Bench product {row},{}.{:02},{category},{},BENCH-{row},{}", row % 500, row % 100, row % 7 == 0, row % 90)?;
+    }
+    file.flush()
+}
+
+fn drop_database(runtime: &tokio::runtime::Runtime, uri: &str) {
+    runtime.block_on(async {
+        let client = Client::with_uri_str(uri).await.expect("BENCH_MONGODB_URI is not a valid connection string");
+        client.database(DATABASE).drop(None).await.expect("Failed to drop the bench database");
+    });
+}
+
+fn import(uri: &str, catalog: &Path) {
+    let status = Command::new(env!("CARGO_BIN_EXE_products-json-api"))
+        .arg("import-csv")
+        .arg(catalog)
+        .env("MONGODB_URI", uri)
+        .env("DATABASE_NAME", DATABASE)
+        .env("EVENT_SOURCING", "true")
+        .env("RUST_LOG", "warn")
+        .stdout(Stdio::null())
+        .status()
+        .expect("Failed to run the import");
+    assert!(status.success(), "The import failed");
+}
+
+fn import_batches(c: &mut Criterion) {
+    let Ok(uri) = env::var("BENCH_MONGODB_URI") else {
+        eprintln!("BENCH_MONGODB_URI is not set, skipping the import benchmark");
+        return;
+    };
+    let catalog: PathBuf = env::temp_dir().join("products_import_bench.csv");
+    write_catalog(&catalog).expect("Failed to write the bench catalog");
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start a runtime");
+
+    let mut group = c.benchmark_group("import");
+    group.sample_size(10).measurement_time(Duration::from_secs(120)).throughput(Throughput::Elements(ROWS as u64));
+    group.bench_function("50k_rows", |b| {
+        b.iter_custom(|runs| {
+            let mut elapsed = Duration::ZERO;
+            for _ in 0..runs {
+                drop_database(&runtime, &uri);
+                let started = Instant::now();
+                import(&uri, &catalog);
+                elapsed += started.elapsed();
+            }
+            elapsed
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, import_batches);
+criterion_main!(benches);
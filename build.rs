@@ -0,0 +1,12 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Use the vendored protoc so builds don't depend on a system install
+    if std::env::var_os("PROTOC").is_none() {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    }
+
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/products.proto"], &["proto"])?;
+
+    Ok(())
+}
@@ -0,0 +1,275 @@
+use std::{io, path::PathBuf, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use futures::future::BoxFuture;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+    sync::{broadcast::error::RecvError, mpsc},
+    time,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::AnalyticsConfig,
+    events::{DomainEvent, EventHub},
+};
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Something the data team wants to count. Recorded with `Analytics::record`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AnalyticsEvent {
+    ProductViewed {
+        product_id: String,
+    },
+    SearchPerformed {
+        query: String,
+        result_count: u64,
+        // catalog for GET /products?filter=..., search_engine for /products/search
+        source: &'static str,
+    },
+    ImportCompleted {
+        import_id: String,
+        imported: usize,
+        failed: usize,
+    },
+}
+
+/// Where analytics events end up. Each sink gets events in batches, every
+/// event a JSON object with `event` and `occurred_at`.
+pub trait AnalyticsSink: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn send<'a>(&'a self, batch: &'a [Value]) -> BoxFuture<'a, Result<(), String>>;
+}
+
+/// Appends events to a file, one JSON object per line.
+pub struct FileSink {
+    path: PathBuf,
+}
+
+impl FileSink {
+    async fn append(&self, batch: &[Value]) -> Result<(), String> {
+        let mut lines = Vec::new();
+        for event in batch {
+            serde_json::to_writer(&mut lines, event).map_err(|e| e.to_string())?;
+            lines.push(b'\n');
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(|e| e.to_string())?;
+        file.write_all(&lines).await.map_err(|e| e.to_string())
+    }
+}
+
+impl AnalyticsSink for FileSink {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn send<'a>(&'a self, batch: &'a [Value]) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(self.append(batch))
+    }
+}
+
+/// POSTs each batch as a JSON array to a collector.
+pub struct HttpSink {
+    http: reqwest::Client,
+    url: String,
+}
+
+impl AnalyticsSink for HttpSink {
+    fn name(&self) -> &'static str {
+        "HTTP collector"
+    }
+
+    fn send<'a>(&'a self, batch: &'a [Value]) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            self.http
+                .post(&self.url)
+                .json(batch)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        })
+    }
+}
+
+/// Produces each batch to a Kafka topic through a Kafka REST proxy, so no
+/// Kafka client library is needed.
+pub struct KafkaRestSink {
+    http: reqwest::Client,
+    // The proxy's produce endpoint for the topic
+    url: String,
+}
+
+impl AnalyticsSink for KafkaRestSink {
+    fn name(&self) -> &'static str {
+        "Kafka"
+    }
+
+    fn send<'a>(&'a self, batch: &'a [Value]) -> BoxFuture<'a, Result<(), String>> {
+        let records: Vec<Value> = batch.iter().map(|event| json!({ "value": event })).collect();
+        Box::pin(async move {
+            self.http
+                .post(&self.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/vnd.kafka.json.v2+json")
+                .body(json!({ "records": records }).to_string())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        })
+    }
+}
+
+/// Records analytics events without waiting for them to be delivered.
+/// Events go through a bounded channel to a background task; when the sink
+/// falls behind and the channel is full, new events are dropped. Cloning
+/// shares the channel; the default records nothing.
+#[derive(Clone, Default)]
+pub struct Analytics {
+    sender: Option<mpsc::Sender<Value>>,
+}
+
+impl Analytics {
+    pub fn record(&self, event: AnalyticsEvent) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        let mut value = match serde_json::to_value(&event) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("Failed to serialize analytics event: {}", e);
+                return;
+            }
+        };
+        if let Some(fields) = value.as_object_mut() {
+            fields.insert("occurred_at".to_string(), Value::String(Utc::now().to_rfc3339()));
+        }
+        if let Err(mpsc::error::TrySendError::Full(_)) = sender.try_send(value) {
+            debug!("Analytics buffer is full, dropped an event");
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn sink_from_config(config: &AnalyticsConfig) -> io::Result<Option<Arc<dyn AnalyticsSink>>> {
+    let http = || {
+        reqwest::Client::builder()
+            .timeout(SEND_TIMEOUT)
+            .build()
+            .expect("Failed to build HTTP client")
+    };
+    let url = |sink: &str| {
+        config
+            .url
+            .as_deref()
+            .map(|url| url.trim_end_matches('/').to_string())
+            .ok_or_else(|| invalid(format!("ANALYTICS_SINK={} needs ANALYTICS_URL", sink)))
+    };
+    let sink: Arc<dyn AnalyticsSink> = match config.sink.as_str() {
+        "off" => return Ok(None),
+        "file" => Arc::new(FileSink { path: config.file.clone() }),
+        "http" => Arc::new(HttpSink { http: http(), url: url("http")? }),
+        "kafka" => Arc::new(KafkaRestSink {
+            http: http(),
+            url: format!("{}/topics/{}", url("kafka")?, config.kafka_topic),
+        }),
+        other => {
+            return Err(invalid(format!("Unknown ANALYTICS_SINK '{}': expected off, file, http or kafka", other)))
+        }
+    };
+    Ok(Some(sink))
+}
+
+async fn flush(sink: &dyn AnalyticsSink, batch: &mut Vec<Value>) {
+    if batch.is_empty() {
+        return;
+    }
+    // Best effort, like event forwarding: a failed batch is not retried
+    if let Err(e) = sink.send(batch).await {
+        warn!("Failed to send {} analytics events to {}: {}", batch.len(), sink.name(), e);
+    }
+    batch.clear();
+}
+
+// Sends events in batches of `batch_size`, or whatever arrived within the
+// flush interval, until every Analytics handle is gone
+async fn deliver(sink: Arc<dyn AnalyticsSink>, mut receiver: mpsc::Receiver<Value>, batch_size: usize, interval: Duration) {
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut ticker = time::interval(interval);
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Some(event) => {
+                    batch.push(event);
+                    if batch.len() < batch_size {
+                        continue;
+                    }
+                }
+                None => {
+                    flush(sink.as_ref(), &mut batch).await;
+                    return;
+                }
+            },
+            _ = ticker.tick() => {}
+        }
+        flush(sink.as_ref(), &mut batch).await;
+    }
+}
+
+// Imports announce themselves on the event hub, wherever they were started
+fn spawn_import_recorder(events: &EventHub, analytics: Analytics) {
+    let mut receiver = events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(DomainEvent::ImportCompleted { import_id, imported, failed }) => {
+                    analytics.record(AnalyticsEvent::ImportCompleted { import_id, imported, failed });
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => warn!("Analytics lagged, missed {} events", skipped),
+                Err(RecvError::Closed) => return,
+            }
+        }
+    });
+}
+
+/// Starts delivering analytics events to ANALYTICS_SINK. Errors on an
+/// unknown sink or a missing URL; with the sink off, the handle records nothing.
+pub fn start(events: &EventHub, config: AnalyticsConfig) -> io::Result<Analytics> {
+    let Some(sink) = sink_from_config(&config)? else {
+        return Ok(Analytics::default());
+    };
+
+    info!(
+        "Sending analytics events to {} in batches of {} at least every {}s",
+        sink.name(),
+        config.batch_size,
+        config.flush_secs
+    );
+    let (sender, receiver) = mpsc::channel(config.buffer.max(1));
+    tokio::spawn(deliver(
+        sink,
+        receiver,
+        config.batch_size.max(1),
+        Duration::from_secs(config.flush_secs.max(1)),
+    ));
+    let analytics = Analytics { sender: Some(sender) };
+    spawn_import_recorder(events, analytics.clone());
+    Ok(analytics)
+}
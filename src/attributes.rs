@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, HashSet};
+
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use mongodb::{
+    bson::{doc, Bson, DateTime, Document},
+    options::ReplaceOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+
+use crate::{
+    config::MongoConfig,
+    errors::ApiError,
+    models::{AttributeValue, Category},
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AttributeType {
+    Text,
+    Number,
+    Boolean,
+    // One of `values`
+    Enum,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct AttributeDefinition {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: AttributeType,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<String>,
+    // Display only, e.g. "V" for voltage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+}
+
+// One document per category, keyed by the category name
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CategoryAttributes {
+    #[serde(rename = "_id")]
+    pub category: String,
+    pub attributes: Vec<AttributeDefinition>,
+    pub updated_at: DateTime,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CategoryAttributesRequest {
+    pub attributes: Vec<AttributeDefinition>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CategoryAttributesResponse {
+    pub category: String,
+    pub attributes: Vec<AttributeDefinition>,
+}
+
+fn collection(db: &MongoConfig) -> Collection<CategoryAttributes> {
+    db.database.collection("category_attributes")
+}
+
+fn bad_request(message: String) -> Error {
+    ApiError::new(StatusCode::BAD_REQUEST, message).into()
+}
+
+// Attribute names become part of field paths and filter syntax
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 50
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn parse_category(category: &str) -> Result<Category, Error> {
+    category.parse().map_err(bad_request)
+}
+
+fn check_definitions(definitions: &[AttributeDefinition]) -> Result<(), String> {
+    let mut seen = HashSet::new();
+    for definition in definitions {
+        if !valid_name(&definition.name) {
+            return Err(format!(
+                "Invalid attribute name '{}': use up to 50 lowercase letters, digits and underscores",
+                definition.name
+            ));
+        }
+        if !seen.insert(definition.name.as_str()) {
+            return Err(format!("Attribute '{}' is defined twice", definition.name));
+        }
+        let is_enum = definition.kind == AttributeType::Enum;
+        if is_enum && definition.values.is_empty() {
+            return Err(format!("Enum attribute '{}' needs at least one value", definition.name));
+        }
+        if !is_enum && !definition.values.is_empty() {
+            return Err(format!("Only enum attributes take values, but '{}' has them", definition.name));
+        }
+    }
+    Ok(())
+}
+
+/// Checks product attributes against the category's definitions: every
+/// attribute must be defined with a matching type and required ones present.
+pub fn validate_attributes(
+    definitions: &[AttributeDefinition],
+    attributes: &BTreeMap<String, AttributeValue>,
+) -> Result<(), String> {
+    let mut problems = Vec::new();
+
+    for (name, value) in attributes {
+        let Some(definition) = definitions.iter().find(|d| &d.name == name) else {
+            problems.push(format!("Unknown attribute '{}'", name));
+            continue;
+        };
+        let matches = match (definition.kind, value) {
+            (AttributeType::Text, AttributeValue::Text(_))
+            | (AttributeType::Number, AttributeValue::Number(_))
+            | (AttributeType::Boolean, AttributeValue::Boolean(_)) => true,
+            (AttributeType::Enum, AttributeValue::Text(value)) => definition.values.contains(value),
+            _ => false,
+        };
+        if !matches {
+            problems.push(match definition.kind {
+                AttributeType::Enum => format!("{} must be one of: {}", name, definition.values.join(", ")),
+                AttributeType::Text => format!("{} must be text", name),
+                AttributeType::Number => format!("{} must be a number", name),
+                AttributeType::Boolean => format!("{} must be true or false", name),
+            });
+        }
+    }
+
+    for definition in definitions.iter().filter(|d| d.required) {
+        if !attributes.contains_key(&definition.name) {
+            problems.push(format!("{} is required", definition.name));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems.join("; "))
+    }
+}
+
+pub async fn definitions_for(db: &MongoConfig, category: &Category) -> Result<Vec<AttributeDefinition>, Error> {
+    let definitions = collection(db)
+        .find_one(doc! { "_id": category.to_string() }, None)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch attribute definitions for {}: {}", category, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+    Ok(definitions.map(|d| d.attributes).unwrap_or_default())
+}
+
+/// Validates the attributes a product in `category` will have, answering 400
+/// with every problem found.
+pub async fn check_product_attributes(
+    db: &MongoConfig,
+    category: &Category,
+    attributes: Option<&BTreeMap<String, AttributeValue>>,
+) -> Result<(), Error> {
+    let definitions = definitions_for(db, category).await?;
+    let empty = BTreeMap::new();
+    validate_attributes(&definitions, attributes.unwrap_or(&empty)).map_err(|problems| {
+        debug!("Rejected product attributes for {}: {}", category, problems);
+        bad_request(format!("Invalid attributes: {}", problems))
+    })
+}
+
+/// Builds a product filter from `name:value` pairs separated by commas, e.g.
+/// "voltage:220,plug:eu". Values match text, numbers and booleans alike.
+pub fn attribute_filter(spec: &str) -> Result<Document, String> {
+    let mut filter = Document::new();
+    for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (name, value) = pair
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid attribute filter '{}': expected name:value", pair))?;
+        let (name, value) = (name.trim(), value.trim());
+        if !valid_name(name) {
+            return Err(format!("Invalid attribute name '{}'", name));
+        }
+
+        let mut candidates = vec![Bson::String(value.to_string())];
+        if let Ok(number) = value.parse::<f64>() {
+            candidates.push(Bson::Double(number));
+        }
+        if let Ok(boolean) = value.parse::<bool>() {
+            candidates.push(Bson::Boolean(boolean));
+        }
+        filter.insert(format!("attributes.{}", name), doc! { "$in": candidates });
+    }
+    Ok(filter)
+}
+
+pub async fn get_category_attributes(
+    db: web::Data<MongoConfig>,
+    category: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let category = parse_category(&category)?;
+    let attributes = definitions_for(&db, &category).await?;
+
+    Ok(HttpResponse::Ok().json(CategoryAttributesResponse { category: category.to_string(), attributes }))
+}
+
+/// Replaces the attribute definitions of a category. Existing products are not
+/// revalidated; they are checked again on their next update.
+pub async fn set_category_attributes(
+    db: web::Data<MongoConfig>,
+    category: web::Path<String>,
+    request: web::Json<CategoryAttributesRequest>,
+) -> Result<HttpResponse, Error> {
+    let category = parse_category(&category)?;
+    let request = request.into_inner();
+    check_definitions(&request.attributes).map_err(bad_request)?;
+
+    let definitions = CategoryAttributes {
+        category: category.to_string(),
+        attributes: request.attributes,
+        updated_at: DateTime::now(),
+    };
+    collection(&db)
+        .replace_one(
+            doc! { "_id": category.to_string() },
+            &definitions,
+            ReplaceOptions::builder().upsert(true).build(),
+        )
+        .await
+        .map_err(|e| {
+            error!("Failed to save attribute definitions for {}: {}", category, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+
+    info!("Attribute definitions for {} updated ({} attributes)", category, definitions.attributes.len());
+    Ok(HttpResponse::Ok().json(CategoryAttributesResponse {
+        category: definitions.category,
+        attributes: definitions.attributes,
+    }))
+}
@@ -1,34 +1,161 @@
-use actix_web::{web, HttpResponse, Error, error::ErrorUnauthorized, dev::{Service, Transform, ServiceRequest, ServiceResponse}};
-use bcrypt::{hash, verify, DEFAULT_COST};
+use actix_web::{http::StatusCode, web, HttpMessage, HttpRequest, HttpResponse, Error, dev::{Service, Transform, ServiceRequest, ServiceResponse}};
 use chrono::{Duration, Utc};
 use jsonwebtoken::{encode, decode, Header, EncodingKey, DecodingKey, Validation, errors::Error as JwtError};
-use mongodb::{Collection, bson::{doc, oid::ObjectId}};
+use mongodb::{Collection, IndexModel, bson::{doc, oid::ObjectId}, options::IndexOptions};
 use serde::{Deserialize, Serialize};
 use validator::Validate;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use std::{
-    future::{Ready, Future},
+    future::Future,
     pin::Pin,
+    rc::Rc,
+    sync::OnceLock,
     task::{Context, Poll},
 };
 use futures_util::future::{ok, Ready as FutureReady};
 
-use crate::config::MongoConfig;
+use crate::{
+    auth_events::{AuthEvent, AuthEventKind},
+    barcode::is_duplicate_key,
+    captcha::LoginThrottle,
+    config::{AuthConfig, InviteConfig, MongoConfig},
+    errors::ApiError,
+    invites,
+    password::{hash_password, verify_dummy, verify_password},
+    password_policy::PasswordPolicy,
+    pii,
+    sessions,
+    two_factor::{self, TwoFactor},
+    validation::validation_error,
+};
+
+// Development fallbacks only. In production, set JWT_SECRET, JWT_REFRESH_SECRET
+// and TWO_FACTOR_SECRET
+const DEV_JWT_SECRET: &str = "your-secret-key";
+const DEV_REFRESH_SECRET: &str = "your-refresh-secret-key";
+const DEV_TWO_FACTOR_SECRET: &str = "your-2fa-challenge-secret-key";
+
+// HS256 wants a key at least as long as its hash
+const MIN_SECRET_LEN: usize = 32;
+
+static JWT_SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+static REFRESH_SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+static TWO_FACTOR_SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+
+fn signing_secret(key: &str, secret: Option<&String>, dev: &str) -> Vec<u8> {
+    secret
+        .cloned()
+        .unwrap_or_else(|| {
+            warn!("{} missing, using development key", key);
+            dev.to_string()
+        })
+        .into_bytes()
+}
+
+/// Sets the token signing keys from the loaded configuration. Runs once at startup.
+pub fn init(config: &AuthConfig) {
+    JWT_SECRET.get_or_init(|| signing_secret("JWT_SECRET", config.jwt_secret.as_ref(), DEV_JWT_SECRET));
+    REFRESH_SECRET
+        .get_or_init(|| signing_secret("JWT_REFRESH_SECRET", config.refresh_secret.as_ref(), DEV_REFRESH_SECRET));
+    TWO_FACTOR_SECRET.get_or_init(|| {
+        signing_secret("TWO_FACTOR_SECRET", config.two_factor_secret.as_ref(), DEV_TWO_FACTOR_SECRET)
+    });
+}
+
+/// The key access tokens are signed with.
+pub(crate) fn jwt_secret() -> &'static [u8] {
+    JWT_SECRET.get().expect("auth::init runs at startup")
+}
+
+fn refresh_secret() -> &'static [u8] {
+    REFRESH_SECRET.get().expect("auth::init runs at startup")
+}
+
+/// The key 2FA challenge tokens are signed with.
+pub(crate) fn two_factor_secret() -> &'static [u8] {
+    TWO_FACTOR_SECRET.get().expect("auth::init runs at startup")
+}
+
+/// What's wrong with the token signing secrets: unset, too short to be safe,
+/// or one secret used for two kinds of token.
+pub fn signing_secret_problems(config: &AuthConfig) -> Vec<String> {
+    let secrets = [
+        ("JWT_SECRET", &config.jwt_secret),
+        ("JWT_REFRESH_SECRET", &config.refresh_secret),
+        ("TWO_FACTOR_SECRET", &config.two_factor_secret),
+    ];
+    let mut problems = Vec::new();
+    for (key, secret) in &secrets {
+        match secret {
+            None => problems.push(format!("{} is not set, tokens are signed with the development key", key)),
+            Some(secret) if secret.len() < MIN_SECRET_LEN => {
+                problems.push(format!("{} is shorter than {} bytes", key, MIN_SECRET_LEN))
+            }
+            Some(_) => {}
+        }
+    }
+    for (i, (key, secret)) in secrets.iter().enumerate() {
+        for (other_key, other) in &secrets[i + 1..] {
+            if secret.is_some() && secret == other {
+                problems.push(format!("{} and {} are the same", key, other_key));
+            }
+        }
+    }
+    problems
+}
 
-const JWT_SECRET: &[u8] = b"your-secret-key"; // In production, use environment variable
-const REFRESH_SECRET: &[u8] = b"your-refresh-secret-key"; // In production, use environment variable
+// Permission scopes carried in access tokens
+pub const SCOPE_PRODUCTS_READ: &str = "products:read";
+pub const SCOPE_PRODUCTS_WRITE: &str = "products:write";
+pub const SCOPE_PRODUCTS_IMPORT: &str = "products:import";
+// Approving price changes above PRICE_APPROVAL_THRESHOLD_PERCENT
+pub const SCOPE_PRICES_APPROVE: &str = "prices:approve";
+pub const SCOPE_ADMIN: &str = "admin";
+pub const SCOPES: [&str; 5] =
+    [SCOPE_PRODUCTS_READ, SCOPE_PRODUCTS_WRITE, SCOPE_PRODUCTS_IMPORT, SCOPE_PRICES_APPROVE, SCOPE_ADMIN];
+
+// Lifetime of impersonation tokens
+const IMPERSONATION_MINUTES: i64 = 15;
+
+pub fn default_scopes() -> Vec<String> {
+    vec![
+        SCOPE_PRODUCTS_READ.to_string(),
+        SCOPE_PRODUCTS_WRITE.to_string(),
+        SCOPE_PRODUCTS_IMPORT.to_string(),
+    ]
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct User {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
+    // Stored encrypted; look users up by email with pii::email_filter
+    #[serde(with = "pii::email")]
     pub email: String,
+    #[serde(with = "pii::text")]
     pub first_name: String,
+    #[serde(with = "pii::text")]
     pub last_name: String,
+    // Empty for accounts created through an external identity provider
+    #[serde(default)]
     pub password_hash: String,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub identities: Vec<ExternalIdentity>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub two_factor: Option<TwoFactor>,
+}
+
+// Link between a user and an account at an external identity provider
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ExternalIdentity {
+    pub provider: String,
+    pub subject: String,
 }
 
 #[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
 pub struct RegisterRequest {
     #[validate(email)]
     pub email: String,
@@ -36,21 +163,53 @@ pub struct RegisterRequest {
     pub first_name: String,
     #[validate(length(min = 2))]
     pub last_name: String,
-    #[validate(length(min = 6))]
+    // Checked against the PasswordPolicy
     pub password: String,
+    // Required unless OPEN_REGISTRATION is set
+    pub invite_code: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct LoginRequest {
     pub email: String,
     pub password: String,
+    // Optional subset of the user's scopes, e.g. read-only tokens for integrations
+    pub scopes: Option<Vec<String>>,
+    // Solved CAPTCHA, once logins from the client's address have failed too often
+    pub captcha_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    // Checked against the PasswordPolicy
+    pub new_password: String,
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct RefreshTokenRequest {
     pub refresh_token: String,
 }
 
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ImpersonateRequest {
+    // Kept in the audit trail, e.g. the support ticket
+    #[validate(length(min = 3, max = 500))]
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImpersonationResponse {
+    pub token: String,
+    pub expires_at: String,
+    pub impersonator_id: String,
+    pub user: UserResponse,
+}
+
 #[derive(Debug, Serialize)]
 pub struct AuthResponse {
     pub token: String,
@@ -64,41 +223,112 @@ pub struct UserResponse {
     pub email: String,
     pub first_name: String,
     pub last_name: String,
+    pub scopes: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
     pub sub: String,     // User ID
     pub exp: i64,        // Expiration time
     pub iat: i64,        // Issued at
+    #[serde(default)]
+    pub scopes: Vec<String>, // Granted permissions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>, // Session the token belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>, // Refresh token ID, single use
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impersonator: Option<String>, // Admin acting as the user
+}
+
+impl Claims {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+
+    pub fn user_id(&self) -> Result<ObjectId, Error> {
+        ObjectId::parse_str(&self.sub).map_err(|e| {
+            error!("Failed to parse ObjectId from token subject: {}", e);
+            ApiError::new(StatusCode::UNAUTHORIZED, "Invalid token subject").into()
+        })
+    }
+
+    /// The admin behind an impersonation token.
+    pub fn impersonator_id(&self) -> Option<ObjectId> {
+        self.impersonator.as_deref().and_then(|id| ObjectId::parse_str(id).ok())
+    }
+
+    /// Rejects impersonation tokens, for changes to the account's own
+    /// security that only its owner may make.
+    pub fn forbid_impersonation(&self) -> Result<(), Error> {
+        match &self.impersonator {
+            Some(_) => Err(ApiError::new(StatusCode::FORBIDDEN, "Not allowed while impersonating a user").into()),
+            None => Ok(()),
+        }
+    }
+}
+
+fn email_taken() -> ApiError {
+    ApiError::new(StatusCode::CONFLICT, "Email already registered")
+}
+
+/// Makes emails unique among users, so two registrations racing for the same
+/// address can't both succeed. Emails are encrypted deterministically, so
+/// equal addresses are equal in the index too.
+pub async fn ensure_email_index(db: &MongoConfig) -> Result<String, mongodb::error::Error> {
+    let index = IndexModel::builder()
+        .keys(doc! { "email": 1 })
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    let result = db.database.collection::<User>("users").create_index(index, None).await?;
+    Ok(result.index_name)
 }
 
 pub async fn register(
+    req: HttpRequest,
     db: web::Data<MongoConfig>,
+    policy: web::Data<PasswordPolicy>,
+    invite_config: web::Data<InviteConfig>,
     user_data: web::Json<RegisterRequest>,
 ) -> Result<HttpResponse, Error> {
+    let mut user_data = user_data.into_inner();
+    user_data.email = pii::normalize_email(&user_data.email);
+
     // Validate request
     if let Err(errors) = user_data.validate() {
-        return Ok(HttpResponse::BadRequest().json(errors));
+        return Err(validation_error(errors));
+    }
+    if let Err(errors) = policy.check("password", &user_data.password).await {
+        return Err(validation_error(errors));
     }
 
     let collection: Collection<User> = db.database.collection("users");
 
-    // Check if email already exists
+    // Check if email already exists; the unique index catches concurrent registrations
     if let Ok(Some(_)) = collection
-        .find_one(doc! { "email": &user_data.email }, None)
+        .find_one(doc! { "email": pii::email_filter(&user_data.email) }, None)
         .await
     {
-        return Ok(HttpResponse::BadRequest().json(doc! {
-            "message": "Email already registered"
-        }));
+        return Err(email_taken().into());
     }
 
+    // Taken before the user exists so a code can't be used twice; given
+    // back if the user can't be created
+    let invite = match &user_data.invite_code {
+        Some(code) => match invites::claim(&db, code, &user_data.email).await? {
+            Some(invite) => Some(invite),
+            None => {
+                return Err(ApiError::new(StatusCode::FORBIDDEN, "Invalid or expired invite code").into());
+            }
+        },
+        None if invite_config.open_registration => None,
+        None => {
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "Registration requires an invite code").into());
+        }
+    };
+
     // Hash password
-    let password_hash = hash(user_data.password.as_bytes(), DEFAULT_COST).map_err(|e| {
-        error!("Failed to hash password: {}", e);
-        actix_web::error::ErrorInternalServerError("Password hashing failed")
-    })?;
+    let password_hash = hash_password(&user_data.password)?;
 
     let user = User {
         id: None,
@@ -106,17 +336,39 @@ pub async fn register(
         first_name: user_data.first_name.clone(),
         last_name: user_data.last_name.clone(),
         password_hash,
+        scopes: invite.as_ref().map(|invite| invite.scopes.clone()).unwrap_or_else(default_scopes),
+        identities: Vec::new(),
+        two_factor: None,
     };
 
     // Insert user
-    let result = collection.insert_one(&user, None).await.map_err(|e| {
-        error!("Failed to insert user: {}", e);
-        actix_web::error::ErrorInternalServerError("Failed to create user")
-    })?;
+    let result = match collection.insert_one(&user, None).await {
+        Ok(result) => result,
+        Err(e) => {
+            if let Some(invite) = &invite {
+                invites::release(&db, invite.id).await;
+            }
+            if is_duplicate_key(&e) {
+                debug!("Rejected concurrent registration of an email already in use");
+                return Err(email_taken().into());
+            }
+            error!("Failed to insert user: {}", e);
+            return Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to create user").into());
+        }
+    };
 
     let user_id = result.inserted_id.as_object_id().unwrap();
+    if let Some(invite) = &invite {
+        invites::accept(&db, invite.id, user_id).await;
+    }
 
     info!("Created new user with ID: {}", user_id);
+    AuthEvent::new(AuthEventKind::Registered)
+        .user(user_id)
+        .email(&user.email)
+        .method("password")
+        .record(&db, &req)
+        .await;
     Ok(HttpResponse::Created().json(doc! {
         "message": "User registered successfully",
         "id": user_id.to_string()
@@ -124,44 +376,123 @@ pub async fn register(
 }
 
 pub async fn login(
+    req: HttpRequest,
     db: web::Data<MongoConfig>,
+    throttle: Option<web::Data<LoginThrottle>>,
     credentials: web::Json<LoginRequest>,
 ) -> Result<HttpResponse, Error> {
     let collection: Collection<User> = db.database.collection("users");
+    let throttle = throttle.as_ref().map(|throttle| throttle.get_ref());
+    let email = pii::normalize_email(&credentials.email);
+
+    // Addresses with too many failed logins have to solve a CAPTCHA first
+    let ip = req.connection_info().realip_remote_addr().map(str::to_string);
+    if let (Some(throttle), Some(ip)) = (throttle, ip.as_deref()) {
+        if throttle.captcha_required(ip).await {
+            let solved = match credentials.captcha_token.as_deref() {
+                Some(token) => throttle.verify(token, ip).await,
+                None => false,
+            };
+            if !solved {
+                let reason = if credentials.captcha_token.is_some() { "captcha_failed" } else { "captcha_missing" };
+                login_failed(&db, &req, &email, None, reason, None).await;
+                return Err(ApiError::new(StatusCode::UNAUTHORIZED, "CAPTCHA required")
+                    .with("captcha_required", true)
+                    .into());
+            }
+        }
+    }
 
     // Find user by email
     let user = match collection
-        .find_one(doc! { "email": &credentials.email }, None)
+        .find_one(doc! { "email": pii::email_filter(&email) }, None)
         .await
         .map_err(|e| {
             error!("Database error: {}", e);
-            actix_web::error::ErrorInternalServerError("Database error")
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
         })? {
         Some(user) => user,
-        None => return Ok(HttpResponse::Unauthorized().json(doc! {
-            "message": "Invalid credentials"
-        })),
+        None => {
+            verify_dummy(&credentials.password);
+            let captcha_required = login_failed(&db, &req, &email, None, "unknown_email", throttle).await;
+            return Err(invalid_credentials(captcha_required).into());
+        }
     };
 
+    // Accounts without a password can only sign in through their identity provider
+    if user.password_hash.is_empty() {
+        verify_dummy(&credentials.password);
+        let captcha_required = login_failed(&db, &req, &email, user.id, "no_password", throttle).await;
+        return Err(invalid_credentials(captcha_required).into());
+    }
+
     // Verify password
-    if !verify(&credentials.password, &user.password_hash).map_err(|e| {
-        error!("Password verification error: {}", e);
-        actix_web::error::ErrorInternalServerError("Password verification failed")
-    })? {
-        return Ok(HttpResponse::Unauthorized().json(doc! {
-            "message": "Invalid credentials"
+    let verification = verify_password(&credentials.password, &user.password_hash)?;
+    if !verification.valid {
+        let captcha_required = login_failed(&db, &req, &email, user.id, "wrong_password", throttle).await;
+        return Err(invalid_credentials(captcha_required).into());
+    }
+
+    // Migrate legacy bcrypt or outdated Argon2 hashes now that we have the plaintext
+    if verification.needs_rehash {
+        match hash_password(&credentials.password) {
+            Ok(new_hash) => {
+                if let Err(e) = collection
+                    .update_one(
+                        doc! { "_id": user.id, "password_hash": &user.password_hash },
+                        doc! { "$set": { "password_hash": new_hash } },
+                        None,
+                    )
+                    .await
+                {
+                    error!("Failed to store rehashed password for user {:?}: {}", user.id, e);
+                } else {
+                    debug!("Rehashed password for user {:?}", user.id);
+                }
+            }
+            Err(_) => error!("Failed to rehash password for user {:?}", user.id),
+        }
+    }
+
+    // Narrow the granted scopes if the client asked for a subset
+    let scopes = match &credentials.scopes {
+        Some(requested) => {
+            if let Some(scope) = requested.iter().find(|s| !user.scopes.contains(s)) {
+                login_failed(&db, &req, &email, user.id, "scope_not_granted", None).await;
+                let message = format!("Scope not granted to user: {}", scope);
+                return Err(ApiError::new(StatusCode::FORBIDDEN, message).into());
+            }
+            requested.clone()
+        }
+        None => user.scopes.clone(),
+    };
+
+    let user_id = user.id.as_ref().unwrap();
+
+    // Second factor required: hand out a challenge instead of tokens
+    if user.two_factor.as_ref().map(|tf| tf.enabled).unwrap_or(false) {
+        let challenge_token = two_factor::issue_challenge(user_id, credentials.scopes.as_deref())?;
+        return Ok(HttpResponse::Ok().json(doc! {
+            "two_factor_required": true,
+            "challenge_token": challenge_token
         }));
     }
 
     // Generate tokens
-    let user_id = user.id.as_ref().unwrap();
-    let (token, refresh_token) = generate_tokens(user_id).await?;
+    let (token, refresh_token) = sessions::start_session(&db, &req, user_id, &scopes).await?;
+    AuthEvent::new(AuthEventKind::LoginSucceeded)
+        .user(*user_id)
+        .email(&user.email)
+        .method("password")
+        .record(&db, &req)
+        .await;
 
     let user_response = UserResponse {
         id: user_id.to_string(),
         email: user.email,
         first_name: user.first_name,
         last_name: user.last_name,
+        scopes,
     };
 
     Ok(HttpResponse::Ok().json(AuthResponse {
@@ -171,31 +502,110 @@ pub async fn login(
     }))
 }
 
+// Failed password logins; the reason is only for the audit trail, clients
+// always get the same answer. Counted against the client's address when
+// `throttle` is given; true once its next login needs a CAPTCHA
+async fn login_failed(
+    db: &MongoConfig,
+    req: &HttpRequest,
+    email: &str,
+    user_id: Option<ObjectId>,
+    reason: &str,
+    throttle: Option<&LoginThrottle>,
+) -> bool {
+    let mut event = AuthEvent::new(AuthEventKind::LoginFailed).email(email).method("password").reason(reason);
+    if let Some(user_id) = user_id {
+        event = event.user(user_id);
+    }
+    event.record(db, req).await;
+
+    let ip = req.connection_info().realip_remote_addr().map(str::to_string);
+    match (throttle, ip) {
+        (Some(throttle), Some(ip)) => throttle.record_failure(&ip).await,
+        _ => false,
+    }
+}
+
+fn invalid_credentials(captcha_required: bool) -> ApiError {
+    if captcha_required {
+        ApiError::new(StatusCode::UNAUTHORIZED, "Invalid credentials")
+            .with("captcha_required", true)
+    } else {
+        ApiError::new(StatusCode::UNAUTHORIZED, "Invalid credentials")
+    }
+}
+
+async fn refresh_failed(db: &MongoConfig, req: &HttpRequest, user_id: Option<ObjectId>, reason: &str) {
+    let mut event = AuthEvent::new(AuthEventKind::TokenRefreshFailed).reason(reason);
+    if let Some(user_id) = user_id {
+        event = event.user(user_id);
+    }
+    event.record(db, req).await;
+}
+
 pub async fn refresh_token(
-    req: web::Json<RefreshTokenRequest>,
+    req: HttpRequest,
+    db: web::Data<MongoConfig>,
+    body: web::Json<RefreshTokenRequest>,
 ) -> Result<HttpResponse, Error> {
     // Verify refresh token
     let claims = match decode::<Claims>(
-        &req.refresh_token,
-        &DecodingKey::from_secret(REFRESH_SECRET),
+        &body.refresh_token,
+        &DecodingKey::from_secret(refresh_secret()),
         &Validation::default(),
     ) {
         Ok(token_data) => token_data.claims,
         Err(e) => {
             error!("Token verification error: {}", e);
-            return Ok(HttpResponse::Unauthorized().json(doc! {
-                "message": "Invalid refresh token"
-            }));
+            refresh_failed(&db, &req, None, "invalid_token").await;
+            return Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid refresh token").into());
         }
     };
 
-    // Generate new tokens
     let user_id = ObjectId::parse_str(&claims.sub).map_err(|e| {
         error!("Failed to parse ObjectId: {}", e);
-        actix_web::error::ErrorInternalServerError("Invalid user ID format")
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Invalid user ID format")
     })?;
 
-    let (token, refresh_token) = generate_tokens(&user_id).await?;
+    // Tokens issued before sessions were tracked cannot be rotated
+    let (session_id, jti) = match (
+        claims.sid.as_deref().and_then(|sid| ObjectId::parse_str(sid).ok()),
+        claims.jti.as_deref(),
+    ) {
+        (Some(session_id), Some(jti)) => (session_id, jti),
+        _ => {
+            refresh_failed(&db, &req, Some(user_id), "untracked_session").await;
+            return Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid refresh token").into());
+        }
+    };
+
+    // Consume the presented token and issue its successor
+    let new_jti = match sessions::rotate_refresh_token(&db, &session_id, &user_id, jti).await? {
+        Some(new_jti) => new_jti,
+        None => {
+            refresh_failed(&db, &req, Some(user_id), "token_not_current").await;
+            return Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid refresh token").into());
+        }
+    };
+
+    // The session keeps the scopes it was given, less any the user has lost since
+    let user = db
+        .database
+        .collection::<User>("users")
+        .find_one(doc! { "_id": user_id }, None)
+        .await
+        .map_err(|e| {
+            error!("Database error: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+        })?;
+    let Some(user) = user else {
+        refresh_failed(&db, &req, Some(user_id), "user_not_found").await;
+        return Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid refresh token").into());
+    };
+    let scopes: Vec<String> = claims.scopes.iter().filter(|scope| user.scopes.contains(scope)).cloned().collect();
+
+    let (token, refresh_token) = generate_tokens(&user_id, &scopes, &session_id, &new_jti).await?;
+    AuthEvent::new(AuthEventKind::TokenRefreshed).user(user_id).record(&db, &req).await;
 
     Ok(HttpResponse::Ok().json(doc! {
         "token": token,
@@ -203,7 +613,57 @@ pub async fn refresh_token(
     }))
 }
 
-pub async fn generate_tokens(user_id: &ObjectId) -> Result<(String, String), Error> {
+/// Replaces the caller's password after checking the current one.
+pub async fn change_password(
+    req: HttpRequest,
+    db: web::Data<MongoConfig>,
+    policy: web::Data<PasswordPolicy>,
+    claims: web::ReqData<Claims>,
+    body: web::Json<ChangePasswordRequest>,
+) -> Result<HttpResponse, Error> {
+    if let Err(errors) = body.validate() {
+        return Err(validation_error(errors));
+    }
+
+    claims.forbid_impersonation()?;
+    let user_id = claims.user_id()?;
+    let collection: Collection<User> = db.database.collection("users");
+    let user = collection
+        .find_one(doc! { "_id": user_id }, None)
+        .await
+        .map_err(|e| {
+            error!("Database error: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+        })?
+        .ok_or_else(|| ApiError::new(StatusCode::UNAUTHORIZED, "User not found"))?;
+
+    if user.password_hash.is_empty() || !verify_password(&body.current_password, &user.password_hash)?.valid {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "Current password is incorrect").into());
+    }
+    if let Err(errors) = policy.check("new_password", &body.new_password).await {
+        return Err(validation_error(errors));
+    }
+
+    let password_hash = hash_password(&body.new_password)?;
+    collection
+        .update_one(doc! { "_id": user_id }, doc! { "$set": { "password_hash": password_hash } }, None)
+        .await
+        .map_err(|e| {
+            error!("Failed to update password: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to change password")
+        })?;
+
+    info!("User {} changed their password", user_id);
+    AuthEvent::new(AuthEventKind::PasswordChanged).user(user_id).email(&user.email).record(&db, &req).await;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn generate_tokens(
+    user_id: &ObjectId,
+    scopes: &[String],
+    session_id: &ObjectId,
+    jti: &str,
+) -> Result<(String, String), Error> {
     let now = Utc::now();
 
     // Access token (2 hours)
@@ -211,6 +671,10 @@ pub async fn generate_tokens(user_id: &ObjectId) -> Result<(String, String), Err
         sub: user_id.to_string(),
         exp: (now + Duration::hours(2)).timestamp(),
         iat: now.timestamp(),
+        scopes: scopes.to_vec(),
+        sid: Some(session_id.to_string()),
+        jti: None,
+        impersonator: None,
     };
 
     // Refresh token (7 days)
@@ -218,47 +682,130 @@ pub async fn generate_tokens(user_id: &ObjectId) -> Result<(String, String), Err
         sub: user_id.to_string(),
         exp: (now + Duration::days(7)).timestamp(),
         iat: now.timestamp(),
+        scopes: scopes.to_vec(),
+        sid: Some(session_id.to_string()),
+        jti: Some(jti.to_string()),
+        impersonator: None,
     };
 
     let token = encode(
         &Header::default(),
         &access_claims,
-        &EncodingKey::from_secret(JWT_SECRET),
+        &EncodingKey::from_secret(jwt_secret()),
     ).map_err(|e| {
         error!("Token generation error: {}", e);
-        actix_web::error::ErrorInternalServerError("Token generation failed")
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Token generation failed")
     })?;
 
     let refresh_token = encode(
         &Header::default(),
         &refresh_claims,
-        &EncodingKey::from_secret(REFRESH_SECRET),
+        &EncodingKey::from_secret(refresh_secret()),
     ).map_err(|e| {
         error!("Refresh token generation error: {}", e);
-        actix_web::error::ErrorInternalServerError("Refresh token generation failed")
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Refresh token generation failed")
     })?;
 
     Ok((token, refresh_token))
 }
 
+/// Mints a short-lived access token acting as another user, for support to
+/// reproduce user-specific issues. Requests made with it are recorded with
+/// the admin as impersonator. There is no refresh token; ask again once it
+/// expires.
+pub async fn impersonate(
+    req: HttpRequest,
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    user_id: web::Path<String>,
+    body: web::Json<ImpersonateRequest>,
+) -> Result<HttpResponse, Error> {
+    if let Err(errors) = body.validate() {
+        return Err(validation_error(errors));
+    }
+    claims.forbid_impersonation()?;
+    let admin_id = claims.user_id()?;
+    let target_id = ObjectId::parse_str(user_id.as_str()).map_err(|_| {
+        error!("Invalid user ID format: {}", user_id);
+        ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format")
+    })?;
+    if target_id == admin_id {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Cannot impersonate yourself").into());
+    }
+
+    let collection: Collection<User> = db.database.collection("users");
+    let user = collection
+        .find_one(doc! { "_id": target_id }, None)
+        .await
+        .map_err(|e| {
+            error!("Database error: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+        })?;
+    let Some(user) = user else {
+        debug!("Impersonation target {} not found", target_id);
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    };
+    // Acting as another admin would hide who holds admin rights
+    if user.scopes.iter().any(|scope| scope == SCOPE_ADMIN) {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "Admins cannot be impersonated").into());
+    }
+
+    let now = Utc::now();
+    let expires_at = now + Duration::minutes(IMPERSONATION_MINUTES);
+    let impersonation_claims = Claims {
+        sub: target_id.to_string(),
+        exp: expires_at.timestamp(),
+        iat: now.timestamp(),
+        scopes: user.scopes.clone(),
+        sid: None,
+        jti: None,
+        impersonator: Some(admin_id.to_string()),
+    };
+    let token = encode(
+        &Header::default(),
+        &impersonation_claims,
+        &EncodingKey::from_secret(jwt_secret()),
+    ).map_err(|e| {
+        error!("Token generation error: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Token generation failed")
+    })?;
+
+    info!("Admin {} started impersonating user {}", admin_id, target_id);
+    AuthEvent::new(AuthEventKind::ImpersonationStarted)
+        .user(target_id)
+        .email(&user.email)
+        .impersonator(admin_id)
+        .reason(&body.reason)
+        .record(&db, &req)
+        .await;
+
+    Ok(HttpResponse::Ok().json(ImpersonationResponse {
+        token,
+        expires_at: expires_at.to_rfc3339(),
+        impersonator_id: admin_id.to_string(),
+        user: UserResponse {
+            id: target_id.to_string(),
+            email: user.email,
+            first_name: user.first_name,
+            last_name: user.last_name,
+            scopes: user.scopes,
+        },
+    }))
+}
+
 pub fn verify_token(token: &str) -> Result<Claims, JwtError> {
     let token_data = decode::<Claims>(
         token,
-        &DecodingKey::from_secret(JWT_SECRET),
+        &DecodingKey::from_secret(jwt_secret()),
         &Validation::default(),
     )?;
     Ok(token_data.claims)
 }
 
 // Auth middleware implementation
+#[derive(Default)]
 pub struct AuthMiddleware;
 
-impl Default for AuthMiddleware {
-    fn default() -> Self {
-        AuthMiddleware
-    }
-}
-
 impl<S, B> Transform<S, ServiceRequest> for AuthMiddleware
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
@@ -272,17 +819,44 @@ where
     type Future = FutureReady<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ok(AuthMiddlewareMiddleware { service })
+        ok(AuthMiddlewareMiddleware { service: Rc::new(service) })
     }
 }
 
 pub struct AuthMiddlewareMiddleware<S> {
-    service: S,
+    service: Rc<S>,
+}
+
+// Tokens of a session that was revoked, or deleted with its account, stop
+// working rather than lasting until they expire. Impersonation tokens have
+// no session and only their own short lifetime.
+async fn check_session(req: &ServiceRequest, claims: &Claims) -> Result<(), Error> {
+    let Some(sid) = &claims.sid else {
+        return Ok(());
+    };
+    let session_id = ObjectId::parse_str(sid).map_err(|_| ApiError::new(StatusCode::UNAUTHORIZED, "Invalid token"))?;
+    let user_id = claims.user_id()?;
+    let db = req.app_data::<web::Data<MongoConfig>>().cloned();
+    let cache = req.app_data::<web::Data<sessions::SessionCache>>().cloned();
+    let (Some(db), Some(cache)) = (db, cache) else {
+        error!("Sessions can't be checked without MongoConfig and SessionCache app data");
+        return Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Session check unavailable").into());
+    };
+    let live = cache.is_live(&db, session_id, user_id).await.map_err(|e| {
+        error!("Failed to check session {}: {}", session_id, e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+    })?;
+    if live {
+        Ok(())
+    } else {
+        debug!("Rejected token of revoked session {}", session_id);
+        Err(ApiError::new(StatusCode::UNAUTHORIZED, "Session revoked").into())
+    }
 }
 
 impl<S, B> Service<ServiceRequest> for AuthMiddlewareMiddleware<S>
 where
-    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
     B: 'static,
 {
@@ -301,7 +875,7 @@ where
             Some(header) => header,
             None => {
                 return Box::pin(async move {
-                    Err(ErrorUnauthorized("No authorization header"))
+                    Err(ApiError::new(StatusCode::UNAUTHORIZED, "No authorization header").into())
                 });
             }
         };
@@ -310,14 +884,14 @@ where
             Ok(str) => str,
             Err(_) => {
                 return Box::pin(async move {
-                    Err(ErrorUnauthorized("Invalid authorization header"))
+                    Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid authorization header").into())
                 });
             }
         };
 
         if !auth_str.starts_with("Bearer ") {
             return Box::pin(async move {
-                Err(ErrorUnauthorized("Invalid authorization header format"))
+                Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid authorization header format").into())
             });
         }
 
@@ -325,24 +899,93 @@ where
 
         match verify_token(token) {
             Ok(claims) => {
-                // Store claims in request extensions if needed
-                // req.extensions_mut().insert(claims);
-                let fut = self.service.call(req);
+                if let Some(impersonator) = &claims.impersonator {
+                    info!("Admin {} acting as user {}: {} {}", impersonator, claims.sub, req.method(), req.path());
+                }
+                let service = Rc::clone(&self.service);
                 Box::pin(async move {
-                    let res = fut.await?;
-                    Ok(res)
+                    check_session(&req, &claims).await?;
+                    // Make claims available to downstream middleware and handlers
+                    req.extensions_mut().insert(claims);
+                    service.call(req).await
                 })
             }
             Err(_) => Box::pin(async move {
-                Err(ErrorUnauthorized("Invalid token"))
+                Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid token").into())
             }),
         }
     }
 }
 
-// Factory for AuthMiddleware
-pub fn auth_middleware<S>(
+// Scope guard, applied per route after AuthMiddleware has run
+pub struct RequireScope {
+    scope: &'static str,
+}
+
+impl RequireScope {
+    pub fn new(scope: &'static str) -> Self {
+        RequireScope { scope }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireScope
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequireScopeMiddleware<S>;
+    type Future = FutureReady<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequireScopeMiddleware {
+            service,
+            scope: self.scope,
+        })
+    }
+}
+
+pub struct RequireScopeMiddleware<S> {
     service: S,
-) -> AuthMiddlewareMiddleware<S> {
-    AuthMiddlewareMiddleware { service }
+    scope: &'static str,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireScopeMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let allowed = req
+            .extensions()
+            .get::<Claims>()
+            .map(|claims| claims.has_scope(self.scope))
+            .unwrap_or(false);
+
+        if !allowed {
+            debug!("Request to {} rejected: missing scope {}", req.path(), self.scope);
+            let scope = self.scope;
+            return Box::pin(async move {
+                Err(ApiError::new(StatusCode::FORBIDDEN, format!("Missing required scope: {}", scope)).into())
+            });
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await?;
+            Ok(res)
+        })
+    }
 }
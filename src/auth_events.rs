@@ -0,0 +1,231 @@
+use actix_web::{http::StatusCode, web, Error, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime as ChronoDateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    options::FindOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+use validator::Validate;
+
+use crate::{
+    auth::Claims,
+    config::{LimitsConfig, MongoConfig},
+    errors::ApiError,
+    validation::{page_offset, ValidatedQuery},
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthEventKind {
+    Registered,
+    LoginSucceeded,
+    LoginFailed,
+    TokenRefreshed,
+    TokenRefreshFailed,
+    PasswordChanged,
+    ImpersonationStarted,
+}
+
+/// One authentication event, kept for security investigations.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuthEvent {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub kind: AuthEventKind,
+    // Unknown when a login names an email nobody registered
+    pub user_id: Option<ObjectId>,
+    // As given by the client, so failed logins for unknown accounts show up too
+    pub email: Option<String>,
+    // How the user authenticated: password, two_factor, or oauth:<provider>
+    pub method: Option<String>,
+    // Why a failed attempt failed, or why an admin impersonated the user;
+    // never sent to the client
+    pub reason: Option<String>,
+    // Admin acting as the user through an impersonation token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impersonator_id: Option<ObjectId>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuthEventResponse {
+    pub id: String,
+    pub kind: AuthEventKind,
+    pub user_id: Option<String>,
+    pub email: Option<String>,
+    pub method: Option<String>,
+    pub reason: Option<String>,
+    pub impersonator_id: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: String,
+}
+
+impl From<&AuthEvent> for AuthEventResponse {
+    fn from(event: &AuthEvent) -> Self {
+        AuthEventResponse {
+            id: event.id.map(|id| id.to_string()).unwrap_or_default(),
+            kind: event.kind,
+            user_id: event.user_id.map(|id| id.to_string()),
+            email: event.email.clone(),
+            method: event.method.clone(),
+            reason: event.reason.clone(),
+            impersonator_id: event.impersonator_id.map(|id| id.to_string()),
+            ip: event.ip.clone(),
+            user_agent: event.user_agent.clone(),
+            created_at: event.created_at.try_to_rfc3339_string().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ListAuthEventsQuery {
+    #[validate(range(min = 1, message = "page must be at least 1"))]
+    page: Option<i64>,
+    #[validate(range(min = 1, message = "per_page must be at least 1"))]
+    per_page: Option<i64>,
+    // Events at or after this time
+    from: Option<ChronoDateTime<Utc>>,
+    // Events before this time
+    to: Option<ChronoDateTime<Utc>>,
+    kind: Option<AuthEventKind>,
+    user_id: Option<String>,
+    email: Option<String>,
+    ip: Option<String>,
+    impersonator_id: Option<String>,
+}
+
+fn auth_events_collection(db: &MongoConfig) -> Collection<AuthEvent> {
+    db.database.collection("auth_events")
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+impl AuthEvent {
+    pub fn new(kind: AuthEventKind) -> Self {
+        AuthEvent {
+            id: None,
+            kind,
+            user_id: None,
+            email: None,
+            method: None,
+            reason: None,
+            impersonator_id: None,
+            ip: None,
+            user_agent: None,
+            created_at: DateTime::now(),
+        }
+    }
+
+    pub fn user(mut self, user_id: ObjectId) -> Self {
+        self.user_id = Some(user_id);
+        self
+    }
+
+    pub fn email(mut self, email: &str) -> Self {
+        self.email = Some(email.to_string());
+        self
+    }
+
+    pub fn method(mut self, method: &str) -> Self {
+        self.method = Some(method.to_string());
+        self
+    }
+
+    pub fn reason(mut self, reason: &str) -> Self {
+        self.reason = Some(reason.to_string());
+        self
+    }
+
+    pub fn impersonator(mut self, impersonator_id: ObjectId) -> Self {
+        self.impersonator_id = Some(impersonator_id);
+        self
+    }
+
+    /// Stores the event with the client's IP and user agent, and the admin
+    /// behind the request when it was made with an impersonation token. The
+    /// audit trail is best effort: a request never fails because it could not be logged.
+    pub async fn record(mut self, db: &MongoConfig, req: &HttpRequest) {
+        if self.impersonator_id.is_none() {
+            self.impersonator_id = req.extensions().get::<Claims>().and_then(Claims::impersonator_id);
+        }
+        self.ip = req.connection_info().realip_remote_addr().map(str::to_string);
+        self.user_agent = req
+            .headers()
+            .get("User-Agent")
+            .and_then(|ua| ua.to_str().ok())
+            .map(str::to_string);
+        if let Err(e) = auth_events_collection(db).insert_one(&self, None).await {
+            warn!("Failed to record {:?} auth event: {}", self.kind, e);
+        }
+    }
+}
+
+/// Authentication events, newest first.
+pub async fn list_auth_events(
+    db: web::Data<MongoConfig>,
+    limits: web::Data<LimitsConfig>,
+    query: ValidatedQuery<ListAuthEventsQuery>,
+) -> Result<HttpResponse, Error> {
+    let per_page = query.per_page.unwrap_or(20);
+    if per_page > limits.max_per_page {
+        let message = format!("per_page must be between 1 and {}", limits.max_per_page);
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, message).into());
+    }
+    let page = query.page.unwrap_or(1);
+
+    let mut filter = Document::new();
+    let mut created_at = Document::new();
+    if let Some(from) = query.from {
+        created_at.insert("$gte", DateTime::from_millis(from.timestamp_millis()));
+    }
+    if let Some(to) = query.to {
+        created_at.insert("$lt", DateTime::from_millis(to.timestamp_millis()));
+    }
+    if !created_at.is_empty() {
+        filter.insert("created_at", created_at);
+    }
+    if let Some(kind) = query.kind {
+        filter.insert("kind", mongodb::bson::to_bson(&kind).map_err(ApiError::internal)?);
+    }
+    if let Some(user_id) = &query.user_id {
+        let user_id = ObjectId::parse_str(user_id)
+            .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "Invalid user ID format"))?;
+        filter.insert("user_id", user_id);
+    }
+    if let Some(email) = &query.email {
+        filter.insert("email", email);
+    }
+    if let Some(ip) = &query.ip {
+        filter.insert("ip", ip);
+    }
+    if let Some(impersonator_id) = &query.impersonator_id {
+        let impersonator_id = ObjectId::parse_str(impersonator_id)
+            .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "Invalid impersonator ID format"))?;
+        filter.insert("impersonator_id", impersonator_id);
+    }
+
+    let options = FindOptions::builder()
+        .sort(doc! { "created_at": -1 })
+        .skip(page_offset(page, per_page)? as u64)
+        .limit(per_page)
+        .build();
+    let events: Vec<AuthEvent> = auth_events_collection(&db)
+        .find(filter, options)
+        .await
+        .map_err(|e| db_error("Failed to fetch auth events", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating auth events", e))?;
+
+    let events: Vec<AuthEventResponse> = events.iter().map(AuthEventResponse::from).collect();
+    Ok(HttpResponse::Ok().json(events))
+}
@@ -0,0 +1,366 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Read, Write},
+};
+
+use actix_web::{http::StatusCode, web, web::Bytes, Error, HttpResponse};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use futures::{stream, StreamExt, TryStreamExt};
+use mongodb::{
+    bson::{doc, Bson, DateTime, Document},
+    options::{FindOptions, ReplaceOptions, UpdateOptions},
+    Collection, Cursor,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    attributes::CategoryAttributes,
+    auth::User,
+    config::{LimitsConfig, MongoConfig},
+    errors::ApiError,
+    event_store::{self, ProductEvent},
+    handlers::payload_too_large,
+    models::Product,
+};
+
+const ARCHIVE_FORMAT: &str = "products-api-backup";
+const ARCHIVE_VERSION: i64 = 1;
+
+// Restored in this order so definitions exist before the products that use them
+const BACKUP_COLLECTIONS: [&str; 3] = ["category_attributes", "users", "products"];
+
+// Credentials never leave the server; restored users keep their existing ones
+const USER_SECRET_FIELDS: [&str; 2] = ["password_hash", "two_factor"];
+
+// Compressed bytes to buffer before sending a chunk
+const CHUNK_BYTES: usize = 64 * 1024;
+
+// Guards against archives that inflate far beyond the upload limit
+const MAX_EXPANSION: usize = 20;
+
+const MAX_REPORTED_ERRORS: usize = 100;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RestoreQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ArchiveHeader {
+    format: String,
+    version: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ArchiveRecord {
+    collection: String,
+    document: Value,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CollectionSummary {
+    documents: usize,
+    created: usize,
+    replaced: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RestoreReport {
+    dry_run: bool,
+    collections: BTreeMap<String, CollectionSummary>,
+    errors: Vec<String>,
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+fn projection(collection: &str) -> Option<Document> {
+    (collection == "users").then(|| USER_SECRET_FIELDS.iter().map(|field| (field.to_string(), Bson::Int32(0))).collect())
+}
+
+struct BackupState {
+    db: web::Data<MongoConfig>,
+    next_collection: usize,
+    cursor: Option<(&'static str, Cursor<Document>)>,
+    encoder: Option<GzEncoder<Vec<u8>>>,
+}
+
+impl BackupState {
+    fn write_line(&mut self, value: &Value) -> std::io::Result<()> {
+        let encoder = self.encoder.as_mut().expect("encoder is present until the archive is finished");
+        serde_json::to_writer(&mut *encoder, value)?;
+        encoder.write_all(b"\n")
+    }
+
+    fn take_compressed(&mut self) -> Vec<u8> {
+        self.encoder.as_mut().map(|encoder| std::mem::take(encoder.get_mut())).unwrap_or_default()
+    }
+
+    fn buffered(&self) -> usize {
+        self.encoder.as_ref().map_or(0, |encoder| encoder.get_ref().len())
+    }
+
+    // Writes documents until a chunk is ready; None once the archive is complete
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, String> {
+        while self.buffered() < CHUNK_BYTES {
+            if self.cursor.is_none() {
+                let Some(&name) = BACKUP_COLLECTIONS.get(self.next_collection) else {
+                    let Some(encoder) = self.encoder.take() else {
+                        return Ok(None);
+                    };
+                    let rest = encoder.finish().map_err(|e| format!("Compression error: {}", e))?;
+                    return Ok(Some(Bytes::from(rest)));
+                };
+                self.next_collection += 1;
+
+                let collection: Collection<Document> = self.db.database.collection(name);
+                let options = FindOptions::builder().sort(doc! { "_id": 1 }).projection(projection(name)).build();
+                let cursor = collection.find(None, options).await.map_err(|e| format!("Database error: {}", e))?;
+                self.cursor = Some((name, cursor));
+            }
+
+            let (name, cursor) = self.cursor.as_mut().expect("cursor was just opened");
+            let name = *name;
+            match cursor.try_next().await.map_err(|e| format!("Database error: {}", e))? {
+                Some(document) => {
+                    let record = json!({
+                        "collection": name,
+                        "document": Bson::Document(document).into_canonical_extjson(),
+                    });
+                    self.write_line(&record).map_err(|e| format!("Compression error: {}", e))?;
+                }
+                None => self.cursor = None,
+            }
+        }
+        Ok(Some(Bytes::from(self.take_compressed())))
+    }
+}
+
+/// Streams a gzip-compressed archive of the catalog and user accounts, one
+/// extended-JSON document per line after a header line.
+pub async fn create_backup(db: web::Data<MongoConfig>) -> Result<HttpResponse, Error> {
+    let mut state = BackupState {
+        db,
+        next_collection: 0,
+        cursor: None,
+        encoder: Some(GzEncoder::new(Vec::new(), Compression::default())),
+    };
+    let created_at = DateTime::now().try_to_rfc3339_string().unwrap_or_default();
+    state
+        .write_line(&json!({ "format": ARCHIVE_FORMAT, "version": ARCHIVE_VERSION, "created_at": created_at }))
+        .map_err(ApiError::internal)?;
+
+    info!("Streaming backup archive");
+    let body = stream::unfold(Some(state), |state| async move {
+        let mut state = state?;
+        match state.next_chunk().await {
+            Ok(Some(chunk)) => Some((Ok::<_, Error>(chunk), Some(state))),
+            Ok(None) => {
+                info!("Backup archive complete");
+                None
+            }
+            Err(e) => {
+                // Headers are already sent, so the client sees a truncated archive
+                error!("Backup aborted: {}", e);
+                Some((Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e).into()), None))
+            }
+        }
+    });
+
+    let filename = format!("backup-{}.jsonl.gz", DateTime::now().timestamp_millis());
+    Ok(HttpResponse::Ok()
+        .content_type("application/gzip")
+        .insert_header(("Content-Disposition", format!("attachment; filename=\"{}\"", filename)))
+        .streaming(body))
+}
+
+// Parses and checks every record; documents are only returned if all of them are valid
+fn read_archive(compressed: &[u8], max_bytes: usize) -> Result<Vec<(String, Document)>, Vec<String>> {
+    let reader = BufReader::new(GzDecoder::new(compressed).take(max_bytes as u64 + 1));
+    let mut records = Vec::new();
+    let mut errors = Vec::new();
+    let mut total_bytes = 0;
+
+    for (index, line) in reader.lines().enumerate() {
+        let line_number = index + 1;
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                errors.push(format!("Archive is not valid gzip-compressed text: {}", e));
+                break;
+            }
+        };
+        total_bytes += line.len() + 1;
+        if total_bytes > max_bytes {
+            errors.push(format!("Archive expands beyond {} bytes", max_bytes));
+            break;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if line_number == 1 {
+            match serde_json::from_str::<ArchiveHeader>(&line) {
+                Ok(header) if header.format == ARCHIVE_FORMAT && header.version == ARCHIVE_VERSION => continue,
+                Ok(header) => errors.push(format!("Unsupported archive {} version {}", header.format, header.version)),
+                Err(_) => errors.push("Missing archive header".to_string()),
+            }
+            break;
+        }
+
+        match parse_record(&line) {
+            Ok(record) => records.push(record),
+            Err(e) if errors.len() < MAX_REPORTED_ERRORS => errors.push(format!("Line {}: {}", line_number, e)),
+            Err(_) => {}
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(records)
+    } else {
+        Err(errors)
+    }
+}
+
+fn parse_record(line: &str) -> Result<(String, Document), String> {
+    let record: ArchiveRecord = serde_json::from_str(line).map_err(|e| format!("Invalid record: {}", e))?;
+    if !BACKUP_COLLECTIONS.contains(&record.collection.as_str()) {
+        return Err(format!("Unknown collection '{}'", record.collection));
+    }
+    let document = match Bson::try_from(record.document) {
+        Ok(Bson::Document(document)) => document,
+        Ok(_) => return Err("Document must be an object".to_string()),
+        Err(e) => return Err(format!("Invalid extended JSON: {}", e)),
+    };
+    if !document.contains_key("_id") {
+        return Err("Document has no _id".to_string());
+    }
+
+    let checked = match record.collection.as_str() {
+        "products" => mongodb::bson::from_document::<Product>(document.clone()).map(|_| ()),
+        "users" => mongodb::bson::from_document::<User>(document.clone()).map(|_| ()),
+        _ => mongodb::bson::from_document::<CategoryAttributes>(document.clone()).map(|_| ()),
+    };
+    checked.map_err(|e| format!("Invalid {} document: {}", record.collection, e))?;
+
+    Ok((record.collection, document))
+}
+
+// How many of the given IDs already exist, counted in batches
+async fn count_existing(db: &MongoConfig, collection: &str, ids: &[Bson]) -> Result<usize, Error> {
+    let collection: Collection<Document> = db.database.collection(collection);
+    let mut existing = 0;
+    for batch in ids.chunks(1000) {
+        existing += collection
+            .count_documents(doc! { "_id": { "$in": batch } }, None)
+            .await
+            .map_err(|e| db_error("Failed to count existing documents", e))? as usize;
+    }
+    Ok(existing)
+}
+
+async fn restore_document(db: &MongoConfig, collection: &str, document: Document) -> Result<bool, mongodb::error::Error> {
+    let target: Collection<Document> = db.database.collection(collection);
+    let filter = doc! { "_id": document.get("_id").cloned().unwrap_or(Bson::Null) };
+
+    let upserted = if collection == "users" {
+        // Merge so an existing account keeps its password and second factor
+        let mut fields = document;
+        fields.remove("_id");
+        for field in USER_SECRET_FIELDS {
+            fields.remove(field);
+        }
+        let options = UpdateOptions::builder().upsert(true).build();
+        target.update_one(filter, doc! { "$set": fields }, options).await?.upserted_id
+    } else {
+        let options = ReplaceOptions::builder().upsert(true).build();
+        target.replace_one(filter, document, options).await?.upserted_id
+    };
+    Ok(upserted.is_some())
+}
+
+/// Restores an archive made by `create_backup`, replacing documents with the
+/// same `_id`. With `dry_run=true` only reports what would change.
+pub async fn restore_backup(
+    db: web::Data<MongoConfig>,
+    limits: web::Data<LimitsConfig>,
+    query: web::Query<RestoreQuery>,
+    mut payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let mut compressed = Vec::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if compressed.len() + chunk.len() > limits.upload_bytes {
+            debug!("Restore archive exceeded {} bytes, aborting", limits.upload_bytes);
+            return Err(payload_too_large(
+                format!("Archive exceeds the limit of {} bytes", limits.upload_bytes),
+                limits.upload_bytes,
+            ).into());
+        }
+        compressed.extend_from_slice(&chunk);
+    }
+
+    let records = match read_archive(&compressed, limits.upload_bytes.saturating_mul(MAX_EXPANSION)) {
+        Ok(records) => records,
+        Err(errors) => {
+            warn!("Rejected restore archive with {} problems", errors.len());
+            return Ok(HttpResponse::UnprocessableEntity().json(RestoreReport {
+                dry_run: query.dry_run,
+                collections: BTreeMap::new(),
+                errors,
+            }));
+        }
+    };
+
+    let mut report = RestoreReport { dry_run: query.dry_run, collections: BTreeMap::new(), errors: Vec::new() };
+    for name in BACKUP_COLLECTIONS {
+        let documents: Vec<Document> =
+            records.iter().filter(|(collection, _)| collection == name).map(|(_, document)| document.clone()).collect();
+        let summary = report.collections.entry(name.to_string()).or_default();
+        summary.documents = documents.len();
+
+        if query.dry_run {
+            let ids: Vec<Bson> = documents.iter().filter_map(|d| d.get("_id").cloned()).collect();
+            summary.replaced = count_existing(&db, name, &ids).await?;
+            summary.created = summary.documents - summary.replaced;
+            continue;
+        }
+
+        for document in documents {
+            let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+            let restored = (name == "products").then(|| ProductEvent::restored(document.clone()));
+            match restore_document(&db, name, document).await {
+                Ok(created) => {
+                    if created {
+                        summary.created += 1;
+                    } else {
+                        summary.replaced += 1;
+                    }
+                    if let (Some(event), Some(product_id)) = (restored, id.as_object_id()) {
+                        event_store::record(&db, product_id, vec![event]).await;
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to restore {} document {}: {}", name, id, e);
+                    if report.errors.len() < MAX_REPORTED_ERRORS {
+                        report.errors.push(format!("{} {}: {}", name, id, e));
+                    }
+                }
+            }
+        }
+    }
+
+    info!(
+        "Restore {} for {} documents with {} errors",
+        if query.dry_run { "checked" } else { "completed" },
+        records.len(),
+        report.errors.len()
+    );
+    Ok(HttpResponse::Ok().json(report))
+}
@@ -0,0 +1,73 @@
+use actix_web::{http::StatusCode, web, Error, HttpRequest, HttpResponse, Responder};
+use mongodb::{
+    bson::doc,
+    error::{ErrorKind, WriteFailure},
+    Collection,
+};
+use tracing::{debug, error, info};
+
+use crate::{config::MongoConfig, errors::ApiError, models::Product, negotiation::Negotiated};
+
+// Server error code for a unique index violation
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Checks an EAN-13 or 12-digit UPC-A code and returns it as 13 digits, so
+/// both forms of the same code are stored and looked up alike.
+pub fn normalize_barcode(code: &str) -> Result<String, String> {
+    let code = code.trim();
+    if !code.chars().all(|c| c.is_ascii_digit()) {
+        return Err("Barcode must contain only digits".to_string());
+    }
+    let code = match code.len() {
+        13 => code.to_string(),
+        // A UPC-A code is an EAN-13 with a leading zero
+        12 => format!("0{}", code),
+        _ => return Err("Barcode must be a 13-digit EAN or 12-digit UPC".to_string()),
+    };
+
+    let digits: Vec<u32> = code.chars().filter_map(|c| c.to_digit(10)).collect();
+    let (payload, check) = digits.split_at(12);
+    let sum: u32 = payload
+        .iter()
+        .enumerate()
+        .map(|(i, d)| if i % 2 == 0 { *d } else { d * 3 })
+        .sum();
+    if (10 - sum % 10) % 10 != check[0] {
+        return Err("Barcode check digit is invalid".to_string());
+    }
+
+    Ok(code)
+}
+
+pub fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(*e.kind, ErrorKind::Write(WriteFailure::WriteError(ref w)) if w.code == DUPLICATE_KEY)
+}
+
+pub async fn get_product_by_barcode(
+    req: HttpRequest,
+    db: web::Data<MongoConfig>,
+    code: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let collection: Collection<Product> = db.catalog_collection("products");
+
+    let barcode = normalize_barcode(&code).map_err(|e| {
+        debug!("Rejected barcode lookup for {}: {}", code, e);
+        ApiError::new(StatusCode::BAD_REQUEST, e)
+    })?;
+
+    let product = collection.find_one(doc! { "barcode": &barcode }, None).await.map_err(|e| {
+        error!("Failed to fetch product by barcode {}: {}", barcode, e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+    })?;
+
+    match product {
+        Some(product) => {
+            info!("Product found for barcode {}", barcode);
+            Ok(Negotiated(product).respond_to(&req))
+        }
+        None => {
+            debug!("No product with barcode {}", barcode);
+            Err(ApiError::blank(StatusCode::NOT_FOUND).into())
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::StatusCode,
+    middleware::Next,
+    web, Error, HttpResponse,
+};
+use mongodb::{
+    bson::doc,
+    error::ErrorKind,
+    event::{
+        command::{CommandEventHandler, CommandFailedEvent, CommandStartedEvent, CommandSucceededEvent},
+        sdam::{SdamEventHandler, ServerHeartbeatFailedEvent},
+    },
+};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{
+    config::MongoConfig,
+    errors::{ApiError, PROBLEM_JSON},
+};
+
+// Routes that report on the database rather than use it
+const UNGUARDED_PATHS: [&str; 2] = ["/ready", "/metrics"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    // Requests go through again; the next result closes or reopens the breaker
+    HalfOpen,
+    Open,
+}
+
+#[derive(Debug)]
+enum Phase {
+    Closed { consecutive_failures: u32 },
+    HalfOpen,
+    Open { until: Instant },
+}
+
+/// Trips after repeated MongoDB failures so requests fail fast with 503
+/// instead of queueing behind a database that is down or hung. Fed by the
+/// driver's command and heartbeat events rather than by each call site.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_for: Duration,
+    // Commands running longer than this count as failed
+    call_timeout: Duration,
+    phase: Mutex<Phase>,
+    in_flight: Mutex<HashMap<i32, Instant>>,
+    failures_total: AtomicU64,
+    rejections_total: AtomicU64,
+    opened_total: AtomicU64,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_for: Duration, call_timeout: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold: failure_threshold.max(1),
+            open_for,
+            call_timeout,
+            phase: Mutex::new(Phase::Closed { consecutive_failures: 0 }),
+            in_flight: Mutex::new(HashMap::new()),
+            failures_total: AtomicU64::new(0),
+            rejections_total: AtomicU64::new(0),
+            opened_total: AtomicU64::new(0),
+        }
+    }
+
+    pub fn call_timeout(&self) -> Duration {
+        self.call_timeout
+    }
+
+    /// Whether a request may use the database, or how long until it may retry.
+    pub fn check(&self) -> Result<(), Duration> {
+        self.expire_hung_commands();
+
+        let mut phase = self.phase.lock().unwrap();
+        if let Phase::Open { until } = *phase {
+            let now = Instant::now();
+            if now < until {
+                self.rejections_total.fetch_add(1, Ordering::Relaxed);
+                return Err(until - now);
+            }
+            info!("MongoDB circuit breaker half-open, letting requests through");
+            *phase = Phase::HalfOpen;
+        }
+        Ok(())
+    }
+
+    pub fn state(&self) -> (BreakerState, Option<Duration>) {
+        match *self.phase.lock().unwrap() {
+            Phase::Closed { .. } => (BreakerState::Closed, None),
+            Phase::HalfOpen => (BreakerState::HalfOpen, None),
+            Phase::Open { until } => (BreakerState::Open, Some(until.saturating_duration_since(Instant::now()))),
+        }
+    }
+
+    fn record_success(&self) {
+        let mut phase = self.phase.lock().unwrap();
+        match *phase {
+            Phase::Closed { ref mut consecutive_failures } => *consecutive_failures = 0,
+            Phase::HalfOpen => {
+                info!("MongoDB circuit breaker closed");
+                *phase = Phase::Closed { consecutive_failures: 0 };
+            }
+            // A slow command finishing late does not cut the open period short
+            Phase::Open { .. } => {}
+        }
+    }
+
+    fn record_failure(&self) {
+        self.failures_total.fetch_add(1, Ordering::Relaxed);
+        let mut phase = self.phase.lock().unwrap();
+        let trip = match *phase {
+            Phase::Closed { ref mut consecutive_failures } => {
+                *consecutive_failures += 1;
+                *consecutive_failures >= self.failure_threshold
+            }
+            Phase::HalfOpen => true,
+            Phase::Open { .. } => false,
+        };
+        if trip {
+            warn!("MongoDB circuit breaker open for {}s", self.open_for.as_secs());
+            self.opened_total.fetch_add(1, Ordering::Relaxed);
+            *phase = Phase::Open { until: Instant::now() + self.open_for };
+        }
+    }
+
+    // Each hung command counts once, when it is first noticed
+    fn expire_hung_commands(&self) {
+        let hung = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            let before = in_flight.len();
+            in_flight.retain(|_, started| started.elapsed() < self.call_timeout);
+            before - in_flight.len()
+        };
+        for _ in 0..hung {
+            self.record_failure();
+        }
+    }
+
+    /// Breaker state and counters in the Prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        let (state, _) = self.state();
+        let state_value = match state {
+            BreakerState::Closed => 0,
+            BreakerState::HalfOpen => 1,
+            BreakerState::Open => 2,
+        };
+
+        let mut metrics = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(metrics, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+        };
+        metric("mongo_circuit_breaker_state", "gauge", "0 = closed, 1 = half-open, 2 = open", state_value);
+        metric(
+            "mongo_circuit_breaker_failures_total",
+            "counter",
+            "Failed, hung or unreachable MongoDB calls",
+            self.failures_total.load(Ordering::Relaxed),
+        );
+        metric(
+            "mongo_circuit_breaker_rejections_total",
+            "counter",
+            "Requests answered 503 while the breaker was open",
+            self.rejections_total.load(Ordering::Relaxed),
+        );
+        metric(
+            "mongo_circuit_breaker_opened_total",
+            "counter",
+            "Times the breaker has opened",
+            self.opened_total.load(Ordering::Relaxed),
+        );
+        metrics
+    }
+}
+
+// Errors that say nothing about the database's health, like a duplicate key,
+// still show that it answered
+fn is_unavailable(kind: &ErrorKind) -> bool {
+    matches!(kind, ErrorKind::Io(_) | ErrorKind::ConnectionPoolCleared { .. } | ErrorKind::ServerSelection { .. })
+}
+
+impl CommandEventHandler for CircuitBreaker {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        self.in_flight.lock().unwrap().insert(event.request_id, Instant::now());
+    }
+
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        self.in_flight.lock().unwrap().remove(&event.request_id);
+        self.record_success();
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        self.in_flight.lock().unwrap().remove(&event.request_id);
+        if is_unavailable(&event.failure.kind) {
+            self.record_failure();
+        } else {
+            self.record_success();
+        }
+    }
+}
+
+impl SdamEventHandler for CircuitBreaker {
+    fn handle_server_heartbeat_failed_event(&self, _event: ServerHeartbeatFailedEvent) {
+        self.record_failure();
+    }
+}
+
+fn retry_after_header(retry_after: Duration) -> (&'static str, String) {
+    // Round up so clients never retry before the breaker half-opens
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    ("Retry-After", seconds.max(1).to_string())
+}
+
+/// Answers 503 with Retry-After while the breaker is open.
+pub async fn reject_when_open(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let db = req.app_data::<web::Data<MongoConfig>>().cloned();
+    if let Some(db) = db.filter(|_| !UNGUARDED_PATHS.contains(&req.path())) {
+        if let Err(retry_after) = db.breaker.check() {
+            let response = HttpResponse::ServiceUnavailable()
+                .insert_header(retry_after_header(retry_after))
+                .content_type(PROBLEM_JSON)
+                .json(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "Database temporarily unavailable"));
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadinessResponse {
+    status: &'static str,
+    database: BreakerState,
+}
+
+/// Readiness probe: ready while the breaker lets requests through and
+/// MongoDB answers a ping in time.
+pub async fn readiness(db: web::Data<MongoConfig>) -> HttpResponse {
+    if let Err(retry_after) = db.breaker.check() {
+        return HttpResponse::ServiceUnavailable()
+            .insert_header(retry_after_header(retry_after))
+            .json(ReadinessResponse { status: "unavailable", database: BreakerState::Open });
+    }
+
+    let ping = tokio::time::timeout(db.breaker.call_timeout(), db.database.run_command(doc! { "ping": 1 }, None)).await;
+    let (database, _) = db.breaker.state();
+    match ping {
+        Ok(Ok(_)) => HttpResponse::Ok().json(ReadinessResponse { status: "ready", database }),
+        _ => HttpResponse::ServiceUnavailable().json(ReadinessResponse { status: "unavailable", database }),
+    }
+}
+
+pub async fn metrics(db: web::Data<MongoConfig>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(db.breaker.render_metrics())
+}
@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, error, info, warn};
+use validator::Validate;
+
+use crate::{
+    config::MongoConfig,
+    errors::ApiError,
+    event_store::{self, ProductEvent},
+    events::{DomainEvent, EventHub},
+    models::{Bundle, BundleComponent, BundlePricing, Product, ProductStatus},
+    money::{self, Decimal},
+    public_ids,
+    validation::validation_error,
+};
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct BundleComponentRequest {
+    pub product_id: String,
+    #[validate(range(min = 1, message = "quantity must be at least 1"))]
+    pub quantity: i64,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct SetBundleRequest {
+    #[validate(length(min = 1, max = 20, message = "a bundle has between 1 and 20 components"))]
+    #[validate]
+    pub components: Vec<BundleComponentRequest>,
+    pub pricing: BundlePricing,
+    // Only with discounted pricing, e.g. 15 for 15% off the sum
+    #[serde(default, with = "money::option_price")]
+    pub discount_percent: Option<Decimal>,
+}
+
+/// A bundle component with the product it refers to.
+#[derive(Debug, Serialize)]
+pub struct ExpandedComponent {
+    pub product_id: String,
+    pub quantity: i64,
+    // Unset when the product no longer exists
+    pub name: Option<String>,
+    #[serde(with = "money::option_price")]
+    pub price: Option<Decimal>,
+    pub stock_quantity: Option<i64>,
+    // Whether the component can currently be sold
+    pub active: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BundleExpansion {
+    pub pricing: BundlePricing,
+    pub components: Vec<ExpandedComponent>,
+    // What the components cost on their own
+    #[serde(with = "money::price")]
+    pub components_total: Decimal,
+    // How many bundles the components' stock allows; null when no component is stock-tracked
+    pub available_quantity: Option<i64>,
+}
+
+fn products_collection(db: &MongoConfig) -> Collection<Product> {
+    db.database.collection("products")
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+async fn load_components(
+    db: &MongoConfig,
+    components: &[BundleComponent],
+) -> Result<HashMap<ObjectId, Product>, mongodb::error::Error> {
+    let ids: Vec<ObjectId> = components.iter().map(|component| component.product_id).collect();
+    let products: Vec<Product> = products_collection(db)
+        .find(doc! { "_id": { "$in": ids } }, None)
+        .await?
+        .try_collect()
+        .await?;
+    Ok(products.into_iter().filter_map(|product| Some((product.id?, product))).collect())
+}
+
+fn components_total(components: &[BundleComponent], products: &HashMap<ObjectId, Product>) -> Decimal {
+    components
+        .iter()
+        .filter_map(|component| {
+            products.get(&component.product_id).map(|product| product.price * Decimal::from(component.quantity))
+        })
+        .sum()
+}
+
+/// The bundle's price under its pricing mode, or None when it keeps its own (fixed).
+fn computed_price(bundle: &Bundle, total: Decimal) -> Option<Decimal> {
+    match bundle.pricing {
+        BundlePricing::Fixed => None,
+        BundlePricing::Sum => Some(total),
+        BundlePricing::Discounted => {
+            let discount = bundle.discount_percent.unwrap_or_default();
+            Some((total * (Decimal::from(100) - discount) / Decimal::from(100)).round_dp(2))
+        }
+    }
+}
+
+/// The bundle's components with their current products, and how many
+/// bundles can be assembled from stock. Missing or inactive components
+/// make the bundle unavailable.
+pub async fn expand(db: &MongoConfig, bundle: &Bundle) -> Result<BundleExpansion, Error> {
+    let products = load_components(db, &bundle.components)
+        .await
+        .map_err(|e| db_error("Failed to fetch bundle components", e))?;
+
+    let mut available_quantity: Option<i64> = None;
+    let mut components = Vec::with_capacity(bundle.components.len());
+    for component in &bundle.components {
+        let product = products.get(&component.product_id);
+        let active = product.is_some_and(|product| product.status == ProductStatus::Active);
+        let limit = match product {
+            _ if !active => Some(0),
+            Some(product) => product.stock_quantity.map(|stock| stock.max(0) / component.quantity),
+            None => Some(0),
+        };
+        if let Some(limit) = limit {
+            available_quantity = Some(available_quantity.map_or(limit, |available| available.min(limit)));
+        }
+        components.push(ExpandedComponent {
+            product_id: component.product_id.to_string(),
+            quantity: component.quantity,
+            name: product.map(|product| product.name.clone()),
+            price: product.map(|product| product.price),
+            stock_quantity: product.and_then(|product| product.stock_quantity),
+            active,
+        });
+    }
+
+    Ok(BundleExpansion {
+        pricing: bundle.pricing,
+        components,
+        components_total: components_total(&bundle.components, &products),
+        available_quantity,
+    })
+}
+
+// Brings a bundle's price in line with its components; returns whether it changed
+async fn reprice(db: &MongoConfig, bundle_product: &Product) -> Result<bool, mongodb::error::Error> {
+    let (Some(bundle_id), Some(bundle)) = (bundle_product.id, &bundle_product.bundle) else {
+        return Ok(false);
+    };
+    let products = load_components(db, &bundle.components).await?;
+    let Some(price) = computed_price(bundle, components_total(&bundle.components, &products)) else {
+        return Ok(false);
+    };
+    if price == bundle_product.price {
+        return Ok(false);
+    }
+    products_collection(db)
+        .update_one(doc! { "_id": bundle_id }, doc! { "$set": { "price": money::to_bson(price) } }, None)
+        .await?;
+    event_store::record(db, bundle_id, vec![ProductEvent::PriceChanged { price }]).await;
+    Ok(true)
+}
+
+// Bundles whose price follows from the component's
+async fn priced_bundles_containing(db: &MongoConfig, component_id: ObjectId) -> Result<Vec<Product>, mongodb::error::Error> {
+    let filter = doc! { "bundle.components.product_id": component_id, "bundle.pricing": { "$ne": "fixed" } };
+    products_collection(db).find(filter, None).await?.try_collect().await
+}
+
+/// Reprices bundles whenever one of their components changes, until the hub closes.
+pub fn spawn_repricer(events: web::Data<EventHub>, db: web::Data<MongoConfig>) {
+    let mut receiver = events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let product_id = match receiver.recv().await {
+                Ok(DomainEvent::ProductUpdated { product_id }) => product_id,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Bundle repricer lagged, dropped {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            let Ok(component_id) = ObjectId::parse_str(&product_id) else {
+                continue;
+            };
+
+            let bundles = match priced_bundles_containing(&db, component_id).await {
+                Ok(bundles) => bundles,
+                Err(e) => {
+                    warn!("Failed to load bundles containing {}: {}", product_id, e);
+                    continue;
+                }
+            };
+            for bundle in &bundles {
+                match reprice(&db, bundle).await {
+                    Ok(true) => {
+                        let bundle_id = bundle.id.map(|id| id.to_string()).unwrap_or_default();
+                        debug!("Repriced bundle {} after {} changed", bundle_id, product_id);
+                        events.publish(DomainEvent::ProductUpdated { product_id: bundle_id });
+                    }
+                    Ok(false) => {}
+                    Err(e) => warn!("Failed to reprice bundle {:?}: {}", bundle.id, e),
+                }
+            }
+        }
+    });
+}
+
+fn bad_request(message: impl Into<String>) -> ApiError {
+    ApiError::new(StatusCode::BAD_REQUEST, message.into())
+}
+
+/// Turns a product into a bundle of other products, or changes its
+/// components or pricing. Bundles can't contain bundles.
+pub async fn set_bundle(
+    db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
+    id: web::Path<String>,
+    request: web::Json<SetBundleRequest>,
+) -> Result<HttpResponse, Error> {
+    request.validate().map_err(validation_error)?;
+    let product_id = public_ids::resolve_product_id(&db, &id).await?;
+
+    match (request.pricing, request.discount_percent) {
+        (BundlePricing::Discounted, Some(discount)) if discount > Decimal::ZERO && discount < Decimal::from(100) => {}
+        (BundlePricing::Discounted, _) => {
+            return Err(bad_request("discounted pricing needs a discount_percent between 0 and 100").into())
+        }
+        (_, Some(_)) => return Err(bad_request("discount_percent only applies to discounted pricing").into()),
+        (_, None) => {}
+    }
+
+    let mut components = Vec::with_capacity(request.components.len());
+    for component in &request.components {
+        let component_id = public_ids::resolve_product_id(&db, &component.product_id).await?;
+        if component_id == product_id {
+            return Err(bad_request("A bundle can't contain itself").into());
+        }
+        if components.iter().any(|existing: &BundleComponent| existing.product_id == component_id) {
+            return Err(bad_request(format!("Component {} is listed twice", component.product_id)).into());
+        }
+        components.push(BundleComponent { product_id: component_id, quantity: component.quantity });
+    }
+
+    let products = products_collection(&db);
+    let exists = products
+        .count_documents(doc! { "_id": product_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch product", e))?
+        > 0;
+    if !exists {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    }
+    let is_component = products
+        .count_documents(doc! { "bundle.components.product_id": product_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to check bundles", e))?
+        > 0;
+    if is_component {
+        return Err(bad_request("The product is part of another bundle, so it can't be a bundle itself").into());
+    }
+
+    let found = load_components(&db, &components)
+        .await
+        .map_err(|e| db_error("Failed to fetch bundle components", e))?;
+    if let Some(missing) = components.iter().find(|component| !found.contains_key(&component.product_id)) {
+        return Err(bad_request(format!("Component {} does not exist", missing.product_id)).into());
+    }
+    if let Some(nested) = components.iter().find(|component| found[&component.product_id].bundle.is_some()) {
+        return Err(bad_request(format!("Component {} is a bundle itself", nested.product_id)).into());
+    }
+
+    let bundle = Bundle { components, pricing: request.pricing, discount_percent: request.discount_percent };
+    let mut set = doc! {
+        "bundle": mongodb::bson::to_bson(&bundle).map_err(ApiError::internal)?,
+    };
+    if let Some(price) = computed_price(&bundle, components_total(&bundle.components, &found)) {
+        set.insert("price", money::to_bson(price));
+    }
+    let changes = ProductEvent::for_update(&set);
+    // A bundle's stock comes from its components
+    let update = doc! { "$set": set, "$unset": { "stock_quantity": "", "low_stock_threshold": "" } };
+    products
+        .update_one(doc! { "_id": product_id }, update, None)
+        .await
+        .map_err(|e| db_error("Failed to save bundle", e))?;
+
+    info!("Product {} is now a bundle of {} products", product_id, bundle.components.len());
+    event_store::record(&db, product_id, changes).await;
+    events.publish(DomainEvent::ProductUpdated { product_id: product_id.to_string() });
+    Ok(HttpResponse::Ok().json(expand(&db, &bundle).await?))
+}
+
+/// Makes a bundle a regular product again; it keeps its current price.
+pub async fn remove_bundle(
+    db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let product_id = public_ids::resolve_product_id(&db, &id).await?;
+    let result = products_collection(&db)
+        .update_one(
+            doc! { "_id": product_id, "bundle": { "$exists": true } },
+            doc! { "$unset": { "bundle": "" } },
+            None,
+        )
+        .await
+        .map_err(|e| db_error("Failed to remove bundle", e))?;
+
+    if result.matched_count == 0 {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    }
+    info!("Product {} is no longer a bundle", product_id);
+    event_store::record(&db, product_id, vec![ProductEvent::ProductUpdated { fields: doc! { "bundle": null } }]).await;
+    events.publish(DomainEvent::ProductUpdated { product_id: product_id.to_string() });
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,196 @@
+use std::{io, sync::Arc, time::Duration};
+
+use futures::future::BoxFuture;
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{
+    config::CaptchaConfig,
+    rate_limit::{self, CounterStore, MemoryCounters},
+};
+
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(5);
+const HCAPTCHA_URL: &str = "https://api.hcaptcha.com/siteverify";
+const RECAPTCHA_URL: &str = "https://www.google.com/recaptcha/api/siteverify";
+
+/// Checks the token a CAPTCHA widget handed the client.
+pub trait CaptchaVerifier: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Whether the provider accepts `token`, solved from address `ip`.
+    fn verify<'a>(&'a self, token: &'a str, ip: &'a str) -> BoxFuture<'a, Result<bool, String>>;
+}
+
+// hCaptcha and reCAPTCHA share the siteverify protocol: a form with the
+// secret, the token and the client's address, answered with "success"
+struct SiteVerify {
+    http: reqwest::Client,
+    url: String,
+    secret: String,
+}
+
+#[derive(Deserialize)]
+struct SiteVerifyResponse {
+    success: bool,
+    #[serde(rename = "error-codes", default)]
+    error_codes: Vec<String>,
+}
+
+impl SiteVerify {
+    fn new(url: String, secret: String) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(VERIFY_TIMEOUT)
+            .build()
+            .expect("Failed to build HTTP client");
+        SiteVerify { http, url, secret }
+    }
+
+    async fn verify(&self, token: &str, ip: &str) -> Result<bool, String> {
+        let response: SiteVerifyResponse = self
+            .http
+            .post(&self.url)
+            .form(&[("secret", self.secret.as_str()), ("response", token), ("remoteip", ip)])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+        // A rejected secret is a configuration error, not a wrong answer
+        if response.error_codes.iter().any(|code| code.starts_with("invalid-input-secret") || code == "missing-input-secret") {
+            return Err(format!("secret rejected: {}", response.error_codes.join(", ")));
+        }
+        Ok(response.success)
+    }
+}
+
+pub struct HCaptcha(SiteVerify);
+
+impl CaptchaVerifier for HCaptcha {
+    fn name(&self) -> &'static str {
+        "hCaptcha"
+    }
+
+    fn verify<'a>(&'a self, token: &'a str, ip: &'a str) -> BoxFuture<'a, Result<bool, String>> {
+        Box::pin(self.0.verify(token, ip))
+    }
+}
+
+pub struct ReCaptcha(SiteVerify);
+
+impl CaptchaVerifier for ReCaptcha {
+    fn name(&self) -> &'static str {
+        "reCAPTCHA"
+    }
+
+    fn verify<'a>(&'a self, token: &'a str, ip: &'a str) -> BoxFuture<'a, Result<bool, String>> {
+        Box::pin(self.0.verify(token, ip))
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Counts failed password logins per client address and asks for a CAPTCHA
+/// once an address has failed too often in the current window.
+pub struct LoginThrottle {
+    after_failures: u64,
+    window: Duration,
+    store: Arc<dyn CounterStore>,
+    verifier: Arc<dyn CaptchaVerifier>,
+}
+
+impl LoginThrottle {
+    /// None when CAPTCHA_PROVIDER is off. Errors on an unknown provider or a
+    /// missing secret, so a login meant to be protected never runs without.
+    pub fn from_config(config: CaptchaConfig) -> io::Result<Option<Self>> {
+        let verifier: Arc<dyn CaptchaVerifier> = match config.provider.as_str() {
+            "off" => return Ok(None),
+            provider @ ("hcaptcha" | "recaptcha") => {
+                let secret = config
+                    .secret
+                    .clone()
+                    .ok_or_else(|| invalid(format!("CAPTCHA_PROVIDER={} needs CAPTCHA_SECRET", provider)))?;
+                if provider == "hcaptcha" {
+                    let url = config.verify_url.clone().unwrap_or_else(|| HCAPTCHA_URL.to_string());
+                    Arc::new(HCaptcha(SiteVerify::new(url, secret)))
+                } else {
+                    let url = config.verify_url.clone().unwrap_or_else(|| RECAPTCHA_URL.to_string());
+                    Arc::new(ReCaptcha(SiteVerify::new(url, secret)))
+                }
+            }
+            other => {
+                return Err(invalid(format!(
+                    "Unknown CAPTCHA_PROVIDER '{}': expected off, hcaptcha or recaptcha",
+                    other
+                )))
+            }
+        };
+
+        let store: Arc<dyn CounterStore> = match config.redis_url.as_deref().map(rate_limit::connect) {
+            Some(Ok(store)) => store,
+            Some(Err(e)) => {
+                warn!("Failed logins are counted per instance, not in RATE_LIMIT_REDIS_URL: {}", e);
+                Arc::new(MemoryCounters::default())
+            }
+            None => Arc::new(MemoryCounters::default()),
+        };
+        info!(
+            "Asking for a {} CAPTCHA after {} failed logins per {}s from an address, counted in {}",
+            verifier.name(),
+            config.after_failures,
+            config.window_secs,
+            store.name()
+        );
+        Ok(Some(LoginThrottle {
+            after_failures: config.after_failures,
+            window: Duration::from_secs(config.window_secs.max(1)),
+            store,
+            verifier,
+        }))
+    }
+
+    fn key(&self, ip: &str) -> (String, Duration) {
+        let (window, reset) = rate_limit::current_window(self.window);
+        (format!("login_failures:{}:{}", ip, window), reset)
+    }
+
+    /// Whether logins from `ip` need a solved CAPTCHA. Like rate limits, a
+    /// failing counter store lets logins through.
+    pub async fn captcha_required(&self, ip: &str) -> bool {
+        let (key, _) = self.key(ip);
+        match self.store.get(&key).await {
+            Ok(failures) => failures >= self.after_failures,
+            Err(e) => {
+                warn!("Failed to read failed logins from {}: {}", self.store.name(), e);
+                false
+            }
+        }
+    }
+
+    /// Counts a failed login from `ip`; true once the next one needs a CAPTCHA.
+    pub async fn record_failure(&self, ip: &str) -> bool {
+        let (key, reset) = self.key(ip);
+        match self.store.increment(&key, reset).await {
+            Ok(failures) => failures >= self.after_failures,
+            Err(e) => {
+                warn!("Failed to count failed login in {}: {}", self.store.name(), e);
+                false
+            }
+        }
+    }
+
+    /// Whether `token` solves the CAPTCHA. An outage of the provider
+    /// shouldn't lock everyone out, so it lets the login through.
+    pub async fn verify(&self, token: &str, ip: &str) -> bool {
+        match self.verifier.verify(token, ip).await {
+            Ok(solved) => solved,
+            Err(e) => {
+                warn!("{} verification failed, allowing the login: {}", self.verifier.name(), e);
+                true
+            }
+        }
+    }
+}
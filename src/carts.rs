@@ -0,0 +1,325 @@
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime},
+    options::ReplaceOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+use validator::Validate;
+
+use crate::{
+    auth::Claims,
+    config::{MongoConfig, TaxConfig},
+    errors::ApiError,
+    models::{PriceTier, Product, TaxClass},
+    money::{self, Decimal},
+    pricing, reservations,
+    tax::{self, PriceBreakdown, TaxQuery, TaxTable, TaxTotals},
+    validation::validation_error,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CartItem {
+    pub product_id: ObjectId,
+    pub name: String,
+    pub quantity: i64,
+    #[serde(with = "money::price")]
+    pub unit_price: Decimal, // Price when the item was first added
+    // Quantity discounts when the item was first added
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub price_tiers: Vec<PriceTier>,
+    #[serde(default)]
+    pub tax_class: TaxClass,
+    pub added_at: DateTime,
+}
+
+impl CartItem {
+    /// Unit price at the current quantity, with quantity discounts applied.
+    pub fn effective_unit_price(&self) -> Decimal {
+        pricing::unit_price(self.unit_price, &self.price_tiers, self.quantity)
+    }
+
+    pub fn line_total(&self) -> Decimal {
+        self.effective_unit_price() * Decimal::from(self.quantity)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Cart {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    pub items: Vec<CartItem>,
+    pub updated_at: DateTime,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct AddCartItemRequest {
+    pub product_id: String,
+    #[validate(range(min = 1))]
+    pub quantity: i64,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateCartItemRequest {
+    #[validate(range(min = 1))]
+    pub quantity: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CartItemResponse {
+    pub product_id: String,
+    pub name: String,
+    pub quantity: i64,
+    #[serde(with = "money::price")]
+    pub unit_price: Decimal,
+    #[serde(with = "money::price")]
+    pub line_total: Decimal,
+    // Breakdown of line_total
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price_breakdown: Option<PriceBreakdown>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CartResponse {
+    pub items: Vec<CartItemResponse>,
+    #[serde(with = "money::price")]
+    pub subtotal: Decimal,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tax: Option<TaxTotals>,
+}
+
+impl CartResponse {
+    /// The cart with its totals; with a tax table, every line and the cart
+    /// as a whole also get a net/tax/gross breakdown.
+    pub fn new(cart: &Cart, tax: Option<&TaxTable>) -> Self {
+        let items: Vec<CartItemResponse> = cart
+            .items
+            .iter()
+            .map(|item| CartItemResponse {
+                product_id: item.product_id.to_string(),
+                name: item.name.clone(),
+                quantity: item.quantity,
+                unit_price: item.effective_unit_price(),
+                line_total: item.line_total(),
+                price_breakdown: tax.map(|tax| tax.breakdown(item.line_total(), item.tax_class)),
+            })
+            .collect();
+        let subtotal = items.iter().map(|item| item.line_total).sum();
+        let tax = tax.map(|_| {
+            let mut totals = TaxTotals::default();
+            items.iter().filter_map(|item| item.price_breakdown.as_ref()).for_each(|b| totals.add(b));
+            totals
+        });
+        CartResponse { items, subtotal, tax }
+    }
+}
+
+pub fn carts_collection(db: &MongoConfig) -> Collection<Cart> {
+    db.database.collection("carts")
+}
+
+fn parse_product_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| {
+        error!("Invalid product ID format: {}", id);
+        ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format").into()
+    })
+}
+
+async fn load_cart(db: &MongoConfig, user_id: &ObjectId) -> Result<Cart, Error> {
+    let cart = carts_collection(db)
+        .find_one(doc! { "user_id": user_id }, None)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch cart for user {}: {}", user_id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+
+    Ok(cart.unwrap_or_else(|| Cart {
+        id: None,
+        user_id: *user_id,
+        items: Vec::new(),
+        updated_at: DateTime::now(),
+    }))
+}
+
+async fn save_cart(db: &MongoConfig, cart: &mut Cart) -> Result<(), Error> {
+    cart.updated_at = DateTime::now();
+    let options = ReplaceOptions::builder().upsert(true).build();
+    carts_collection(db)
+        .replace_one(doc! { "user_id": cart.user_id }, &*cart, options)
+        .await
+        .map_err(|e| {
+            error!("Failed to save cart for user {}: {}", cart.user_id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+    Ok(())
+}
+
+async fn find_product(db: &MongoConfig, product_id: &ObjectId) -> Result<Option<Product>, Error> {
+    let products: Collection<Product> = db.transactional_collection("products");
+    products
+        .find_one(doc! { "_id": product_id }, None)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch product {}: {}", product_id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+        })
+}
+
+async fn cart_response(db: &MongoConfig, config: &TaxConfig, query: &TaxQuery, cart: &Cart) -> Result<HttpResponse, Error> {
+    let tax = tax::load_table(db, config, query.region.as_deref()).await?;
+    Ok(HttpResponse::Ok().json(CartResponse::new(cart, tax.as_ref())))
+}
+
+// Stock the user can still put in their cart: units others have reserved are
+// not for sale. None for products that do not track stock.
+async fn available_stock(db: &MongoConfig, product: &Product, user_id: ObjectId) -> Result<Option<i64>, Error> {
+    let (Some(stock), Some(product_id)) = (product.stock_quantity, product.id) else {
+        return Ok(None);
+    };
+    let reserved = reservations::reserved_by_others(db, product_id, user_id).await?;
+    Ok(Some((stock - reserved).max(0)))
+}
+
+fn insufficient_stock(product: &Product, available: i64) -> ApiError {
+    ApiError::new(StatusCode::CONFLICT, format!("Insufficient stock for {}", product.name))
+        .with("available", available)
+}
+
+pub async fn get_cart(
+    db: web::Data<MongoConfig>,
+    tax_config: web::Data<TaxConfig>,
+    query: web::Query<TaxQuery>,
+    claims: web::ReqData<Claims>,
+) -> Result<HttpResponse, Error> {
+    let cart = load_cart(&db, &claims.user_id()?).await?;
+    cart_response(&db, &tax_config, &query, &cart).await
+}
+
+pub async fn add_cart_item(
+    db: web::Data<MongoConfig>,
+    tax_config: web::Data<TaxConfig>,
+    query: web::Query<TaxQuery>,
+    claims: web::ReqData<Claims>,
+    item: web::Json<AddCartItemRequest>,
+) -> Result<HttpResponse, Error> {
+    if let Err(errors) = item.validate() {
+        return Err(validation_error(errors));
+    }
+
+    let user_id = claims.user_id()?;
+    let product_id = parse_product_id(&item.product_id)?;
+
+    let product = match find_product(&db, &product_id).await? {
+        Some(product) => product,
+        None => {
+            debug!("Product not found for cart: {}", product_id);
+            return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+        }
+    };
+
+    let mut cart = load_cart(&db, &user_id).await?;
+    let existing = cart.items.iter().position(|i| i.product_id == product_id);
+    let quantity = existing.map(|idx| cart.items[idx].quantity).unwrap_or(0) + item.quantity;
+
+    if let Some(available) = available_stock(&db, &product, user_id).await? {
+        if quantity > available {
+            return Err(insufficient_stock(&product, available).into());
+        }
+    }
+
+    match existing {
+        Some(idx) => cart.items[idx].quantity = quantity,
+        None => cart.items.push(CartItem {
+            product_id,
+            name: product.name.clone(),
+            quantity,
+            unit_price: product.price,
+            price_tiers: product.price_tiers.clone(),
+            tax_class: product.tax_class,
+            added_at: DateTime::now(),
+        }),
+    }
+
+    save_cart(&db, &mut cart).await?;
+    info!("User {} added {} x {} to cart", user_id, item.quantity, product_id);
+    cart_response(&db, &tax_config, &query, &cart).await
+}
+
+pub async fn update_cart_item(
+    db: web::Data<MongoConfig>,
+    tax_config: web::Data<TaxConfig>,
+    query: web::Query<TaxQuery>,
+    claims: web::ReqData<Claims>,
+    product_id: web::Path<String>,
+    update: web::Json<UpdateCartItemRequest>,
+) -> Result<HttpResponse, Error> {
+    if let Err(errors) = update.validate() {
+        return Err(validation_error(errors));
+    }
+
+    let user_id = claims.user_id()?;
+    let product_id = parse_product_id(&product_id)?;
+
+    let mut cart = load_cart(&db, &user_id).await?;
+    let idx = match cart.items.iter().position(|i| i.product_id == product_id) {
+        Some(idx) => idx,
+        None => return Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    };
+
+    if let Some(product) = find_product(&db, &product_id).await? {
+        if let Some(available) = available_stock(&db, &product, user_id).await? {
+            if update.quantity > available {
+                return Err(insufficient_stock(&product, available).into());
+            }
+        }
+    }
+
+    cart.items[idx].quantity = update.quantity;
+    save_cart(&db, &mut cart).await?;
+    cart_response(&db, &tax_config, &query, &cart).await
+}
+
+pub async fn remove_cart_item(
+    db: web::Data<MongoConfig>,
+    tax_config: web::Data<TaxConfig>,
+    query: web::Query<TaxQuery>,
+    claims: web::ReqData<Claims>,
+    product_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let user_id = claims.user_id()?;
+    let product_id = parse_product_id(&product_id)?;
+
+    let mut cart = load_cart(&db, &user_id).await?;
+    let before = cart.items.len();
+    cart.items.retain(|i| i.product_id != product_id);
+
+    if cart.items.len() == before {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    }
+
+    save_cart(&db, &mut cart).await?;
+    cart_response(&db, &tax_config, &query, &cart).await
+}
+
+pub async fn clear_cart(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+) -> Result<HttpResponse, Error> {
+    let user_id = claims.user_id()?;
+
+    carts_collection(&db)
+        .delete_one(doc! { "user_id": user_id }, None)
+        .await
+        .map_err(|e| {
+            error!("Failed to clear cart for user {}: {}", user_id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+
+    info!("Cleared cart for user {}", user_id);
+    Ok(HttpResponse::NoContent().finish())
+}
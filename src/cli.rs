@@ -0,0 +1,508 @@
+use std::{error::Error, fs::File, io, path::PathBuf, time::Duration};
+
+use clap::{Parser, Subcommand};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::IndexOptions,
+    Collection, IndexModel,
+};
+use tracing::{info, warn};
+use validator::Validate;
+
+use crate::{
+    auth::{self, default_scopes, RegisterRequest, User, SCOPE_ADMIN, SCOPE_PRICES_APPROVE},
+    config::{AppConfig, MailConfig, MongoConfig, PasswordPolicyConfig, SearchEngineConfig},
+    doctor, event_store,
+    events::EventHub,
+    exports,
+    handlers::import_records,
+    import_batches::BatchLimits,
+    import_formats::FileFormat,
+    import_rules::{DecimalSeparator, ImportRules, PriceFormat},
+    import_history::{ImportLog, ImportOrigin},
+    mail::{self, EmailTemplate},
+    money,
+    password::hash_password,
+    password_policy::{self, PasswordPolicy},
+    pii, public_ids, search,
+    search_engine::{self, SyncMode},
+    seed, slugs,
+    validation_webhook::ValidationWebhook,
+};
+
+type CliResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+#[derive(Debug, Parser)]
+#[command(about = "Products JSON API server and admin tools")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the HTTP and gRPC servers (the default)
+    Serve,
+    /// Create an admin user, or grant admin scope to an existing one
+    CreateAdminUser {
+        #[arg(long)]
+        email: String,
+        #[arg(long, env = "ADMIN_PASSWORD", hide_env_values = true)]
+        password: String,
+        #[arg(long, default_value = "Admin")]
+        first_name: String,
+        #[arg(long, default_value = "User")]
+        last_name: String,
+    },
+    /// Import products from a CSV, TSV, XLSX or JSON file, by its extension
+    ImportCsv {
+        file: PathBuf,
+        /// How prices write decimals; guessed per price when omitted
+        #[arg(long, value_enum)]
+        decimal_separator: Option<DecimalSeparator>,
+        /// ISO 4217 code of the prices; prices marked with another currency are rejected
+        #[arg(long)]
+        currency: Option<String>,
+    },
+    /// Export products as CSV to a file or stdout
+    ExportCsv {
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Include draft and archived products
+        #[arg(long)]
+        all: bool,
+    },
+    /// Create indexes and backfill fields; safe to run repeatedly
+    Migrate,
+    /// Check the database, indexes, JWT secrets, export storage and SMTP
+    /// and print a pass/fail table; exits non-zero when a check fails
+    Doctor,
+    /// Encrypt the names and emails of users stored before encryption
+    EncryptUsers,
+    /// Convert floating point prices to Decimal128
+    MigratePrices {
+        /// Only count the documents that would change
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Record the current state of products that have no event history yet
+    SnapshotEvents,
+    /// Rebuild products from their event history
+    ReplayEvents {
+        /// Only report what would change
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Send every product to the search engine again and drop documents of deleted products
+    ReindexSearch,
+    /// Compare the search engine with the database and report drift
+    CheckSearchIndex {
+        /// Also fix the differences found
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Fill the database with fake products and a demo user (requires ALLOW_SEED=true)
+    Seed {
+        #[arg(long, default_value_t = 100)]
+        products: usize,
+        #[arg(long, default_value = "demo@example.com")]
+        demo_email: String,
+        #[arg(long, default_value = "demo-password")]
+        demo_password: String,
+    },
+    /// Build the breached password filter for PASSWORD_BREACH_CHECK=bloom from
+    /// a list of passwords or SHA-1 hashes, one per line
+    BuildPasswordFilter {
+        input: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+        #[arg(long, default_value_t = 0.001)]
+        false_positive_rate: f64,
+    },
+    /// Send an email template filled with sample values, to check MAIL_URL
+    SendTestEmail {
+        to: String,
+        #[arg(long, value_enum, default_value_t = EmailTemplate::ImportCompleted)]
+        template: EmailTemplate,
+    },
+}
+
+/// Runs an admin subcommand against the configured database.
+pub async fn run(command: Command, config: AppConfig) -> CliResult {
+    if matches!(command, Command::Seed { .. }) && !config.allow_seed {
+        return Err("Seeding is disabled; set ALLOW_SEED=true in development environments".into());
+    }
+
+    // Commands that don't touch the database, or connect on their own
+    if let Command::Doctor = command {
+        return Ok(doctor::run(&config).await?);
+    }
+    if let Command::SendTestEmail { to, template } = command {
+        return send_test_email(&to, template, config.mail).await;
+    }
+    if let Command::BuildPasswordFilter { input, output, false_positive_rate } = command {
+        let count = password_policy::build_bloom_filter(&input, &output, false_positive_rate)?;
+        info!("Added {} entries to {}", count, output.display());
+        return Ok(());
+    }
+
+    let db = MongoConfig::init(&config.mongo).await?;
+
+    match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::CreateAdminUser { email, password, first_name, last_name } => {
+            let request = RegisterRequest { email, first_name, last_name, password, invite_code: None };
+            create_admin_user(&db, request, config.password_policy).await
+        }
+        Command::ImportCsv { file, decimal_separator, currency } => {
+            let prices = PriceFormat { decimal_separator, currency };
+            prices.check()?;
+            let batching = BatchLimits::new(&config.import);
+            let webhook = ValidationWebhook::from_config(config.validation_webhook)?;
+            import_csv(&db, &batching, file, ImportRules { prices, ..ImportRules::default() }, webhook.as_ref()).await
+        }
+        Command::ExportCsv { output, all } => export_csv(&db, output, all).await,
+        Command::Migrate => migrate(&db).await,
+        Command::EncryptUsers => {
+            let encrypted = pii::encrypt_users(&db).await?;
+            info!("Encrypted personal fields of {} users", encrypted);
+            Ok(())
+        }
+        Command::MigratePrices { dry_run } => migrate_prices(&db, dry_run).await,
+        Command::SnapshotEvents => {
+            let recorded = event_store::snapshot_products(&db).await?;
+            info!("Recorded snapshots of {} products without history", recorded);
+            Ok(())
+        }
+        Command::ReplayEvents { dry_run } => replay_events(&db, dry_run).await,
+        Command::ReindexSearch => sync_search_index(&db, &config.search_engine, SyncMode::Rebuild).await,
+        Command::CheckSearchIndex { repair } => {
+            let mode = if repair { SyncMode::Repair } else { SyncMode::Check };
+            sync_search_index(&db, &config.search_engine, mode).await
+        }
+        Command::Seed { products, demo_email, demo_password } => {
+            seed::seed(&db, products, &demo_email, &demo_password).await
+        }
+        Command::SendTestEmail { .. } | Command::BuildPasswordFilter { .. } | Command::Doctor => {
+            unreachable!("handled before connecting")
+        }
+    }
+}
+
+async fn sync_search_index(db: &MongoConfig, engine_config: &SearchEngineConfig, mode: SyncMode) -> CliResult {
+    let engine = search_engine::from_config(engine_config).ok_or("SEARCH_ENGINE_URL is not set")?;
+    let report = search_engine::sync(db, engine.as_ref(), mode).await?;
+    info!("{}", report.summary());
+    Ok(())
+}
+
+async fn send_test_email(to: &str, template: EmailTemplate, mail_config: MailConfig) -> CliResult {
+    let mailer = mail::from_config(mail_config);
+    let email = template.render(to, &[
+        ("first_name", "Ada"),
+        ("link", "https://example.com/test-link"),
+        ("expires_in", "24 hours"),
+        ("filename", "products.csv"),
+        ("imported", "42"),
+        ("rejected", "0"),
+    ])?;
+    mailer.send(&email).await?;
+    info!("Sent {:?} test email to {} via {}", template, to, mailer.name());
+    Ok(())
+}
+
+async fn create_admin_user(db: &MongoConfig, mut request: RegisterRequest, policy: PasswordPolicyConfig) -> CliResult {
+    request.email = pii::normalize_email(&request.email);
+    request.validate()?;
+    PasswordPolicy::new(policy)?
+        .check("password", &request.password)
+        .await?;
+
+    let collection: Collection<User> = db.database.collection("users");
+
+    let mut scopes = default_scopes();
+    scopes.push(SCOPE_PRICES_APPROVE.to_string());
+    scopes.push(SCOPE_ADMIN.to_string());
+
+    if let Some(existing) = collection.find_one(doc! { "email": pii::email_filter(&request.email) }, None).await? {
+        collection
+            .update_one(
+                doc! { "_id": existing.id },
+                doc! { "$addToSet": { "scopes": { "$each": &scopes } } },
+                None,
+            )
+            .await?;
+        info!("Granted admin scope to existing user {}", request.email);
+        return Ok(());
+    }
+
+    let user = User {
+        id: None,
+        email: request.email.clone(),
+        first_name: request.first_name,
+        last_name: request.last_name,
+        password_hash: hash_password(&request.password).map_err(|e| e.to_string())?,
+        scopes,
+        identities: Vec::new(),
+        two_factor: None,
+    };
+
+    let result = collection.insert_one(&user, None).await?;
+    info!("Created admin user {} with ID: {}", request.email, result.inserted_id);
+    Ok(())
+}
+
+async fn import_csv(
+    db: &MongoConfig,
+    batching: &BatchLimits,
+    path: PathBuf,
+    rules: ImportRules,
+    webhook: Option<&ValidationWebhook>,
+) -> CliResult {
+    let file = File::open(&path)?;
+
+    // Nobody subscribes from the CLI; events only matter to a running server
+    let events = EventHub::default();
+    let import = ImportLog::begin(ImportOrigin::Cli, None)
+        .filename(path.file_name().map(|name| name.to_string_lossy().into_owned()));
+    let format = path
+        .to_str()
+        .and_then(FileFormat::from_file_name)
+        .unwrap_or(FileFormat::Csv);
+    let (imported, errors) = import_records(db, &events, batching, import.id, format, &rules, webhook, file).await;
+    import.finish(db, imported, errors.len()).await;
+
+    info!("Imported {} products from {}", imported, path.display());
+    for error in &errors {
+        warn!("Rejected row: {}", error);
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} rows were rejected", errors.len()).into())
+    }
+}
+
+async fn export_csv(db: &MongoConfig, output: Option<PathBuf>, all: bool) -> CliResult {
+    let writer: Box<dyn io::Write> = match &output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    let count = exports::write_products_csv(db, all, writer).await?;
+
+    info!("Exported {} products", count);
+    Ok(())
+}
+
+// Reservations, unapplied import diffs and 2FA challenge attempts are removed
+// once they expire; the server also ensures the challenge index at startup
+const EXPIRING_COLLECTIONS: [&str; 3] = ["reservations", "import_diffs", "two_factor_challenges"];
+
+// Optional product fields that are unique where set
+const SPARSE_UNIQUE_FIELDS: [&str; 4] = ["barcode", "slug", "public_id", "sku"];
+
+// Collection, keys and whether the index is unique
+fn indexes() -> [(&'static str, Document, bool); 45] {
+    [
+        ("products", doc! { "name": 1 }, false),
+        ("products", doc! { "view_count": -1 }, false),
+        ("product_views", doc! { "product_id": 1, "day": 1 }, true),
+        ("product_views", doc! { "day": 1 }, false),
+        ("search_queries", doc! { "query": 1, "day": 1 }, true),
+        ("search_queries", doc! { "day": 1 }, false),
+        ("products", doc! { "status": 1 }, false),
+        ("products", doc! { "category": 1 }, false),
+        ("sessions", doc! { "user_id": 1 }, false),
+        ("favorites", doc! { "user_id": 1, "product_id": 1 }, true),
+        ("carts", doc! { "user_id": 1 }, true),
+        ("orders", doc! { "user_id": 1, "created_at": -1 }, false),
+        ("import_sources", doc! { "enabled": 1, "next_run_at": 1 }, false),
+        ("import_runs", doc! { "source_id": 1, "started_at": -1 }, false),
+        ("saved_filters", doc! { "owner_id": 1 }, false),
+        ("saved_filters", doc! { "shared_with": 1 }, false),
+        ("products_trash", doc! { "deleted_at": -1 }, false),
+        ("product_events", doc! { "product_id": 1, "_id": 1 }, false),
+        ("product_events", doc! { "occurred_at": 1 }, false),
+        ("tax_rates", doc! { "region": 1, "tax_class": 1 }, true),
+        ("products", doc! { "supplier_id": 1 }, false),
+        ("products", doc! { "import_id": 1 }, false),
+        ("products", doc! { "previous_slugs": 1 }, false),
+        ("products", doc! { "search_grams": 1 }, false),
+        ("products", doc! { "bundle.components.product_id": 1 }, false),
+        ("product_relationships", doc! { "source_id": 1, "target_id": 1, "kind": 1 }, true),
+        ("product_relationships", doc! { "target_id": 1 }, false),
+        ("purchase_orders", doc! { "supplier_id": 1, "created_at": -1 }, false),
+        ("purchase_orders", doc! { "status": 1, "created_at": -1 }, false),
+        ("locations", doc! { "code": 1 }, true),
+        ("stock_levels", doc! { "product_id": 1, "location_id": 1 }, true),
+        ("stock_levels", doc! { "location_id": 1 }, false),
+        ("reservations", doc! { "product_id": 1, "user_id": 1 }, true),
+        ("imports", doc! { "started_at": -1 }, false),
+        ("imports", doc! { "user_id": 1, "started_at": -1 }, false),
+        ("auth_events", doc! { "created_at": -1 }, false),
+        ("auth_events", doc! { "user_id": 1, "created_at": -1 }, false),
+        ("auth_events", doc! { "email": 1, "created_at": -1 }, false),
+        ("notifications", doc! { "user_id": 1, "created_at": -1 }, false),
+        ("export_jobs", doc! { "user_id": 1 }, false),
+        ("export_jobs", doc! { "expires_at": 1 }, false),
+        ("price_change_requests", doc! { "status": 1, "created_at": -1 }, false),
+        ("price_change_requests", doc! { "product_id": 1, "status": 1 }, false),
+        ("invites", doc! { "code_hash": 1 }, true),
+        ("invites", doc! { "email": 1, "accepted_at": 1 }, false),
+    ]
+}
+
+/// Every index `migrate` creates, as collection, keys and whether it is unique.
+pub(crate) fn required_indexes() -> Vec<(&'static str, Document, bool)> {
+    let mut required = indexes().to_vec();
+    required.extend(EXPIRING_COLLECTIONS.map(|collection_name| (collection_name, doc! { "expires_at": 1 }, false)));
+    required.extend(SPARSE_UNIQUE_FIELDS.map(|field| ("products", doc! { field: 1 }, true)));
+    required.push(("users", doc! { "email": 1 }, true));
+    required
+}
+
+async fn migrate(db: &MongoConfig) -> CliResult {
+    for (collection_name, keys, unique) in indexes() {
+        let collection: Collection<Document> = db.database.collection(collection_name);
+        let index = IndexModel::builder()
+            .keys(keys)
+            .options(IndexOptions::builder().unique(unique).build())
+            .build();
+        let result = collection.create_index(index, None).await?;
+        info!("Ensured index {} on {}", result.index_name, collection_name);
+    }
+
+    for collection_name in EXPIRING_COLLECTIONS {
+        let collection: Collection<Document> = db.database.collection(collection_name);
+        let index = IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(IndexOptions::builder().expire_after(Duration::from_secs(0)).build())
+            .build();
+        let result = collection.create_index(index, None).await?;
+        info!("Ensured index {} on {}", result.index_name, collection_name);
+    }
+
+    // Products created before slugs, public IDs and fuzzy search existed get them
+    let backfilled = slugs::backfill(db).await?;
+    info!("Backfilled slugs on {} products", backfilled);
+    let backfilled = public_ids::backfill(db).await?;
+    info!("Backfilled public IDs on {} products", backfilled);
+    let backfilled = search::backfill_grams(db).await?;
+    info!("Backfilled search trigrams on {} products", backfilled);
+
+    // Barcodes and SKUs are optional, so only products that have one are in the
+    // unique index; slugs and public IDs too, for products written by an older version
+    let products: Collection<Document> = db.database.collection("products");
+    for field in SPARSE_UNIQUE_FIELDS {
+        let index = IndexModel::builder()
+            .keys(doc! { field: 1 })
+            .options(IndexOptions::builder().unique(true).sparse(true).build())
+            .build();
+        let result = products.create_index(index, None).await?;
+        info!("Ensured index {} on products", result.index_name);
+    }
+
+    // Emails stored before they were normalized, which register and login no longer find
+    let normalized = pii::normalize_user_emails(db).await?;
+    info!("Normalized the emails of {} users", normalized);
+
+    // The server ensures this one at startup too; duplicate emails make it fail
+    let index = auth::ensure_email_index(db).await?;
+    info!("Ensured index {} on users", index);
+
+    // Products created before the lifecycle was introduced are active
+    let result = products
+        .update_many(
+            doc! { "status": { "$exists": false } },
+            doc! { "$set": { "status": "active" } },
+            None,
+        )
+        .await?;
+    info!("Backfilled status on {} products", result.modified_count);
+
+    Ok(())
+}
+
+// Money fields written as doubles before prices were stored as Decimal128
+const PRICE_FIELDS: [(&str, &[&str]); 5] = [
+    ("products", &["price"]),
+    ("products_trash", &["product.price"]),
+    ("carts", &["items.unit_price"]),
+    ("orders", &["total", "items.unit_price", "items.line_total"]),
+    ("product_events", &["event.price", "event.product.price"]),
+];
+
+// Converts the numeric value at a dotted path, descending into arrays.
+// Returns whether anything changed.
+fn decimalize(document: &mut Document, path: &[&str]) -> bool {
+    let Some((field, rest)) = path.split_first() else {
+        return false;
+    };
+    match document.get_mut(*field) {
+        Some(value @ (Bson::Double(_) | Bson::Int32(_) | Bson::Int64(_))) if rest.is_empty() => {
+            match money::from_bson(value) {
+                Some(price) => {
+                    *value = money::to_bson(price);
+                    true
+                }
+                None => false,
+            }
+        }
+        Some(Bson::Document(inner)) => decimalize(inner, rest),
+        Some(Bson::Array(items)) => items.iter_mut().fold(false, |changed, item| match item {
+            Bson::Document(inner) => decimalize(inner, rest) | changed,
+            _ => changed,
+        }),
+        _ => false,
+    }
+}
+
+async fn migrate_prices(db: &MongoConfig, dry_run: bool) -> CliResult {
+    for (collection_name, paths) in PRICE_FIELDS {
+        let collection: Collection<Document> = db.database.collection(collection_name);
+        let numeric: Vec<Document> = paths
+            .iter()
+            .map(|path| doc! { *path: { "$type": ["double", "int", "long"] } })
+            .collect();
+
+        let mut cursor = collection.find(doc! { "$or": numeric }, None).await?;
+        let mut converted = 0;
+        while let Some(mut document) = cursor.try_next().await? {
+            let mut changed = false;
+            for path in paths {
+                let segments: Vec<&str> = path.split('.').collect();
+                changed |= decimalize(&mut document, &segments);
+            }
+            if !changed {
+                continue;
+            }
+            if !dry_run {
+                let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+                collection.replace_one(doc! { "_id": id }, document, None).await?;
+            }
+            converted += 1;
+        }
+
+        if dry_run {
+            info!("{} documents in {} have prices to convert", converted, collection_name);
+        } else {
+            info!("Converted prices in {} documents in {}", converted, collection_name);
+        }
+    }
+    Ok(())
+}
+
+async fn replay_events(db: &MongoConfig, dry_run: bool) -> CliResult {
+    let summary = event_store::replay(db, dry_run).await?;
+    info!(
+        "{}: {} products rebuilt, {} removed, {} unchanged ({} events)",
+        if dry_run { "Replay dry run" } else { "Replay finished" },
+        summary.rebuilt,
+        summary.removed,
+        summary.unchanged,
+        summary.events
+    );
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use std::collections::BTreeSet;
+
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{debug, error};
+
+use crate::{config::MongoConfig, errors::ApiError, models::Product, money};
+
+const MIN_COMPARE: usize = 2;
+const MAX_COMPARE: usize = 4;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompareQuery {
+    // Comma-separated product IDs, in display order
+    ids: String,
+}
+
+/// One row of the comparison matrix: a field and its value for each product,
+/// in the same order as `products`.
+#[derive(Debug, Serialize)]
+pub struct ComparisonRow {
+    field: String,
+    values: Vec<Value>,
+    differs: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CompareResponse {
+    products: Vec<Product>,
+    rows: Vec<ComparisonRow>,
+}
+
+fn row(field: String, values: Vec<Value>) -> ComparisonRow {
+    let differs = values.windows(2).any(|pair| pair[0] != pair[1]);
+    ComparisonRow { field, values, differs }
+}
+
+// Fixed fields first, then every attribute any of the products has
+fn comparison_rows(products: &[Product]) -> Vec<ComparisonRow> {
+    let column = |f: &dyn Fn(&Product) -> Value| products.iter().map(f).collect::<Vec<_>>();
+
+    let mut rows = vec![
+        row("name".to_string(), column(&|p| json!(p.name))),
+        row("price".to_string(), column(&|p| money::to_json(p.price))),
+        row("category".to_string(), column(&|p| json!(p.category))),
+        row("has_active_sale".to_string(), column(&|p| json!(p.has_active_sale))),
+        row("in_stock".to_string(), column(&|p| json!(p.stock_quantity.is_none_or(|q| q > 0)))),
+    ];
+
+    let attribute_names: BTreeSet<&String> = products
+        .iter()
+        .filter_map(|p| p.attributes.as_ref())
+        .flat_map(|attributes| attributes.keys())
+        .collect();
+    for name in attribute_names {
+        let values = column(&|p| {
+            p.attributes.as_ref().and_then(|a| a.get(name)).map(|v| json!(v)).unwrap_or(Value::Null)
+        });
+        rows.push(row(format!("attributes.{}", name), values));
+    }
+
+    rows
+}
+
+/// Active products side by side for the storefront compare view.
+pub async fn compare_products(
+    db: web::Data<MongoConfig>,
+    query: web::Query<CompareQuery>,
+) -> Result<HttpResponse, Error> {
+    let collection: Collection<Product> = db.catalog_collection("products");
+
+    let mut ids: Vec<ObjectId> = Vec::new();
+    for id in query.ids.split(',').map(str::trim).filter(|id| !id.is_empty()) {
+        let object_id = ObjectId::parse_str(id).map_err(|_| {
+            error!("Invalid product ID format: {}", id);
+            ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format")
+        })?;
+        if !ids.contains(&object_id) {
+            ids.push(object_id);
+        }
+    }
+    if !(MIN_COMPARE..=MAX_COMPARE).contains(&ids.len()) {
+        let message = format!("ids must list between {} and {} distinct products", MIN_COMPARE, MAX_COMPARE);
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, message).into());
+    }
+
+    let mut found: Vec<Product> = collection
+        .find(doc! { "_id": { "$in": &ids }, "status": { "$in": ["active", null] } }, None)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch products to compare: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?
+        .try_collect()
+        .await
+        .map_err(|e| {
+            error!("Error while iterating products to compare: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+
+    // Keep the requested order so columns line up with what the shopper picked
+    let mut products = Vec::with_capacity(ids.len());
+    let mut missing = Vec::new();
+    for id in &ids {
+        match found.iter().position(|p| p.id.as_ref() == Some(id)) {
+            Some(index) => products.push(found.swap_remove(index)),
+            None => missing.push(id.to_string()),
+        }
+    }
+    if !missing.is_empty() {
+        debug!("Products not found for comparison: {:?}", missing);
+        return Err(ApiError::new(StatusCode::NOT_FOUND, format!("Products not found: {}", missing.join(", "))).into());
+    }
+
+    let rows = comparison_rows(&products);
+    Ok(HttpResponse::Ok().json(CompareResponse { products, rows }))
+}
@@ -0,0 +1,88 @@
+use actix_web::{http::StatusCode, Error, HttpRequest};
+use mongodb::bson::{doc, Document};
+
+use crate::{config::ComplianceConfig, errors::ApiError, models::Category, tax};
+
+/// Region the customer shops from, e.g. "DE" or "US-CA".
+pub const CUSTOMER_REGION_HEADER: &str = "X-Customer-Region";
+/// The customer's verified age in years.
+pub const CUSTOMER_AGE_HEADER: &str = "X-Customer-Age";
+
+// Categories whose products may be age-restricted, e.g. alcohol, mature books or games
+const AGE_RESTRICTED_CATEGORIES: [Category; 4] = [Category::Food, Category::Books, Category::Electronics, Category::Other];
+// Categories whose products may be hazardous goods, e.g. batteries or solvents
+const HAZARDOUS_CATEGORIES: [Category; 2] = [Category::Electronics, Category::Other];
+
+/// Checks the compliance flags fit the product's category.
+pub fn check_flags(category: &Category, age_restricted: bool, hazardous: bool) -> Result<(), Error> {
+    if age_restricted && !AGE_RESTRICTED_CATEGORIES.contains(category) {
+        let message = format!("Products in {} cannot be age-restricted", category);
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, message).into());
+    }
+    if hazardous && !HAZARDOUS_CATEGORIES.contains(category) {
+        let message = format!("Products in {} cannot be hazardous", category);
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, message).into());
+    }
+    Ok(())
+}
+
+/// Region restrictions normalized like tax regions, without duplicates.
+pub fn normalize_regions(regions: &[String]) -> Result<Vec<String>, Error> {
+    let mut normalized = Vec::new();
+    for region in regions {
+        let region = tax::normalize_region(region)?;
+        if !normalized.contains(&region) {
+            normalized.push(region);
+        }
+    }
+    Ok(normalized)
+}
+
+/// Who is asking, as far as the caller told: products are hidden from
+/// customers in a region they are restricted in, and age-restricted ones
+/// from customers under COMPLIANCE_MINIMUM_AGE. Without the headers nothing
+/// is hidden, e.g. for back-office clients.
+#[derive(Debug, Default)]
+pub struct CustomerContext {
+    region: Option<String>,
+    age: Option<u32>,
+}
+
+impl CustomerContext {
+    pub fn from_request(req: &HttpRequest) -> Result<Self, Error> {
+        let header = |name: &str| -> Result<Option<&str>, Error> {
+            req.headers()
+                .get(name)
+                .map(|value| {
+                    value
+                        .to_str()
+                        .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid {} header", name)).into())
+                })
+                .transpose()
+        };
+        let region = header(CUSTOMER_REGION_HEADER)?.map(tax::normalize_region).transpose()?;
+        let age = header(CUSTOMER_AGE_HEADER)?
+            .map(|age| {
+                age.trim()
+                    .parse()
+                    .map_err(|_| {
+                        let message = format!("{} must be a whole number of years", CUSTOMER_AGE_HEADER);
+                        Error::from(ApiError::new(StatusCode::BAD_REQUEST, message))
+                    })
+            })
+            .transpose()?;
+        Ok(CustomerContext { region, age })
+    }
+
+    /// Conditions leaving out the products this customer may not see.
+    pub fn filter(&self, config: &ComplianceConfig) -> Document {
+        let mut filter = Document::new();
+        if let Some(region) = &self.region {
+            filter.insert("region_restrictions", doc! { "$ne": region });
+        }
+        if self.age.is_some_and(|age| age < config.minimum_age) {
+            filter.insert("age_restricted", doc! { "$ne": true });
+        }
+        filter
+    }
+}
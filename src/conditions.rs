@@ -0,0 +1,195 @@
+use std::future::{ready, Ready};
+
+use actix_web::{dev::Payload, http::StatusCode, web, Error, FromRequest, HttpRequest};
+use mongodb::bson::{doc, oid::ObjectId, Bson, Document};
+use regex::escape;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use validator::{Validate, ValidationError};
+
+use crate::{
+    errors::ApiError,
+    models::{Category, ProductStatus, TaxClass, Unit},
+    money,
+    validation::{query_error_handler, validation_error},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FieldKind {
+    Text,
+    Money,
+    Integer,
+    Bool,
+    Category,
+    Status,
+    TaxClass,
+    Unit,
+    Id,
+}
+
+// Fields that can be filtered on, and whether products may lack them
+const FIELDS: [(&str, FieldKind, bool); 15] = [
+    ("name", FieldKind::Text, false),
+    ("slug", FieldKind::Text, true),
+    ("barcode", FieldKind::Text, true),
+    ("supplier_sku", FieldKind::Text, true),
+    ("price", FieldKind::Money, false),
+    ("price_per_unit", FieldKind::Money, true),
+    ("stock_quantity", FieldKind::Integer, true),
+    ("has_active_sale", FieldKind::Bool, false),
+    ("age_restricted", FieldKind::Bool, false),
+    ("hazardous", FieldKind::Bool, false),
+    ("category", FieldKind::Category, false),
+    ("status", FieldKind::Status, false),
+    ("tax_class", FieldKind::TaxClass, false),
+    ("unit", FieldKind::Unit, false),
+    ("supplier_id", FieldKind::Id, true),
+];
+
+/// One `filter[field][op]=value` condition of a product listing.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    pub field: String,
+    pub op: String,
+    pub value: String,
+}
+
+impl Condition {
+    /// The Mongo filter for this condition, or why the field, operator or
+    /// value is not accepted.
+    pub fn filter(&self) -> Result<Document, String> {
+        let Some(&(field, kind, optional)) = FIELDS.iter().find(|(name, _, _)| *name == self.field) else {
+            let fields: Vec<&str> = FIELDS.iter().map(|(name, _, _)| *name).collect();
+            return Err(format!("Cannot filter on '{}': expected one of {}", self.field, fields.join(", ")));
+        };
+
+        let value = self.value.trim();
+        let condition = match (self.op.as_str(), kind) {
+            ("exists", _) if optional => doc! { "$exists": parse_bool(value)? },
+            ("eq" | "ne", _) => doc! { format!("${}", self.op): convert(kind, value)? },
+            ("in" | "nin", _) if kind != FieldKind::Bool => {
+                let values = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|v| !v.is_empty())
+                    .map(|v| convert(kind, v))
+                    .collect::<Result<Vec<_>, _>>()?;
+                doc! { format!("${}", self.op): values }
+            }
+            ("gt" | "gte" | "lt" | "lte", FieldKind::Money | FieldKind::Integer) => {
+                doc! { format!("${}", self.op): convert(kind, value)? }
+            }
+            ("contains", FieldKind::Text) => doc! { "$regex": format!("(?i){}", escape(value)) },
+            ("starts_with", FieldKind::Text) => doc! { "$regex": format!("(?i)^{}", escape(value)) },
+            (op, _) => return Err(format!("Operator '{}' is not supported on {}", op, field)),
+        };
+        Ok(doc! { field: condition })
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    value.parse().map_err(|_| format!("Invalid boolean '{}': expected true or false", value))
+}
+
+// A value in the form the field is stored in
+fn convert(kind: FieldKind, value: &str) -> Result<Bson, String> {
+    let converted = match kind {
+        FieldKind::Text => Bson::String(value.to_string()),
+        FieldKind::Money => money::parse(value)
+            .map(money::to_bson)
+            .ok_or_else(|| format!("Invalid amount '{}'", value))?,
+        FieldKind::Integer => Bson::Int64(value.parse().map_err(|_| format!("Invalid number '{}'", value))?),
+        FieldKind::Bool => Bson::Boolean(parse_bool(value)?),
+        FieldKind::Category => Bson::String(value.parse::<Category>()?.to_string()),
+        FieldKind::Status => Bson::String(value.parse::<ProductStatus>()?.to_string()),
+        FieldKind::TaxClass => Bson::String(value.parse::<TaxClass>()?.to_string()),
+        FieldKind::Unit => Bson::String(value.parse::<Unit>()?.to_string()),
+        FieldKind::Id => Bson::ObjectId(ObjectId::parse_str(value).map_err(|_| format!("Invalid ID '{}'", value))?),
+    };
+    Ok(converted)
+}
+
+/// All conditions combined; every one of them has to match.
+pub fn filter(conditions: &[Condition]) -> Result<Document, String> {
+    let filters = conditions.iter().map(Condition::filter).collect::<Result<Vec<_>, _>>()?;
+    Ok(doc! { "$and": filters })
+}
+
+/// Validator for conditions that arrive in a JSON body, such as a saved filter.
+pub fn validate(conditions: &[Condition]) -> Result<(), ValidationError> {
+    for condition in conditions {
+        if let Err(message) = condition.filter() {
+            let mut error = ValidationError::new("condition");
+            error.message = Some(message.into());
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+/// The request's conditions, plus the saved ones on fields the request
+/// leaves alone.
+pub fn merge(request: Option<Vec<Condition>>, saved: Option<Vec<Condition>>) -> Option<Vec<Condition>> {
+    let Some(request) = request else {
+        return saved;
+    };
+    let mut merged = request.clone();
+    merged.extend(
+        saved
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|condition| !request.iter().any(|own| own.field == condition.field)),
+    );
+    Some(merged)
+}
+
+// "filter[price][gte]" is ("price", "gte"); "filter[price]" compares for equality
+fn condition_key(key: &str) -> Option<(&str, &str)> {
+    let inner = key.strip_prefix("filter[")?.strip_suffix(']')?;
+    Some(inner.split_once("][").unwrap_or((inner, "eq")))
+}
+
+/// Query extractor that takes the `filter[field][op]=value` pairs out of
+/// the query string and deserializes and validates the rest like
+/// `ValidatedQuery`.
+pub struct ConditionalQuery<T>(pub T, pub Vec<Condition>);
+
+impl<T: DeserializeOwned + Validate> ConditionalQuery<T> {
+    fn extract(req: &HttpRequest) -> Result<Self, Error> {
+        let mut rest = Vec::new();
+        let mut conditions = Vec::new();
+        for pair in req.query_string().split('&').filter(|pair| !pair.is_empty()) {
+            let decoded = web::Query::<Vec<(String, String)>>::from_query(pair)
+                .map_err(|e| query_error_handler(e, req))?
+                .into_inner();
+            let Some((key, value)) = decoded.into_iter().next() else {
+                continue;
+            };
+            let Some((field, op)) = condition_key(&key) else {
+                rest.push(pair);
+                continue;
+            };
+
+            let condition = Condition { field: field.to_string(), op: op.to_string(), value };
+            if let Err(message) = condition.filter() {
+                return Err(ApiError::new(StatusCode::BAD_REQUEST, message).into());
+            }
+            conditions.push(condition);
+        }
+
+        let query = web::Query::<T>::from_query(&rest.join("&"))
+            .map_err(|e| query_error_handler(e, req))?
+            .into_inner();
+        query.validate().map_err(validation_error)?;
+        Ok(ConditionalQuery(query, conditions))
+    }
+}
+
+impl<T: DeserializeOwned + Validate + 'static> FromRequest for ConditionalQuery<T> {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Self::extract(req))
+    }
+}
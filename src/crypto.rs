@@ -0,0 +1,79 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::OnceLock;
+use tracing::warn;
+
+use crate::config::EncryptionConfig;
+
+// Development fallback only. In production, set ENCRYPTION_KEY (32 bytes, base64)
+const DEV_ENCRYPTION_KEY: &[u8; 32] = b"dev-only-encryption-key-32-bytes";
+
+const NONCE_LEN: usize = 12;
+
+static KEY: OnceLock<Vec<u8>> = OnceLock::new();
+static CIPHER: OnceLock<Aes256Gcm> = OnceLock::new();
+
+/// Sets the encryption key from the loaded configuration. Runs once at startup.
+pub fn init(config: &EncryptionConfig) {
+    KEY.get_or_init(|| {
+        config.key.clone().unwrap_or_else(|| {
+            warn!("ENCRYPTION_KEY missing, using development key");
+            DEV_ENCRYPTION_KEY.to_vec()
+        })
+    });
+}
+
+fn key() -> &'static [u8] {
+    KEY.get().expect("crypto::init runs at startup")
+}
+
+fn cipher() -> &'static Aes256Gcm {
+    CIPHER.get_or_init(|| Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key())))
+}
+
+fn seal(nonce: &Nonce<<Aes256Gcm as AeadCore>::NonceSize>, plaintext: &[u8]) -> Result<String, String> {
+    let ciphertext = cipher()
+        .encrypt(nonce, plaintext)
+        .map_err(|e| format!("Encryption failed: {}", e))?;
+
+    let mut out = nonce.to_vec();
+    out.extend_from_slice(&ciphertext);
+    Ok(STANDARD.encode(out))
+}
+
+/// Encrypts with AES-256-GCM and returns base64(nonce || ciphertext).
+pub fn encrypt(plaintext: &[u8]) -> Result<String, String> {
+    seal(&Aes256Gcm::generate_nonce(&mut OsRng), plaintext)
+}
+
+/// Like [`encrypt`], but the same plaintext always gives the same output, so
+/// the result can be searched for. The nonce is an HMAC of the plaintext;
+/// only use this where equal values may be recognised as equal.
+pub fn encrypt_deterministic(plaintext: &[u8]) -> Result<String, String> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key()).expect("HMAC accepts any key length");
+    mac.update(b"deterministic-nonce");
+    mac.update(plaintext);
+    let digest = mac.finalize().into_bytes();
+    seal(Nonce::from_slice(&digest[..NONCE_LEN]), plaintext)
+}
+
+/// Reverses [`encrypt`].
+pub fn decrypt(encoded: &str) -> Result<Vec<u8>, String> {
+    let data = STANDARD
+        .decode(encoded)
+        .map_err(|e| format!("Invalid ciphertext encoding: {}", e))?;
+
+    if data.len() < NONCE_LEN {
+        return Err("Ciphertext too short".to_string());
+    }
+
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    cipher()
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|e| format!("Decryption failed: {}", e))
+}
@@ -0,0 +1,177 @@
+use std::pin::Pin;
+
+use actix_web::{
+    body::{self, EitherBody, MessageBody},
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::PayloadError,
+    http::header,
+    middleware::Next,
+    web::{self, Bytes, BytesMut},
+    Error, HttpMessage,
+};
+use futures::{future, stream, Stream, StreamExt};
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::config::DebugLogConfig;
+
+// JSON keys and query parameters whose values never appear in the log; a key
+// is redacted when it contains any of these
+const SENSITIVE_KEYS: [&str; 6] = ["password", "token", "secret", "authorization", "api_key", "otpauth"];
+
+const REDACTED: &str = "[redacted]";
+
+// Two-factor and OAuth codes too, but not e.g. barcodes
+fn is_sensitive(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    SENSITIVE_KEYS.iter().any(|sensitive| key.contains(sensitive))
+        || key == "code"
+        || key.ends_with("_code")
+        || key.ends_with("_codes")
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if is_sensitive(key) {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn redact_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, _)) if is_sensitive(key) => format!("{}={}", key, REDACTED),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+// A body as it goes into the log: redacted JSON, or just its size otherwise,
+// since form and multipart bodies can't be redacted reliably
+fn loggable(body: &[u8], is_json: bool, max_bytes: usize) -> String {
+    if body.is_empty() {
+        return "<empty>".to_string();
+    }
+    if !is_json {
+        return format!("<{} bytes>", body.len());
+    }
+    let mut text = match serde_json::from_slice::<Value>(body) {
+        Ok(mut value) => {
+            redact(&mut value);
+            value.to_string()
+        }
+        Err(_) => return format!("<{} bytes of invalid JSON>", body.len()),
+    };
+    if text.len() > max_bytes {
+        let mut end = max_bytes;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        text.push_str("...");
+    }
+    text
+}
+
+fn is_json(content_type: Option<&header::HeaderValue>) -> bool {
+    content_type
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json") || v.contains("+json"))
+}
+
+// The request body, if it is at most `max_bytes`. Reading stops once it
+// is longer: what was read goes back in front of the rest, which reaches
+// the handler unread, so a large body is never held in memory for the log.
+async fn capture_body(req: &mut ServiceRequest, max_bytes: usize) -> Result<Option<Bytes>, Error> {
+    let declared = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if declared.is_some_and(|length| length > max_bytes) {
+        return Ok(None);
+    }
+
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > max_bytes {
+            let read = stream::once(future::ready(Ok::<_, PayloadError>(body.freeze())));
+            let rest: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> = Box::pin(read.chain(payload));
+            req.set_payload(Payload::from(rest));
+            return Ok(None);
+        }
+    }
+    let body = body.freeze();
+    req.set_payload(Payload::from(body.clone()));
+    Ok(Some(body))
+}
+
+/// Logs the request and response bodies of requests that fail with a 4xx or
+/// 5xx status, with passwords, tokens and codes redacted. Does nothing
+/// unless DebugLogConfig is enabled.
+pub async fn log_failed_requests(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let config = req.app_data::<web::Data<DebugLogConfig>>().cloned();
+    let Some(config) = config.filter(|config| config.enabled) else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+
+    // Only JSON bodies are buffered; uploads stream through untouched
+    let request_is_json = is_json(req.headers().get(header::CONTENT_TYPE));
+    let request_body = if request_is_json { capture_body(&mut req, config.max_body_bytes).await? } else { None };
+    let method = req.method().clone();
+    let target = match req.query_string() {
+        "" => req.path().to_string(),
+        query => format!("{}?{}", req.path(), redact_query(query)),
+    };
+    let request_id = req.extensions().get::<tracing_actix_web::RequestId>().map(|id| id.to_string());
+
+    let res = next.call(req).await?;
+    let status = res.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return Ok(res.map_into_left_body());
+    }
+
+    let response_is_json = is_json(res.headers().get(header::CONTENT_TYPE));
+    let (req, res) = res.into_parts();
+    let (res, response_body) = res.into_parts();
+    let response_body = match body::to_bytes(response_body).await {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            warn!("Could not read the response body of {} {} for the debug log", method, target);
+            Bytes::new()
+        }
+    };
+
+    info!(
+        request_id = request_id.as_deref().unwrap_or("-"),
+        "Failed request {} {} -> {}; request body: {}; response body: {}",
+        method,
+        target,
+        status.as_u16(),
+        match (&request_body, request_is_json) {
+            (Some(body), _) => loggable(body, true, config.max_body_bytes),
+            (None, true) => format!("<over {} bytes, not captured>", config.max_body_bytes),
+            (None, false) => "<not captured>".to_string(),
+        },
+        loggable(&response_body, response_is_json, config.max_body_bytes),
+    );
+
+    let res = res.set_body(response_body).map_into_boxed_body();
+    Ok(ServiceResponse::new(req, res).map_into_right_body())
+}
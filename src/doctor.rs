@@ -0,0 +1,201 @@
+use std::{collections::HashMap, fmt, time::Instant};
+
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    error::ErrorKind,
+    Collection,
+};
+
+use crate::{
+    auth,
+    cli::required_indexes,
+    config::{AppConfig, MongoConfig},
+    export_storage, mail,
+};
+
+// Mongo's code for a collection that doesn't exist yet
+const NAMESPACE_NOT_FOUND: i32 = 26;
+
+// Top level, so the local backend leaves no directory behind
+const PROBE_KEY_PREFIX: &str = "doctor-probe";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    Pass,
+    Fail,
+    // Not configured, or depends on a check that failed
+    Skip,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Status::Pass => "PASS",
+            Status::Fail => "FAIL",
+            Status::Skip => "SKIP",
+        })
+    }
+}
+
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+}
+
+impl Check {
+    fn from_result(name: &'static str, result: Result<String, String>) -> Self {
+        match result {
+            Ok(detail) => Check { name, status: Status::Pass, detail },
+            Err(detail) => Check { name, status: Status::Fail, detail },
+        }
+    }
+
+    fn skip(name: &'static str, detail: impl Into<String>) -> Self {
+        Check { name, status: Status::Skip, detail: detail.into() }
+    }
+}
+
+/// Checks what a deployment needs before it takes traffic and prints a table
+/// of the results. Errors when any check fails, so pipelines can stop there.
+pub async fn run(config: &AppConfig) -> Result<(), String> {
+    let mut checks = Vec::new();
+
+    match ping(config).await {
+        Ok((db, detail)) => {
+            checks.push(Check::from_result("mongo", Ok(detail)));
+            checks.push(Check::from_result("indexes", check_indexes(&db).await));
+        }
+        Err(detail) => {
+            checks.push(Check::from_result("mongo", Err(detail)));
+            checks.push(Check::skip("indexes", "needs mongo"));
+        }
+    }
+    checks.push(Check::from_result("jwt secrets", check_secrets(config)));
+    checks.push(Check::from_result("export storage", check_storage(config).await));
+    checks.push(match &config.mail.url {
+        None => Check::skip("smtp", "MAIL_URL is not set, emails are only logged"),
+        Some(_) => Check::from_result("smtp", check_mail(config).await),
+    });
+
+    print_table(&checks);
+    let failed = checks.iter().filter(|check| check.status == Status::Fail).count();
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(format!("{} of {} checks failed", failed, checks.len()))
+    }
+}
+
+fn print_table(checks: &[Check]) {
+    let width = checks.iter().map(|check| check.name.len()).chain(["CHECK".len()]).max().unwrap_or(0);
+    println!("{:<width$}  STATUS  DETAIL", "CHECK");
+    for check in checks {
+        println!("{:<width$}  {:<6}  {}", check.name, check.status, check.detail);
+    }
+}
+
+async fn ping(config: &AppConfig) -> Result<(MongoConfig, String), String> {
+    let db = MongoConfig::init(&config.mongo).await.map_err(|e| e.to_string())?;
+    let started = Instant::now();
+    db.database.run_command(doc! { "ping": 1 }, None).await.map_err(|e| e.to_string())?;
+    let detail = format!("database {} answered in {}ms", config.mongo.database_name, started.elapsed().as_millis());
+    Ok((db, detail))
+}
+
+// Index keys as written, with 1 and 1.0 alike
+type KeySpec = Vec<(String, String)>;
+
+fn key_spec(keys: &Document) -> KeySpec {
+    keys.iter()
+        .map(|(field, value)| {
+            let value = match value {
+                Bson::Int32(n) => n.to_string(),
+                Bson::Int64(n) => n.to_string(),
+                Bson::Double(n) => (*n as i64).to_string(),
+                other => other.to_string(),
+            };
+            (field.clone(), value)
+        })
+        .collect()
+}
+
+// The keys of each index on a collection and whether it is unique
+async fn existing_indexes(db: &MongoConfig, collection_name: &str) -> Result<Vec<(KeySpec, bool)>, String> {
+    let collection: Collection<Document> = db.database.collection(collection_name);
+    let indexes = match collection.list_indexes(None).await {
+        Ok(cursor) => cursor.try_collect::<Vec<_>>().await.map_err(|e| e.to_string())?,
+        Err(e) if matches!(*e.kind, ErrorKind::Command(ref c) if c.code == NAMESPACE_NOT_FOUND) => Vec::new(),
+        Err(e) => return Err(e.to_string()),
+    };
+    Ok(indexes
+        .iter()
+        .map(|index| {
+            let unique = index.options.as_ref().and_then(|options| options.unique).unwrap_or(false);
+            (key_spec(&index.keys), unique)
+        })
+        .collect())
+}
+
+async fn check_indexes(db: &MongoConfig) -> Result<String, String> {
+    let required = required_indexes();
+    let mut existing = HashMap::new();
+    let mut missing = Vec::new();
+
+    for (collection_name, keys, unique) in &required {
+        if !existing.contains_key(collection_name) {
+            existing.insert(*collection_name, existing_indexes(db, collection_name).await?);
+        }
+        let spec = key_spec(keys);
+        let found = existing[collection_name]
+            .iter()
+            .any(|(existing_keys, existing_unique)| *existing_keys == spec && (*existing_unique || !unique));
+        if !found {
+            let kind = if *unique { "unique " } else { "" };
+            missing.push(format!("{}index {} on {}", kind, keys, collection_name));
+        }
+    }
+
+    if missing.is_empty() {
+        Ok(format!("all {} present", required.len()))
+    } else {
+        Err(format!("{} missing, run migrate: {}", missing.len(), missing.join(", ")))
+    }
+}
+
+fn check_secrets(config: &AppConfig) -> Result<String, String> {
+    let problems = auth::signing_secret_problems(&config.auth);
+    if problems.is_empty() {
+        Ok("JWT_SECRET, JWT_REFRESH_SECRET and TWO_FACTOR_SECRET are set and long enough".to_string())
+    } else {
+        Err(problems.join("; "))
+    }
+}
+
+// Writes, reads back and deletes an object, as export jobs and downloads will
+async fn check_storage(config: &AppConfig) -> Result<String, String> {
+    let storage = export_storage::from_config(&config.export);
+    let key = format!("{}-{}", PROBE_KEY_PREFIX, uuid::Uuid::now_v7());
+    let probe = b"products-api doctor".to_vec();
+
+    storage.put(&key, probe.clone()).await.map_err(|e| format!("{}: write failed: {}", storage.name(), e))?;
+    let read = storage.get(&key).await;
+    let deleted = storage.delete(&key).await;
+    match read {
+        Ok(Some(data)) if data == probe => {}
+        Ok(_) => return Err(format!("{}: {} read back different content", storage.name(), key)),
+        Err(e) => return Err(format!("{}: read failed: {}", storage.name(), e)),
+    }
+    deleted.map_err(|e| format!("{}: delete failed: {}", storage.name(), e))?;
+    Ok(format!("{}: wrote, read and deleted {}", storage.name(), key))
+}
+
+async fn check_mail(config: &AppConfig) -> Result<String, String> {
+    let mailer = mail::from_config(config.mail.clone());
+    if mailer.name() == "log" {
+        return Err("MAIL_URL is invalid or not smtp:// or smtps://, emails are only logged".to_string());
+    }
+    mailer.check().await?;
+    Ok(format!("{} server accepted a session", mailer.name()))
+}
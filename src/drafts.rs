@@ -0,0 +1,224 @@
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime},
+    options::ReplaceOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+
+use crate::{
+    auth::Claims,
+    barcode::normalize_barcode,
+    config::{MongoConfig, PriceApprovalConfig},
+    errors::ApiError,
+    events::EventHub,
+    handlers::apply_product_update,
+    models::{Product, UpdateProductRequest},
+    public_ids,
+    validation_webhook::ValidationWebhook,
+};
+
+/// Edits staged for a live product, applied when the draft is published.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProductDraft {
+    #[serde(rename = "_id")]
+    pub product_id: ObjectId,
+    pub changes: UpdateProductRequest,
+    pub updated_by: ObjectId,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProductDraftResponse<'a> {
+    pub product_id: String,
+    pub changes: &'a UpdateProductRequest,
+    pub updated_by: String,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl<'a> From<&'a ProductDraft> for ProductDraftResponse<'a> {
+    fn from(draft: &'a ProductDraft) -> Self {
+        ProductDraftResponse {
+            product_id: draft.product_id.to_string(),
+            changes: &draft.changes,
+            updated_by: draft.updated_by.to_string(),
+            created_at: draft.created_at.try_to_rfc3339_string().unwrap_or_default(),
+            updated_at: draft.updated_at.try_to_rfc3339_string().unwrap_or_default(),
+        }
+    }
+}
+
+fn drafts_collection(db: &MongoConfig) -> Collection<ProductDraft> {
+    db.database.collection("product_drafts")
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+pub async fn find_draft(db: &MongoConfig, product_id: ObjectId) -> Result<Option<ProductDraft>, Error> {
+    drafts_collection(db)
+        .find_one(doc! { "_id": product_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch product draft", e))
+}
+
+/// The product as it will look once `changes` are published. Only for
+/// display: the changes are checked when they are published.
+pub fn preview(product: &mut Product, changes: &UpdateProductRequest) {
+    if let Some(name) = &changes.name {
+        product.name = name.clone();
+    }
+    if let Some(price) = changes.price {
+        product.price = price;
+    }
+    if let Some(category) = &changes.category {
+        product.category = category.clone();
+    }
+    if let Some(tax_class) = changes.tax_class {
+        product.tax_class = tax_class;
+    }
+    if let Some(unit) = changes.unit {
+        product.unit = unit;
+    }
+    if let Some(price_per_unit) = changes.price_per_unit {
+        product.price_per_unit = Some(price_per_unit);
+    }
+    if let Some(price_tiers) = &changes.price_tiers {
+        product.price_tiers = price_tiers.clone();
+    }
+    if let Some(has_active_sale) = changes.has_active_sale {
+        product.has_active_sale = has_active_sale;
+    }
+    if let Some(sale_ends_at) = changes.sale_ends_at {
+        product.sale_ends_at = Some(DateTime::from_millis(sale_ends_at.timestamp_millis()));
+    }
+    if let Some(stock_quantity) = changes.stock_quantity {
+        product.stock_quantity = Some(stock_quantity);
+    }
+    if let Some(low_stock_threshold) = changes.low_stock_threshold {
+        product.low_stock_threshold = Some(low_stock_threshold);
+    }
+    if let Some(barcode) = &changes.barcode {
+        product.barcode = Some(normalize_barcode(barcode).unwrap_or_else(|_| barcode.clone()));
+    }
+    if let Some(attributes) = &changes.attributes {
+        product.attributes = Some(attributes.clone());
+    }
+    match changes.supplier_id.as_deref() {
+        Some("") => product.supplier_id = None,
+        Some(id) => product.supplier_id = ObjectId::parse_str(id).ok(),
+        None => {}
+    }
+    if let Some(supplier_sku) = &changes.supplier_sku {
+        product.supplier_sku = Some(supplier_sku.clone());
+    }
+    if let Some(cost_price) = changes.cost_price {
+        product.cost_price = Some(cost_price);
+    }
+    if let Some(age_restricted) = changes.age_restricted {
+        product.age_restricted = age_restricted;
+    }
+    if let Some(hazardous) = changes.hazardous {
+        product.hazardous = hazardous;
+    }
+    if let Some(region_restrictions) = &changes.region_restrictions {
+        product.region_restrictions = region_restrictions.iter().map(|region| region.trim().to_uppercase()).collect();
+    }
+}
+
+pub async fn get_draft(db: web::Data<MongoConfig>, id: web::Path<String>) -> Result<HttpResponse, Error> {
+    let product_id = public_ids::resolve_product_id(&db, &id).await?;
+    match find_draft(&db, product_id).await? {
+        Some(draft) => Ok(HttpResponse::Ok().json(ProductDraftResponse::from(&draft))),
+        None => Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    }
+}
+
+/// Stages edits for a product, replacing any draft it already has. Takes
+/// the same body as `PUT /products/{id}`; the live product is unchanged.
+pub async fn save_draft(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+    changes: web::Json<UpdateProductRequest>,
+) -> Result<HttpResponse, Error> {
+    let product_id = public_ids::resolve_product_id(&db, &id).await?;
+    let products: Collection<Product> = db.database.collection("products");
+    let exists = products
+        .count_documents(doc! { "_id": product_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch product", e))?
+        > 0;
+    if !exists {
+        debug!("Product not found for draft: {}", id);
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    }
+
+    let now = DateTime::now();
+    let created_at = find_draft(&db, product_id).await?.map(|draft| draft.created_at).unwrap_or(now);
+    let draft = ProductDraft {
+        product_id,
+        changes: changes.into_inner(),
+        updated_by: claims.user_id()?,
+        created_at,
+        updated_at: now,
+    };
+    let options = ReplaceOptions::builder().upsert(true).build();
+    drafts_collection(&db)
+        .replace_one(doc! { "_id": product_id }, &draft, options)
+        .await
+        .map_err(|e| db_error("Failed to save product draft", e))?;
+
+    info!("Saved draft of product {}", product_id);
+    Ok(HttpResponse::Ok().json(ProductDraftResponse::from(&draft)))
+}
+
+pub async fn discard_draft(db: web::Data<MongoConfig>, id: web::Path<String>) -> Result<HttpResponse, Error> {
+    let product_id = public_ids::resolve_product_id(&db, &id).await?;
+    let result = drafts_collection(&db)
+        .delete_one(doc! { "_id": product_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to discard product draft", e))?;
+
+    if result.deleted_count == 0 {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    }
+    info!("Discarded draft of product {}", product_id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Applies the draft to the live product like an update, and removes it
+/// once that succeeded. A failed update leaves the draft in place to fix.
+pub async fn publish_draft(
+    db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
+    approvals: web::Data<PriceApprovalConfig>,
+    webhook: Option<web::Data<ValidationWebhook>>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let product_id = public_ids::resolve_product_id(&db, &id).await?;
+    let Some(draft) = find_draft(&db, product_id).await? else {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    };
+
+    let webhook = webhook.as_ref().map(|webhook| webhook.get_ref());
+    let response =
+        apply_product_update(&db, &events, &approvals, webhook, claims.user_id()?, &id, &draft.changes).await?;
+    if !response.status().is_success() {
+        return Ok(response);
+    }
+
+    // Only if nobody saved a newer draft while this one was being published
+    drafts_collection(&db)
+        .delete_one(doc! { "_id": product_id, "updated_at": draft.updated_at }, None)
+        .await
+        .map_err(|e| db_error("Failed to remove published draft", e))?;
+    info!("Published draft of product {}", product_id);
+    Ok(response)
+}
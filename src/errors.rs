@@ -0,0 +1,658 @@
+use std::fmt;
+
+use actix_web::{
+    body::{self, BodySize, BoxBody, EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    error::InternalError,
+    http::{header, StatusCode},
+    middleware::Next,
+    Error, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tracing::{debug, warn};
+use tracing_actix_web::RequestId;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+// Members RFC 7807 defines; a handler's body can't override them
+const PROBLEM_MEMBERS: [&str; 5] = ["type", "title", "status", "detail", "instance"];
+
+// Larger error bodies are not errors worth rewriting, e.g. a restore report
+const MAX_CONVERTED_BODY: usize = 64 * 1024;
+
+/// An RFC 7807 problem document, the body of every error response. Handlers
+/// may still answer with `{"message": ...}`; problem_details turns those into
+/// one of these on the way out.
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    // No problem types are documented yet, so the status says it all
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    // urn:uuid: of the request id that appears in the logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+    // Extension members, e.g. a machine readable code or the failed validations
+    #[serde(flatten)]
+    extensions: Map<String, Value>,
+}
+
+impl ApiError {
+    /// A problem with only its status, for when the status says it all.
+    pub fn blank(status: StatusCode) -> Self {
+        ApiError {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail: None,
+            instance: None,
+            extensions: Map::new(),
+        }
+    }
+
+    pub fn new(status: StatusCode, detail: impl Into<String>) -> Self {
+        ApiError { detail: Some(detail.into()), ..ApiError::blank(status) }
+    }
+
+    /// A 400 describing `error`, for `.map_err(ApiError::bad_request)`.
+    pub fn bad_request(error: impl fmt::Display) -> Self {
+        ApiError::new(StatusCode::BAD_REQUEST, error.to_string())
+    }
+
+    /// A 500 describing `error`, for `.map_err(ApiError::internal)`.
+    pub fn internal(error: impl fmt::Display) -> Self {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+    }
+
+    /// Adds the extension member `name`, unless it is one of the standard members.
+    pub fn with(mut self, name: &str, value: impl Serialize) -> Self {
+        if !PROBLEM_MEMBERS.contains(&name) {
+            self.extensions.insert(name.to_string(), serde_json::to_value(value).unwrap_or(Value::Null));
+        }
+        self
+    }
+
+    fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    // The problem a handler meant with the body it wrote: its "message" or
+    // text becomes the detail, other JSON members are kept as extensions
+    fn from_body(status: StatusCode, body: &[u8]) -> Self {
+        let problem = ApiError::blank(status);
+        match serde_json::from_slice::<Value>(body) {
+            Ok(Value::Object(members)) => members.into_iter().fold(problem, |mut problem, (name, value)| {
+                match (name.as_str(), value) {
+                    ("message" | "detail", Value::String(detail)) => problem.detail = Some(detail),
+                    (name, value) => problem = problem.with(name, value),
+                }
+                problem
+            }),
+            Ok(Value::String(detail)) => ApiError { detail: Some(detail), ..problem },
+            Ok(Value::Null) => problem,
+            Ok(other) => problem.with("errors", other),
+            Err(_) => match String::from_utf8_lossy(body).trim() {
+                "" => problem,
+                text => ApiError { detail: Some(text.to_string()), ..problem },
+            },
+        }
+    }
+
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.detail.as_deref().unwrap_or(&self.title))
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status()).content_type(PROBLEM_JSON).json(self)
+    }
+}
+
+// The response as a problem document naming the request
+async fn into_problem(res: HttpResponse, request_id: Option<RequestId>) -> HttpResponse {
+    let status = res.status();
+    let convertible = match res.body().size() {
+        BodySize::None => true,
+        BodySize::Sized(size) => size as usize <= MAX_CONVERTED_BODY,
+        BodySize::Stream => false,
+    };
+    if !(status.is_client_error() || status.is_server_error()) || !convertible {
+        return res;
+    }
+
+    let (mut res, body) = res.into_parts();
+    let body = body::to_bytes(body).await.unwrap_or_else(|_| {
+        warn!("Could not read an error body to rewrite it");
+        Default::default()
+    });
+    let mut problem = ApiError::from_body(status, &body);
+    problem.instance = request_id.map(|id| format!("urn:uuid:{}", id));
+    res.headers_mut().insert(header::CONTENT_TYPE, header::HeaderValue::from_static(PROBLEM_JSON));
+    res.headers_mut().remove(header::CONTENT_LENGTH);
+    res.set_body(serde_json::to_vec(&problem).unwrap_or_default()).map_into_boxed_body()
+}
+
+/// Rewrites every 4xx and 5xx response as application/problem+json, keeping
+/// its status and headers, and names the request in `instance`. Streamed
+/// bodies pass as they are. Must run inside TracingLogger, which assigns the
+/// request id.
+pub async fn problem_details(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let request_id = req.extensions().get::<RequestId>().copied();
+    match next.call(req).await {
+        Ok(res) => {
+            let (req, res) = res.into_parts();
+            Ok(ServiceResponse::new(req, into_problem(res.map_into_boxed_body(), request_id).await))
+        }
+        Err(e) => {
+            let response = into_problem(e.error_response(), request_id).await;
+            Err(InternalError::from_response(e, response).into())
+        }
+    }
+}
+
+/// Default service: unknown routes get a JSON 404 instead of an empty body.
+pub async fn not_found(req: HttpRequest) -> HttpResponse {
+    let message = format!("No route for {} {}", req.method(), req.path());
+    debug!("{}", message);
+    ApiError::new(StatusCode::NOT_FOUND, message).with("code", "not_found").error_response()
+}
+
+/// Replaces the empty 405 actix answers for a known path with an unsupported
+/// method by a JSON one, keeping its Allow header. CORS preflights never get
+/// here; the CORS middleware answers them itself.
+pub async fn json_method_not_allowed(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let res = next.call(req).await?;
+    let is_empty = matches!(res.response().body().size(), BodySize::None | BodySize::Sized(0));
+    if res.status() != StatusCode::METHOD_NOT_ALLOWED || !is_empty {
+        return Ok(res.map_into_left_body());
+    }
+
+    let (req, res) = res.into_parts();
+    let message = format!("Method {} is not allowed on {}", req.method(), req.path());
+    let mut response = HttpResponse::MethodNotAllowed();
+    if let Some(allow) = res.headers().get(header::ALLOW) {
+        response.insert_header((header::ALLOW, allow.clone()));
+    }
+    let response = response
+        .content_type(PROBLEM_JSON)
+        .json(ApiError::new(StatusCode::METHOD_NOT_ALLOWED, message).with("code", "method_not_allowed"));
+    Ok(ServiceResponse::new(req, response).map_into_right_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    use actix_web::{
+        body::to_bytes,
+        middleware::from_fn,
+        test::{self, TestRequest},
+        web::{self, Bytes},
+        App,
+    };
+    use chrono::Utc;
+    use futures::{
+        future::{self, BoxFuture},
+        stream,
+    };
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use mongodb::bson::oid::ObjectId;
+    use serde::Deserialize;
+    use serde_json::json;
+    use tracing_actix_web::TracingLogger;
+    use validator::Validate;
+
+    use super::*;
+    use crate::{
+        auth::{self, AuthMiddleware, Claims, RequireScope, SCOPE_ADMIN, SCOPE_PRODUCTS_IMPORT, SCOPE_PRODUCTS_READ},
+        config::{
+            AuthConfig, ExportConfig, FeedConfig, FeedProfile, LimitsConfig, MongoConfig, MongoSettings,
+            RequestTimeoutConfig,
+        },
+        export_storage::{ExportStorage, LocalStorage},
+        exports::download_export,
+        feeds::product_feed,
+        handlers::preview_products_import,
+        search_engine::{
+            search_sync_status, start_search_sync, EngineQuery, SearchDocument, SearchEngine, SearchPage, SearchSync,
+        },
+        timeouts,
+        validation::{self, ValidatedQuery},
+    };
+
+    fn problem_from(status: StatusCode, body: &str) -> Value {
+        serde_json::to_value(ApiError::from_body(status, body.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn message_becomes_the_detail_and_other_members_are_kept() {
+        let problem = problem_from(StatusCode::CONFLICT, r#"{"message": "Slug taken", "code": "slug_taken"}"#);
+        assert_eq!(
+            problem,
+            json!({
+                "type": "about:blank",
+                "title": "Conflict",
+                "status": 409,
+                "detail": "Slug taken",
+                "code": "slug_taken",
+            })
+        );
+    }
+
+    #[test]
+    fn body_cannot_override_standard_members() {
+        let problem = problem_from(StatusCode::BAD_REQUEST, r#"{"detail": "Bad page", "status": 200, "type": "x"}"#);
+        assert_eq!(problem["status"], 400);
+        assert_eq!(problem["type"], "about:blank");
+        assert_eq!(problem["detail"], "Bad page");
+    }
+
+    #[test]
+    fn string_body_becomes_the_detail() {
+        let problem = problem_from(StatusCode::NOT_FOUND, r#""Product not found""#);
+        assert_eq!(problem["detail"], "Product not found");
+    }
+
+    #[test]
+    fn null_and_empty_bodies_have_no_detail() {
+        for body in ["null", "", "  \n"] {
+            let problem = problem_from(StatusCode::UNAUTHORIZED, body);
+            assert_eq!(problem, json!({ "type": "about:blank", "title": "Unauthorized", "status": 401 }));
+        }
+    }
+
+    #[test]
+    fn other_json_is_kept_as_errors() {
+        let problem = problem_from(StatusCode::UNPROCESSABLE_ENTITY, r#"[{"line": 2}]"#);
+        assert_eq!(problem["errors"], json!([{ "line": 2 }]));
+        assert!(problem.get("detail").is_none());
+    }
+
+    #[test]
+    fn text_body_becomes_the_detail() {
+        let problem = problem_from(StatusCode::INTERNAL_SERVER_ERROR, "Database error: connection refused\n");
+        assert_eq!(problem["detail"], "Database error: connection refused");
+    }
+
+    async fn body_of(res: HttpResponse) -> Bytes {
+        to_bytes(res.into_body()).await.unwrap()
+    }
+
+    #[actix_web::test]
+    async fn successful_responses_are_left_alone() {
+        let res = into_problem(HttpResponse::Ok().json(json!({ "message": "ok" })), None).await;
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/json");
+        assert_eq!(body_of(res).await, r#"{"message":"ok"}"#);
+    }
+
+    #[actix_web::test]
+    async fn error_bodies_are_rewritten() {
+        let res = into_problem(HttpResponse::BadRequest().json(json!({ "message": "Invalid ID" })), None).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), PROBLEM_JSON);
+        let problem: Value = serde_json::from_slice(&body_of(res).await).unwrap();
+        assert_eq!(problem["detail"], "Invalid ID");
+    }
+
+    #[actix_web::test]
+    async fn oversized_bodies_are_left_alone() {
+        let body = "x".repeat(MAX_CONVERTED_BODY + 1);
+        let res = into_problem(HttpResponse::BadRequest().content_type("text/plain").body(body.clone()), None).await;
+        assert!(res.headers().get(header::CONTENT_TYPE).is_some_and(|content_type| content_type != PROBLEM_JSON));
+        assert_eq!(body_of(res).await, body);
+    }
+
+    #[actix_web::test]
+    async fn streamed_bodies_are_left_alone() {
+        let chunks = stream::iter([Ok::<_, Error>(Bytes::from_static(b"partial report"))]);
+        let res = into_problem(HttpResponse::InternalServerError().streaming(chunks), None).await;
+        assert!(res.headers().get(header::CONTENT_TYPE).is_some_and(|content_type| content_type != PROBLEM_JSON));
+        assert_eq!(body_of(res).await, "partial report");
+    }
+
+    #[derive(Deserialize, Validate)]
+    struct PageQuery {
+        #[validate(range(min = 1, max = 100, message = "per_page must be between 1 and 100"))]
+        per_page: u32,
+    }
+
+    // The error handling middleware in the order main wraps it
+    macro_rules! app {
+        () => {
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new(RequestTimeoutConfig { default_secs: 1, routes: Vec::new() }))
+                    .wrap(from_fn(timeouts::abort_slow_requests))
+                    .wrap(from_fn(json_method_not_allowed))
+                    .wrap(from_fn(problem_details))
+                    .wrap(TracingLogger::default())
+                    .service(web::resource("/api/items").route(web::get().to(|| async { HttpResponse::Ok().finish() })))
+                    .route(
+                        "/api/slow",
+                        web::get().to(|| async {
+                            tokio::time::sleep(Duration::from_secs(5)).await;
+                            HttpResponse::Ok().finish()
+                        }),
+                    )
+                    .route(
+                        "/api/pages",
+                        web::get().to(|query: ValidatedQuery<PageQuery>| async move {
+                            HttpResponse::Ok().body(query.per_page.to_string())
+                        }),
+                    )
+                    .route(
+                        "/api/broken",
+                        web::get().to(|| async {
+                            Err::<HttpResponse, _>(ApiError::internal(
+                                "Database error: connection refused",
+                            ))
+                        }),
+                    )
+                    .default_service(web::to(not_found)),
+            )
+            .await
+        };
+    }
+
+    // What `app` answers `request` with; errors that reach the server are
+    // answered with their response
+    macro_rules! respond {
+        ($app:expr, $request:expr) => {
+            match test::try_call_service(&$app, $request.to_request()).await {
+                Ok(res) => res.into_parts().1.map_into_boxed_body(),
+                Err(e) => e.error_response(),
+            }
+        };
+    }
+
+    async fn call(request: TestRequest, status: StatusCode) -> Value {
+        let app = app!();
+        problem_of(respond!(app, request), status).await
+    }
+
+    // The problem document in `res`, checking it has `status` and names the request
+    async fn problem_of(res: HttpResponse, status: StatusCode) -> Value {
+        assert_eq!(res.status(), status);
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), PROBLEM_JSON);
+        let problem: Value = serde_json::from_slice(&body_of(res).await).unwrap();
+        assert_eq!(problem["status"], status.as_u16());
+        assert!(problem["instance"].as_str().is_some_and(|instance| instance.starts_with("urn:uuid:")));
+        problem
+    }
+
+    #[actix_web::test]
+    async fn unknown_route_is_a_404_problem() {
+        let problem = call(TestRequest::get().uri("/api/nothing"), StatusCode::NOT_FOUND).await;
+        assert_eq!(problem["detail"], "No route for GET /api/nothing");
+        assert_eq!(problem["code"], "not_found");
+    }
+
+    #[actix_web::test]
+    async fn wrong_method_is_a_405_problem() {
+        let problem = call(TestRequest::delete().uri("/api/items"), StatusCode::METHOD_NOT_ALLOWED).await;
+        assert_eq!(problem["detail"], "Method DELETE is not allowed on /api/items");
+        assert_eq!(problem["code"], "method_not_allowed");
+    }
+
+    #[actix_web::test]
+    async fn slow_request_is_a_504_problem() {
+        let problem = call(TestRequest::get().uri("/api/slow"), StatusCode::GATEWAY_TIMEOUT).await;
+        assert_eq!(problem["code"], "request_timeout");
+    }
+
+    #[actix_web::test]
+    async fn failed_validation_is_a_400_problem() {
+        let problem = call(TestRequest::get().uri("/api/pages?per_page=500"), StatusCode::BAD_REQUEST).await;
+        assert_eq!(problem["detail"], "per_page must be between 1 and 100");
+        assert!(problem["errors"]["per_page"].is_array());
+    }
+
+    #[actix_web::test]
+    async fn database_error_is_a_500_problem() {
+        let problem = call(TestRequest::get().uri("/api/broken"), StatusCode::INTERNAL_SERVER_ERROR).await;
+        assert_eq!(problem["detail"], "Database error: connection refused");
+    }
+
+    // An engine that never answers, so a sync started on it keeps running
+    struct StalledEngine;
+
+    impl SearchEngine for StalledEngine {
+        fn name(&self) -> &'static str {
+            "stalled"
+        }
+
+        fn configure(&self) -> BoxFuture<'_, Result<(), String>> {
+            Box::pin(future::pending())
+        }
+
+        fn upsert<'a>(&'a self, _: &'a [SearchDocument]) -> BoxFuture<'a, Result<(), String>> {
+            Box::pin(future::pending())
+        }
+
+        fn delete<'a>(&'a self, _: &'a [String]) -> BoxFuture<'a, Result<(), String>> {
+            Box::pin(future::pending())
+        }
+
+        fn search<'a>(&'a self, _: &'a EngineQuery) -> BoxFuture<'a, Result<SearchPage, String>> {
+            Box::pin(future::pending())
+        }
+
+        fn documents(&self, _: usize, _: usize) -> BoxFuture<'_, Result<Vec<SearchDocument>, String>> {
+            Box::pin(future::pending())
+        }
+    }
+
+    // An access token with `scopes` and no session, so nothing is looked up
+    fn token(scopes: &[&str]) -> String {
+        auth::init(&AuthConfig {
+            jwt_secret: None,
+            refresh_secret: None,
+            two_factor_secret: None,
+            totp_issuer: "Products API".to_string(),
+        });
+        let now = Utc::now().timestamp();
+        let claims = Claims {
+            sub: ObjectId::new().to_hex(),
+            exp: now + 60,
+            iat: now,
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+            sid: None,
+            jti: None,
+            impersonator: None,
+        };
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(auth::jwt_secret())).unwrap()
+    }
+
+    // A spreadsheet upload that isn't one
+    fn upload(uri: &str, token: &str) -> TestRequest {
+        let body = "--BOUNDARY\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"products.xlsx\"\r\n\
+            Content-Type: application/octet-stream\r\n\r\n\
+            not a spreadsheet\r\n\
+            --BOUNDARY--\r\n";
+        TestRequest::post()
+            .uri(uri)
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+            .insert_header((header::CONTENT_TYPE, "multipart/form-data; boundary=BOUNDARY"))
+            .set_payload(body)
+    }
+
+    // Real handlers at their paths in main, with the app data their failure
+    // paths need. MongoDB is unreachable, and none of them get that far.
+    macro_rules! routes_app {
+        () => {{
+            let db = MongoConfig::init(&MongoSettings {
+                uri: "mongodb://127.0.0.1:1".to_string(),
+                database_name: "products_test".to_string(),
+                timeout_ms: 100,
+                breaker_failures: 5,
+                breaker_open_secs: 30,
+                event_sourcing: false,
+                catalog_max_staleness_secs: None,
+                catalog_read_preference: None,
+                catalog_read_concern: None,
+                transactional_read_concern: None,
+            })
+            .await
+            .unwrap();
+            let feed = FeedProfile {
+                access_token: "feed-token".to_string(),
+                link_template: "https://shop.example/products/{id}".to_string(),
+                image_link_template: None,
+                brand: None,
+                currency: "USD".to_string(),
+                category_map: HashMap::new(),
+            };
+            let exports = ExportConfig {
+                dir: std::env::temp_dir(),
+                s3: None,
+                url_ttl_secs: 60,
+                retention_hours: 1,
+                signing_key: b"export-signing-key".to_vec(),
+            };
+            let storage: Arc<dyn ExportStorage> = Arc::new(LocalStorage::new(exports.dir.clone()));
+            let limits = LimitsConfig { json_payload_bytes: 1024, upload_bytes: 1024, csv_max_rows: 10, max_per_page: 100 };
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new(db))
+                    .app_data(web::Data::new(limits))
+                    .app_data(web::Data::new(FeedConfig { profiles: HashMap::from([("google".to_string(), feed)]) }))
+                    .app_data(web::Data::new(exports))
+                    .app_data(web::Data::from(storage))
+                    .app_data(web::Data::new(SearchSync::new(Arc::new(StalledEngine))))
+                    .app_data(web::QueryConfig::default().error_handler(validation::query_error_handler))
+                    .wrap(from_fn(problem_details))
+                    .wrap(TracingLogger::default())
+                    .service(
+                        web::scope("/api")
+                            .service(web::resource("/feeds/{profile}/{format}").route(web::get().to(product_feed)))
+                            .service(web::resource("/exports/{id}/download").route(web::get().to(download_export)))
+                            .service(
+                                web::scope("/products").wrap(AuthMiddleware).service(
+                                    web::resource("/import/preview").route(
+                                        web::post()
+                                            .to(preview_products_import)
+                                            .wrap(RequireScope::new(SCOPE_PRODUCTS_IMPORT)),
+                                    ),
+                                ),
+                            )
+                            .service(
+                                web::scope("/admin")
+                                    .wrap(RequireScope::new(SCOPE_ADMIN))
+                                    .wrap(AuthMiddleware)
+                                    .service(
+                                        web::resource("/search/sync")
+                                            .route(web::get().to(search_sync_status))
+                                            .route(web::post().to(start_search_sync)),
+                                    ),
+                            ),
+                    ),
+            )
+            .await
+        }};
+    }
+
+    #[actix_web::test]
+    async fn invalid_preview_query_is_a_400_problem() {
+        let app = routes_app!();
+        let request = upload("/api/products/import/preview?rows=500", &token(&[SCOPE_PRODUCTS_IMPORT]));
+        let problem = problem_of(respond!(app, request), StatusCode::BAD_REQUEST).await;
+        assert_eq!(problem["detail"], "rows must be between 1 and 100");
+        assert!(problem["errors"]["rows"].is_array());
+    }
+
+    #[actix_web::test]
+    async fn missing_or_wrong_credentials_are_401_problems() {
+        let app = routes_app!();
+        let request = TestRequest::post().uri("/api/products/import/preview");
+        let problem = problem_of(respond!(app, request), StatusCode::UNAUTHORIZED).await;
+        assert_eq!(problem["detail"], "No authorization header");
+
+        let request = TestRequest::get().uri("/api/feeds/google/google.xml?token=guess");
+        let problem = problem_of(respond!(app, request), StatusCode::UNAUTHORIZED).await;
+        assert_eq!(problem["detail"], "Invalid or missing feed token");
+    }
+
+    #[actix_web::test]
+    async fn missing_scope_or_bad_signature_are_403_problems() {
+        let app = routes_app!();
+        let request = upload("/api/products/import/preview", &token(&[SCOPE_PRODUCTS_READ]));
+        let problem = problem_of(respond!(app, request), StatusCode::FORBIDDEN).await;
+        assert_eq!(problem["detail"], format!("Missing required scope: {}", SCOPE_PRODUCTS_IMPORT));
+
+        let expires = Utc::now().timestamp() + 60;
+        let uri = format!("/api/exports/{}/download?expires={}&signature=forged", ObjectId::new(), expires);
+        let problem = problem_of(respond!(app, TestRequest::get().uri(&uri)), StatusCode::FORBIDDEN).await;
+        assert_eq!(problem["detail"], "Invalid download signature");
+    }
+
+    #[actix_web::test]
+    async fn unknown_feed_is_a_404_problem() {
+        let app = routes_app!();
+        let request = TestRequest::get().uri("/api/feeds/bing/google.xml?token=feed-token");
+        let problem = problem_of(respond!(app, request), StatusCode::NOT_FOUND).await;
+        assert_eq!(problem["title"], "Not Found");
+
+        let request = TestRequest::get().uri("/api/feeds/google/bing.xml?token=feed-token");
+        problem_of(respond!(app, request), StatusCode::NOT_FOUND).await;
+    }
+
+    #[actix_web::test]
+    async fn second_search_sync_is_a_409_problem() {
+        let app = routes_app!();
+        let token = token(&[SCOPE_ADMIN]);
+        let start = || {
+            TestRequest::post()
+                .uri("/api/admin/search/sync")
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .set_json(json!({ "mode": "check" }))
+        };
+        assert_eq!(respond!(app, start()).status(), StatusCode::ACCEPTED);
+
+        // The first sync starts in the background and stalls on the engine
+        let status = || {
+            TestRequest::get()
+                .uri("/api/admin/search/sync")
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+        };
+        let mut running = false;
+        for _ in 0..50 {
+            let body: Value = serde_json::from_slice(&body_of(respond!(app, status())).await).unwrap();
+            running = body["running"] == true;
+            if running {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(running);
+
+        let problem = problem_of(respond!(app, start()), StatusCode::CONFLICT).await;
+        assert_eq!(problem["detail"], "A search index sync is already running");
+    }
+
+    #[actix_web::test]
+    async fn unreadable_upload_is_a_422_problem() {
+        let app = routes_app!();
+        let request = upload("/api/products/import/preview", &token(&[SCOPE_PRODUCTS_IMPORT]));
+        let problem = problem_of(respond!(app, request), StatusCode::UNPROCESSABLE_ENTITY).await;
+        assert!(problem["detail"].as_str().is_some_and(|detail| detail.starts_with("Could not read file")));
+    }
+}
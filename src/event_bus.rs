@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use futures::future::BoxFuture;
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::{
+    config::EventBusConfig,
+    events::{DomainEvent, EventHub},
+};
+
+/// An external destination for domain events. Each delivery channel
+/// implements this and gets the same subjects, payloads and forwarding loop.
+pub trait EventBus: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn publish<'a>(&'a self, subject: &'a str, payload: &'a [u8]) -> BoxFuture<'a, Result<(), String>>;
+}
+
+/// Subject of an event below the configured prefix, e.g. `product.created`.
+pub fn subject(event: &DomainEvent) -> &'static str {
+    match event {
+        DomainEvent::ProductCreated { .. } => "product.created",
+        DomainEvent::ProductUpdated { .. } => "product.updated",
+        DomainEvent::ProductDeleted { .. } => "product.deleted",
+        DomainEvent::LowStock { .. } => "product.low_stock",
+        DomainEvent::ImportCompleted { .. } => "import.completed",
+    }
+}
+
+/// JSON message body: the event subject, when it was forwarded and the event itself.
+pub fn payload(event: &DomainEvent) -> Result<Vec<u8>, serde_json::Error> {
+    serde_json::to_vec(&json!({
+        "type": subject(event),
+        "occurred_at": Utc::now().to_rfc3339(),
+        "data": event,
+    }))
+}
+
+/// Forwards every event published on `events` to `bus` until the hub closes.
+/// Delivery is best effort: failures are logged and the event is dropped.
+pub fn spawn_forwarder(events: &EventHub, bus: Arc<dyn EventBus>, subject_prefix: String) {
+    let mut receiver = events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("{} forwarder lagged, dropped {} events", bus.name(), skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            let subject = format!("{}.{}", subject_prefix, subject(&event));
+            let body = match payload(&event) {
+                Ok(body) => body,
+                Err(e) => {
+                    warn!("Failed to serialize event for {}: {}", bus.name(), e);
+                    continue;
+                }
+            };
+            if let Err(e) = bus.publish(&subject, &body).await {
+                warn!("Failed to publish {} to {}: {}", subject, bus.name(), e);
+            }
+        }
+    });
+}
+
+fn connect(url: &str) -> Result<Arc<dyn EventBus>, String> {
+    match url.split_once("://").map(|(scheme, _)| scheme) {
+        #[cfg(feature = "nats")]
+        Some("nats") => Ok(Arc::new(crate::nats::NatsBus::from_url(url)?)),
+        #[cfg(not(feature = "nats"))]
+        Some("nats") => Err("this build lacks the `nats` feature".to_string()),
+        _ => Err("unsupported scheme, only nats:// is supported".to_string()),
+    }
+}
+
+/// Starts forwarding events to the broker in EVENT_BUS_URL, if one is set.
+pub fn start(events: &EventHub, config: EventBusConfig) {
+    let Some(url) = config.url else {
+        return;
+    };
+
+    match connect(&url) {
+        Ok(bus) => {
+            info!("Forwarding events to {} with subject prefix {}", bus.name(), config.subject_prefix);
+            spawn_forwarder(events, bus, config.subject_prefix);
+        }
+        Err(e) => warn!("Events are not forwarded to EVENT_BUS_URL: {}", e),
+    }
+}
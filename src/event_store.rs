@@ -0,0 +1,455 @@
+use std::collections::BTreeMap;
+
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Bson, DateTime, Document},
+    options::{FindOneAndUpdateOptions, FindOptions, ReplaceOptions, ReturnDocument},
+    ClientSession, Collection,
+};
+use chrono::{DateTime as ChronoDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+use validator::Validate;
+
+use crate::{
+    config::MongoConfig,
+    errors::ApiError,
+    models::{Product, ProductStatus},
+    money::{self, Decimal},
+    public_ids,
+    validation::ValidatedQuery,
+};
+
+// Maintained outside the product aggregate, so kept as-is when the projection is rebuilt
+const UNTRACKED_FIELDS: [&str; 1] = ["view_count"];
+
+/// Something that happened to a product. With event sourcing enabled these
+/// are the source of truth and the products collection is their projection.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProductEvent {
+    ProductCreated { product: Document },
+    // Changed fields other than price and status, with their new values
+    ProductUpdated { fields: Document },
+    PriceChanged {
+        #[serde(with = "money::price")]
+        price: Decimal,
+    },
+    StatusChanged { status: ProductStatus },
+    StockAdjusted { delta: i64 },
+    SaleEnded,
+    ProductDeleted {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        deleted_by: Option<ObjectId>,
+    },
+    ProductRestored { product: Document },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredEvent {
+    // Global sequence number; replaying in this order rebuilds the projection
+    #[serde(rename = "_id")]
+    pub sequence: i64,
+    pub product_id: ObjectId,
+    pub event: ProductEvent,
+    pub occurred_at: DateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StoredEventResponse {
+    pub sequence: i64,
+    #[serde(flatten)]
+    pub event: ProductEvent,
+    pub occurred_at: String,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeType {
+    Created,
+    Updated,
+    Deleted,
+    Restored,
+}
+
+/// One entry of the change feed: which product changed, how, and which of
+/// its fields are affected.
+#[derive(Debug, Serialize)]
+pub struct ProductChange {
+    pub sequence: i64,
+    pub product_id: String,
+    pub change_type: ChangeType,
+    pub fields: Vec<String>,
+    pub occurred_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChangesPage {
+    pub changes: Vec<ProductChange>,
+    // Pass as `since` to get the changes after this page
+    pub next: Option<String>,
+    pub has_more: bool,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ListChangesQuery {
+    // A sequence number from `next`, or an RFC 3339 time
+    since: Option<String>,
+    #[validate(range(min = 1, max = 1000, message = "limit must be between 1 and 1000"))]
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub events: usize,
+    pub rebuilt: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+}
+
+fn events_collection(db: &MongoConfig) -> Collection<StoredEvent> {
+    db.database.collection("product_events")
+}
+
+fn snapshot(mut product: Document) -> Document {
+    product.remove("_id");
+    for field in UNTRACKED_FIELDS {
+        product.remove(field);
+    }
+    product
+}
+
+impl ProductEvent {
+    pub fn created(product: &Product) -> Result<Self, mongodb::bson::ser::Error> {
+        Ok(ProductEvent::ProductCreated { product: snapshot(mongodb::bson::to_document(product)?) })
+    }
+
+    pub fn restored(product: Document) -> Self {
+        ProductEvent::ProductRestored { product: snapshot(product) }
+    }
+
+    /// Splits the `$set` document of a product update into events.
+    pub fn for_update(set: &Document) -> Vec<Self> {
+        let mut events = Vec::new();
+        let mut fields = set.clone();
+        if let Some(price) = fields.remove("price").and_then(|price| money::from_bson(&price)) {
+            events.push(ProductEvent::PriceChanged { price });
+        }
+        if let Some(status) = fields.remove("status").and_then(|s| s.as_str().and_then(|s| s.parse().ok())) {
+            events.push(ProductEvent::StatusChanged { status });
+        }
+        if !fields.is_empty() {
+            events.push(ProductEvent::ProductUpdated { fields });
+        }
+        events
+    }
+
+    /// The product after this event, or None once it is deleted.
+    pub fn apply(&self, product_id: ObjectId, state: Option<Document>) -> Option<Document> {
+        let with_id = |product: &Document| {
+            let mut product = product.clone();
+            product.insert("_id", product_id);
+            product
+        };
+
+        match self {
+            ProductEvent::ProductCreated { product } | ProductEvent::ProductRestored { product } => {
+                Some(with_id(product))
+            }
+            ProductEvent::ProductDeleted { .. } => None,
+            // Changes to a product that is not in the projection have nothing to change
+            _ => {
+                let mut product = state?;
+                match self {
+                    ProductEvent::ProductUpdated { fields } => product.extend(fields.clone()),
+                    ProductEvent::PriceChanged { price } => {
+                        product.insert("price", money::to_bson(*price));
+                    }
+                    ProductEvent::StatusChanged { status } => {
+                        product.insert("status", status.to_string());
+                    }
+                    ProductEvent::StockAdjusted { delta } => {
+                        if let Ok(quantity) = product.get_i64("stock_quantity") {
+                            product.insert("stock_quantity", quantity + delta);
+                        }
+                    }
+                    ProductEvent::SaleEnded => {
+                        product.insert("has_active_sale", false);
+                        product.remove("sale_ends_at");
+                    }
+                    _ => unreachable!("handled above"),
+                }
+                Some(product)
+            }
+        }
+    }
+}
+
+impl StoredEvent {
+    /// The event as a change feed entry.
+    pub fn change(&self) -> ProductChange {
+        let keys = |fields: &Document| fields.keys().cloned().collect::<Vec<_>>();
+        let (change_type, fields) = match &self.event {
+            ProductEvent::ProductCreated { product } => (ChangeType::Created, keys(product)),
+            ProductEvent::ProductRestored { product } => (ChangeType::Restored, keys(product)),
+            ProductEvent::ProductDeleted { .. } => (ChangeType::Deleted, Vec::new()),
+            ProductEvent::ProductUpdated { fields } => (ChangeType::Updated, keys(fields)),
+            ProductEvent::PriceChanged { .. } => (ChangeType::Updated, vec!["price".to_string()]),
+            ProductEvent::StatusChanged { .. } => (ChangeType::Updated, vec!["status".to_string()]),
+            ProductEvent::StockAdjusted { .. } => (ChangeType::Updated, vec!["stock_quantity".to_string()]),
+            ProductEvent::SaleEnded => {
+                (ChangeType::Updated, vec!["has_active_sale".to_string(), "sale_ends_at".to_string()])
+            }
+        };
+        ProductChange {
+            sequence: self.sequence,
+            product_id: self.product_id.to_string(),
+            change_type,
+            fields,
+            occurred_at: self.occurred_at.try_to_rfc3339_string().unwrap_or_default(),
+        }
+    }
+}
+
+// Sequence numbers come from a counter outside any transaction, so an aborted
+// transaction leaves a gap rather than holding the counter
+async fn next_sequences(db: &MongoConfig, count: usize) -> Result<i64, mongodb::error::Error> {
+    let counters: Collection<Document> = db.database.collection("counters");
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .build();
+    let counter = counters
+        .find_one_and_update(doc! { "_id": "product_events" }, doc! { "$inc": { "sequence": count as i64 } }, options)
+        .await?
+        .unwrap_or_default();
+    Ok(counter.get_i64("sequence").unwrap_or(count as i64) - count as i64 + 1)
+}
+
+async fn stored(db: &MongoConfig, product_id: ObjectId, events: Vec<ProductEvent>) -> Result<Vec<StoredEvent>, mongodb::error::Error> {
+    stored_for_products(db, events.into_iter().map(|event| (product_id, event)).collect()).await
+}
+
+async fn stored_for_products(
+    db: &MongoConfig,
+    events: Vec<(ObjectId, ProductEvent)>,
+) -> Result<Vec<StoredEvent>, mongodb::error::Error> {
+    let first = next_sequences(db, events.len()).await?;
+    let occurred_at = DateTime::now();
+    Ok(events
+        .into_iter()
+        .enumerate()
+        .map(|(i, (product_id, event))| StoredEvent { sequence: first + i as i64, product_id, event, occurred_at })
+        .collect())
+}
+
+/// Appends events for a product that was just changed. Does nothing unless
+/// event sourcing is enabled; a failure is logged since the change itself
+/// has already been made.
+pub async fn record(db: &MongoConfig, product_id: ObjectId, events: Vec<ProductEvent>) {
+    if !db.event_sourcing || events.is_empty() {
+        return;
+    }
+    let result = match stored(db, product_id, events).await {
+        Ok(events) => events_collection(db).insert_many(events, None).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        error!("Failed to record events for product {}; its history is incomplete: {}", product_id, e);
+    }
+}
+
+/// Appends events for many products at once, e.g. the ones an import batch
+/// created, with a single insert. Failures are logged like `record`'s.
+pub async fn record_many(db: &MongoConfig, events: Vec<(ObjectId, ProductEvent)>) {
+    if !db.event_sourcing || events.is_empty() {
+        return;
+    }
+    let count = events.len();
+    let result = match stored_for_products(db, events).await {
+        Ok(events) => events_collection(db).insert_many(events, None).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        error!("Failed to record events for {} products; their history is incomplete: {}", count, e);
+    }
+}
+
+/// Appends events as part of the transaction in `session`.
+pub async fn record_with_session(
+    db: &MongoConfig,
+    session: &mut ClientSession,
+    product_id: ObjectId,
+    events: Vec<ProductEvent>,
+) -> Result<(), mongodb::error::Error> {
+    if !db.event_sourcing || events.is_empty() {
+        return Ok(());
+    }
+    let events = stored(db, product_id, events).await?;
+    events_collection(db).insert_many_with_session(events, None, session).await?;
+    Ok(())
+}
+
+/// Records the current state of every product that has no events yet, so a
+/// catalog that predates event sourcing can be replayed.
+pub async fn snapshot_products(db: &MongoConfig) -> Result<usize, mongodb::error::Error> {
+    let with_history: Vec<Bson> = events_collection(db).distinct("product_id", None, None).await?;
+    let products: Collection<Document> = db.database.collection("products");
+    let mut cursor = products.find(doc! { "_id": { "$nin": with_history } }, None).await?;
+
+    let mut recorded = 0;
+    while let Some(product) = cursor.try_next().await? {
+        let Ok(product_id) = product.get_object_id("_id") else {
+            continue;
+        };
+        let events = stored(db, product_id, vec![ProductEvent::ProductCreated { product: snapshot(product) }]).await?;
+        events_collection(db).insert_many(events, None).await?;
+        recorded += 1;
+    }
+    Ok(recorded)
+}
+
+/// Rebuilds every product that has events from its history. Products without
+/// history are left alone. With `dry_run` only counts what would change.
+pub async fn replay(db: &MongoConfig, dry_run: bool) -> Result<ReplaySummary, mongodb::error::Error> {
+    let mut summary = ReplaySummary::default();
+    let mut states: BTreeMap<ObjectId, Option<Document>> = BTreeMap::new();
+
+    let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+    let mut cursor = events_collection(db).find(None, options).await?;
+    while let Some(stored) = cursor.try_next().await? {
+        let state = states.remove(&stored.product_id).flatten();
+        states.insert(stored.product_id, stored.event.apply(stored.product_id, state));
+        summary.events += 1;
+    }
+
+    let products: Collection<Document> = db.database.collection("products");
+    for (product_id, state) in states {
+        let current = products.find_one(doc! { "_id": product_id }, None).await?;
+        match (state, current) {
+            (Some(mut rebuilt), current) => {
+                let current = current.unwrap_or_default();
+                if snapshot(current.clone()) == snapshot(rebuilt.clone()) {
+                    summary.unchanged += 1;
+                    continue;
+                }
+                for field in UNTRACKED_FIELDS {
+                    if let Some(value) = current.get(field) {
+                        rebuilt.insert(field, value.clone());
+                    }
+                }
+                if !dry_run {
+                    let options = ReplaceOptions::builder().upsert(true).build();
+                    products.replace_one(doc! { "_id": product_id }, rebuilt, options).await?;
+                }
+                summary.rebuilt += 1;
+            }
+            (None, Some(_)) => {
+                if !dry_run {
+                    products.delete_one(doc! { "_id": product_id }, None).await?;
+                }
+                summary.removed += 1;
+            }
+            (None, None) => summary.unchanged += 1,
+        }
+    }
+
+    Ok(summary)
+}
+
+/// The recorded history of a product, oldest first.
+pub async fn product_history(db: web::Data<MongoConfig>, id: web::Path<String>) -> Result<HttpResponse, Error> {
+    let product_id = public_ids::resolve_product_id(&db, &id).await?;
+
+    let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+    let events: Vec<StoredEvent> = events_collection(&db)
+        .find(doc! { "product_id": product_id }, options)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch history of product {}: {}", product_id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?
+        .try_collect()
+        .await
+        .map_err(|e| {
+            error!("Error while iterating history of product {}: {}", product_id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+
+    if events.is_empty() {
+        debug!("No history for product {}", product_id);
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    }
+
+    info!("Returning {} events for product {}", events.len(), product_id);
+    let events: Vec<StoredEventResponse> = events
+        .into_iter()
+        .map(|stored| StoredEventResponse {
+            sequence: stored.sequence,
+            event: stored.event,
+            occurred_at: stored.occurred_at.try_to_rfc3339_string().unwrap_or_default(),
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(events))
+}
+
+/// Product changes in the order they were recorded, for clients that sync
+/// deltas instead of the whole catalog. `since` takes the `next` value of the
+/// previous page, or a time to start from.
+pub async fn product_changes(
+    db: web::Data<MongoConfig>,
+    query: ValidatedQuery<ListChangesQuery>,
+) -> Result<HttpResponse, Error> {
+    if !db.event_sourcing {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The change feed needs event sourcing (EVENT_SOURCING=true)",
+        ).into());
+    }
+
+    let filter = match query.since.as_deref() {
+        None => Document::new(),
+        Some(since) => match since.parse::<i64>() {
+            Ok(sequence) => doc! { "_id": { "$gt": sequence } },
+            Err(_) => match since.parse::<ChronoDateTime<Utc>>() {
+                Ok(at) => doc! { "occurred_at": { "$gte": DateTime::from_millis(at.timestamp_millis()) } },
+                Err(_) => {
+                    return Err(ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        "since must be a sequence number or an RFC 3339 time",
+                    ).into());
+                }
+            },
+        },
+    };
+    let limit = query.limit.unwrap_or(100);
+
+    // One extra event tells whether there is another page
+    let options = FindOptions::builder().sort(doc! { "_id": 1 }).limit(limit + 1).build();
+    let mut events: Vec<StoredEvent> = events_collection(&db)
+        .find(filter, options)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch product changes: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?
+        .try_collect()
+        .await
+        .map_err(|e| {
+            error!("Error while iterating product changes: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+
+    let has_more = events.len() as i64 > limit;
+    events.truncate(limit as usize);
+    let next = events.last().map(|stored| stored.sequence.to_string()).or_else(|| query.since.clone());
+
+    debug!("Returning {} product changes", events.len());
+    Ok(HttpResponse::Ok().json(ChangesPage {
+        changes: events.iter().map(StoredEvent::change).collect(),
+        next,
+        has_more,
+    }))
+}
@@ -0,0 +1,137 @@
+use std::sync::{Arc, Mutex};
+
+use actix_web::{web, web::Bytes, Error, HttpResponse};
+use futures::stream;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, warn};
+
+const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DomainEvent {
+    ProductCreated {
+        product_id: String,
+    },
+    ProductUpdated {
+        product_id: String,
+    },
+    ProductDeleted {
+        product_id: String,
+    },
+    LowStock {
+        product_id: String,
+        name: String,
+        stock_quantity: i64,
+        threshold: i64,
+    },
+    // One CSV or XLSX file finished importing
+    ImportCompleted {
+        import_id: String,
+        imported: usize,
+        failed: usize,
+    },
+}
+
+impl DomainEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DomainEvent::ProductCreated { .. } => "product_created",
+            DomainEvent::ProductUpdated { .. } => "product_updated",
+            DomainEvent::ProductDeleted { .. } => "product_deleted",
+            DomainEvent::LowStock { .. } => "low_stock",
+            DomainEvent::ImportCompleted { .. } => "import_completed",
+        }
+    }
+
+    pub fn product_id(&self) -> Option<&str> {
+        match self {
+            DomainEvent::ProductCreated { product_id }
+            | DomainEvent::ProductUpdated { product_id }
+            | DomainEvent::ProductDeleted { product_id }
+            | DomainEvent::LowStock { product_id, .. } => Some(product_id),
+            DomainEvent::ImportCompleted { .. } => None,
+        }
+    }
+}
+
+// In-process fan-out of domain events to live subscribers. Clones share
+// the subscribers
+#[derive(Clone)]
+pub struct EventHub {
+    sender: broadcast::Sender<DomainEvent>,
+    // Set on hubs from `held`: what was published and not yet released
+    held: Option<Arc<Mutex<Vec<DomainEvent>>>>,
+}
+
+impl Default for EventHub {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        EventHub { sender, held: None }
+    }
+}
+
+impl EventHub {
+    pub fn publish(&self, event: DomainEvent) {
+        if let Some(held) = &self.held {
+            held.lock().unwrap().push(event);
+            return;
+        }
+        debug!("Publishing event: {:?}", event);
+        // An error only means nobody is listening right now
+        let _ = self.sender.send(event);
+    }
+
+    /// A hub for changes that may still be undone: what is published to it
+    /// reaches the subscribers of this one on `release`, and never if the
+    /// hub is dropped first.
+    pub fn held(&self) -> EventHub {
+        EventHub { sender: self.sender.clone(), held: Some(Arc::default()) }
+    }
+
+    /// Publishes what a hub from `held` kept back, in order.
+    pub fn release(&self) {
+        let Some(held) = &self.held else { return };
+        for event in std::mem::take(&mut *held.lock().unwrap()) {
+            debug!("Publishing event: {:?}", event);
+            let _ = self.sender.send(event);
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<DomainEvent> {
+        self.sender.subscribe()
+    }
+}
+
+/// Streams domain events as Server-Sent Events.
+pub async fn stream_events(events: web::Data<EventHub>) -> Result<HttpResponse, Error> {
+    let receiver = events.subscribe();
+
+    let body = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let payload = match serde_json::to_string(&event) {
+                        Ok(payload) => payload,
+                        Err(e) => {
+                            warn!("Failed to serialize event: {}", e);
+                            continue;
+                        }
+                    };
+                    let frame = Bytes::from(format!("data: {}\n\n", payload));
+                    return Some((Ok::<_, Error>(frame), receiver));
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Event subscriber lagged, skipped {} events", skipped);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body))
+}
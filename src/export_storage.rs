@@ -0,0 +1,245 @@
+use std::{io, path::PathBuf, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::config::{ExportConfig, S3Config};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Where finished export files are kept. Jobs only deal with keys; each
+/// backend decides how they map to files or objects.
+pub trait ExportStorage: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<(), String>>;
+
+    // None when nothing is stored under the key
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, String>>;
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), String>>;
+
+    /// A link clients can download the file from directly, valid for `ttl`.
+    /// None when files are only served through the API.
+    fn presigned_url(&self, key: &str, ttl: Duration) -> Option<String>;
+}
+
+/// Files in a local directory, served through the API's download endpoint.
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(dir: PathBuf) -> Self {
+        LocalStorage { dir }
+    }
+
+    // Keys are generated by the export jobs, but never let one leave the directory
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        if key.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid key: {}", key)));
+        }
+        Ok(self.dir.join(key))
+    }
+}
+
+impl ExportStorage for LocalStorage {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let path = self.path(key).map_err(|e| e.to_string())?;
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await.map_err(|e| e.to_string())?;
+            }
+            tokio::fs::write(&path, data).await.map_err(|e| format!("{}: {}", path.display(), e))
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, String>> {
+        Box::pin(async move {
+            let path = self.path(key).map_err(|e| e.to_string())?;
+            match tokio::fs::read(&path).await {
+                Ok(data) => Ok(Some(data)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(format!("{}: {}", path.display(), e)),
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let path = self.path(key).map_err(|e| e.to_string())?;
+            match tokio::fs::remove_file(&path).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(format!("{}: {}", path.display(), e)),
+                _ => Ok(()),
+            }
+        })
+    }
+
+    fn presigned_url(&self, _key: &str, _ttl: Duration) -> Option<String> {
+        None
+    }
+}
+
+/// Objects in an S3 bucket. Every request, including uploads, goes through
+/// a presigned URL, so clients download straight from the bucket.
+pub struct S3Storage {
+    config: S3Config,
+    client: reqwest::Client,
+}
+
+// Percent-encoding as SigV4 expects it: everything but unreserved characters
+fn uri_encode(value: &str, keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+impl S3Storage {
+    pub fn new(config: S3Config) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Failed to build S3 HTTP client");
+        S3Storage { config, client }
+    }
+
+    /// A SigV4 query-string signed URL for `method` on the object at `key`.
+    fn presign(&self, method: &str, key: &str, ttl: Duration, now: DateTime<Utc>) -> Result<String, String> {
+        let endpoint = reqwest::Url::parse(&self.config.endpoint).map_err(|e| e.to_string())?;
+        let mut host = endpoint.host_str().ok_or("EXPORT_S3_ENDPOINT lacks a host")?.to_string();
+        if let Some(port) = endpoint.port() {
+            host = format!("{}:{}", host, port);
+        }
+
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let path = format!("/{}/{}", uri_encode(&self.config.bucket, false), uri_encode(key, true));
+
+        // Already in the sorted order SigV4 requires
+        let query = [
+            ("X-Amz-Algorithm", "AWS4-HMAC-SHA256".to_string()),
+            ("X-Amz-Credential", format!("{}/{}", self.config.access_key_id, scope)),
+            ("X-Amz-Date", timestamp.clone()),
+            ("X-Amz-Expires", ttl.as_secs().clamp(1, 7 * 24 * 60 * 60).to_string()),
+            ("X-Amz-SignedHeaders", "host".to_string()),
+        ]
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, uri_encode(value, false)))
+        .collect::<Vec<_>>()
+        .join("&");
+
+        let canonical_request = format!("{}\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD", method, path, query, host);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            to_hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let key = format!("AWS4{}", self.config.secret_access_key);
+        let key = hmac_sha256(key.as_bytes(), &date);
+        let key = hmac_sha256(&key, &self.config.region);
+        let key = hmac_sha256(&key, "s3");
+        let key = hmac_sha256(&key, "aws4_request");
+        let signature = to_hex(&hmac_sha256(&key, &string_to_sign));
+
+        Ok(format!("{}{}?{}&X-Amz-Signature={}", self.config.endpoint, path, query, signature))
+    }
+
+    async fn send(&self, method: reqwest::Method, key: &str, body: Option<Vec<u8>>) -> Result<reqwest::Response, String> {
+        let url = self.presign(method.as_str(), key, Duration::from_secs(300), Utc::now())?;
+        let mut request = self.client.request(method, url);
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+        request.send().await.map_err(|e| e.to_string())
+    }
+}
+
+impl ExportStorage for S3Storage {
+    fn name(&self) -> &'static str {
+        "S3"
+    }
+
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let response = self.send(reqwest::Method::PUT, key, Some(data)).await?;
+            if !response.status().is_success() {
+                return Err(format!("S3 upload of {} failed with {}", key, response.status()));
+            }
+            Ok(())
+        })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>, String>> {
+        Box::pin(async move {
+            let response = self.send(reqwest::Method::GET, key, None).await?;
+            if response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            if !response.status().is_success() {
+                return Err(format!("S3 download of {} failed with {}", key, response.status()));
+            }
+            response.bytes().await.map(|bytes| Some(bytes.to_vec())).map_err(|e| e.to_string())
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let response = self.send(reqwest::Method::DELETE, key, None).await?;
+            // S3 answers 204 whether or not the object existed
+            if !response.status().is_success() && response.status() != reqwest::StatusCode::NOT_FOUND {
+                return Err(format!("S3 delete of {} failed with {}", key, response.status()));
+            }
+            Ok(())
+        })
+    }
+
+    fn presigned_url(&self, key: &str, ttl: Duration) -> Option<String> {
+        match self.presign("GET", key, ttl, Utc::now()) {
+            Ok(url) => Some(url),
+            Err(e) => {
+                warn!("Failed to presign S3 URL for {}: {}", key, e);
+                None
+            }
+        }
+    }
+}
+
+/// S3 when EXPORT_S3_BUCKET is set, the local EXPORT_DIR otherwise.
+pub fn from_config(config: &ExportConfig) -> Arc<dyn ExportStorage> {
+    match &config.s3 {
+        Some(s3) => {
+            info!("Storing exports in S3 bucket {} at {}", s3.bucket, s3.endpoint);
+            Arc::new(S3Storage::new(s3.clone()))
+        }
+        None => {
+            info!("Storing exports in {}", config.dir.display());
+            Arc::new(LocalStorage::new(config.dir.clone()))
+        }
+    }
+}
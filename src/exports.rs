@@ -0,0 +1,378 @@
+use std::{error::Error as StdError, io, time::Duration};
+
+use actix_web::{http::{header, StatusCode}, web, Error, HttpResponse};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine};
+use futures::TryStreamExt;
+use hmac::{Hmac, Mac};
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    options::FindOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{error, info, warn};
+
+use crate::{
+    auth::Claims,
+    config::{ExportConfig, MongoConfig},
+    errors::ApiError,
+    export_storage::ExportStorage,
+    models::Product,
+};
+
+const HOUR_MILLIS: i64 = 60 * 60 * 1000;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportJobStatus {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// A product export generated in the background, and the file it produced.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExportJob {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub user_id: ObjectId,
+    pub status: ExportJobStatus,
+    // Include drafts and archived products, like `export-csv --all`
+    pub all: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rows: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size_bytes: Option<i64>,
+    // Where the file is in the export storage, once it is written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub created_at: DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime>,
+    // The job and its file are deleted after this
+    pub expires_at: DateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportJobResponse {
+    pub id: String,
+    pub status: ExportJobStatus,
+    pub all: bool,
+    pub rows: Option<i64>,
+    pub size_bytes: Option<i64>,
+    pub error: Option<String>,
+    // Only once the export succeeded; a fresh link is issued on every request
+    pub download_url: Option<String>,
+    pub download_url_expires_at: Option<String>,
+    pub created_at: String,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    pub expires_at: String,
+}
+
+impl From<&ExportJob> for ExportJobResponse {
+    fn from(job: &ExportJob) -> Self {
+        ExportJobResponse {
+            id: job.id.to_string(),
+            status: job.status,
+            all: job.all,
+            rows: job.rows,
+            size_bytes: job.size_bytes,
+            error: job.error.clone(),
+            download_url: None,
+            download_url_expires_at: None,
+            created_at: job.created_at.try_to_rfc3339_string().unwrap_or_default(),
+            started_at: job.started_at.and_then(|at| at.try_to_rfc3339_string().ok()),
+            finished_at: job.finished_at.and_then(|at| at.try_to_rfc3339_string().ok()),
+            expires_at: job.expires_at.try_to_rfc3339_string().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateExportJobQuery {
+    #[serde(default)]
+    all: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DownloadQuery {
+    // Unix seconds
+    expires: i64,
+    signature: String,
+}
+
+fn export_jobs_collection(db: &MongoConfig) -> Collection<ExportJob> {
+    db.database.collection("export_jobs")
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+fn parse_job_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| {
+        error!("Invalid export job ID format: {}", id);
+        ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format").into()
+    })
+}
+
+/// Writes the catalog as CSV, sorted by name: active products only unless
+/// `all` is set. Returns the number of rows written.
+pub async fn write_products_csv<W: io::Write>(
+    db: &MongoConfig,
+    all: bool,
+    writer: W,
+) -> Result<i64, Box<dyn StdError + Send + Sync>> {
+    let collection: Collection<Product> = db.catalog_collection("products");
+
+    let filter = if all {
+        Document::new()
+    } else {
+        doc! { "status": { "$in": ["active", null] } }
+    };
+    let options = FindOptions::builder().sort(doc! { "name": 1 }).build();
+
+    // Same columns the importer reads, so exports can be re-imported
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record(["name", "price", "category", "has_active_sale"])?;
+
+    let mut count = 0;
+    let mut cursor = collection.find(filter, options).await?;
+    while let Some(product) = cursor.try_next().await? {
+        wtr.write_record([
+            product.name,
+            product.price.to_string(),
+            product.category.to_string(),
+            product.has_active_sale.to_string(),
+        ])?;
+        count += 1;
+    }
+    wtr.flush()?;
+    Ok(count)
+}
+
+fn sign(key: &[u8], job_id: &ObjectId, expires: i64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(format!("{}:{}", job_id, expires).as_bytes());
+    mac
+}
+
+// A link to the file and when it stops working (unix seconds): straight
+// from the storage when it can presign one, through the API otherwise
+fn download_url(storage: &dyn ExportStorage, config: &ExportConfig, job: &ExportJob, key: &str) -> (String, i64) {
+    let now = DateTime::now().timestamp_millis() / 1000;
+    let expires = (now + config.url_ttl_secs as i64).min(job.expires_at.timestamp_millis() / 1000);
+    if let Some(url) = storage.presigned_url(key, Duration::from_secs((expires - now).max(1) as u64)) {
+        return (url, expires);
+    }
+    let signature = BASE64_URL.encode(sign(&config.signing_key, &job.id, expires).finalize().into_bytes());
+    (format!("/api/exports/{}/download?expires={}&signature={}", job.id, expires, signature), expires)
+}
+
+fn job_response(storage: &dyn ExportStorage, config: &ExportConfig, job: &ExportJob) -> ExportJobResponse {
+    let mut response = ExportJobResponse::from(job);
+    if let (ExportJobStatus::Succeeded, Some(key)) = (job.status, &job.storage_key) {
+        let (url, expires) = download_url(storage, config, job, key);
+        response.download_url = Some(url);
+        response.download_url_expires_at = DateTime::from_millis(expires * 1000).try_to_rfc3339_string().ok();
+    }
+    response
+}
+
+async fn generate(
+    db: &MongoConfig,
+    storage: &dyn ExportStorage,
+    job: &ExportJob,
+) -> Result<(i64, i64, String), Box<dyn StdError + Send + Sync>> {
+    let mut data = Vec::new();
+    let rows = write_products_csv(db, job.all, &mut data).await?;
+    let size = data.len() as i64;
+    let key = format!("products-{}.csv", job.id);
+    storage.put(&key, data).await?;
+    Ok((rows, size, key))
+}
+
+async fn run_export_job(
+    db: web::Data<MongoConfig>,
+    storage: web::Data<dyn ExportStorage>,
+    config: web::Data<ExportConfig>,
+    job: ExportJob,
+) {
+    let jobs = export_jobs_collection(&db);
+    let started = jobs
+        .update_one(
+            doc! { "_id": job.id },
+            doc! { "$set": { "status": "running", "started_at": DateTime::now() } },
+            None,
+        )
+        .await;
+    if let Err(e) = started {
+        warn!("Failed to mark export job {} running: {}", job.id, e);
+    }
+
+    let update = match generate(&db, storage.as_ref(), &job).await {
+        Ok((rows, size, key)) => {
+            info!("Export job {} wrote {} products ({} bytes) to {} storage", job.id, rows, size, storage.name());
+            let finished_at = DateTime::now();
+            doc! {
+                "status": "succeeded",
+                "rows": rows,
+                "size_bytes": size,
+                "storage_key": key,
+                "finished_at": finished_at,
+                // Kept for the retention period from when it became available
+                "expires_at": DateTime::from_millis(finished_at.timestamp_millis() + config.retention_hours * HOUR_MILLIS),
+            }
+        }
+        Err(e) => {
+            warn!("Export job {} failed: {}", job.id, e);
+            doc! { "status": "failed", "error": e.to_string(), "finished_at": DateTime::now() }
+        }
+    };
+    if let Err(e) = jobs.update_one(doc! { "_id": job.id }, doc! { "$set": update }, None).await {
+        error!("Failed to record the outcome of export job {}: {}", job.id, e);
+    }
+}
+
+/// Jobs that were pending or running when the server stopped never finish;
+/// marks them failed so clients stop polling.
+pub async fn fail_interrupted_jobs(db: &MongoConfig) -> Result<u64, mongodb::error::Error> {
+    let result = export_jobs_collection(db)
+        .update_many(
+            doc! { "status": { "$in": ["pending", "running"] } },
+            doc! { "$set": { "status": "failed", "error": "interrupted by a server restart", "finished_at": DateTime::now() } },
+            None,
+        )
+        .await?;
+    Ok(result.modified_count)
+}
+
+/// Deletes expired jobs along with their files.
+pub async fn purge_expired(db: &MongoConfig, storage: &dyn ExportStorage) -> Result<String, String> {
+    let jobs = export_jobs_collection(db);
+    let expired: Vec<ExportJob> = jobs
+        .find(doc! { "expires_at": { "$lte": DateTime::now() } }, None)
+        .await
+        .map_err(|e| format!("Database error: {}", e))?
+        .try_collect()
+        .await
+        .map_err(|e| format!("Database error: {}", e))?;
+
+    let mut purged = 0;
+    for job in &expired {
+        // Keep the job when its file lingers, so the next run tries again
+        if let Some(key) = &job.storage_key {
+            if let Err(e) = storage.delete(key).await {
+                warn!("Failed to delete export file {}: {}", key, e);
+                continue;
+            }
+        }
+        jobs.delete_one(doc! { "_id": job.id }, None)
+            .await
+            .map_err(|e| format!("Database error: {}", e))?;
+        purged += 1;
+    }
+    Ok(format!("Purged {} expired export jobs", purged))
+}
+
+/// Queues an export of the catalog and returns right away; poll the job
+/// for its status and download link.
+pub async fn create_export_job(
+    db: web::Data<MongoConfig>,
+    storage: web::Data<dyn ExportStorage>,
+    config: web::Data<ExportConfig>,
+    claims: web::ReqData<Claims>,
+    query: web::Query<CreateExportJobQuery>,
+) -> Result<HttpResponse, Error> {
+    let now = DateTime::now();
+    let job = ExportJob {
+        id: ObjectId::new(),
+        user_id: claims.user_id()?,
+        status: ExportJobStatus::Pending,
+        all: query.all,
+        rows: None,
+        size_bytes: None,
+        storage_key: None,
+        error: None,
+        created_at: now,
+        started_at: None,
+        finished_at: None,
+        expires_at: DateTime::from_millis(now.timestamp_millis() + config.retention_hours * HOUR_MILLIS),
+    };
+    export_jobs_collection(&db)
+        .insert_one(&job, None)
+        .await
+        .map_err(|e| db_error("Failed to create export job", e))?;
+
+    info!("User {} started export job {}", job.user_id, job.id);
+    let response = job_response(storage.as_ref(), &config, &job);
+    tokio::spawn(run_export_job(db, storage, config, job));
+    Ok(HttpResponse::Accepted().json(response))
+}
+
+/// One of the caller's export jobs, with a download link once it succeeded.
+pub async fn get_export_job(
+    db: web::Data<MongoConfig>,
+    storage: web::Data<dyn ExportStorage>,
+    config: web::Data<ExportConfig>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let job_id = parse_job_id(&id)?;
+    let job = export_jobs_collection(&db)
+        .find_one(doc! { "_id": job_id, "user_id": claims.user_id()? }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch export job", e))?;
+
+    match job {
+        Some(job) => Ok(HttpResponse::Ok().json(job_response(storage.as_ref(), &config, &job))),
+        None => Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    }
+}
+
+/// Serves an export file. The signed link stands in for authentication,
+/// so it can be handed to a browser or download tool as is.
+pub async fn download_export(
+    db: web::Data<MongoConfig>,
+    storage: web::Data<dyn ExportStorage>,
+    config: web::Data<ExportConfig>,
+    id: web::Path<String>,
+    query: web::Query<DownloadQuery>,
+) -> Result<HttpResponse, Error> {
+    let job_id = parse_job_id(&id)?;
+    let signature = BASE64_URL.decode(&query.signature).unwrap_or_default();
+    if sign(&config.signing_key, &job_id, query.expires).verify_slice(&signature).is_err() {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "Invalid download signature").into());
+    }
+    if query.expires < DateTime::now().timestamp_millis() / 1000 {
+        return Err(ApiError::new(StatusCode::GONE, "Download link expired").into());
+    }
+
+    let job = export_jobs_collection(&db)
+        .find_one(doc! { "_id": job_id, "status": "succeeded" }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch export job", e))?;
+    let Some(key) = job.and_then(|job| job.storage_key) else {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    };
+
+    let data = storage.get(&key).await.map_err(|e| {
+        error!("Failed to read export file {}: {}", key, e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read export file")
+    })?;
+    match data {
+        Some(data) => Ok(HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", key)))
+            .body(data)),
+        None => Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    }
+}
@@ -0,0 +1,176 @@
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime},
+    options::{FindOptions, UpdateOptions},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::{debug, error, info};
+
+use crate::{auth::Claims, config::MongoConfig, errors::ApiError, models::Product};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Favorite {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    pub product_id: ObjectId,
+    pub created_at: DateTime,
+}
+
+fn favorites_collection(db: &MongoConfig) -> Collection<Favorite> {
+    db.database.collection("favorites")
+}
+
+fn parse_product_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| {
+        error!("Invalid product ID format: {}", id);
+        ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format").into()
+    })
+}
+
+/// Returns which of `product_ids` the user has marked as favorite.
+pub async fn favorite_product_ids(
+    db: &MongoConfig,
+    user_id: &ObjectId,
+    product_ids: &[ObjectId],
+) -> Result<HashSet<ObjectId>, Error> {
+    let mut cursor = favorites_collection(db)
+        .find(doc! { "user_id": user_id, "product_id": { "$in": product_ids } }, None)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch favorites for user {}: {}", user_id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+
+    let mut ids = HashSet::new();
+    while let Some(favorite) = cursor.try_next().await.map_err(|e| {
+        error!("Error while iterating favorites: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+    })? {
+        ids.insert(favorite.product_id);
+    }
+    Ok(ids)
+}
+
+pub async fn list_favorites(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+) -> Result<HttpResponse, Error> {
+    let user_id = claims.user_id()?;
+
+    let options = FindOptions::builder()
+        .sort(doc! { "created_at": -1 })
+        .build();
+
+    let mut cursor = favorites_collection(&db)
+        .find(doc! { "user_id": user_id }, options)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch favorites for user {}: {}", user_id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+
+    let mut product_ids = Vec::new();
+    while let Some(favorite) = cursor.try_next().await.map_err(|e| {
+        error!("Error while iterating favorites: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+    })? {
+        product_ids.push(favorite.product_id);
+    }
+
+    let products: Collection<Product> = db.database.collection("products");
+    let mut cursor = products
+        .find(doc! { "_id": { "$in": &product_ids } }, None)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch favorite products: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+
+    let mut found = Vec::new();
+    while let Some(product) = cursor.try_next().await.map_err(|e| {
+        error!("Error while iterating products: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+    })? {
+        found.push(product);
+    }
+
+    // Keep most recently favorited first
+    found.sort_by_key(|p| product_ids.iter().position(|id| Some(*id) == p.id));
+
+    debug!("Found {} favorites for user {}", found.len(), user_id);
+    Ok(HttpResponse::Ok().json(found))
+}
+
+pub async fn add_favorite(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    product_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let user_id = claims.user_id()?;
+    let product_id = parse_product_id(&product_id)?;
+
+    let products: Collection<Product> = db.database.collection("products");
+    let exists = products
+        .find_one(doc! { "_id": product_id }, None)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch product {}: {}", product_id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?
+        .is_some();
+
+    if !exists {
+        debug!("Product not found for favorite: {}", product_id);
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    }
+
+    // Upsert keeps adding idempotent
+    let options = UpdateOptions::builder().upsert(true).build();
+    let result = favorites_collection(&db)
+        .update_one(
+            doc! { "user_id": user_id, "product_id": product_id },
+            doc! { "$setOnInsert": { "created_at": DateTime::now() } },
+            options,
+        )
+        .await
+        .map_err(|e| {
+            error!("Failed to add favorite: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+
+    if result.upserted_id.is_some() {
+        info!("User {} favorited product {}", user_id, product_id);
+        Ok(HttpResponse::Created().finish())
+    } else {
+        Ok(HttpResponse::Ok().finish())
+    }
+}
+
+pub async fn remove_favorite(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    product_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let user_id = claims.user_id()?;
+    let product_id = parse_product_id(&product_id)?;
+
+    let result = favorites_collection(&db)
+        .delete_one(doc! { "user_id": user_id, "product_id": product_id }, None)
+        .await
+        .map_err(|e| {
+            error!("Failed to remove favorite: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+
+    if result.deleted_count == 0 {
+        debug!("Favorite not found: {} / {}", user_id, product_id);
+        Err(ApiError::blank(StatusCode::NOT_FOUND).into())
+    } else {
+        info!("User {} unfavorited product {}", user_id, product_id);
+        Ok(HttpResponse::Ok().finish())
+    }
+}
@@ -0,0 +1,386 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    pin::Pin,
+    sync::RwLock,
+    time::Duration,
+};
+
+use actix_web::{dev::Payload, http::StatusCode, web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use futures::{StreamExt, TryStreamExt};
+use mongodb::{
+    bson::{self, doc, DateTime, Document},
+    options::FindOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    auth::Claims,
+    barcode::is_duplicate_key,
+    config::{FeatureFlagConfig, MongoConfig},
+    errors::ApiError,
+};
+
+// How often flags are reloaded when change streams are unavailable, as on a
+// standalone server without a replica set
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A switch for a feature that can differ per environment and per tenant. A
+/// tenant is the authenticated user, identified by their user ID.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeatureFlag {
+    #[serde(rename = "_id")]
+    pub name: String,
+    pub description: Option<String>,
+    // Applies wherever no override does
+    pub enabled: bool,
+    // Overrides by environment (APP_ENV), e.g. "staging" => true
+    #[serde(default)]
+    pub environments: BTreeMap<String, bool>,
+    // Overrides by tenant; these win over the environment ones
+    #[serde(default)]
+    pub tenants: BTreeMap<String, bool>,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+impl FeatureFlag {
+    pub fn is_enabled(&self, environment: &str, tenant: Option<&str>) -> bool {
+        tenant
+            .and_then(|tenant| self.tenants.get(tenant))
+            .or_else(|| self.environments.get(environment))
+            .copied()
+            .unwrap_or(self.enabled)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateFeatureFlagRequest {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub environments: BTreeMap<String, bool>,
+    #[serde(default)]
+    pub tenants: BTreeMap<String, bool>,
+}
+
+// Fields left out are kept; override maps are replaced as a whole
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateFeatureFlagRequest {
+    pub description: Option<String>,
+    pub enabled: Option<bool>,
+    pub environments: Option<BTreeMap<String, bool>>,
+    pub tenants: Option<BTreeMap<String, bool>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FeatureFlagResponse {
+    pub name: String,
+    pub description: Option<String>,
+    pub enabled: bool,
+    pub environments: BTreeMap<String, bool>,
+    pub tenants: BTreeMap<String, bool>,
+    // Whether the flag is on in this instance's environment, before tenant overrides
+    pub active: bool,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl FeatureFlagResponse {
+    fn new(flag: FeatureFlag, environment: &str) -> Self {
+        FeatureFlagResponse {
+            active: flag.is_enabled(environment, None),
+            name: flag.name,
+            description: flag.description,
+            enabled: flag.enabled,
+            environments: flag.environments,
+            tenants: flag.tenants,
+            created_at: flag.created_at.try_to_rfc3339_string().unwrap_or_default(),
+            updated_at: flag.updated_at.try_to_rfc3339_string().unwrap_or_default(),
+        }
+    }
+}
+
+fn flags_collection(db: &MongoConfig) -> Collection<FeatureFlag> {
+    db.database.collection("feature_flags")
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+// Flag names are used in code, so keep them to identifiers like "graphql_api"
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-' || c == '.')
+}
+
+/// Every flag, kept in memory so checking one costs no query. Reloaded
+/// whenever the feature_flags collection changes.
+pub struct FeatureFlagStore {
+    environment: String,
+    flags: RwLock<HashMap<String, FeatureFlag>>,
+}
+
+impl FeatureFlagStore {
+    pub fn new(config: FeatureFlagConfig) -> Self {
+        FeatureFlagStore { environment: config.environment, flags: RwLock::new(HashMap::new()) }
+    }
+
+    pub fn environment(&self) -> &str {
+        &self.environment
+    }
+
+    /// Whether `name` is on for `tenant` in this environment. Unknown flags are off.
+    pub fn is_enabled(&self, name: &str, tenant: Option<&str>) -> bool {
+        self.flags
+            .read()
+            .unwrap()
+            .get(name)
+            .is_some_and(|flag| flag.is_enabled(&self.environment, tenant))
+    }
+
+    /// Names of the flags that are on for `tenant`, sorted.
+    pub fn enabled_flags(&self, tenant: Option<&str>) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .flags
+            .read()
+            .unwrap()
+            .values()
+            .filter(|flag| flag.is_enabled(&self.environment, tenant))
+            .map(|flag| flag.name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    pub async fn reload(&self, db: &MongoConfig) -> Result<usize, mongodb::error::Error> {
+        let flags: Vec<FeatureFlag> = flags_collection(db).find(None, None).await?.try_collect().await?;
+        let count = flags.len();
+        *self.flags.write().unwrap() = flags.into_iter().map(|flag| (flag.name.clone(), flag)).collect();
+        Ok(count)
+    }
+}
+
+/// Loads the flags, then keeps them current: through a change stream on the
+/// collection where the deployment supports one, by polling otherwise.
+pub fn spawn_refresh(store: web::Data<FeatureFlagStore>, db: web::Data<MongoConfig>) {
+    tokio::spawn(async move {
+        match store.reload(&db).await {
+            Ok(count) => info!("Loaded {} feature flags for environment {}", count, store.environment()),
+            Err(e) => error!("Failed to load feature flags: {}", e),
+        }
+
+        loop {
+            match flags_collection(&db).watch(None, None).await {
+                Ok(mut changes) => {
+                    debug!("Watching feature flags for changes");
+                    while let Some(change) = changes.next().await {
+                        if let Err(e) = change {
+                            warn!("Feature flag change stream failed: {}", e);
+                            break;
+                        }
+                        if let Err(e) = store.reload(&db).await {
+                            error!("Failed to reload feature flags: {}", e);
+                        }
+                    }
+                }
+                Err(e) => debug!("Feature flag change stream unavailable, polling instead: {}", e),
+            }
+
+            // Changes made while not watching are picked up here
+            tokio::time::sleep(POLL_INTERVAL).await;
+            if let Err(e) = store.reload(&db).await {
+                error!("Failed to reload feature flags: {}", e);
+            }
+        }
+    });
+}
+
+/// Extractor for checking flags in a handler, for the tenant making the request.
+pub struct Features {
+    store: web::Data<FeatureFlagStore>,
+    tenant: Option<String>,
+}
+
+// No endpoint is behind a flag right now
+#[allow(dead_code)]
+impl Features {
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.store.is_enabled(name, self.tenant.as_deref())
+    }
+
+    /// Guard for endpoints behind a flag: 404 while it is off, as if the
+    /// endpoint did not exist.
+    pub fn require(&self, name: &str) -> Result<(), Error> {
+        if self.is_enabled(name) {
+            Ok(())
+        } else {
+            debug!("Feature {} is off for tenant {:?}", name, self.tenant);
+            Err(ApiError::new(StatusCode::NOT_FOUND, "Not found").into())
+        }
+    }
+
+    pub fn enabled(&self) -> Vec<String> {
+        self.store.enabled_flags(self.tenant.as_deref())
+    }
+}
+
+impl FromRequest for Features {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Error>>>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let store = req.app_data::<web::Data<FeatureFlagStore>>().cloned();
+        let tenant = req.extensions().get::<Claims>().map(|claims| claims.sub.clone());
+        Box::pin(async move {
+            let store = store.ok_or_else(|| {
+                error!("Feature flag store is not configured");
+                ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Feature flags unavailable")
+            })?;
+            Ok(Features { store, tenant })
+        })
+    }
+}
+
+/// The flags that are on for the signed-in user, so clients can show or
+/// hide features to match.
+pub async fn list_my_flags(features: Features) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(features.enabled()))
+}
+
+pub async fn list_flags(
+    db: web::Data<MongoConfig>,
+    store: web::Data<FeatureFlagStore>,
+) -> Result<HttpResponse, Error> {
+    let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+    let flags: Vec<FeatureFlag> = flags_collection(&db)
+        .find(None, options)
+        .await
+        .map_err(|e| db_error("Failed to fetch feature flags", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating feature flags", e))?;
+
+    let flags: Vec<FeatureFlagResponse> =
+        flags.into_iter().map(|flag| FeatureFlagResponse::new(flag, store.environment())).collect();
+    Ok(HttpResponse::Ok().json(flags))
+}
+
+pub async fn get_flag(
+    db: web::Data<MongoConfig>,
+    store: web::Data<FeatureFlagStore>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let flag = flags_collection(&db)
+        .find_one(doc! { "_id": name.as_str() }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch feature flag", e))?;
+
+    match flag {
+        Some(flag) => Ok(HttpResponse::Ok().json(FeatureFlagResponse::new(flag, store.environment()))),
+        None => Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    }
+}
+
+pub async fn create_flag(
+    db: web::Data<MongoConfig>,
+    store: web::Data<FeatureFlagStore>,
+    request: web::Json<CreateFeatureFlagRequest>,
+) -> Result<HttpResponse, Error> {
+    let request = request.into_inner();
+    if !is_valid_name(&request.name) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "name must be 1 to 64 lowercase letters, digits, '_', '-' or '.'",
+        ).into());
+    }
+
+    let now = DateTime::now();
+    let flag = FeatureFlag {
+        name: request.name,
+        description: request.description,
+        enabled: request.enabled,
+        environments: request.environments,
+        tenants: request.tenants,
+        created_at: now,
+        updated_at: now,
+    };
+
+    if let Err(e) = flags_collection(&db).insert_one(&flag, None).await {
+        if is_duplicate_key(&e) {
+            let message = format!("Feature flag {} already exists", flag.name);
+            return Err(ApiError::new(StatusCode::CONFLICT, message).into());
+        }
+        return Err(db_error("Failed to create feature flag", e));
+    }
+    store.reload(&db).await.map_err(|e| db_error("Failed to reload feature flags", e))?;
+
+    info!("Feature flag {} created (enabled: {})", flag.name, flag.enabled);
+    Ok(HttpResponse::Created().json(FeatureFlagResponse::new(flag, store.environment())))
+}
+
+pub async fn update_flag(
+    db: web::Data<MongoConfig>,
+    store: web::Data<FeatureFlagStore>,
+    name: web::Path<String>,
+    request: web::Json<UpdateFeatureFlagRequest>,
+) -> Result<HttpResponse, Error> {
+    let request = request.into_inner();
+    let mut update = doc! { "updated_at": DateTime::now() };
+    if let Some(description) = request.description {
+        update.insert("description", description);
+    }
+    if let Some(enabled) = request.enabled {
+        update.insert("enabled", enabled);
+    }
+    if let Some(environments) = request.environments {
+        update.insert("environments", overrides_document(&environments));
+    }
+    if let Some(tenants) = request.tenants {
+        update.insert("tenants", overrides_document(&tenants));
+    }
+
+    let result = flags_collection(&db)
+        .update_one(doc! { "_id": name.as_str() }, doc! { "$set": update }, None)
+        .await
+        .map_err(|e| db_error("Failed to update feature flag", e))?;
+    if result.matched_count == 0 {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    }
+    store.reload(&db).await.map_err(|e| db_error("Failed to reload feature flags", e))?;
+
+    info!("Feature flag {} updated", name);
+    get_flag(db, store, name).await
+}
+
+pub async fn delete_flag(
+    db: web::Data<MongoConfig>,
+    store: web::Data<FeatureFlagStore>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let result = flags_collection(&db)
+        .delete_one(doc! { "_id": name.as_str() }, None)
+        .await
+        .map_err(|e| db_error("Failed to delete feature flag", e))?;
+
+    if result.deleted_count == 0 {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    }
+    store.reload(&db).await.map_err(|e| db_error("Failed to reload feature flags", e))?;
+
+    info!("Feature flag {} deleted", name);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn overrides_document(overrides: &BTreeMap<String, bool>) -> Document {
+    overrides.iter().map(|(key, enabled)| (key.clone(), bson::Bson::Boolean(*enabled))).collect()
+}
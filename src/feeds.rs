@@ -0,0 +1,250 @@
+use std::fmt::Write as _;
+
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{bson::doc, options::FindOptions, Collection};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{debug, error, warn};
+
+use crate::{
+    config::{FeedConfig, FeedProfile, MongoConfig},
+    errors::ApiError,
+    models::Product,
+    money::Decimal,
+};
+
+const GOOGLE_CSV_COLUMNS: [&str; 11] = [
+    "id", "title", "description", "link", "image_link", "availability", "price", "brand", "condition",
+    "google_product_category", "product_type",
+];
+
+// Columns of Shopify's product import template that a single-variant product needs
+const SHOPIFY_CSV_COLUMNS: [&str; 15] = [
+    "Handle", "Title", "Body (HTML)", "Vendor", "Product Category", "Type", "Published", "Option1 Name",
+    "Option1 Value", "Variant SKU", "Variant Inventory Tracker", "Variant Inventory Qty", "Variant Price",
+    "Image Src", "Status",
+];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeedQuery {
+    token: Option<String>,
+}
+
+// Platform-neutral view of a product, built once per product from the profile
+struct FeedItem {
+    id: String,
+    title: String,
+    link: String,
+    image_link: String,
+    in_stock: bool,
+    stock_quantity: Option<i64>,
+    price: Decimal,
+    category: String,
+    platform_category: String,
+}
+
+impl FeedItem {
+    fn new(product: &Product, profile: &FeedProfile) -> Option<Self> {
+        let id = product.id?.to_hex();
+        let category = product.category.to_string();
+        Some(FeedItem {
+            link: profile.link_template.replace("{id}", &id),
+            image_link: profile
+                .image_link_template
+                .as_ref()
+                .map(|template| template.replace("{id}", &id))
+                .unwrap_or_default(),
+            title: product.name.clone(),
+            in_stock: product.stock_quantity.is_none_or(|q| q > 0),
+            stock_quantity: product.stock_quantity,
+            price: product.price,
+            platform_category: profile.category_map.get(&category).cloned().unwrap_or_default(),
+            category,
+            id,
+        })
+    }
+
+    fn availability(&self) -> &'static str {
+        if self.in_stock { "in stock" } else { "out of stock" }
+    }
+
+    fn google_price(&self, profile: &FeedProfile) -> String {
+        format!("{:.2} {}", self.price, profile.currency)
+    }
+
+    // Shopify handles are URL slugs and must be unique, so the ID is appended
+    fn handle(&self) -> String {
+        let slug: String = self
+            .title
+            .to_lowercase()
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '-' })
+            .collect();
+        let slug = slug.split('-').filter(|part| !part.is_empty()).collect::<Vec<_>>().join("-");
+        format!("{}-{}", slug, self.id)
+    }
+}
+
+fn escape_xml(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn google_xml(items: &[FeedItem], profile: &FeedProfile, profile_name: &str) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<rss version=\"2.0\" xmlns:g=\"http://base.google.com/ns/1.0\">\n<channel>\n");
+    let _ = writeln!(xml, "<title>{}</title>", escape_xml(profile_name));
+
+    for item in items {
+        let brand = profile.brand.as_deref().unwrap_or_default();
+        let fields = [
+            ("g:id", item.id.as_str()),
+            ("g:title", item.title.as_str()),
+            ("g:description", item.title.as_str()),
+            ("g:link", item.link.as_str()),
+            ("g:image_link", item.image_link.as_str()),
+            ("g:availability", item.availability()),
+            ("g:price", &item.google_price(profile)),
+            ("g:brand", brand),
+            ("g:condition", "new"),
+            ("g:google_product_category", item.platform_category.as_str()),
+            ("g:product_type", item.category.as_str()),
+        ];
+
+        xml.push_str("<item>\n");
+        for (tag, value) in fields.iter().filter(|(_, value)| !value.is_empty()) {
+            let _ = writeln!(xml, "<{tag}>{}</{tag}>", escape_xml(value));
+        }
+        xml.push_str("</item>\n");
+    }
+
+    xml.push_str("</channel>\n</rss>\n");
+    xml
+}
+
+fn google_csv(items: &[FeedItem], profile: &FeedProfile) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(GOOGLE_CSV_COLUMNS)?;
+    for item in items {
+        writer.write_record([
+            item.id.as_str(),
+            &item.title,
+            &item.title,
+            &item.link,
+            &item.image_link,
+            item.availability(),
+            &item.google_price(profile),
+            profile.brand.as_deref().unwrap_or_default(),
+            "new",
+            &item.platform_category,
+            &item.category,
+        ])?;
+    }
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+fn shopify_csv(items: &[FeedItem], profile: &FeedProfile) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(SHOPIFY_CSV_COLUMNS)?;
+    for item in items {
+        // Products without stock tracking are left to Shopify's own inventory settings
+        let (tracker, quantity) = match item.stock_quantity {
+            Some(quantity) => ("shopify", quantity.to_string()),
+            None => ("", String::new()),
+        };
+        writer.write_record([
+            item.handle().as_str(),
+            &item.title,
+            "",
+            profile.brand.as_deref().unwrap_or_default(),
+            &item.platform_category,
+            &item.category,
+            "TRUE",
+            "Title",
+            "Default Title",
+            &item.id,
+            tracker,
+            &quantity,
+            &format!("{:.2}", item.price),
+            &item.image_link,
+            "active",
+        ])?;
+    }
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+// Compares digests so response timing reveals nothing about the token
+pub(crate) fn token_matches(given: &str, expected: &str) -> bool {
+    Sha256::digest(given.as_bytes()) == Sha256::digest(expected.as_bytes())
+}
+
+/// Active products as a Google Merchant (`google.xml`, `google.csv`) or
+/// Shopify (`shopify.csv`) feed, shaped by the named profile.
+pub async fn product_feed(
+    db: web::Data<MongoConfig>,
+    feeds: web::Data<FeedConfig>,
+    path: web::Path<(String, String)>,
+    query: web::Query<FeedQuery>,
+) -> Result<HttpResponse, Error> {
+    let (profile_name, format) = path.into_inner();
+
+    let Some(profile) = feeds.profiles.get(&profile_name) else {
+        debug!("Unknown feed profile: {}", profile_name);
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    };
+    if !query.token.as_deref().is_some_and(|token| token_matches(token, &profile.access_token)) {
+        warn!("Rejected feed request for profile {} with a missing or wrong token", profile_name);
+        return Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid or missing feed token").into());
+    }
+    if !matches!(format.as_str(), "google.xml" | "google.csv" | "shopify.csv") {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    }
+
+    let collection: Collection<Product> = db.catalog_collection("products");
+    let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+    let products: Vec<Product> = collection
+        .find(doc! { "status": { "$in": ["active", null] } }, options)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch products for feed: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?
+        .try_collect()
+        .await
+        .map_err(|e| {
+            error!("Error while iterating products for feed: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+
+    let items: Vec<FeedItem> = products.iter().filter_map(|p| FeedItem::new(p, profile)).collect();
+    debug!("Generating {} feed for profile {} with {} products", format, profile_name, items.len());
+
+    let csv_error = |e: csv::Error| {
+        error!("Failed to write feed: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to generate feed")
+    };
+    let response = match format.as_str() {
+        "google.xml" => HttpResponse::Ok()
+            .content_type("application/xml; charset=utf-8")
+            .body(google_xml(&items, profile, &profile_name)),
+        "google.csv" => HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .body(google_csv(&items, profile).map_err(csv_error)?),
+        _ => HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .body(shopify_csv(&items, profile).map_err(csv_error)?),
+    };
+    Ok(response)
+}
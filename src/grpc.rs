@@ -0,0 +1,508 @@
+// tonic::Status is large, but it is the error type every handler must return
+#![allow(clippy::result_large_err)]
+
+use std::pin::Pin;
+
+use actix_web::web;
+use futures::{stream, Stream, TryStreamExt};
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    options::FindOptions,
+    Collection,
+};
+use regex::escape;
+use tokio::sync::broadcast::error::RecvError;
+use tonic::{
+    metadata::{MetadataMap, MetadataValue},
+    transport::Server,
+    Code, Request, Response, Status,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    barcode::{is_duplicate_key, normalize_barcode},
+    config::{GrpcConfig, LimitsConfig, MongoConfig, PriceApprovalConfig},
+    event_store::{self, ProductEvent},
+    events::{DomainEvent, EventHub},
+    feeds::token_matches,
+    locations,
+    models::{
+        Category, CreateProductRequest, Product as ProductModel, ProductStatus, TaxClass, Unit, UpdateProductRequest,
+    },
+    money::{self, Decimal},
+    price_approvals, public_ids,
+    rate_limit::{key_bucket, RateLimiter},
+    search, slugs, stock, trash,
+    validation::page_offset,
+    validation_webhook::{Rejection, ValidationWebhook},
+};
+
+pub mod proto {
+    tonic::include_proto!("products.v1");
+}
+
+use proto::product_service_server::{ProductService, ProductServiceServer};
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Status {
+    // The barcode index is the only unique index on products
+    if slugs::is_duplicate_slug(&e) {
+        return Status::aborted("Another product was given the same slug at the same time; try again");
+    }
+    if is_duplicate_key(&e) {
+        return Status::already_exists("A product with this barcode already exists");
+    }
+    error!("{}: {}", context, e);
+    Status::internal(format!("Database error: {}", e))
+}
+
+fn rejected(rejection: Rejection) -> Status {
+    match rejection {
+        Rejection::Denied(message) => Status::invalid_argument(message),
+        Rejection::Unavailable => Status::unavailable(rejection.message()),
+    }
+}
+
+fn parse_id(id: &str) -> Result<ObjectId, Status> {
+    ObjectId::parse_str(id).map_err(|_| Status::invalid_argument("Invalid ID format"))
+}
+
+fn parse_price(price: f64) -> Result<Decimal, Status> {
+    money::from_input_f64(price).map_err(Status::invalid_argument)
+}
+
+fn parse_category(category: &str) -> Result<Category, Status> {
+    category.parse().map_err(Status::invalid_argument)
+}
+
+impl From<ProductModel> for proto::Product {
+    fn from(product: ProductModel) -> Self {
+        proto::Product {
+            id: product.id.map(|id| id.to_string()).unwrap_or_default(),
+            name: product.name,
+            price: money::to_f64(product.price),
+            category: product.category.to_string(),
+            status: product.status.to_string(),
+            has_active_sale: product.has_active_sale,
+            stock_quantity: product.stock_quantity,
+            low_stock_threshold: product.low_stock_threshold,
+            barcode: product.barcode,
+        }
+    }
+}
+
+impl From<DomainEvent> for proto::ProductEvent {
+    fn from(event: DomainEvent) -> Self {
+        proto::ProductEvent {
+            r#type: event.kind().to_string(),
+            product_id: event.product_id().unwrap_or_default().to_string(),
+        }
+    }
+}
+
+// Serves the product catalog to internal consumers over gRPC
+pub struct ProductGrpcService {
+    db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
+    limits: web::Data<LimitsConfig>,
+    approvals: web::Data<PriceApprovalConfig>,
+    webhook: Option<web::Data<ValidationWebhook>>,
+    limiter: Option<web::Data<RateLimiter>>,
+}
+
+// The token a call was made with, when it has one
+fn bearer_token<T>(request: &Request<T>) -> Option<&str> {
+    request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+impl ProductGrpcService {
+    fn collection(&self) -> Collection<ProductModel> {
+        self.db.database.collection("products")
+    }
+
+    // Counts a call against the rate limit of the API key it was made with;
+    // without GRPC_AUTH_TOKEN, all calls share one bucket. Calls over the
+    // limit fail with RESOURCE_EXHAUSTED and the X-RateLimit-* metadata
+    async fn limit<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let Some(limiter) = &self.limiter else {
+            return Ok(());
+        };
+        let bucket = key_bucket(bearer_token(request).unwrap_or_default());
+        match limiter.count(&bucket).await {
+            Ok((true, usage)) => {
+                debug!("gRPC API key {} is over the rate limit", bucket);
+                let mut metadata = MetadataMap::new();
+                for (name, value) in usage.fields() {
+                    metadata.insert(name, MetadataValue::from(value));
+                }
+                metadata.insert("retry-after", MetadataValue::from(usage.retry_after_secs()));
+                Err(Status::with_metadata(Code::ResourceExhausted, "Rate limit exceeded, try again later", metadata))
+            }
+            Ok((false, _)) => Ok(()),
+            // As over HTTP, a failing store lets calls through
+            Err(e) => {
+                warn!("Failed to count gRPC call against the rate limit in {}: {}", limiter.store_name(), e);
+                Ok(())
+            }
+        }
+    }
+
+    async fn fetch(&self, object_id: ObjectId) -> Result<proto::Product, Status> {
+        self.collection()
+            .find_one(doc! { "_id": object_id }, None)
+            .await
+            .map_err(|e| db_error("Failed to fetch product", e))?
+            .map(proto::Product::from)
+            .ok_or_else(|| Status::not_found("Product not found"))
+    }
+}
+
+type ProductEventStream = Pin<Box<dyn Stream<Item = Result<proto::ProductEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl ProductService for ProductGrpcService {
+    async fn get_product(
+        &self,
+        request: Request<proto::GetProductRequest>,
+    ) -> Result<Response<proto::Product>, Status> {
+        self.limit(&request).await?;
+        let object_id = parse_id(&request.get_ref().id)?;
+        debug!("gRPC GetProduct: {}", object_id);
+        self.fetch(object_id).await.map(Response::new)
+    }
+
+    async fn list_products(
+        &self,
+        request: Request<proto::ListProductsRequest>,
+    ) -> Result<Response<proto::ListProductsResponse>, Status> {
+        self.limit(&request).await?;
+        let query = request.into_inner();
+        let per_page = if query.per_page > 0 { query.per_page } else { 15 };
+        let page = query.page.max(1);
+        if per_page > self.limits.max_per_page {
+            return Err(Status::invalid_argument(format!(
+                "per_page must be between 1 and {}", self.limits.max_per_page
+            )));
+        }
+
+        let mut filter = Document::new();
+        if !query.filter.is_empty() {
+            filter.insert("name", doc! { "$regex": format!("(?i){}", escape(&query.filter)) });
+        }
+        match query.status.as_str() {
+            "" | "active" => {
+                filter.insert("status", doc! { "$in": ["active", null] });
+            }
+            status @ ("draft" | "archived") => {
+                filter.insert("status", status);
+            }
+            "all" => {}
+            other => {
+                return Err(Status::invalid_argument(format!(
+                    "Invalid status '{}': expected draft, active, archived or all", other
+                )));
+            }
+        }
+
+        let collection = self.collection();
+        let total_count = collection
+            .count_documents(filter.clone(), None)
+            .await
+            .map_err(|e| db_error("Failed to count products", e))?;
+        let total_pages = ((total_count as f64) / (per_page as f64)).ceil() as i64;
+        let skip = page_offset(page, per_page).map_err(|_| Status::invalid_argument("page is too large"))?;
+
+        let find_options = FindOptions::builder()
+            .sort(doc! { "name": 1 })
+            .skip(skip as u64)
+            .limit(per_page)
+            .build();
+
+        let products: Vec<ProductModel> = collection
+            .find(filter, find_options)
+            .await
+            .map_err(|e| db_error("Failed to fetch products", e))?
+            .try_collect()
+            .await
+            .map_err(|e| db_error("Failed to read products", e))?;
+
+        Ok(Response::new(proto::ListProductsResponse {
+            products: products.into_iter().map(proto::Product::from).collect(),
+            total_pages,
+        }))
+    }
+
+    async fn create_product(
+        &self,
+        request: Request<proto::CreateProductRequest>,
+    ) -> Result<Response<proto::Product>, Status> {
+        self.limit(&request).await?;
+        let product = request.into_inner();
+
+        if product.stock_quantity.is_some_and(|q| q < 0) {
+            return Err(Status::invalid_argument("Stock quantity must be non-negative"));
+        }
+        let status = match product.status.as_deref() {
+            Some(status) => status.parse::<ProductStatus>().map_err(Status::invalid_argument)?,
+            None => ProductStatus::default(),
+        };
+        let price = parse_price(product.price)?;
+        let category = parse_category(&product.category)?;
+        let barcode = product.barcode.as_deref().map(normalize_barcode).transpose().map_err(Status::invalid_argument)?;
+        if let Some(webhook) = &self.webhook {
+            let request = CreateProductRequest {
+                status: Some(status),
+                has_active_sale: product.has_active_sale,
+                stock_quantity: product.stock_quantity,
+                low_stock_threshold: product.low_stock_threshold,
+                barcode: barcode.clone(),
+                ..CreateProductRequest::new(product.name.clone(), price, category.clone())
+            };
+            webhook.check_create(&request).await.map_err(rejected)?;
+        }
+
+        let slug = slugs::unique_slug(&self.db, &product.name, None)
+            .await
+            .map_err(|e| db_error("Failed to generate slug", e))?;
+        let new_product = ProductModel {
+            id: None,
+            public_id: Some(public_ids::new_public_id()),
+            search_grams: search::name_grams(&product.name),
+            sku: None,
+            description: None,
+            name: product.name,
+            slug: Some(slug),
+            previous_slugs: Vec::new(),
+            price,
+            category,
+            status,
+            tax_class: TaxClass::default(),
+            unit: Unit::default(),
+            price_per_unit: None,
+            price_tiers: Vec::new(),
+            has_active_sale: product.has_active_sale,
+            sale_ends_at: None,
+            stock_quantity: product.stock_quantity,
+            low_stock_threshold: product.low_stock_threshold,
+            barcode,
+            attributes: None,
+            supplier_id: None,
+            supplier_sku: None,
+            cost_price: None,
+            import_id: None,
+            bundle: None,
+            age_restricted: false,
+            hazardous: false,
+            region_restrictions: Vec::new(),
+        };
+
+        let created = ProductEvent::created(&new_product).map_err(|e| Status::internal(e.to_string()))?;
+        let result = self
+            .collection()
+            .insert_one(&new_product, None)
+            .await
+            .map_err(|e| db_error("Failed to create product", e))?;
+        let object_id = result
+            .inserted_id
+            .as_object_id()
+            .ok_or_else(|| Status::internal("Unexpected inserted ID"))?;
+
+        info!("Product created via gRPC with ID: {}", object_id);
+        event_store::record(&self.db, object_id, vec![created]).await;
+        self.events.publish(DomainEvent::ProductCreated { product_id: object_id.to_string() });
+        stock::check_low_stock(&self.db, &self.events, &[object_id]).await;
+
+        self.fetch(object_id).await.map(Response::new)
+    }
+
+    async fn update_product(
+        &self,
+        request: Request<proto::UpdateProductRequest>,
+    ) -> Result<Response<proto::Product>, Status> {
+        self.limit(&request).await?;
+        let update = request.into_inner();
+        let object_id = parse_id(&update.id)?;
+        let price = update.price.map(parse_price).transpose()?;
+        let category = update.category.as_deref().map(parse_category).transpose()?;
+        let barcode = update.barcode.as_deref().map(normalize_barcode).transpose().map_err(Status::invalid_argument)?;
+        if update.stock_quantity.is_some_and(|quantity| quantity < 0) {
+            return Err(Status::invalid_argument("Stock quantity must be non-negative"));
+        }
+        if let Some(webhook) = &self.webhook {
+            let changes = UpdateProductRequest {
+                name: update.name.clone(),
+                price,
+                category: category.clone(),
+                has_active_sale: update.has_active_sale,
+                stock_quantity: update.stock_quantity,
+                low_stock_threshold: update.low_stock_threshold,
+                barcode: barcode.clone(),
+                ..UpdateProductRequest::default()
+            };
+            webhook.check_update(&update.id, &changes).await.map_err(rejected)?;
+        }
+
+        let mut update_doc = Document::new();
+        let existing = if update.name.is_some() || price.is_some() {
+            Some(
+                self.collection()
+                    .find_one(doc! { "_id": object_id }, None)
+                    .await
+                    .map_err(|e| db_error("Failed to fetch product", e))?
+                    .ok_or_else(|| Status::not_found("Product not found"))?,
+            )
+        } else {
+            None
+        };
+        if let (Some(name), Some(existing)) = (update.name, &existing) {
+            // A new name needs a new slug
+            update_doc.extend(
+                slugs::rename_fields(&self.db, existing, &name)
+                    .await
+                    .map_err(|e| db_error("Failed to generate slug", e))?,
+            );
+            update_doc.extend(search::rename_fields(&name));
+            update_doc.insert("name", name);
+        }
+        if let (Some(price), Some(existing)) = (price, &existing) {
+            // gRPC callers have no user to file an approval request for
+            if price_approvals::needs_approval(&self.approvals, existing.price, price) {
+                return Err(Status::failed_precondition(
+                    "Price change needs approval, request it through PUT /api/products/{id}",
+                ));
+            }
+            update_doc.insert("price", money::to_bson(price));
+        }
+        if let Some(category) = category {
+            update_doc.insert("category", category.to_string());
+        }
+        if let Some(has_active_sale) = update.has_active_sale {
+            update_doc.insert("has_active_sale", has_active_sale);
+        }
+        if let Some(stock_quantity) = update.stock_quantity {
+            update_doc.insert("stock_quantity", stock_quantity);
+        }
+        if let Some(low_stock_threshold) = update.low_stock_threshold {
+            update_doc.insert("low_stock_threshold", low_stock_threshold);
+        }
+        if let Some(barcode) = barcode {
+            update_doc.insert("barcode", barcode);
+        }
+
+        if update_doc.is_empty() {
+            return Err(Status::invalid_argument("No fields to update"));
+        }
+
+        let changes = ProductEvent::for_update(&update_doc);
+        let written = match update.stock_quantity {
+            // Units the product no longer has come off its locations too
+            Some(stock_quantity) => locations::update_with_stock(&self.db, object_id, update_doc, stock_quantity).await,
+            None => self
+                .collection()
+                .update_one(doc! { "_id": object_id }, doc! { "$set": update_doc }, None)
+                .await
+                .map(|result| result.matched_count > 0),
+        };
+
+        if !written.map_err(|e| db_error("Failed to update product", e))? {
+            return Err(Status::not_found("Product not found"));
+        }
+
+        info!("Product updated via gRPC: {}", object_id);
+        event_store::record(&self.db, object_id, changes).await;
+        self.events.publish(DomainEvent::ProductUpdated { product_id: object_id.to_string() });
+        if update.stock_quantity.is_some() || update.low_stock_threshold.is_some() {
+            stock::check_low_stock(&self.db, &self.events, &[object_id]).await;
+        }
+
+        self.fetch(object_id).await.map(Response::new)
+    }
+
+    async fn delete_product(
+        &self,
+        request: Request<proto::DeleteProductRequest>,
+    ) -> Result<Response<proto::DeleteProductResponse>, Status> {
+        self.limit(&request).await?;
+        let object_id = parse_id(&request.get_ref().id)?;
+
+        let trashed = trash::move_to_trash(&self.db, object_id, None)
+            .await
+            .map_err(|e| db_error("Failed to delete product", e))?;
+
+        if !trashed {
+            return Err(Status::not_found("Product not found"));
+        }
+
+        info!("Product deleted via gRPC: {}", object_id);
+        self.events.publish(DomainEvent::ProductDeleted { product_id: object_id.to_string() });
+        Ok(Response::new(proto::DeleteProductResponse {}))
+    }
+
+    type WatchProductsStream = ProductEventStream;
+
+    async fn watch_products(
+        &self,
+        request: Request<proto::WatchProductsRequest>,
+    ) -> Result<Response<Self::WatchProductsStream>, Status> {
+        self.limit(&request).await?;
+        let receiver = self.events.subscribe();
+
+        let events = stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    // Only events about a single product are part of this stream
+                    Ok(event) if event.product_id().is_none() => {}
+                    Ok(event) => return Some((Ok(proto::ProductEvent::from(event)), receiver)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("gRPC watcher lagged, skipped {} events", skipped);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+/// Starts the gRPC server on GRPC_ADDR in the background. When GRPC_AUTH_TOKEN
+/// is set, every call must carry it as `authorization: Bearer <token>`. Calls
+/// are rate limited per API key like product routes are per user.
+pub fn spawn_server(
+    db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
+    limits: web::Data<LimitsConfig>,
+    approvals: web::Data<PriceApprovalConfig>,
+    webhook: Option<web::Data<ValidationWebhook>>,
+    limiter: Option<web::Data<RateLimiter>>,
+    config: &GrpcConfig,
+) {
+    let addr = config.addr;
+    let expected = config.auth_token.clone();
+    if expected.is_none() {
+        warn!("GRPC_AUTH_TOKEN not set, gRPC server accepts unauthenticated calls");
+    }
+
+    let check_auth = move |request: Request<()>| -> Result<Request<()>, Status> {
+        let Some(expected) = &expected else {
+            return Ok(request);
+        };
+        if bearer_token(&request).is_some_and(|token| token_matches(token, expected)) {
+            Ok(request)
+        } else {
+            Err(Status::unauthenticated("Invalid or missing token"))
+        }
+    };
+
+    let service = ProductServiceServer::with_interceptor(ProductGrpcService { db, events, limits, approvals, webhook, limiter }, check_auth);
+
+    tokio::spawn(async move {
+        info!("gRPC server listening on {}", addr);
+        if let Err(e) = Server::builder().add_service(service).serve(addr).await {
+            error!("gRPC server failed: {}", e);
+        }
+    });
+}
@@ -1,178 +1,863 @@
-use actix_web::{web, HttpResponse, Error};
-use actix_multipart::Multipart;
+use actix_web::{
+    error::JsonPayloadError, http::StatusCode, web, web::Bytes, HttpRequest, HttpResponse, Error, Responder, ResponseError,
+};
+use actix_multipart::{Field, Multipart};
 use mongodb::{
-    bson::{doc, oid::ObjectId, Document},
+    bson::{doc, oid::ObjectId, Bson, Document},
     options::FindOptions,
     Collection,
 };
-use futures::TryStreamExt;
+use futures::{channel::mpsc, future::{self, BoxFuture}, stream, SinkExt, TryStreamExt};
 use tracing::{info, error, debug};
 use serde::{Deserialize, Serialize};
 use csv::ReaderBuilder;
-use tempfile::NamedTempFile;
 use regex::escape;
+use validator::Validate;
 use futures_util::StreamExt;
-use std::io::Write;
-use crate::{config::MongoConfig, models::{Product, CreateProductRequest, UpdateProductRequest, Category}};
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::{analytics::{Analytics, AnalyticsEvent}, attributes, compliance::{self, CustomerContext}, conditions::{self, Condition, ConditionalQuery}, auth::{Claims, SCOPE_PRODUCTS_WRITE}, bundles::{self, BundleExpansion}, drafts, errors::ApiError, event_store::{self, ProductEvent}, barcode::{is_duplicate_key, normalize_barcode}, config::{ComplianceConfig, LimitsConfig, MongoConfig, PriceApprovalConfig, TaxConfig}, events::{DomainEvent, EventHub}, favorites, price_approvals::{self, PriceChangeResponse}, public_ids, relationships::{self, RelatedProduct, RelationshipKind}, import_batches::{BatchInserter, BatchLimits}, import_formats::{Delimited, FileFormat, ImportQuery, RawRecord, RawTable}, import_rules::{DecimalSeparator, ImportRules, PriceFormat}, import_history::{self, ImportLog, ImportOrigin}, locations::{self, LocationStock}, money::{self, Decimal}, negotiation::{Negotiated, Tabular}, saved_filters, search, search_queries::SearchLog, slugs, tax::{self, PriceBreakdown, TaxTable}, trash, versioning::ApiVersion, views::ViewCounter, stock, pricing, suppliers, models::{Product, ProductStatus, TaxClass, Unit, CreateProductRequest, UpdateProductRequest, Category}, validation::{page_offset, validation_error}, validation_webhook::ValidationWebhook};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Validate, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct ListProductsQuery {
+    #[validate(range(min = 1, message = "page must be at least 1"))]
     page: Option<i64>,
+    // The upper bound is LimitsConfig::max_per_page, checked in the handler
+    #[validate(range(min = 1, message = "per_page must be at least 1"))]
     per_page: Option<i64>,
     filter: Option<String>,
-    price: Option<f64>,
+    // Matches `filter` against names with small typos, best match first
+    fuzzy: Option<bool>,
+    #[serde(default, with = "money::option_input_price")]
+    price: Option<Decimal>,
+    status: Option<String>,
     sort: Option<String>,
     direction: Option<String>,
+    with_favorites: Option<bool>,
+    // Adds per-location stock to each product
+    with_locations: Option<bool>,
+    include_total: Option<TotalMode>,
+    // name:value pairs, e.g. "voltage:220,plug:eu"
+    attributes: Option<String>,
+    // Saved filter to start from; parameters on the request override it
+    filter_id: Option<String>,
+    // Answers with the data/meta/links envelope of v2 from v1
+    envelope: Option<bool>,
+    // Tax region for price breakdowns, TAX_DEFAULT_REGION when unset
+    region: Option<String>,
+    // filter[field][op]=value pairs, taken from the query string by ConditionalQuery
+    #[validate(custom = "conditions::validate")]
+    conditions: Option<Vec<Condition>>,
+}
+
+impl ListProductsQuery {
+    /// The query as stored in a saved filter, which never pins a page or
+    /// points at another filter.
+    pub fn for_saving(self) -> Self {
+        ListProductsQuery { page: None, filter_id: None, envelope: None, ..self }
+    }
+
+    fn or_saved(self, saved: ListProductsQuery) -> Self {
+        ListProductsQuery {
+            page: self.page,
+            per_page: self.per_page.or(saved.per_page),
+            filter: self.filter.or(saved.filter),
+            fuzzy: self.fuzzy.or(saved.fuzzy),
+            price: self.price.or(saved.price),
+            status: self.status.or(saved.status),
+            sort: self.sort.or(saved.sort),
+            direction: self.direction.or(saved.direction),
+            with_favorites: self.with_favorites.or(saved.with_favorites),
+            with_locations: self.with_locations.or(saved.with_locations),
+            include_total: self.include_total.or(saved.include_total),
+            attributes: self.attributes.or(saved.attributes),
+            filter_id: None,
+            envelope: self.envelope,
+            region: self.region.or(saved.region),
+            conditions: conditions::merge(self.conditions, saved.conditions),
+        }
+    }
+}
+
+// How list_products computes total_pages; counting is the expensive part of a listing
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum TotalMode {
+    #[default]
+    #[serde(rename = "true")]
+    Exact,
+    #[serde(rename = "false")]
+    Skip,
+    // Uses the collection metadata count when nothing is filtered, exact otherwise
+    #[serde(rename = "estimated")]
+    Estimated,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct StreamProductsQuery {
+    status: Option<String>,
+    // Products read from the database per round trip
+    #[validate(range(min = 1, max = 10000, message = "batch_size must be between 1 and 10000"))]
+    batch_size: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProductListItem {
+    #[serde(flatten)]
+    product: Product,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_favorite: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    price_breakdown: Option<PriceBreakdown>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    availability: Option<Vec<LocationStock>>,
+    // Components and stock of a bundle, on single-product responses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expanded_bundle: Option<BundleExpansion>,
+    // Related, upsell and cross-sell products, on single-product responses
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    relationships: BTreeMap<RelationshipKind, Vec<RelatedProduct>>,
+}
+
+impl ProductListItem {
+    fn new(product: Product, is_favorite: Option<bool>, tax: Option<&TaxTable>) -> Self {
+        let price_breakdown = tax.map(|tax| tax.breakdown(product.price, product.tax_class));
+        ProductListItem { product, is_favorite, price_breakdown, availability: None, expanded_bundle: None, relationships: BTreeMap::new() }
+    }
+
+    async fn with_bundle_expanded(mut self, db: &MongoConfig) -> Result<Self, Error> {
+        if let Some(bundle) = &self.product.bundle {
+            self.expanded_bundle = Some(bundles::expand(db, bundle).await?);
+        }
+        Ok(self)
+    }
+
+    async fn with_relationships(mut self, db: &MongoConfig) -> Result<Self, Error> {
+        if let Some(id) = self.product.id {
+            self.relationships = relationships::embedded(db, id).await?;
+        }
+        Ok(self)
+    }
 }
 
 #[derive(Debug, Serialize)]
 pub struct ListProductsResponse {
-    products: Vec<Product>,
-    total_pages: i64,
+    products: Vec<ProductListItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_pages: Option<i64>,
+    has_more: bool,
+}
+
+// The importer's columns come first, so CSV responses can be imported again
+impl Tabular for Product {
+    fn header() -> &'static [&'static str] {
+        &["name", "price", "category", "has_active_sale", "id", "public_id", "slug", "status", "stock_quantity", "barcode"]
+    }
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        vec![vec![
+            self.name.clone(),
+            self.price.to_string(),
+            self.category.to_string(),
+            self.has_active_sale.to_string(),
+            optional(self.id.map(|id| id.to_string())),
+            optional(self.public_id.clone()),
+            optional(self.slug.clone()),
+            self.status.to_string(),
+            optional(self.stock_quantity.map(|quantity| quantity.to_string())),
+            optional(self.barcode.clone()),
+        ]]
+    }
+}
+
+impl Tabular for ProductListItem {
+    fn header() -> &'static [&'static str] {
+        Product::header()
+    }
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        self.product.rows()
+    }
+}
+
+impl Tabular for ListProductsResponse {
+    fn header() -> &'static [&'static str] {
+        ProductListItem::header()
+    }
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        self.products.iter().flat_map(Tabular::rows).collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListMeta {
+    page: i64,
+    per_page: i64,
+    // Unset when the count was skipped with include_total=false
+    total: Option<u64>,
+    total_pages: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListLinks {
+    #[serde(rename = "self")]
+    self_link: String,
+    next: Option<String>,
+    prev: Option<String>,
+    first: String,
+    last: Option<String>,
+}
+
+/// Standard list envelope: the page of items, paging metadata and absolute
+/// links to neighbouring pages.
+#[derive(Debug, Serialize)]
+pub struct ListEnvelope<T> {
+    data: Vec<T>,
+    meta: ListMeta,
+    links: ListLinks,
+}
+
+impl<T> ListEnvelope<T> {
+    pub fn new(req: &HttpRequest, data: Vec<T>, page: i64, per_page: i64, total: Option<u64>, has_more: bool) -> Self {
+        let total_pages = total.map(|total| ((total as f64) / (per_page as f64)).ceil() as i64);
+        let link = |page: i64| page_link(req, page);
+        ListEnvelope {
+            data,
+            meta: ListMeta { page, per_page, total, total_pages },
+            links: ListLinks {
+                self_link: link(page),
+                next: has_more.then(|| link(page + 1)),
+                prev: (page > 1).then(|| link(page - 1)),
+                first: link(1),
+                // An empty listing still has a first page
+                last: total_pages.map(|pages| link(pages.max(1))),
+            },
+        }
+    }
+}
+
+impl<T: Tabular> Tabular for ListEnvelope<T> {
+    fn header() -> &'static [&'static str] {
+        T::header()
+    }
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        self.data.iter().flat_map(Tabular::rows).collect()
+    }
+}
+
+// The request's absolute URL with `page` replaced
+fn page_link(req: &HttpRequest, page: i64) -> String {
+    let info = req.connection_info();
+    let base = format!("{}://{}{}", info.scheme(), info.host(), req.path());
+    let Ok(mut url) = reqwest::Url::parse(&format!("{}?{}", base, req.query_string())) else {
+        return base;
+    };
+
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| key != "page")
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    url.query_pairs_mut().clear().extend_pairs(pairs).append_pair("page", &page.to_string());
+    url.to_string()
+}
+
+/// Whether to answer with the list envelope: always from v2 on, and in v1
+/// when asked for with `envelope=true`.
+pub fn wants_envelope(req: &HttpRequest, envelope: Option<bool>) -> bool {
+    envelope.unwrap_or_else(|| ApiVersion::of(req) >= ApiVersion::V2)
+}
+
+// Turns oversized JSON bodies into a 413 and malformed ones (including unknown
+// fields) into a 400, both with a JSON body instead of a plain-text error
+pub fn json_error_handler(err: JsonPayloadError, req: &HttpRequest) -> Error {
+    match &err {
+        JsonPayloadError::Overflow { limit } | JsonPayloadError::OverflowKnownLength { limit, .. } => {
+            debug!("Rejected oversized JSON payload on {}", req.path());
+            let limit = *limit as i64;
+            actix_web::error::InternalError::from_response(
+                err,
+                ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, format!("JSON payload exceeds the limit of {} bytes", limit))
+                    .with("limit", limit)
+                    .error_response(),
+            )
+            .into()
+        }
+        JsonPayloadError::Deserialize(e) => {
+            debug!("Rejected invalid JSON payload on {}: {}", req.path(), e);
+            let message = format!("Invalid JSON body: {}", e);
+            actix_web::error::InternalError::from_response(
+                err,
+                ApiError::new(StatusCode::BAD_REQUEST, message).error_response(),
+            )
+            .into()
+        }
+        _ => err.into(),
+    }
+}
+
+pub fn payload_too_large(message: String, limit: usize) -> ApiError {
+    ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, message).with("limit", limit as i64)
 }
 
 pub async fn create_product(
     db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
+    webhook: Option<web::Data<ValidationWebhook>>,
     product: web::Json<CreateProductRequest>,
 ) -> Result<HttpResponse, Error> {
     let collection: Collection<Product> = db.database.collection("products");
 
     debug!("Creating new product: {:?}", product);
 
+    if product.stock_quantity.is_some_and(|q| q < 0) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Stock quantity must be non-negative").into());
+    }
+    let sku = product.sku.as_deref().map(check_sku).transpose().map_err(ApiError::bad_request)?;
+    let description = product
+        .description
+        .as_deref()
+        .map(clean_description)
+        .transpose()
+        .map_err(ApiError::bad_request)?;
+    let unit = product.unit.unwrap_or_default();
+    let price_tiers = product.price_tiers.clone().unwrap_or_default();
+    pricing::check_pricing(product.price, unit, product.price_per_unit, &price_tiers)
+        .map_err(ApiError::bad_request)?;
+    if product.cost_price.is_some_and(|cost| cost.is_sign_negative()) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Cost price must be non-negative").into());
+    }
+    attributes::check_product_attributes(&db, &product.category, product.attributes.as_ref()).await?;
+    compliance::check_flags(&product.category, product.age_restricted, product.hazardous)?;
+    let region_restrictions =
+        compliance::normalize_regions(product.region_restrictions.as_deref().unwrap_or_default())?;
+    let supplier_id = match &product.supplier_id {
+        Some(id) => Some(suppliers::check_supplier(&db, id).await?),
+        None => None,
+    };
+    let barcode = product
+        .barcode
+        .as_deref()
+        .map(normalize_barcode)
+        .transpose()
+        .map_err(ApiError::bad_request)?;
+    // Only products that pass our own checks are worth sending out
+    if let Some(webhook) = &webhook {
+        webhook.check_create(&product).await?;
+    }
+    let slug = slugs::unique_slug(&db, &product.name, None).await.map_err(|e| {
+        error!("Failed to generate slug: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+    })?;
+
     let new_product = Product {
         id: None,
+        public_id: Some(public_ids::new_public_id()),
         name: product.name.clone(),
+        slug: Some(slug),
+        previous_slugs: Vec::new(),
+        search_grams: search::name_grams(&product.name),
+        sku,
+        description,
         price: product.price,
         category: product.category.clone(),
+        status: product.status.unwrap_or_default(),
+        tax_class: product.tax_class.unwrap_or_default(),
+        unit,
+        price_per_unit: product.price_per_unit,
+        price_tiers,
         has_active_sale: product.has_active_sale,
+        sale_ends_at: product.sale_ends_at.map(|t| mongodb::bson::DateTime::from_millis(t.timestamp_millis())),
+        stock_quantity: product.stock_quantity,
+        low_stock_threshold: product.low_stock_threshold,
+        barcode,
+        attributes: product.attributes.clone(),
+        supplier_id,
+        supplier_sku: product.supplier_sku.clone(),
+        cost_price: product.cost_price,
+        import_id: None,
+        bundle: None,
+        age_restricted: product.age_restricted,
+        hazardous: product.hazardous,
+        region_restrictions,
     };
 
-    let result = collection.insert_one(new_product, None).await.map_err(|e| {
+    let created = ProductEvent::created(&new_product).map_err(ApiError::internal)?;
+    let result = collection.insert_one(&new_product, None).await.map_err(|e| {
+        if slugs::is_duplicate_slug(&e) {
+            debug!("Slug {:?} was taken concurrently", new_product.slug);
+            return ApiError::new(StatusCode::CONFLICT, CONCURRENT_SLUG_MESSAGE);
+        }
+        if is_duplicate_sku(&e) {
+            debug!("Rejected product with a SKU already in use");
+            return ApiError::new(StatusCode::CONFLICT, "A product with this SKU already exists");
+        }
+        if is_duplicate_key(&e) {
+            debug!("Rejected product with a barcode already in use");
+            return ApiError::new(StatusCode::CONFLICT, "A product with this barcode already exists");
+        }
         error!("Failed to create product: {}", e);
-        actix_web::error::ErrorInternalServerError(format!("Database error: {}", e))
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
     })?;
 
     info!("Product created successfully with ID: {}", result.inserted_id);
+
+    if let Some(product_id) = result.inserted_id.as_object_id() {
+        event_store::record(&db, product_id, vec![created]).await;
+        events.publish(DomainEvent::ProductCreated { product_id: product_id.to_string() });
+        stock::check_low_stock(&db, &events, &[product_id]).await;
+    }
     Ok(HttpResponse::Created().json(doc! { "id": result.inserted_id }))
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GetProductQuery {
+    region: Option<String>,
+    // Shows the product as it will be once its draft is published
+    #[serde(default)]
+    draft: bool,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn get_product(
+    req: HttpRequest,
     db: web::Data<MongoConfig>,
+    views: web::Data<ViewCounter>,
+    analytics: web::Data<Analytics>,
+    tax_config: web::Data<TaxConfig>,
+    claims: web::ReqData<Claims>,
     id: web::Path<String>,
+    query: web::Query<GetProductQuery>,
 ) -> Result<HttpResponse, Error> {
     let collection: Collection<Product> = db.database.collection("products");
 
     debug!("Fetching product with ID: {}", id);
 
-    let object_id = ObjectId::parse_str(id.as_str()).map_err(|_| {
-        error!("Invalid product ID format: {}", id);
-        actix_web::error::ErrorBadRequest("Invalid ID format")
-    })?;
+    let object_id = public_ids::resolve_product_id(&db, &id).await?;
 
     let filter = doc! { "_id": object_id };
     let product = collection.find_one(filter, None).await.map_err(|e| {
         error!("Failed to fetch product {}: {}", id, e);
-        actix_web::error::ErrorInternalServerError(format!("Database error: {}", e))
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
     })?;
 
     match product {
+        Some(mut product) if query.draft => {
+            // Staged copy is only for those who can publish it
+            if !claims.has_scope(SCOPE_PRODUCTS_WRITE) {
+                let message = format!("Missing required scope: {}", SCOPE_PRODUCTS_WRITE);
+                return Err(ApiError::new(StatusCode::FORBIDDEN, message).into());
+            }
+            if let Some(draft) = drafts::find_draft(&db, object_id).await? {
+                drafts::preview(&mut product, &draft.changes);
+            }
+            let tax = tax::load_table(&db, &tax_config, query.region.as_deref()).await?;
+            let item = ProductListItem::new(product, None, tax.as_ref())
+                .with_bundle_expanded(&db)
+                .await?
+                .with_relationships(&db)
+                .await?;
+            Ok(Negotiated(item).respond_to(&req))
+        },
         Some(product) => {
             info!("Product found: {}", id);
-            Ok(HttpResponse::Ok().json(product))
+            views.record(object_id);
+            analytics.record(AnalyticsEvent::ProductViewed { product_id: object_id.to_string() });
+            let tax = tax::load_table(&db, &tax_config, query.region.as_deref()).await?;
+            let item = ProductListItem::new(product, None, tax.as_ref())
+                .with_bundle_expanded(&db)
+                .await?
+                .with_relationships(&db)
+                .await?;
+            Ok(Negotiated(item).respond_to(&req))
         },
         None => {
             debug!("Product not found: {}", id);
-            Ok(HttpResponse::NotFound().finish())
+            Err(ApiError::blank(StatusCode::NOT_FOUND).into())
         },
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn list_products(
+    req: HttpRequest,
     db: web::Data<MongoConfig>,
-    query: web::Query<ListProductsQuery>,
+    analytics: web::Data<Analytics>,
+    searches: web::Data<SearchLog>,
+    limits: web::Data<LimitsConfig>,
+    tax_config: web::Data<TaxConfig>,
+    compliance: web::Data<ComplianceConfig>,
+    claims: web::ReqData<Claims>,
+    query: ConditionalQuery<ListProductsQuery>,
 ) -> Result<HttpResponse, Error> {
-    let collection: Collection<Product> = db.database.collection("products");
+    let collection: Collection<Product> = db.catalog_collection("products");
+
+    let ConditionalQuery(mut query, conditions) = query;
+    if !conditions.is_empty() {
+        query.conditions = Some(conditions);
+    }
+    let query = match &query.filter_id {
+        Some(filter_id) => match saved_filters::find_visible(&db, filter_id, &claims.user_id()?).await? {
+            Some(saved) => query.or_saved(saved),
+            None => {
+                debug!("Saved filter not found: {}", filter_id);
+                return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+            }
+        },
+        None => query,
+    };
 
     // Set up pagination
     let per_page = query.per_page.unwrap_or(15);
+    if per_page > limits.max_per_page {
+        let message = format!("per_page must be between 1 and {}", limits.max_per_page);
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, message).into());
+    }
     let page = query.page.unwrap_or(1).max(1);
-    let skip = (page - 1) * per_page;
+    let skip = page_offset(page, per_page)?;
 
     // Build filter
     let mut filter = Document::new();
-    if let Some(name_filter) = &query.filter {
+    let fuzzy_query = query.filter.as_deref().filter(|_| query.fuzzy.unwrap_or(false));
+    if let Some(name_filter) = fuzzy_query {
+        let Some(fuzzy_filter) = search::fuzzy_filter(name_filter) else {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "filter must contain a letter or digit for fuzzy search",
+            ).into());
+        };
+        filter.extend(fuzzy_filter);
+    } else if let Some(name_filter) = &query.filter {
         filter.insert("name", doc! {
             "$regex": format!("(?i){}", escape(name_filter))
         });
     }
     if let Some(price) = query.price {
-        filter.insert("price", price);
+        filter.insert("price", money::to_bson(price));
+    }
+    if let Some(spec) = &query.attributes {
+        match attributes::attribute_filter(spec) {
+            Ok(attribute_filter) => filter.extend(attribute_filter),
+            Err(message) => return Err(ApiError::new(StatusCode::BAD_REQUEST, message).into()),
+        }
+    }
+    let conditions = query.conditions.as_deref().unwrap_or_default();
+    if !conditions.is_empty() {
+        match conditions::filter(conditions) {
+            Ok(condition_filter) => filter.extend(condition_filter),
+            Err(message) => return Err(ApiError::new(StatusCode::BAD_REQUEST, message).into()),
+        }
     }
 
-    // Build sort
-    let allowed_sort_columns = ["name", "price"];
+    if let Some(status) = status_filter(query.status.as_deref(), conditions)? {
+        filter.insert("status", status);
+    }
+    filter.extend(CustomerContext::from_request(&req)?.filter(&compliance));
+
+    // Build sort; popularity is most viewed first unless asked otherwise, and
+    // fuzzy searches are best match first
+    let allowed_sort_columns = ["name", "price", "popularity"];
     let sort_column = query.sort
         .as_deref()
         .filter(|&s| allowed_sort_columns.contains(&s))
-        .unwrap_or("name");
+        .unwrap_or(if fuzzy_query.is_some() { "relevance" } else { "name" });
 
-    let sort_direction = match query.direction.as_deref() {
-        Some("desc") => -1,
+    let sort_direction = match (query.direction.as_deref(), sort_column) {
+        (Some("desc"), _) | (None, "popularity") => -1,
         _ => 1,
     };
 
-    let sort_doc = doc! { sort_column: sort_direction };
+    let sort_doc = match sort_column {
+        "popularity" => doc! { "view_count": sort_direction, "name": 1 },
+        "relevance" => doc! { "fuzzy_score": -1, "name": 1 },
+        _ => doc! { sort_column: sort_direction },
+    };
 
-    // Set up options with sort and pagination
-    let find_options = FindOptions::builder()
-        .sort(sort_doc)
-        .skip(skip as u64)
-        .limit(per_page as i64)
-        .build();
+    // One extra row tells whether another page exists
+    let mut page_stages = vec![
+        doc! { "$sort": sort_doc },
+        doc! { "$skip": skip },
+        doc! { "$limit": per_page + 1 },
+    ];
+    if let Some(fuzzy_query) = fuzzy_query {
+        page_stages.insert(0, search::fuzzy_score_stage(fuzzy_query));
+        page_stages.push(doc! { "$unset": "fuzzy_score" });
+    }
+
+    let (mut products, total_count) = match query.include_total.unwrap_or_default() {
+        TotalMode::Skip => (fetch_page(&collection, filter, page_stages).await?, None),
+        TotalMode::Estimated if filter.is_empty() => {
+            let estimate = collection.estimated_document_count(None).await.map_err(|e| {
+                error!("Failed to estimate product count: {}", e);
+                ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+            })?;
+            (fetch_page(&collection, filter, page_stages).await?, Some(estimate))
+        }
+        TotalMode::Exact | TotalMode::Estimated => {
+            let (products, total) = fetch_page_with_total(&collection, filter, page_stages).await?;
+            (products, Some(total))
+        }
+    };
+
+    let total_pages = total_count.map(|count| ((count as f64) / (per_page as f64)).ceil() as i64);
+
+    let has_more = products.len() as i64 > per_page;
+    products.truncate(per_page as usize);
+
+    if let Some(search) = &query.filter {
+        let result_count = total_count.unwrap_or(products.len() as u64);
+        searches.record(search, result_count);
+        analytics.record(AnalyticsEvent::SearchPerformed { query: search.clone(), result_count, source: "catalog" });
+    }
+
+    match total_pages {
+        Some(total_pages) => info!("Retrieved {} products (page {} of {})", products.len(), page, total_pages),
+        None => info!("Retrieved {} products (page {})", products.len(), page),
+    }
 
-    // Get total count for pagination
-    let total_count = collection.count_documents(filter.clone(), None).await.map_err(|e| {
-        error!("Failed to count products: {}", e);
-        actix_web::error::ErrorInternalServerError(format!("Database error: {}", e))
+    // Optionally mark the caller's favorites
+    let favorite_ids = if query.with_favorites.unwrap_or(false) {
+        let product_ids: Vec<ObjectId> = products.iter().filter_map(|p| p.id).collect();
+        Some(favorites::favorite_product_ids(&db, &claims.user_id()?, &product_ids).await?)
+    } else {
+        None
+    };
+
+    // Optionally add stock per location
+    let mut availability = if query.with_locations.unwrap_or(false) {
+        let product_ids: Vec<ObjectId> = products.iter().filter_map(|p| p.id).collect();
+        Some(locations::availability(&db, &product_ids).await?)
+    } else {
+        None
+    };
+
+    let tax = tax::load_table(&db, &tax_config, query.region.as_deref()).await?;
+    let products: Vec<ProductListItem> = products
+        .into_iter()
+        .map(|product| {
+            let is_favorite = favorite_ids
+                .as_ref()
+                .map(|ids| product.id.map(|id| ids.contains(&id)).unwrap_or(false));
+            let stocks = availability
+                .as_mut()
+                .map(|stocks| product.id.and_then(|id| stocks.remove(&id)).unwrap_or_default());
+            let mut item = ProductListItem::new(product, is_favorite, tax.as_ref());
+            item.availability = stocks;
+            item
+        })
+        .collect();
+
+    if wants_envelope(&req, query.envelope) {
+        return Ok(Negotiated(ListEnvelope::new(&req, products, page, per_page, total_count, has_more)).respond_to(&req));
+    }
+    Ok(Negotiated(ListProductsResponse {
+        products,
+        total_pages,
+        has_more,
+    })
+    .respond_to(&req))
+}
+
+// Bytes of NDJSON collected before they are handed to the connection
+const STREAM_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Streams every matching product as one JSON object per line, oldest
+/// first. The cursor is only read as fast as the client takes the data, so
+/// memory stays flat however large the catalog is.
+pub async fn stream_products(
+    db: web::Data<MongoConfig>,
+    query: ConditionalQuery<StreamProductsQuery>,
+) -> Result<HttpResponse, Error> {
+    let ConditionalQuery(query, conditions) = query;
+    let mut filter = if conditions.is_empty() {
+        Document::new()
+    } else {
+        conditions::filter(&conditions).map_err(ApiError::bad_request)?
+    };
+    if let Some(status) = status_filter(query.status.as_deref(), &conditions)? {
+        filter.insert("status", status);
+    }
+
+    let collection: Collection<Product> = db.catalog_collection("products");
+    let options = FindOptions::builder()
+        .sort(doc! { "_id": 1 })
+        .batch_size(query.batch_size.unwrap_or(1000))
+        .build();
+    let cursor = collection.find(filter, options).await.map_err(|e| {
+        error!("Failed to stream products: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
     })?;
 
-    let total_pages = ((total_count as f64) / (per_page as f64)).ceil() as i64;
+    info!("Streaming products as NDJSON");
+    let body = stream::unfold(Some(cursor), |cursor| async move {
+        let mut cursor = cursor?;
+        let mut chunk = Vec::new();
+        while chunk.len() < STREAM_CHUNK_BYTES {
+            let product = match cursor.try_next().await {
+                Ok(Some(product)) => product,
+                Ok(None) => {
+                    info!("Product stream complete");
+                    return (!chunk.is_empty()).then(|| (Ok(Bytes::from(chunk)), None));
+                }
+                Err(e) => {
+                    // Headers are already sent, so the client sees a truncated stream
+                    error!("Product stream aborted: {}", e);
+                    let problem = ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e));
+                    return Some((Err(problem.into()), None));
+                }
+            };
+            if let Err(e) = serde_json::to_writer(&mut chunk, &ProductListItem::new(product, None, None)) {
+                error!("Product stream aborted: {}", e);
+                return Some((Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into()), None));
+            }
+            chunk.push(b'\n');
+        }
+        Some((Ok::<_, Error>(Bytes::from(chunk)), Some(cursor)))
+    });
 
-    // Fetch products
-    let mut products = Vec::new();
-    let mut cursor = collection.find(filter, find_options).await.map_err(|e| {
+    Ok(HttpResponse::Ok().content_type("application/x-ndjson").streaming(body))
+}
+
+async fn fetch_page(
+    collection: &Collection<Product>,
+    filter: Document,
+    page_stages: Vec<Document>,
+) -> Result<Vec<Product>, Error> {
+    let mut pipeline = vec![doc! { "$match": filter }];
+    pipeline.extend(page_stages);
+
+    let mut cursor = collection.aggregate(pipeline, None).await.map_err(|e| {
         error!("Failed to fetch products: {}", e);
-        actix_web::error::ErrorInternalServerError(format!("Database error: {}", e))
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
     })?;
 
-    while let Some(result) = cursor.try_next().await.map_err(|e| {
+    let mut products = Vec::new();
+    while let Some(document) = cursor.try_next().await.map_err(|e| {
         error!("Error while iterating products: {}", e);
-        actix_web::error::ErrorInternalServerError(format!("Database error: {}", e))
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
     })? {
-        products.push(result);
+        products.push(product_from_document(document)?);
     }
+    Ok(products)
+}
 
-    info!("Retrieved {} products (page {} of {})", products.len(), page, total_pages);
+// Only active products unless a status is requested, here or as a
+// condition; "all" disables the filter
+fn status_filter(status: Option<&str>, conditions: &[Condition]) -> Result<Option<Bson>, Error> {
+    let status_condition = conditions.iter().any(|condition| condition.field == "status");
+    match status {
+        None if status_condition => Ok(None),
+        // Documents without a status predate the lifecycle and count as active
+        None | Some("active") => Ok(Some(doc! { "$in": ["active", null] }.into())),
+        Some(status @ ("draft" | "archived")) => Ok(Some(status.into())),
+        Some("all") => Ok(None),
+        Some(other) => Err(ApiError::new(StatusCode::BAD_REQUEST, format!(
+            "Invalid status '{}': expected draft, active, archived or all", other
+        )).into()),
+    }
+}
 
-    Ok(HttpResponse::Ok().json(ListProductsResponse {
-        products,
-        total_pages,
-    }))
+// Page and total count in a single round trip
+async fn fetch_page_with_total(
+    collection: &Collection<Product>,
+    filter: Document,
+    page_stages: Vec<Document>,
+) -> Result<(Vec<Product>, u64), Error> {
+    let pipeline = vec![
+        doc! { "$match": filter },
+        doc! { "$facet": {
+            "products": page_stages,
+            "total": [{ "$count": "count" }],
+        } },
+    ];
+
+    let mut cursor = collection.aggregate(pipeline, None).await.map_err(|e| {
+        error!("Failed to fetch products: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+    })?;
+
+    let result = cursor
+        .try_next()
+        .await
+        .map_err(|e| {
+            error!("Error while reading product page: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?
+        .unwrap_or_default();
+
+    let products = result
+        .get_array("products")
+        .map(|items| items.iter().filter_map(|item| item.as_document().cloned()).collect())
+        .unwrap_or_else(|_| Vec::new())
+        .into_iter()
+        .map(product_from_document)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // $count yields an int32 until the total outgrows it
+    let total = match result
+        .get_array("total")
+        .ok()
+        .and_then(|t| t.first())
+        .and_then(|t| t.as_document())
+        .and_then(|t| t.get("count"))
+    {
+        Some(Bson::Int32(count)) => *count as u64,
+        Some(Bson::Int64(count)) => *count as u64,
+        _ => 0,
+    };
+
+    Ok((products, total))
+}
+
+fn product_from_document(document: Document) -> Result<Product, Error> {
+    mongodb::bson::from_document(document).map_err(|e| {
+        error!("Failed to decode product: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+    })
 }
 
 pub async fn update_product(
     db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
+    approvals: web::Data<PriceApprovalConfig>,
+    webhook: Option<web::Data<ValidationWebhook>>,
+    claims: web::ReqData<Claims>,
     id: web::Path<String>,
     update: web::Json<UpdateProductRequest>,
+) -> Result<HttpResponse, Error> {
+    let webhook = webhook.as_ref().map(|webhook| webhook.get_ref());
+    apply_product_update(&db, &events, &approvals, webhook, claims.user_id()?, &id, &update).await
+}
+
+/// Applies an update to a product, as `PUT /products/{id}` and publishing a
+/// draft do.
+pub async fn apply_product_update(
+    db: &MongoConfig,
+    events: &EventHub,
+    approvals: &PriceApprovalConfig,
+    webhook: Option<&ValidationWebhook>,
+    user_id: ObjectId,
+    id: &str,
+    update: &UpdateProductRequest,
 ) -> Result<HttpResponse, Error> {
     let collection: Collection<Product> = db.database.collection("products");
 
     debug!("Updating product {}: {:?}", id, update);
 
-    let object_id = ObjectId::parse_str(id.as_str()).map_err(|_| {
-        error!("Invalid product ID format: {}", id);
-        actix_web::error::ErrorBadRequest("Invalid ID format")
-    })?;
+    let object_id = public_ids::resolve_product_id(db, id).await?;
+
+    if update.stock_quantity.is_some_and(|q| q < 0) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Stock quantity must be non-negative").into());
+    }
+    if update.cost_price.is_some_and(|cost| cost.is_sign_negative()) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Cost price must be non-negative").into());
+    }
 
     let mut update_doc = doc! {};
 
@@ -180,266 +865,1009 @@ pub async fn update_product(
         update_doc.insert("name", name);
     }
     if let Some(price) = update.price {
-        update_doc.insert("price", price);
+        update_doc.insert("price", money::to_bson(price));
     }
     if let Some(category) = &update.category {
         update_doc.insert("category", category.to_string());
     }
+    if let Some(tax_class) = update.tax_class {
+        update_doc.insert("tax_class", tax_class.to_string());
+    }
+    if let Some(unit) = update.unit {
+        update_doc.insert("unit", unit.to_string());
+    }
+    if let Some(price_per_unit) = update.price_per_unit {
+        update_doc.insert("price_per_unit", money::to_bson(price_per_unit));
+    }
+    if let Some(price_tiers) = &update.price_tiers {
+        update_doc.insert("price_tiers", pricing::tiers_to_bson(price_tiers));
+    }
     if let Some(has_active_sale) = update.has_active_sale {
         update_doc.insert("has_active_sale", has_active_sale);
     }
+    if let Some(sale_ends_at) = update.sale_ends_at {
+        update_doc.insert("sale_ends_at", mongodb::bson::DateTime::from_millis(sale_ends_at.timestamp_millis()));
+    }
+    if let Some(stock_quantity) = update.stock_quantity {
+        update_doc.insert("stock_quantity", stock_quantity);
+    }
+    if let Some(low_stock_threshold) = update.low_stock_threshold {
+        update_doc.insert("low_stock_threshold", low_stock_threshold);
+    }
+    if let Some(barcode) = &update.barcode {
+        update_doc.insert("barcode", normalize_barcode(barcode).map_err(ApiError::bad_request)?);
+    }
+    match update.supplier_id.as_deref() {
+        Some("") => {
+            update_doc.insert("supplier_id", Bson::Null);
+        }
+        Some(id) => {
+            update_doc.insert("supplier_id", suppliers::check_supplier(db, id).await?);
+        }
+        None => {}
+    }
+    if let Some(supplier_sku) = &update.supplier_sku {
+        update_doc.insert("supplier_sku", supplier_sku);
+    }
+    if let Some(sku) = &update.sku {
+        update_doc.insert("sku", check_sku(sku).map_err(ApiError::bad_request)?);
+    }
+    if let Some(description) = &update.description {
+        update_doc.insert("description", clean_description(description).map_err(ApiError::bad_request)?);
+    }
+    if let Some(cost_price) = update.cost_price {
+        update_doc.insert("cost_price", money::to_bson(cost_price));
+    }
+    if let Some(age_restricted) = update.age_restricted {
+        update_doc.insert("age_restricted", age_restricted);
+    }
+    if let Some(hazardous) = update.hazardous {
+        update_doc.insert("hazardous", hazardous);
+    }
+    if let Some(region_restrictions) = &update.region_restrictions {
+        update_doc.insert("region_restrictions", compliance::normalize_regions(region_restrictions)?);
+    }
 
-    let filter = doc! { "_id": object_id };
-    let update_doc = doc! { "$set": update_doc };
+    if let Some(webhook) = webhook {
+        webhook.check_update(id, update).await?;
+    }
+
+    // A new category or new attributes must fit the category's attribute definitions,
+    // as must new compliance flags, new pricing must be consistent with the
+    // pricing it keeps, and a new name needs a new slug
+    let changes_pricing = update.price.is_some()
+        || update.unit.is_some()
+        || update.price_per_unit.is_some()
+        || update.price_tiers.is_some();
+    let changes_compliance = update.age_restricted.is_some() || update.hazardous.is_some();
+    let mut price_change = None;
+    if update.name.is_some()
+        || update.category.is_some()
+        || update.attributes.is_some()
+        || changes_pricing
+        || changes_compliance
+    {
+        let Some(existing) = collection.find_one(doc! { "_id": object_id }, None).await.map_err(|e| {
+            error!("Failed to fetch product {}: {}", id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })? else {
+            debug!("Product not found for update: {}", id);
+            return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+        };
+        if changes_pricing {
+            pricing::check_pricing(
+                update.price.unwrap_or(existing.price),
+                update.unit.unwrap_or(existing.unit),
+                update.price_per_unit.or(existing.price_per_unit),
+                update.price_tiers.as_ref().unwrap_or(&existing.price_tiers),
+            )
+            .map_err(ApiError::bad_request)?;
+        }
+        if update.category.is_some() || update.attributes.is_some() {
+            let category = update.category.as_ref().unwrap_or(&existing.category);
+            let attributes = update.attributes.as_ref().or(existing.attributes.as_ref());
+            attributes::check_product_attributes(db, category, attributes).await?;
+        }
+        if update.category.is_some() || changes_compliance {
+            compliance::check_flags(
+                update.category.as_ref().unwrap_or(&existing.category),
+                update.age_restricted.unwrap_or(existing.age_restricted),
+                update.hazardous.unwrap_or(existing.hazardous),
+            )?;
+        }
+        if let Some(name) = &update.name {
+            update_doc.extend(slugs::rename_fields(db, &existing, name).await.map_err(|e| {
+                error!("Failed to generate slug for {}: {}", id, e);
+                ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+            })?);
+            update_doc.extend(search::rename_fields(name));
+        }
+        // Large price changes wait for approval; the rest of the update goes through
+        let held_price = update.price.filter(|price| price_approvals::needs_approval(approvals, existing.price, *price));
+        if let Some(price) = held_price {
+            update_doc.remove("price");
+            let request = price_approvals::request_change(db, &existing, price, user_id)
+                .await
+                .map_err(|e| {
+                    error!("Failed to request price change of {}: {}", id, e);
+                    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+                })?;
+            price_change = Some(request);
+        }
+    }
+    if let Some(attributes) = &update.attributes {
+        update_doc.insert(
+            "attributes",
+            mongodb::bson::to_bson(attributes).map_err(ApiError::internal)?,
+        );
+    }
+
+    if let Some(request) = &price_change {
+        if update_doc.is_empty() {
+            return Ok(HttpResponse::Accepted().json(PriceChangeResponse::from(request)));
+        }
+    }
+
+    let changes = ProductEvent::for_update(&update_doc);
+    let written = match update.stock_quantity {
+        // Units the product no longer has come off its locations too
+        Some(stock_quantity) => locations::update_with_stock(db, object_id, update_doc, stock_quantity).await,
+        None => collection
+            .update_one(doc! { "_id": object_id }, doc! { "$set": update_doc }, None)
+            .await
+            .map(|result| result.matched_count > 0),
+    };
 
-    let result = collection.update_one(filter, update_doc, None).await.map_err(|e| {
+    let found = written.map_err(|e| {
+        if slugs::is_duplicate_slug(&e) {
+            debug!("Slug for {} was taken concurrently", id);
+            return ApiError::new(StatusCode::CONFLICT, CONCURRENT_SLUG_MESSAGE);
+        }
+        if is_duplicate_sku(&e) {
+            debug!("Rejected update of {} to a SKU already in use", id);
+            return ApiError::new(StatusCode::CONFLICT, "A product with this SKU already exists");
+        }
+        if is_duplicate_key(&e) {
+            debug!("Rejected update of {} to a barcode already in use", id);
+            return ApiError::new(StatusCode::CONFLICT, "A product with this barcode already exists");
+        }
         error!("Failed to update product {}: {}", id, e);
-        actix_web::error::ErrorInternalServerError(format!("Database error: {}", e))
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
     })?;
 
-    if result.matched_count == 0 {
+    if !found {
         debug!("Product not found for update: {}", id);
-        Ok(HttpResponse::NotFound().finish())
+        Err(ApiError::blank(StatusCode::NOT_FOUND).into())
     } else {
         info!("Product updated successfully: {}", id);
-        Ok(HttpResponse::Ok().finish())
+        event_store::record(db, object_id, changes).await;
+        events.publish(DomainEvent::ProductUpdated { product_id: object_id.to_string() });
+        if update.stock_quantity.is_some() || update.low_stock_threshold.is_some() {
+            stock::check_low_stock(db, events, &[object_id]).await;
+        }
+        match &price_change {
+            Some(request) => Ok(HttpResponse::Accepted().json(PriceChangeResponse::from(request))),
+            None => Ok(HttpResponse::Ok().finish()),
+        }
     }
 }
 
-pub async fn delete_product(
+async fn transition_status(
+    db: &MongoConfig,
+    events: &EventHub,
+    id: &str,
+    from: &[ProductStatus],
+    to: ProductStatus,
+) -> Result<HttpResponse, Error> {
+    let collection: Collection<Product> = db.database.collection("products");
+
+    let object_id = public_ids::resolve_product_id(db, id).await?;
+
+    let product = collection.find_one(doc! { "_id": object_id }, None).await.map_err(|e| {
+        error!("Failed to fetch product {}: {}", id, e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+    })?;
+
+    let current = match product {
+        Some(product) => product.status,
+        None => {
+            debug!("Product not found for status change: {}", id);
+            return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+        }
+    };
+
+    if !from.contains(&current) {
+        let message = format!("Cannot change product status from {} to {}", current, to);
+        return Err(ApiError::new(StatusCode::CONFLICT, message).into());
+    }
+
+    collection
+        .update_one(
+            doc! { "_id": object_id },
+            doc! { "$set": { "status": to.to_string() } },
+            None,
+        )
+        .await
+        .map_err(|e| {
+            error!("Failed to update status of product {}: {}", id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+
+    info!("Product {} moved from {} to {}", id, current, to);
+    event_store::record(db, object_id, vec![ProductEvent::StatusChanged { status: to }]).await;
+    events.publish(DomainEvent::ProductUpdated { product_id: object_id.to_string() });
+    Ok(HttpResponse::Ok().json(doc! { "id": id, "status": to.to_string() }))
+}
+
+pub async fn publish_product(
     db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
     id: web::Path<String>,
 ) -> Result<HttpResponse, Error> {
-    let collection: Collection<Product> = db.database.collection("products");
+    transition_status(&db, &events, &id, &[ProductStatus::Draft, ProductStatus::Archived], ProductStatus::Active).await
+}
 
+pub async fn archive_product(
+    db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    transition_status(&db, &events, &id, &[ProductStatus::Draft, ProductStatus::Active], ProductStatus::Archived).await
+}
+
+/// Moves a product to the trash; it can be restored until it is purged.
+pub async fn delete_product(
+    db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
     debug!("Deleting product: {}", id);
 
-    let object_id = ObjectId::parse_str(id.as_str()).map_err(|_| {
-        error!("Invalid product ID format: {}", id);
-        actix_web::error::ErrorBadRequest("Invalid ID format")
-    })?;
+    let object_id = public_ids::resolve_product_id(&db, &id).await?;
 
-    let filter = doc! { "_id": object_id };
-    let result = collection.delete_one(filter, None).await.map_err(|e| {
+    let trashed = trash::move_to_trash(&db, object_id, Some(claims.user_id()?)).await.map_err(|e| {
         error!("Failed to delete product {}: {}", id, e);
-        actix_web::error::ErrorInternalServerError(format!("Database error: {}", e))
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
     })?;
 
-    if result.deleted_count == 0 {
+    if !trashed {
         debug!("Product not found for deletion: {}", id);
-        Ok(HttpResponse::NotFound().finish())
+        Err(ApiError::blank(StatusCode::NOT_FOUND).into())
     } else {
-        info!("Product deleted successfully: {}", id);
+        info!("Product moved to the trash: {}", id);
+        events.publish(DomainEvent::ProductDeleted { product_id: object_id.to_string() });
         Ok(HttpResponse::Ok().finish())
     }
 }
 
+// Chunks of an upload received ahead of the parser
+const UPLOAD_BUFFER_CHUNKS: usize = 8;
+
+enum UploadOutcome {
+    Imported(usize, Vec<Document>),
+    // Nothing of the upload was kept
+    Refused(ApiError),
+}
+
+// Why receiving a streamed upload stopped before its end
+enum UploadStop {
+    TooLarge,
+    Failed,
+}
+
+fn upload_too_large(limits: &LimitsConfig) -> ApiError {
+    payload_too_large(format!("Upload exceeds the limit of {} bytes", limits.upload_bytes), limits.upload_bytes)
+}
+
+fn unreadable_upload(e: &str) -> ApiError {
+    ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, format!("Could not read file: {}", e))
+}
+
+// Takes back what an upload imported before it broke a limit
+async fn discard_upload(db: &MongoConfig, events: &EventHub, import: ImportLog, user_id: ObjectId, imported: usize, reason: &str) {
+    if imported > 0 {
+        if let Err(e) = import_history::roll_back(db, events, import.id, user_id).await {
+            error!("Failed to roll back import {}: {}", import.id, e);
+        }
+    }
+    import.fail(db, reason).await;
+}
+
+/// Imports a CSV or TSV upload while it arrives. The parser pulls chunks
+/// through a small channel, so a slow database slows down reading the request
+/// instead of piling it up in memory or on disk. The limits are checked on
+/// the way; an upload that breaks one is rolled back, and the products it
+/// created are never announced.
+#[allow(clippy::too_many_arguments)]
+async fn stream_upload(
+    db: &MongoConfig,
+    events: &EventHub,
+    batching: &BatchLimits,
+    limits: &LimitsConfig,
+    rules: &ImportRules,
+    webhook: Option<&ValidationWebhook>,
+    user_id: ObjectId,
+    import: ImportLog,
+    reader: &'static Delimited,
+    field: &mut Field,
+    total_bytes: &mut usize,
+) -> Result<UploadOutcome, Error> {
+    let (sender, receiver) = mpsc::channel::<Bytes>(UPLOAD_BUFFER_CHUNKS);
+    let too_many_rows = AtomicBool::new(false);
+    // Kept back until the whole upload is known to be within the limits
+    let held = events.held();
+
+    // Ends when the request does or the parser stops taking chunks; dropping
+    // the sender then ends the file for the parser
+    let receive = async {
+        let mut sender = sender;
+        while let Some(chunk) = field.next().await {
+            let data = chunk.map_err(|e| {
+                error!("Error reading multipart chunk: {}", e);
+                UploadStop::Failed
+            })?;
+            *total_bytes += data.len();
+            if *total_bytes > limits.upload_bytes {
+                debug!("Upload exceeded {} bytes, aborting", limits.upload_bytes);
+                return Err(UploadStop::TooLarge);
+            }
+            if sender.send(data).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    };
+    let parse = async {
+        let table = reader.read_async(receiver.map(Ok::<_, io::Error>).into_async_read()).await?;
+        let rows = table
+            .rows
+            .enumerate()
+            .take_while(|(index, _)| {
+                let over = *index >= limits.csv_max_rows;
+                if over {
+                    too_many_rows.store(true, Ordering::Relaxed);
+                }
+                future::ready(!over)
+            })
+            .map(|(_, row)| row)
+            .boxed();
+        let table = RawTable { headers: table.headers, rows };
+        let (imported, errors, _) = import_rows_until(db, &held, batching, import.id, table, rules, webhook, None).await;
+        Ok::<_, String>((imported, errors))
+    };
+    let (received, parsed) = future::join(receive, parse).await;
+
+    let imported = parsed.as_ref().map_or(0, |(imported, _)| *imported);
+    match received {
+        Err(UploadStop::Failed) => {
+            discard_upload(db, &held, import, user_id, imported, "Failed to read uploaded file").await;
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, "Failed to read uploaded file").into());
+        }
+        Err(UploadStop::TooLarge) => {
+            discard_upload(db, &held, import, user_id, imported, "Upload exceeded the size limit").await;
+            return Ok(UploadOutcome::Refused(upload_too_large(limits)));
+        }
+        Ok(()) => {}
+    }
+    if too_many_rows.load(Ordering::Relaxed) {
+        debug!("Upload has more than {} rows", limits.csv_max_rows);
+        discard_upload(db, &held, import, user_id, imported, "File exceeded the row limit").await;
+        return Ok(UploadOutcome::Refused(payload_too_large(
+            format!("File exceeds the limit of {} rows", limits.csv_max_rows),
+            limits.csv_max_rows,
+        )));
+    }
+    // What was imported stays, even from a file that turned out unreadable
+    held.release();
+
+    match parsed {
+        Ok((imported, errors)) => {
+            import.finish(db, imported, errors.len()).await;
+            Ok(UploadOutcome::Imported(imported, errors))
+        }
+        Err(e) => {
+            debug!("Could not read uploaded file: {}", e);
+            import.fail(db, &e).await;
+            Ok(UploadOutcome::Refused(unreadable_upload(&e)))
+        }
+    }
+}
+
+/// Imports an upload in a format that needs the whole file, such as XLSX,
+/// after receiving it into memory.
+#[allow(clippy::too_many_arguments)]
+async fn buffered_upload(
+    db: &MongoConfig,
+    events: &EventHub,
+    batching: &BatchLimits,
+    limits: &LimitsConfig,
+    rules: &ImportRules,
+    webhook: Option<&ValidationWebhook>,
+    import: ImportLog,
+    format: FileFormat,
+    field: &mut Field,
+    total_bytes: &mut usize,
+) -> Result<UploadOutcome, Error> {
+    let mut data = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let chunk = chunk.map_err(|e| {
+            error!("Error reading multipart chunk: {}", e);
+            ApiError::new(StatusCode::BAD_REQUEST, "Failed to read uploaded file")
+        })?;
+        *total_bytes += chunk.len();
+        if *total_bytes > limits.upload_bytes {
+            debug!("Upload exceeded {} bytes, aborting", limits.upload_bytes);
+            import.fail(db, "Upload exceeded the size limit").await;
+            return Ok(UploadOutcome::Refused(upload_too_large(limits)));
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    // Enforce the row cap before importing anything
+    let row_count = match format.reader().count_rows(Box::new(data.as_slice())).await {
+        Ok(row_count) => row_count,
+        Err(e) => {
+            debug!("Could not read uploaded file: {}", e);
+            import.fail(db, &e).await;
+            return Ok(UploadOutcome::Refused(unreadable_upload(&e)));
+        }
+    };
+    if row_count > limits.csv_max_rows {
+        debug!("Upload has {} rows, limit is {}", row_count, limits.csv_max_rows);
+        import.fail(db, "File exceeded the row limit").await;
+        return Ok(UploadOutcome::Refused(payload_too_large(
+            format!("File has {} rows, exceeding the limit of {} rows", row_count, limits.csv_max_rows),
+            limits.csv_max_rows,
+        )));
+    }
+
+    let (imported, errors) = import_records(db, events, batching, import.id, format, rules, webhook, data.as_slice()).await;
+    import.finish(db, imported, errors.len()).await;
+    Ok(UploadOutcome::Imported(imported, errors))
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn upload_products_csv(
+    req: HttpRequest,
     db: web::Data<MongoConfig>,
+    limits: web::Data<LimitsConfig>,
+    events: web::Data<EventHub>,
+    batching: web::Data<BatchLimits>,
+    webhook: Option<web::Data<ValidationWebhook>>,
+    claims: web::ReqData<Claims>,
+    query: web::Query<ImportQuery>,
     mut payload: Multipart,
 ) -> Result<HttpResponse, Error> {
-    let collection: Collection<Product> = db.database.collection("products");
+    let user_id = claims.user_id()?;
+    let rules = query.rules().map_err(ApiError::bad_request)?;
     let mut errors = Vec::new();
     let mut success_count = 0;
+    let mut total_bytes: usize = 0;
+
+    // Reject obviously oversized uploads before reading anything
+    let declared_length = req
+        .headers()
+        .get("Content-Length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if let Some(length) = declared_length.filter(|&l| l > limits.upload_bytes) {
+        debug!("Rejected upload with Content-Length {}", length);
+        return Err(upload_too_large(&limits).into());
+    }
 
     // Process the multipart form data
     while let Some(item) = payload.next().await {
         let mut field = item.map_err(|e| {
             error!("Error getting multipart field: {}", e);
-            actix_web::error::ErrorBadRequest(format!("Multipart error: {}", e))
+            ApiError::new(StatusCode::BAD_REQUEST, format!("Multipart error: {}", e))
         })?;
 
         if field.name() == "file" {
-            // Create a temporary file to store the CSV data
-            let mut temp_file = NamedTempFile::new().map_err(|e| {
-                error!("Failed to create temp file: {}", e);
-                actix_web::error::ErrorInternalServerError("Failed to process file")
-            })?;
+            let filename = field.content_disposition().get_filename().map(str::to_string);
+            let format = query
+                .format
+                .or_else(|| filename.as_deref().and_then(FileFormat::from_file_name))
+                .unwrap_or(FileFormat::Csv);
+            let import = ImportLog::begin(ImportOrigin::Upload, Some(user_id)).filename(filename);
 
-            // Write the field data to the temp file
-            while let Some(chunk) = field.next().await {
-                let data = chunk.map_err(|e| {
-                    error!("Error reading multipart chunk: {}", e);
-                    actix_web::error::ErrorBadRequest("Failed to read uploaded file")
-                })?;
-                temp_file.write_all(&data).map_err(|e| {
-                    error!("Failed to write to temp file: {}", e);
-                    actix_web::error::ErrorInternalServerError("Failed to process file")
-                })?;
+            let webhook = webhook.as_ref().map(|webhook| webhook.get_ref());
+            let outcome = match format.streaming_reader() {
+                Some(reader) => {
+                    stream_upload(
+                        &db,
+                        &events,
+                        &batching,
+                        &limits,
+                        &rules,
+                        webhook,
+                        user_id,
+                        import,
+                        reader,
+                        &mut field,
+                        &mut total_bytes,
+                    )
+                    .await?
+                }
+                None => {
+                    buffered_upload(&db, &events, &batching, &limits, &rules, webhook, import, format, &mut field, &mut total_bytes)
+                        .await?
+                }
+            };
+            match outcome {
+                UploadOutcome::Imported(imported, mut field_errors) => {
+                    success_count += imported;
+                    errors.append(&mut field_errors);
+                }
+                UploadOutcome::Refused(problem) => return Err(problem.into()),
             }
+        }
+    }
 
-            // Create a CSV reader
-            let mut rdr = ReaderBuilder::new()
-                .flexible(true)
-                .trim(csv::Trim::All)
-                .from_reader(temp_file.reopen().map_err(|e| {
-                    error!("Failed to reopen temp file: {}", e);
-                    actix_web::error::ErrorInternalServerError("Failed to process file")
-                })?);
-
-            let mut line_number = 2; // Start from 2 to account for header row
-            for result in rdr.records() {
-                match result {
-                    Ok(record) => {
-                        let mut has_error = false;
-
-                        if record.len() < 4 {
-                            errors.push(doc! {
-                                "line": line_number,
-                                "error": "Invalid number of columns",
-                                "data": record.iter().collect::<Vec<_>>()
-                            });
-                            has_error = true;
-                        }
-
-                        // Parse the CSV record - safely get values or use empty strings
-                        let raw_name = record.get(0).unwrap_or("").trim();
-
-                        // Split the name into product name and ID parts
-                        let (product_name, product_id) = if let Some((name, id)) = raw_name.split_once("#") {
-                            (name.trim(), id.trim())
-                        } else {
-                            (raw_name, "")
-                        };
-
-                        // Validate and sanitize product ID
-                        let sanitized_id = if !product_id.is_empty() {
-                            // Remove any surrounding parentheses
-                            let id_content = product_id.trim_start_matches('(').trim_end_matches(')');
-
-                            // Only allow alphanumeric and basic symbols in ID
-                            let clean_id = id_content
-                                .chars()
-                                .filter(|c| c.is_alphanumeric() || *c == '-' || *c == '_')
-                                .collect::<String>();
-
-                            if clean_id.is_empty() {
-                                String::new()
-                            } else {
-                                format!("#{}", clean_id)
-                            }
-                        } else {
-                            String::new()
-                        };
-
-                        // Sanitize product name
-                        let clean_name = product_name
-                            .chars()
-                            .filter(|c| c.is_alphanumeric() || c.is_whitespace() || *c == '-')
-                            .collect::<String>()
-                            .trim()
-                            .to_string();
-
-                        let price_str = record.get(1).unwrap_or("").trim();
-                        let category_str = record.get(2).unwrap_or("").trim().to_lowercase();
-                        let has_active_sale = record.get(3).unwrap_or("false").trim().parse::<bool>();
-
-                        // Validate name
-                        if clean_name.is_empty() {
-                            errors.push(doc! {
-                                "line": line_number,
-                                "error": "Name is required",
-                                "data": record.iter().collect::<Vec<_>>()
-                            });
-                            has_error = true;
-                        }
-
-                        // Parse and validate price
-                        let price = if price_str.is_empty() {
-                            errors.push(doc! {
-                                "line": line_number,
-                                "error": "Price is required",
-                                "data": record.iter().collect::<Vec<_>>()
-                            });
-                            has_error = true;
-                            0.0
-                        } else {
-                            // Remove '$', whitespace, and any hidden characters
-                            let cleaned_price = price_str
-                                .trim_start_matches('$')
-                                .trim()
-                                .replace(['\u{200B}', '\u{FEFF}', '\r', '\n'], ""); // Remove zero-width spaces, BOM, and line endings
-
-                            match cleaned_price.parse::<f64>() {
-                                Ok(p) if p >= 0.0 => p,
-                                Ok(p) => {
-                                    errors.push(doc! {
-                                        "line": line_number,
-                                        "error": format!("Invalid price: must be non-negative, got: '{}'", p),
-                                        "data": record.iter().collect::<Vec<_>>()
-                                    });
-                                    has_error = true;
-                                    0.0
-                                },
-                                Err(e) => {
-                                    errors.push(doc! {
-                                        "line": line_number,
-                                        "error": format!("Invalid price format. Expected format: $X.XX, got: '{}'. Parse error: {}", price_str, e),
-                                        "data": record.iter().collect::<Vec<_>>()
-                                    });
-                                    has_error = true;
-                                    0.0
-                                }
-                            }
-                        };
-
-                        let category = match category_str.as_str() {
-                            "electronics" => Category::Electronics,
-                            "clothing" => Category::Clothing,
-                            "food" => Category::Food,
-                            "books" => Category::Books,
-                            _ => Category::Other,
-                        };
-
-                        let has_active_sale = has_active_sale.unwrap_or(false);
-
-                        // Only proceed with insertion if there are no errors for this record
-                        if !has_error {
-                            let product = Product {
-                                id: None,
-                                name: format!("{} {}", clean_name.clone(), sanitized_id).to_string(),
-                                price,
-                                category,
-                                has_active_sale,
-                            };
-
-                            // Insert the product into the database
-                            match collection.insert_one(product, None).await {
-                                Ok(_) => success_count += 1,
-                                Err(e) => {
-                                    error!("Failed to insert product at line {}: {}", line_number, e);
-                                    errors.push(doc! {
-                                        "line": line_number,
-                                        "error": format!("Database error: {}", e),
-                                        "data": record.iter().collect::<Vec<_>>()
-                                    });
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        error!("Error reading CSV record at line {}: {}", line_number, e);
-                        errors.push(doc! {
-                            "line": line_number,
-                            "error": format!("Failed to parse CSV record: {}", e),
-                        });
-                    }
-                }
-                line_number += 1;
+    Ok(import_report(success_count, errors))
+}
+
+const DEFAULT_PREVIEW_ROWS: usize = 20;
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ImportPreviewQuery {
+    format: Option<FileFormat>,
+    decimal_separator: Option<DecimalSeparator>,
+    currency: Option<String>,
+    #[validate(range(min = 1, max = 100, message = "rows must be between 1 and 100"))]
+    rows: Option<usize>,
+}
+
+/// Reads the first rows of an uploaded file the way an import would and
+/// shows what they would become, without importing anything.
+pub async fn preview_products_import(
+    limits: web::Data<LimitsConfig>,
+    query: web::Query<ImportPreviewQuery>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    if let Err(errors) = query.validate() {
+        return Err(validation_error(errors));
+    }
+    let prices = PriceFormat { decimal_separator: query.decimal_separator, currency: query.currency.clone() };
+    prices.check().map_err(ApiError::bad_request)?;
+    let rules = ImportRules { prices, ..ImportRules::default() };
+
+    let mut upload = None;
+    while let Some(item) = payload.next().await {
+        let mut field = item.map_err(|e| {
+            error!("Error getting multipart field: {}", e);
+            ApiError::new(StatusCode::BAD_REQUEST, format!("Multipart error: {}", e))
+        })?;
+        if field.name() != "file" {
+            continue;
+        }
+
+        let filename = field.content_disposition().get_filename().map(str::to_string);
+        let mut data = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|e| {
+                error!("Error reading multipart chunk: {}", e);
+                ApiError::new(StatusCode::BAD_REQUEST, "Failed to read uploaded file")
+            })?;
+            if data.len() + chunk.len() > limits.upload_bytes {
+                debug!("Preview upload exceeded {} bytes, aborting", limits.upload_bytes);
+                return Err(payload_too_large(
+                    format!("Upload exceeds the limit of {} bytes", limits.upload_bytes),
+                    limits.upload_bytes,
+                ).into());
             }
+            data.extend_from_slice(&chunk);
         }
+        upload = Some((filename, data));
+        break;
     }
+    let Some((filename, data)) = upload else {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "No file uploaded").into());
+    };
+
+    let format = query
+        .format
+        .or_else(|| filename.as_deref().and_then(FileFormat::from_file_name))
+        .unwrap_or(FileFormat::Csv);
+    let table = match format.reader().read(Box::new(data.as_slice())) {
+        Ok(table) => table,
+        Err(e) => {
+            debug!("Could not read file to preview: {}", e);
+            return Err(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, format!("Could not read file: {}", e)).into());
+        }
+    };
+
+    let columns = ImportColumns::new(&table.headers);
+    let rows = table
+        .rows
+        .take(query.rows.unwrap_or(DEFAULT_PREVIEW_ROWS))
+        .map(|result| match result {
+            Ok(record) => match parse_import_row(&columns, &rules, &record) {
+                Ok(row) => PreviewRow { line: record.line, values: record.values, product: Some(row), errors: Vec::new() },
+                Err(problems) => PreviewRow { line: record.line, values: record.values, product: None, errors: problems },
+            },
+            Err(e) => PreviewRow { line: e.line, values: Vec::new(), product: None, errors: vec![e.message] },
+        })
+        .collect()
+        .await;
+
+    Ok(HttpResponse::Ok().json(ImportPreview {
+        format,
+        columns: columns.detected(&table.headers),
+        headers: table.headers,
+        rows,
+    }))
+}
+
+pub fn csv_row_count<R: Read>(reader: R) -> usize {
+    ReaderBuilder::new().flexible(true).from_reader(reader).records().count()
+}
 
-    // Return response with results
+/// The response for a finished import: a summary when every row went in,
+/// otherwise a 422 listing the rejected rows.
+pub fn import_report(success_count: usize, errors: Vec<Document>) -> HttpResponse {
     if errors.is_empty() {
         debug!("Successfully imported {} products", success_count);
-        Ok(HttpResponse::Ok().json(doc! {
+        HttpResponse::Ok().json(doc! {
             "message": format!("Successfully imported {} products", success_count)
-        }))
+        })
     } else {
         debug!("Found {} errors while importing products", errors.len());
-        Ok(HttpResponse::UnprocessableEntity().json(doc! {
-            "errors": errors
-        }))
+        ApiError::blank(StatusCode::UNPROCESSABLE_ENTITY).with("errors", errors).error_response()
+    }
+}
+
+/// How far an import has come, as reported to an `ImportCheckpoint`.
+#[derive(Debug, Clone, Copy)]
+pub struct ImportProgress {
+    pub processed: usize,
+    pub imported: usize,
+    pub rejected: usize,
+}
+
+/// Called by `import_rows_until` every `IMPORT_CHECKPOINT_ROWS` rows,
+/// e.g. to save progress. Returning false stops the import before the next row.
+pub trait ImportCheckpoint: Send {
+    fn reached(&mut self, progress: ImportProgress) -> BoxFuture<'_, bool>;
+}
+
+pub const IMPORT_CHECKPOINT_ROWS: usize = 100;
+
+const MAX_SKU_LENGTH: usize = 64;
+const MAX_DESCRIPTION_LENGTH: usize = 5000;
+
+// SKUs are codes: letters, digits, '-', '_', '.' and '/'
+fn check_sku(sku: &str) -> Result<String, String> {
+    let sku = sku.trim();
+    if sku.is_empty() || sku.chars().count() > MAX_SKU_LENGTH {
+        return Err(format!("SKU must be 1 to {} characters", MAX_SKU_LENGTH));
     }
+    if let Some(c) = sku.chars().find(|c| !c.is_alphanumeric() && !matches!(c, '-' | '_' | '.' | '/')) {
+        return Err(format!("Invalid character '{}' in SKU '{}'", c, sku));
+    }
+    Ok(sku.to_string())
+}
+
+// Control characters other than line breaks and tabs are dropped
+fn clean_description(description: &str) -> Result<String, String> {
+    let cleaned: String = description.chars().filter(|c| !c.is_control() || matches!(c, '\n' | '\t')).collect();
+    let cleaned = cleaned.trim();
+    if cleaned.chars().count() > MAX_DESCRIPTION_LENGTH {
+        return Err(format!("Description must be at most {} characters", MAX_DESCRIPTION_LENGTH));
+    }
+    Ok(cleaned.to_string())
+}
+
+// Two writes derived the same slug from different names; the loser can just retry
+const CONCURRENT_SLUG_MESSAGE: &str = "Another product was given the same slug at the same time; try again";
+
+/// Whether an insert or update failed because the SKU belongs to another product.
+fn is_duplicate_sku(e: &mongodb::error::Error) -> bool {
+    is_duplicate_key(e) && e.to_string().contains("index: sku_1")
+}
+
+/// Where the optional columns of an import file are, found by header name;
+/// files with only the four core columns import as before.
+struct ImportColumns {
+    sku: Option<usize>,
+    stock_quantity: Option<usize>,
+    description: Option<usize>,
+}
+
+impl ImportColumns {
+    fn new(headers: &[String]) -> Self {
+        let column = |name: &str| headers.iter().position(|h| h.eq_ignore_ascii_case(name));
+        ImportColumns { sku: column("sku"), stock_quantity: column("stock_quantity"), description: column("description") }
+    }
+
+    // The core fields are always the first four columns
+    fn detected(&self, headers: &[String]) -> Vec<DetectedColumn> {
+        let core = ["name", "price", "category", "has_active_sale"].into_iter().enumerate();
+        let optional = [("sku", self.sku), ("stock_quantity", self.stock_quantity), ("description", self.description)]
+            .into_iter()
+            .filter_map(|(field, index)| index.map(|index| (index, field)));
+        core.chain(optional)
+            .map(|(index, field)| DetectedColumn { field, index, header: headers.get(index).cloned() })
+            .collect()
+    }
+}
+
+/// Where the import found a product field.
+#[derive(Debug, Serialize)]
+pub struct DetectedColumn {
+    field: &'static str,
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    header: Option<String>,
+}
+
+/// One row of an import preview: the values as read and the product they
+/// would become, or why the row would be rejected.
+#[derive(Debug, Serialize)]
+pub struct PreviewRow {
+    line: u64,
+    values: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    product: Option<ImportRow>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportPreview {
+    format: FileFormat,
+    headers: Vec<String>,
+    columns: Vec<DetectedColumn>,
+    rows: Vec<PreviewRow>,
+}
+
+/// A row of an import file that passed validation.
+#[derive(Debug, Serialize)]
+pub struct ImportRow {
+    name: String,
+    #[serde(with = "money::price")]
+    price: Decimal,
+    category: Category,
+    has_active_sale: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sku: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stock_quantity: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+}
+
+/// Checks and sanitizes one row of an import file, whatever its format.
+/// Returns every problem with the row, not just the first.
+fn parse_import_row(
+    columns: &ImportColumns,
+    rules: &ImportRules,
+    record: &RawRecord,
+) -> Result<ImportRow, Vec<String>> {
+    let mut problems = Vec::new();
+
+    if record.values.len() < 4 {
+        problems.push("Invalid number of columns".to_string());
+    }
+
+    let name = rules.sanitization.clean_name(record.get(0).unwrap_or("").trim());
+    let price_str = record.get(1).unwrap_or("").trim();
+    let category_str = record.get(2).unwrap_or("").trim().to_lowercase();
+    let has_active_sale = record.get(3).unwrap_or("false").trim().parse::<bool>();
+
+    // Validate name
+    if name.is_empty() {
+        problems.push("Name is required".to_string());
+    }
+
+    // Parse and validate price
+    let price = if price_str.is_empty() {
+        problems.push("Price is required".to_string());
+        Decimal::ZERO
+    } else {
+        match rules.prices.parse(price_str) {
+            Ok(p) if !p.is_sign_negative() => p,
+            Ok(p) => {
+                problems.push(format!("Invalid price: must be non-negative, got: '{}'", p));
+                Decimal::ZERO
+            }
+            Err(e) => {
+                problems.push(format!("Invalid price '{}': {}", price_str, e));
+                Decimal::ZERO
+            }
+        }
+    };
+
+    let category = category_str.parse::<Category>().unwrap_or(Category::Other);
+    let has_active_sale = has_active_sale.unwrap_or(false);
+
+    let optional = |column: Option<usize>| column.and_then(|i| record.get(i)).filter(|value| !value.is_empty());
+    let sku = match optional(columns.sku).map(check_sku) {
+        Some(Ok(sku)) => Some(sku),
+        Some(Err(e)) => {
+            problems.push(e);
+            None
+        }
+        None => None,
+    };
+    let stock_quantity = match optional(columns.stock_quantity).map(str::parse::<i64>) {
+        Some(Ok(quantity)) if quantity >= 0 => Some(quantity),
+        Some(_) => {
+            problems.push(format!(
+                "Invalid stock_quantity: expected a whole number of at least 0, got: '{}'",
+                optional(columns.stock_quantity).unwrap_or_default()
+            ));
+            None
+        }
+        None => None,
+    };
+    let description = match optional(columns.description).map(clean_description) {
+        Some(Ok(description)) => Some(description).filter(|d| !d.is_empty()),
+        Some(Err(e)) => {
+            problems.push(e);
+            None
+        }
+        None => None,
+    };
+
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+    Ok(ImportRow { name, price, category, has_active_sale, sku, stock_quantity, description })
+}
+
+impl ImportRow {
+    // What the validation webhook is asked about the row
+    fn to_request(&self) -> CreateProductRequest {
+        CreateProductRequest {
+            sku: self.sku.clone(),
+            description: self.description.clone(),
+            has_active_sale: self.has_active_sale,
+            stock_quantity: self.stock_quantity,
+            ..CreateProductRequest::new(self.name.clone(), self.price, self.category.clone())
+        }
+    }
+
+    /// The row as an active product tagged with `import_id`. The slug is
+    /// left for the insert, which makes it unique.
+    fn into_product(self, import_id: ObjectId) -> Product {
+        Product {
+            id: None,
+            public_id: Some(public_ids::new_public_id()),
+            search_grams: search::name_grams(&self.name),
+            sku: self.sku,
+            description: self.description,
+            name: self.name,
+            slug: None,
+            previous_slugs: Vec::new(),
+            price: self.price,
+            category: self.category,
+            status: ProductStatus::Active,
+            tax_class: TaxClass::default(),
+            unit: Unit::default(),
+            price_per_unit: None,
+            price_tiers: Vec::new(),
+            has_active_sale: self.has_active_sale,
+            sale_ends_at: None,
+            stock_quantity: self.stock_quantity,
+            low_stock_threshold: None,
+            barcode: None,
+            attributes: None,
+            supplier_id: None,
+            supplier_sku: None,
+            cost_price: None,
+            import_id: Some(import_id),
+            bundle: None,
+            age_restricted: false,
+            hazardous: false,
+            region_restrictions: Vec::new(),
+        }
+    }
+}
+
+/// Imports products from a file in `format` (name, price, category,
+/// has_active_sale, then optional columns), returning how many rows were
+/// inserted and a report entry per rejected row. Inserted products are
+/// tagged with `import_id`. Rows `webhook` refuses are rejected.
+#[allow(clippy::too_many_arguments)]
+pub async fn import_records<R: Read + Send>(
+    db: &MongoConfig,
+    events: &EventHub,
+    batching: &BatchLimits,
+    import_id: ObjectId,
+    format: FileFormat,
+    rules: &ImportRules,
+    webhook: Option<&ValidationWebhook>,
+    reader: R,
+) -> (usize, Vec<Document>) {
+    match format.reader().read(Box::new(reader)) {
+        Ok(table) => {
+            let (success_count, errors, _) =
+                import_rows_until(db, events, batching, import_id, table, rules, webhook, None).await;
+            (success_count, errors)
+        }
+        Err(e) => {
+            error!("Failed to read import {}: {}", import_id, e);
+            events.publish(DomainEvent::ImportCompleted { import_id: import_id.to_string(), imported: 0, failed: 1 });
+            (0, vec![doc! { "line": 1, "error": e }])
+        }
+    }
+}
+
+/// Imports the rows of an already read file, read according to `rules` and
+/// checked with `webhook`, stopping when `checkpoint` says so. Also returns
+/// whether every row was processed; rows imported before stopping are kept.
+#[allow(clippy::too_many_arguments)]
+pub async fn import_rows_until(
+    db: &MongoConfig,
+    events: &EventHub,
+    batching: &BatchLimits,
+    import_id: ObjectId,
+    table: RawTable<'_>,
+    rules: &ImportRules,
+    webhook: Option<&ValidationWebhook>,
+    mut checkpoint: Option<&mut dyn ImportCheckpoint>,
+) -> (usize, Vec<Document>, bool) {
+    let mut errors = Vec::new();
+    let mut inserter = BatchInserter::new(db, events, batching, import_id);
+    let mut completed = true;
+
+    let columns = ImportColumns::new(&table.headers);
+    let mut rows = table.rows.enumerate();
+    while let Some((processed, result)) = rows.next().await {
+        let at_checkpoint = processed > 0 && processed.is_multiple_of(IMPORT_CHECKPOINT_ROWS);
+        if let Some(checkpoint) = checkpoint.as_deref_mut().filter(|_| at_checkpoint) {
+            // Rows in batches still being inserted are counted at the next checkpoint
+            let (imported, rejected) = inserter.progress().await;
+            let progress = ImportProgress { processed, imported, rejected: errors.len() + rejected };
+            if !checkpoint.reached(progress).await {
+                info!("Import {} stopped after {} rows", import_id, processed);
+                completed = false;
+                break;
+            }
+        }
+        let record = match result {
+            Ok(record) => record,
+            Err(e) => {
+                error!("Error reading import record at line {}: {}", e.line, e.message);
+                errors.push(doc! { "line": e.line as i64, "error": e.message });
+                continue;
+            }
+        };
+
+        let row = match parse_import_row(&columns, rules, &record) {
+            Ok(row) => match webhook {
+                Some(webhook) => webhook.check_create(&row.to_request()).await.map(|_| row).map_err(|rejection| {
+                    vec![format!("Rejected by product validation: {}", rejection)]
+                }),
+                None => Ok(row),
+            },
+            Err(problems) => Err(problems),
+        };
+        match row {
+            Ok(row) => inserter.push(record.line, record.values, row.into_product(import_id)).await,
+            Err(problems) => {
+                for problem in problems {
+                    errors.push(doc! {
+                        "line": record.line as i64,
+                        "error": problem,
+                        "data": &record.values
+                    });
+                }
+            }
+        }
+    }
+
+    let (success_count, insert_errors) = inserter.finish().await;
+    errors.extend(insert_errors);
+    // Batches finish in any order; the report follows the file
+    errors.sort_by_key(|error| error.get_i64("line").unwrap_or_default());
+
+    events.publish(DomainEvent::ImportCompleted {
+        import_id: import_id.to_string(),
+        imported: success_count,
+        failed: errors.len(),
+    });
+    (success_count, errors, completed)
 }
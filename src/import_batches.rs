@@ -0,0 +1,227 @@
+use std::{collections::HashMap, sync::Arc};
+
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    error::{BulkWriteError, ErrorKind},
+    options::InsertManyOptions,
+    Collection,
+};
+use tokio::{sync::Semaphore, task::JoinHandle};
+use tracing::{error, warn};
+
+use crate::{
+    config::{ImportConfig, MongoConfig},
+    event_store::{self, ProductEvent},
+    events::{DomainEvent, EventHub},
+    models::Product,
+    slugs,
+};
+
+const DUPLICATE_KEY: i32 = 11000;
+
+// A row whose slug was taken by a batch running at the same time gets a new
+// one and is tried again, this many times in all
+const SLUG_ATTEMPTS: usize = 3;
+
+/// How imports write their rows: IMPORT_BATCH_SIZE rows per insert_many, at
+/// most IMPORT_INSERT_CONCURRENCY batches at once. Clones share the permits,
+/// so one value serves every import of the process.
+#[derive(Clone)]
+pub struct BatchLimits {
+    batch_size: usize,
+    permits: Arc<Semaphore>,
+}
+
+impl BatchLimits {
+    pub fn new(config: &ImportConfig) -> Self {
+        BatchLimits {
+            batch_size: config.batch_size.max(1),
+            permits: Arc::new(Semaphore::new(config.insert_concurrency.max(1))),
+        }
+    }
+}
+
+// A validated row waiting for its batch; the line and values are for the
+// error report
+struct PendingRow {
+    line: u64,
+    values: Vec<String>,
+    product: Product,
+}
+
+#[derive(Default)]
+struct BatchOutcome {
+    imported: usize,
+    errors: Vec<Document>,
+}
+
+impl BatchOutcome {
+    fn reject(&mut self, row: &PendingRow, problem: String) {
+        self.errors.push(doc! { "line": row.line as i64, "error": problem, "data": &row.values });
+    }
+}
+
+/// Collects the validated rows of an import and inserts them in batches as
+/// `BatchLimits` says. Batches run in the background; adding a row waits
+/// while the limit of them are running, so reading the file never runs far
+/// ahead of the database.
+pub struct BatchInserter {
+    db: MongoConfig,
+    events: EventHub,
+    limits: BatchLimits,
+    import_id: ObjectId,
+    buffer: Vec<PendingRow>,
+    running: Vec<JoinHandle<BatchOutcome>>,
+    finished: BatchOutcome,
+}
+
+impl BatchInserter {
+    pub fn new(db: &MongoConfig, events: &EventHub, limits: &BatchLimits, import_id: ObjectId) -> Self {
+        BatchInserter {
+            db: db.clone(),
+            events: events.clone(),
+            limits: limits.clone(),
+            import_id,
+            buffer: Vec::new(),
+            running: Vec::new(),
+            finished: BatchOutcome::default(),
+        }
+    }
+
+    /// Queues `product`, read from `line` of the file, for insertion.
+    pub async fn push(&mut self, line: u64, values: Vec<String>, product: Product) {
+        self.buffer.push(PendingRow { line, values, product });
+        if self.buffer.len() >= self.limits.batch_size {
+            self.flush().await;
+        }
+    }
+
+    async fn flush(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let rows = std::mem::take(&mut self.buffer);
+        let permit = self.limits.permits.clone().acquire_owned().await.expect("the semaphore is never closed");
+        let (db, events, import_id) = (self.db.clone(), self.events.clone(), self.import_id);
+        self.running.push(tokio::spawn(async move {
+            let outcome = insert_batch(&db, &events, import_id, rows).await;
+            drop(permit);
+            outcome
+        }));
+        self.collect(false).await;
+    }
+
+    // Adds up the batches that are done, or with `wait` all of them
+    async fn collect(&mut self, wait: bool) {
+        let (done, running) = std::mem::take(&mut self.running)
+            .into_iter()
+            .partition(|batch: &JoinHandle<BatchOutcome>| wait || batch.is_finished());
+        self.running = running;
+        for batch in done {
+            match batch.await {
+                Ok(outcome) => {
+                    self.finished.imported += outcome.imported;
+                    self.finished.errors.extend(outcome.errors);
+                }
+                Err(e) => error!("An insert batch of import {} failed: {}", self.import_id, e),
+            }
+        }
+    }
+
+    /// Rows inserted and rejected by the batches that are done so far.
+    pub async fn progress(&mut self) -> (usize, usize) {
+        self.collect(false).await;
+        (self.finished.imported, self.finished.errors.len())
+    }
+
+    /// Inserts the rows still queued and waits for every batch. Returns how
+    /// many rows were inserted and a report entry per rejected row.
+    pub async fn finish(mut self) -> (usize, Vec<Document>) {
+        self.flush().await;
+        self.collect(true).await;
+        (self.finished.imported, self.finished.errors)
+    }
+}
+
+// Why a row wasn't inserted, and whether another slug could fix that
+fn write_problem(row: &PendingRow, error: &BulkWriteError) -> (String, bool) {
+    if error.code == DUPLICATE_KEY && error.message.contains("index: slug_1") {
+        return (format!("Slug '{}' is already in use", row.product.slug.as_deref().unwrap_or_default()), true);
+    }
+    if error.code == DUPLICATE_KEY && error.message.contains("index: sku_1") {
+        return (format!("SKU '{}' is already in use", row.product.sku.as_deref().unwrap_or_default()), false);
+    }
+    error!("Failed to insert product at line {}: {}", row.line, error.message);
+    (format!("Database error: {}", error.message), false)
+}
+
+async fn insert_batch(db: &MongoConfig, events: &EventHub, import_id: ObjectId, rows: Vec<PendingRow>) -> BatchOutcome {
+    let collection: Collection<Product> = db.database.collection("products");
+    // Unordered, so one bad row doesn't stop the rest of the batch
+    let options = InsertManyOptions::builder().ordered(false).build();
+    let mut outcome = BatchOutcome::default();
+    let mut pending = rows;
+
+    for attempt in 1..=SLUG_ATTEMPTS {
+        let names: Vec<&str> = pending.iter().map(|row| row.product.name.as_str()).collect();
+        let slugs = match slugs::unique_slugs(db, &names).await {
+            Ok(slugs) => slugs,
+            Err(e) => {
+                error!("Failed to generate slugs for import {}: {}", import_id, e);
+                for row in &pending {
+                    outcome.reject(row, format!("Database error: {}", e));
+                }
+                return outcome;
+            }
+        };
+        for (row, slug) in pending.iter_mut().zip(slugs) {
+            row.product.slug = Some(slug);
+            // Set here so the IDs are known even when some rows fail
+            row.product.id.get_or_insert_with(ObjectId::new);
+        }
+
+        let write_errors: HashMap<usize, BulkWriteError> =
+            match collection.insert_many(pending.iter().map(|row| &row.product), options.clone()).await {
+                Ok(_) => HashMap::new(),
+                Err(e) => match *e.kind {
+                    ErrorKind::BulkWrite(failure) => {
+                        if let Some(concern) = failure.write_concern_error {
+                            warn!("Import {} batch was not acknowledged as requested: {}", import_id, concern.message);
+                        }
+                        failure.write_errors.unwrap_or_default().into_iter().map(|error| (error.index, error)).collect()
+                    }
+                    _ => {
+                        error!("Failed to insert a batch of import {}: {}", import_id, e);
+                        for row in &pending {
+                            outcome.reject(row, format!("Database error: {}", e));
+                        }
+                        return outcome;
+                    }
+                },
+            };
+
+        let mut retry = Vec::new();
+        let mut created = Vec::new();
+        for (index, row) in pending.into_iter().enumerate() {
+            let Some(error) = write_errors.get(&index) else {
+                let product_id = row.product.id.expect("set before inserting");
+                if let Ok(event) = ProductEvent::created(&row.product) {
+                    created.push((product_id, event));
+                }
+                events.publish(DomainEvent::ProductCreated { product_id: product_id.to_string() });
+                outcome.imported += 1;
+                continue;
+            };
+            match write_problem(&row, error) {
+                (_, true) if attempt < SLUG_ATTEMPTS => retry.push(row),
+                (problem, _) => outcome.reject(&row, problem),
+            }
+        }
+        event_store::record_many(db, created).await;
+        if retry.is_empty() {
+            break;
+        }
+        pending = retry;
+    }
+    outcome
+}
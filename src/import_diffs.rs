@@ -0,0 +1,779 @@
+use std::collections::{HashMap, HashSet};
+
+use actix_multipart::Multipart;
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use csv::{ReaderBuilder, StringRecord};
+use futures::{FutureExt, StreamExt, TryStreamExt};
+use mongodb::{
+    bson::{doc, oid::ObjectId, Bson, DateTime, Document},
+    ClientSession, Collection,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, error, info};
+
+use crate::{
+    auth::Claims,
+    config::{LimitsConfig, MongoConfig, PriceApprovalConfig},
+    errors::ApiError,
+    event_store::{self, ProductEvent},
+    events::{DomainEvent, EventHub},
+    handlers::{csv_row_count, payload_too_large},
+    import_history::{ImportLog, ImportOrigin},
+    models::{Category, CreateProductRequest, Product, ProductStatus, TaxClass, Unit, UpdateProductRequest},
+    money::{self, Decimal},
+    price_approvals::{self, PriceChangeResponse},
+    pricing, public_ids, search, slugs, suppliers,
+    transactions::{run_in_transaction, TransactionError},
+    trash,
+    validation_webhook::ValidationWebhook,
+};
+
+// A diff must be applied within this time, or computed again
+const DIFF_TTL_SECS: i64 = 60 * 60;
+
+/// The product fields a supplier catalog file sets, keyed by the supplier's SKU.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CatalogRow {
+    pub sku: String,
+    pub name: String,
+    #[serde(with = "money::price")]
+    pub price: Decimal,
+    pub category: Category,
+    pub has_active_sale: bool,
+    #[serde(default, with = "money::option_price", skip_serializing_if = "Option::is_none")]
+    pub cost_price: Option<Decimal>,
+}
+
+impl CatalogRow {
+    fn of(product: &Product, sku: &str) -> Self {
+        CatalogRow {
+            sku: sku.to_string(),
+            name: product.name.clone(),
+            price: product.price,
+            category: product.category.clone(),
+            has_active_sale: product.has_active_sale,
+            cost_price: product.cost_price,
+        }
+    }
+
+    // The `$set` turning a product with the values in `before` into this row
+    fn changes_from(&self, before: &CatalogRow) -> Document {
+        let mut set = Document::new();
+        if self.name != before.name {
+            set.insert("name", self.name.clone());
+            set.extend(search::rename_fields(&self.name));
+        }
+        if self.price != before.price {
+            set.insert("price", money::to_bson(self.price));
+        }
+        if self.category != before.category {
+            set.insert("category", self.category.to_string());
+        }
+        if self.has_active_sale != before.has_active_sale {
+            set.insert("has_active_sale", self.has_active_sale);
+        }
+        if self.cost_price != before.cost_price {
+            set.insert("cost_price", self.cost_price.map(money::to_bson).unwrap_or(Bson::Null));
+        }
+        set
+    }
+
+    // The changes as the validation webhook is asked about them
+    fn request_from(&self, before: &CatalogRow) -> UpdateProductRequest {
+        UpdateProductRequest {
+            name: (self.name != before.name).then(|| self.name.clone()),
+            price: (self.price != before.price).then_some(self.price),
+            category: (self.category != before.category).then(|| self.category.clone()),
+            has_active_sale: (self.has_active_sale != before.has_active_sale).then_some(self.has_active_sale),
+            cost_price: self.cost_price.filter(|_| self.cost_price != before.cost_price),
+            ..UpdateProductRequest::default()
+        }
+    }
+
+    // Matches the supplier's product only while it still has these values
+    fn filter(&self, product_id: ObjectId, supplier_id: ObjectId) -> Document {
+        doc! {
+            "_id": product_id,
+            "supplier_id": supplier_id,
+            "supplier_sku": self.sku.clone(),
+            "name": self.name.clone(),
+            "price": money::to_bson(self.price),
+            "category": self.category.to_string(),
+            "has_active_sale": self.has_active_sale,
+            "cost_price": self.cost_price.map(money::to_bson).unwrap_or(Bson::Null),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DiffUpdate {
+    pub product_id: ObjectId,
+    pub before: CatalogRow,
+    pub after: CatalogRow,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DiffRemoval {
+    pub product_id: ObjectId,
+    pub before: CatalogRow,
+}
+
+/// The difference between a supplier's catalog file and its current
+/// products, kept until it is applied or expires.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImportDiff {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub supplier_id: ObjectId,
+    pub filename: Option<String>,
+    pub created_by: ObjectId,
+    pub adds: Vec<CatalogRow>,
+    pub updates: Vec<DiffUpdate>,
+    pub removals: Vec<DiffRemoval>,
+    pub unchanged: i64,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub applied_at: Option<DateTime>,
+    // The import history entry written when the diff was applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub import_id: Option<ObjectId>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiffQuery {
+    supplier_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffUpdateResponse {
+    pub product_id: String,
+    pub before: CatalogRow,
+    pub after: CatalogRow,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffRemovalResponse {
+    pub product_id: String,
+    pub sku: String,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportDiffResponse {
+    pub id: String,
+    pub supplier_id: String,
+    pub filename: Option<String>,
+    pub adds: Vec<CatalogRow>,
+    pub updates: Vec<DiffUpdateResponse>,
+    pub removals: Vec<DiffRemovalResponse>,
+    pub unchanged: i64,
+    pub created_at: String,
+    pub expires_at: String,
+    pub applied_at: Option<String>,
+    pub import_id: Option<String>,
+}
+
+impl From<&ImportDiff> for ImportDiffResponse {
+    fn from(diff: &ImportDiff) -> Self {
+        ImportDiffResponse {
+            id: diff.id.to_string(),
+            supplier_id: diff.supplier_id.to_string(),
+            filename: diff.filename.clone(),
+            adds: diff.adds.clone(),
+            updates: diff
+                .updates
+                .iter()
+                .map(|update| DiffUpdateResponse {
+                    product_id: update.product_id.to_string(),
+                    before: update.before.clone(),
+                    after: update.after.clone(),
+                })
+                .collect(),
+            removals: diff
+                .removals
+                .iter()
+                .map(|removal| DiffRemovalResponse {
+                    product_id: removal.product_id.to_string(),
+                    sku: removal.before.sku.clone(),
+                    name: removal.before.name.clone(),
+                })
+                .collect(),
+            unchanged: diff.unchanged,
+            created_at: diff.created_at.try_to_rfc3339_string().unwrap_or_default(),
+            expires_at: diff.expires_at.try_to_rfc3339_string().unwrap_or_default(),
+            applied_at: diff.applied_at.and_then(|at| at.try_to_rfc3339_string().ok()),
+            import_id: diff.import_id.map(|id| id.to_string()),
+        }
+    }
+}
+
+// A row of the catalog file. Optional columns the file does not have are
+// None, which leaves the value of existing products alone.
+struct FileRow {
+    line: usize,
+    sku: String,
+    name: String,
+    price: Decimal,
+    category: Option<Category>,
+    has_active_sale: Option<bool>,
+    cost_price: Option<Option<Decimal>>,
+}
+
+// What compute_diff found
+struct Changes {
+    adds: Vec<CatalogRow>,
+    updates: Vec<DiffUpdate>,
+    removals: Vec<DiffRemoval>,
+    unchanged: i64,
+}
+
+// Business reasons for refusing to apply a diff
+enum DiffRejection {
+    AlreadyApplied,
+    // A product changed since the diff was computed
+    Stale(String),
+}
+
+pub fn diffs_collection(db: &MongoConfig) -> Collection<ImportDiff> {
+    db.database.collection("import_diffs")
+}
+
+fn products_collection(db: &MongoConfig) -> Collection<Product> {
+    db.database.collection("products")
+}
+
+fn parse_diff_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| {
+        error!("Invalid diff ID format: {}", id);
+        ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format").into()
+    })
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+fn row_error(line: usize, error: String, record: &StringRecord) -> Document {
+    doc! {
+        "line": line as i64,
+        "error": error,
+        "data": record.iter().collect::<Vec<_>>()
+    }
+}
+
+fn parse_price(value: &str) -> Result<Decimal, String> {
+    match money::parse(value.trim_start_matches('$')) {
+        Some(price) if !price.is_sign_negative() => Ok(price),
+        Some(price) => Err(format!("Invalid price: must be non-negative, got: '{}'", price)),
+        None => Err(format!("Invalid price format, got: '{}'", value)),
+    }
+}
+
+// Reads a catalog file with a header row. sku, name and price are required;
+// category, has_active_sale and cost_price are optional.
+fn parse_catalog(data: &[u8]) -> Result<Vec<FileRow>, Vec<Document>> {
+    let mut reader = ReaderBuilder::new().flexible(true).trim(csv::Trim::All).from_reader(data);
+    let headers = reader
+        .headers()
+        .map_err(|e| vec![doc! { "line": 1, "error": format!("Could not read header row: {}", e) }])?
+        .clone();
+    let column = |name: &str| headers.iter().position(|h| h.eq_ignore_ascii_case(name));
+
+    let (Some(sku_column), Some(name_column), Some(price_column)) = (column("sku"), column("name"), column("price"))
+    else {
+        return Err(vec![doc! { "line": 1, "error": "The header row must have sku, name and price columns" }]);
+    };
+    let category_column = column("category");
+    let sale_column = column("has_active_sale");
+    let cost_column = column("cost_price");
+
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    let mut seen = HashSet::new();
+
+    // Line numbers start from 2 to account for header row
+    for (line, result) in (2..).zip(reader.records()) {
+        let record = match result {
+            Ok(record) => record,
+            Err(e) => {
+                errors.push(doc! { "line": line as i64, "error": format!("Failed to parse CSV record: {}", e) });
+                continue;
+            }
+        };
+        let cell = |i: usize| record.get(i).unwrap_or("");
+        let errors_before = errors.len();
+
+        let sku = cell(sku_column).to_string();
+        if sku.is_empty() {
+            errors.push(row_error(line, "SKU is required".to_string(), &record));
+        } else if !seen.insert(sku.clone()) {
+            errors.push(row_error(line, format!("Duplicate SKU '{}'", sku), &record));
+        }
+        let name = cell(name_column).to_string();
+        if name.is_empty() {
+            errors.push(row_error(line, "Name is required".to_string(), &record));
+        }
+        let price = parse_price(cell(price_column)).unwrap_or_else(|e| {
+            errors.push(row_error(line, e, &record));
+            Decimal::ZERO
+        });
+        let category = category_column.map(|i| match cell(i) {
+            "" => Category::Other,
+            value => value.parse::<Category>().unwrap_or_else(|e| {
+                errors.push(row_error(line, e, &record));
+                Category::Other
+            }),
+        });
+        let has_active_sale = sale_column.map(|i| match cell(i) {
+            "" => false,
+            value => value.parse::<bool>().unwrap_or_else(|_| {
+                errors.push(row_error(line, format!("Invalid has_active_sale '{}'", value), &record));
+                false
+            }),
+        });
+        let cost_price = cost_column.map(|i| match cell(i) {
+            "" => None,
+            value => parse_price(value).map(Some).unwrap_or_else(|e| {
+                errors.push(row_error(line, format!("Cost price: {}", e), &record));
+                None
+            }),
+        });
+
+        if errors.len() == errors_before {
+            rows.push(FileRow { line, sku, name, price, category, has_active_sale, cost_price });
+        }
+    }
+
+    if errors.is_empty() { Ok(rows) } else { Err(errors) }
+}
+
+// Matches the file's rows to the supplier's products by SKU. Products
+// whose SKU is not in the file are removed.
+fn compute_diff(
+    rows: Vec<FileRow>,
+    products: &[Product],
+) -> Result<Changes, Vec<Document>> {
+    let mut by_sku: HashMap<&str, Vec<&Product>> = HashMap::new();
+    for product in products {
+        if let Some(sku) = product.supplier_sku.as_deref() {
+            by_sku.entry(sku).or_default().push(product);
+        }
+    }
+
+    let mut adds = Vec::new();
+    let mut updates = Vec::new();
+    let mut errors = Vec::new();
+    let mut unchanged = 0;
+    for row in &rows {
+        match by_sku.get(row.sku.as_str()).map(Vec::as_slice) {
+            None => adds.push(CatalogRow {
+                sku: row.sku.clone(),
+                name: row.name.clone(),
+                price: row.price,
+                category: row.category.clone().unwrap_or(Category::Other),
+                has_active_sale: row.has_active_sale.unwrap_or(false),
+                cost_price: row.cost_price.flatten(),
+            }),
+            Some([product]) => {
+                let Some(product_id) = product.id else { continue };
+                let before = CatalogRow::of(product, &row.sku);
+                let after = CatalogRow {
+                    sku: row.sku.clone(),
+                    name: row.name.clone(),
+                    price: row.price,
+                    category: row.category.clone().unwrap_or_else(|| before.category.clone()),
+                    has_active_sale: row.has_active_sale.unwrap_or(before.has_active_sale),
+                    cost_price: row.cost_price.unwrap_or(before.cost_price),
+                };
+                if after == before {
+                    unchanged += 1;
+                    continue;
+                }
+                if let Err(e) = pricing::check_pricing(after.price, product.unit, product.price_per_unit, &product.price_tiers) {
+                    errors.push(doc! { "line": row.line as i64, "sku": row.sku.clone(), "error": e });
+                    continue;
+                }
+                updates.push(DiffUpdate { product_id, before, after });
+            }
+            Some(_) => errors.push(doc! {
+                "line": row.line as i64,
+                "sku": row.sku.clone(),
+                "error": "SKU matches several products of this supplier",
+            }),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let wanted: HashSet<&str> = rows.iter().map(|row| row.sku.as_str()).collect();
+    let removals = products
+        .iter()
+        .filter_map(|product| {
+            let sku = product.supplier_sku.as_deref()?;
+            let product_id = product.id?;
+            (!wanted.contains(sku)).then(|| DiffRemoval { product_id, before: CatalogRow::of(product, sku) })
+        })
+        .collect();
+
+    Ok(Changes { adds, updates, removals, unchanged })
+}
+
+/// Computes what importing a supplier's full catalog file would change: the
+/// products to add, update and remove, matched by the supplier's SKU. Nothing
+/// is changed until the diff is applied.
+pub async fn create_import_diff(
+    db: web::Data<MongoConfig>,
+    limits: web::Data<LimitsConfig>,
+    claims: web::ReqData<Claims>,
+    query: web::Query<DiffQuery>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    let supplier_id = suppliers::check_supplier(&db, &query.supplier_id).await?;
+
+    let mut upload = None;
+    while let Some(item) = payload.next().await {
+        let mut field = item.map_err(|e| {
+            error!("Error getting multipart field: {}", e);
+            ApiError::new(StatusCode::BAD_REQUEST, format!("Multipart error: {}", e))
+        })?;
+        if field.name() != "file" {
+            continue;
+        }
+
+        let filename = field.content_disposition().get_filename().map(str::to_string);
+        let mut data = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|e| {
+                error!("Error reading multipart chunk: {}", e);
+                ApiError::new(StatusCode::BAD_REQUEST, "Failed to read uploaded file")
+            })?;
+            if data.len() + chunk.len() > limits.upload_bytes {
+                debug!("Upload exceeded {} bytes, aborting", limits.upload_bytes);
+                return Err(payload_too_large(
+                    format!("Upload exceeds the limit of {} bytes", limits.upload_bytes),
+                    limits.upload_bytes,
+                ).into());
+            }
+            data.extend_from_slice(&chunk);
+        }
+        upload = Some((filename, data));
+        break;
+    }
+    let Some((filename, data)) = upload else {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "No file uploaded").into());
+    };
+
+    let row_count = csv_row_count(data.as_slice());
+    if row_count > limits.csv_max_rows {
+        debug!("CSV has {} rows, limit is {}", row_count, limits.csv_max_rows);
+        return Err(payload_too_large(
+            format!("CSV has {} rows, exceeding the limit of {} rows", row_count, limits.csv_max_rows),
+            limits.csv_max_rows,
+        ).into());
+    }
+
+    let rows = match parse_catalog(&data) {
+        Ok(rows) => rows,
+        Err(errors) => return Err(ApiError::blank(StatusCode::UNPROCESSABLE_ENTITY).with("errors", errors).into()),
+    };
+
+    let products: Vec<Product> = products_collection(&db)
+        .find(doc! { "supplier_id": supplier_id, "supplier_sku": { "$exists": true } }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch supplier products", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating supplier products", e))?;
+
+    let changes = match compute_diff(rows, &products) {
+        Ok(changes) => changes,
+        Err(errors) => return Err(ApiError::blank(StatusCode::UNPROCESSABLE_ENTITY).with("errors", errors).into()),
+    };
+
+    let now = DateTime::now();
+    let diff = ImportDiff {
+        id: ObjectId::new(),
+        supplier_id,
+        filename,
+        created_by: claims.user_id()?,
+        adds: changes.adds,
+        updates: changes.updates,
+        removals: changes.removals,
+        unchanged: changes.unchanged,
+        created_at: now,
+        expires_at: DateTime::from_millis(now.timestamp_millis() + DIFF_TTL_SECS * 1000),
+        applied_at: None,
+        import_id: None,
+    };
+    diffs_collection(&db)
+        .insert_one(&diff, None)
+        .await
+        .map_err(|e| db_error("Failed to save import diff", e))?;
+
+    info!(
+        "Import diff {} for supplier {}: {} to add, {} to update, {} to remove",
+        diff.id,
+        supplier_id,
+        diff.adds.len(),
+        diff.updates.len(),
+        diff.removals.len()
+    );
+    Ok(HttpResponse::Created().json(ImportDiffResponse::from(&diff)))
+}
+
+pub async fn get_import_diff(db: web::Data<MongoConfig>, id: web::Path<String>) -> Result<HttpResponse, Error> {
+    let diff_id = parse_diff_id(&id)?;
+
+    let diff = diffs_collection(&db)
+        .find_one(doc! { "_id": diff_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch import diff", e))?;
+
+    match diff {
+        Some(diff) => Ok(HttpResponse::Ok().json(ImportDiffResponse::from(&diff))),
+        None => Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    }
+}
+
+// What apply_diff wrote: the products it created and those it changed
+struct Applied {
+    created: Vec<ObjectId>,
+    updated: Vec<ObjectId>,
+}
+
+// Prices of products in `held` are left alone; their changes wait for approval
+async fn apply_diff(
+    db: &MongoConfig,
+    session: &mut ClientSession,
+    diff: &ImportDiff,
+    held: &HashSet<ObjectId>,
+    import_id: ObjectId,
+    user_id: ObjectId,
+) -> Result<Applied, TransactionError<DiffRejection>> {
+    let claimed = diffs_collection(db)
+        .update_one_with_session(
+            doc! { "_id": diff.id, "applied_at": { "$exists": false } },
+            doc! { "$set": { "applied_at": DateTime::now(), "import_id": import_id } },
+            None,
+            session,
+        )
+        .await?;
+    if claimed.matched_count == 0 {
+        return Err(TransactionError::Aborted(DiffRejection::AlreadyApplied));
+    }
+
+    let products = products_collection(db);
+    for removal in &diff.removals {
+        let filter = removal.before.filter(removal.product_id, diff.supplier_id);
+        if products.count_documents_with_session(filter, None, session).await? == 0
+            || !trash::move_to_trash_with_session(db, session, removal.product_id, Some(user_id)).await?
+        {
+            return Err(TransactionError::Aborted(DiffRejection::Stale(removal.before.sku.clone())));
+        }
+    }
+
+    let mut updated = Vec::with_capacity(diff.updates.len());
+    for update in &diff.updates {
+        let mut set = update.after.changes_from(&update.before);
+        if held.contains(&update.product_id) {
+            set.remove("price");
+        }
+        let filter = update.before.filter(update.product_id, diff.supplier_id);
+        // A held price may have been the only change, and $set can't be empty
+        let matched = if set.is_empty() {
+            products.count_documents_with_session(filter, None, session).await?
+        } else {
+            products.update_one_with_session(filter, doc! { "$set": set.clone() }, None, session).await?.matched_count
+        };
+        if matched == 0 {
+            return Err(TransactionError::Aborted(DiffRejection::Stale(update.before.sku.clone())));
+        }
+        if !set.is_empty() {
+            event_store::record_with_session(db, session, update.product_id, ProductEvent::for_update(&set)).await?;
+            updated.push(update.product_id);
+        }
+    }
+
+    let mut created = Vec::with_capacity(diff.adds.len());
+    for row in &diff.adds {
+        let taken = products
+            .count_documents_with_session(doc! { "supplier_id": diff.supplier_id, "supplier_sku": row.sku.clone() }, None, session)
+            .await?;
+        if taken > 0 {
+            return Err(TransactionError::Aborted(DiffRejection::Stale(row.sku.clone())));
+        }
+
+        let product = Product {
+            id: None,
+            public_id: Some(public_ids::new_public_id()),
+            name: row.name.clone(),
+            slug: Some(slugs::unique_slug(db, &row.name, None).await?),
+            previous_slugs: Vec::new(),
+            search_grams: search::name_grams(&row.name),
+            sku: None,
+            description: None,
+            price: row.price,
+            category: row.category.clone(),
+            status: ProductStatus::Active,
+            tax_class: TaxClass::default(),
+            unit: Unit::default(),
+            price_per_unit: None,
+            price_tiers: Vec::new(),
+            has_active_sale: row.has_active_sale,
+            sale_ends_at: None,
+            stock_quantity: None,
+            low_stock_threshold: None,
+            barcode: None,
+            attributes: None,
+            supplier_id: Some(diff.supplier_id),
+            supplier_sku: Some(row.sku.clone()),
+            cost_price: row.cost_price,
+            import_id: Some(import_id),
+            bundle: None,
+            age_restricted: false,
+            hazardous: false,
+            region_restrictions: Vec::new(),
+        };
+        let result = products.insert_one_with_session(&product, None, session).await?;
+        if let Some(product_id) = result.inserted_id.as_object_id() {
+            if let Ok(event) = ProductEvent::created(&product) {
+                event_store::record_with_session(db, session, product_id, vec![event]).await?;
+            }
+            created.push(product_id);
+        }
+    }
+    Ok(Applied { created, updated })
+}
+
+// Files the held price changes once the rest of the diff is in
+async fn request_held_prices(
+    db: &MongoConfig,
+    diff: &ImportDiff,
+    held: &HashSet<ObjectId>,
+    user_id: ObjectId,
+) -> Result<Vec<PriceChangeResponse>, Error> {
+    let mut requests = Vec::with_capacity(held.len());
+    for update in diff.updates.iter().filter(|update| held.contains(&update.product_id)) {
+        let product = products_collection(db)
+            .find_one(doc! { "_id": update.product_id }, None)
+            .await
+            .map_err(|e| db_error("Failed to fetch product", e))?;
+        let Some(product) = product else {
+            continue;
+        };
+        let request = price_approvals::request_change(db, &product, update.after.price, user_id)
+            .await
+            .map_err(|e| db_error("Failed to request price change", e))?;
+        requests.push(PriceChangeResponse::from(&request));
+    }
+    Ok(requests)
+}
+
+// Asks the validation webhook about every add and update, before anything
+// is written
+async fn check_with_webhook(webhook: &ValidationWebhook, diff: &ImportDiff) -> Result<(), Error> {
+    for row in &diff.adds {
+        let product = CreateProductRequest {
+            has_active_sale: row.has_active_sale,
+            supplier_id: Some(diff.supplier_id.to_hex()),
+            supplier_sku: Some(row.sku.clone()),
+            cost_price: row.cost_price,
+            ..CreateProductRequest::new(row.name.clone(), row.price, row.category.clone())
+        };
+        webhook.check_create(&product).await.map_err(|rejection| rejection.to_problem().with("sku", &row.sku))?;
+    }
+    for update in &diff.updates {
+        let changes = update.after.request_from(&update.before);
+        webhook
+            .check_update(&update.product_id.to_hex(), &changes)
+            .await
+            .map_err(|rejection| rejection.to_problem().with("sku", &update.before.sku))?;
+    }
+    Ok(())
+}
+
+/// Applies a computed diff in one transaction: either every add, update and
+/// removal is made or none is. Refused if any of the products changed since
+/// the diff was computed, or the validation webhook refuses any of the rows.
+/// Price changes beyond the approval threshold are filed as price change
+/// requests instead of made.
+pub async fn apply_import_diff(
+    db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
+    approvals: web::Data<PriceApprovalConfig>,
+    webhook: Option<web::Data<ValidationWebhook>>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let diff_id = parse_diff_id(&id)?;
+    let user_id = claims.user_id()?;
+
+    let diff = diffs_collection(&db)
+        .find_one(doc! { "_id": diff_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch import diff", e))?;
+    let Some(diff) = diff else {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    };
+    if diff.applied_at.is_some() {
+        return Err(ApiError::new(StatusCode::CONFLICT, "Diff was already applied").into());
+    }
+    if let Some(webhook) = &webhook {
+        check_with_webhook(webhook, &diff).await?;
+    }
+
+    let held: HashSet<ObjectId> = diff
+        .updates
+        .iter()
+        .filter(|update| price_approvals::needs_approval(&approvals, update.before.price, update.after.price))
+        .map(|update| update.product_id)
+        .collect();
+
+    let import = ImportLog::begin(ImportOrigin::Diff, Some(user_id)).filename(diff.filename.clone());
+    let outcome = run_in_transaction(
+        &db,
+        (&**db, &diff, &held, import.id, user_id),
+        |session, (db, diff, held, import_id, user_id)| apply_diff(db, session, diff, held, *import_id, *user_id).boxed(),
+    )
+    .await;
+
+    match outcome {
+        Ok(Applied { created, updated }) => {
+            let import_id = import.id;
+            import.finish(&db, diff.adds.len() + diff.updates.len(), 0).await;
+            let price_changes = request_held_prices(&db, &diff, &held, user_id).await?;
+            for product_id in &created {
+                events.publish(DomainEvent::ProductCreated { product_id: product_id.to_string() });
+            }
+            for product_id in &updated {
+                events.publish(DomainEvent::ProductUpdated { product_id: product_id.to_string() });
+            }
+            for removal in &diff.removals {
+                events.publish(DomainEvent::ProductDeleted { product_id: removal.product_id.to_string() });
+            }
+            info!("Import diff {} applied by {}", diff.id, user_id);
+            Ok(HttpResponse::Ok().json(json!({
+                "message": "Import diff applied",
+                "import_id": import_id.to_string(),
+                "added": created.len(),
+                "updated": diff.updates.len(),
+                "removed": diff.removals.len(),
+                "price_changes": price_changes,
+            })))
+        }
+        Err(TransactionError::Aborted(DiffRejection::AlreadyApplied)) => {
+            Err(ApiError::new(StatusCode::CONFLICT, "Diff was already applied").into())
+        }
+        Err(TransactionError::Aborted(DiffRejection::Stale(sku))) => {
+            import.fail(&db, &format!("Product with SKU '{}' changed since the diff was computed", sku)).await;
+            let message = "Products changed since the diff was computed; upload the file again";
+            Err(ApiError::new(StatusCode::CONFLICT, message).with("sku", sku).into())
+        }
+        Err(TransactionError::Database(e)) => Err(db_error("Failed to apply import diff", e)),
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    fmt,
+    io::{Cursor, Read},
+};
+
+use calamine::{Reader, Xlsx};
+use csv::ReaderBuilder;
+use csv_async::AsyncReaderBuilder;
+use futures::{
+    future::BoxFuture,
+    io::AsyncRead,
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::import_rules::{DecimalSeparator, ImportRules, PriceFormat};
+
+const XLSX_CONTENT_TYPE: &str = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+
+// Fields every import file starts with, in this order
+const CORE_FIELDS: [&str; 4] = ["name", "price", "category", "has_active_sale"];
+
+/// One data row of an import file, whatever its format: the values in the
+/// order of the file's headers.
+#[derive(Debug, Clone)]
+pub struct RawRecord {
+    // Where the row is in the file, for error reports; 1 is the header row
+    pub line: u64,
+    pub values: Vec<String>,
+}
+
+impl RawRecord {
+    pub fn get(&self, column: usize) -> Option<&str> {
+        self.values.get(column).map(String::as_str)
+    }
+}
+
+/// A row that could not be read at all.
+#[derive(Debug)]
+pub struct RecordError {
+    pub line: u64,
+    pub message: String,
+}
+
+pub type RawRecords<'a> = BoxStream<'a, Result<RawRecord, RecordError>>;
+
+/// An import file reduced to its header row and data rows.
+pub struct RawTable<'a> {
+    pub headers: Vec<String>,
+    pub rows: RawRecords<'a>,
+}
+
+/// Turns the bytes of one kind of import file into rows the import checks
+/// and inserts without knowing where they came from.
+pub trait ImportFormat: Sync {
+    fn read<'a>(&self, reader: Box<dyn Read + Send + 'a>) -> Result<RawTable<'a>, String>;
+
+    /// Data rows in the file, for the row limit.
+    fn count_rows<'a>(&'a self, reader: Box<dyn Read + Send + 'a>) -> BoxFuture<'a, Result<usize, String>> {
+        Box::pin(async move { Ok(self.read(reader)?.rows.count().await) })
+    }
+}
+
+/// CSV, or TSV with a tab as the delimiter.
+pub struct Delimited {
+    delimiter: u8,
+    name: &'static str,
+}
+
+impl Delimited {
+    /// Reads a file while it arrives, e.g. an upload still being received.
+    /// Rows are parsed as they are taken, so the file is never held whole.
+    pub async fn read_async<'a, R: AsyncRead + Unpin + Send + 'a>(&self, reader: R) -> Result<RawTable<'a>, String> {
+        let mut rdr = AsyncReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .flexible(true)
+            .trim(csv_async::Trim::All)
+            .create_reader(reader);
+        let headers = rdr
+            .headers()
+            .await
+            .map_err(|e| format!("Could not read header row: {}", e))?
+            .iter()
+            .map(str::to_string)
+            .collect();
+
+        let name = self.name;
+        let rows = stream::iter(2..).zip(rdr.into_records()).map(move |(line, result)| match result {
+            Ok(record) => Ok(RawRecord { line, values: record.iter().map(str::to_string).collect() }),
+            Err(e) => Err(RecordError { line, message: format!("Failed to parse {} record: {}", name, e) }),
+        });
+        Ok(RawTable { headers, rows: rows.boxed() })
+    }
+}
+
+impl ImportFormat for Delimited {
+    fn read<'a>(&self, reader: Box<dyn Read + Send + 'a>) -> Result<RawTable<'a>, String> {
+        let mut rdr = ReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        let headers = rdr
+            .headers()
+            .map_err(|e| format!("Could not read header row: {}", e))?
+            .iter()
+            .map(str::to_string)
+            .collect();
+
+        let name = self.name;
+        // Line numbers start from 2 to account for header row
+        let rows = (2..).zip(rdr.into_records()).map(move |(line, result)| match result {
+            Ok(record) => Ok(RawRecord { line, values: record.iter().map(str::to_string).collect() }),
+            Err(e) => Err(RecordError { line, message: format!("Failed to parse {} record: {}", name, e) }),
+        });
+        Ok(RawTable { headers, rows: stream::iter(rows).boxed() })
+    }
+}
+
+/// The first sheet of a workbook, read as if it were a CSV file.
+pub struct Workbook;
+
+impl ImportFormat for Workbook {
+    fn read<'a>(&self, mut reader: Box<dyn Read + Send + 'a>) -> Result<RawTable<'a>, String> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+        let invalid = |e: &dyn fmt::Display| e.to_string();
+
+        let mut workbook: Xlsx<_> = Xlsx::new(Cursor::new(bytes)).map_err(|e| invalid(&e))?;
+        let range = workbook
+            .worksheet_range_at(0)
+            .ok_or_else(|| "workbook has no sheets".to_string())?
+            .map_err(|e| invalid(&e))?;
+
+        let mut rows = range
+            .rows()
+            .map(|row| row.iter().map(|cell| cell.to_string().trim().to_string()).collect::<Vec<_>>());
+        let headers = rows.next().unwrap_or_default();
+        let records: Vec<_> = (2..).zip(rows).map(|(line, values)| Ok(RawRecord { line, values })).collect();
+        Ok(RawTable { headers, rows: stream::iter(records).boxed() })
+    }
+}
+
+/// An array of objects keyed by column name. The core fields come first, as
+/// in the other formats, followed by the remaining keys in order of appearance.
+pub struct JsonArray;
+
+fn json_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.trim().to_string(),
+        other => other.to_string(),
+    }
+}
+
+impl ImportFormat for JsonArray {
+    fn read<'a>(&self, reader: Box<dyn Read + Send + 'a>) -> Result<RawTable<'a>, String> {
+        let items: Vec<Value> =
+            serde_json::from_reader(reader).map_err(|e| format!("JSON imports must be an array of objects: {}", e))?;
+
+        let mut headers: Vec<String> = CORE_FIELDS.iter().map(|field| field.to_string()).collect();
+        for item in &items {
+            for key in item.as_object().into_iter().flat_map(Map::keys) {
+                if !headers.iter().any(|header| header == key) {
+                    headers.push(key.clone());
+                }
+            }
+        }
+
+        // Objects are numbered from 2 so the first lines up with the first row of a CSV file
+        let columns = headers.clone();
+        let records: Vec<_> = (2..)
+            .zip(items)
+            .map(|(line, item)| match item {
+                Value::Object(object) => Ok(RawRecord {
+                    line,
+                    values: columns.iter().map(|key| object.get(key).map(json_text).unwrap_or_default()).collect(),
+                }),
+                other => Err(RecordError { line, message: format!("Expected an object, got: {}", other) }),
+            })
+            .collect();
+        Ok(RawTable { headers, rows: stream::iter(records).boxed() })
+    }
+}
+
+const CSV: Delimited = Delimited { delimiter: b',', name: "CSV" };
+const TSV: Delimited = Delimited { delimiter: b'\t', name: "TSV" };
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    Csv,
+    Tsv,
+    Xlsx,
+    Json,
+}
+
+impl FileFormat {
+    pub fn reader(self) -> &'static dyn ImportFormat {
+        match self {
+            FileFormat::Csv => &CSV,
+            FileFormat::Tsv => &TSV,
+            FileFormat::Xlsx => &Workbook,
+            FileFormat::Json => &JsonArray,
+        }
+    }
+
+    /// The reader for formats that can be read while they arrive. Workbooks
+    /// and JSON arrays need the whole file first.
+    pub fn streaming_reader(self) -> Option<&'static Delimited> {
+        match self {
+            FileFormat::Csv => Some(&CSV),
+            FileFormat::Tsv => Some(&TSV),
+            FileFormat::Xlsx | FileFormat::Json => None,
+        }
+    }
+
+    pub fn from_file_name(name: &str) -> Option<FileFormat> {
+        let (_, extension) = name.rsplit_once('.')?;
+        match extension.to_lowercase().as_str() {
+            "csv" => Some(FileFormat::Csv),
+            "tsv" | "tab" => Some(FileFormat::Tsv),
+            "xlsx" => Some(FileFormat::Xlsx),
+            "json" => Some(FileFormat::Json),
+            _ => None,
+        }
+    }
+
+    /// The format of a download: `requested` if given, else its Content-Type,
+    /// else the extension of `name`. Errors with the unsupported type.
+    pub fn detect(content_type: Option<&str>, name: &str, requested: Option<FileFormat>) -> Result<FileFormat, String> {
+        let from_type = match content_type {
+            Some(XLSX_CONTENT_TYPE) => Some(FileFormat::Xlsx),
+            Some("text/csv" | "application/csv" | "text/comma-separated-values" | "text/plain" | "application/vnd.ms-excel") => {
+                Some(FileFormat::Csv)
+            }
+            Some("text/tab-separated-values") => Some(FileFormat::Tsv),
+            Some("application/json") => Some(FileFormat::Json),
+            // Generic types say nothing about the contents; fall back to the request or the file name
+            None | Some("application/octet-stream" | "binary/octet-stream") => None,
+            Some(other) => return Err(other.to_string()),
+        };
+        requested
+            .or(from_type)
+            .or_else(|| FileFormat::from_file_name(name))
+            .ok_or_else(|| "unknown; pass format explicitly".to_string())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImportQuery {
+    // Taken from the file name when omitted, CSV if that says nothing
+    pub format: Option<FileFormat>,
+    pub decimal_separator: Option<DecimalSeparator>,
+    pub currency: Option<String>,
+}
+
+impl ImportQuery {
+    pub fn rules(&self) -> Result<ImportRules, String> {
+        let prices = PriceFormat { decimal_separator: self.decimal_separator, currency: self.currency.clone() };
+        prices.check()?;
+        Ok(ImportRules { prices, ..ImportRules::default() })
+    }
+}
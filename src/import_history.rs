@@ -0,0 +1,340 @@
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use chrono::{DateTime as ChronoDateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    options::FindOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+use validator::Validate;
+
+use crate::{
+    auth::Claims,
+    config::{LimitsConfig, MongoConfig},
+    errors::ApiError,
+    events::{DomainEvent, EventHub},
+    import_sources::RunStatus,
+    models::Product,
+    notifications::{self, Notification, NotificationKind},
+    trash,
+    validation::{page_offset, ValidatedQuery},
+};
+
+/// How an import was started.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportOrigin {
+    Upload,
+    Url,
+    ImportSource,
+    Cli,
+    Diff,
+}
+
+/// What is known about one import: where the file came from, who started
+/// it, how many rows went in and how long it took.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ImportRecord {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub origin: ImportOrigin,
+    pub filename: Option<String>,
+    pub url: Option<String>,
+    pub user_id: Option<ObjectId>,
+    pub import_source_id: Option<ObjectId>,
+    pub status: RunStatus,
+    pub imported: i64,
+    pub rejected: i64,
+    pub message: Option<String>,
+    pub started_at: DateTime,
+    pub finished_at: DateTime,
+    pub duration_ms: i64,
+    // Set once the products the import created have been moved to the trash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rolled_back_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rolled_back_by: Option<ObjectId>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportRecordResponse {
+    pub id: String,
+    pub origin: ImportOrigin,
+    pub filename: Option<String>,
+    pub url: Option<String>,
+    pub user_id: Option<String>,
+    pub import_source_id: Option<String>,
+    pub status: RunStatus,
+    pub imported: i64,
+    pub rejected: i64,
+    pub message: Option<String>,
+    pub started_at: String,
+    pub finished_at: String,
+    pub duration_ms: i64,
+    pub rolled_back_at: Option<String>,
+    pub rolled_back_by: Option<String>,
+}
+
+impl From<&ImportRecord> for ImportRecordResponse {
+    fn from(record: &ImportRecord) -> Self {
+        ImportRecordResponse {
+            id: record.id.to_string(),
+            origin: record.origin,
+            filename: record.filename.clone(),
+            url: record.url.clone(),
+            user_id: record.user_id.map(|id| id.to_string()),
+            import_source_id: record.import_source_id.map(|id| id.to_string()),
+            status: record.status,
+            imported: record.imported,
+            rejected: record.rejected,
+            message: record.message.clone(),
+            started_at: record.started_at.try_to_rfc3339_string().unwrap_or_default(),
+            finished_at: record.finished_at.try_to_rfc3339_string().unwrap_or_default(),
+            duration_ms: record.duration_ms,
+            rolled_back_at: record.rolled_back_at.and_then(|at| at.try_to_rfc3339_string().ok()),
+            rolled_back_by: record.rolled_back_by.map(|id| id.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ListImportsQuery {
+    #[validate(range(min = 1, message = "page must be at least 1"))]
+    page: Option<i64>,
+    #[validate(range(min = 1, message = "per_page must be at least 1"))]
+    per_page: Option<i64>,
+    // Imports started at or after this time
+    from: Option<ChronoDateTime<Utc>>,
+    // Imports started before this time
+    to: Option<ChronoDateTime<Utc>>,
+    user_id: Option<String>,
+    status: Option<RunStatus>,
+    origin: Option<ImportOrigin>,
+}
+
+pub fn imports_collection(db: &MongoConfig) -> Collection<ImportRecord> {
+    db.database.collection("imports")
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+/// An import in progress. Its ID is known up front so products can be
+/// tagged with it; `finish` writes the record.
+pub struct ImportLog {
+    pub id: ObjectId,
+    origin: ImportOrigin,
+    filename: Option<String>,
+    url: Option<String>,
+    user_id: Option<ObjectId>,
+    import_source_id: Option<ObjectId>,
+    started_at: DateTime,
+}
+
+impl ImportLog {
+    pub fn begin(origin: ImportOrigin, user_id: Option<ObjectId>) -> Self {
+        ImportLog {
+            id: ObjectId::new(),
+            origin,
+            filename: None,
+            url: None,
+            user_id,
+            import_source_id: None,
+            started_at: DateTime::now(),
+        }
+    }
+
+    pub fn filename(mut self, filename: Option<String>) -> Self {
+        self.filename = filename;
+        self
+    }
+
+    pub fn url(mut self, url: &str) -> Self {
+        self.url = Some(url.to_string());
+        self
+    }
+
+    pub fn import_source(mut self, import_source_id: ObjectId) -> Self {
+        self.import_source_id = Some(import_source_id);
+        self
+    }
+
+    /// Records an import that ran, with or without rejected rows.
+    pub async fn finish(self, db: &MongoConfig, imported: usize, rejected: usize) {
+        let status = if rejected == 0 { RunStatus::Succeeded } else { RunStatus::CompletedWithErrors };
+        self.write(db, status, imported, rejected, None).await;
+    }
+
+    /// Records an import that was stopped part way, with the rows done until then.
+    pub async fn cancel(self, db: &MongoConfig, imported: usize, rejected: usize) {
+        self.write(db, RunStatus::Cancelled, imported, rejected, Some("Cancelled".to_string())).await;
+    }
+
+    /// Records an import that could not run, e.g. because the file could not be fetched.
+    pub async fn fail(self, db: &MongoConfig, message: &str) {
+        self.write(db, RunStatus::Failed, 0, 0, Some(message.to_string())).await;
+    }
+
+    // History is best effort: an import is not failed because it could not be logged
+    async fn write(self, db: &MongoConfig, status: RunStatus, imported: usize, rejected: usize, message: Option<String>) {
+        let finished_at = DateTime::now();
+        let record = ImportRecord {
+            id: self.id,
+            origin: self.origin,
+            filename: self.filename,
+            url: self.url,
+            user_id: self.user_id,
+            import_source_id: self.import_source_id,
+            status,
+            imported: imported as i64,
+            rejected: rejected as i64,
+            message,
+            started_at: self.started_at,
+            finished_at,
+            duration_ms: finished_at.timestamp_millis() - self.started_at.timestamp_millis(),
+            rolled_back_at: None,
+            rolled_back_by: None,
+        };
+        if let Err(e) = imports_collection(db).insert_one(&record, None).await {
+            warn!("Failed to record import {}: {}", record.id, e);
+        }
+
+        if let Some(user_id) = record.user_id {
+            let what = record.filename.as_deref().or(record.url.as_deref()).unwrap_or("your file");
+            let message = match &record.message {
+                Some(message) if record.status == RunStatus::Failed => format!("Import of {} failed: {}", what, message),
+                _ if record.status == RunStatus::Cancelled => {
+                    format!("Import of {} cancelled after {} products, {} rows rejected", what, record.imported, record.rejected)
+                }
+                _ => format!("Imported {} products from {}, {} rows rejected", record.imported, what, record.rejected),
+            };
+            let notification = Notification::new(user_id, NotificationKind::ImportCompleted, message).import(record.id);
+            notifications::notify(db, notification).await;
+        }
+    }
+}
+
+/// Past imports, newest first, filtered by start time, user, status or origin.
+pub async fn list_imports(
+    db: web::Data<MongoConfig>,
+    limits: web::Data<LimitsConfig>,
+    query: ValidatedQuery<ListImportsQuery>,
+) -> Result<HttpResponse, Error> {
+    let per_page = query.per_page.unwrap_or(20);
+    if per_page > limits.max_per_page {
+        let message = format!("per_page must be between 1 and {}", limits.max_per_page);
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, message).into());
+    }
+    let page = query.page.unwrap_or(1);
+
+    let mut filter = Document::new();
+    let mut started_at = Document::new();
+    if let Some(from) = query.from {
+        started_at.insert("$gte", DateTime::from_millis(from.timestamp_millis()));
+    }
+    if let Some(to) = query.to {
+        started_at.insert("$lt", DateTime::from_millis(to.timestamp_millis()));
+    }
+    if !started_at.is_empty() {
+        filter.insert("started_at", started_at);
+    }
+    if let Some(user_id) = &query.user_id {
+        let user_id = ObjectId::parse_str(user_id)
+            .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "Invalid user ID format"))?;
+        filter.insert("user_id", user_id);
+    }
+    if let Some(status) = query.status {
+        filter.insert("status", mongodb::bson::to_bson(&status).map_err(ApiError::internal)?);
+    }
+    if let Some(origin) = query.origin {
+        filter.insert("origin", mongodb::bson::to_bson(&origin).map_err(ApiError::internal)?);
+    }
+
+    let options = FindOptions::builder()
+        .sort(doc! { "started_at": -1 })
+        .skip(page_offset(page, per_page)? as u64)
+        .limit(per_page)
+        .build();
+    let records: Vec<ImportRecord> = imports_collection(&db)
+        .find(filter, options)
+        .await
+        .map_err(|e| db_error("Failed to fetch imports", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating imports", e))?;
+
+    let records: Vec<ImportRecordResponse> = records.iter().map(ImportRecordResponse::from).collect();
+    Ok(HttpResponse::Ok().json(records))
+}
+
+/// Moves the products tagged with `import_id` to the trash and marks the
+/// import rolled back. Returns how many products were moved.
+pub async fn roll_back(
+    db: &MongoConfig,
+    events: &EventHub,
+    import_id: ObjectId,
+    user_id: ObjectId,
+) -> Result<i64, mongodb::error::Error> {
+    let products: Collection<Product> = db.database.collection("products");
+    let products: Vec<Product> = products.find(doc! { "import_id": import_id }, None).await?.try_collect().await?;
+
+    // A failure part way leaves the import open, so the rollback can be retried
+    let mut removed: i64 = 0;
+    for product_id in products.iter().filter_map(|product| product.id) {
+        if trash::move_to_trash(db, product_id, Some(user_id)).await? {
+            removed += 1;
+            events.publish(DomainEvent::ProductDeleted { product_id: product_id.to_string() });
+        }
+    }
+
+    imports_collection(db)
+        .update_one(
+            doc! { "_id": import_id },
+            doc! { "$set": { "rolled_back_at": DateTime::now(), "rolled_back_by": user_id } },
+            None,
+        )
+        .await?;
+    Ok(removed)
+}
+
+/// Undoes an import by moving every product it created to the trash, where
+/// they can still be restored until purged. Products are matched by the
+/// import ID they were tagged with, so nothing else in the catalog is touched.
+pub async fn rollback_import(
+    db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let import_id = ObjectId::parse_str(id.as_str()).map_err(|_| {
+        error!("Invalid import ID format: {}", id);
+        ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format")
+    })?;
+    let user_id = claims.user_id()?;
+
+    let record = imports_collection(&db)
+        .find_one(doc! { "_id": import_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch import", e))?;
+    let Some(record) = record else {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    };
+    if record.rolled_back_at.is_some() {
+        return Err(ApiError::new(StatusCode::CONFLICT, "Import was already rolled back").into());
+    }
+
+    let removed = roll_back(&db, &events, import_id, user_id)
+        .await
+        .map_err(|e| db_error("Failed to roll back import", e))?;
+
+    info!("Import {} rolled back by {}, {} products moved to the trash", import_id, user_id, removed);
+    Ok(HttpResponse::Ok().json(doc! {
+        "message": format!("Moved {} products to the trash", removed),
+        "removed": removed,
+    }))
+}
@@ -0,0 +1,410 @@
+use actix_multipart::Multipart;
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::{future::BoxFuture, StreamExt};
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    options::{FindOneAndUpdateOptions, ReturnDocument},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    auth::Claims,
+    config::{LimitsConfig, MongoConfig},
+    errors::ApiError,
+    events::EventHub,
+    handlers::{import_rows_until, payload_too_large, ImportCheckpoint, ImportProgress},
+    import_batches::BatchLimits,
+    import_formats::FileFormat,
+    import_rules::{DecimalSeparator, ImportRules, PriceFormat},
+    import_history::{self, ImportLog, ImportOrigin},
+    validation_webhook::ValidationWebhook,
+};
+
+// Rejected rows kept on the job; the count covers all of them
+const MAX_REPORTED_ERRORS: usize = 100;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportJobStatus {
+    Pending,
+    Running,
+    Succeeded,
+    CompletedWithErrors,
+    Cancelled,
+    Failed,
+}
+
+/// A CSV import running in the background. Shares its ID with the import's
+/// history record, so it can be rolled back like any other import.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ImportJob {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub user_id: ObjectId,
+    pub filename: Option<String>,
+    pub status: ImportJobStatus,
+    pub total_rows: i64,
+    pub processed_rows: i64,
+    pub imported: i64,
+    pub rejected: i64,
+    // The first rejected rows, like the 422 of a synchronous import
+    #[serde(default)]
+    pub errors: Vec<Document>,
+    // Move the products imported so far to the trash when cancelled
+    pub rollback_on_cancel: bool,
+    #[serde(default)]
+    pub cancel_requested: bool,
+    // Products moved to the trash after cancelling
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rolled_back: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub created_at: DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportJobResponse {
+    pub id: String,
+    pub filename: Option<String>,
+    pub status: ImportJobStatus,
+    pub total_rows: i64,
+    pub processed_rows: i64,
+    pub imported: i64,
+    pub rejected: i64,
+    pub errors: Vec<Document>,
+    pub rollback_on_cancel: bool,
+    pub cancel_requested: bool,
+    pub rolled_back: Option<i64>,
+    pub error: Option<String>,
+    pub created_at: String,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+}
+
+impl From<&ImportJob> for ImportJobResponse {
+    fn from(job: &ImportJob) -> Self {
+        ImportJobResponse {
+            id: job.id.to_string(),
+            filename: job.filename.clone(),
+            status: job.status,
+            total_rows: job.total_rows,
+            processed_rows: job.processed_rows,
+            imported: job.imported,
+            rejected: job.rejected,
+            errors: job.errors.clone(),
+            rollback_on_cancel: job.rollback_on_cancel,
+            cancel_requested: job.cancel_requested,
+            rolled_back: job.rolled_back,
+            error: job.error.clone(),
+            created_at: job.created_at.try_to_rfc3339_string().unwrap_or_default(),
+            started_at: job.started_at.and_then(|at| at.try_to_rfc3339_string().ok()),
+            finished_at: job.finished_at.and_then(|at| at.try_to_rfc3339_string().ok()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateImportJobQuery {
+    #[serde(default)]
+    rollback_on_cancel: bool,
+    // Taken from the file name when omitted, CSV if that says nothing
+    format: Option<FileFormat>,
+    decimal_separator: Option<DecimalSeparator>,
+    currency: Option<String>,
+}
+
+fn import_jobs_collection(db: &MongoConfig) -> Collection<ImportJob> {
+    db.database.collection("import_jobs")
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+fn parse_job_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| {
+        error!("Invalid import job ID format: {}", id);
+        ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format").into()
+    })
+}
+
+// Saves progress on the job and picks up cancellation requests, which may
+// have been made through another instance
+struct JobCheckpoint<'a> {
+    db: &'a MongoConfig,
+    job_id: ObjectId,
+}
+
+impl ImportCheckpoint for JobCheckpoint<'_> {
+    fn reached(&mut self, progress: ImportProgress) -> BoxFuture<'_, bool> {
+        Box::pin(async move {
+            let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+            let update = doc! { "$set": {
+                "processed_rows": progress.processed as i64,
+                "imported": progress.imported as i64,
+                "rejected": progress.rejected as i64,
+            } };
+            match import_jobs_collection(self.db)
+                .find_one_and_update(doc! { "_id": self.job_id }, update, options)
+                .await
+            {
+                Ok(job) => !job.is_some_and(|job| job.cancel_requested),
+                Err(e) => {
+                    warn!("Failed to save progress of import job {}: {}", self.job_id, e);
+                    true
+                }
+            }
+        })
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_import_job(
+    db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
+    batching: web::Data<BatchLimits>,
+    webhook: Option<web::Data<ValidationWebhook>>,
+    job: ImportJob,
+    import: ImportLog,
+    format: FileFormat,
+    rules: ImportRules,
+    data: Vec<u8>,
+) {
+    let jobs = import_jobs_collection(&db);
+    let started = jobs
+        .update_one(
+            doc! { "_id": job.id },
+            doc! { "$set": { "status": "running", "started_at": DateTime::now() } },
+            None,
+        )
+        .await;
+    if let Err(e) = started {
+        warn!("Failed to mark import job {} running: {}", job.id, e);
+    }
+
+    // The file was read once already to count its rows
+    let table = match format.reader().read(Box::new(data.as_slice())) {
+        Ok(table) => table,
+        Err(e) => {
+            warn!("Import job {} could not read its file: {}", job.id, e);
+            import.fail(&db, &e).await;
+            let failed = doc! { "$set": { "status": "failed", "error": e, "finished_at": DateTime::now() } };
+            if let Err(e) = jobs.update_one(doc! { "_id": job.id }, failed, None).await {
+                error!("Failed to record the outcome of import job {}: {}", job.id, e);
+            }
+            return;
+        }
+    };
+    let mut checkpoint = JobCheckpoint { db: &db, job_id: job.id };
+    let (imported, mut errors, completed) =
+        import_rows_until(&db, &events, &batching, job.id, table, &rules, webhook.as_ref().map(|webhook| webhook.get_ref()), Some(&mut checkpoint)).await;
+    let rejected = errors.len();
+    let processed = if completed { job.total_rows } else { (imported + rejected) as i64 };
+    errors.truncate(MAX_REPORTED_ERRORS);
+
+    let mut update = doc! {
+        "processed_rows": processed,
+        "imported": imported as i64,
+        "rejected": rejected as i64,
+        "errors": errors,
+        "finished_at": DateTime::now(),
+    };
+    if completed {
+        info!("Import job {} imported {} products, rejected {} rows", job.id, imported, rejected);
+        import.finish(&db, imported, rejected).await;
+        update.insert("status", if rejected == 0 { "succeeded" } else { "completed_with_errors" });
+    } else {
+        info!("Import job {} cancelled after importing {} products", job.id, imported);
+        import.cancel(&db, imported, rejected).await;
+        update.insert("status", "cancelled");
+        if job.rollback_on_cancel {
+            match import_history::roll_back(&db, &events, job.id, job.user_id).await {
+                Ok(removed) => {
+                    info!("Rolled back cancelled import job {}, {} products moved to the trash", job.id, removed);
+                    update.insert("rolled_back", removed);
+                }
+                Err(e) => {
+                    warn!("Failed to roll back cancelled import job {}: {}", job.id, e);
+                    update.insert("error", format!("Rollback failed, retry it through the import history: {}", e));
+                }
+            }
+        }
+    }
+    if let Err(e) = jobs.update_one(doc! { "_id": job.id }, doc! { "$set": update }, None).await {
+        error!("Failed to record the outcome of import job {}: {}", job.id, e);
+    }
+}
+
+/// Jobs that were pending or running when the server stopped never finish;
+/// marks them failed. Products they imported until then are kept.
+pub async fn fail_interrupted_jobs(db: &MongoConfig) -> Result<u64, mongodb::error::Error> {
+    let result = import_jobs_collection(db)
+        .update_many(
+            doc! { "status": { "$in": ["pending", "running"] } },
+            doc! { "$set": { "status": "failed", "error": "interrupted by a server restart", "finished_at": DateTime::now() } },
+            None,
+        )
+        .await?;
+    Ok(result.modified_count)
+}
+
+/// Starts importing an uploaded CSV file in the background and returns
+/// right away; poll the job for its progress.
+#[allow(clippy::too_many_arguments)]
+pub async fn create_import_job(
+    db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
+    batching: web::Data<BatchLimits>,
+    limits: web::Data<LimitsConfig>,
+    webhook: Option<web::Data<ValidationWebhook>>,
+    claims: web::ReqData<Claims>,
+    query: web::Query<CreateImportJobQuery>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    let user_id = claims.user_id()?;
+    let prices = PriceFormat { decimal_separator: query.decimal_separator, currency: query.currency.clone() };
+    prices.check().map_err(ApiError::bad_request)?;
+    let rules = ImportRules { prices, ..ImportRules::default() };
+
+    let mut upload = None;
+    while let Some(item) = payload.next().await {
+        let mut field = item.map_err(|e| {
+            error!("Error getting multipart field: {}", e);
+            ApiError::new(StatusCode::BAD_REQUEST, format!("Multipart error: {}", e))
+        })?;
+        if field.name() != "file" {
+            continue;
+        }
+
+        let filename = field.content_disposition().get_filename().map(str::to_string);
+        let mut data = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|e| {
+                error!("Error reading multipart chunk: {}", e);
+                ApiError::new(StatusCode::BAD_REQUEST, "Failed to read uploaded file")
+            })?;
+            if data.len() + chunk.len() > limits.upload_bytes {
+                debug!("Upload exceeded {} bytes, aborting", limits.upload_bytes);
+                return Err(payload_too_large(
+                    format!("Upload exceeds the limit of {} bytes", limits.upload_bytes),
+                    limits.upload_bytes,
+                ).into());
+            }
+            data.extend_from_slice(&chunk);
+        }
+        upload = Some((filename, data));
+        break;
+    }
+    let Some((filename, data)) = upload else {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "No file uploaded").into());
+    };
+
+    let format = query
+        .format
+        .or_else(|| filename.as_deref().and_then(FileFormat::from_file_name))
+        .unwrap_or(FileFormat::Csv);
+    let row_count = match format.reader().count_rows(Box::new(data.as_slice())).await {
+        Ok(row_count) => row_count,
+        Err(e) => {
+            debug!("Could not read uploaded file: {}", e);
+            return Err(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, format!("Could not read file: {}", e)).into());
+        }
+    };
+    if row_count > limits.csv_max_rows {
+        debug!("Upload has {} rows, limit is {}", row_count, limits.csv_max_rows);
+        return Err(payload_too_large(
+            format!("File has {} rows, exceeding the limit of {} rows", row_count, limits.csv_max_rows),
+            limits.csv_max_rows,
+        ).into());
+    }
+
+    let import = ImportLog::begin(ImportOrigin::Upload, Some(user_id)).filename(filename.clone());
+    let job = ImportJob {
+        id: import.id,
+        user_id,
+        filename,
+        status: ImportJobStatus::Pending,
+        total_rows: row_count as i64,
+        processed_rows: 0,
+        imported: 0,
+        rejected: 0,
+        errors: Vec::new(),
+        rollback_on_cancel: query.rollback_on_cancel,
+        cancel_requested: false,
+        rolled_back: None,
+        error: None,
+        created_at: DateTime::now(),
+        started_at: None,
+        finished_at: None,
+    };
+    import_jobs_collection(&db)
+        .insert_one(&job, None)
+        .await
+        .map_err(|e| db_error("Failed to create import job", e))?;
+
+    info!("User {} started import job {} with {} rows", user_id, job.id, row_count);
+    let response = ImportJobResponse::from(&job);
+    tokio::spawn(run_import_job(db, events, batching, webhook, job, import, format, rules, data));
+    Ok(HttpResponse::Accepted().json(response))
+}
+
+/// One of the caller's import jobs, with its progress.
+pub async fn get_import_job(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let job_id = parse_job_id(&id)?;
+    let job = import_jobs_collection(&db)
+        .find_one(doc! { "_id": job_id, "user_id": claims.user_id()? }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch import job", e))?;
+
+    match job {
+        Some(job) => Ok(HttpResponse::Ok().json(ImportJobResponse::from(&job))),
+        None => Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    }
+}
+
+/// Asks one of the caller's running import jobs to stop. The job stops at
+/// its next checkpoint, within `IMPORT_CHECKPOINT_ROWS` rows, and keeps or
+/// rolls back what it imported depending on `rollback_on_cancel`.
+pub async fn cancel_import_job(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let job_id = parse_job_id(&id)?;
+    let user_id = claims.user_id()?;
+    let jobs = import_jobs_collection(&db);
+
+    let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+    let cancelled = jobs
+        .find_one_and_update(
+            doc! { "_id": job_id, "user_id": user_id, "status": { "$in": ["pending", "running"] } },
+            doc! { "$set": { "cancel_requested": true } },
+            options,
+        )
+        .await
+        .map_err(|e| db_error("Failed to cancel import job", e))?;
+    if let Some(job) = cancelled {
+        info!("User {} cancelled import job {}", user_id, job_id);
+        return Ok(HttpResponse::Accepted().json(ImportJobResponse::from(&job)));
+    }
+
+    let exists = jobs
+        .count_documents(doc! { "_id": job_id, "user_id": user_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch import job", e))?;
+    if exists == 0 {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    }
+    Err(ApiError::new(StatusCode::CONFLICT, "Import job already finished").into())
+}
@@ -0,0 +1,332 @@
+use serde::{Deserialize, Serialize};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use crate::money::Decimal;
+
+const MAX_SYMBOLS: usize = 32;
+
+// Invisible characters that only get into names by accident: zero-width
+// space, direction marks and overrides, word joiners and the byte order mark.
+// Zero-width (non-)joiners are left alone; Persian and Indic text needs them
+fn is_denied(c: char) -> bool {
+    c.is_control()
+        || matches!(c, '\u{200B}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{2064}')
+        || matches!(c, '\u{2066}'..='\u{2069}' | '\u{FEFF}')
+}
+
+/// How product names are cleaned up on import. The defaults are the rules
+/// every import applies; import sources can relax them for their feed.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SanitizationRules {
+    // Read "Widget #A-1" as the name "Widget" followed by the ID "#A-1"
+    pub split_id_suffix: bool,
+    // "#(A-1)" becomes "#A-1"
+    pub strip_id_parentheses: bool,
+    // Keep only letters, digits, whitespace and name_symbols in names
+    pub filter_name_characters: bool,
+    pub name_symbols: String,
+    // Characters other than letters and digits kept in the ID suffix
+    pub id_symbols: String,
+}
+
+impl Default for SanitizationRules {
+    fn default() -> Self {
+        SanitizationRules {
+            split_id_suffix: true,
+            strip_id_parentheses: true,
+            filter_name_characters: false,
+            name_symbols: "-".to_string(),
+            id_symbols: "-_".to_string(),
+        }
+    }
+}
+
+impl SanitizationRules {
+    pub fn check(&self) -> Result<(), String> {
+        for (field, symbols) in [("name_symbols", &self.name_symbols), ("id_symbols", &self.id_symbols)] {
+            if symbols.chars().count() > MAX_SYMBOLS {
+                return Err(format!("{} must be at most {} characters", field, MAX_SYMBOLS));
+            }
+            // Letters, digits and whitespace are always kept
+            if let Some(c) = symbols.chars().find(|c| c.is_alphanumeric() || c.is_whitespace() || c.is_control()) {
+                return Err(format!("{} may only list symbols, got {:?}", field, c));
+            }
+        }
+        Ok(())
+    }
+
+    fn clean_id(&self, id: &str) -> String {
+        let id = if self.strip_id_parentheses {
+            id.trim_start_matches('(').trim_end_matches(')')
+        } else {
+            id
+        };
+        let id: String = id
+            .chars()
+            .filter(|c| c.is_alphanumeric() || self.id_symbols.contains(*c))
+            .collect();
+        if id.is_empty() {
+            String::new()
+        } else {
+            format!("#{}", id)
+        }
+    }
+
+    /// The product name for the name column of an import row; empty when
+    /// nothing is left of it. Names are NFC normalized, so "é" is one
+    /// character however the file spelled it, and runs of whitespace become
+    /// one space.
+    pub fn clean_name(&self, raw_name: &str) -> String {
+        let raw_name: String = raw_name.nfc().collect();
+        let (name, id) = match raw_name.split_once('#').filter(|_| self.split_id_suffix) {
+            Some((name, id)) => (name.trim(), self.clean_id(id.trim())),
+            None => (raw_name.as_str(), String::new()),
+        };
+
+        // Marks that have no precomposed form stay with their letter
+        let kept = |c: &char| {
+            if self.filter_name_characters {
+                c.is_alphanumeric() || is_combining_mark(*c) || c.is_whitespace() || self.name_symbols.contains(*c)
+            } else {
+                c.is_whitespace() || !is_denied(*c)
+            }
+        };
+        let name = name.chars().filter(kept).collect::<String>();
+        let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        if name.is_empty() || id.is_empty() {
+            name
+        } else {
+            format!("{} {}", name, id)
+        }
+    }
+}
+
+// Currency symbols and the ISO codes they may stand for
+const CURRENCY_SYMBOLS: [(&str, &[&str]); 10] = [
+    ("R$", &["BRL"]),
+    ("$", &["USD", "CAD", "AUD", "NZD", "MXN", "SGD", "HKD"]),
+    ("€", &["EUR"]),
+    ("£", &["GBP"]),
+    ("¥", &["JPY", "CNY"]),
+    ("₹", &["INR"]),
+    ("₩", &["KRW"]),
+    ("₽", &["RUB"]),
+    ("zł", &["PLN"]),
+    ("kr", &["SEK", "NOK", "DKK", "ISK"]),
+];
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DecimalSeparator {
+    // 1,299.90
+    Point,
+    // 1.299,90
+    Comma,
+}
+
+impl DecimalSeparator {
+    fn char(self) -> char {
+        match self {
+            DecimalSeparator::Point => '.',
+            DecimalSeparator::Comma => ',',
+        }
+    }
+}
+
+/// How the prices of an import are written: the locale's decimal separator
+/// and the currency they are in. Without hints, prices use a decimal point
+/// unless they show otherwise, and any currency is accepted.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PriceFormat {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decimal_separator: Option<DecimalSeparator>,
+    // ISO 4217 code, e.g. "EUR"; prices marked with another currency are rejected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+}
+
+// Splits "1.299,90 €" into the number and the currency marker around it
+fn split_currency(price: &str) -> Result<(&str, Option<&str>), String> {
+    let is_number = |c: char| c.is_ascii_digit() || matches!(c, '.' | ',' | '\'' | '-' | '+');
+    let start = price.find(is_number).ok_or_else(|| "no amount".to_string())?;
+    let end = price.rfind(is_number).map_or(start, |i| i + 1);
+    let (prefix, suffix) = (price[..start].trim(), price[end..].trim());
+    match (prefix.is_empty(), suffix.is_empty()) {
+        (true, true) => Ok((&price[start..end], None)),
+        (false, true) => Ok((&price[start..end], Some(prefix))),
+        (true, false) => Ok((&price[start..end], Some(suffix))),
+        (false, false) => Err("unexpected text on both sides of the amount".to_string()),
+    }
+}
+
+fn check_currency(marker: &str, expected: Option<&str>) -> Result<(), String> {
+    let codes: Vec<&str> = match CURRENCY_SYMBOLS.iter().find(|(symbol, _)| symbol.eq_ignore_ascii_case(marker)) {
+        Some((_, codes)) => codes.to_vec(),
+        None if marker.len() == 3 && marker.chars().all(|c| c.is_ascii_alphabetic()) => vec![marker],
+        None => return Err(format!("unknown currency '{}'", marker)),
+    };
+    match expected {
+        Some(expected) if !codes.iter().any(|code| code.eq_ignore_ascii_case(expected)) => {
+            Err(format!("price is in {} but the import is in {}", marker, expected))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Digits grouped in thousands: 1 to 3 digits, then groups of exactly 3
+fn ungroup(integer: &str, grouping: &[char]) -> Option<String> {
+    let mut groups = integer.split(|c| grouping.contains(&c));
+    let first = groups.next()?;
+    if first.is_empty() || (first.len() > 3 && integer.contains(grouping)) {
+        return None;
+    }
+    let mut digits = first.to_string();
+    for group in groups {
+        if group.len() != 3 {
+            return None;
+        }
+        digits.push_str(group);
+    }
+    digits.chars().all(|c| c.is_ascii_digit()).then_some(digits)
+}
+
+impl PriceFormat {
+    pub fn check(&self) -> Result<(), String> {
+        match &self.currency {
+            Some(code) if code.len() != 3 || !code.chars().all(|c| c.is_ascii_uppercase()) => {
+                Err(format!("currency must be an ISO 4217 code such as EUR, got '{}'", code))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The decimal separator of an amount, when it can be told
+    fn decimal_separator(&self, amount: &str) -> Result<Option<char>, String> {
+        if let Some(separator) = self.decimal_separator {
+            return Ok(Some(separator.char()).filter(|&c| amount.contains(c)));
+        }
+        // With both, the last one separates the decimals: 1.299,90 or 1,299.90
+        let last = amount.rfind(['.', ',']);
+        let Some(last) = last else {
+            return Ok(None);
+        };
+        let separator = amount[last..].chars().next().expect("found above");
+        let other = if separator == '.' { ',' } else { '.' };
+        if amount.contains(other) {
+            return Ok(Some(separator));
+        }
+        if amount.matches(separator).count() > 1 {
+            // 1.299.000 only groups thousands
+            return Ok(None);
+        }
+        // A lone comma before three digits may group thousands or separate
+        // decimals. A lone point always separates decimals, as it always has
+        let decimals = &amount[last + 1..];
+        if separator == ',' && decimals.len() == 3 && !amount.starts_with("0,") {
+            return Err("ambiguous, set decimal_separator to say whether ',' separates thousands or decimals".to_string());
+        }
+        Ok(Some(separator))
+    }
+
+    /// Parses a price as written in an import file, e.g. "$1,299.90",
+    /// "1.299,90 €" or "CHF 1'299.90". The result may be negative.
+    pub fn parse(&self, price: &str) -> Result<Decimal, String> {
+        // Spaces, including non-breaking and thin ones, group thousands in some locales
+        let cleaned: String = price
+            .chars()
+            .filter(|&c| !c.is_whitespace() && !is_denied(c) && !matches!(c, '\u{202F}' | '\u{00A0}'))
+            .collect();
+        let (amount, marker) = split_currency(&cleaned)?;
+        if let Some(marker) = marker {
+            check_currency(marker, self.currency.as_deref())?;
+        }
+
+        let (sign, unsigned) = match amount.strip_prefix('-') {
+            Some(rest) => ("-", rest),
+            None => ("", amount.strip_prefix('+').unwrap_or(amount)),
+        };
+        let separator = self.decimal_separator(unsigned)?;
+        let (integer, fraction) = match separator {
+            Some(separator) => match unsigned.rsplit_once(separator) {
+                Some((integer, fraction)) => (integer, Some(fraction)),
+                None => (unsigned, None),
+            },
+            None => (unsigned, None),
+        };
+        let grouping: Vec<char> = ['.', ',', '\''].into_iter().filter(|&c| Some(c) != separator).collect();
+        let invalid = || "not a number".to_string();
+        let integer = if integer.is_empty() && fraction.is_some() {
+            "0".to_string()
+        } else {
+            ungroup(integer, &grouping).ok_or_else(invalid)?
+        };
+        let normalized = match fraction {
+            Some(fraction) if !fraction.is_empty() && fraction.chars().all(|c| c.is_ascii_digit()) => {
+                format!("{}{}.{}", sign, integer, fraction)
+            }
+            Some(_) => return Err(invalid()),
+            None => format!("{}{}", sign, integer),
+        };
+        normalized.parse::<Decimal>().map_err(|e| format!("{}: {}", invalid(), e))
+    }
+}
+
+/// Everything an import can be told about its file beyond the format.
+#[derive(Debug, Clone, Default)]
+pub struct ImportRules {
+    pub sanitization: SanitizationRules,
+    pub prices: PriceFormat,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clean(raw_name: &str) -> String {
+        SanitizationRules::default().clean_name(raw_name)
+    }
+
+    #[test]
+    fn names_are_nfc_normalized() {
+        let name = clean("Cafe\u{301} Noir");
+        assert_eq!(name, "Café Noir");
+        assert_eq!(name.chars().count(), 9);
+    }
+
+    #[test]
+    fn letters_outside_ascii_are_kept() {
+        assert_eq!(clean("Straße Karte"), "Straße Karte");
+        assert_eq!(clean("緑茶 ティーバッグ"), "緑茶 ティーバッグ");
+        assert_eq!(clean("Ørsted & Co. (2-pack)"), "Ørsted & Co. (2-pack)");
+    }
+
+    #[test]
+    fn invisible_characters_are_dropped() {
+        assert_eq!(clean("Zero\u{200B}Width\u{FEFF}"), "ZeroWidth");
+        assert_eq!(clean("\u{202E}Reversed\u{202C} \u{2067}Isolated\u{2069}"), "Reversed Isolated");
+        assert_eq!(clean("Tab\tand\u{0007}bell"), "Tab andbell");
+    }
+
+    #[test]
+    fn zero_width_joiners_are_kept() {
+        assert_eq!(clean("می\u{200C}خواهم"), "می\u{200C}خواهم");
+    }
+
+    #[test]
+    fn id_suffix_is_split_off_and_cleaned() {
+        assert_eq!(clean("Widget  #(A-1)"), "Widget #A-1");
+        assert_eq!(clean("   "), "");
+    }
+
+    #[test]
+    fn filtering_keeps_letters_digits_and_combining_marks() {
+        let rules = SanitizationRules { filter_name_characters: true, ..SanitizationRules::default() };
+        assert_eq!(rules.clean_name("Deluxe™ Widget-2000!"), "Deluxe Widget-2000");
+        // No precomposed form exists for g with a tilde
+        assert_eq!(rules.clean_name("Ag\u{303}ua"), "Ag\u{303}ua");
+        assert_eq!(rules.clean_name("Straße"), "Straße");
+    }
+}
@@ -0,0 +1,594 @@
+use std::str::FromStr;
+
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use chrono::Utc;
+use cron::Schedule;
+use futures::{StreamExt, TryStreamExt};
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument},
+    Collection,
+};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+use validator::Validate;
+
+use crate::{
+    config::{LimitsConfig, MongoConfig},
+    errors::ApiError,
+    events::EventHub,
+    handlers::import_rows_until,
+    import_batches::BatchLimits,
+    import_formats::{FileFormat, RawRecord, RawTable},
+    import_history::{ImportLog, ImportOrigin},
+    import_rules::{ImportRules, PriceFormat, SanitizationRules},
+    imports::UrlFetcher,
+    validation::validation_error,
+    validation_webhook::ValidationWebhook,
+};
+
+// Rejected rows kept on a run; the counts always cover every row
+const MAX_STORED_ERRORS: usize = 100;
+const RUN_HISTORY_LIMIT: i64 = 50;
+
+/// Source column names for each product field. Unmapped fields are looked up
+/// by their own name, and a missing category or sale column is left empty.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ColumnMapping {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub has_active_sale: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ImportSource {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub name: String,
+    pub url: String,
+    pub format: Option<FileFormat>,
+    // Without a mapping, columns are read by position like CSV uploads
+    pub column_mapping: Option<ColumnMapping>,
+    #[serde(default)]
+    pub sanitization: SanitizationRules,
+    #[serde(default)]
+    pub price_format: PriceFormat,
+    pub schedule: String,
+    pub enabled: bool,
+    pub next_run_at: Option<DateTime>,
+    pub last_run_at: Option<DateTime>,
+    pub last_status: Option<RunStatus>,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Succeeded,
+    CompletedWithErrors,
+    Failed,
+    // Stopped on request part way through
+    Cancelled,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ImportRun {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub source_id: ObjectId,
+    pub started_at: DateTime,
+    pub finished_at: DateTime,
+    pub status: RunStatus,
+    pub imported: i64,
+    pub error_count: i64,
+    pub errors: Vec<Document>,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ImportSourceRequest {
+    #[validate(length(min = 1, max = 100, message = "name must be between 1 and 100 characters"))]
+    pub name: String,
+    pub url: String,
+    pub format: Option<FileFormat>,
+    pub column_mapping: Option<ColumnMapping>,
+    #[serde(default)]
+    pub sanitization: SanitizationRules,
+    #[serde(default)]
+    pub price_format: PriceFormat,
+    pub schedule: String,
+    pub enabled: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportSourceResponse {
+    pub id: String,
+    pub name: String,
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<FileFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column_mapping: Option<ColumnMapping>,
+    pub sanitization: SanitizationRules,
+    pub price_format: PriceFormat,
+    pub schedule: String,
+    pub enabled: bool,
+    pub next_run_at: Option<String>,
+    pub last_run_at: Option<String>,
+    pub last_status: Option<RunStatus>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl From<&ImportSource> for ImportSourceResponse {
+    fn from(source: &ImportSource) -> Self {
+        ImportSourceResponse {
+            id: source.id.map(|id| id.to_string()).unwrap_or_default(),
+            name: source.name.clone(),
+            url: source.url.clone(),
+            format: source.format,
+            column_mapping: source.column_mapping.clone(),
+            sanitization: source.sanitization.clone(),
+            price_format: source.price_format.clone(),
+            schedule: source.schedule.clone(),
+            enabled: source.enabled,
+            next_run_at: source.next_run_at.and_then(|t| t.try_to_rfc3339_string().ok()),
+            last_run_at: source.last_run_at.and_then(|t| t.try_to_rfc3339_string().ok()),
+            last_status: source.last_status,
+            created_at: source.created_at.try_to_rfc3339_string().unwrap_or_default(),
+            updated_at: source.updated_at.try_to_rfc3339_string().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportRunResponse {
+    pub id: String,
+    pub source_id: String,
+    pub started_at: String,
+    pub finished_at: String,
+    pub status: RunStatus,
+    pub imported: i64,
+    pub error_count: i64,
+    pub errors: Vec<Document>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl From<&ImportRun> for ImportRunResponse {
+    fn from(run: &ImportRun) -> Self {
+        ImportRunResponse {
+            id: run.id.map(|id| id.to_string()).unwrap_or_default(),
+            source_id: run.source_id.to_string(),
+            started_at: run.started_at.try_to_rfc3339_string().unwrap_or_default(),
+            finished_at: run.finished_at.try_to_rfc3339_string().unwrap_or_default(),
+            status: run.status,
+            imported: run.imported,
+            error_count: run.error_count,
+            errors: run.errors.clone(),
+            message: run.message.clone(),
+        }
+    }
+}
+
+pub fn sources_collection(db: &MongoConfig) -> Collection<ImportSource> {
+    db.database.collection("import_sources")
+}
+
+pub fn runs_collection(db: &MongoConfig) -> Collection<ImportRun> {
+    db.database.collection("import_runs")
+}
+
+fn parse_source_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| {
+        error!("Invalid import source ID format: {}", id);
+        ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format").into()
+    })
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+fn bad_request(message: String) -> Error {
+    ApiError::new(StatusCode::BAD_REQUEST, message).into()
+}
+
+/// Parses a cron expression. Standard five-field expressions are accepted and
+/// run at second 0; six and seven fields add seconds and years.
+pub fn parse_schedule(expression: &str) -> Result<Schedule, String> {
+    let expression = expression.trim();
+    let expression = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression)
+    } else {
+        expression.to_string()
+    };
+    Schedule::from_str(&expression).map_err(|e| format!("Invalid schedule: {}", e))
+}
+
+fn next_run(schedule: &Schedule) -> Option<DateTime> {
+    schedule.upcoming(Utc).next().map(|t| DateTime::from_millis(t.timestamp_millis()))
+}
+
+// Checks a create/update request and returns its parsed schedule
+fn check_request(request: &ImportSourceRequest) -> Result<Schedule, Error> {
+    request.validate().map_err(validation_error)?;
+
+    match Url::parse(&request.url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+        Ok(_) => return Err(bad_request("url must be an http or https URL".to_string())),
+        Err(e) => return Err(bad_request(format!("Invalid URL: {}", e))),
+    }
+
+    request.sanitization.check().map_err(bad_request)?;
+    request.price_format.check().map_err(bad_request)?;
+    parse_schedule(&request.schedule).map_err(bad_request)
+}
+
+// Puts the mapped columns first, in the order the import expects. The file's
+// own columns follow, so optional columns such as sku are still found by name
+fn apply_column_mapping<'a>(table: RawTable<'a>, mapping: &ColumnMapping) -> Result<RawTable<'a>, String> {
+    let fields = [
+        ("name", &mapping.name, true),
+        ("price", &mapping.price, true),
+        ("category", &mapping.category, false),
+        ("has_active_sale", &mapping.has_active_sale, false),
+    ];
+    let mut columns = Vec::with_capacity(fields.len());
+    for (field, mapped, required) in fields {
+        let wanted = mapped.as_deref().unwrap_or(field);
+        let position = table.headers.iter().position(|h| h.eq_ignore_ascii_case(wanted));
+        if position.is_none() && (required || mapped.is_some()) {
+            return Err(format!("Column '{}' for {} not found", wanted, field));
+        }
+        columns.push(position);
+    }
+
+    let mut headers: Vec<String> = fields.iter().map(|(field, ..)| field.to_string()).collect();
+    headers.extend(table.headers);
+    let rows = table.rows.map(move |result| {
+        result.map(|record| {
+            let mut values: Vec<String> =
+                columns.iter().map(|c| c.and_then(|i| record.get(i)).unwrap_or("").to_string()).collect();
+            values.extend(record.values);
+            RawRecord { line: record.line, values }
+        })
+    });
+    Ok(RawTable { headers, rows: rows.boxed() })
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn import_feed(
+    db: &MongoConfig,
+    events: &EventHub,
+    batching: &BatchLimits,
+    fetcher: &UrlFetcher,
+    limits: &LimitsConfig,
+    webhook: Option<&ValidationWebhook>,
+    source: &ImportSource,
+    import_id: ObjectId,
+) -> Result<(usize, Vec<Document>), String> {
+    let (format, data) = fetcher
+        .fetch(&source.url, source.format, limits.upload_bytes)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let row_count = format.reader().count_rows(Box::new(data.as_slice())).await?;
+    if row_count > limits.csv_max_rows {
+        return Err(format!("File has {} rows, exceeding the limit of {} rows", row_count, limits.csv_max_rows));
+    }
+
+    let table = format.reader().read(Box::new(data.as_slice()))?;
+    let table = match &source.column_mapping {
+        Some(mapping) => apply_column_mapping(table, mapping)?,
+        None => table,
+    };
+    let rules = ImportRules { sanitization: source.sanitization.clone(), prices: source.price_format.clone() };
+    let (imported, errors, _) = import_rows_until(db, events, batching, import_id, table, &rules, webhook, None).await;
+    Ok((imported, errors))
+}
+
+/// Fetches and imports one source, recording the run and the source's last status.
+pub async fn run_import_source(
+    db: &MongoConfig,
+    events: &EventHub,
+    batching: &BatchLimits,
+    fetcher: &UrlFetcher,
+    limits: &LimitsConfig,
+    webhook: Option<&ValidationWebhook>,
+    source: &ImportSource,
+) -> Result<ImportRun, String> {
+    let source_id = source.id.ok_or("Import source has no ID")?;
+    let started_at = DateTime::now();
+    let import = ImportLog::begin(ImportOrigin::ImportSource, None).url(&source.url).import_source(source_id);
+
+    let (status, imported, mut errors, message) = match import_feed(db, events, batching, fetcher, limits, webhook, source, import.id).await {
+        Ok((imported, errors)) => {
+            import.finish(db, imported, errors.len()).await;
+            let status = if errors.is_empty() { RunStatus::Succeeded } else { RunStatus::CompletedWithErrors };
+            (status, imported, errors, None)
+        }
+        Err(message) => {
+            warn!("Import source {} failed: {}", source.name, message);
+            import.fail(db, &message).await;
+            (RunStatus::Failed, 0, Vec::new(), Some(message))
+        }
+    };
+    let error_count = errors.len() as i64;
+    errors.truncate(MAX_STORED_ERRORS);
+
+    let mut run = ImportRun {
+        id: None,
+        source_id,
+        started_at,
+        finished_at: DateTime::now(),
+        status,
+        imported: imported as i64,
+        error_count,
+        errors,
+        message,
+    };
+
+    let result = runs_collection(db)
+        .insert_one(&run, None)
+        .await
+        .map_err(|e| format!("Database error: {}", e))?;
+    run.id = result.inserted_id.as_object_id();
+
+    sources_collection(db)
+        .update_one(
+            doc! { "_id": source_id },
+            doc! { "$set": {
+                "last_run_at": run.started_at,
+                "last_status": mongodb::bson::to_bson(&run.status).map_err(|e| e.to_string())?,
+            } },
+            None,
+        )
+        .await
+        .map_err(|e| format!("Database error: {}", e))?;
+
+    info!("Import source {} imported {} products ({} errors)", source.name, run.imported, run.error_count);
+    Ok(run)
+}
+
+/// Runs every enabled source whose next run is due. Each source is claimed by
+/// moving its next_run_at forward first, so a run is never started twice.
+pub async fn run_due_import_sources(
+    db: &MongoConfig,
+    events: &EventHub,
+    batching: &BatchLimits,
+    fetcher: &UrlFetcher,
+    limits: &LimitsConfig,
+    webhook: Option<&ValidationWebhook>,
+) -> Result<String, String> {
+    let now = DateTime::now();
+    let due: Vec<ImportSource> = sources_collection(db)
+        .find(doc! { "enabled": true, "next_run_at": { "$lte": now } }, None)
+        .await
+        .map_err(|e| format!("Database error: {}", e))?
+        .try_collect()
+        .await
+        .map_err(|e| format!("Database error: {}", e))?;
+
+    let mut ran = 0;
+    let mut failed = 0;
+    for source in due {
+        let Some(source_id) = source.id else { continue };
+        let next_run_at = parse_schedule(&source.schedule).ok().and_then(|s| next_run(&s));
+
+        let claimed = sources_collection(db)
+            .update_one(
+                doc! { "_id": source_id, "next_run_at": source.next_run_at },
+                doc! { "$set": { "next_run_at": next_run_at } },
+                None,
+            )
+            .await
+            .map_err(|e| format!("Database error: {}", e))?;
+        if claimed.modified_count == 0 {
+            debug!("Import source {} was claimed elsewhere", source.name);
+            continue;
+        }
+
+        match run_import_source(db, events, batching, fetcher, limits, webhook, &source).await {
+            Ok(run) if run.status == RunStatus::Failed => failed += 1,
+            Ok(_) => {}
+            Err(e) => {
+                error!("Failed to record run of import source {}: {}", source.name, e);
+                failed += 1;
+            }
+        }
+        ran += 1;
+    }
+
+    Ok(format!("Ran {} import sources ({} failed)", ran, failed))
+}
+
+pub async fn create_import_source(
+    db: web::Data<MongoConfig>,
+    request: web::Json<ImportSourceRequest>,
+) -> Result<HttpResponse, Error> {
+    let schedule = check_request(&request)?;
+
+    let now = DateTime::now();
+    let request = request.into_inner();
+    let mut source = ImportSource {
+        id: None,
+        name: request.name,
+        url: request.url,
+        format: request.format,
+        column_mapping: request.column_mapping,
+        sanitization: request.sanitization,
+        price_format: request.price_format,
+        schedule: request.schedule,
+        enabled: request.enabled.unwrap_or(true),
+        next_run_at: next_run(&schedule),
+        last_run_at: None,
+        last_status: None,
+        created_at: now,
+        updated_at: now,
+    };
+
+    let result = sources_collection(&db)
+        .insert_one(&source, None)
+        .await
+        .map_err(|e| db_error("Failed to create import source", e))?;
+    source.id = result.inserted_id.as_object_id();
+
+    info!("Import source created with ID: {}", result.inserted_id);
+    Ok(HttpResponse::Created().json(ImportSourceResponse::from(&source)))
+}
+
+pub async fn list_import_sources(db: web::Data<MongoConfig>) -> Result<HttpResponse, Error> {
+    let options = FindOptions::builder().sort(doc! { "name": 1 }).build();
+    let sources: Vec<ImportSource> = sources_collection(&db)
+        .find(None, options)
+        .await
+        .map_err(|e| db_error("Failed to fetch import sources", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating import sources", e))?;
+
+    let sources: Vec<ImportSourceResponse> = sources.iter().map(ImportSourceResponse::from).collect();
+    Ok(HttpResponse::Ok().json(sources))
+}
+
+pub async fn get_import_source(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let source_id = parse_source_id(&id)?;
+
+    let source = sources_collection(&db)
+        .find_one(doc! { "_id": source_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch import source", e))?;
+
+    match source {
+        Some(source) => Ok(HttpResponse::Ok().json(ImportSourceResponse::from(&source))),
+        None => Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    }
+}
+
+pub async fn update_import_source(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+    request: web::Json<ImportSourceRequest>,
+) -> Result<HttpResponse, Error> {
+    let source_id = parse_source_id(&id)?;
+    let schedule = check_request(&request)?;
+
+    let request = request.into_inner();
+    let mut update = doc! {
+        "name": request.name,
+        "url": request.url,
+        "format": mongodb::bson::to_bson(&request.format).map_err(ApiError::internal)?,
+        "column_mapping": mongodb::bson::to_bson(&request.column_mapping).map_err(ApiError::internal)?,
+        "sanitization": mongodb::bson::to_bson(&request.sanitization).map_err(ApiError::internal)?,
+        "price_format": mongodb::bson::to_bson(&request.price_format).map_err(ApiError::internal)?,
+        "schedule": request.schedule,
+        "next_run_at": next_run(&schedule),
+        "updated_at": DateTime::now(),
+    };
+    if let Some(enabled) = request.enabled {
+        update.insert("enabled", enabled);
+    }
+
+    let source = sources_collection(&db)
+        .find_one_and_update(
+            doc! { "_id": source_id },
+            doc! { "$set": update },
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await
+        .map_err(|e| db_error("Failed to update import source", e))?;
+
+    match source {
+        Some(source) => Ok(HttpResponse::Ok().json(ImportSourceResponse::from(&source))),
+        None => Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    }
+}
+
+pub async fn delete_import_source(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let source_id = parse_source_id(&id)?;
+
+    let result = sources_collection(&db)
+        .delete_one(doc! { "_id": source_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to delete import source", e))?;
+    if result.deleted_count == 0 {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    }
+
+    if let Err(e) = runs_collection(&db).delete_many(doc! { "source_id": source_id }, None).await {
+        warn!("Failed to delete runs of import source {}: {}", source_id, e);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// The most recent runs of a source, newest first.
+pub async fn list_import_runs(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let source_id = parse_source_id(&id)?;
+
+    let options = FindOptions::builder()
+        .sort(doc! { "started_at": -1 })
+        .limit(RUN_HISTORY_LIMIT)
+        .build();
+    let runs: Vec<ImportRun> = runs_collection(&db)
+        .find(doc! { "source_id": source_id }, options)
+        .await
+        .map_err(|e| db_error("Failed to fetch import runs", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating import runs", e))?;
+
+    let runs: Vec<ImportRunResponse> = runs.iter().map(ImportRunResponse::from).collect();
+    Ok(HttpResponse::Ok().json(runs))
+}
+
+/// Runs a source immediately, outside its schedule.
+pub async fn trigger_import_source(
+    db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
+    batching: web::Data<BatchLimits>,
+    fetcher: web::Data<UrlFetcher>,
+    limits: web::Data<LimitsConfig>,
+    webhook: Option<web::Data<ValidationWebhook>>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let source_id = parse_source_id(&id)?;
+
+    let source = sources_collection(&db)
+        .find_one(doc! { "_id": source_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch import source", e))?;
+    let Some(source) = source else {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    };
+
+    let webhook = webhook.as_ref().map(|webhook| webhook.get_ref());
+    let run = run_import_source(&db, &events, &batching, &fetcher, &limits, webhook, &source)
+        .await
+        .map_err(|e| {
+            error!("Failed to run import source {}: {}", source_id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e)
+        })?;
+    Ok(HttpResponse::Ok().json(ImportRunResponse::from(&run)))
+}
@@ -0,0 +1,313 @@
+use std::{
+    error::Error as StdError,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use mongodb::bson::doc;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    header,
+    redirect::Policy,
+    Client, Response, Url,
+};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::{
+    auth::Claims,
+    config::{ImportConfig, LimitsConfig, MongoConfig},
+    errors::ApiError,
+    events::EventHub,
+    handlers::{import_records, import_report, payload_too_large},
+    import_formats::FileFormat,
+    import_rules::{DecimalSeparator, ImportRules, PriceFormat},
+    import_batches::BatchLimits,
+    import_history::{ImportLog, ImportOrigin},
+    validation_webhook::ValidationWebhook,
+};
+
+#[derive(Debug)]
+pub enum FetchError {
+    InvalidUrl(String),
+    // The URL points at loopback, private or otherwise internal addresses
+    Blocked(String),
+    TooLarge(usize),
+    UnsupportedType(String),
+    InvalidFile(String),
+    Timeout,
+    Upstream(String),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::InvalidUrl(reason) => write!(f, "Invalid URL: {}", reason),
+            FetchError::Blocked(host) => write!(f, "URL host {} is not allowed", host),
+            FetchError::TooLarge(limit) => write!(f, "File exceeds the limit of {} bytes", limit),
+            FetchError::UnsupportedType(content_type) => write!(f, "Unsupported content type: {}", content_type),
+            FetchError::InvalidFile(reason) => write!(f, "Could not read file: {}", reason),
+            FetchError::Timeout => write!(f, "Timed out fetching file"),
+            FetchError::Upstream(reason) => write!(f, "Failed to fetch file: {}", reason),
+        }
+    }
+}
+
+impl FetchError {
+    pub fn to_problem(&self) -> ApiError {
+        let status = match self {
+            FetchError::InvalidUrl(_) | FetchError::Blocked(_) => StatusCode::BAD_REQUEST,
+            FetchError::TooLarge(limit) => return payload_too_large(self.to_string(), *limit),
+            FetchError::UnsupportedType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            FetchError::InvalidFile(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            FetchError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            FetchError::Upstream(_) => StatusCode::BAD_GATEWAY,
+        };
+        ApiError::new(status, self.to_string())
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            let this_network = a == 0; // 0.0.0.0/8
+            let shared = a == 100 && (b & 0xc0) == 64; // 100.64.0.0/10
+            let benchmarking = a == 198 && (b & 0xfe) == 18; // 198.18.0.0/15
+            let reserved = a >= 240; // 240.0.0.0/4, including broadcast
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_documentation()
+                || this_network
+                || shared
+                || benchmarking
+                || reserved)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let segments = ip.segments();
+                let unique_local = (segments[0] & 0xfe00) == 0xfc00;
+                let link_local = (segments[0] & 0xffc0) == 0xfe80;
+                // NAT64 and 6to4 both embed an IPv4 address that may be internal
+                let nat64 = segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]; // 64:ff9b::/96
+                let six_to_four = segments[0] == 0x2002; // 2002::/16
+                !(ip.is_loopback() || ip.is_unspecified() || unique_local || link_local || nat64 || six_to_four)
+            }
+        },
+    }
+}
+
+// Raised by the resolver so the fetch can report a refused host rather than a
+// connection failure
+#[derive(Debug)]
+struct BlockedHost(String);
+
+impl fmt::Display for BlockedHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} only resolves to internal addresses", self.0)
+    }
+}
+
+impl StdError for BlockedHost {}
+
+/// Resolves hosts for the fetcher and drops every address that isn't public.
+/// The client connects to what this returns, so a host can't pass the check
+/// and then rebind to an internal address before the connection is made.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            let public: Vec<SocketAddr> = addresses.into_iter().filter(|addr| is_public(addr.ip())).collect();
+            if public.is_empty() {
+                warn!("Refusing to fetch import file from {}", host);
+                return Err(Box::new(BlockedHost(host)) as Box<dyn StdError + Send + Sync>);
+            }
+            Ok(Box::new(public.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Downloads import files from supplier URLs, refusing internal hosts and
+/// enforcing the upload size limit and a fetch timeout.
+pub struct UrlFetcher {
+    http: Client,
+    config: ImportConfig,
+}
+
+impl UrlFetcher {
+    pub fn new(config: ImportConfig) -> Self {
+        let mut builder = Client::builder()
+            .timeout(Duration::from_secs(config.fetch_timeout_secs))
+            // Redirects are followed by hand so every hop gets the scheme check
+            .redirect(Policy::none());
+        if !config.allow_private_urls {
+            builder = builder.dns_resolver(Arc::new(PublicResolver));
+        }
+        let http = builder.build().expect("Failed to build HTTP client");
+        UrlFetcher { http, config }
+    }
+
+    // Host names are checked by `PublicResolver` as the connection is made;
+    // only literal addresses, which skip resolution, are checked here
+    fn check_destination(&self, url: &Url) -> Result<(), FetchError> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(FetchError::InvalidUrl("only http and https URLs are supported".to_string()));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| FetchError::InvalidUrl("missing host".to_string()))?;
+        let bare_host = host.trim_start_matches('[').trim_end_matches(']');
+        match bare_host.parse::<IpAddr>() {
+            Ok(ip) if !self.config.allow_private_urls && !is_public(ip) => {
+                warn!("Refusing to fetch import file from {}", host);
+                Err(FetchError::Blocked(host.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn read_body(response: Response, max_bytes: usize) -> Result<Vec<u8>, FetchError> {
+        if response.content_length().is_some_and(|l| l as usize > max_bytes) {
+            return Err(FetchError::TooLarge(max_bytes));
+        }
+
+        let mut response = response;
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(request_error)? {
+            if body.len() + chunk.len() > max_bytes {
+                debug!("Remote file exceeded {} bytes, aborting", max_bytes);
+                return Err(FetchError::TooLarge(max_bytes));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body)
+    }
+
+    /// Fetches the file at `url` and returns it with its format.
+    pub async fn fetch(
+        &self,
+        url: &str,
+        requested: Option<FileFormat>,
+        max_bytes: usize,
+    ) -> Result<(FileFormat, Vec<u8>), FetchError> {
+        let mut url = Url::parse(url).map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+
+        for _ in 0..=self.config.max_redirects {
+            self.check_destination(&url)?;
+            let response = self.http.get(url.clone()).send().await.map_err(request_error)?;
+
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(header::LOCATION)
+                    .and_then(|v| v.to_str().ok())
+                    .ok_or_else(|| FetchError::Upstream("redirect without a location".to_string()))?;
+                url = url.join(location).map_err(|e| FetchError::InvalidUrl(e.to_string()))?;
+                debug!("Following redirect to {}", url);
+                continue;
+            }
+            if !response.status().is_success() {
+                return Err(FetchError::Upstream(format!("server responded with {}", response.status())));
+            }
+
+            let content_type = response
+                .headers()
+                .get(header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.split(';').next().unwrap_or("").trim().to_lowercase());
+            let format =
+                FileFormat::detect(content_type.as_deref(), url.path(), requested).map_err(FetchError::UnsupportedType)?;
+
+            let body = Self::read_body(response, max_bytes).await?;
+            return Ok((format, body));
+        }
+
+        Err(FetchError::Upstream("too many redirects".to_string()))
+    }
+}
+
+fn request_error(e: reqwest::Error) -> FetchError {
+    let mut source = e.source();
+    while let Some(cause) = source {
+        if let Some(BlockedHost(host)) = cause.downcast_ref::<BlockedHost>() {
+            return FetchError::Blocked(host.clone());
+        }
+        source = cause.source();
+    }
+    if e.is_timeout() {
+        FetchError::Timeout
+    } else {
+        FetchError::Upstream(e.to_string())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImportUrlRequest {
+    url: String,
+    // Inferred from the response Content-Type or the file extension when omitted
+    format: Option<FileFormat>,
+    decimal_separator: Option<DecimalSeparator>,
+    currency: Option<String>,
+}
+
+/// Imports products from a file hosted at a URL, in any import format, using
+/// the same rules and report as the upload.
+#[allow(clippy::too_many_arguments)]
+pub async fn import_products_from_url(
+    db: web::Data<MongoConfig>,
+    limits: web::Data<LimitsConfig>,
+    events: web::Data<EventHub>,
+    fetcher: web::Data<UrlFetcher>,
+    batching: web::Data<BatchLimits>,
+    webhook: Option<web::Data<ValidationWebhook>>,
+    claims: web::ReqData<Claims>,
+    request: web::Json<ImportUrlRequest>,
+) -> Result<HttpResponse, Error> {
+    let prices = PriceFormat { decimal_separator: request.decimal_separator, currency: request.currency.clone() };
+    prices.check().map_err(ApiError::bad_request)?;
+    let rules = ImportRules { prices, ..ImportRules::default() };
+    let import = ImportLog::begin(ImportOrigin::Url, Some(claims.user_id()?)).url(&request.url);
+
+    let (format, data) = match fetcher.fetch(&request.url, request.format, limits.upload_bytes).await {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            warn!("Import from {} failed: {}", request.url, e);
+            import.fail(&db, &e.to_string()).await;
+            return Err(e.to_problem().into());
+        }
+    };
+
+    // Enforce the row cap before importing anything
+    let row_count = match format.reader().count_rows(Box::new(data.as_slice())).await {
+        Ok(row_count) => row_count,
+        Err(e) => {
+            let e = FetchError::InvalidFile(e);
+            import.fail(&db, &e.to_string()).await;
+            return Err(e.to_problem().into());
+        }
+    };
+    if row_count > limits.csv_max_rows {
+        debug!("Remote file has {} rows, limit is {}", row_count, limits.csv_max_rows);
+        import.fail(&db, "File exceeded the row limit").await;
+        return Err(payload_too_large(
+            format!("File has {} rows, exceeding the limit of {} rows", row_count, limits.csv_max_rows),
+            limits.csv_max_rows,
+        ).into());
+    }
+
+    let webhook = webhook.as_ref().map(|webhook| webhook.get_ref());
+    let (imported, errors) =
+        import_records(&db, &events, &batching, import.id, format, &rules, webhook, data.as_slice()).await;
+    import.finish(&db, imported, errors.len()).await;
+    info!("Imported {} products from {}", imported, request.url);
+    Ok(import_report(imported, errors))
+}
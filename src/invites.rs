@@ -0,0 +1,414 @@
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Bson, DateTime, Document},
+    options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument},
+    Collection,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{error, info, warn};
+use validator::Validate;
+
+use crate::{
+    auth::{default_scopes, Claims, SCOPES},
+    config::{InviteConfig, MongoConfig},
+    errors::ApiError,
+    mail::{EmailTemplate, Mailer},
+    validation::{validation_error, ValidatedQuery},
+};
+
+const CODE_LENGTH: usize = 24;
+const HOUR_MILLIS: i64 = 3_600_000;
+// Longest an invitation can stay open: 30 days
+const MAX_TTL_HOURS: i64 = 720;
+
+/// An invitation to register. Only a hash of the code is stored; the code
+/// itself is shown once, when the invite is created or resent.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Invite {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub code_hash: String,
+    // Only this email can use the invite, and it is where the invite is sent
+    pub email: Option<String>,
+    // What the new account may do
+    pub scopes: Vec<String>,
+    pub created_by: ObjectId,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+    pub accepted_at: Option<DateTime>,
+    pub accepted_by: Option<ObjectId>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InviteStatus {
+    Pending,
+    Accepted,
+    Expired,
+}
+
+impl Invite {
+    pub fn status(&self) -> InviteStatus {
+        if self.accepted_at.is_some() {
+            InviteStatus::Accepted
+        } else if self.expires_at <= DateTime::now() {
+            InviteStatus::Expired
+        } else {
+            InviteStatus::Pending
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct CreateInviteRequest {
+    #[validate(email)]
+    pub email: Option<String>,
+    // Defaults to the scopes of a self-registered user
+    pub scopes: Option<Vec<String>>,
+    #[validate(range(min = 1, max = 720))]
+    pub expires_in_hours: Option<i64>,
+}
+
+// Only pending invites can change; fields left out are kept
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateInviteRequest {
+    #[validate(email)]
+    pub email: Option<String>,
+    pub scopes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ListInvitesQuery {
+    pub status: Option<InviteStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InviteResponse {
+    pub id: String,
+    pub email: Option<String>,
+    pub scopes: Vec<String>,
+    pub status: InviteStatus,
+    pub created_by: String,
+    pub created_at: String,
+    pub expires_at: String,
+    pub accepted_at: Option<String>,
+    pub accepted_by: Option<String>,
+    // Only when the invite was just created or resent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emailed: Option<bool>,
+}
+
+impl From<&Invite> for InviteResponse {
+    fn from(invite: &Invite) -> Self {
+        InviteResponse {
+            id: invite.id.to_string(),
+            email: invite.email.clone(),
+            scopes: invite.scopes.clone(),
+            status: invite.status(),
+            created_by: invite.created_by.to_string(),
+            created_at: invite.created_at.try_to_rfc3339_string().unwrap_or_default(),
+            expires_at: invite.expires_at.try_to_rfc3339_string().unwrap_or_default(),
+            accepted_at: invite.accepted_at.and_then(|at| at.try_to_rfc3339_string().ok()),
+            accepted_by: invite.accepted_by.map(|id| id.to_string()),
+            code: None,
+            emailed: None,
+        }
+    }
+}
+
+fn invites_collection(db: &MongoConfig) -> Collection<Invite> {
+    db.database.collection("invites")
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+fn new_code() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(CODE_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+fn hash_code(code: &str) -> String {
+    format!("{:x}", Sha256::digest(code.trim().as_bytes()))
+}
+
+fn expires_at(hours: i64) -> DateTime {
+    DateTime::from_millis(DateTime::now().timestamp_millis() + hours * HOUR_MILLIS)
+}
+
+fn unknown_scope(scopes: &[String]) -> Option<ApiError> {
+    let unknown = scopes.iter().find(|scope| !SCOPES.contains(&scope.as_str()))?;
+    let message = format!("Unknown scope '{}': expected one of {}", unknown, SCOPES.join(", "));
+    Some(ApiError::new(StatusCode::BAD_REQUEST, message))
+}
+
+fn parse_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "Invalid invite ID").into())
+}
+
+// Emails the invite's code to its recipient; whether that worked
+async fn send_invite(mailer: &dyn Mailer, config: &InviteConfig, invite: &Invite, code: &str) -> bool {
+    let Some(to) = &invite.email else {
+        return false;
+    };
+    let link = match &config.signup_url {
+        Some(url) => url.replace("{code}", code),
+        None => code.to_string(),
+    };
+    let hours = (invite.expires_at.timestamp_millis() - DateTime::now().timestamp_millis()) / HOUR_MILLIS;
+    let expires_in = if hours >= 48 { format!("{} days", hours / 24) } else { format!("{} hours", hours.max(1)) };
+
+    let sent = match EmailTemplate::Invitation.render(to, &[("link", &link), ("expires_in", &expires_in)]) {
+        Ok(email) => mailer.send(&email).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = &sent {
+        warn!("Failed to email invite {}: {}", invite.id, e);
+    }
+    sent.is_ok()
+}
+
+/// Takes the invite with `code` for a registration as `email`, so nobody
+/// else can use it. None when the code is unknown, used, expired or meant
+/// for another email.
+pub async fn claim(db: &MongoConfig, code: &str, email: &str) -> Result<Option<Invite>, Error> {
+    claim_where(db, doc! { "code_hash": hash_code(code), "$or": [{ "email": null }, { "email": email.to_lowercase() }] })
+        .await
+}
+
+/// Takes a pending invite addressed to `email`, for sign-ups through an
+/// external identity provider, which cannot carry an invite code.
+pub async fn claim_for_email(db: &MongoConfig, email: &str) -> Result<Option<Invite>, Error> {
+    claim_where(db, doc! { "email": email.to_lowercase() }).await
+}
+
+async fn claim_where(db: &MongoConfig, mut filter: Document) -> Result<Option<Invite>, Error> {
+    filter.insert("accepted_at", Bson::Null);
+    filter.insert("expires_at", doc! { "$gt": DateTime::now() });
+    let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+    invites_collection(db)
+        .find_one_and_update(filter, doc! { "$set": { "accepted_at": DateTime::now() } }, options)
+        .await
+        .map_err(|e| db_error("Failed to claim invite", e))
+}
+
+/// Records who registered with a claimed invite.
+pub async fn accept(db: &MongoConfig, invite_id: ObjectId, user_id: ObjectId) {
+    if let Err(e) = invites_collection(db)
+        .update_one(doc! { "_id": invite_id }, doc! { "$set": { "accepted_by": user_id } }, None)
+        .await
+    {
+        error!("Failed to record acceptance of invite {}: {}", invite_id, e);
+    }
+}
+
+/// Gives a claimed invite back after the registration failed.
+pub async fn release(db: &MongoConfig, invite_id: ObjectId) {
+    if let Err(e) = invites_collection(db)
+        .update_one(doc! { "_id": invite_id }, doc! { "$set": { "accepted_at": Bson::Null } }, None)
+        .await
+    {
+        error!("Failed to release invite {}: {}", invite_id, e);
+    }
+}
+
+pub async fn list_invites(
+    db: web::Data<MongoConfig>,
+    query: ValidatedQuery<ListInvitesQuery>,
+) -> Result<HttpResponse, Error> {
+    let now = DateTime::now();
+    let filter = match query.status {
+        Some(InviteStatus::Pending) => doc! { "accepted_at": null, "expires_at": { "$gt": now } },
+        Some(InviteStatus::Accepted) => doc! { "accepted_at": { "$ne": null } },
+        Some(InviteStatus::Expired) => doc! { "accepted_at": null, "expires_at": { "$lte": now } },
+        None => doc! {},
+    };
+    let options = FindOptions::builder().sort(doc! { "created_at": -1 }).build();
+    let invites: Vec<Invite> = invites_collection(&db)
+        .find(filter, options)
+        .await
+        .map_err(|e| db_error("Failed to fetch invites", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating invites", e))?;
+
+    let invites: Vec<InviteResponse> = invites.iter().map(InviteResponse::from).collect();
+    Ok(HttpResponse::Ok().json(invites))
+}
+
+pub async fn get_invite(db: web::Data<MongoConfig>, id: web::Path<String>) -> Result<HttpResponse, Error> {
+    let invite = invites_collection(&db)
+        .find_one(doc! { "_id": parse_id(&id)? }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch invite", e))?;
+
+    match invite {
+        Some(invite) => Ok(HttpResponse::Ok().json(InviteResponse::from(&invite))),
+        None => Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    }
+}
+
+/// Creates an invite and, when it names an email, sends it there. The code
+/// is in the response only this once.
+pub async fn create_invite(
+    db: web::Data<MongoConfig>,
+    config: web::Data<InviteConfig>,
+    mailer: web::Data<dyn Mailer>,
+    claims: web::ReqData<Claims>,
+    request: web::Json<CreateInviteRequest>,
+) -> Result<HttpResponse, Error> {
+    if let Err(errors) = request.validate() {
+        return Err(validation_error(errors));
+    }
+    let request = request.into_inner();
+    let scopes = request.scopes.unwrap_or_else(default_scopes);
+    if let Some(problem) = unknown_scope(&scopes) {
+        return Err(problem.into());
+    }
+
+    let code = new_code();
+    let invite = Invite {
+        id: ObjectId::new(),
+        code_hash: hash_code(&code),
+        email: request.email.map(|email| email.to_lowercase()),
+        scopes,
+        created_by: claims.user_id()?,
+        created_at: DateTime::now(),
+        expires_at: expires_at(request.expires_in_hours.unwrap_or(config.ttl_hours).min(MAX_TTL_HOURS)),
+        accepted_at: None,
+        accepted_by: None,
+    };
+    invites_collection(&db)
+        .insert_one(&invite, None)
+        .await
+        .map_err(|e| db_error("Failed to create invite", e))?;
+
+    let emailed = invite.email.is_some() && send_invite(mailer.as_ref(), &config, &invite, &code).await;
+    info!("Invite {} created by {} with scopes {:?}", invite.id, invite.created_by, invite.scopes);
+    let mut response = InviteResponse::from(&invite);
+    response.code = Some(code);
+    response.emailed = Some(emailed);
+    Ok(HttpResponse::Created().json(response))
+}
+
+pub async fn update_invite(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+    request: web::Json<UpdateInviteRequest>,
+) -> Result<HttpResponse, Error> {
+    if let Err(errors) = request.validate() {
+        return Err(validation_error(errors));
+    }
+    let request = request.into_inner();
+    let mut update = Document::new();
+    if let Some(email) = request.email {
+        update.insert("email", email.to_lowercase());
+    }
+    if let Some(scopes) = request.scopes {
+        if let Some(problem) = unknown_scope(&scopes) {
+            return Err(problem.into());
+        }
+        update.insert("scopes", scopes);
+    }
+
+    let invite_id = parse_id(&id)?;
+    let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+    let invite = invites_collection(&db)
+        .find_one_and_update(doc! { "_id": invite_id, "accepted_at": null }, doc! { "$set": update }, options)
+        .await
+        .map_err(|e| db_error("Failed to update invite", e))?;
+
+    match invite {
+        Some(invite) => {
+            info!("Invite {} updated", invite_id);
+            Ok(HttpResponse::Ok().json(InviteResponse::from(&invite)))
+        }
+        None => not_pending(&db, invite_id).await,
+    }
+}
+
+// 409 for an invite that was already used, 404 for one that doesn't exist
+async fn not_pending(db: &MongoConfig, invite_id: ObjectId) -> Result<HttpResponse, Error> {
+    let exists = invites_collection(db)
+        .count_documents(doc! { "_id": invite_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch invite", e))?
+        > 0;
+    if exists {
+        Err(ApiError::new(StatusCode::CONFLICT, "Invite has already been accepted").into())
+    } else {
+        Err(ApiError::blank(StatusCode::NOT_FOUND).into())
+    }
+}
+
+/// Revokes an invite that has not been used. Accepted invites stay as the
+/// record of how an account came to be.
+pub async fn delete_invite(db: web::Data<MongoConfig>, id: web::Path<String>) -> Result<HttpResponse, Error> {
+    let invite_id = parse_id(&id)?;
+    let result = invites_collection(&db)
+        .delete_one(doc! { "_id": invite_id, "accepted_at": null }, None)
+        .await
+        .map_err(|e| db_error("Failed to delete invite", e))?;
+
+    if result.deleted_count == 0 {
+        return not_pending(&db, invite_id).await;
+    }
+    info!("Invite {} revoked", invite_id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Sends an unused invite again, with a new code and a fresh expiry; the
+/// old code stops working.
+pub async fn resend_invite(
+    db: web::Data<MongoConfig>,
+    config: web::Data<InviteConfig>,
+    mailer: web::Data<dyn Mailer>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let invite_id = parse_id(&id)?;
+    let code = new_code();
+    let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+    let invite = invites_collection(&db)
+        .find_one_and_update(
+            doc! { "_id": invite_id, "accepted_at": null, "email": { "$ne": null } },
+            doc! { "$set": { "code_hash": hash_code(&code), "expires_at": expires_at(config.ttl_hours.min(MAX_TTL_HOURS)) } },
+            options,
+        )
+        .await
+        .map_err(|e| db_error("Failed to renew invite", e))?;
+
+    let Some(invite) = invite else {
+        let invite = invites_collection(&db)
+            .find_one(doc! { "_id": invite_id }, None)
+            .await
+            .map_err(|e| db_error("Failed to fetch invite", e))?;
+        let problem = match invite {
+            Some(invite) if invite.accepted_at.is_some() => {
+                ApiError::new(StatusCode::CONFLICT, "Invite has already been accepted")
+            }
+            Some(_) => ApiError::new(StatusCode::BAD_REQUEST, "Invite has no email to send it to"),
+            None => ApiError::blank(StatusCode::NOT_FOUND),
+        };
+        return Err(problem.into());
+    };
+
+    let emailed = send_invite(mailer.as_ref(), &config, &invite, &code).await;
+    info!("Invite {} resent (emailed: {})", invite_id, emailed);
+    let mut response = InviteResponse::from(&invite);
+    response.code = Some(code);
+    response.emailed = Some(emailed);
+    Ok(HttpResponse::Ok().json(response))
+}
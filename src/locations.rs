@@ -0,0 +1,605 @@
+use std::{collections::HashMap, convert::Infallible};
+
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::{FutureExt, TryStreamExt};
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument, UpdateOptions},
+    ClientSession, Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+use validator::Validate;
+
+use crate::{
+    barcode::is_duplicate_key,
+    config::MongoConfig,
+    errors::ApiError,
+    event_store::{self, ProductEvent},
+    models::Product,
+    public_ids,
+    transactions::{run_in_transaction, TransactionError},
+    validation::validation_error,
+};
+
+/// A warehouse, store or other place stock is kept.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Location {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    // Short unique code, e.g. "WH-BERLIN"
+    pub code: String,
+    pub name: String,
+    pub address: Option<String>,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+/// Units of a product at one location. The levels of a product never add up
+/// to more than its stock_quantity; the rest is not allocated to a location.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StockLevel {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub product_id: ObjectId,
+    pub location_id: ObjectId,
+    pub quantity: i64,
+    pub updated_at: DateTime,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct LocationRequest {
+    #[validate(length(min = 1, max = 30, message = "code must be between 1 and 30 characters"))]
+    pub code: String,
+    #[validate(length(min = 1, max = 100, message = "name must be between 1 and 100 characters"))]
+    pub name: String,
+    #[validate(length(max = 500, message = "address must be at most 500 characters"))]
+    pub address: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LocationResponse {
+    pub id: String,
+    pub code: String,
+    pub name: String,
+    pub address: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl From<&Location> for LocationResponse {
+    fn from(location: &Location) -> Self {
+        LocationResponse {
+            id: location.id.map(|id| id.to_string()).unwrap_or_default(),
+            code: location.code.clone(),
+            name: location.name.clone(),
+            address: location.address.clone(),
+            created_at: location.created_at.try_to_rfc3339_string().unwrap_or_default(),
+            updated_at: location.updated_at.try_to_rfc3339_string().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct SetStockLevelRequest {
+    #[validate(range(min = 0, message = "quantity must be non-negative"))]
+    pub quantity: i64,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct StockTransferRequest {
+    pub from_location_id: String,
+    pub to_location_id: String,
+    #[validate(range(min = 1, message = "quantity must be at least 1"))]
+    pub quantity: i64,
+}
+
+/// Stock of a product at one location, as shown on products.
+#[derive(Debug, Serialize, Clone)]
+pub struct LocationStock {
+    pub location_id: String,
+    pub code: String,
+    pub quantity: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProductStockResponse {
+    pub product_id: String,
+    pub stock_quantity: i64,
+    pub locations: Vec<LocationStock>,
+    // Units not allocated to any location
+    pub unallocated: i64,
+}
+
+// Business reasons for refusing a stock change
+enum StockRejection {
+    ProductNotFound,
+    Untracked,
+    InsufficientStock(i64),
+}
+
+pub fn locations_collection(db: &MongoConfig) -> Collection<Location> {
+    db.database.collection("locations")
+}
+
+pub fn stock_levels_collection(db: &MongoConfig) -> Collection<StockLevel> {
+    db.database.collection("stock_levels")
+}
+
+fn parse_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| {
+        error!("Invalid ID format: {}", id);
+        ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format").into()
+    })
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+fn rejection_response(rejection: StockRejection) -> ApiError {
+    match rejection {
+        StockRejection::ProductNotFound => ApiError::blank(StatusCode::NOT_FOUND),
+        StockRejection::Untracked => ApiError::new(StatusCode::CONFLICT, "Product does not track stock"),
+        StockRejection::InsufficientStock(available) => {
+            ApiError::new(StatusCode::CONFLICT, "Insufficient stock at the source location")
+                .with("available", available)
+        }
+    }
+}
+
+/// Parses a location ID and checks the location exists.
+pub async fn check_location(db: &MongoConfig, id: &str) -> Result<ObjectId, Error> {
+    let location_id = parse_id(id)?;
+    let count = locations_collection(db)
+        .count_documents(doc! { "_id": location_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch location", e))?;
+    if count == 0 {
+        debug!("Rejected unknown location {}", location_id);
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, format!("Location not found: {}", location_id)).into());
+    }
+    Ok(location_id)
+}
+
+/// Adds `quantity` units at a location inside `session`'s transaction. The
+/// caller adds them to the product's stock_quantity.
+pub async fn add_to_location_with_session(
+    db: &MongoConfig,
+    session: &mut ClientSession,
+    product_id: ObjectId,
+    location_id: ObjectId,
+    quantity: i64,
+) -> Result<(), mongodb::error::Error> {
+    stock_levels_collection(db)
+        .update_one_with_session(
+            doc! { "product_id": product_id, "location_id": location_id },
+            doc! { "$inc": { "quantity": quantity }, "$set": { "updated_at": DateTime::now() } },
+            UpdateOptions::builder().upsert(true).build(),
+            session,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Takes units off the fullest locations of a product whose stock dropped to
+/// `stock_left`, so its levels never add up to more than it has. Unallocated
+/// units are sold first.
+pub async fn draw_from_locations_with_session(
+    db: &MongoConfig,
+    session: &mut ClientSession,
+    product_id: ObjectId,
+    stock_left: i64,
+) -> Result<(), mongodb::error::Error> {
+    let options = FindOptions::builder().sort(doc! { "quantity": -1 }).build();
+    let levels: Vec<StockLevel> = stock_levels_collection(db)
+        .find_with_session(doc! { "product_id": product_id, "quantity": { "$gt": 0 } }, options, session)
+        .await?
+        .stream(session)
+        .try_collect()
+        .await?;
+
+    let mut excess = levels.iter().map(|level| level.quantity).sum::<i64>() - stock_left;
+    for level in levels {
+        if excess <= 0 {
+            break;
+        }
+        let taken = excess.min(level.quantity);
+        stock_levels_collection(db)
+            .update_one_with_session(
+                doc! { "_id": level.id },
+                doc! { "$inc": { "quantity": -taken }, "$set": { "updated_at": DateTime::now() } },
+                None,
+                session,
+            )
+            .await?;
+        excess -= taken;
+    }
+    Ok(())
+}
+
+// Writes `set`, which gives the product `stock_quantity` units, and draws the
+// units it no longer has from its locations
+async fn apply_product_stock(
+    db: &MongoConfig,
+    session: &mut ClientSession,
+    product_id: ObjectId,
+    set: &Document,
+    stock_quantity: i64,
+) -> Result<bool, TransactionError<Infallible>> {
+    let products: Collection<Product> = db.database.collection("products");
+    let result = products
+        .update_one_with_session(doc! { "_id": product_id }, doc! { "$set": set.clone() }, None, session)
+        .await?;
+    if result.matched_count == 0 {
+        return Ok(false);
+    }
+    draw_from_locations_with_session(db, session, product_id, stock_quantity).await?;
+    Ok(true)
+}
+
+/// Updates a product with `set`, which sets its stock_quantity to
+/// `stock_quantity`, in one transaction with taking the units it lost off its
+/// locations. Returns whether the product exists.
+pub async fn update_with_stock(
+    db: &MongoConfig,
+    product_id: ObjectId,
+    set: Document,
+    stock_quantity: i64,
+) -> Result<bool, mongodb::error::Error> {
+    let outcome = run_in_transaction(db, (db, set), |session, (db, set)| {
+        apply_product_stock(db, session, product_id, set, stock_quantity).boxed()
+    })
+    .await;
+    match outcome {
+        Ok(found) => Ok(found),
+        Err(TransactionError::Database(e)) => Err(e),
+        Err(TransactionError::Aborted(never)) => match never {},
+    }
+}
+
+/// Per-location stock of each of `product_ids` that has any.
+pub async fn availability(db: &MongoConfig, product_ids: &[ObjectId]) -> Result<HashMap<ObjectId, Vec<LocationStock>>, Error> {
+    let mut availability: HashMap<ObjectId, Vec<LocationStock>> = HashMap::new();
+    if product_ids.is_empty() {
+        return Ok(availability);
+    }
+
+    let levels: Vec<StockLevel> = db
+        .catalog_collection::<StockLevel>("stock_levels")
+        .find(doc! { "product_id": { "$in": product_ids } }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch stock levels", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating stock levels", e))?;
+    if levels.is_empty() {
+        return Ok(availability);
+    }
+
+    let codes: HashMap<ObjectId, String> = db
+        .catalog_collection::<Location>("locations")
+        .find(None, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch locations", e))?
+        .try_collect::<Vec<Location>>()
+        .await
+        .map_err(|e| db_error("Error while iterating locations", e))?
+        .into_iter()
+        .filter_map(|location| location.id.map(|id| (id, location.code)))
+        .collect();
+
+    for level in levels {
+        let code = codes.get(&level.location_id).cloned().unwrap_or_default();
+        availability.entry(level.product_id).or_default().push(LocationStock {
+            location_id: level.location_id.to_string(),
+            code,
+            quantity: level.quantity,
+        });
+    }
+    for stocks in availability.values_mut() {
+        stocks.sort_by(|a, b| a.code.cmp(&b.code));
+    }
+    Ok(availability)
+}
+
+pub async fn create_location(
+    db: web::Data<MongoConfig>,
+    request: web::Json<LocationRequest>,
+) -> Result<HttpResponse, Error> {
+    request.validate().map_err(validation_error)?;
+
+    let now = DateTime::now();
+    let request = request.into_inner();
+    let mut location = Location {
+        id: None,
+        code: request.code.trim().to_uppercase(),
+        name: request.name,
+        address: request.address,
+        created_at: now,
+        updated_at: now,
+    };
+
+    let result = locations_collection(&db).insert_one(&location, None).await.map_err(|e| {
+        if is_duplicate_key(&e) {
+            return ApiError::new(StatusCode::CONFLICT, "A location with this code already exists").into();
+        }
+        db_error("Failed to create location", e)
+    })?;
+    location.id = result.inserted_id.as_object_id();
+
+    info!("Location {} created with ID: {}", location.code, result.inserted_id);
+    Ok(HttpResponse::Created().json(LocationResponse::from(&location)))
+}
+
+pub async fn list_locations(db: web::Data<MongoConfig>) -> Result<HttpResponse, Error> {
+    let options = FindOptions::builder().sort(doc! { "code": 1 }).build();
+    let locations: Vec<Location> = locations_collection(&db)
+        .find(None, options)
+        .await
+        .map_err(|e| db_error("Failed to fetch locations", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating locations", e))?;
+
+    let locations: Vec<LocationResponse> = locations.iter().map(LocationResponse::from).collect();
+    Ok(HttpResponse::Ok().json(locations))
+}
+
+pub async fn get_location(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let location_id = parse_id(&id)?;
+
+    let location = locations_collection(&db)
+        .find_one(doc! { "_id": location_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch location", e))?;
+
+    match location {
+        Some(location) => Ok(HttpResponse::Ok().json(LocationResponse::from(&location))),
+        None => Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    }
+}
+
+pub async fn update_location(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+    request: web::Json<LocationRequest>,
+) -> Result<HttpResponse, Error> {
+    let location_id = parse_id(&id)?;
+    request.validate().map_err(validation_error)?;
+
+    let request = request.into_inner();
+    let update = doc! {
+        "code": request.code.trim().to_uppercase(),
+        "name": request.name,
+        "address": request.address,
+        "updated_at": DateTime::now(),
+    };
+
+    let location = locations_collection(&db)
+        .find_one_and_update(
+            doc! { "_id": location_id },
+            doc! { "$set": update },
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await
+        .map_err(|e| {
+            if is_duplicate_key(&e) {
+                return ApiError::new(StatusCode::CONFLICT, "A location with this code already exists").into();
+            }
+            db_error("Failed to update location", e)
+        })?;
+
+    match location {
+        Some(location) => Ok(HttpResponse::Ok().json(LocationResponse::from(&location))),
+        None => Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    }
+}
+
+/// Deletes a location that holds no stock anymore.
+pub async fn delete_location(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let location_id = parse_id(&id)?;
+
+    let stocked = stock_levels_collection(&db)
+        .count_documents(doc! { "location_id": location_id, "quantity": { "$gt": 0 } }, None)
+        .await
+        .map_err(|e| db_error("Failed to count stock levels", e))?;
+    if stocked > 0 {
+        return Err(ApiError::new(StatusCode::CONFLICT, format!("Location still holds stock of {} products", stocked))
+            .with("stocked_products", stocked as i64)
+            .into());
+    }
+
+    let result = locations_collection(&db)
+        .delete_one(doc! { "_id": location_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to delete location", e))?;
+    if result.deleted_count == 0 {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    }
+
+    stock_levels_collection(&db)
+        .delete_many(doc! { "location_id": location_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to delete empty stock levels", e))?;
+    info!("Location deleted: {}", location_id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Stock of a product per location, plus what is not allocated to one.
+pub async fn get_product_stock(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let product_id = public_ids::resolve_product_id(&db, &id).await?;
+
+    let products: Collection<Product> = db.database.collection("products");
+    let Some(product) = products
+        .find_one(doc! { "_id": product_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch product", e))?
+    else {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    };
+    let Some(stock_quantity) = product.stock_quantity else {
+        return Err(rejection_response(StockRejection::Untracked).into());
+    };
+
+    let locations = availability(&db, &[product_id]).await?.remove(&product_id).unwrap_or_default();
+    let allocated: i64 = locations.iter().map(|stock| stock.quantity).sum();
+    Ok(HttpResponse::Ok().json(ProductStockResponse {
+        product_id: product_id.to_string(),
+        stock_quantity,
+        unallocated: stock_quantity - allocated,
+        locations,
+    }))
+}
+
+// Sets the level at a location and moves the product's total by the difference
+async fn apply_stock_level(
+    db: &MongoConfig,
+    session: &mut ClientSession,
+    product_id: ObjectId,
+    location_id: ObjectId,
+    quantity: i64,
+) -> Result<i64, TransactionError<StockRejection>> {
+    let products: Collection<Product> = db.database.collection("products");
+    let Some(product) = products.find_one_with_session(doc! { "_id": product_id }, None, session).await? else {
+        return Err(TransactionError::Aborted(StockRejection::ProductNotFound));
+    };
+    let Some(stock_quantity) = product.stock_quantity else {
+        return Err(TransactionError::Aborted(StockRejection::Untracked));
+    };
+
+    let current = stock_levels_collection(db)
+        .find_one_with_session(doc! { "product_id": product_id, "location_id": location_id }, None, session)
+        .await?
+        .map(|level| level.quantity)
+        .unwrap_or(0);
+    let delta = quantity - current;
+
+    stock_levels_collection(db)
+        .update_one_with_session(
+            doc! { "product_id": product_id, "location_id": location_id },
+            doc! { "$set": { "quantity": quantity, "updated_at": DateTime::now() } },
+            UpdateOptions::builder().upsert(true).build(),
+            session,
+        )
+        .await?;
+    if delta != 0 {
+        products
+            .update_one_with_session(doc! { "_id": product_id }, doc! { "$inc": { "stock_quantity": delta } }, None, session)
+            .await?;
+        event_store::record_with_session(db, session, product_id, vec![ProductEvent::StockAdjusted { delta }]).await?;
+    }
+    Ok(stock_quantity + delta)
+}
+
+/// Sets the units of a product at a location, e.g. after a stock count. The
+/// product's stock_quantity changes by the same amount.
+pub async fn set_stock_level(
+    db: web::Data<MongoConfig>,
+    path: web::Path<(String, String)>,
+    request: web::Json<SetStockLevelRequest>,
+) -> Result<HttpResponse, Error> {
+    request.validate().map_err(validation_error)?;
+    let (product_id, location_id) = path.into_inner();
+    let product_id = public_ids::resolve_product_id(&db, &product_id).await?;
+    let location_id = check_location(&db, &location_id).await?;
+    let quantity = request.quantity;
+
+    let outcome = run_in_transaction(&db, &**db, |session, db| {
+        apply_stock_level(db, session, product_id, location_id, quantity).boxed()
+    })
+    .await;
+
+    match outcome {
+        Ok(stock_quantity) => {
+            info!("Stock of {} at {} set to {}", product_id, location_id, quantity);
+            Ok(HttpResponse::Ok().json(doc! {
+                "product_id": product_id.to_string(),
+                "location_id": location_id.to_string(),
+                "quantity": quantity,
+                "stock_quantity": stock_quantity,
+            }))
+        }
+        Err(TransactionError::Aborted(rejection)) => Err(rejection_response(rejection).into()),
+        Err(TransactionError::Database(e)) => Err(db_error("Failed to set stock level", e)),
+    }
+}
+
+async fn apply_transfer(
+    db: &MongoConfig,
+    session: &mut ClientSession,
+    product_id: ObjectId,
+    from: ObjectId,
+    to: ObjectId,
+    quantity: i64,
+) -> Result<(), TransactionError<StockRejection>> {
+    let result = stock_levels_collection(db)
+        .update_one_with_session(
+            doc! { "product_id": product_id, "location_id": from, "quantity": { "$gte": quantity } },
+            doc! { "$inc": { "quantity": -quantity }, "$set": { "updated_at": DateTime::now() } },
+            None,
+            session,
+        )
+        .await?;
+    if result.modified_count == 0 {
+        let available = stock_levels_collection(db)
+            .find_one_with_session(doc! { "product_id": product_id, "location_id": from }, None, session)
+            .await?
+            .map(|level| level.quantity)
+            .unwrap_or(0);
+        return Err(TransactionError::Aborted(StockRejection::InsufficientStock(available)));
+    }
+    add_to_location_with_session(db, session, product_id, to, quantity).await?;
+    Ok(())
+}
+
+/// Moves units of a product from one location to another. The product's
+/// stock_quantity does not change.
+pub async fn transfer_stock(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+    request: web::Json<StockTransferRequest>,
+) -> Result<HttpResponse, Error> {
+    request.validate().map_err(validation_error)?;
+    let product_id = public_ids::resolve_product_id(&db, &id).await?;
+    let from = check_location(&db, &request.from_location_id).await?;
+    let to = check_location(&db, &request.to_location_id).await?;
+    if from == to {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "from_location_id and to_location_id must differ").into());
+    }
+    let quantity = request.quantity;
+
+    let outcome = run_in_transaction(&db, &**db, |session, db| {
+        apply_transfer(db, session, product_id, from, to, quantity).boxed()
+    })
+    .await;
+
+    match outcome {
+        Ok(()) => {
+            info!("Moved {} x {} from {} to {}", quantity, product_id, from, to);
+            Ok(HttpResponse::Ok().json(doc! {
+                "product_id": product_id.to_string(),
+                "from_location_id": from.to_string(),
+                "to_location_id": to.to_string(),
+                "quantity": quantity,
+            }))
+        }
+        Err(TransactionError::Aborted(rejection)) => Err(rejection_response(rejection).into()),
+        Err(TransactionError::Database(e)) => Err(db_error("Failed to transfer stock", e)),
+    }
+}
@@ -0,0 +1,68 @@
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use mongodb::bson::doc;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+use tracing_subscriber::{layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry};
+
+use crate::{auth::Claims, errors::ApiError};
+
+/// Swaps the active log filter of the running process.
+pub type LogLevelHandle = reload::Handle<EnvFilter, Registry>;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SetLogLevelRequest {
+    /// An `EnvFilter` directive, as in RUST_LOG: `debug`, `info,products_api=trace`...
+    pub filter: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LogLevelResponse {
+    pub filter: String,
+}
+
+/// Installs the global subscriber, filtered by RUST_LOG, and returns the
+/// handle that changes the filter later on.
+pub fn init() -> LogLevelHandle {
+    let (filter, handle) = reload::Layer::new(EnvFilter::from_default_env());
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+    handle
+}
+
+fn current_filter(handle: &LogLevelHandle) -> Result<String, Error> {
+    handle.with_current(|filter| filter.to_string()).map_err(|e| {
+        error!("Failed to read the log filter: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read the log filter").into()
+    })
+}
+
+pub async fn get_log_level(handle: web::Data<LogLevelHandle>) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(LogLevelResponse { filter: current_filter(&handle)? }))
+}
+
+/// Replaces the log filter until the next restart, which goes back to RUST_LOG.
+pub async fn set_log_level(
+    handle: web::Data<LogLevelHandle>,
+    claims: web::ReqData<Claims>,
+    request: web::Json<SetLogLevelRequest>,
+) -> Result<HttpResponse, Error> {
+    let filter = match EnvFilter::try_new(request.filter.trim()) {
+        Ok(filter) => filter,
+        Err(e) => {
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid log filter: {}", e)).into());
+        }
+    };
+
+    let previous = current_filter(&handle)?;
+    handle.reload(filter).map_err(|e| {
+        error!("Failed to change the log filter: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to change the log filter")
+    })?;
+
+    let filter = current_filter(&handle)?;
+    warn!("Log filter changed from {} to {} by user {}", previous, filter, claims.sub);
+    Ok(HttpResponse::Ok().json(LogLevelResponse { filter }))
+}
@@ -0,0 +1,330 @@
+use std::{io, sync::Arc, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::Utc;
+use futures::future::BoxFuture;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::timeout,
+};
+use tracing::{debug, info, warn};
+
+use crate::{config::MailConfig, settings::url_credentials};
+
+const DEFAULT_SMTP_PORT: u16 = 587;
+const DEFAULT_SMTPS_PORT: u16 = 465;
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    // Plain text
+    pub body: String,
+}
+
+/// Sends email. Each delivery method implements this; features only deal
+/// with templates and recipients.
+pub trait Mailer: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), String>>;
+
+    /// Whether mail could be sent now, without sending any.
+    fn check(&self) -> BoxFuture<'_, Result<(), String>>;
+}
+
+/// The messages the API sends. Templates live in templates/email, with a
+/// `Subject:` line, a blank line and the body, and `{{name}}` placeholders.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum EmailTemplate {
+    Verification,
+    PasswordReset,
+    ImportCompleted,
+    Invitation,
+}
+
+impl EmailTemplate {
+    fn source(self) -> &'static str {
+        match self {
+            EmailTemplate::Verification => include_str!("../templates/email/verification.txt"),
+            EmailTemplate::PasswordReset => include_str!("../templates/email/password_reset.txt"),
+            EmailTemplate::ImportCompleted => include_str!("../templates/email/import_completed.txt"),
+            EmailTemplate::Invitation => include_str!("../templates/email/invitation.txt"),
+        }
+    }
+
+    /// The email for `to`, with every placeholder filled from `vars`. Fails
+    /// on placeholders without a value rather than sending them verbatim.
+    pub fn render(self, to: &str, vars: &[(&str, &str)]) -> Result<Email, String> {
+        let text = fill(self.source(), vars)?;
+        let (subject, body) = text
+            .strip_prefix("Subject:")
+            .and_then(|rest| rest.split_once('\n'))
+            .ok_or_else(|| format!("template {:?} lacks a Subject line", self))?;
+        Ok(Email {
+            to: to.to_string(),
+            subject: subject.trim().to_string(),
+            body: body.trim_start_matches('\n').to_string(),
+        })
+    }
+}
+
+fn fill(template: &str, vars: &[(&str, &str)]) -> Result<String, String> {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..].find("}}").ok_or("unclosed placeholder")? + start;
+        let name = rest[start + 2..end].trim();
+        let value = vars
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| *value)
+            .ok_or_else(|| format!("no value for {{{{{}}}}}", name))?;
+        output.push_str(&rest[..start]);
+        output.push_str(value);
+        rest = &rest[end + 2..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Writes emails to the log instead of sending them, for development.
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), String>> {
+        info!("Email to {}: {}\n{}", email.to, email.subject, email.body);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn check(&self) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Delivers through an SMTP server, one connection per message. smtp://
+/// upgrades with STARTTLS when the server offers it, and only logs in after
+/// upgrading; plain SMTP is left for local relays without credentials.
+/// smtps:// uses implicit TLS.
+pub struct SmtpMailer {
+    host: String,
+    port: u16,
+    tls: bool,
+    user: Option<String>,
+    password: Option<String>,
+    from: String,
+}
+
+fn smtp_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// A line break in an address or header would start a new header or SMTP command
+fn single_line<'a>(field: &str, value: &'a str) -> io::Result<&'a str> {
+    if value.contains(['\r', '\n']) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} contains a line break", field)));
+    }
+    Ok(value)
+}
+
+// Header values with non-ASCII text go out as RFC 2047 encoded words
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        value.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", BASE64.encode(value))
+    }
+}
+
+// CRLF line endings, and leading dots doubled so no line ends the DATA section
+fn encode_body(body: &str) -> String {
+    body.lines()
+        .map(|line| if line.starts_with('.') { format!(".{}\r\n", line) } else { format!("{}\r\n", line) })
+        .collect()
+}
+
+impl SmtpMailer {
+    pub fn from_url(url: &str, from: String) -> Result<Self, String> {
+        let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+        let tls = url.scheme() == "smtps";
+        let (user, password) = url_credentials(&url)?;
+        single_line("MAIL_FROM", &from).map_err(|e| e.to_string())?;
+        Ok(SmtpMailer {
+            host: url.host_str().ok_or("missing host")?.to_string(),
+            port: url.port().unwrap_or(if tls { DEFAULT_SMTPS_PORT } else { DEFAULT_SMTP_PORT }),
+            tls,
+            user,
+            password,
+            from,
+        })
+    }
+
+    async fn secure(&self, stream: TcpStream) -> io::Result<BufReader<Box<dyn Stream>>> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let server_name = rustls::pki_types::ServerName::try_from(self.host.clone()).map_err(io::Error::other)?;
+        let stream = tokio_rustls::TlsConnector::from(Arc::new(config)).connect(server_name, stream).await?;
+        Ok(BufReader::new(Box::new(stream)))
+    }
+
+    // The text of a reply's lines, after checking the code of the last one
+    async fn read_reply<S: Stream>(connection: &mut BufReader<S>, expected: &[u16]) -> io::Result<Vec<String>> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if connection.read_line(&mut line).await? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+            }
+            // "250-..." continues, "250 ..." is the last line
+            let last = line.as_bytes().get(3) != Some(&b'-');
+            lines.push(line);
+            if last {
+                break;
+            }
+        }
+        let line = lines.last().map(String::as_str).unwrap_or_default();
+        let code: u16 = line.get(..3).and_then(|code| code.parse().ok()).unwrap_or(0);
+        if expected.contains(&code) {
+            Ok(lines.iter().map(|line| line.get(4..).unwrap_or_default().trim_end().to_string()).collect())
+        } else {
+            Err(smtp_error(format!("server replied {}", line.trim_end())))
+        }
+    }
+
+    async fn command<S: Stream>(
+        connection: &mut BufReader<S>,
+        command: &str,
+        expected: &[u16],
+    ) -> io::Result<Vec<String>> {
+        connection.get_mut().write_all(format!("{}\r\n", command).as_bytes()).await?;
+        Self::read_reply(connection, expected).await
+    }
+
+    // A connection past the greeting, over TLS when the server offers it,
+    // and, with credentials, logged in
+    async fn open(&self) -> io::Result<BufReader<Box<dyn Stream>>> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let mut connection = if self.tls {
+            let mut connection = self.secure(stream).await?;
+            Self::read_reply(&mut connection, &[220]).await?;
+            Self::command(&mut connection, "EHLO localhost", &[250]).await?;
+            connection
+        } else {
+            let mut plain = BufReader::new(stream);
+            Self::read_reply(&mut plain, &[220]).await?;
+            let extensions = Self::command(&mut plain, "EHLO localhost", &[250]).await?;
+            if extensions.iter().any(|extension| extension.eq_ignore_ascii_case("STARTTLS")) {
+                Self::command(&mut plain, "STARTTLS", &[220]).await?;
+                // The server waits for the TLS handshake, so nothing is left buffered
+                let mut connection = self.secure(plain.into_inner()).await?;
+                Self::command(&mut connection, "EHLO localhost", &[250]).await?;
+                connection
+            } else if self.user.is_some() {
+                let refusal = "server doesn't offer STARTTLS, refusing to send credentials in cleartext";
+                return Err(smtp_error(refusal.to_string()));
+            } else {
+                BufReader::new(Box::new(plain.into_inner()) as Box<dyn Stream>)
+            }
+        };
+        if let (Some(user), Some(password)) = (&self.user, &self.password) {
+            let credentials = BASE64.encode(format!("\0{}\0{}", user, password));
+            Self::command(&mut connection, &format!("AUTH PLAIN {}", credentials), &[235]).await?;
+        }
+        Ok(connection)
+    }
+
+    async fn deliver(&self, email: &Email) -> io::Result<()> {
+        let to = single_line("recipient", &email.to)?;
+        let subject = single_line("subject", &email.subject)?;
+        let mut connection = self.open().await?;
+        Self::command(&mut connection, &format!("MAIL FROM:<{}>", self.from), &[250]).await?;
+        Self::command(&mut connection, &format!("RCPT TO:<{}>", to), &[250, 251]).await?;
+        Self::command(&mut connection, "DATA", &[354]).await?;
+
+        let domain = self.from.rsplit_once('@').map(|(_, domain)| domain).unwrap_or("localhost");
+        let message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\nMIME-Version: 1.0\r\n\
+             Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}.\r\n",
+            self.from,
+            to,
+            encode_header(subject),
+            Utc::now().to_rfc2822(),
+            uuid::Uuid::now_v7(),
+            domain,
+            encode_body(&email.body),
+        );
+        connection.get_mut().write_all(message.as_bytes()).await?;
+        Self::read_reply(&mut connection, &[250]).await?;
+
+        // The message is accepted at this point, so a failed goodbye doesn't matter
+        if let Err(e) = Self::command(&mut connection, "QUIT", &[221]).await {
+            debug!("SMTP QUIT failed: {}", e);
+        }
+        Ok(())
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn name(&self) -> &'static str {
+        "SMTP"
+    }
+
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            match timeout(IO_TIMEOUT, self.deliver(email)).await {
+                Ok(result) => result.map_err(|e| e.to_string()),
+                Err(_) => Err(format!("timed out talking to {}:{}", self.host, self.port)),
+            }
+        })
+    }
+
+    fn check(&self) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let greeted = async {
+                let mut connection = self.open().await?;
+                Self::command(&mut connection, "QUIT", &[221]).await.map(|_| ())
+            };
+            match timeout(IO_TIMEOUT, greeted).await {
+                Ok(result) => result.map_err(|e| format!("{}:{}: {}", self.host, self.port, e)),
+                Err(_) => Err(format!("timed out talking to {}:{}", self.host, self.port)),
+            }
+        })
+    }
+}
+
+/// The mailer for MAIL_URL: SMTP for smtp:// and smtps:// URLs, the log
+/// otherwise.
+pub fn from_config(config: MailConfig) -> Arc<dyn Mailer> {
+    let Some(url) = config.url else {
+        return Arc::new(LogMailer);
+    };
+    match SmtpMailer::from_url(&url, config.from) {
+        Ok(mailer) if matches!(url.split_once("://"), Some(("smtp" | "smtps", _))) => {
+            info!("Sending email through {}:{}", mailer.host, mailer.port);
+            Arc::new(mailer)
+        }
+        Ok(_) => {
+            warn!("Emails are only logged: unsupported MAIL_URL scheme, use smtp:// or smtps://");
+            Arc::new(LogMailer)
+        }
+        Err(e) => {
+            warn!("Emails are only logged, MAIL_URL is invalid: {}", e);
+            Arc::new(LogMailer)
+        }
+    }
+}
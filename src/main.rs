@@ -1,25 +1,192 @@
 use actix_cors::Cors;
-use actix_web::{web, App, HttpServer, middleware::Logger};
+use actix_web::{web, App, HttpServer, middleware::{from_fn, Logger}};
 use tracing_actix_web::TracingLogger;
-use tracing::info;
+use tracing::{info, warn};
 use dotenv::dotenv;
-use std::env;
+use clap::Parser;
 
 mod config;
+mod settings;
 mod models;
+mod money;
+mod pricing;
+mod tax;
 mod handlers;
 mod auth;
+mod oauth;
+mod sessions;
+mod crypto;
+mod pii;
+mod password;
+mod password_policy;
+mod invites;
+mod two_factor;
+mod favorites;
+mod carts;
+mod orders;
+mod transactions;
+mod events;
+mod stock;
+mod reservations;
+mod locations;
+mod purchase_orders;
+mod suppliers;
+mod stats;
+mod scheduler;
+mod grpc;
+mod cli;
+mod doctor;
+mod seed;
+mod tls;
+mod validation;
+mod search;
+mod views;
+mod search_queries;
+mod imports;
+mod import_formats;
+mod import_rules;
+mod import_sources;
+mod import_history;
+mod import_diffs;
+mod import_jobs;
+mod import_batches;
+mod feeds;
+mod barcode;
+mod slugs;
+mod public_ids;
+mod attributes;
+mod conditions;
+mod negotiation;
+mod structured_data;
+mod privacy;
+mod saved_filters;
+mod compare;
+mod trash;
+mod backup;
+mod event_store;
+mod event_bus;
+mod analytics;
+mod breaker;
+mod versioning;
+mod errors;
+mod debug_log;
+mod log_level;
+mod maintenance;
+mod timeouts;
+mod feature_flags;
+mod auth_events;
+mod mail;
+mod notifications;
+mod exports;
+mod export_storage;
+mod reports;
+mod price_approvals;
+mod drafts;
+mod bundles;
+mod relationships;
+mod search_engine;
+#[cfg(feature = "nats")]
+mod nats;
+#[cfg(feature = "redis")]
+mod redis;
+mod rate_limit;
+mod captcha;
+mod validation_webhook;
+mod compliance;
 
-use config::MongoConfig;
+use config::{AppConfig, MongoConfig};
 use handlers::{
     create_product,
     get_product,
     list_products,
+    stream_products,
     update_product,
     delete_product,
+    publish_product,
+    archive_product,
     upload_products_csv,
+    preview_products_import,
+    json_error_handler,
 };
-use auth::{register, login, refresh_token};
+use auth::{
+    register,
+    login,
+    refresh_token,
+    change_password,
+    impersonate,
+    RequireScope,
+    SCOPE_PRODUCTS_READ,
+    SCOPE_PRODUCTS_WRITE,
+    SCOPE_PRODUCTS_IMPORT,
+    SCOPE_PRICES_APPROVE,
+    SCOPE_ADMIN,
+};
+use oauth::{oauth_authorize, oauth_callback, OAuthProviders};
+use password_policy::PasswordPolicy;
+use invites::{create_invite, delete_invite, get_invite, list_invites, resend_invite, update_invite};
+use sessions::{list_sessions, revoke_session, SessionCache};
+use privacy::{delete_account, export_personal_data};
+use carts::{get_cart, add_cart_item, update_cart_item, remove_cart_item, clear_cart};
+use orders::{create_order, list_my_orders, get_my_order, cancel_my_order, admin_list_orders, admin_update_order_status};
+use events::{stream_events, EventHub};
+use stock::low_stock_report;
+use reservations::{release_reservation, reserve_product};
+use locations::{
+    create_location, delete_location, get_location, get_product_stock, list_locations, set_stock_level, transfer_stock,
+    update_location,
+};
+use purchase_orders::{
+    cancel_purchase_order, create_purchase_order, get_purchase_order, list_purchase_orders, receive_purchase_order,
+};
+use suppliers::{create_supplier, delete_supplier, get_supplier, list_supplier_products, list_suppliers, update_supplier};
+use search::{related_products, suggest_products};
+use views::{record_view, trending_products, ViewCounter};
+use search_queries::{search_analytics, SearchLog};
+use imports::{import_products_from_url, UrlFetcher};
+use import_batches::BatchLimits;
+use import_history::{list_imports, rollback_import};
+use import_diffs::{apply_import_diff, create_import_diff, get_import_diff};
+use import_jobs::{cancel_import_job, create_import_job, get_import_job};
+use feeds::product_feed;
+use barcode::get_product_by_barcode;
+use slugs::get_product_by_slug;
+use attributes::{get_category_attributes, set_category_attributes};
+use compare::compare_products;
+use saved_filters::{create_saved_filter, delete_saved_filter, list_saved_filters, update_saved_filter};
+use import_sources::{
+    create_import_source, delete_import_source, get_import_source, list_import_runs, list_import_sources,
+    trigger_import_source, update_import_source,
+};
+use stats::{get_stats, StatsCache};
+use cli::{Cli, Command};
+use scheduler::{list_jobs, register_default_jobs, Scheduler};
+use trash::{list_trash, purge_product, restore_product};
+use backup::{create_backup, restore_backup};
+use event_store::{product_changes, product_history};
+use structured_data::product_jsonld;
+use tax::{delete_tax_rate, list_tax_rates, set_tax_rate};
+use log_level::{get_log_level, set_log_level, LogLevelHandle};
+use maintenance::{get_maintenance, set_maintenance, MaintenanceMode};
+use rate_limit::RateLimiter;
+use captcha::LoginThrottle;
+use validation_webhook::ValidationWebhook;
+use auth_events::list_auth_events;
+use exports::{create_export_job, download_export, get_export_job};
+use reports::run_report;
+use drafts::{discard_draft, get_draft, publish_draft, save_draft};
+use bundles::{remove_bundle, set_bundle};
+use relationships::{create_relationship, delete_relationship, import_relationships, list_relationships};
+use search_engine::{search_products, search_sync_status, start_search_sync, SearchSync};
+use price_approvals::{approve_price_change, get_price_change, list_price_changes, reject_price_change};
+use notifications::{
+    get_notification_preferences, list_notifications, mark_all_notifications_read, mark_notification_read,
+    update_notification_preferences,
+};
+use feature_flags::{create_flag, delete_flag, get_flag, list_flags, list_my_flags, update_flag, FeatureFlagStore};
+use breaker::{metrics, readiness};
+use versioning::ApiVersion;
+use favorites::{list_favorites, add_favorite, remove_favorite};
+use two_factor::{setup_two_factor, verify_two_factor_setup, disable_two_factor, login_two_factor};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -29,14 +196,136 @@ async fn main() -> std::io::Result<()> {
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "info");
     }
-    tracing_subscriber::fmt::init();
+    let log_level = log_level::init();
+
+    let command = Cli::parse().command;
+    let config = AppConfig::load()?;
+    info!("Configuration for profile {}:\n{}", config.profile, settings::settings().dump());
+    auth::init(&config.auth);
+    two_factor::init(&config.auth);
+    crypto::init(&config.encryption);
+    money::init(&config.money);
+    password::init(&config.password_hash);
 
+    match command {
+        None | Some(Command::Serve) => serve(config, log_level).await,
+        Some(command) => cli::run(command, config).await.map_err(std::io::Error::other),
+    }
+}
+
+async fn serve(config: AppConfig, log_level: LogLevelHandle) -> std::io::Result<()> {
     info!("Starting server...");
 
-    let db = MongoConfig::init().await.expect("Failed to initialize MongoDB");
+    let db = MongoConfig::init(&config.mongo).await.expect("Failed to initialize MongoDB");
+    // Hashed up front so the first login for an unknown email is not slower than the rest
+    password::dummy_hash();
     let db_data = web::Data::new(db);
+    let oauth_data = web::Data::new(OAuthProviders::new(config.oauth));
+    let limits_data = web::Data::new(config.limits);
+    let events_data = web::Data::new(EventHub::default());
+    event_bus::start(&events_data, config.event_bus);
+    let analytics_data = web::Data::new(analytics::start(&events_data, config.analytics)?);
+    notifications::spawn_notifier(&events_data, db_data.clone());
+    bundles::spawn_repricer(events_data.clone(), db_data.clone());
+    let stats_data = web::Data::new(StatsCache::default());
+    let search_data = web::Data::new(config.search);
+    let search_engine_config = config.search_engine;
+    let search_engine = search_engine::from_config(&search_engine_config);
+    if let Some(engine) = &search_engine {
+        search_engine::spawn_indexer(&events_data, db_data.clone(), engine.clone());
+    }
+    let search_sync_data = search_engine.clone().map(|engine| web::Data::new(SearchSync::new(engine)));
+    let search_engine_data: Option<web::Data<dyn search_engine::SearchEngine>> = search_engine.map(web::Data::from);
+    let views_data = web::Data::new(ViewCounter::default());
+    let session_cache_data = web::Data::new(SessionCache::default());
+    let searches_data = web::Data::new(SearchLog::default());
+    let batch_limits_data = web::Data::new(BatchLimits::new(&config.import));
+    let fetcher_data = web::Data::new(UrlFetcher::new(config.import));
+    let feeds_data = web::Data::new(config.feeds);
+    let storefront_data = web::Data::new(config.storefront);
+    let trash_data = web::Data::new(config.trash);
+    let price_approval_data = web::Data::new(config.price_approval);
+    let tax_data = web::Data::new(config.tax);
+    let reservation_data = web::Data::new(config.reservation);
+    let versioning_data = web::Data::new(config.versioning);
+    let debug_log_data = web::Data::new(config.debug_log);
+    let request_timeout_data = web::Data::new(config.request_timeout);
+    let compliance_data = web::Data::new(config.compliance);
+    let invite_data = web::Data::new(config.invite);
+    let password_policy_data = web::Data::new(PasswordPolicy::new(config.password_policy)?);
+    let log_level_data = web::Data::new(log_level);
+    let maintenance_data = web::Data::new(MaintenanceMode::default());
+    if let Err(e) = maintenance_data.refresh(&db_data).await {
+        warn!("Failed to load maintenance settings: {}", e);
+    }
+    let flags_data = web::Data::new(FeatureFlagStore::new(config.feature_flags));
+    feature_flags::spawn_refresh(flags_data.clone(), db_data.clone());
+    let mailer_data: web::Data<dyn mail::Mailer> = web::Data::from(mail::from_config(config.mail));
+    let rate_limit_data = RateLimiter::from_config(config.rate_limit).map(web::Data::new);
+    let login_throttle_data = LoginThrottle::from_config(config.captcha)?.map(web::Data::new);
+    let validation_webhook_data = ValidationWebhook::from_config(config.validation_webhook)?.map(web::Data::new);
+    let export_config = config.export;
+    let export_storage_data: web::Data<dyn export_storage::ExportStorage> =
+        web::Data::from(export_storage::from_config(&export_config));
+    let export_data = web::Data::new(export_config);
+    match exports::fail_interrupted_jobs(&db_data).await {
+        Ok(0) => {}
+        Ok(interrupted) => warn!("Marked {} interrupted export jobs as failed", interrupted),
+        Err(e) => warn!("Failed to clean up interrupted export jobs: {}", e),
+    }
+    // Duplicate accounts from before the index existed make this fail until they are merged
+    match auth::ensure_email_index(&db_data).await {
+        Ok(index) => info!("Ensured index {} on users", index),
+        Err(e) => warn!("Failed to ensure the unique email index on users: {}", e),
+    }
+    // Without it, 2FA challenge attempt counters would pile up
+    match two_factor::ensure_challenge_index(&db_data).await {
+        Ok(index) => info!("Ensured index {} on two_factor_challenges", index),
+        Err(e) => warn!("Failed to ensure the expiry index on two_factor_challenges: {}", e),
+    }
+    match import_jobs::fail_interrupted_jobs(&db_data).await {
+        Ok(0) => {}
+        Ok(interrupted) => warn!("Marked {} interrupted import jobs as failed", interrupted),
+        Err(e) => warn!("Failed to clean up interrupted import jobs: {}", e),
+    }
+
+    // Background jobs
+    let scheduler_data = web::Data::new(Scheduler::default());
+    register_default_jobs(
+        &scheduler_data,
+        db_data.clone(),
+        stats_data.clone(),
+        views_data.clone(),
+        searches_data.clone(),
+        events_data.clone(),
+        batch_limits_data.clone(),
+        fetcher_data.clone(),
+        limits_data.clone(),
+        validation_webhook_data.clone(),
+        trash_data.clone(),
+        maintenance_data.clone(),
+        export_storage_data.clone(),
+    );
+    if let Some(search_sync) = &search_sync_data {
+        search_engine::schedule_repair(&scheduler_data, db_data.clone(), search_sync.clone(), &search_engine_config);
+    }
 
-    HttpServer::new(move || {
+    // Internal gRPC API on its own port
+    grpc::spawn_server(
+        db_data.clone(),
+        events_data.clone(),
+        limits_data.clone(),
+        price_approval_data.clone(),
+        validation_webhook_data.clone(),
+        rate_limit_data.clone(),
+        &config.grpc,
+    );
+
+    // Optional TLS termination; HSTS is only sent when serving HTTPS
+    let tls_config = config.tls;
+    let tls_data = tls_config.clone().map(web::Data::new);
+
+    let server = HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
             .allow_any_method()
@@ -44,30 +333,414 @@ async fn main() -> std::io::Result<()> {
             .max_age(3600);
 
         App::new()
+            .wrap(from_fn(timeouts::abort_slow_requests))
+            .wrap(from_fn(errors::json_method_not_allowed))
+            .wrap(from_fn(debug_log::log_failed_requests))
             .wrap(cors)
             .wrap(Logger::default())
+            .wrap(from_fn(tls::strict_transport_security))
+            .wrap(from_fn(breaker::reject_when_open))
+            .wrap(from_fn(maintenance::reject_during_maintenance))
+            .wrap(from_fn(versioning::negotiate_version))
+            .wrap(from_fn(errors::problem_details))
             .wrap(TracingLogger::default())
+            .configure(|cfg| {
+                if let Some(tls_data) = &tls_data {
+                    cfg.app_data(tls_data.clone());
+                }
+                if let Some(rate_limit_data) = &rate_limit_data {
+                    cfg.app_data(rate_limit_data.clone());
+                }
+                if let Some(login_throttle_data) = &login_throttle_data {
+                    cfg.app_data(login_throttle_data.clone());
+                }
+                if let Some(validation_webhook_data) = &validation_webhook_data {
+                    cfg.app_data(validation_webhook_data.clone());
+                }
+                if let Some(search_engine_data) = &search_engine_data {
+                    cfg.app_data(search_engine_data.clone());
+                }
+                if let Some(search_sync_data) = &search_sync_data {
+                    cfg.app_data(search_sync_data.clone());
+                }
+            })
             .app_data(db_data.clone())
-            // Public routes
+            .app_data(oauth_data.clone())
+            .app_data(limits_data.clone())
+            .app_data(batch_limits_data.clone())
+            .app_data(events_data.clone())
+            .app_data(analytics_data.clone())
+            .app_data(stats_data.clone())
+            .app_data(search_data.clone())
+            .app_data(views_data.clone())
+            .app_data(session_cache_data.clone())
+            .app_data(searches_data.clone())
+            .app_data(fetcher_data.clone())
+            .app_data(feeds_data.clone())
+            .app_data(storefront_data.clone())
+            .app_data(trash_data.clone())
+            .app_data(price_approval_data.clone())
+            .app_data(tax_data.clone())
+            .app_data(reservation_data.clone())
+            .app_data(versioning_data.clone())
+            .app_data(debug_log_data.clone())
+            .app_data(request_timeout_data.clone())
+            .app_data(compliance_data.clone())
+            .app_data(password_policy_data.clone())
+            .app_data(invite_data.clone())
+            .app_data(log_level_data.clone())
+            .app_data(maintenance_data.clone())
+            .app_data(flags_data.clone())
+            .app_data(mailer_data.clone())
+            .app_data(export_data.clone())
+            .app_data(export_storage_data.clone())
+            .app_data(scheduler_data.clone())
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(limits_data.json_payload_bytes)
+                    .error_handler(json_error_handler),
+            )
+            .app_data(web::QueryConfig::default().error_handler(validation::query_error_handler))
+            // Probes, outside the API and its circuit breaker
+            .service(web::resource("/ready").route(web::get().to(readiness)))
+            .service(web::resource("/metrics").route(web::get().to(metrics)))
+            // Versioned API; v1 is also served unversioned under /api for existing clients
+            .service(web::scope("/api/v1").app_data(ApiVersion::V1).configure(api_routes))
+            .service(web::scope("/api/v2").app_data(ApiVersion::V2).configure(api_routes))
+            .service(web::scope("/api").configure(api_routes))
+            .default_service(web::to(errors::not_found))
+    });
+
+    let Some(tls_config) = tls_config else {
+        return server.bind(("127.0.0.1", 8080))?.run().await;
+    };
+
+    info!("TLS enabled, serving HTTPS on port {}", tls_config.https_port);
+    let server = server
+        .bind_rustls_0_23(("127.0.0.1", tls_config.https_port), tls::load_server_config(&tls_config)?)?
+        .run();
+
+    let Some(redirect_port) = tls_config.redirect_port else {
+        return server.await;
+    };
+
+    info!("Redirecting plain HTTP on port {} to HTTPS", redirect_port);
+    let tls_data = web::Data::new(tls_config);
+    let redirect_server = HttpServer::new(move || {
+        App::new()
+            .app_data(tls_data.clone())
+            .default_service(web::to(tls::redirect_to_https))
+    })
+    .bind(("127.0.0.1", redirect_port))?
+    .run();
+
+    futures::try_join!(server, redirect_server).map(|_| ())
+}
+
+// Every API route, relative to the /api or /api/v{n} scope it is mounted in
+fn api_routes(cfg: &mut web::ServiceConfig) {
+    // Public routes
+    cfg.service(
+        web::scope("/auth")
+            .service(web::resource("/register").route(web::post().to(register)))
+            .service(web::resource("/login").route(web::post().to(login)))
+            .service(web::resource("/refresh").route(web::post().to(refresh_token)))
+            .service(web::resource("/2fa/verify").route(web::post().to(login_two_factor)))
+            .service(web::resource("/oauth/{provider}/authorize").route(web::get().to(oauth_authorize)))
+            .service(web::resource("/oauth/{provider}/callback").route(web::get().to(oauth_callback)))
+    )
+    // Product feeds for ad platforms, authenticated by the profile's access token
+    .service(web::resource("/feeds/{profile}/{format}").route(web::get().to(product_feed)))
+    // Export downloads, authenticated by the signed link handed out with the job
+    .service(web::resource("/exports/{id}/download").route(web::get().to(download_export)))
+    // Protected routes
+    .service(
+        web::scope("/users/me")
+            .wrap(auth::AuthMiddleware)
+            .service(web::resource("").route(web::delete().to(delete_account)))
+            .service(web::resource("/export").route(web::get().to(export_personal_data)))
+            .service(web::resource("/password").route(web::post().to(change_password)))
+            .service(web::resource("/sessions").route(web::get().to(list_sessions)))
+            .service(web::resource("/sessions/{id}").route(web::delete().to(revoke_session)))
+            .service(web::resource("/2fa/setup").route(web::post().to(setup_two_factor)))
+            .service(web::resource("/2fa/verify").route(web::post().to(verify_two_factor_setup)))
+            .service(web::resource("/2fa/disable").route(web::post().to(disable_two_factor)))
+            .service(web::resource("/favorites").route(web::get().to(list_favorites)))
+            .service(web::resource("/flags").route(web::get().to(list_my_flags)))
+            .service(web::resource("/notifications").route(web::get().to(list_notifications)))
+            .service(web::resource("/notifications/read").route(web::post().to(mark_all_notifications_read)))
+            .service(web::resource("/notifications/{id}/read").route(web::post().to(mark_notification_read)))
             .service(
-                web::scope("/api/auth")
-                    .route("/register", web::post().to(register))
-                    .route("/login", web::post().to(login))
-                    .route("/refresh", web::post().to(refresh_token))
+                web::resource("/notification-preferences")
+                    .route(web::get().to(get_notification_preferences))
+                    .route(web::put().to(update_notification_preferences))
             )
-            // Protected routes
             .service(
-                web::scope("/api/products")
-                    .wrap(auth::AuthMiddleware::default())
-                    .route("", web::post().to(create_product))
-                    .route("", web::get().to(list_products))
-                    .route("/{id}", web::get().to(get_product))
-                    .route("/{id}", web::put().to(update_product))
-                    .route("/{id}", web::delete().to(delete_product))
-                    .route("/import/csv", web::post().to(upload_products_csv))
+                web::resource("/favorites/{product_id}")
+                    .route(web::post().to(add_favorite))
+                    .route(web::delete().to(remove_favorite))
             )
-    })
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    )
+    .service(
+        web::scope("/cart")
+            .wrap(auth::AuthMiddleware)
+            .service(
+                web::resource("")
+                    .route(web::get().to(get_cart))
+                    .route(web::delete().to(clear_cart))
+            )
+            .service(web::resource("/items").route(web::post().to(add_cart_item)))
+            .service(
+                web::resource("/items/{product_id}")
+                    .route(web::put().to(update_cart_item))
+                    .route(web::delete().to(remove_cart_item))
+            )
+    )
+    .service(
+        web::scope("/orders")
+            .wrap(auth::AuthMiddleware)
+            .service(
+                web::resource("")
+                    .route(web::post().to(create_order))
+                    .route(web::get().to(list_my_orders))
+            )
+            .service(web::resource("/{id}").route(web::get().to(get_my_order)))
+            .service(web::resource("/{id}/cancel").route(web::post().to(cancel_my_order)))
+    )
+    .service(
+        web::scope("/products")
+            .wrap(from_fn(rate_limit::limit_per_user))
+            .wrap(auth::AuthMiddleware)
+            .service(
+                web::resource("")
+                    .route(web::post().to(create_product).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE)))
+                    .route(web::get().to(list_products).wrap(RequireScope::new(SCOPE_PRODUCTS_READ)))
+            )
+            .service(web::resource("/low-stock").route(web::get().to(low_stock_report).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(web::resource("/stats").route(web::get().to(get_stats).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(web::resource("/search").route(web::get().to(search_products).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(web::resource("/suggest").route(web::get().to(suggest_products).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(web::resource("/trending").route(web::get().to(trending_products).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(web::resource("/changes").route(web::get().to(product_changes).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(web::resource("/stream").route(web::get().to(stream_products).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(
+                web::resource("/filters")
+                    .route(web::post().to(create_saved_filter).wrap(RequireScope::new(SCOPE_PRODUCTS_READ)))
+                    .route(web::get().to(list_saved_filters).wrap(RequireScope::new(SCOPE_PRODUCTS_READ)))
+            )
+            .service(
+                web::resource("/filters/{id}")
+                    .route(web::put().to(update_saved_filter).wrap(RequireScope::new(SCOPE_PRODUCTS_READ)))
+                    .route(web::delete().to(delete_saved_filter).wrap(RequireScope::new(SCOPE_PRODUCTS_READ)))
+            )
+            .service(web::resource("/compare").route(web::get().to(compare_products).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(web::resource("/by-barcode/{code}").route(web::get().to(get_product_by_barcode).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(web::resource("/slug/{slug}").route(web::get().to(get_product_by_slug).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(web::resource("/export/jobs").route(web::post().to(create_export_job).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(web::resource("/export/jobs/{id}").route(web::get().to(get_export_job).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(web::resource("/imports").route(web::get().to(list_imports).wrap(RequireScope::new(SCOPE_PRODUCTS_IMPORT))))
+            .service(web::resource("/relationships/import").route(web::post().to(import_relationships).wrap(RequireScope::new(SCOPE_PRODUCTS_IMPORT))))
+            .service(web::resource("/imports/{id}/rollback").route(web::post().to(rollback_import).wrap(RequireScope::new(SCOPE_PRODUCTS_IMPORT))))
+            .service(
+                web::resource("/{id}")
+                    .route(web::get().to(get_product).wrap(RequireScope::new(SCOPE_PRODUCTS_READ)))
+                    .route(web::put().to(update_product).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE)))
+                    .route(web::delete().to(delete_product).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE)))
+            )
+            .service(
+                web::resource("/{id}/draft")
+                    .route(web::get().to(get_draft).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE)))
+                    .route(web::put().to(save_draft).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE)))
+                    .route(web::delete().to(discard_draft).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE)))
+            )
+            .service(
+                web::resource("/{id}/bundle")
+                    .route(web::put().to(set_bundle).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE)))
+                    .route(web::delete().to(remove_bundle).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE)))
+            )
+            .service(
+                web::resource("/{id}/relationships")
+                    .route(web::get().to(list_relationships).wrap(RequireScope::new(SCOPE_PRODUCTS_READ)))
+                    .route(web::post().to(create_relationship).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE)))
+            )
+            .service(web::resource("/{id}/relationships/{relationship_id}").route(web::delete().to(delete_relationship).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE))))
+            .service(web::resource("/{id}/draft/publish").route(web::post().to(publish_draft).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE))))
+            .service(web::resource("/{id}/publish").route(web::post().to(publish_product).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE))))
+            .service(web::resource("/{id}/archive").route(web::post().to(archive_product).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE))))
+            .service(web::resource("/{id}/view").route(web::post().to(record_view).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(web::resource("/{id}/related").route(web::get().to(related_products).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(
+                web::resource("/{id}/reserve")
+                    .route(web::post().to(reserve_product).wrap(RequireScope::new(SCOPE_PRODUCTS_READ)))
+                    .route(web::delete().to(release_reservation).wrap(RequireScope::new(SCOPE_PRODUCTS_READ)))
+            )
+            .service(web::resource("/{id}/stock").route(web::get().to(get_product_stock).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(web::resource("/{id}/stock/transfer").route(web::post().to(transfer_stock).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE))))
+            .service(web::resource("/{id}/stock/{location_id}").route(web::put().to(set_stock_level).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE))))
+            .service(web::resource("/{id}/history").route(web::get().to(product_history).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(web::resource("/{id}/jsonld").route(web::get().to(product_jsonld).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(web::resource("/import/csv").route(web::post().to(upload_products_csv).wrap(RequireScope::new(SCOPE_PRODUCTS_IMPORT))))
+            .service(web::resource("/import/preview").route(web::post().to(preview_products_import).wrap(RequireScope::new(SCOPE_PRODUCTS_IMPORT))))
+            .service(web::resource("/import/url").route(web::post().to(import_products_from_url).wrap(RequireScope::new(SCOPE_PRODUCTS_IMPORT))))
+            .service(web::resource("/import/diff").route(web::post().to(create_import_diff).wrap(RequireScope::new(SCOPE_PRODUCTS_IMPORT))))
+            .service(web::resource("/import/diff/{id}").route(web::get().to(get_import_diff).wrap(RequireScope::new(SCOPE_PRODUCTS_IMPORT))))
+            .service(web::resource("/import/diff/{id}/apply").route(web::post().to(apply_import_diff).wrap(RequireScope::new(SCOPE_PRODUCTS_IMPORT))))
+            .service(web::resource("/import/jobs").route(web::post().to(create_import_job).wrap(RequireScope::new(SCOPE_PRODUCTS_IMPORT))))
+            .service(
+                web::resource("/import/jobs/{id}")
+                    .route(web::get().to(get_import_job).wrap(RequireScope::new(SCOPE_PRODUCTS_IMPORT)))
+                    .route(web::delete().to(cancel_import_job).wrap(RequireScope::new(SCOPE_PRODUCTS_IMPORT)))
+            )
+    )
+    .service(
+        web::scope("/price-changes")
+            .wrap(RequireScope::new(SCOPE_PRICES_APPROVE))
+            .wrap(auth::AuthMiddleware)
+            .service(web::resource("").route(web::get().to(list_price_changes)))
+            .service(web::resource("/{id}").route(web::get().to(get_price_change)))
+            .service(web::resource("/{id}/approve").route(web::post().to(approve_price_change)))
+            .service(web::resource("/{id}/reject").route(web::post().to(reject_price_change)))
+    )
+    .service(
+        web::scope("/reports")
+            .wrap(auth::AuthMiddleware)
+            .service(web::resource("/run").route(web::post().to(run_report).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+    )
+    .service(
+        web::scope("/categories")
+            .wrap(auth::AuthMiddleware)
+            .service(
+                web::resource("/{category}/attributes")
+                    .route(web::get().to(get_category_attributes).wrap(RequireScope::new(SCOPE_PRODUCTS_READ)))
+                    .route(web::put().to(set_category_attributes).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE)))
+            )
+    )
+    .service(
+        web::scope("/suppliers")
+            .wrap(auth::AuthMiddleware)
+            .service(
+                web::resource("")
+                    .route(web::post().to(create_supplier).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE)))
+                    .route(web::get().to(list_suppliers).wrap(RequireScope::new(SCOPE_PRODUCTS_READ)))
+            )
+            .service(
+                web::resource("/{id}")
+                    .route(web::get().to(get_supplier).wrap(RequireScope::new(SCOPE_PRODUCTS_READ)))
+                    .route(web::put().to(update_supplier).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE)))
+                    .route(web::delete().to(delete_supplier).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE)))
+            )
+            .service(web::resource("/{id}/products").route(web::get().to(list_supplier_products).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+    )
+    .service(
+        web::scope("/locations")
+            .wrap(auth::AuthMiddleware)
+            .service(
+                web::resource("")
+                    .route(web::post().to(create_location).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE)))
+                    .route(web::get().to(list_locations).wrap(RequireScope::new(SCOPE_PRODUCTS_READ)))
+            )
+            .service(
+                web::resource("/{id}")
+                    .route(web::get().to(get_location).wrap(RequireScope::new(SCOPE_PRODUCTS_READ)))
+                    .route(web::put().to(update_location).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE)))
+                    .route(web::delete().to(delete_location).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE)))
+            )
+    )
+    .service(
+        web::scope("/purchase-orders")
+            .wrap(auth::AuthMiddleware)
+            .service(
+                web::resource("")
+                    .route(web::post().to(create_purchase_order).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE)))
+                    .route(web::get().to(list_purchase_orders).wrap(RequireScope::new(SCOPE_PRODUCTS_READ)))
+            )
+            .service(web::resource("/{id}").route(web::get().to(get_purchase_order).wrap(RequireScope::new(SCOPE_PRODUCTS_READ))))
+            .service(web::resource("/{id}/receive").route(web::post().to(receive_purchase_order).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE))))
+            .service(web::resource("/{id}/cancel").route(web::post().to(cancel_purchase_order).wrap(RequireScope::new(SCOPE_PRODUCTS_WRITE))))
+    )
+    .service(
+        web::scope("/import-sources")
+            .wrap(RequireScope::new(SCOPE_PRODUCTS_IMPORT))
+            .wrap(auth::AuthMiddleware)
+            .service(
+                web::resource("")
+                    .route(web::post().to(create_import_source))
+                    .route(web::get().to(list_import_sources))
+            )
+            .service(
+                web::resource("/{id}")
+                    .route(web::get().to(get_import_source))
+                    .route(web::put().to(update_import_source))
+                    .route(web::delete().to(delete_import_source))
+            )
+            .service(web::resource("/{id}/runs").route(web::get().to(list_import_runs)))
+            .service(web::resource("/{id}/run").route(web::post().to(trigger_import_source)))
+    )
+    .service(
+        web::scope("/events")
+            .wrap(RequireScope::new(SCOPE_PRODUCTS_READ))
+            .wrap(auth::AuthMiddleware)
+            .service(web::resource("").route(web::get().to(stream_events)))
+    )
+    // Admin routes
+    .service(
+        web::scope("/admin")
+            .wrap(RequireScope::new(SCOPE_ADMIN))
+            .wrap(auth::AuthMiddleware)
+            .service(web::resource("/orders").route(web::get().to(admin_list_orders)))
+            .service(web::resource("/orders/{id}/status").route(web::put().to(admin_update_order_status)))
+            .service(web::resource("/jobs").route(web::get().to(list_jobs)))
+            .service(web::resource("/auth-events").route(web::get().to(list_auth_events)))
+            .service(web::resource("/search-analytics").route(web::get().to(search_analytics)))
+            .service(web::resource("/impersonate/{user_id}").route(web::post().to(impersonate)))
+            .service(
+                web::resource("/invites")
+                    .route(web::get().to(list_invites))
+                    .route(web::post().to(create_invite))
+            )
+            .service(
+                web::resource("/invites/{id}")
+                    .route(web::get().to(get_invite))
+                    .route(web::put().to(update_invite))
+                    .route(web::delete().to(delete_invite))
+            )
+            .service(web::resource("/invites/{id}/resend").route(web::post().to(resend_invite)))
+            .service(
+                web::resource("/search/sync")
+                    .route(web::get().to(search_sync_status))
+                    .route(web::post().to(start_search_sync))
+            )
+            .service(web::resource("/products/trash").route(web::get().to(list_trash)))
+            .service(web::resource("/products/trash/{id}/restore").route(web::post().to(restore_product)))
+            .service(web::resource("/products/trash/{id}").route(web::delete().to(purge_product)))
+            .service(web::resource("/backup").route(web::post().to(create_backup)))
+            .service(web::resource("/restore").route(web::post().to(restore_backup)))
+            .service(web::resource("/tax-rates").route(web::get().to(list_tax_rates)))
+            .service(
+                web::resource("/tax-rates/{region}/{tax_class}")
+                    .route(web::put().to(set_tax_rate))
+                    .route(web::delete().to(delete_tax_rate))
+            )
+            .service(
+                web::resource("/log-level")
+                    .route(web::get().to(get_log_level))
+                    .route(web::put().to(set_log_level))
+            )
+            .service(
+                web::resource("/maintenance")
+                    .route(web::get().to(get_maintenance))
+                    .route(web::put().to(set_maintenance))
+            )
+            .service(
+                web::resource("/flags")
+                    .route(web::get().to(list_flags))
+                    .route(web::post().to(create_flag))
+            )
+            .service(
+                web::resource("/flags/{name}")
+                    .route(web::get().to(get_flag))
+                    .route(web::put().to(update_flag))
+                    .route(web::delete().to(delete_flag))
+            )
+    );
 }
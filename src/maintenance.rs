@@ -0,0 +1,181 @@
+use std::sync::RwLock;
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::StatusCode,
+    middleware::Next,
+    web, Error, HttpResponse, ResponseError,
+};
+use mongodb::{
+    bson::{doc, DateTime},
+    options::ReplaceOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::{auth::Claims, config::MongoConfig, errors::ApiError};
+
+// Probes keep reporting on the instance while it is in maintenance
+const PROBE_PATHS: [&str; 2] = ["/ready", "/metrics"];
+
+// Route scopes under /api that stay open, so admins can still sign in and
+// turn maintenance off again
+const OPEN_SCOPES: [&str; 2] = ["admin", "auth"];
+
+const DEFAULT_MESSAGE: &str = "The API is down for maintenance, please try again later";
+
+// The single document in the settings collection holding the flag
+const SETTINGS_ID: &str = "maintenance";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceSettings {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub enabled: bool,
+    pub message: Option<String>,
+    pub updated_at: DateTime,
+    pub updated_by: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SetMaintenanceRequest {
+    pub enabled: bool,
+    /// Shown to clients instead of the default message
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MaintenanceResponse {
+    pub enabled: bool,
+    pub message: Option<String>,
+    pub updated_at: Option<String>,
+    pub updated_by: Option<String>,
+}
+
+impl From<Option<&MaintenanceSettings>> for MaintenanceResponse {
+    fn from(settings: Option<&MaintenanceSettings>) -> Self {
+        match settings {
+            Some(settings) => MaintenanceResponse {
+                enabled: settings.enabled,
+                message: settings.message.clone(),
+                updated_at: settings.updated_at.try_to_rfc3339_string().ok(),
+                updated_by: settings.updated_by.clone(),
+            },
+            None => MaintenanceResponse { enabled: false, message: None, updated_at: None, updated_by: None },
+        }
+    }
+}
+
+fn settings_collection(db: &MongoConfig) -> Collection<MaintenanceSettings> {
+    db.database.collection("settings")
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+/// The maintenance flag as last read from the database. Kept in memory so
+/// the middleware doesn't query MongoDB on every request; `refresh` picks up
+/// changes made through other instances.
+#[derive(Default)]
+pub struct MaintenanceMode {
+    settings: RwLock<Option<MaintenanceSettings>>,
+}
+
+impl MaintenanceMode {
+    pub async fn refresh(&self, db: &MongoConfig) -> Result<bool, mongodb::error::Error> {
+        let settings = settings_collection(db).find_one(doc! { "_id": SETTINGS_ID }, None).await?;
+        let enabled = settings.as_ref().is_some_and(|settings| settings.enabled);
+        *self.settings.write().unwrap() = settings;
+        Ok(enabled)
+    }
+
+    /// The message to answer with, while maintenance mode is on.
+    fn active_message(&self) -> Option<String> {
+        let settings = self.settings.read().unwrap();
+        let settings = settings.as_ref().filter(|settings| settings.enabled)?;
+        Some(settings.message.clone().unwrap_or_else(|| DEFAULT_MESSAGE.to_string()))
+    }
+}
+
+// Whether a path stays reachable during maintenance: probes, and /api/admin
+// and /api/auth in every API version
+fn is_exempt(path: &str) -> bool {
+    if PROBE_PATHS.contains(&path) {
+        return true;
+    }
+    let Some(rest) = path.strip_prefix("/api/") else { return false };
+    let mut segments = rest.split('/');
+    let mut scope = segments.next().unwrap_or_default();
+    if scope.strip_prefix('v').is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())) {
+        scope = segments.next().unwrap_or_default();
+    }
+    OPEN_SCOPES.contains(&scope)
+}
+
+/// Answers 503 while maintenance mode is on, except for probes and the
+/// admin and auth routes.
+pub async fn reject_during_maintenance(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let maintenance = req.app_data::<web::Data<MaintenanceMode>>().cloned();
+    if let Some(message) = maintenance.and_then(|maintenance| maintenance.active_message()) {
+        if !is_exempt(req.path()) {
+            let response = ApiError::new(StatusCode::SERVICE_UNAVAILABLE, message)
+                .with("maintenance", true)
+                .error_response();
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+pub async fn get_maintenance(
+    db: web::Data<MongoConfig>,
+    maintenance: web::Data<MaintenanceMode>,
+) -> Result<HttpResponse, Error> {
+    maintenance
+        .refresh(&db)
+        .await
+        .map_err(|e| db_error("Failed to fetch maintenance settings", e))?;
+    let settings = maintenance.settings.read().unwrap();
+    Ok(HttpResponse::Ok().json(MaintenanceResponse::from(settings.as_ref())))
+}
+
+/// Turns maintenance mode on or off. The flag is stored in MongoDB, so it
+/// survives restarts and reaches the other instances on their next refresh.
+pub async fn set_maintenance(
+    db: web::Data<MongoConfig>,
+    maintenance: web::Data<MaintenanceMode>,
+    claims: web::ReqData<Claims>,
+    request: web::Json<SetMaintenanceRequest>,
+) -> Result<HttpResponse, Error> {
+    let request = request.into_inner();
+    let settings = MaintenanceSettings {
+        id: SETTINGS_ID.to_string(),
+        enabled: request.enabled,
+        message: request.message.map(|m| m.trim().to_string()).filter(|m| !m.is_empty()),
+        updated_at: DateTime::now(),
+        updated_by: Some(claims.sub.clone()),
+    };
+
+    let options = ReplaceOptions::builder().upsert(true).build();
+    settings_collection(&db)
+        .replace_one(doc! { "_id": SETTINGS_ID }, &settings, options)
+        .await
+        .map_err(|e| db_error("Failed to save maintenance settings", e))?;
+
+    if settings.enabled {
+        warn!("Maintenance mode enabled by user {}", claims.sub);
+    } else {
+        warn!("Maintenance mode disabled by user {}", claims.sub);
+    }
+    let response = MaintenanceResponse::from(Some(&settings));
+    *maintenance.settings.write().unwrap() = Some(settings);
+    Ok(HttpResponse::Ok().json(response))
+}
@@ -1,7 +1,11 @@
-use mongodb::bson::{oid::ObjectId, Document};
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime as ChronoDateTime, Utc};
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{collections::BTreeMap, fmt, str::FromStr};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+use crate::money::{self, Decimal};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum Category {
     Electronics,
@@ -11,40 +15,377 @@ pub enum Category {
     Other,
 }
 
-impl ToString for Category {
-    fn to_string(&self) -> String {
-        match self {
-            Category::Electronics => "electronics".to_string(),
-            Category::Clothing => "clothing".to_string(),
-            Category::Food => "food".to_string(),
-            Category::Books => "books".to_string(),
-            Category::Other => "other".to_string(),
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Category::Electronics => "electronics",
+            Category::Clothing => "clothing",
+            Category::Food => "food",
+            Category::Books => "books",
+            Category::Other => "other",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Category {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "electronics" => Ok(Category::Electronics),
+            "clothing" => Ok(Category::Clothing),
+            "food" => Ok(Category::Food),
+            "books" => Ok(Category::Books),
+            "other" => Ok(Category::Other),
+            other => Err(format!("Unknown category: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ProductStatus {
+    Draft,
+    // Documents created before the status field existed are active
+    #[default]
+    Active,
+    Archived,
+}
+
+impl fmt::Display for ProductStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ProductStatus::Draft => "draft",
+            ProductStatus::Active => "active",
+            ProductStatus::Archived => "archived",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for ProductStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "draft" => Ok(ProductStatus::Draft),
+            "active" => Ok(ProductStatus::Active),
+            "archived" => Ok(ProductStatus::Archived),
+            other => Err(format!("Unknown status: {}", other)),
+        }
+    }
+}
+
+/// VAT class of a product; the rate for each class is configured per region.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TaxClass {
+    #[default]
+    Standard,
+    Reduced,
+    SuperReduced,
+    Zero,
+    Exempt,
+}
+
+impl fmt::Display for TaxClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TaxClass::Standard => "standard",
+            TaxClass::Reduced => "reduced",
+            TaxClass::SuperReduced => "super_reduced",
+            TaxClass::Zero => "zero",
+            TaxClass::Exempt => "exempt",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for TaxClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "standard" => Ok(TaxClass::Standard),
+            "reduced" => Ok(TaxClass::Reduced),
+            "super_reduced" => Ok(TaxClass::SuperReduced),
+            "zero" => Ok(TaxClass::Zero),
+            "exempt" => Ok(TaxClass::Exempt),
+            other => Err(format!("Unknown tax class: {}", other)),
+        }
+    }
+}
+
+/// Unit a product is sold and priced in.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Unit {
+    #[default]
+    Piece,
+    Kg,
+    Liter,
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Unit::Piece => "piece",
+            Unit::Kg => "kg",
+            Unit::Liter => "liter",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Unit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "piece" => Ok(Unit::Piece),
+            "kg" => Ok(Unit::Kg),
+            "liter" => Ok(Unit::Liter),
+            other => Err(format!("Unknown unit: {}", other)),
         }
     }
 }
 
+/// Lower unit price from a quantity on, e.g. 10+ at 4.50 each.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PriceTier {
+    pub min_quantity: i64,
+    #[serde(with = "money::price")]
+    pub price: Decimal,
+}
+
+// A price tier as clients send it, its price read with money::input_price
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PriceTierInput {
+    min_quantity: i64,
+    #[serde(with = "money::input_price")]
+    price: Decimal,
+}
+
+// `price_tiers` of product requests
+fn input_tiers<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<PriceTier>>, D::Error> {
+    let tiers = Option::<Vec<PriceTierInput>>::deserialize(deserializer)?;
+    Ok(tiers.map(|tiers| {
+        tiers.into_iter().map(|tier| PriceTier { min_quantity: tier.min_quantity, price: tier.price }).collect()
+    }))
+}
+
+/// How a bundle's price follows from its components.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BundlePricing {
+    // The components' prices times their quantities
+    Sum,
+    // The bundle product's own price, set like any other
+    Fixed,
+    // The sum, less discount_percent
+    Discounted,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BundleComponent {
+    pub product_id: ObjectId,
+    pub quantity: i64,
+}
+
+/// What a bundle product, e.g. a gift basket, is assembled from.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Bundle {
+    pub components: Vec<BundleComponent>,
+    pub pricing: BundlePricing,
+    #[serde(default, with = "money::option_price", skip_serializing_if = "Option::is_none")]
+    pub discount_percent: Option<Decimal>,
+}
+
+/// A custom attribute value on a product, e.g. a voltage or a size.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum AttributeValue {
+    Boolean(bool),
+    Number(f64),
+    Text(String),
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Product {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
+    // Stable ID for external systems, accepted wherever a product ID is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_id: Option<String>,
     pub name: String,
-    pub price: f64,
+    // URL-friendly unique name, for storefront links
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slug: Option<String>,
+    // Slugs from before the product was renamed; they redirect to the current one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub previous_slugs: Vec<String>,
+    // Trigrams of the name for fuzzy search, kept in step with the name
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub search_grams: Vec<String>,
+    // The merchant's own article number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sku: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(with = "money::price")]
+    pub price: Decimal,
     pub category: Category,
+    #[serde(default)]
+    pub status: ProductStatus,
+    // Products created before tax classes existed are taxed at the standard rate
+    #[serde(default)]
+    pub tax_class: TaxClass,
+    #[serde(default)]
+    pub unit: Unit,
+    // Comparison price per kg or liter, for products sold in packs
+    #[serde(default, with = "money::option_price", skip_serializing_if = "Option::is_none")]
+    pub price_per_unit: Option<Decimal>,
+    // Quantity discounts, ordered by min_quantity
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub price_tiers: Vec<PriceTier>,
     pub has_active_sale: bool,
+    // When set, the sale is switched off by the scheduler after this time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sale_ends_at: Option<DateTime>,
+    // Units on hand; products without it are not stock-tracked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stock_quantity: Option<i64>,
+    // Stock level at which the product is reported as low on stock
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub low_stock_threshold: Option<i64>,
+    // EAN-13, with UPC-A codes stored in their 13-digit form
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub barcode: Option<String>,
+    // Checked against the category's attribute definitions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<BTreeMap<String, AttributeValue>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub supplier_id: Option<ObjectId>,
+    // The supplier's own article number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub supplier_sku: Option<String>,
+    // Purchase price from the supplier, for margin reporting
+    #[serde(default, with = "money::option_price", skip_serializing_if = "Option::is_none")]
+    pub cost_price: Option<Decimal>,
+    // The import that created the product, so the import can be rolled back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub import_id: Option<ObjectId>,
+    // Set on bundles; their stock is whatever their components allow
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bundle: Option<Bundle>,
+    // Only shown to customers of COMPLIANCE_MINIMUM_AGE or older
+    #[serde(default)]
+    pub age_restricted: bool,
+    // Dangerous goods, e.g. lithium batteries, with their own shipping rules
+    #[serde(default)]
+    pub hazardous: bool,
+    // Regions the product may not be sold in, e.g. "DE" or "US-CA"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub region_restrictions: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct CreateProductRequest {
     pub name: String,
-    pub price: f64,
+    pub sku: Option<String>,
+    pub description: Option<String>,
+    #[serde(with = "money::input_price")]
+    pub price: Decimal,
     pub category: Category,
+    pub status: Option<ProductStatus>,
+    pub tax_class: Option<TaxClass>,
+    pub unit: Option<Unit>,
+    #[serde(default, with = "money::option_input_price")]
+    pub price_per_unit: Option<Decimal>,
+    #[serde(default, deserialize_with = "input_tiers")]
+    pub price_tiers: Option<Vec<PriceTier>>,
     pub has_active_sale: bool,
+    pub sale_ends_at: Option<ChronoDateTime<Utc>>,
+    pub stock_quantity: Option<i64>,
+    pub low_stock_threshold: Option<i64>,
+    pub barcode: Option<String>,
+    pub attributes: Option<BTreeMap<String, AttributeValue>>,
+    pub supplier_id: Option<String>,
+    pub supplier_sku: Option<String>,
+    #[serde(default, with = "money::option_input_price")]
+    pub cost_price: Option<Decimal>,
+    #[serde(default)]
+    pub age_restricted: bool,
+    #[serde(default)]
+    pub hazardous: bool,
+    pub region_restrictions: Option<Vec<String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl CreateProductRequest {
+    /// A request with just the required fields, for products created
+    /// other than through the JSON API, e.g. by an import.
+    pub fn new(name: String, price: Decimal, category: Category) -> Self {
+        CreateProductRequest {
+            name,
+            sku: None,
+            description: None,
+            price,
+            category,
+            status: None,
+            tax_class: None,
+            unit: None,
+            price_per_unit: None,
+            price_tiers: None,
+            has_active_sale: false,
+            sale_ends_at: None,
+            stock_quantity: None,
+            low_stock_threshold: None,
+            barcode: None,
+            attributes: None,
+            supplier_id: None,
+            supplier_sku: None,
+            cost_price: None,
+            age_restricted: false,
+            hazardous: false,
+            region_restrictions: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct UpdateProductRequest {
     pub name: Option<String>,
-    pub price: Option<f64>,
+    pub sku: Option<String>,
+    pub description: Option<String>,
+    #[serde(default, with = "money::option_input_price")]
+    pub price: Option<Decimal>,
     pub category: Option<Category>,
+    pub tax_class: Option<TaxClass>,
+    pub unit: Option<Unit>,
+    #[serde(default, with = "money::option_input_price")]
+    pub price_per_unit: Option<Decimal>,
+    // Replaces all tiers; an empty list removes quantity pricing
+    #[serde(default, deserialize_with = "input_tiers")]
+    pub price_tiers: Option<Vec<PriceTier>>,
     pub has_active_sale: Option<bool>,
+    pub sale_ends_at: Option<ChronoDateTime<Utc>>,
+    pub stock_quantity: Option<i64>,
+    pub low_stock_threshold: Option<i64>,
+    pub barcode: Option<String>,
+    // Replaces all attributes of the product
+    pub attributes: Option<BTreeMap<String, AttributeValue>>,
+    // An empty string unlinks the product from its supplier
+    pub supplier_id: Option<String>,
+    pub supplier_sku: Option<String>,
+    #[serde(default, with = "money::option_input_price")]
+    pub cost_price: Option<Decimal>,
+    pub age_restricted: Option<bool>,
+    pub hazardous: Option<bool>,
+    // Replaces all restrictions; an empty list lifts them
+    pub region_restrictions: Option<Vec<String>>,
 }
@@ -0,0 +1,173 @@
+use std::{str::FromStr, sync::OnceLock};
+
+use mongodb::bson::{Bson, Decimal128};
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+pub use rust_decimal::Decimal;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::config::{MoneyConfig, PriceJsonFormat};
+
+// Prices have at most this many decimal places. Input with more is refused;
+// doubles stored before prices were decimal are rounded to it, which drops
+// binary noise like the ...0000002 in 19.990000000000002
+const SCALE: u32 = 4;
+
+// Significant digits an f64 always keeps. A number written with more may
+// not be the one that arrived
+const EXACT_FLOAT_DIGITS: usize = 15;
+
+static JSON_FORMAT: OnceLock<PriceJsonFormat> = OnceLock::new();
+
+/// Sets PRICE_JSON_FORMAT from the loaded configuration. Runs once at startup.
+pub fn init(config: &MoneyConfig) {
+    JSON_FORMAT.get_or_init(|| config.json_format);
+}
+
+// PRICE_JSON_FORMAT=string writes prices as JSON strings; numbers otherwise
+fn json_format() -> PriceJsonFormat {
+    *JSON_FORMAT.get().expect("money::init runs at startup")
+}
+
+pub fn from_f64(value: f64) -> Option<Decimal> {
+    Decimal::from_f64(value).map(|d| d.round_dp(SCALE).normalize())
+}
+
+// A price given by a client, refused rather than rounded when it has more
+// than four decimal places
+fn check_scale(value: Decimal) -> Result<Decimal, String> {
+    if value.normalize().scale() > SCALE {
+        return Err(format!("invalid price: {} has more than {} decimal places", value, SCALE));
+    }
+    Ok(value)
+}
+
+/// A price a client sent as a number: the decimal it was written as, which
+/// is the shortest one that reads back as `value`. Numbers with more digits
+/// than an f64 keeps are refused, since digits may have been lost.
+pub fn from_input_f64(value: f64) -> Result<Decimal, String> {
+    let text = value.to_string();
+    let significant = text.trim_start_matches('-').replace('.', "").trim_matches('0').len();
+    if significant > EXACT_FLOAT_DIGITS {
+        return Err(format!("invalid price: {} has too many digits for a number, send it as a string", text));
+    }
+    parse(&text).ok_or_else(|| format!("invalid price: {}", text)).and_then(check_scale)
+}
+
+// A price from JSON or a query string, exactly as written
+fn from_input(value: &Bson) -> Result<Decimal, String> {
+    match value {
+        Bson::Double(value) => from_input_f64(*value),
+        other => from_bson(other).ok_or_else(|| format!("invalid price: {}", other)).and_then(check_scale),
+    }
+}
+
+pub fn to_f64(value: Decimal) -> f64 {
+    value.to_f64().unwrap_or_default()
+}
+
+/// Parses a price written out in plain or scientific notation.
+pub fn parse(value: &str) -> Option<Decimal> {
+    let value = value.trim();
+    Decimal::from_str(value).or_else(|_| Decimal::from_scientific(value)).ok()
+}
+
+/// The price as it appears in JSON responses.
+pub fn to_json(value: Decimal) -> serde_json::Value {
+    match json_format() {
+        PriceJsonFormat::Number => serde_json::json!(to_f64(value)),
+        PriceJsonFormat::String => serde_json::Value::String(value.to_string()),
+    }
+}
+
+pub fn to_bson(value: Decimal) -> Bson {
+    match Decimal128::from_str(&value.to_string()) {
+        Ok(decimal) => Bson::Decimal128(decimal),
+        Err(_) => Bson::Double(to_f64(value)),
+    }
+}
+
+/// Reads a price stored as Decimal128, or as a double or integer from before
+/// prices were decimal.
+pub fn from_bson(value: &Bson) -> Option<Decimal> {
+    match value {
+        Bson::Decimal128(decimal) => parse(&decimal.to_string()),
+        Bson::Double(value) => from_f64(*value),
+        Bson::Int32(value) => Some(Decimal::from(*value)),
+        Bson::Int64(value) => Some(Decimal::from(*value)),
+        Bson::String(value) => parse(value),
+        _ => None,
+    }
+}
+
+/// Serde adapter for prices: Decimal128 in BSON and a number, or a string
+/// with PRICE_JSON_FORMAT=string, in JSON. Accepts any numeric value or a
+/// numeric string.
+pub mod price {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &Decimal, serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return to_bson(*value).serialize(serializer);
+        }
+        to_json(*value).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+        let value = Bson::deserialize(deserializer)?;
+        from_bson(&value).ok_or_else(|| D::Error::custom(format!("invalid price: {}", value)))
+    }
+}
+
+/// `price` for optional fields; use with `#[serde(default)]`.
+pub mod option_price {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &Option<Decimal>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => price::serialize(value, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Decimal>, D::Error> {
+        match Option::<Bson>::deserialize(deserializer)? {
+            None | Some(Bson::Null) => Ok(None),
+            Some(value) => from_bson(&value)
+                .map(Some)
+                .ok_or_else(|| D::Error::custom(format!("invalid price: {}", value))),
+        }
+    }
+}
+
+/// `price` for prices clients send: read from JSON or a query string exactly
+/// as written, and refused with more than four decimal places. Stored
+/// documents are read like `price`.
+pub mod input_price {
+    use super::*;
+
+    pub use super::price::serialize;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+        if !deserializer.is_human_readable() {
+            return price::deserialize(deserializer);
+        }
+        from_input(&Bson::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// `input_price` for optional fields; use with `#[serde(default)]`.
+pub mod option_input_price {
+    use super::*;
+
+    pub use super::option_price::serialize;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Decimal>, D::Error> {
+        if !deserializer.is_human_readable() {
+            return option_price::deserialize(deserializer);
+        }
+        match Option::<Bson>::deserialize(deserializer)? {
+            None | Some(Bson::Null) => Ok(None),
+            Some(value) => from_input(&value).map(Some).map_err(D::Error::custom),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::{io, sync::Arc, time::Duration};
+
+use futures::future::BoxFuture;
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    sync::Mutex,
+    time::timeout,
+};
+use tracing::{debug, info, warn};
+
+use crate::{event_bus::EventBus, settings::url_credentials};
+
+const DEFAULT_PORT: u16 = 4222;
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Publishes to a NATS server over its plain-text client protocol. Messages
+/// are fire-and-forget, matching core NATS delivery.
+pub struct NatsBus {
+    address: String,
+    user: Option<String>,
+    password: Option<String>,
+    // Shared with the task answering the server's keep-alive pings
+    writer: Arc<Mutex<Option<OwnedWriteHalf>>>,
+}
+
+fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl NatsBus {
+    pub fn from_url(url: &str) -> Result<Self, String> {
+        let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+        let host = url.host_str().ok_or("missing host")?;
+        let (user, password) = url_credentials(&url)?;
+        Ok(NatsBus {
+            address: format!("{}:{}", host, url.port().unwrap_or(DEFAULT_PORT)),
+            user,
+            password,
+            writer: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    // Handshake: read INFO, send CONNECT, and wait for the PONG so bad
+    // credentials fail here rather than silently dropping messages
+    async fn connect(&self) -> io::Result<OwnedWriteHalf> {
+        let stream = TcpStream::connect(&self.address).await?;
+        let (read, mut write) = stream.into_split();
+        let mut reader = BufReader::new(read);
+
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        if !line.starts_with("INFO") {
+            return Err(protocol_error(format!("unexpected greeting: {}", line.trim())));
+        }
+
+        let options = json!({
+            "verbose": false,
+            "pedantic": false,
+            "name": env!("CARGO_PKG_NAME"),
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "user": self.user,
+            "pass": self.password,
+        });
+        write.write_all(format!("CONNECT {}\r\nPING\r\n", options).as_bytes()).await?;
+
+        line.clear();
+        reader.read_line(&mut line).await?;
+        if !line.starts_with("PONG") {
+            return Err(protocol_error(format!("connection refused: {}", line.trim())));
+        }
+
+        let writer = self.writer.clone();
+        tokio::spawn(async move {
+            let mut line = String::new();
+            loop {
+                line.clear();
+                match reader.read_line(&mut line).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) if line.starts_with("PING") => {
+                        if let Some(write) = writer.lock().await.as_mut() {
+                            let _ = write.write_all(b"PONG\r\n").await;
+                        }
+                    }
+                    Ok(_) if line.starts_with("-ERR") => warn!("NATS server error: {}", line.trim()),
+                    Ok(_) => {}
+                }
+            }
+            debug!("NATS connection closed");
+        });
+
+        info!("Connected to NATS at {}", self.address);
+        Ok(write)
+    }
+
+    // Reconnects once if the connection broke, so a server restart costs at most a message
+    async fn send(&self, frame: &[u8]) -> io::Result<()> {
+        let mut writer = self.writer.lock().await;
+        if let Some(write) = writer.as_mut() {
+            match timeout(IO_TIMEOUT, write.write_all(frame)).await {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(e)) => debug!("NATS write failed, reconnecting: {}", e),
+                Err(_) => debug!("NATS write timed out, reconnecting"),
+            }
+            *writer = None;
+        }
+
+        let mut write = timeout(IO_TIMEOUT, self.connect()).await??;
+        timeout(IO_TIMEOUT, write.write_all(frame)).await??;
+        *writer = Some(write);
+        Ok(())
+    }
+}
+
+impl EventBus for NatsBus {
+    fn name(&self) -> &'static str {
+        "NATS"
+    }
+
+    fn publish<'a>(&'a self, subject: &'a str, payload: &'a [u8]) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let mut frame = format!("PUB {} {}\r\n", subject, payload.len()).into_bytes();
+            frame.extend_from_slice(payload);
+            frame.extend_from_slice(b"\r\n");
+            self.send(&frame).await.map_err(|e| e.to_string())
+        })
+    }
+}
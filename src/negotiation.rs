@@ -0,0 +1,76 @@
+use actix_web::{
+    body::BoxBody,
+    http::{
+        header::{self, Accept, Header, HeaderValue},
+        StatusCode,
+    },
+    HttpRequest, HttpResponse, Responder, ResponseError,
+};
+use serde::Serialize;
+use tracing::error;
+
+use crate::errors::ApiError;
+
+/// Representation a client asked for in its Accept header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    /// The supported type the client ranks highest; JSON when the header is
+    /// missing or names nothing we offer.
+    pub fn of(req: &HttpRequest) -> Self {
+        let Ok(accept) = Accept::parse(req) else {
+            return Format::Json;
+        };
+        for mime in accept.ranked() {
+            match mime.essence_str() {
+                "text/csv" => return Format::Csv,
+                "application/json" | "application/*" | "*/*" => return Format::Json,
+                _ => {}
+            }
+        }
+        Format::Json
+    }
+}
+
+/// A response body that can also be written as CSV rows.
+pub trait Tabular {
+    fn header() -> &'static [&'static str];
+    fn rows(&self) -> Vec<Vec<String>>;
+}
+
+fn csv_body<T: Tabular>(value: &T) -> Result<Vec<u8>, csv::Error> {
+    let mut wtr = csv::Writer::from_writer(Vec::new());
+    wtr.write_record(T::header())?;
+    for row in value.rows() {
+        wtr.write_record(row)?;
+    }
+    wtr.into_inner().map_err(|e| e.into_error().into())
+}
+
+/// Answers with JSON or CSV depending on the request's Accept header, so one
+/// handler serves both.
+pub struct Negotiated<T>(pub T);
+
+impl<T: Serialize + Tabular> Responder for Negotiated<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        let mut response = match Format::of(req) {
+            Format::Json => HttpResponse::Ok().json(&self.0),
+            Format::Csv => match csv_body(&self.0) {
+                Ok(body) => HttpResponse::Ok().content_type("text/csv; charset=utf-8").body(body),
+                Err(e) => {
+                    error!("Failed to write CSV response: {}", e);
+                    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to write CSV").error_response()
+                }
+            },
+        };
+        // Caches must keep the two representations apart
+        response.headers_mut().insert(header::VARY, HeaderValue::from_static("Accept"));
+        response
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::BTreeMap;
+
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime},
+    options::{FindOptions, UpdateOptions},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, error, info, warn};
+use validator::Validate;
+
+use crate::{
+    auth::Claims,
+    config::{LimitsConfig, MongoConfig},
+    errors::ApiError,
+    events::{DomainEvent, EventHub},
+    validation::{page_offset, ValidatedQuery},
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    ImportCompleted,
+    LowStock,
+}
+
+const KINDS: [NotificationKind; 2] = [NotificationKind::ImportCompleted, NotificationKind::LowStock];
+
+impl NotificationKind {
+    fn key(self) -> &'static str {
+        match self {
+            NotificationKind::ImportCompleted => "import_completed",
+            NotificationKind::LowStock => "low_stock",
+        }
+    }
+
+    // Users hear about their own imports unless they opt out; low-stock
+    // alerts concern the whole catalog, so only those who ask get them
+    fn enabled_by_default(self) -> bool {
+        match self {
+            NotificationKind::ImportCompleted => true,
+            NotificationKind::LowStock => false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Notification {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    pub kind: NotificationKind,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub import_id: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_at: Option<DateTime>,
+    pub created_at: DateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NotificationResponse {
+    pub id: String,
+    pub kind: NotificationKind,
+    pub message: String,
+    pub product_id: Option<String>,
+    pub import_id: Option<String>,
+    pub read: bool,
+    pub read_at: Option<String>,
+    pub created_at: String,
+}
+
+impl From<&Notification> for NotificationResponse {
+    fn from(notification: &Notification) -> Self {
+        NotificationResponse {
+            id: notification.id.map(|id| id.to_string()).unwrap_or_default(),
+            kind: notification.kind,
+            message: notification.message.clone(),
+            product_id: notification.product_id.clone(),
+            import_id: notification.import_id.map(|id| id.to_string()),
+            read: notification.read_at.is_some(),
+            read_at: notification.read_at.and_then(|at| at.try_to_rfc3339_string().ok()),
+            created_at: notification.created_at.try_to_rfc3339_string().unwrap_or_default(),
+        }
+    }
+}
+
+/// Which kinds of notifications a user wants, where they differ from the defaults.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NotificationPreferences {
+    #[serde(rename = "_id")]
+    pub user_id: ObjectId,
+    // Keyed by kind, e.g. "low_stock" => true
+    #[serde(default)]
+    pub kinds: BTreeMap<String, bool>,
+    pub updated_at: DateTime,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ListNotificationsQuery {
+    #[validate(range(min = 1, message = "page must be at least 1"))]
+    page: Option<i64>,
+    #[validate(range(min = 1, message = "per_page must be at least 1"))]
+    per_page: Option<i64>,
+    // Only notifications not yet marked read
+    #[serde(default)]
+    unread: bool,
+}
+
+fn notifications_collection(db: &MongoConfig) -> Collection<Notification> {
+    db.database.collection("notifications")
+}
+
+fn preferences_collection(db: &MongoConfig) -> Collection<NotificationPreferences> {
+    db.database.collection("notification_preferences")
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+// Every kind with whether it is on, defaults filled in
+fn effective(preferences: Option<&NotificationPreferences>) -> BTreeMap<&'static str, bool> {
+    KINDS
+        .iter()
+        .map(|kind| {
+            let enabled = preferences
+                .and_then(|preferences| preferences.kinds.get(kind.key()))
+                .copied()
+                .unwrap_or(kind.enabled_by_default());
+            (kind.key(), enabled)
+        })
+        .collect()
+}
+
+impl Notification {
+    pub fn new(user_id: ObjectId, kind: NotificationKind, message: String) -> Self {
+        Notification {
+            id: None,
+            user_id,
+            kind,
+            message,
+            product_id: None,
+            import_id: None,
+            read_at: None,
+            created_at: DateTime::now(),
+        }
+    }
+
+    pub fn import(mut self, import_id: ObjectId) -> Self {
+        self.import_id = Some(import_id);
+        self
+    }
+
+    pub fn product(mut self, product_id: &str) -> Self {
+        self.product_id = Some(product_id.to_string());
+        self
+    }
+}
+
+/// Stores a notification for its user, unless they turned that kind off.
+/// Best effort: whatever triggered it does not fail when this does.
+pub async fn notify(db: &MongoConfig, notification: Notification) {
+    let preferences = match preferences_collection(db).find_one(doc! { "_id": notification.user_id }, None).await {
+        Ok(preferences) => preferences,
+        Err(e) => {
+            warn!("Failed to load notification preferences of {}: {}", notification.user_id, e);
+            None
+        }
+    };
+    if !effective(preferences.as_ref())[notification.kind.key()] {
+        debug!("User {} has {:?} notifications turned off", notification.user_id, notification.kind);
+        return;
+    }
+    if let Err(e) = notifications_collection(db).insert_one(&notification, None).await {
+        warn!("Failed to store notification for {}: {}", notification.user_id, e);
+    }
+}
+
+// Low-stock alerts go to every user who opted in
+async fn notify_low_stock(
+    db: &MongoConfig,
+    product_id: &str,
+    name: &str,
+    stock_quantity: i64,
+) -> Result<usize, mongodb::error::Error> {
+    let filter = doc! { format!("kinds.{}", NotificationKind::LowStock.key()): true };
+    let subscribers: Vec<NotificationPreferences> =
+        preferences_collection(db).find(filter, None).await?.try_collect().await?;
+    if subscribers.is_empty() {
+        return Ok(0);
+    }
+
+    let message = format!("{} is low on stock ({} left)", name, stock_quantity);
+    let notifications: Vec<Notification> = subscribers
+        .iter()
+        .map(|subscriber| {
+            Notification::new(subscriber.user_id, NotificationKind::LowStock, message.clone()).product(product_id)
+        })
+        .collect();
+    notifications_collection(db).insert_many(&notifications, None).await?;
+    Ok(notifications.len())
+}
+
+/// Turns domain events into notifications until the hub closes.
+pub fn spawn_notifier(events: &EventHub, db: web::Data<MongoConfig>) {
+    let mut receiver = events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Notifier lagged, dropped {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            if let DomainEvent::LowStock { product_id, name, stock_quantity, .. } = &event {
+                match notify_low_stock(&db, product_id, name, *stock_quantity).await {
+                    Ok(notified) => debug!("Notified {} users of low stock on {}", notified, product_id),
+                    Err(e) => warn!("Failed to notify users of low stock on {}: {}", product_id, e),
+                }
+            }
+        }
+    });
+}
+
+/// The caller's notifications, newest first.
+pub async fn list_notifications(
+    db: web::Data<MongoConfig>,
+    limits: web::Data<LimitsConfig>,
+    claims: web::ReqData<Claims>,
+    query: ValidatedQuery<ListNotificationsQuery>,
+) -> Result<HttpResponse, Error> {
+    let per_page = query.per_page.unwrap_or(20);
+    if per_page > limits.max_per_page {
+        let message = format!("per_page must be between 1 and {}", limits.max_per_page);
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, message).into());
+    }
+    let page = query.page.unwrap_or(1);
+
+    let mut filter = doc! { "user_id": claims.user_id()? };
+    if query.unread {
+        filter.insert("read_at", doc! { "$exists": false });
+    }
+
+    let options = FindOptions::builder()
+        .sort(doc! { "created_at": -1 })
+        .skip(page_offset(page, per_page)? as u64)
+        .limit(per_page)
+        .build();
+    let notifications: Vec<Notification> = notifications_collection(&db)
+        .find(filter, options)
+        .await
+        .map_err(|e| db_error("Failed to fetch notifications", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating notifications", e))?;
+
+    let notifications: Vec<NotificationResponse> = notifications.iter().map(NotificationResponse::from).collect();
+    Ok(HttpResponse::Ok().json(notifications))
+}
+
+pub async fn mark_notification_read(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let notification_id = ObjectId::parse_str(id.as_str()).map_err(|_| {
+        error!("Invalid notification ID format: {}", id);
+        ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format")
+    })?;
+
+    // Marking an already read notification again keeps its original read_at
+    let result = notifications_collection(&db)
+        .update_one(
+            doc! { "_id": notification_id, "user_id": claims.user_id()? },
+            vec![doc! { "$set": { "read_at": { "$ifNull": ["$read_at", "$$NOW"] } } }],
+            None,
+        )
+        .await
+        .map_err(|e| db_error("Failed to mark notification read", e))?;
+
+    if result.matched_count == 0 {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn mark_all_notifications_read(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+) -> Result<HttpResponse, Error> {
+    let result = notifications_collection(&db)
+        .update_many(
+            doc! { "user_id": claims.user_id()?, "read_at": { "$exists": false } },
+            doc! { "$set": { "read_at": DateTime::now() } },
+            None,
+        )
+        .await
+        .map_err(|e| db_error("Failed to mark notifications read", e))?;
+
+    Ok(HttpResponse::Ok().json(doc! { "marked_read": result.modified_count as i64 }))
+}
+
+pub async fn get_notification_preferences(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+) -> Result<HttpResponse, Error> {
+    let preferences = preferences_collection(&db)
+        .find_one(doc! { "_id": claims.user_id()? }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch notification preferences", e))?;
+
+    Ok(HttpResponse::Ok().json(effective(preferences.as_ref())))
+}
+
+/// Turns kinds of notifications on or off, e.g. `{ "low_stock": true }`.
+/// Kinds left out keep their setting.
+pub async fn update_notification_preferences(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    request: web::Json<BTreeMap<String, bool>>,
+) -> Result<HttpResponse, Error> {
+    let user_id = claims.user_id()?;
+
+    let mut update = doc! { "updated_at": DateTime::now() };
+    for (key, enabled) in request.iter() {
+        if !KINDS.iter().any(|kind| kind.key() == key) {
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, format!("Unknown notification kind: {}", key)).into());
+        }
+        update.insert(format!("kinds.{}", key), *enabled);
+    }
+
+    let options = UpdateOptions::builder().upsert(true).build();
+    preferences_collection(&db)
+        .update_one(doc! { "_id": user_id }, doc! { "$set": update }, options)
+        .await
+        .map_err(|e| db_error("Failed to save notification preferences", e))?;
+
+    info!("User {} updated their notification preferences", user_id);
+    let preferences = preferences_collection(&db)
+        .find_one(doc! { "_id": user_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch notification preferences", e))?;
+    Ok(HttpResponse::Ok().json(effective(preferences.as_ref())))
+}
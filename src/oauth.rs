@@ -0,0 +1,354 @@
+use actix_web::{
+    cookie::{time, Cookie, SameSite},
+    http::StatusCode,
+    web, HttpRequest, HttpResponse, Error,
+};
+use chrono::{Duration, Utc};
+use jsonwebtoken::{encode, decode, Header, EncodingKey, DecodingKey, Validation};
+use mongodb::{Collection, bson::{doc, to_bson}};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+
+use crate::{
+    auth::{default_scopes, jwt_secret, AuthResponse, ExternalIdentity, User, UserResponse},
+    auth_events::{AuthEvent, AuthEventKind},
+    config::{InviteConfig, MongoConfig, OAuthConfig, OAuthProviderConfig},
+    errors::ApiError,
+    feeds::token_matches,
+    invites, pii, sessions, two_factor,
+};
+
+// Registered identity providers plus a shared HTTP client for talking to them
+pub struct OAuthProviders {
+    providers: std::collections::HashMap<String, OAuthProviderConfig>,
+    http: reqwest::Client,
+}
+
+impl OAuthProviders {
+    pub fn new(config: OAuthConfig) -> Self {
+        for name in config.providers.keys() {
+            info!("Registered OAuth provider: {}", name);
+        }
+        OAuthProviders {
+            providers: config.providers,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&OAuthProviderConfig> {
+        self.providers.get(name)
+    }
+}
+
+// Cookie holding the state's nonce, so a callback is only accepted in the
+// browser that started the login
+const STATE_COOKIE: &str = "oauth_state";
+const STATE_TTL_MINUTES: i64 = 10;
+
+// Signed, short-lived state parameter protecting the callback against CSRF
+#[derive(Debug, Serialize, Deserialize)]
+struct OAuthState {
+    provider: String,
+    nonce: String,
+    exp: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+// Standard OpenID Connect userinfo claims
+#[derive(Debug, Deserialize)]
+struct UserInfo {
+    sub: String,
+    email: Option<String>,
+    email_verified: Option<bool>,
+    given_name: Option<String>,
+    family_name: Option<String>,
+}
+
+pub async fn oauth_authorize(
+    providers: web::Data<OAuthProviders>,
+    provider: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let config = providers.get(provider.as_str()).ok_or_else(|| {
+        debug!("Unknown OAuth provider: {}", provider);
+        ApiError::new(StatusCode::NOT_FOUND, "Unknown identity provider")
+    })?;
+
+    let nonce: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+
+    let state = encode(
+        &Header::default(),
+        &OAuthState {
+            provider: config.name.clone(),
+            nonce: nonce.clone(),
+            exp: (Utc::now() + Duration::minutes(STATE_TTL_MINUTES)).timestamp(),
+        },
+        &EncodingKey::from_secret(jwt_secret()),
+    ).map_err(|e| {
+        error!("Failed to sign OAuth state: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to start login")
+    })?;
+
+    let url = reqwest::Url::parse_with_params(
+        &config.auth_url,
+        &[
+            ("response_type", "code"),
+            ("client_id", config.client_id.as_str()),
+            ("redirect_uri", config.redirect_uri.as_str()),
+            ("scope", config.scopes.as_str()),
+            ("state", state.as_str()),
+        ],
+    ).map_err(|e| {
+        error!("Invalid authorization URL for provider {}: {}", config.name, e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Invalid provider configuration")
+    })?;
+
+    // Lax, not Strict: the provider sends the browser back with a cross-site redirect
+    let cookie = Cookie::build(STATE_COOKIE, nonce)
+        .path(callback_path(config))
+        .http_only(true)
+        .secure(config.redirect_uri.starts_with("https://"))
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::minutes(STATE_TTL_MINUTES))
+        .finish();
+
+    Ok(HttpResponse::Found()
+        .insert_header(("Location", url.to_string()))
+        .cookie(cookie)
+        .finish())
+}
+
+// The state cookie is only sent to the provider's callback
+fn callback_path(config: &OAuthProviderConfig) -> String {
+    reqwest::Url::parse(&config.redirect_uri)
+        .map(|url| url.path().to_string())
+        .unwrap_or_else(|_| "/".to_string())
+}
+
+// Expires the state cookie once the login it was issued for is complete
+fn used_state_cookie(config: &OAuthProviderConfig) -> Cookie<'static> {
+    let mut cookie = Cookie::build(STATE_COOKIE, "").path(callback_path(config)).finish();
+    cookie.make_removal();
+    cookie
+}
+
+pub async fn oauth_callback(
+    req: HttpRequest,
+    db: web::Data<MongoConfig>,
+    providers: web::Data<OAuthProviders>,
+    invite_config: web::Data<InviteConfig>,
+    provider: web::Path<String>,
+    query: web::Query<CallbackQuery>,
+) -> Result<HttpResponse, Error> {
+    let config = providers.get(provider.as_str()).ok_or_else(|| {
+        debug!("Unknown OAuth provider: {}", provider);
+        ApiError::new(StatusCode::NOT_FOUND, "Unknown identity provider")
+    })?;
+
+    if let Some(err) = &query.error {
+        debug!("Provider {} returned error: {}", config.name, err);
+        return Err(ApiError::new(StatusCode::UNAUTHORIZED, format!("Login was not completed: {}", err)).into());
+    }
+
+    let (code, state) = match (&query.code, &query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return Err(ApiError::new(StatusCode::BAD_REQUEST, "Missing code or state parameter").into()),
+    };
+
+    // Verify the state we issued in the authorize step, and that it was
+    // issued to this browser rather than planted from someone else's login
+    let browser_nonce = req.cookie(STATE_COOKIE);
+    match decode::<OAuthState>(state, &DecodingKey::from_secret(jwt_secret()), &Validation::default()) {
+        Ok(data)
+            if data.claims.provider == config.name
+                && browser_nonce.is_some_and(|cookie| token_matches(cookie.value(), &data.claims.nonce)) => {}
+        _ => {
+            return Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid or expired state").into());
+        }
+    }
+
+    // Exchange the authorization code for an access token
+    let token: TokenResponse = providers.http
+        .post(&config.token_url)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code.as_str()),
+            ("redirect_uri", config.redirect_uri.as_str()),
+            ("client_id", config.client_id.as_str()),
+            ("client_secret", config.client_secret.as_str()),
+        ])
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| {
+            error!("Token exchange with {} failed: {}", config.name, e);
+            ApiError::new(StatusCode::BAD_GATEWAY, "Identity provider token exchange failed")
+        })?
+        .json()
+        .await
+        .map_err(|e| {
+            error!("Invalid token response from {}: {}", config.name, e);
+            ApiError::new(StatusCode::BAD_GATEWAY, "Invalid identity provider response")
+        })?;
+
+    let info: UserInfo = providers.http
+        .get(&config.userinfo_url)
+        .bearer_auth(&token.access_token)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| {
+            error!("Userinfo request to {} failed: {}", config.name, e);
+            ApiError::new(StatusCode::BAD_GATEWAY, "Identity provider userinfo request failed")
+        })?
+        .json()
+        .await
+        .map_err(|e| {
+            error!("Invalid userinfo response from {}: {}", config.name, e);
+            ApiError::new(StatusCode::BAD_GATEWAY, "Invalid identity provider response")
+        })?;
+
+    // Only link accounts by email when the provider vouches for it
+    let email = match info.email {
+        Some(email) if config.trust_email || info.email_verified == Some(true) => pii::normalize_email(&email),
+        _ => {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                "Identity provider did not return a verified email",
+            ).into());
+        }
+    };
+
+    let identity = ExternalIdentity {
+        provider: config.name.clone(),
+        subject: info.sub,
+    };
+    let identity_bson = to_bson(&identity).map_err(|e| {
+        error!("Failed to serialize identity: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to link account")
+    })?;
+
+    let collection: Collection<User> = db.database.collection("users");
+
+    let existing = collection
+        .find_one(doc! {
+            "$or": [
+                { "identities": { "provider": &identity.provider, "subject": &identity.subject } },
+                { "email": pii::email_filter(&email) },
+            ]
+        }, None)
+        .await
+        .map_err(|e| {
+            error!("Database error: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+        })?;
+
+    let user = match existing {
+        Some(mut user) => {
+            if !user.identities.contains(&identity) {
+                collection
+                    .update_one(
+                        doc! { "_id": user.id },
+                        doc! { "$addToSet": { "identities": identity_bson } },
+                        None,
+                    )
+                    .await
+                    .map_err(|e| {
+                        error!("Failed to link identity: {}", e);
+                        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to link account")
+                    })?;
+                info!("Linked {} identity to user {:?}", identity.provider, user.id);
+                user.identities.push(identity);
+            }
+            user
+        }
+        None => {
+            // New accounts need an invite addressed to the provider's email
+            let invite = invites::claim_for_email(&db, &email).await?;
+            if invite.is_none() && !invite_config.open_registration {
+                info!("Refused {} sign-up of {} without an invite", config.name, email);
+                return Err(ApiError::new(StatusCode::FORBIDDEN, "Registration requires an invitation").into());
+            }
+
+            let mut user = User {
+                id: None,
+                email,
+                first_name: info.given_name.unwrap_or_default(),
+                last_name: info.family_name.unwrap_or_default(),
+                password_hash: String::new(),
+                scopes: invite.as_ref().map(|invite| invite.scopes.clone()).unwrap_or_else(default_scopes),
+                identities: vec![identity],
+                two_factor: None,
+            };
+            let result = match collection.insert_one(&user, None).await {
+                Ok(result) => result,
+                Err(e) => {
+                    error!("Failed to insert user: {}", e);
+                    if let Some(invite) = &invite {
+                        invites::release(&db, invite.id).await;
+                    }
+                    return Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to create user").into());
+                }
+            };
+            user.id = result.inserted_id.as_object_id();
+            if let (Some(invite), Some(user_id)) = (&invite, user.id) {
+                invites::accept(&db, invite.id, user_id).await;
+            }
+            info!("Created new user {:?} via {}", user.id, config.name);
+            let mut event = AuthEvent::new(AuthEventKind::Registered)
+                .email(&user.email)
+                .method(&format!("oauth:{}", config.name));
+            if let Some(user_id) = user.id {
+                event = event.user(user_id);
+            }
+            event.record(&db, &req).await;
+            user
+        }
+    };
+
+    let user_id = user.id.as_ref().unwrap();
+
+    // The provider vouches for the first factor only, as a password would
+    if user.two_factor.as_ref().map(|tf| tf.enabled).unwrap_or(false) {
+        let challenge_token = two_factor::issue_challenge(user_id, None)?;
+        return Ok(HttpResponse::Ok().cookie(used_state_cookie(config)).json(doc! {
+            "two_factor_required": true,
+            "challenge_token": challenge_token
+        }));
+    }
+
+    let (token, refresh_token) = sessions::start_session(&db, &req, user_id, &user.scopes).await?;
+    AuthEvent::new(AuthEventKind::LoginSucceeded)
+        .user(*user_id)
+        .email(&user.email)
+        .method(&format!("oauth:{}", config.name))
+        .record(&db, &req)
+        .await;
+
+    Ok(HttpResponse::Ok().cookie(used_state_cookie(config)).json(AuthResponse {
+        token,
+        refresh_token,
+        user: UserResponse {
+            id: user_id.to_string(),
+            email: user.email,
+            first_name: user.first_name,
+            last_name: user.last_name,
+            scopes: user.scopes,
+        },
+    }))
+}
@@ -0,0 +1,450 @@
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::{FutureExt, TryStreamExt};
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    options::FindOptions,
+    ClientSession, Collection,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tracing::{debug, error, info};
+
+use crate::{
+    auth::Claims,
+    carts::{carts_collection, Cart},
+    config::MongoConfig,
+    errors::ApiError,
+    event_store::{self, ProductEvent},
+    events::EventHub,
+    locations,
+    models::Product,
+    money::{self, Decimal},
+    reservations,
+    stock,
+    transactions::{run_in_transaction, TransactionError},
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderStatus {
+    Pending,
+    Paid,
+    Shipped,
+    Cancelled,
+}
+
+impl OrderStatus {
+    pub fn can_transition_to(self, next: OrderStatus) -> bool {
+        matches!(
+            (self, next),
+            (OrderStatus::Pending, OrderStatus::Paid)
+                | (OrderStatus::Paid, OrderStatus::Shipped)
+                | (OrderStatus::Pending, OrderStatus::Cancelled)
+                | (OrderStatus::Paid, OrderStatus::Cancelled)
+        )
+    }
+}
+
+impl fmt::Display for OrderStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OrderStatus::Pending => "pending",
+            OrderStatus::Paid => "paid",
+            OrderStatus::Shipped => "shipped",
+            OrderStatus::Cancelled => "cancelled",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OrderItem {
+    pub product_id: ObjectId,
+    pub name: String,
+    pub quantity: i64,
+    #[serde(with = "money::price")]
+    pub unit_price: Decimal,
+    #[serde(with = "money::price")]
+    pub line_total: Decimal,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Order {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    pub items: Vec<OrderItem>,
+    #[serde(with = "money::price")]
+    pub total: Decimal,
+    pub status: OrderStatus,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrderItemResponse {
+    pub product_id: String,
+    pub name: String,
+    pub quantity: i64,
+    #[serde(with = "money::price")]
+    pub unit_price: Decimal,
+    #[serde(with = "money::price")]
+    pub line_total: Decimal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrderResponse {
+    pub id: String,
+    pub user_id: String,
+    pub items: Vec<OrderItemResponse>,
+    #[serde(with = "money::price")]
+    pub total: Decimal,
+    pub status: OrderStatus,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl From<&Order> for OrderResponse {
+    fn from(order: &Order) -> Self {
+        OrderResponse {
+            id: order.id.map(|id| id.to_string()).unwrap_or_default(),
+            user_id: order.user_id.to_string(),
+            items: order
+                .items
+                .iter()
+                .map(|item| OrderItemResponse {
+                    product_id: item.product_id.to_string(),
+                    name: item.name.clone(),
+                    quantity: item.quantity,
+                    unit_price: item.unit_price,
+                    line_total: item.line_total,
+                })
+                .collect(),
+            total: order.total,
+            status: order.status,
+            created_at: order.created_at.try_to_rfc3339_string().unwrap_or_default(),
+            updated_at: order.updated_at.try_to_rfc3339_string().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListOrdersQuery {
+    status: Option<OrderStatus>,
+    user_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateOrderStatusRequest {
+    pub status: OrderStatus,
+}
+
+// Business reasons for refusing to place an order
+enum OrderRejection {
+    ProductUnavailable(String),
+    InsufficientStock(String),
+}
+
+pub fn orders_collection(db: &MongoConfig) -> Collection<Order> {
+    db.database.collection("orders")
+}
+
+fn parse_order_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| {
+        error!("Invalid order ID format: {}", id);
+        ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format").into()
+    })
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+async fn collect_orders(db: &MongoConfig, filter: Document) -> Result<Vec<OrderResponse>, Error> {
+    let options = FindOptions::builder()
+        .sort(doc! { "created_at": -1 })
+        .build();
+
+    let mut cursor = orders_collection(db)
+        .find(filter, options)
+        .await
+        .map_err(|e| db_error("Failed to fetch orders", e))?;
+
+    let mut orders = Vec::new();
+    while let Some(order) = cursor
+        .try_next()
+        .await
+        .map_err(|e| db_error("Error while iterating orders", e))?
+    {
+        orders.push(OrderResponse::from(&order));
+    }
+    Ok(orders)
+}
+
+// Decrements stock, writes the order and empties the cart inside `session`'s transaction
+async fn place_order(
+    db: &MongoConfig,
+    session: &mut ClientSession,
+    cart: &Cart,
+) -> Result<Order, TransactionError<OrderRejection>> {
+    let products: Collection<Product> = db.database.collection("products");
+    let mut items = Vec::with_capacity(cart.items.len());
+
+    for item in &cart.items {
+        let product = match products
+            .find_one_with_session(doc! { "_id": item.product_id }, None, session)
+            .await?
+        {
+            Some(product) => product,
+            None => return Err(TransactionError::Aborted(OrderRejection::ProductUnavailable(item.name.clone()))),
+        };
+
+        if let Some(stock_quantity) = product.stock_quantity {
+            // Units other users have reserved are not for sale
+            reservations::lock_product_with_session(db, session, item.product_id).await?;
+            let reserved =
+                reservations::reserved_by_others_with_session(db, session, item.product_id, cart.user_id).await?;
+            if stock_quantity - reserved < item.quantity {
+                return Err(TransactionError::Aborted(OrderRejection::InsufficientStock(product.name)));
+            }
+
+            let result = products
+                .update_one_with_session(
+                    doc! { "_id": item.product_id, "stock_quantity": { "$gte": item.quantity } },
+                    doc! { "$inc": { "stock_quantity": -item.quantity } },
+                    None,
+                    session,
+                )
+                .await?;
+            if result.modified_count == 0 {
+                return Err(TransactionError::Aborted(OrderRejection::InsufficientStock(product.name)));
+            }
+            let adjusted = ProductEvent::StockAdjusted { delta: -item.quantity };
+            event_store::record_with_session(db, session, item.product_id, vec![adjusted]).await?;
+            locations::draw_from_locations_with_session(db, session, item.product_id, stock_quantity - item.quantity)
+                .await?;
+        }
+
+        items.push(OrderItem {
+            product_id: item.product_id,
+            name: item.name.clone(),
+            quantity: item.quantity,
+            unit_price: item.effective_unit_price(),
+            line_total: item.line_total(),
+        });
+    }
+
+    let product_ids: Vec<ObjectId> = items.iter().map(|item| item.product_id).collect();
+    reservations::consume_with_session(db, session, cart.user_id, &product_ids).await?;
+
+    let now = DateTime::now();
+    let mut order = Order {
+        id: None,
+        user_id: cart.user_id,
+        total: items.iter().map(|item| item.line_total).sum(),
+        items,
+        status: OrderStatus::Pending,
+        created_at: now,
+        updated_at: now,
+    };
+
+    let result = orders_collection(db)
+        .insert_one_with_session(&order, None, session)
+        .await?;
+    order.id = result.inserted_id.as_object_id();
+
+    carts_collection(db)
+        .delete_one_with_session(doc! { "user_id": cart.user_id }, None, session)
+        .await?;
+
+    Ok(order)
+}
+
+// Moves the order to `next`, returning stock for cancellations in the same transaction
+async fn apply_transition(
+    db: &MongoConfig,
+    session: &mut ClientSession,
+    order: &Order,
+    next: OrderStatus,
+) -> Result<(), TransactionError<()>> {
+    // Guard on the current status so concurrent transitions can't both win
+    let result = orders_collection(db)
+        .update_one_with_session(
+            doc! { "_id": order.id, "status": order.status.to_string() },
+            doc! { "$set": { "status": next.to_string(), "updated_at": DateTime::now() } },
+            None,
+            session,
+        )
+        .await?;
+
+    if result.modified_count == 0 {
+        return Err(TransactionError::Aborted(()));
+    }
+
+    if next == OrderStatus::Cancelled {
+        for item in &order.items {
+            stock::adjust_stock_with_session(db, session, item.product_id, item.quantity).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn transition_order(
+    db: &MongoConfig,
+    order_id: &ObjectId,
+    owner: Option<&ObjectId>,
+    next: OrderStatus,
+) -> Result<HttpResponse, Error> {
+    let mut filter = doc! { "_id": order_id };
+    if let Some(user_id) = owner {
+        filter.insert("user_id", user_id);
+    }
+
+    let order = match orders_collection(db)
+        .find_one(filter, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch order", e))?
+    {
+        Some(order) => order,
+        None => return Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    };
+
+    if !order.status.can_transition_to(next) {
+        let message = format!("Cannot change order status from {} to {}", order.status, next);
+        return Err(ApiError::new(StatusCode::CONFLICT, message).into());
+    }
+
+    let outcome = run_in_transaction(db, (db, &order), |session, (db, order)| {
+        apply_transition(db, session, order, next).boxed()
+    })
+    .await;
+
+    match outcome {
+        Ok(()) => {}
+        Err(TransactionError::Aborted(())) => {
+            return Err(ApiError::new(StatusCode::CONFLICT, "Order was modified concurrently, retry").into());
+        }
+        Err(TransactionError::Database(e)) => {
+            return Err(db_error("Failed to update order status", e));
+        }
+    }
+
+    info!("Order {} moved from {} to {}", order_id, order.status, next);
+    let mut updated = order;
+    updated.status = next;
+    Ok(HttpResponse::Ok().json(OrderResponse::from(&updated)))
+}
+
+pub async fn create_order(
+    db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
+    claims: web::ReqData<Claims>,
+) -> Result<HttpResponse, Error> {
+    let user_id = claims.user_id()?;
+
+    let cart = match carts_collection(&db)
+        .find_one(doc! { "user_id": user_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch cart", e))?
+    {
+        Some(cart) if !cart.items.is_empty() => cart,
+        _ => {
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, "Cart is empty").into());
+        }
+    };
+
+    let outcome = run_in_transaction(&db, (&**db, &cart), |session, (db, cart)| {
+        place_order(db, session, cart).boxed()
+    })
+    .await;
+
+    let order = match outcome {
+        Ok(order) => order,
+        Err(TransactionError::Aborted(rejection)) => {
+            let message = match rejection {
+                OrderRejection::ProductUnavailable(name) => format!("Product is no longer available: {}", name),
+                OrderRejection::InsufficientStock(name) => format!("Insufficient stock for {}", name),
+            };
+            debug!("Order rejected for user {}: {}", user_id, message);
+            return Err(ApiError::new(StatusCode::CONFLICT, message).into());
+        }
+        Err(TransactionError::Database(e)) => {
+            return Err(db_error("Failed to place order", e));
+        }
+    };
+
+    info!("User {} placed order {:?} totalling {}", user_id, order.id, order.total);
+
+    let product_ids: Vec<ObjectId> = order.items.iter().map(|item| item.product_id).collect();
+    stock::check_low_stock(&db, &events, &product_ids).await;
+
+    Ok(HttpResponse::Created().json(OrderResponse::from(&order)))
+}
+
+pub async fn list_my_orders(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+) -> Result<HttpResponse, Error> {
+    let orders = collect_orders(&db, doc! { "user_id": claims.user_id()? }).await?;
+    Ok(HttpResponse::Ok().json(orders))
+}
+
+pub async fn get_my_order(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let order_id = parse_order_id(&id)?;
+
+    let order = orders_collection(&db)
+        .find_one(doc! { "_id": order_id, "user_id": claims.user_id()? }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch order", e))?;
+
+    match order {
+        Some(order) => Ok(HttpResponse::Ok().json(OrderResponse::from(&order))),
+        None => Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    }
+}
+
+pub async fn cancel_my_order(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let order_id = parse_order_id(&id)?;
+    let user_id = claims.user_id()?;
+    transition_order(&db, &order_id, Some(&user_id), OrderStatus::Cancelled).await
+}
+
+pub async fn admin_list_orders(
+    db: web::Data<MongoConfig>,
+    query: web::Query<ListOrdersQuery>,
+) -> Result<HttpResponse, Error> {
+    let mut filter = Document::new();
+    if let Some(status) = query.status {
+        filter.insert("status", status.to_string());
+    }
+    if let Some(user_id) = &query.user_id {
+        let user_id = ObjectId::parse_str(user_id).map_err(|_| {
+            ApiError::new(StatusCode::BAD_REQUEST, "Invalid user ID format")
+        })?;
+        filter.insert("user_id", user_id);
+    }
+
+    let orders = collect_orders(&db, filter).await?;
+    Ok(HttpResponse::Ok().json(orders))
+}
+
+pub async fn admin_update_order_status(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+    body: web::Json<UpdateOrderStatusRequest>,
+) -> Result<HttpResponse, Error> {
+    let order_id = parse_order_id(&id)?;
+    transition_order(&db, &order_id, None, body.status).await
+}
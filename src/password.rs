@@ -0,0 +1,92 @@
+use actix_web::http::StatusCode;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use std::sync::OnceLock;
+use tracing::error;
+
+use crate::{config::PasswordHashConfig, errors::ApiError};
+
+static PARAMS: OnceLock<Params> = OnceLock::new();
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+/// Sets the Argon2id parameters from the loaded configuration. Runs once at startup.
+pub fn init(config: &PasswordHashConfig) {
+    PARAMS.get_or_init(|| config.params.clone());
+}
+
+fn params() -> &'static Params {
+    PARAMS.get().expect("password::init runs at startup")
+}
+
+fn hasher() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params().clone())
+}
+
+pub fn hash_password(password: &str) -> Result<String, actix_web::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    hasher()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| {
+            error!("Failed to hash password: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Password hashing failed").into()
+        })
+}
+
+pub struct Verification {
+    pub valid: bool,
+    // The stored hash uses bcrypt or outdated Argon2 parameters
+    pub needs_rehash: bool,
+}
+
+/// Verifies against Argon2 hashes as well as legacy bcrypt hashes.
+pub fn verify_password(password: &str, stored_hash: &str) -> Result<Verification, actix_web::Error> {
+    if stored_hash.starts_with("$2") {
+        let valid = bcrypt::verify(password, stored_hash).map_err(|e| {
+            error!("Password verification error: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Password verification failed")
+        })?;
+        return Ok(Verification { valid, needs_rehash: valid });
+    }
+
+    let parsed = PasswordHash::new(stored_hash).map_err(|e| {
+        error!("Invalid password hash format: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Password verification failed")
+    })?;
+
+    let valid = hasher().verify_password(password.as_bytes(), &parsed).is_ok();
+    let current = params();
+    let needs_rehash = valid
+        && (parsed.algorithm != Algorithm::Argon2id.ident()
+            || Params::try_from(&parsed)
+                .map(|p| {
+                    p.m_cost() != current.m_cost()
+                        || p.t_cost() != current.t_cost()
+                        || p.p_cost() != current.p_cost()
+                })
+                .unwrap_or(true));
+
+    Ok(Verification { valid, needs_rehash })
+}
+
+/// A hash of a password nobody has, with the current parameters. Computed
+/// once; call it at startup so the first use doesn't pay for hashing.
+pub fn dummy_hash() -> &'static str {
+    DUMMY_HASH.get_or_init(|| {
+        let salt = SaltString::generate(&mut OsRng);
+        hasher()
+            .hash_password(salt.as_str().as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .unwrap_or_default()
+    })
+}
+
+/// Burns the time of a real verification for logins that cannot succeed,
+/// e.g. for an unknown email, so response times don't tell which accounts exist.
+pub fn verify_dummy(password: &str) {
+    if let Ok(parsed) = PasswordHash::new(dummy_hash()) {
+        let _ = hasher().verify_password(password.as_bytes(), &parsed);
+    }
+}
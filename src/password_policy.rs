@@ -0,0 +1,221 @@
+use std::{
+    borrow::Cow,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    time::Duration,
+};
+
+use sha1::{Digest, Sha1};
+use tracing::{info, warn};
+use validator::{ValidationError, ValidationErrors};
+
+use crate::config::PasswordPolicyConfig;
+
+const HIBP_TIMEOUT: Duration = Duration::from_secs(3);
+
+type CharacterTest = fn(char) -> bool;
+
+const CHARACTER_CLASSES: [(&str, CharacterTest); 4] = [
+    ("lowercase", |c| c.is_lowercase()),
+    ("uppercase", |c| c.is_uppercase()),
+    ("digit", |c| c.is_numeric()),
+    ("symbol", |c| !c.is_alphanumeric() && !c.is_whitespace()),
+];
+
+/// Bit array that answers "possibly breached" or "certainly not" for a
+/// password's SHA-1, built with the `build-password-filter` command.
+///
+/// File layout: the number of hash functions as a little-endian u32, then
+/// the bits.
+pub struct BloomFilter {
+    hashes: u32,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        if data.len() <= 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "password filter is empty"));
+        }
+        let (header, bits) = data.split_at(4);
+        let hashes = u32::from_le_bytes(header.try_into().expect("header is 4 bytes"));
+        Ok(BloomFilter { hashes, bits: bits.to_vec() })
+    }
+
+    // Double hashing: the i-th position is h1 + i * h2, both taken from the digest
+    fn positions(&self, digest: &[u8; 20]) -> impl Iterator<Item = usize> {
+        let h1 = u64::from_le_bytes(digest[..8].try_into().expect("digest has 20 bytes"));
+        let h2 = u64::from_le_bytes(digest[8..16].try_into().expect("digest has 20 bytes"));
+        let len = self.bits.len() as u64 * 8;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    pub fn contains(&self, digest: &[u8; 20]) -> bool {
+        self.positions(digest).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn insert(&mut self, digest: &[u8; 20]) {
+        for bit in self.positions(digest).collect::<Vec<_>>() {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+}
+
+// A line of a password list: either a SHA-1 as in the haveibeenpwned
+// downloads ("HASH" or "HASH:COUNT") or a plain password
+fn line_digest(line: &str) -> [u8; 20] {
+    let hash = line.split(':').next().unwrap_or_default();
+    if hash.len() == 40 && hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        let mut digest = [0; 20];
+        for (i, byte) in digest.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hash[i * 2..i * 2 + 2], 16).expect("checked to be hex");
+        }
+        return digest;
+    }
+    Sha1::digest(line.as_bytes()).into()
+}
+
+/// Builds a filter file from a password list with one entry per line;
+/// returns how many entries went in.
+pub fn build_bloom_filter(input: &Path, output: &Path, false_positive_rate: f64) -> io::Result<usize> {
+    let lines = || -> io::Result<_> {
+        Ok(BufReader::new(File::open(input)?)
+            .lines()
+            .map_while(Result::ok)
+            .filter(|line| !line.trim().is_empty()))
+    };
+    let count = lines()?.count().max(1);
+
+    // Optimal size and number of hash functions for the requested rate
+    let ln2 = std::f64::consts::LN_2;
+    let bits = (-(count as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil() as usize;
+    let hashes = ((bits as f64 / count as f64) * ln2).round().max(1.0) as u32;
+    let mut filter = BloomFilter { hashes, bits: vec![0; bits.div_ceil(8).max(1)] };
+    for line in lines()? {
+        filter.insert(&line_digest(line.trim()));
+    }
+
+    let mut file = File::create(output)?;
+    file.write_all(&hashes.to_le_bytes())?;
+    file.write_all(&filter.bits)?;
+    info!("Wrote password filter of {} bytes with {} hash functions", filter.bits.len(), hashes);
+    Ok(count)
+}
+
+fn rejection(code: &'static str, message: String) -> ValidationError {
+    let mut error = ValidationError::new(code);
+    error.message = Some(Cow::Owned(message));
+    error
+}
+
+enum BreachCheck {
+    Off,
+    // haveibeenpwned range API: only the first 5 hex digits of the SHA-1 leave the server
+    Hibp { http: reqwest::Client, url: String },
+    Bloom(BloomFilter),
+}
+
+/// The configured password rules, checked wherever a password is set.
+pub struct PasswordPolicy {
+    config: PasswordPolicyConfig,
+    breach_check: BreachCheck,
+}
+
+impl PasswordPolicy {
+    pub fn new(config: PasswordPolicyConfig) -> io::Result<Self> {
+        let breach_check = match config.breach_check.as_str() {
+            "hibp" => BreachCheck::Hibp {
+                http: reqwest::Client::builder()
+                    .timeout(HIBP_TIMEOUT)
+                    .build()
+                    .expect("Failed to build HTTP client"),
+                url: config.hibp_url.trim_end_matches('/').to_string(),
+            },
+            "bloom" => {
+                let path = config.bloom_filter_path.as_deref().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "PASSWORD_BREACH_CHECK=bloom needs PASSWORD_BLOOM_FILTER")
+                })?;
+                BreachCheck::Bloom(BloomFilter::load(path)?)
+            }
+            "off" => BreachCheck::Off,
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unknown PASSWORD_BREACH_CHECK '{}': expected off, hibp or bloom", other),
+                ))
+            }
+        };
+        for class in &config.required_classes {
+            if !CHARACTER_CLASSES.iter().any(|(name, _)| name == class) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unknown character class '{}' in PASSWORD_REQUIRED_CLASSES", class),
+                ));
+            }
+        }
+        Ok(PasswordPolicy { config, breach_check })
+    }
+
+    async fn breached(&self, password: &str) -> bool {
+        let digest: [u8; 20] = Sha1::digest(password.as_bytes()).into();
+        match &self.breach_check {
+            BreachCheck::Off => false,
+            BreachCheck::Bloom(filter) => filter.contains(&digest),
+            BreachCheck::Hibp { http, url } => {
+                let hash: String = digest.iter().map(|byte| format!("{:02X}", byte)).collect();
+                let (prefix, suffix) = hash.split_at(5);
+                let response = http
+                    .get(format!("{}/range/{}", url, prefix))
+                    .header("Add-Padding", "true")
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                let body = match response {
+                    Ok(response) => response.text().await,
+                    Err(e) => Err(e),
+                };
+                match body {
+                    // Padding entries have a count of 0
+                    Ok(body) => body.lines().any(|line| {
+                        line.split_once(':')
+                            .is_some_and(|(candidate, count)| candidate == suffix && count.trim() != "0")
+                    }),
+                    Err(e) => {
+                        // An outage of the service shouldn't stop people signing up
+                        warn!("Breached password check failed, allowing the password: {}", e);
+                        false
+                    }
+                }
+            }
+        }
+    }
+
+    /// Everything wrong with `password`, reported on `field` like other
+    /// validation errors.
+    pub async fn check(&self, field: &'static str, password: &str) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::new();
+        let length = password.chars().count();
+        if length < self.config.min_length || length > self.config.max_length {
+            let message = format!("Must be between {} and {} characters", self.config.min_length, self.config.max_length);
+            errors.add(field, rejection("length", message));
+        }
+        for (class, matches) in CHARACTER_CLASSES {
+            if self.config.required_classes.iter().any(|required| required == class) && !password.chars().any(matches) {
+                errors.add(field, rejection("character_class", format!("Must contain a {} character", class)));
+            }
+        }
+        // Only worth asking about passwords that are otherwise acceptable
+        if errors.is_empty() && self.breached(password).await {
+            let message = "This password appeared in a data breach; choose another".to_string();
+            errors.add(field, rejection("breached", message));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
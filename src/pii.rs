@@ -0,0 +1,156 @@
+use std::error::Error as StdError;
+
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    Collection,
+};
+use serde::{de::Error as _, ser::Error as _, Deserialize, Deserializer, Serializer};
+use tracing::error;
+
+use crate::{config::MongoConfig, crypto};
+
+// Marks encrypted values, so documents written before encryption still read
+const PREFIX: &str = "pii:v1:";
+
+// User fields stored encrypted, and whether equal values encrypt equally so
+// they can be looked up
+const USER_FIELDS: [(&str, bool); 3] = [("email", true), ("first_name", false), ("last_name", false)];
+
+fn seal(value: &str, deterministic: bool) -> Result<String, String> {
+    let sealed = if deterministic {
+        crypto::encrypt_deterministic(value.as_bytes())?
+    } else {
+        crypto::encrypt(value.as_bytes())?
+    };
+    Ok(format!("{}{}", PREFIX, sealed))
+}
+
+fn open(value: &str) -> Result<String, String> {
+    let Some(sealed) = value.strip_prefix(PREFIX) else {
+        return Ok(value.to_string());
+    };
+    let plaintext = crypto::decrypt(sealed)?;
+    String::from_utf8(plaintext).map_err(|e| format!("Decrypted value is not UTF-8: {}", e))
+}
+
+fn deserialize_opened<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    open(&value).map_err(D::Error::custom)
+}
+
+/// Serde adapter for an email: encrypted deterministically, so
+/// [`email_filter`] can find it.
+pub mod email {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&seal(value, true).map_err(S::Error::custom)?)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+        deserialize_opened(deserializer)
+    }
+}
+
+/// Serde adapter for personal text that is never searched for, such as names.
+pub mod text {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&seal(value, false).map_err(S::Error::custom)?)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+        deserialize_opened(deserializer)
+    }
+}
+
+/// The form emails are stored and looked up in, so `Ann@Example.com ` and
+/// `ann@example.com` are the same account.
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Matches a user's stored email, encrypted or from before encryption.
+/// Expects an email already passed through [`normalize_email`].
+pub fn email_filter(email: &str) -> Bson {
+    match seal(email, true) {
+        Ok(sealed) => Bson::Document(doc! { "$in": [sealed, email] }),
+        Err(e) => {
+            error!("Failed to encrypt email for lookup: {}", e);
+            Bson::String(email.to_string())
+        }
+    }
+}
+
+/// Decrypts the personal fields of a raw user document, for code that reads
+/// users without going through `User`.
+pub fn open_user_fields(document: &mut Document) {
+    for (field, _) in USER_FIELDS {
+        let Ok(value) = document.get_str(field) else {
+            continue;
+        };
+        match open(value) {
+            Ok(plaintext) => {
+                document.insert(field, plaintext);
+            }
+            Err(e) => error!("Failed to decrypt user {}: {}", field, e),
+        }
+    }
+}
+
+/// Encrypts the personal fields of users written before encryption; returns
+/// how many users changed. Safe to run repeatedly.
+pub async fn encrypt_users(db: &MongoConfig) -> Result<u64, Box<dyn StdError + Send + Sync>> {
+    let collection: Collection<Document> = db.database.collection("users");
+    let plaintext: Vec<Document> = USER_FIELDS
+        .iter()
+        .map(|(field, _)| doc! { *field: { "$type": "string", "$not": { "$regex": format!("^{}", PREFIX) } } })
+        .collect();
+
+    let mut cursor = collection.find(doc! { "$or": plaintext }, None).await?;
+    let mut encrypted = 0;
+    while let Some(document) = cursor.try_next().await? {
+        let mut set = Document::new();
+        for (field, deterministic) in USER_FIELDS {
+            if let Ok(value) = document.get_str(field) {
+                if !value.starts_with(PREFIX) {
+                    set.insert(field, seal(value, deterministic)?);
+                }
+            }
+        }
+        let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+        collection.update_one(doc! { "_id": id }, doc! { "$set": set }, None).await?;
+        encrypted += 1;
+    }
+    Ok(encrypted)
+}
+
+/// Rewrites the emails of users stored before emails were normalized;
+/// returns how many changed. A user whose normalized email belongs to
+/// another account is logged and left as it is, to be merged by hand.
+pub async fn normalize_user_emails(db: &MongoConfig) -> Result<u64, Box<dyn StdError + Send + Sync>> {
+    let collection: Collection<Document> = db.database.collection("users");
+    let mut cursor = collection.find(doc! { "email": { "$type": "string" } }, None).await?;
+    let mut normalized = 0;
+    while let Some(document) = cursor.try_next().await? {
+        let Ok(stored) = document.get_str("email") else {
+            continue;
+        };
+        let email = open(stored)?;
+        let normal = normalize_email(&email);
+        if normal == email {
+            continue;
+        }
+        let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+        let taken = doc! { "_id": { "$ne": &id }, "email": email_filter(&normal) };
+        if collection.find_one(taken, None).await?.is_some() {
+            error!("User {} has the same normalized email as another user; merge them by hand", id);
+            continue;
+        }
+        collection.update_one(doc! { "_id": &id }, doc! { "$set": { "email": seal(&normal, true)? } }, None).await?;
+        normalized += 1;
+    }
+    Ok(normalized)
+}
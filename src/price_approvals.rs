@@ -0,0 +1,366 @@
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    options::FindOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use validator::Validate;
+
+use crate::{
+    auth::Claims,
+    config::{LimitsConfig, MongoConfig, PriceApprovalConfig},
+    errors::ApiError,
+    event_store::{self, ProductEvent},
+    events::{DomainEvent, EventHub},
+    models::Product,
+    money::{self, Decimal},
+    pricing,
+    validation::{page_offset, validation_error, ValidatedQuery},
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceChangeStatus {
+    Pending,
+    Approved,
+    Rejected,
+    // A newer request for the same product replaced it
+    Superseded,
+}
+
+/// A price change held back until someone other than the requester approves it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PriceChangeRequest {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub product_id: ObjectId,
+    pub product_name: String,
+    // The price when the change was requested; approval fails if it moved since
+    #[serde(with = "money::price")]
+    pub current_price: Decimal,
+    #[serde(with = "money::price")]
+    pub requested_price: Decimal,
+    pub status: PriceChangeStatus,
+    pub requested_by: ObjectId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decided_by: Option<ObjectId>,
+    // Given by the approver when rejecting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub created_at: DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decided_at: Option<DateTime>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PriceChangeResponse {
+    pub id: String,
+    pub product_id: String,
+    pub product_name: String,
+    #[serde(with = "money::price")]
+    pub current_price: Decimal,
+    #[serde(with = "money::price")]
+    pub requested_price: Decimal,
+    // Relative to current_price; null when that is zero
+    pub change_percent: Option<f64>,
+    pub status: PriceChangeStatus,
+    pub requested_by: String,
+    pub decided_by: Option<String>,
+    pub reason: Option<String>,
+    pub created_at: String,
+    pub decided_at: Option<String>,
+}
+
+impl From<&PriceChangeRequest> for PriceChangeResponse {
+    fn from(request: &PriceChangeRequest) -> Self {
+        PriceChangeResponse {
+            id: request.id.map(|id| id.to_string()).unwrap_or_default(),
+            product_id: request.product_id.to_string(),
+            product_name: request.product_name.clone(),
+            current_price: request.current_price,
+            requested_price: request.requested_price,
+            change_percent: change_percent(request.current_price, request.requested_price),
+            status: request.status,
+            requested_by: request.requested_by.to_string(),
+            decided_by: request.decided_by.map(|id| id.to_string()),
+            reason: request.reason.clone(),
+            created_at: request.created_at.try_to_rfc3339_string().unwrap_or_default(),
+            decided_at: request.decided_at.and_then(|at| at.try_to_rfc3339_string().ok()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ListPriceChangesQuery {
+    #[validate(range(min = 1, message = "page must be at least 1"))]
+    page: Option<i64>,
+    #[validate(range(min = 1, message = "per_page must be at least 1"))]
+    per_page: Option<i64>,
+    status: Option<PriceChangeStatus>,
+    product_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct RejectPriceChangeRequest {
+    #[validate(length(min = 1, max = 500, message = "reason must be between 1 and 500 characters"))]
+    pub reason: String,
+}
+
+fn price_changes_collection(db: &MongoConfig) -> Collection<PriceChangeRequest> {
+    db.database.collection("price_change_requests")
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+fn parse_request_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| {
+        error!("Invalid price change request ID format: {}", id);
+        ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format").into()
+    })
+}
+
+fn change_percent(current: Decimal, requested: Decimal) -> Option<f64> {
+    if current.is_zero() {
+        return None;
+    }
+    Some(money::to_f64((requested - current) / current * Decimal::from(100)))
+}
+
+/// Whether moving from `current` to `requested` is beyond the configured
+/// threshold. Any change away from a zero price is.
+pub fn needs_approval(config: &PriceApprovalConfig, current: Decimal, requested: Decimal) -> bool {
+    let Some(threshold) = config.threshold_percent else {
+        return false;
+    };
+    if current == requested {
+        return false;
+    }
+    match change_percent(current, requested) {
+        Some(percent) => percent.abs() > threshold,
+        None => true,
+    }
+}
+
+/// Files a pending change of the product's price, replacing any request
+/// for it still waiting for a decision.
+pub async fn request_change(
+    db: &MongoConfig,
+    product: &Product,
+    requested_price: Decimal,
+    requested_by: ObjectId,
+) -> Result<PriceChangeRequest, mongodb::error::Error> {
+    let product_id = product.id.unwrap_or_default();
+    let collection = price_changes_collection(db);
+    collection
+        .update_many(
+            doc! { "product_id": product_id, "status": "pending" },
+            doc! { "$set": { "status": "superseded", "decided_at": DateTime::now() } },
+            None,
+        )
+        .await?;
+
+    let mut request = PriceChangeRequest {
+        id: None,
+        product_id,
+        product_name: product.name.clone(),
+        current_price: product.price,
+        requested_price,
+        status: PriceChangeStatus::Pending,
+        requested_by,
+        decided_by: None,
+        reason: None,
+        created_at: DateTime::now(),
+        decided_at: None,
+    };
+    let result = collection.insert_one(&request, None).await?;
+    request.id = result.inserted_id.as_object_id();
+    info!(
+        "Price change of {} from {} to {} by {} awaits approval",
+        product_id, request.current_price, requested_price, requested_by
+    );
+    Ok(request)
+}
+
+async fn find_request(db: &MongoConfig, id: &str) -> Result<Option<PriceChangeRequest>, Error> {
+    let request_id = parse_request_id(id)?;
+    price_changes_collection(db)
+        .find_one(doc! { "_id": request_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch price change request", e))
+}
+
+// A decision on a request that is still pending, by someone other than its requester
+fn check_decidable(request: &PriceChangeRequest, approver: &ObjectId) -> Option<ApiError> {
+    if request.status != PriceChangeStatus::Pending {
+        let message = format!("The request is already {:?}", request.status).to_lowercase();
+        return Some(ApiError::new(StatusCode::CONFLICT, message));
+    }
+    if &request.requested_by == approver {
+        let message = "Price changes must be approved by someone other than the requester";
+        return Some(ApiError::new(StatusCode::FORBIDDEN, message));
+    }
+    None
+}
+
+/// Price change requests, newest first.
+pub async fn list_price_changes(
+    db: web::Data<MongoConfig>,
+    limits: web::Data<LimitsConfig>,
+    query: ValidatedQuery<ListPriceChangesQuery>,
+) -> Result<HttpResponse, Error> {
+    let per_page = query.per_page.unwrap_or(20);
+    if per_page > limits.max_per_page {
+        let message = format!("per_page must be between 1 and {}", limits.max_per_page);
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, message).into());
+    }
+    let page = query.page.unwrap_or(1);
+
+    let mut filter = Document::new();
+    if let Some(status) = query.status {
+        filter.insert("status", mongodb::bson::to_bson(&status).map_err(ApiError::internal)?);
+    }
+    if let Some(product_id) = &query.product_id {
+        let product_id = ObjectId::parse_str(product_id)
+            .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "Invalid product ID format"))?;
+        filter.insert("product_id", product_id);
+    }
+
+    let options = FindOptions::builder()
+        .sort(doc! { "created_at": -1 })
+        .skip(page_offset(page, per_page)? as u64)
+        .limit(per_page)
+        .build();
+    let requests: Vec<PriceChangeRequest> = price_changes_collection(&db)
+        .find(filter, options)
+        .await
+        .map_err(|e| db_error("Failed to fetch price change requests", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating price change requests", e))?;
+
+    let requests: Vec<PriceChangeResponse> = requests.iter().map(PriceChangeResponse::from).collect();
+    Ok(HttpResponse::Ok().json(requests))
+}
+
+pub async fn get_price_change(db: web::Data<MongoConfig>, id: web::Path<String>) -> Result<HttpResponse, Error> {
+    match find_request(&db, &id).await? {
+        Some(request) => Ok(HttpResponse::Ok().json(PriceChangeResponse::from(&request))),
+        None => Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    }
+}
+
+/// Applies the requested price. Fails with 409 when the product's price
+/// moved since the request, or the new price no longer fits its price tiers.
+pub async fn approve_price_change(
+    db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let approver = claims.user_id()?;
+    let Some(request) = find_request(&db, &id).await? else {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    };
+    if let Some(problem) = check_decidable(&request, &approver) {
+        return Err(problem.into());
+    }
+
+    let products: Collection<Product> = db.database.collection("products");
+    let product = products
+        .find_one(doc! { "_id": request.product_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch product", e))?;
+    let Some(product) = product else {
+        return Err(ApiError::new(StatusCode::CONFLICT, "The product no longer exists").into());
+    };
+    if let Err(e) =
+        pricing::check_pricing(request.requested_price, product.unit, product.price_per_unit, &product.price_tiers)
+    {
+        return Err(ApiError::new(StatusCode::CONFLICT, e).into());
+    }
+
+    // Only if the price is still the one the request was based on
+    let result = products
+        .update_one(
+            doc! { "_id": request.product_id, "price": money::to_bson(request.current_price) },
+            doc! { "$set": { "price": money::to_bson(request.requested_price) } },
+            None,
+        )
+        .await
+        .map_err(|e| db_error("Failed to update product price", e))?;
+    if result.matched_count == 0 {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "The product's price changed since the request was made; reject it and request the change again",
+        ).into());
+    }
+
+    let decided_at = DateTime::now();
+    price_changes_collection(&db)
+        .update_one(
+            doc! { "_id": request.id, "status": "pending" },
+            doc! { "$set": { "status": "approved", "decided_by": approver, "decided_at": decided_at } },
+            None,
+        )
+        .await
+        .map_err(|e| db_error("Failed to record price change approval", e))?;
+
+    info!("Price change {} of product {} approved by {}", id, request.product_id, approver);
+    event_store::record(&db, request.product_id, vec![ProductEvent::PriceChanged { price: request.requested_price }])
+        .await;
+    events.publish(DomainEvent::ProductUpdated { product_id: request.product_id.to_string() });
+
+    let approved = PriceChangeRequest {
+        status: PriceChangeStatus::Approved,
+        decided_by: Some(approver),
+        decided_at: Some(decided_at),
+        ..request
+    };
+    Ok(HttpResponse::Ok().json(PriceChangeResponse::from(&approved)))
+}
+
+pub async fn reject_price_change(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+    body: web::Json<RejectPriceChangeRequest>,
+) -> Result<HttpResponse, Error> {
+    body.validate().map_err(validation_error)?;
+    let approver = claims.user_id()?;
+    let Some(request) = find_request(&db, &id).await? else {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    };
+    if let Some(problem) = check_decidable(&request, &approver) {
+        return Err(problem.into());
+    }
+
+    let decided_at = DateTime::now();
+    let result = price_changes_collection(&db)
+        .update_one(
+            doc! { "_id": request.id, "status": "pending" },
+            doc! { "$set": { "status": "rejected", "decided_by": approver, "reason": &body.reason, "decided_at": decided_at } },
+            None,
+        )
+        .await
+        .map_err(|e| db_error("Failed to record price change rejection", e))?;
+    if result.modified_count == 0 {
+        return Err(ApiError::new(StatusCode::CONFLICT, "The request was decided in the meantime").into());
+    }
+
+    info!("Price change {} of product {} rejected by {}", id, request.product_id, approver);
+    let rejected = PriceChangeRequest {
+        status: PriceChangeStatus::Rejected,
+        decided_by: Some(approver),
+        reason: Some(body.into_inner().reason),
+        decided_at: Some(decided_at),
+        ..request
+    };
+    Ok(HttpResponse::Ok().json(PriceChangeResponse::from(&rejected)))
+}
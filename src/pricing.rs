@@ -0,0 +1,63 @@
+use mongodb::bson::{doc, Bson};
+
+use crate::{
+    models::{PriceTier, Unit},
+    money::{self, Decimal},
+};
+
+const MAX_PRICE_TIERS: usize = 10;
+
+/// Price of one unit when buying `quantity`: the price of the highest tier
+/// reached, else the base price.
+pub fn unit_price(base: Decimal, tiers: &[PriceTier], quantity: i64) -> Decimal {
+    tiers
+        .iter()
+        .filter(|tier| quantity >= tier.min_quantity)
+        .max_by_key(|tier| tier.min_quantity)
+        .map(|tier| tier.price)
+        .unwrap_or(base)
+}
+
+/// Checks the unit pricing of a product. Tiers must start above a quantity of
+/// one, be ordered by min_quantity and get cheaper than the base price and
+/// every tier before them.
+pub fn check_pricing(price: Decimal, unit: Unit, price_per_unit: Option<Decimal>, tiers: &[PriceTier]) -> Result<(), String> {
+    if let Some(price_per_unit) = price_per_unit {
+        if unit == Unit::Piece {
+            return Err("price_per_unit requires a unit of kg or liter".to_string());
+        }
+        if price_per_unit <= Decimal::ZERO {
+            return Err("price_per_unit must be positive".to_string());
+        }
+    }
+
+    if tiers.len() > MAX_PRICE_TIERS {
+        return Err(format!("At most {} price tiers are allowed", MAX_PRICE_TIERS));
+    }
+    let mut previous = (1, price);
+    for tier in tiers {
+        if tier.min_quantity <= previous.0 {
+            return Err(format!(
+                "Price tier min_quantity must be above {}, got {}",
+                previous.0, tier.min_quantity
+            ));
+        }
+        if tier.price <= Decimal::ZERO || tier.price >= previous.1 {
+            return Err(format!(
+                "Price tier for {}+ must be positive and below {}",
+                tier.min_quantity, previous.1
+            ));
+        }
+        previous = (tier.min_quantity, tier.price);
+    }
+    Ok(())
+}
+
+pub fn tiers_to_bson(tiers: &[PriceTier]) -> Bson {
+    Bson::Array(
+        tiers
+            .iter()
+            .map(|tier| Bson::Document(doc! { "min_quantity": tier.min_quantity, "price": money::to_bson(tier.price) }))
+            .collect(),
+    )
+}
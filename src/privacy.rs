@@ -0,0 +1,236 @@
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Bson, DateTime, Document},
+    Collection,
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tracing::{error, info};
+
+use crate::{
+    auth::{Claims, User},
+    config::MongoConfig,
+    errors::ApiError,
+    password::verify_password,
+    pii, sessions, two_factor,
+};
+
+// Personal data that goes with the account: the collection and the field
+// linking a document to the user
+const DELETED: [(&str, &str); 9] = [
+    ("sessions", "user_id"),
+    ("favorites", "user_id"),
+    ("carts", "user_id"),
+    ("reservations", "user_id"),
+    ("notifications", "user_id"),
+    ("notification_preferences", "_id"),
+    ("saved_filters", "owner_id"),
+    ("export_jobs", "user_id"),
+    ("import_jobs", "user_id"),
+];
+
+// Records the business keeps, such as orders for accounting. The user's ID
+// is replaced with an anonymous one and the listed personal fields removed
+const ANONYMIZED: [(&str, &str, &[&str]); 15] = [
+    ("orders", "user_id", &[]),
+    ("auth_events", "user_id", &["email", "ip", "user_agent"]),
+    ("auth_events", "impersonator_id", &[]),
+    ("imports", "user_id", &[]),
+    ("imports", "rolled_back_by", &[]),
+    ("import_diffs", "created_by", &[]),
+    ("price_change_requests", "requested_by", &[]),
+    ("price_change_requests", "decided_by", &[]),
+    ("product_drafts", "updated_by", &[]),
+    ("products_trash", "deleted_by", &[]),
+    ("product_events", "event.deleted_by", &[]),
+    ("purchase_orders", "created_by", &[]),
+    ("product_relationships", "created_by", &[]),
+    ("invites", "accepted_by", &["email"]),
+    ("invites", "created_by", &[]),
+];
+
+// Credentials never leave the server, not even to their owner
+const SECRET_FIELDS: [(&str, &[&str]); 2] = [
+    ("users", &["password_hash", "two_factor"]),
+    ("sessions", &["refresh_jti", "consumed_jtis"]),
+];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeleteAccountRequest {
+    // Accounts from an external identity provider have none, and log in again instead
+    pub password: Option<String>,
+    // Also needed when two-factor authentication is on: a TOTP code or a recovery code
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+fn without_secrets(collection: &str, mut document: Document) -> Value {
+    if collection == "users" {
+        pii::open_user_fields(&mut document);
+    }
+    for (name, fields) in SECRET_FIELDS {
+        if name == collection {
+            for field in fields {
+                document.remove(field);
+            }
+        }
+    }
+    Bson::Document(document).into_relaxed_extjson()
+}
+
+async fn find_all(db: &MongoConfig, collection: &str, filter: Document) -> Result<Vec<Value>, Error> {
+    let documents: Vec<Document> = db
+        .database
+        .collection::<Document>(collection)
+        .find(filter, None)
+        .await
+        .map_err(|e| db_error("Failed to export personal data", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while exporting personal data", e))?;
+    Ok(documents.into_iter().map(|document| without_secrets(collection, document)).collect())
+}
+
+/// Everything stored about the caller, as JSON, grouped by collection.
+pub async fn export_personal_data(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+) -> Result<HttpResponse, Error> {
+    claims.forbid_impersonation()?;
+    let user_id = claims.user_id()?;
+
+    let user = find_all(&db, "users", doc! { "_id": user_id }).await?.pop();
+    let Some(user) = user else {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    };
+
+    let mut collections = Map::new();
+    let links = DELETED
+        .iter()
+        .map(|(name, field)| (*name, *field))
+        .chain(ANONYMIZED.iter().map(|(name, field, _)| (*name, *field)));
+    for (name, field) in links {
+        let documents = find_all(&db, name, doc! { field: user_id }).await?;
+        if let Value::Array(existing) = collections.entry(name).or_insert_with(|| Value::Array(Vec::new())) {
+            existing.extend(documents);
+        }
+    }
+
+    info!("User {} exported their personal data", user_id);
+    let exported_at = DateTime::now().try_to_rfc3339_string().unwrap_or_default();
+    Ok(HttpResponse::Ok()
+        .insert_header(("Content-Disposition", format!("attachment; filename=\"personal-data-{}.json\"", user_id)))
+        .json(json!({ "exported_at": exported_at, "user": user, "collections": collections })))
+}
+
+/// Deletes the caller's account once confirmed with their password, or a
+/// fresh login for accounts without one, plus a second factor when 2FA is
+/// on. Personal data is deleted; records the business has to keep are
+/// anonymized.
+pub async fn delete_account(
+    db: web::Data<MongoConfig>,
+    cache: web::Data<sessions::SessionCache>,
+    claims: web::ReqData<Claims>,
+    body: web::Json<DeleteAccountRequest>,
+) -> Result<HttpResponse, Error> {
+    claims.forbid_impersonation()?;
+    let user_id = claims.user_id()?;
+
+    let users: Collection<User> = db.database.collection("users");
+    let user = users
+        .find_one(doc! { "_id": user_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch user", e))?;
+    let Some(user) = user else {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    };
+
+    let confirmed = if user.password_hash.is_empty() {
+        sessions::recently_logged_in(&db, &claims).await?
+    } else {
+        match body.password.as_deref() {
+            Some(password) => verify_password(password, &user.password_hash)?.valid,
+            None => false,
+        }
+    };
+    if !confirmed {
+        info!("Rejected unconfirmed deletion of account {}", user_id);
+        let message = if user.password_hash.is_empty() {
+            "Log in again through your identity provider to delete the account"
+        } else {
+            "Confirm with your password to delete the account"
+        };
+        return Err(ApiError::new(StatusCode::UNAUTHORIZED, message).into());
+    }
+
+    if let Some(second_factor) = user.two_factor.as_ref().filter(|tf| tf.enabled) {
+        let verified = two_factor::verify_second_factor(
+            &users,
+            &user_id,
+            second_factor,
+            body.code.as_deref(),
+            body.recovery_code.as_deref(),
+        )
+        .await?;
+        if !verified {
+            info!("Rejected deletion of account {} without a valid second factor", user_id);
+            let message = "Confirm with a verification or recovery code to delete the account";
+            return Err(ApiError::new(StatusCode::UNAUTHORIZED, message).with("two_factor_required", true).into());
+        }
+    }
+
+    // Kept records of the same person stay linked to each other, but to nobody
+    let anonymous_id = ObjectId::new();
+    for (name, field, personal_fields) in ANONYMIZED {
+        let mut update = doc! { "$set": { field: anonymous_id } };
+        if !personal_fields.is_empty() {
+            let unset: Document = personal_fields.iter().map(|field| (field.to_string(), Bson::String(String::new()))).collect();
+            update.insert("$unset", unset);
+        }
+        db.database
+            .collection::<Document>(name)
+            .update_many(doc! { field: user_id }, update, None)
+            .await
+            .map_err(|e| db_error("Failed to anonymize records", e))?;
+    }
+    // Failed logins name the account by email only
+    db.database
+        .collection::<Document>("auth_events")
+        .update_many(
+            doc! { "email": &user.email },
+            doc! { "$unset": { "email": "", "ip": "", "user_agent": "" } },
+            None,
+        )
+        .await
+        .map_err(|e| db_error("Failed to anonymize records", e))?;
+
+    for (name, field) in DELETED {
+        db.database
+            .collection::<Document>(name)
+            .delete_many(doc! { field: user_id }, None)
+            .await
+            .map_err(|e| db_error("Failed to delete personal data", e))?;
+    }
+    db.database
+        .collection::<Document>("saved_filters")
+        .update_many(doc! { "shared_with": user_id }, doc! { "$pull": { "shared_with": user_id } }, None)
+        .await
+        .map_err(|e| db_error("Failed to delete personal data", e))?;
+
+    // Last, so a deletion that failed part way can be retried
+    users
+        .delete_one(doc! { "_id": user_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to delete user", e))?;
+
+    cache.forget_user(&user_id);
+    info!("Deleted account {}; kept records now belong to {}", user_id, anonymous_id);
+    Ok(HttpResponse::NoContent().finish())
+}
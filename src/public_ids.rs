@@ -0,0 +1,68 @@
+use actix_web::{http::StatusCode, Error};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    options::{FindOneOptions, FindOptions},
+    Collection,
+};
+use tracing::{debug, error};
+use uuid::Uuid;
+
+use crate::{config::MongoConfig, errors::ApiError};
+
+/// A new public product ID: a UUIDv7, so IDs sort by creation time like
+/// ObjectIds without exposing anything Mongo-specific.
+pub fn new_public_id() -> String {
+    Uuid::now_v7().to_string()
+}
+
+/// The ObjectId of the product a route's `{id}` refers to, given either as
+/// an ObjectId or as the product's public_id.
+pub async fn resolve_product_id(db: &MongoConfig, id: &str) -> Result<ObjectId, Error> {
+    if let Ok(object_id) = ObjectId::parse_str(id) {
+        return Ok(object_id);
+    }
+    if Uuid::parse_str(id).is_err() {
+        error!("Invalid product ID format: {}", id);
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format").into());
+    }
+
+    let products: Collection<Document> = db.database.collection("products");
+    let options = FindOneOptions::builder().projection(doc! { "_id": 1 }).build();
+    let product = products.find_one(doc! { "public_id": id }, options).await.map_err(|e| {
+        error!("Failed to resolve product {}: {}", id, e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+    })?;
+
+    match product.and_then(|product| product.get_object_id("_id").ok()) {
+        Some(object_id) => Ok(object_id),
+        None => {
+            debug!("No product with public ID {}", id);
+            Err(ApiError::new(StatusCode::NOT_FOUND, "Product not found").into())
+        }
+    }
+}
+
+/// Gives every product without a public_id one. Returns how many were updated.
+pub async fn backfill(db: &MongoConfig) -> Result<usize, mongodb::error::Error> {
+    let products: Collection<Document> = db.database.collection("products");
+    let options = FindOptions::builder().projection(doc! { "_id": 1 }).build();
+    let ids: Vec<Document> = products
+        .find(doc! { "public_id": { "$exists": false } }, options)
+        .await?
+        .try_collect()
+        .await?;
+
+    let mut updated = 0;
+    for product_id in ids.iter().filter_map(|product| product.get_object_id("_id").ok()) {
+        products
+            .update_one(
+                doc! { "_id": product_id, "public_id": { "$exists": false } },
+                doc! { "$set": { "public_id": new_public_id() } },
+                None,
+            )
+            .await?;
+        updated += 1;
+    }
+    Ok(updated)
+}
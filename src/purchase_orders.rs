@@ -0,0 +1,409 @@
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use chrono::{DateTime as ChronoDateTime, Utc};
+use futures::{FutureExt, TryStreamExt};
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    options::FindOptions,
+    ClientSession, Collection,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use tracing::{debug, error, info};
+use validator::Validate;
+
+use crate::{
+    auth::Claims,
+    config::MongoConfig,
+    errors::ApiError,
+    locations::{self, check_location},
+    models::Product,
+    money::{self, Decimal},
+    stock,
+    suppliers::{parse_supplier_id, suppliers_collection},
+    transactions::{run_in_transaction, TransactionError},
+    validation::validation_error,
+};
+
+const MAX_ITEMS: u64 = 100;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PurchaseOrderStatus {
+    Open,
+    Received,
+    Cancelled,
+}
+
+impl fmt::Display for PurchaseOrderStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PurchaseOrderStatus::Open => "open",
+            PurchaseOrderStatus::Received => "received",
+            PurchaseOrderStatus::Cancelled => "cancelled",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PurchaseOrderItem {
+    pub product_id: ObjectId,
+    pub name: String,
+    pub quantity: i64,
+    #[serde(with = "money::price")]
+    pub unit_cost: Decimal,
+    #[serde(with = "money::price")]
+    pub line_total: Decimal,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PurchaseOrder {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub supplier_id: ObjectId,
+    // Where received goods are put; unallocated when unset
+    #[serde(default)]
+    pub location_id: Option<ObjectId>,
+    pub items: Vec<PurchaseOrderItem>,
+    #[serde(with = "money::price")]
+    pub total: Decimal,
+    pub status: PurchaseOrderStatus,
+    pub notes: Option<String>,
+    pub expected_at: Option<DateTime>,
+    pub created_by: ObjectId,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+    pub received_at: Option<DateTime>,
+}
+
+// Serialize lets validation errors echo the rejected lines
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct PurchaseOrderItemRequest {
+    pub product_id: String,
+    #[validate(range(min = 1, message = "quantity must be at least 1"))]
+    pub quantity: i64,
+    // Defaults to the product's cost_price
+    #[serde(default, with = "money::option_input_price")]
+    pub unit_cost: Option<Decimal>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct CreatePurchaseOrderRequest {
+    pub supplier_id: String,
+    pub location_id: Option<String>,
+    #[validate(length(min = 1, max = "MAX_ITEMS", message = "items must hold between 1 and 100 lines"))]
+    #[validate]
+    pub items: Vec<PurchaseOrderItemRequest>,
+    #[validate(length(max = 1000, message = "notes must be at most 1000 characters"))]
+    pub notes: Option<String>,
+    pub expected_at: Option<ChronoDateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListPurchaseOrdersQuery {
+    status: Option<PurchaseOrderStatus>,
+    supplier_id: Option<String>,
+    product_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PurchaseOrderItemResponse {
+    pub product_id: String,
+    pub name: String,
+    pub quantity: i64,
+    #[serde(with = "money::price")]
+    pub unit_cost: Decimal,
+    #[serde(with = "money::price")]
+    pub line_total: Decimal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PurchaseOrderResponse {
+    pub id: String,
+    pub supplier_id: String,
+    pub location_id: Option<String>,
+    pub items: Vec<PurchaseOrderItemResponse>,
+    #[serde(with = "money::price")]
+    pub total: Decimal,
+    pub status: PurchaseOrderStatus,
+    pub notes: Option<String>,
+    pub expected_at: Option<String>,
+    pub created_by: String,
+    pub created_at: String,
+    pub updated_at: String,
+    pub received_at: Option<String>,
+}
+
+impl From<&PurchaseOrder> for PurchaseOrderResponse {
+    fn from(order: &PurchaseOrder) -> Self {
+        PurchaseOrderResponse {
+            id: order.id.map(|id| id.to_string()).unwrap_or_default(),
+            supplier_id: order.supplier_id.to_string(),
+            location_id: order.location_id.map(|id| id.to_string()),
+            items: order
+                .items
+                .iter()
+                .map(|item| PurchaseOrderItemResponse {
+                    product_id: item.product_id.to_string(),
+                    name: item.name.clone(),
+                    quantity: item.quantity,
+                    unit_cost: item.unit_cost,
+                    line_total: item.line_total,
+                })
+                .collect(),
+            total: order.total,
+            status: order.status,
+            notes: order.notes.clone(),
+            expected_at: order.expected_at.and_then(|t| t.try_to_rfc3339_string().ok()),
+            created_by: order.created_by.to_string(),
+            created_at: order.created_at.try_to_rfc3339_string().unwrap_or_default(),
+            updated_at: order.updated_at.try_to_rfc3339_string().unwrap_or_default(),
+            received_at: order.received_at.and_then(|t| t.try_to_rfc3339_string().ok()),
+        }
+    }
+}
+
+pub fn purchase_orders_collection(db: &MongoConfig) -> Collection<PurchaseOrder> {
+    db.database.collection("purchase_orders")
+}
+
+fn parse_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| {
+        error!("Invalid ID format: {}", id);
+        ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format").into()
+    })
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+async fn find_purchase_order(db: &MongoConfig, id: &str) -> Result<Option<PurchaseOrder>, Error> {
+    let order_id = parse_id(id)?;
+    purchase_orders_collection(db)
+        .find_one(doc! { "_id": order_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch purchase order", e))
+}
+
+// Turns the requested lines into order items, pricing each at the requested
+// cost or the product's cost price
+async fn build_items(db: &MongoConfig, requests: &[PurchaseOrderItemRequest]) -> Result<Vec<PurchaseOrderItem>, Error> {
+    let products: Collection<Product> = db.database.collection("products");
+    let mut items = Vec::with_capacity(requests.len());
+
+    for request in requests {
+        let product_id = parse_id(&request.product_id)?;
+        let Some(product) = products
+            .find_one(doc! { "_id": product_id }, None)
+            .await
+            .map_err(|e| db_error("Failed to fetch product", e))?
+        else {
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, format!("Product not found: {}", product_id)).into());
+        };
+
+        let unit_cost = match request.unit_cost.or(product.cost_price) {
+            Some(cost) if !cost.is_sign_negative() => cost,
+            Some(_) => return Err(ApiError::new(StatusCode::BAD_REQUEST, "unit_cost must be non-negative").into()),
+            None => {
+                return Err(ApiError::new(StatusCode::BAD_REQUEST, format!(
+                    "unit_cost is required for {}, which has no cost_price",
+                    product.name
+                )).into());
+            }
+        };
+
+        items.push(PurchaseOrderItem {
+            product_id,
+            name: product.name,
+            quantity: request.quantity,
+            unit_cost,
+            line_total: unit_cost * Decimal::from(request.quantity),
+        });
+    }
+    Ok(items)
+}
+
+pub async fn create_purchase_order(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    request: web::Json<CreatePurchaseOrderRequest>,
+) -> Result<HttpResponse, Error> {
+    request.validate().map_err(validation_error)?;
+
+    let supplier_id = parse_supplier_id(&request.supplier_id)?;
+    let supplier_exists = suppliers_collection(&db)
+        .count_documents(doc! { "_id": supplier_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch supplier", e))?;
+    if supplier_exists == 0 {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, format!("Supplier not found: {}", supplier_id)).into());
+    }
+
+    let location_id = match &request.location_id {
+        Some(id) => Some(check_location(&db, id).await?),
+        None => None,
+    };
+    let items = build_items(&db, &request.items).await?;
+    let now = DateTime::now();
+    let request = request.into_inner();
+    let mut order = PurchaseOrder {
+        id: None,
+        supplier_id,
+        location_id,
+        total: items.iter().map(|item| item.line_total).sum(),
+        items,
+        status: PurchaseOrderStatus::Open,
+        notes: request.notes,
+        expected_at: request.expected_at.map(|t| DateTime::from_millis(t.timestamp_millis())),
+        created_by: claims.user_id()?,
+        created_at: now,
+        updated_at: now,
+        received_at: None,
+    };
+
+    let result = purchase_orders_collection(&db)
+        .insert_one(&order, None)
+        .await
+        .map_err(|e| db_error("Failed to create purchase order", e))?;
+    order.id = result.inserted_id.as_object_id();
+
+    info!("Purchase order {} created for supplier {}", result.inserted_id, supplier_id);
+    Ok(HttpResponse::Created().json(PurchaseOrderResponse::from(&order)))
+}
+
+/// Purchase orders, newest first, by status, supplier or product.
+pub async fn list_purchase_orders(
+    db: web::Data<MongoConfig>,
+    query: web::Query<ListPurchaseOrdersQuery>,
+) -> Result<HttpResponse, Error> {
+    let mut filter = Document::new();
+    if let Some(status) = query.status {
+        filter.insert("status", status.to_string());
+    }
+    if let Some(supplier_id) = &query.supplier_id {
+        filter.insert("supplier_id", parse_id(supplier_id)?);
+    }
+    if let Some(product_id) = &query.product_id {
+        filter.insert("items.product_id", parse_id(product_id)?);
+    }
+
+    let options = FindOptions::builder().sort(doc! { "created_at": -1 }).build();
+    let orders: Vec<PurchaseOrder> = purchase_orders_collection(&db)
+        .find(filter, options)
+        .await
+        .map_err(|e| db_error("Failed to fetch purchase orders", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating purchase orders", e))?;
+
+    let orders: Vec<PurchaseOrderResponse> = orders.iter().map(PurchaseOrderResponse::from).collect();
+    Ok(HttpResponse::Ok().json(orders))
+}
+
+pub async fn get_purchase_order(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    match find_purchase_order(&db, &id).await? {
+        Some(order) => Ok(HttpResponse::Ok().json(PurchaseOrderResponse::from(&order))),
+        None => Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    }
+}
+
+// Closes an open purchase order, adding its quantities to stock for receipts,
+// inside `session`'s transaction. Aborts when the order is no longer open.
+async fn close_purchase_order(
+    db: &MongoConfig,
+    session: &mut ClientSession,
+    order: &PurchaseOrder,
+    next: PurchaseOrderStatus,
+) -> Result<DateTime, TransactionError<()>> {
+    let now = DateTime::now();
+    let mut set = doc! { "status": next.to_string(), "updated_at": now };
+    if next == PurchaseOrderStatus::Received {
+        set.insert("received_at", now);
+    }
+
+    let result = purchase_orders_collection(db)
+        .update_one_with_session(
+            doc! { "_id": order.id, "status": PurchaseOrderStatus::Open.to_string() },
+            doc! { "$set": set },
+            None,
+            session,
+        )
+        .await?;
+    if result.modified_count == 0 {
+        return Err(TransactionError::Aborted(()));
+    }
+
+    if next == PurchaseOrderStatus::Received {
+        for item in &order.items {
+            if !stock::adjust_stock_with_session(db, session, item.product_id, item.quantity).await? {
+                debug!("Product {} does not track stock, nothing to receive", item.product_id);
+                continue;
+            }
+            if let Some(location_id) = order.location_id {
+                locations::add_to_location_with_session(db, session, item.product_id, location_id, item.quantity)
+                    .await?;
+            }
+        }
+    }
+    Ok(now)
+}
+
+async fn transition_purchase_order(
+    db: &MongoConfig,
+    id: &str,
+    next: PurchaseOrderStatus,
+) -> Result<HttpResponse, Error> {
+    let Some(mut order) = find_purchase_order(db, id).await? else {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    };
+    if order.status != PurchaseOrderStatus::Open {
+        return Err(ApiError::new(StatusCode::CONFLICT, format!("Purchase order is already {}", order.status)).into());
+    }
+
+    let outcome = run_in_transaction(db, (db, &order), |session, (db, order)| {
+        close_purchase_order(db, session, order, next).boxed()
+    })
+    .await;
+
+    let now = match outcome {
+        Ok(now) => now,
+        Err(TransactionError::Aborted(())) => {
+            return Err(ApiError::new(StatusCode::CONFLICT, "Purchase order was modified concurrently, retry").into());
+        }
+        Err(TransactionError::Database(e)) => {
+            return Err(db_error("Failed to update purchase order", e));
+        }
+    };
+
+    info!("Purchase order {} is now {}", id, next);
+    order.status = next;
+    order.updated_at = now;
+    if next == PurchaseOrderStatus::Received {
+        order.received_at = Some(now);
+    }
+    Ok(HttpResponse::Ok().json(PurchaseOrderResponse::from(&order)))
+}
+
+/// Marks an open purchase order as delivered and adds its quantities to the
+/// stock of every stock-tracked product on it.
+pub async fn receive_purchase_order(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    transition_purchase_order(&db, &id, PurchaseOrderStatus::Received).await
+}
+
+pub async fn cancel_purchase_order(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    transition_purchase_order(&db, &id, PurchaseOrderStatus::Cancelled).await
+}
@@ -0,0 +1,222 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{HeaderName, HeaderValue},
+        StatusCode,
+    },
+    middleware::Next,
+    web, Error, HttpMessage, HttpResponse,
+};
+use futures::future::BoxFuture;
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::{
+    auth::Claims,
+    config::RateLimitConfig,
+    errors::{ApiError, PROBLEM_JSON},
+};
+
+/// Where request counters live. Each instance counts on its own in memory;
+/// clustered deployments share counters through Redis.
+pub trait CounterStore: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Adds one to `key` and returns the new count. The counter is dropped
+    /// `ttl` after it was created.
+    fn increment<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, Result<u64, String>>;
+
+    /// The count of `key` without adding to it; 0 once it expired.
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<u64, String>>;
+}
+
+#[derive(Default)]
+pub struct MemoryCounters {
+    counters: Mutex<HashMap<String, (u64, Instant)>>,
+}
+
+impl CounterStore for MemoryCounters {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn increment<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, Result<u64, String>> {
+        let now = Instant::now();
+        let mut counters = self.counters.lock().unwrap();
+        if !counters.contains_key(key) {
+            // Counters of past windows go once a new one starts
+            counters.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+        let (count, _) = counters.entry(key.to_string()).or_insert((0, now + ttl));
+        *count += 1;
+        let count = *count;
+        Box::pin(async move { Ok(count) })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<u64, String>> {
+        let count = match self.counters.lock().unwrap().get(key) {
+            Some((count, expires_at)) if *expires_at > Instant::now() => *count,
+            _ => 0,
+        };
+        Box::pin(async move { Ok(count) })
+    }
+}
+
+/// A store for the counters in `url`; only redis:// is supported.
+pub fn connect(url: &str) -> Result<Arc<dyn CounterStore>, String> {
+    match url.split_once("://").map(|(scheme, _)| scheme) {
+        #[cfg(feature = "redis")]
+        Some("redis") => Ok(Arc::new(crate::redis::RedisCounters::from_url(url)?)),
+        #[cfg(not(feature = "redis"))]
+        Some("redis") => Err("this build lacks the `redis` feature".to_string()),
+        _ => Err("unsupported scheme, only redis:// is supported".to_string()),
+    }
+}
+
+/// Fixed-window request limits per authenticated user, and per API key for
+/// clients of the gRPC API.
+pub struct RateLimiter {
+    limit: u64,
+    window: Duration,
+    store: Arc<dyn CounterStore>,
+}
+
+impl RateLimiter {
+    /// A limiter counting in RATE_LIMIT_REDIS_URL when set, in memory
+    /// otherwise. None when limits are turned off.
+    pub fn from_config(config: RateLimitConfig) -> Option<Self> {
+        if config.requests == 0 {
+            info!("Rate limiting per user and API key is disabled");
+            return None;
+        }
+
+        let store: Arc<dyn CounterStore> = match config.redis_url.as_deref().map(connect) {
+            Some(Ok(store)) => store,
+            Some(Err(e)) => {
+                warn!("Rate limits are counted per instance, not in RATE_LIMIT_REDIS_URL: {}", e);
+                Arc::new(MemoryCounters::default())
+            }
+            None => Arc::new(MemoryCounters::default()),
+        };
+        info!(
+            "Rate limiting to {} requests per {}s per user and API key, counted in {}",
+            config.requests,
+            config.window_secs,
+            store.name()
+        );
+        Some(RateLimiter { limit: config.requests, window: Duration::from_secs(config.window_secs.max(1)), store })
+    }
+
+    /// Counts a request against `bucket`, e.g. from `user_bucket` or
+    /// `key_bucket`. Returns whether it is over the limit, and the usage.
+    pub async fn count(&self, bucket: &str) -> Result<(bool, Usage), String> {
+        let (window, reset) = current_window(self.window);
+        let key = format!("ratelimit:{}:{}", bucket, window);
+        let count = self.store.increment(&key, reset).await?;
+        let usage = Usage { limit: self.limit, remaining: self.limit.saturating_sub(count), reset };
+        Ok((count > self.limit, usage))
+    }
+
+    pub fn store_name(&self) -> &'static str {
+        self.store.name()
+    }
+}
+
+pub struct Usage {
+    limit: u64,
+    remaining: u64,
+    // Until the window resets
+    reset: Duration,
+}
+
+impl Usage {
+    /// The X-RateLimit-* values, as header names and numbers.
+    pub fn fields(&self) -> [(&'static str, u64); 3] {
+        [
+            ("x-ratelimit-limit", self.limit),
+            ("x-ratelimit-remaining", self.remaining),
+            ("x-ratelimit-reset", self.reset.as_secs().max(1)),
+        ]
+    }
+
+    pub fn retry_after_secs(&self) -> u64 {
+        self.reset.as_secs().max(1)
+    }
+
+    fn headers(&self) -> [(HeaderName, HeaderValue); 3] {
+        self.fields().map(|(name, value)| (HeaderName::from_static(name), HeaderValue::from(value)))
+    }
+}
+
+/// The number of the current fixed window of `length` and the time until it
+/// ends. Windows are aligned to the epoch so every instance agrees on where
+/// they start.
+pub fn current_window(length: Duration) -> (u64, Duration) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let window_secs = length.as_secs().max(1);
+    let window = now.as_secs() / window_secs;
+    (window, Duration::from_secs((window + 1) * window_secs).saturating_sub(now))
+}
+
+fn user_bucket(user_id: &str) -> String {
+    format!("user:{}", user_id)
+}
+
+/// The bucket of the API key `key`, named by a digest so the key itself is
+/// never written to the counter store.
+pub fn key_bucket(key: &str) -> String {
+    let digest = Sha256::digest(key.as_bytes());
+    let fingerprint: String = digest[..8].iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("key:{}", fingerprint)
+}
+
+/// Answers 429 once a user has used up the requests of the current window,
+/// and reports their usage in X-RateLimit-* headers. Must run after
+/// AuthMiddleware; unauthenticated requests are not counted.
+pub async fn limit_per_user(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let limiter = req.app_data::<web::Data<RateLimiter>>().cloned();
+    let user_id = req.extensions().get::<Claims>().map(|claims| claims.sub.clone());
+    let (Some(limiter), Some(user_id)) = (limiter, user_id) else {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    };
+
+    // Counting must not take the API down with it, so a failing store lets requests through
+    let usage = match limiter.count(&user_bucket(&user_id)).await {
+        Ok((true, usage)) => {
+            debug!("User {} is over the rate limit of {} requests", user_id, limiter.limit);
+            let mut response = HttpResponse::TooManyRequests();
+            for header in usage.headers() {
+                response.insert_header(header);
+            }
+            let response = response
+                .insert_header(("Retry-After", usage.retry_after_secs().to_string()))
+                .content_type(PROBLEM_JSON)
+                .json(ApiError::new(StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded, try again later"));
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+        Ok((false, usage)) => Some(usage),
+        Err(e) => {
+            warn!("Failed to count request against the rate limit in {}: {}", limiter.store.name(), e);
+            None
+        }
+    };
+
+    let mut res = next.call(req).await?;
+    if let Some(usage) = usage {
+        let headers = res.headers_mut();
+        for (name, value) in usage.headers() {
+            headers.insert(name, value);
+        }
+    }
+    Ok(res.map_into_left_body())
+}
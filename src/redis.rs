@@ -0,0 +1,196 @@
+use std::{io, time::Duration};
+
+use futures::future::BoxFuture;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::Mutex,
+    time::timeout,
+};
+use tracing::{debug, info};
+
+use crate::{rate_limit::CounterStore, settings::url_credentials};
+
+const DEFAULT_PORT: u16 = 6379;
+const IO_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Rate limit counters in Redis, shared by every instance of the API. Speaks
+/// just enough of RESP for INCR, EXPIRE and GET over a single connection.
+pub struct RedisCounters {
+    address: String,
+    user: Option<String>,
+    password: Option<String>,
+    database: Option<u32>,
+    connection: Mutex<Option<BufReader<TcpStream>>>,
+}
+
+fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// A command as a RESP array of bulk strings
+fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut frame = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        frame.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        frame.extend_from_slice(arg);
+        frame.extend_from_slice(b"\r\n");
+    }
+    frame
+}
+
+async fn read_line(connection: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let mut line = String::new();
+    if connection.read_line(&mut line).await? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+    }
+    Ok(line.trim_end().to_string())
+}
+
+// Simple string, integer and bulk string replies, all that the commands used
+// here return. A missing key reads as an empty string
+async fn read_reply(connection: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let line = read_line(connection).await?;
+    match line.split_at_checked(1) {
+        Some(("+" | ":", value)) => Ok(value.to_string()),
+        Some(("$", "-1")) => Ok(String::new()),
+        // Counters are short enough to never span lines
+        Some(("$", _)) => read_line(connection).await,
+        Some(("-", message)) => Err(protocol_error(format!("server error: {}", message))),
+        _ => Err(protocol_error(format!("unexpected reply: {}", line))),
+    }
+}
+
+// The server closed the connection, e.g. after a restart, so a command
+// written to it was never run
+fn connection_lost(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+async fn call(connection: &mut BufReader<TcpStream>, args: &[&[u8]]) -> io::Result<String> {
+    connection.get_mut().write_all(&encode(args)).await?;
+    read_reply(connection).await
+}
+
+enum Command<'a> {
+    Increment(&'a str, Duration),
+    Get(&'a str),
+}
+
+impl Command<'_> {
+    // Whether sending it again after a failure could count a request twice
+    fn safe_to_retry(&self, e: &io::Error) -> bool {
+        match self {
+            Command::Increment(..) => connection_lost(e),
+            Command::Get(_) => true,
+        }
+    }
+}
+
+impl RedisCounters {
+    pub fn from_url(url: &str) -> Result<Self, String> {
+        let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+        let host = url.host_str().ok_or("missing host")?;
+        let database = match url.path().trim_start_matches('/') {
+            "" => None,
+            database => Some(database.parse().map_err(|_| format!("invalid database number: {}", database))?),
+        };
+        let (user, password) = url_credentials(&url)?;
+        Ok(RedisCounters {
+            address: format!("{}:{}", host, url.port().unwrap_or(DEFAULT_PORT)),
+            user,
+            password,
+            database,
+            connection: Mutex::new(None),
+        })
+    }
+
+    async fn connect(&self) -> io::Result<BufReader<TcpStream>> {
+        let mut connection = BufReader::new(TcpStream::connect(&self.address).await?);
+        if let Some(password) = &self.password {
+            match &self.user {
+                Some(user) => call(&mut connection, &[b"AUTH", user.as_bytes(), password.as_bytes()]).await?,
+                None => call(&mut connection, &[b"AUTH", password.as_bytes()]).await?,
+            };
+        }
+        if let Some(database) = self.database {
+            call(&mut connection, &[b"SELECT", database.to_string().as_bytes()]).await?;
+        }
+        info!("Connected to Redis at {}", self.address);
+        Ok(connection)
+    }
+
+    // INCR and EXPIRE pipelined in one round trip
+    async fn incr_with_expiry(connection: &mut BufReader<TcpStream>, key: &str, ttl: Duration) -> io::Result<u64> {
+        let ttl = ttl.as_secs().max(1).to_string();
+        let mut frame = encode(&[b"INCR", key.as_bytes()]);
+        frame.extend_from_slice(&encode(&[b"EXPIRE", key.as_bytes(), ttl.as_bytes()]));
+        connection.get_mut().write_all(&frame).await?;
+
+        let count = read_reply(connection).await?;
+        // The INCR has run by now, so this is not a lost connection to retry on
+        read_reply(connection)
+            .await
+            .map_err(|e| io::Error::other(format!("EXPIRE after INCR failed: {}", e)))?;
+        count.parse().map_err(|_| protocol_error(format!("INCR returned {}", count)))
+    }
+
+    async fn get(connection: &mut BufReader<TcpStream>, key: &str) -> io::Result<u64> {
+        let value = call(connection, &[b"GET", key.as_bytes()]).await?;
+        if value.is_empty() {
+            return Ok(0);
+        }
+        value.parse().map_err(|_| protocol_error(format!("GET returned {}", value)))
+    }
+
+    async fn send(connection: &mut BufReader<TcpStream>, command: &Command<'_>) -> io::Result<u64> {
+        match *command {
+            Command::Increment(key, ttl) => Self::incr_with_expiry(connection, key, ttl).await,
+            Command::Get(key) => Self::get(connection, key).await,
+        }
+    }
+
+    // Reconnects once if the connection broke, so a Redis restart costs at most
+    // a request. An INCR that timed out or failed mid-reply may have run
+    // already, so it is not sent again
+    async fn execute(&self, command: Command<'_>) -> io::Result<u64> {
+        let mut connection = self.connection.lock().await;
+        if let Some(open) = connection.as_mut() {
+            let error = match timeout(IO_TIMEOUT, Self::send(open, &command)).await {
+                Ok(Ok(count)) => return Ok(count),
+                Ok(Err(e)) => e,
+                Err(_) => io::Error::new(io::ErrorKind::TimedOut, "Redis command timed out"),
+            };
+            *connection = None;
+            if !command.safe_to_retry(&error) {
+                return Err(error);
+            }
+            debug!("Redis command failed, reconnecting: {}", error);
+        }
+
+        let mut open = timeout(IO_TIMEOUT, self.connect()).await??;
+        let count = timeout(IO_TIMEOUT, Self::send(&mut open, &command)).await??;
+        *connection = Some(open);
+        Ok(count)
+    }
+}
+
+impl CounterStore for RedisCounters {
+    fn name(&self) -> &'static str {
+        "Redis"
+    }
+
+    fn increment<'a>(&'a self, key: &'a str, ttl: Duration) -> BoxFuture<'a, Result<u64, String>> {
+        Box::pin(async move { self.execute(Command::Increment(key, ttl)).await.map_err(|e| e.to_string()) })
+    }
+
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Result<u64, String>> {
+        Box::pin(async move { self.execute(Command::Get(key)).await.map_err(|e| e.to_string()) })
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    options::FindOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+use validator::Validate;
+
+use crate::{
+    auth::Claims,
+    barcode::is_duplicate_key,
+    config::MongoConfig,
+    errors::ApiError,
+    models::Product,
+    money::{self, Decimal},
+    public_ids,
+    validation::{validation_error, ValidatedQuery},
+};
+
+const RELATIONSHIPS: &str = "product_relationships";
+// Bounds the cycle search; chains this long are a data problem anyway
+const MAX_CHAIN_DEPTH: i32 = 50;
+const MAX_IMPORT_ROWS: usize = 1000;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum RelationshipKind {
+    Related,
+    Upsell,
+    CrossSell,
+}
+
+impl RelationshipKind {
+    fn key(self) -> &'static str {
+        match self {
+            RelationshipKind::Related => "related",
+            RelationshipKind::Upsell => "upsell",
+            RelationshipKind::CrossSell => "cross_sell",
+        }
+    }
+}
+
+/// A directed link from one product to another, e.g. "offer B as an
+/// upgrade on A's page".
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProductRelationship {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub source_id: ObjectId,
+    pub target_id: ObjectId,
+    pub kind: RelationshipKind,
+    // Lower comes first among the source's relationships of a kind
+    #[serde(default)]
+    pub position: i32,
+    pub created_by: ObjectId,
+    pub created_at: DateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RelationshipResponse {
+    pub id: String,
+    pub source_id: String,
+    pub target_id: String,
+    pub kind: RelationshipKind,
+    pub position: i32,
+    pub created_at: String,
+}
+
+impl From<&ProductRelationship> for RelationshipResponse {
+    fn from(relationship: &ProductRelationship) -> Self {
+        RelationshipResponse {
+            id: relationship.id.map(|id| id.to_string()).unwrap_or_default(),
+            source_id: relationship.source_id.to_string(),
+            target_id: relationship.target_id.to_string(),
+            kind: relationship.kind,
+            position: relationship.position,
+            created_at: relationship.created_at.try_to_rfc3339_string().unwrap_or_default(),
+        }
+    }
+}
+
+/// A related product as embedded in product responses.
+#[derive(Debug, Serialize)]
+pub struct RelatedProduct {
+    pub id: String,
+    pub name: String,
+    #[serde(with = "money::price")]
+    pub price: Decimal,
+    pub position: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct RelationshipRequest {
+    pub target_id: String,
+    pub kind: RelationshipKind,
+    #[serde(default)]
+    #[validate(range(min = 0, max = 1000, message = "position must be between 0 and 1000"))]
+    pub position: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ImportRelationshipRow {
+    pub source_id: String,
+    pub target_id: String,
+    pub kind: RelationshipKind,
+    #[serde(default)]
+    #[validate(range(min = 0, max = 1000, message = "position must be between 0 and 1000"))]
+    pub position: i32,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ImportRelationshipsRequest {
+    #[validate(length(min = 1, message = "relationships must not be empty"))]
+    #[validate]
+    pub relationships: Vec<ImportRelationshipRow>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+pub struct ListRelationshipsQuery {
+    kind: Option<RelationshipKind>,
+}
+
+enum LinkError {
+    SelfLink,
+    UnknownProduct(String),
+    Duplicate,
+    Cycle,
+    Database(mongodb::error::Error),
+}
+
+impl LinkError {
+    fn message(&self) -> String {
+        match self {
+            LinkError::SelfLink => "A product can't be related to itself".to_string(),
+            LinkError::UnknownProduct(id) => format!("Product {} does not exist", id),
+            LinkError::Duplicate => "The relationship already exists".to_string(),
+            LinkError::Cycle => "The relationship would create a cycle".to_string(),
+            LinkError::Database(e) => format!("Database error: {}", e),
+        }
+    }
+
+    fn to_problem(&self) -> ApiError {
+        let status = match self {
+            LinkError::SelfLink | LinkError::UnknownProduct(_) => StatusCode::BAD_REQUEST,
+            LinkError::Duplicate | LinkError::Cycle => StatusCode::CONFLICT,
+            LinkError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ApiError::new(status, self.message())
+    }
+}
+
+fn relationships_collection(db: &MongoConfig) -> Collection<ProductRelationship> {
+    db.database.collection(RELATIONSHIPS)
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+// Whether `source` can already be reached from `target` over relationships of `kind`
+async fn creates_cycle(
+    db: &MongoConfig,
+    source_id: ObjectId,
+    target_id: ObjectId,
+    kind: RelationshipKind,
+) -> Result<bool, mongodb::error::Error> {
+    let pipeline = vec![
+        doc! { "$match": { "source_id": target_id, "kind": kind.key() } },
+        doc! {
+            "$graphLookup": {
+                "from": RELATIONSHIPS,
+                "startWith": "$target_id",
+                "connectFromField": "target_id",
+                "connectToField": "source_id",
+                "as": "reachable",
+                "maxDepth": MAX_CHAIN_DEPTH,
+                "restrictSearchWithMatch": { "kind": kind.key() },
+            }
+        },
+        doc! { "$match": { "$or": [{ "target_id": source_id }, { "reachable.target_id": source_id }] } },
+        doc! { "$limit": 1 },
+    ];
+    let mut cursor = relationships_collection(db).aggregate(pipeline, None).await?;
+    Ok(cursor.try_next().await?.is_some())
+}
+
+async fn product_exists(db: &MongoConfig, id: ObjectId) -> Result<bool, mongodb::error::Error> {
+    let products: Collection<Document> = db.database.collection("products");
+    Ok(products.count_documents(doc! { "_id": id }, None).await? > 0)
+}
+
+async fn link(
+    db: &MongoConfig,
+    source_id: ObjectId,
+    target_id: ObjectId,
+    kind: RelationshipKind,
+    position: i32,
+    created_by: ObjectId,
+) -> Result<ProductRelationship, LinkError> {
+    if source_id == target_id {
+        return Err(LinkError::SelfLink);
+    }
+    for id in [source_id, target_id] {
+        if !product_exists(db, id).await.map_err(LinkError::Database)? {
+            return Err(LinkError::UnknownProduct(id.to_string()));
+        }
+    }
+    if creates_cycle(db, source_id, target_id, kind).await.map_err(LinkError::Database)? {
+        return Err(LinkError::Cycle);
+    }
+
+    let mut relationship = ProductRelationship {
+        id: None,
+        source_id,
+        target_id,
+        kind,
+        position,
+        created_by,
+        created_at: DateTime::now(),
+    };
+    let result = relationships_collection(db).insert_one(&relationship, None).await.map_err(|e| {
+        if is_duplicate_key(&e) {
+            LinkError::Duplicate
+        } else {
+            LinkError::Database(e)
+        }
+    })?;
+    relationship.id = result.inserted_id.as_object_id();
+    Ok(relationship)
+}
+
+/// The product's active related products, grouped by kind, for embedding
+/// in product responses. Links to deleted or inactive products are left out.
+pub async fn embedded(
+    db: &MongoConfig,
+    product_id: ObjectId,
+) -> Result<BTreeMap<RelationshipKind, Vec<RelatedProduct>>, Error> {
+    let options = FindOptions::builder().sort(doc! { "position": 1, "created_at": 1 }).build();
+    let relationships: Vec<ProductRelationship> = relationships_collection(db)
+        .find(doc! { "source_id": product_id }, options)
+        .await
+        .map_err(|e| db_error("Failed to fetch product relationships", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating product relationships", e))?;
+    if relationships.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+
+    let ids: Vec<ObjectId> = relationships.iter().map(|relationship| relationship.target_id).collect();
+    let products: Collection<Product> = db.catalog_collection("products");
+    let targets: Vec<Product> = products
+        .find(doc! { "_id": { "$in": ids }, "status": { "$in": ["active", null] } }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch related products", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating related products", e))?;
+
+    let mut embedded: BTreeMap<RelationshipKind, Vec<RelatedProduct>> = BTreeMap::new();
+    for relationship in &relationships {
+        let Some(target) = targets.iter().find(|target| target.id == Some(relationship.target_id)) else {
+            continue;
+        };
+        embedded.entry(relationship.kind).or_default().push(RelatedProduct {
+            id: relationship.target_id.to_string(),
+            name: target.name.clone(),
+            price: target.price,
+            position: relationship.position,
+        });
+    }
+    Ok(embedded)
+}
+
+/// The product's outgoing relationships, including those to inactive products.
+pub async fn list_relationships(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+    query: ValidatedQuery<ListRelationshipsQuery>,
+) -> Result<HttpResponse, Error> {
+    let product_id = public_ids::resolve_product_id(&db, &id).await?;
+    let mut filter = doc! { "source_id": product_id };
+    if let Some(kind) = query.kind {
+        filter.insert("kind", kind.key());
+    }
+
+    let options = FindOptions::builder().sort(doc! { "kind": 1, "position": 1, "created_at": 1 }).build();
+    let relationships: Vec<ProductRelationship> = relationships_collection(&db)
+        .find(filter, options)
+        .await
+        .map_err(|e| db_error("Failed to fetch product relationships", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating product relationships", e))?;
+
+    let relationships: Vec<RelationshipResponse> = relationships.iter().map(RelationshipResponse::from).collect();
+    Ok(HttpResponse::Ok().json(relationships))
+}
+
+pub async fn create_relationship(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+    request: web::Json<RelationshipRequest>,
+) -> Result<HttpResponse, Error> {
+    request.validate().map_err(validation_error)?;
+    let source_id = public_ids::resolve_product_id(&db, &id).await?;
+    let target_id = public_ids::resolve_product_id(&db, &request.target_id).await?;
+
+    match link(&db, source_id, target_id, request.kind, request.position, claims.user_id()?).await {
+        Ok(relationship) => {
+            info!("Linked product {} to {} as {}", source_id, target_id, request.kind.key());
+            Ok(HttpResponse::Created().json(RelationshipResponse::from(&relationship)))
+        }
+        Err(LinkError::Database(e)) => Err(db_error("Failed to create product relationship", e)),
+        Err(e) => {
+            debug!("Rejected relationship from {} to {}: {}", source_id, target_id, e.message());
+            Err(e.to_problem().into())
+        }
+    }
+}
+
+pub async fn delete_relationship(
+    db: web::Data<MongoConfig>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (id, relationship_id) = path.into_inner();
+    let source_id = public_ids::resolve_product_id(&db, &id).await?;
+    let relationship_id = ObjectId::parse_str(&relationship_id)
+        .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "Invalid relationship ID format"))?;
+
+    let result = relationships_collection(&db)
+        .delete_one(doc! { "_id": relationship_id, "source_id": source_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to delete product relationship", e))?;
+    if result.deleted_count == 0 {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Creates many relationships at once, in order, so later rows see the
+/// earlier ones when checking for cycles. Rows that fail are reported and
+/// skipped; the others are kept.
+pub async fn import_relationships(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    request: web::Json<ImportRelationshipsRequest>,
+) -> Result<HttpResponse, Error> {
+    request.validate().map_err(validation_error)?;
+    if request.relationships.len() > MAX_IMPORT_ROWS {
+        let message = format!("At most {} relationships can be imported at once", MAX_IMPORT_ROWS);
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, message).into());
+    }
+    let user_id = claims.user_id()?;
+
+    let mut imported = 0;
+    let mut errors = Vec::new();
+    for (index, row) in request.relationships.iter().enumerate() {
+        let ids = (
+            public_ids::resolve_product_id(&db, &row.source_id).await,
+            public_ids::resolve_product_id(&db, &row.target_id).await,
+        );
+        let (Ok(source_id), Ok(target_id)) = ids else {
+            errors.push(format!("Row {}: unknown or invalid product ID", index + 1));
+            continue;
+        };
+        match link(&db, source_id, target_id, row.kind, row.position, user_id).await {
+            Ok(_) => imported += 1,
+            Err(LinkError::Database(e)) => return Err(db_error("Failed to import product relationships", e)),
+            Err(e) => errors.push(format!("Row {}: {}", index + 1, e.message())),
+        }
+    }
+
+    info!("Imported {} product relationships, rejected {}", imported, errors.len());
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "imported": imported, "errors": errors })))
+}
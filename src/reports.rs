@@ -0,0 +1,285 @@
+use std::time::Duration;
+
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use chrono::{DateTime as ChronoDateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Bson, DateTime, Document},
+    options::AggregateOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::{debug, error};
+use validator::Validate;
+
+use crate::{
+    config::MongoConfig,
+    errors::ApiError,
+    models::{Category, ProductStatus},
+    money,
+    validation::validation_error,
+};
+
+// Reports scan the catalog; stop the ones that take too long
+const MAX_TIME: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportDimension {
+    Category,
+    Status,
+}
+
+impl ReportDimension {
+    fn key(self) -> &'static str {
+        match self {
+            ReportDimension::Category => "category",
+            ReportDimension::Status => "status",
+        }
+    }
+
+    fn expression(self) -> Bson {
+        match self {
+            ReportDimension::Category => Bson::String("$category".to_string()),
+            // Products from before statuses existed are active
+            ReportDimension::Status => Bson::Document(doc! { "$ifNull": ["$status", "active"] }),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportMetric {
+    Count,
+    AvgPrice,
+    MinPrice,
+    MaxPrice,
+    TotalStockUnits,
+    TotalStockValue,
+}
+
+impl ReportMetric {
+    fn key(self) -> &'static str {
+        match self {
+            ReportMetric::Count => "count",
+            ReportMetric::AvgPrice => "avg_price",
+            ReportMetric::MinPrice => "min_price",
+            ReportMetric::MaxPrice => "max_price",
+            ReportMetric::TotalStockUnits => "total_stock_units",
+            ReportMetric::TotalStockValue => "total_stock_value",
+        }
+    }
+
+    fn accumulator(self) -> Document {
+        let stock = doc! { "$ifNull": ["$stock_quantity", 0] };
+        match self {
+            ReportMetric::Count => doc! { "$sum": 1 },
+            ReportMetric::AvgPrice => doc! { "$avg": "$price" },
+            ReportMetric::MinPrice => doc! { "$min": "$price" },
+            ReportMetric::MaxPrice => doc! { "$max": "$price" },
+            ReportMetric::TotalStockUnits => doc! { "$sum": stock },
+            ReportMetric::TotalStockValue => doc! { "$sum": { "$multiply": ["$price", stock] } },
+        }
+    }
+
+    fn is_amount(self) -> bool {
+        matches!(
+            self,
+            ReportMetric::AvgPrice | ReportMetric::MinPrice | ReportMetric::MaxPrice | ReportMetric::TotalStockValue
+        )
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ReportFilters {
+    // Any of these; every status when empty
+    #[serde(default)]
+    pub statuses: Vec<ProductStatus>,
+    #[serde(default)]
+    pub categories: Vec<Category>,
+    pub has_active_sale: Option<bool>,
+    // Products created at or after this time
+    pub created_from: Option<ChronoDateTime<Utc>>,
+    // Products created before this time
+    pub created_to: Option<ChronoDateTime<Utc>>,
+}
+
+/// What a report computes. Only the dimensions and metrics below can be
+/// named, so every spec turns into a known-safe aggregation pipeline.
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ReportSpec {
+    // One row per combination of these; a single row for the whole catalog when empty
+    #[serde(default)]
+    #[validate(length(max = 2, message = "group_by takes at most 2 dimensions"))]
+    pub group_by: Vec<ReportDimension>,
+    #[validate(length(min = 1, max = 6, message = "metrics must list between 1 and 6 metrics"))]
+    pub metrics: Vec<ReportMetric>,
+    #[serde(default)]
+    #[validate]
+    pub filters: ReportFilters,
+    // Highest first; rows are ordered by their group otherwise
+    pub sort_by: Option<ReportMetric>,
+    #[validate(range(min = 1, max = 1000, message = "limit must be between 1 and 1000"))]
+    pub limit: Option<i64>,
+}
+
+fn has_duplicates<T: PartialEq>(items: &[T]) -> bool {
+    items.iter().enumerate().any(|(i, item)| items[..i].contains(item))
+}
+
+// The smallest ObjectId generated at `at`, for matching on creation time
+fn object_id_at(at: ChronoDateTime<Utc>) -> ObjectId {
+    let mut bytes = [0u8; 12];
+    bytes[..4].copy_from_slice(&(at.timestamp().clamp(0, u32::MAX as i64) as u32).to_be_bytes());
+    ObjectId::from_bytes(bytes)
+}
+
+impl ReportSpec {
+    // What the enums cannot rule out; None when the spec is usable
+    fn problem(&self) -> Option<String> {
+        if has_duplicates(&self.group_by) {
+            return Some("group_by lists a dimension twice".to_string());
+        }
+        if has_duplicates(&self.metrics) {
+            return Some("metrics lists a metric twice".to_string());
+        }
+        if let Some(sort_by) = self.sort_by {
+            if !self.metrics.contains(&sort_by) {
+                return Some(format!("sort_by must be one of the report's metrics, not {}", sort_by.key()));
+            }
+        }
+        if let (Some(from), Some(to)) = (self.filters.created_from, self.filters.created_to) {
+            if from >= to {
+                return Some("created_from must be before created_to".to_string());
+            }
+        }
+        None
+    }
+
+    fn filter(&self) -> Result<Document, mongodb::bson::ser::Error> {
+        let filters = &self.filters;
+        let mut filter = Document::new();
+        if !filters.statuses.is_empty() {
+            let mut statuses = mongodb::bson::to_bson(&filters.statuses)?;
+            // Products without a status count as active
+            if filters.statuses.contains(&ProductStatus::Active) {
+                if let Bson::Array(statuses) = &mut statuses {
+                    statuses.push(Bson::Null);
+                }
+            }
+            filter.insert("status", doc! { "$in": statuses });
+        }
+        if !filters.categories.is_empty() {
+            filter.insert("category", doc! { "$in": mongodb::bson::to_bson(&filters.categories)? });
+        }
+        if let Some(has_active_sale) = filters.has_active_sale {
+            filter.insert("has_active_sale", has_active_sale);
+        }
+        // Products have no creation date of their own; their ObjectId carries it
+        let mut created = Document::new();
+        if let Some(from) = filters.created_from {
+            created.insert("$gte", object_id_at(from));
+        }
+        if let Some(to) = filters.created_to {
+            created.insert("$lt", object_id_at(to));
+        }
+        if !created.is_empty() {
+            filter.insert("_id", created);
+        }
+        Ok(filter)
+    }
+
+    fn pipeline(&self) -> Result<Vec<Document>, mongodb::bson::ser::Error> {
+        let group_id = if self.group_by.is_empty() {
+            Bson::Null
+        } else {
+            let mut id = Document::new();
+            for dimension in &self.group_by {
+                id.insert(dimension.key(), dimension.expression());
+            }
+            Bson::Document(id)
+        };
+
+        let mut group = doc! { "_id": group_id };
+        for metric in &self.metrics {
+            group.insert(metric.key(), metric.accumulator());
+        }
+
+        let sort = match self.sort_by {
+            Some(metric) => doc! { metric.key(): -1, "_id": 1 },
+            None => doc! { "_id": 1 },
+        };
+
+        let mut pipeline = vec![doc! { "$match": self.filter()? }, doc! { "$group": group }, doc! { "$sort": sort }];
+        if let Some(limit) = self.limit {
+            pipeline.push(doc! { "$limit": limit });
+        }
+        Ok(pipeline)
+    }
+
+    // One output row: the group's dimensions, then its metrics
+    fn row(&self, result: &Document) -> Map<String, Value> {
+        let mut row = Map::new();
+        let group = result.get_document("_id").ok();
+        for dimension in &self.group_by {
+            let value = group.and_then(|group| group.get_str(dimension.key()).ok());
+            row.insert(dimension.key().to_string(), value.map(Value::from).unwrap_or(Value::Null));
+        }
+        for metric in &self.metrics {
+            let value = match result.get(metric.key()) {
+                Some(value) if metric.is_amount() => money::from_bson(value).map(money::to_json),
+                Some(Bson::Int32(value)) => Some(Value::from(*value)),
+                Some(Bson::Int64(value)) => Some(Value::from(*value)),
+                Some(Bson::Double(value)) => Some(Value::from(*value)),
+                _ => None,
+            };
+            row.insert(metric.key().to_string(), value.unwrap_or(Value::Null));
+        }
+        row
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportResponse {
+    pub group_by: Vec<ReportDimension>,
+    pub metrics: Vec<ReportMetric>,
+    pub rows: Vec<Map<String, Value>>,
+    pub generated_at: String,
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+/// Runs an ad hoc catalog report, e.g. average price and stock value per
+/// category of the active products.
+pub async fn run_report(db: web::Data<MongoConfig>, spec: web::Json<ReportSpec>) -> Result<HttpResponse, Error> {
+    spec.validate().map_err(validation_error)?;
+    if let Some(problem) = spec.problem() {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, problem).into());
+    }
+
+    let pipeline = spec.pipeline().map_err(ApiError::internal)?;
+    debug!("Running report pipeline {:?}", pipeline);
+
+    let collection: Collection<Document> = db.catalog_collection("products");
+    let options = AggregateOptions::builder().max_time(MAX_TIME).build();
+    let results: Vec<Document> = collection
+        .aggregate(pipeline, options)
+        .await
+        .map_err(|e| db_error("Failed to run report", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating report rows", e))?;
+
+    Ok(HttpResponse::Ok().json(ReportResponse {
+        group_by: spec.group_by.clone(),
+        metrics: spec.metrics.clone(),
+        rows: results.iter().map(|result| spec.row(result)).collect(),
+        generated_at: DateTime::now().try_to_rfc3339_string().unwrap_or_default(),
+    }))
+}
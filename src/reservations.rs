@@ -0,0 +1,251 @@
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::{FutureExt, TryStreamExt};
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    options::{ReplaceOptions, UpdateOptions},
+    ClientSession, Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use validator::Validate;
+
+use crate::{
+    auth::Claims,
+    config::{MongoConfig, ReservationConfig},
+    errors::ApiError,
+    models::Product,
+    public_ids,
+    transactions::{run_in_transaction, TransactionError},
+    validation::validation_error,
+};
+
+/// Units of a product held for one user during checkout. Expired
+/// reservations are removed by a TTL index on expires_at, and never count
+/// against stock even before the server gets to them.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Reservation {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub product_id: ObjectId,
+    pub user_id: ObjectId,
+    pub quantity: i64,
+    pub expires_at: DateTime,
+    pub created_at: DateTime,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ReserveRequest {
+    #[validate(range(min = 1, message = "quantity must be at least 1"))]
+    pub quantity: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReservationResponse {
+    pub product_id: String,
+    pub quantity: i64,
+    pub expires_at: String,
+}
+
+impl From<&Reservation> for ReservationResponse {
+    fn from(reservation: &Reservation) -> Self {
+        ReservationResponse {
+            product_id: reservation.product_id.to_string(),
+            quantity: reservation.quantity,
+            expires_at: reservation.expires_at.try_to_rfc3339_string().unwrap_or_default(),
+        }
+    }
+}
+
+// Business reasons for refusing a reservation
+enum ReservationRejection {
+    ProductNotFound,
+    Untracked,
+    InsufficientStock(i64),
+}
+
+pub fn reservations_collection(db: &MongoConfig) -> Collection<Reservation> {
+    db.database.collection("reservations")
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+/// Writes the product's lock document, so concurrent transactions that
+/// reserve or sell the same product conflict and one of them is retried
+/// instead of both counting the same units as free.
+pub async fn lock_product_with_session(
+    db: &MongoConfig,
+    session: &mut ClientSession,
+    product_id: ObjectId,
+) -> Result<(), mongodb::error::Error> {
+    let locks: Collection<Document> = db.database.collection("reservation_locks");
+    locks
+        .update_one_with_session(
+            doc! { "_id": product_id },
+            doc! { "$inc": { "version": 1 } },
+            UpdateOptions::builder().upsert(true).build(),
+            session,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Units of a product held by unexpired reservations of other users.
+pub async fn reserved_by_others_with_session(
+    db: &MongoConfig,
+    session: &mut ClientSession,
+    product_id: ObjectId,
+    user_id: ObjectId,
+) -> Result<i64, mongodb::error::Error> {
+    let filter = doc! {
+        "product_id": product_id,
+        "user_id": { "$ne": user_id },
+        "expires_at": { "$gt": DateTime::now() },
+    };
+    let reservations: Vec<Reservation> = reservations_collection(db)
+        .find_with_session(filter, None, session)
+        .await?
+        .stream(session)
+        .try_collect()
+        .await?;
+    Ok(reservations.iter().map(|reservation| reservation.quantity).sum())
+}
+
+/// Units of a product held by unexpired reservations of other users, outside
+/// a transaction; for stock checks that do not sell anything.
+pub async fn reserved_by_others(db: &MongoConfig, product_id: ObjectId, user_id: ObjectId) -> Result<i64, Error> {
+    let filter = doc! {
+        "product_id": product_id,
+        "user_id": { "$ne": user_id },
+        "expires_at": { "$gt": DateTime::now() },
+    };
+    let reservations: Vec<Reservation> = reservations_collection(db)
+        .find(filter, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch reservations", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating reservations", e))?;
+    Ok(reservations.iter().map(|reservation| reservation.quantity).sum())
+}
+
+/// Removes the user's reservations of products they just bought.
+pub async fn consume_with_session(
+    db: &MongoConfig,
+    session: &mut ClientSession,
+    user_id: ObjectId,
+    product_ids: &[ObjectId],
+) -> Result<(), mongodb::error::Error> {
+    reservations_collection(db)
+        .delete_many_with_session(doc! { "user_id": user_id, "product_id": { "$in": product_ids } }, None, session)
+        .await?;
+    Ok(())
+}
+
+async fn apply_reservation(
+    db: &MongoConfig,
+    session: &mut ClientSession,
+    reservation: &Reservation,
+) -> Result<(), TransactionError<ReservationRejection>> {
+    lock_product_with_session(db, session, reservation.product_id).await?;
+
+    let products: Collection<Product> = db.database.collection("products");
+    let Some(product) = products
+        .find_one_with_session(doc! { "_id": reservation.product_id }, None, session)
+        .await?
+    else {
+        return Err(TransactionError::Aborted(ReservationRejection::ProductNotFound));
+    };
+    let Some(stock_quantity) = product.stock_quantity else {
+        return Err(TransactionError::Aborted(ReservationRejection::Untracked));
+    };
+
+    let reserved = reserved_by_others_with_session(db, session, reservation.product_id, reservation.user_id).await?;
+    let available = stock_quantity - reserved;
+    if reservation.quantity > available {
+        return Err(TransactionError::Aborted(ReservationRejection::InsufficientStock(available.max(0))));
+    }
+
+    // One reservation per user and product; reserving again replaces it
+    reservations_collection(db)
+        .replace_one_with_session(
+            doc! { "product_id": reservation.product_id, "user_id": reservation.user_id },
+            reservation,
+            ReplaceOptions::builder().upsert(true).build(),
+            session,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Holds units of a product for the caller until they order it or the
+/// reservation expires. Other users can't buy or reserve held units.
+pub async fn reserve_product(
+    db: web::Data<MongoConfig>,
+    config: web::Data<ReservationConfig>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+    request: web::Json<ReserveRequest>,
+) -> Result<HttpResponse, Error> {
+    request.validate().map_err(validation_error)?;
+    let product_id = public_ids::resolve_product_id(&db, &id).await?;
+
+    let now = DateTime::now();
+    let reservation = Reservation {
+        id: None,
+        product_id,
+        user_id: claims.user_id()?,
+        quantity: request.quantity,
+        expires_at: DateTime::from_millis(now.timestamp_millis() + config.ttl_secs * 1000),
+        created_at: now,
+    };
+
+    let outcome = run_in_transaction(&db, (&**db, &reservation), |session, (db, reservation)| {
+        apply_reservation(db, session, reservation).boxed()
+    })
+    .await;
+
+    match outcome {
+        Ok(()) => {
+            info!("User {} reserved {} x {}", reservation.user_id, reservation.quantity, product_id);
+            Ok(HttpResponse::Ok().json(ReservationResponse::from(&reservation)))
+        }
+        Err(TransactionError::Aborted(ReservationRejection::ProductNotFound)) => {
+            Err(ApiError::blank(StatusCode::NOT_FOUND).into())
+        }
+        Err(TransactionError::Aborted(ReservationRejection::Untracked)) => {
+            Err(ApiError::new(StatusCode::CONFLICT, "Product does not track stock").into())
+        }
+        Err(TransactionError::Aborted(ReservationRejection::InsufficientStock(available))) => {
+            Err(ApiError::new(StatusCode::CONFLICT, "Insufficient stock to reserve")
+                .with("available", available)
+                .into())
+        }
+        Err(TransactionError::Database(e)) => Err(db_error("Failed to reserve product", e)),
+    }
+}
+
+/// Gives the caller's reserved units of a product back before expiry.
+pub async fn release_reservation(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let product_id = public_ids::resolve_product_id(&db, &id).await?;
+    let user_id = claims.user_id()?;
+
+    let result = reservations_collection(&db)
+        .delete_one(doc! { "product_id": product_id, "user_id": user_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to release reservation", e))?;
+
+    if result.deleted_count == 0 {
+        Err(ApiError::blank(StatusCode::NOT_FOUND).into())
+    } else {
+        info!("User {} released their reservation of {}", user_id, product_id);
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
@@ -0,0 +1,223 @@
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime},
+    options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+use validator::Validate;
+
+use crate::{
+    auth::Claims,
+    config::MongoConfig,
+    errors::ApiError,
+    handlers::ListProductsQuery,
+    validation::validation_error,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedFilter {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub owner_id: ObjectId,
+    pub name: String,
+    pub query: ListProductsQuery,
+    // Users who may list and apply the filter; only the owner can change it
+    pub shared_with: Vec<ObjectId>,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct SavedFilterRequest {
+    #[validate(length(min = 1, max = 100, message = "name must be between 1 and 100 characters"))]
+    pub name: String,
+    #[validate]
+    pub query: ListProductsQuery,
+    #[serde(default)]
+    pub shared_with: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SavedFilterResponse {
+    pub id: String,
+    pub owner_id: String,
+    pub name: String,
+    pub query: ListProductsQuery,
+    pub shared_with: Vec<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl From<SavedFilter> for SavedFilterResponse {
+    fn from(filter: SavedFilter) -> Self {
+        SavedFilterResponse {
+            id: filter.id.map(|id| id.to_string()).unwrap_or_default(),
+            owner_id: filter.owner_id.to_string(),
+            name: filter.name,
+            query: filter.query,
+            shared_with: filter.shared_with.iter().map(|id| id.to_string()).collect(),
+            created_at: filter.created_at.try_to_rfc3339_string().unwrap_or_default(),
+            updated_at: filter.updated_at.try_to_rfc3339_string().unwrap_or_default(),
+        }
+    }
+}
+
+fn filters_collection(db: &MongoConfig) -> Collection<SavedFilter> {
+    db.database.collection("saved_filters")
+}
+
+fn parse_filter_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| {
+        error!("Invalid saved filter ID format: {}", id);
+        ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format").into()
+    })
+}
+
+fn parse_user_ids(ids: &[String]) -> Result<Vec<ObjectId>, Error> {
+    ids.iter()
+        .map(|id| {
+            ObjectId::parse_str(id).map_err(|_| {
+                ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid user ID in shared_with: {}", id)).into()
+            })
+        })
+        .collect()
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+// Filters the user owns or that were shared with them
+fn visible_to(user_id: &ObjectId) -> mongodb::bson::Document {
+    doc! { "$or": [{ "owner_id": user_id }, { "shared_with": user_id }] }
+}
+
+/// The saved query of a filter the user may apply, if it exists.
+pub async fn find_visible(
+    db: &MongoConfig,
+    filter_id: &str,
+    user_id: &ObjectId,
+) -> Result<Option<ListProductsQuery>, Error> {
+    let filter_id = parse_filter_id(filter_id)?;
+
+    let mut filter = visible_to(user_id);
+    filter.insert("_id", filter_id);
+    let saved = filters_collection(db)
+        .find_one(filter, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch saved filter", e))?;
+
+    Ok(saved.map(|saved| saved.query))
+}
+
+pub async fn create_saved_filter(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    request: web::Json<SavedFilterRequest>,
+) -> Result<HttpResponse, Error> {
+    request.validate().map_err(validation_error)?;
+    let request = request.into_inner();
+
+    let now = DateTime::now();
+    let mut saved = SavedFilter {
+        id: None,
+        owner_id: claims.user_id()?,
+        name: request.name,
+        query: request.query.for_saving(),
+        shared_with: parse_user_ids(&request.shared_with)?,
+        created_at: now,
+        updated_at: now,
+    };
+
+    let result = filters_collection(&db)
+        .insert_one(&saved, None)
+        .await
+        .map_err(|e| db_error("Failed to save filter", e))?;
+    saved.id = result.inserted_id.as_object_id();
+
+    info!("Saved filter created with ID: {}", result.inserted_id);
+    Ok(HttpResponse::Created().json(SavedFilterResponse::from(saved)))
+}
+
+/// The caller's own filters followed by those shared with them.
+pub async fn list_saved_filters(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+) -> Result<HttpResponse, Error> {
+    let user_id = claims.user_id()?;
+
+    let options = FindOptions::builder().sort(doc! { "name": 1 }).build();
+    let filters: Vec<SavedFilter> = filters_collection(&db)
+        .find(visible_to(&user_id), options)
+        .await
+        .map_err(|e| db_error("Failed to fetch saved filters", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating saved filters", e))?;
+
+    let (mut own, shared): (Vec<_>, Vec<_>) = filters.into_iter().partition(|f| f.owner_id == user_id);
+    own.extend(shared);
+
+    debug!("Returning {} saved filters for user {}", own.len(), user_id);
+    let filters: Vec<SavedFilterResponse> = own.into_iter().map(SavedFilterResponse::from).collect();
+    Ok(HttpResponse::Ok().json(filters))
+}
+
+/// Replaces a filter's name, query and sharing. Only the owner can do this.
+pub async fn update_saved_filter(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+    request: web::Json<SavedFilterRequest>,
+) -> Result<HttpResponse, Error> {
+    let filter_id = parse_filter_id(&id)?;
+    request.validate().map_err(validation_error)?;
+    let request = request.into_inner();
+
+    let update = doc! {
+        "name": request.name,
+        "query": mongodb::bson::to_bson(&request.query.for_saving())
+            .map_err(ApiError::internal)?,
+        "shared_with": parse_user_ids(&request.shared_with)?,
+        "updated_at": DateTime::now(),
+    };
+
+    let saved = filters_collection(&db)
+        .find_one_and_update(
+            doc! { "_id": filter_id, "owner_id": claims.user_id()? },
+            doc! { "$set": update },
+            FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build(),
+        )
+        .await
+        .map_err(|e| db_error("Failed to update saved filter", e))?;
+
+    match saved {
+        Some(saved) => Ok(HttpResponse::Ok().json(SavedFilterResponse::from(saved))),
+        None => Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    }
+}
+
+pub async fn delete_saved_filter(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let filter_id = parse_filter_id(&id)?;
+
+    let result = filters_collection(&db)
+        .delete_one(doc! { "_id": filter_id, "owner_id": claims.user_id()? }, None)
+        .await
+        .map_err(|e| db_error("Failed to delete saved filter", e))?;
+
+    if result.deleted_count == 0 {
+        Err(ApiError::blank(StatusCode::NOT_FOUND).into())
+    } else {
+        info!("Saved filter deleted: {}", filter_id);
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
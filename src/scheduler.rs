@@ -0,0 +1,242 @@
+use actix_web::{web, Error, HttpResponse};
+use futures::future::BoxFuture;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime},
+    Collection,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::time::MissedTickBehavior;
+use tracing::{error, info};
+
+use crate::{
+    config::{LimitsConfig, MongoConfig, TrashConfig},
+    event_store::{self, ProductEvent},
+    events::EventHub,
+    export_storage::ExportStorage,
+    exports,
+    import_batches::BatchLimits,
+    import_sources,
+    imports::UrlFetcher,
+    maintenance::MaintenanceMode,
+    models::Product,
+    stats::StatsCache,
+    trash,
+    search_queries::SearchLog,
+    validation_webhook::ValidationWebhook,
+    views::ViewCounter,
+};
+
+type JobFn = Arc<dyn Fn() -> BoxFuture<'static, Result<String, String>> + Send + Sync>;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub name: String,
+    pub interval_secs: u64,
+    pub running: bool,
+    pub run_count: u64,
+    pub last_started_at: Option<String>,
+    pub last_duration_ms: Option<u64>,
+    pub last_success: Option<bool>,
+    pub last_message: Option<String>,
+}
+
+// Runs registered jobs on fixed intervals inside the server process
+#[derive(Default)]
+pub struct Scheduler {
+    statuses: Arc<Mutex<BTreeMap<String, JobStatus>>>,
+}
+
+impl Scheduler {
+    /// Spawns `job` to run every `every`, starting immediately.
+    pub fn register<F, Fut>(&self, name: &str, every: Duration, job: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String, String>> + Send + 'static,
+    {
+        let job: JobFn = Arc::new(move || Box::pin(job()));
+        let name = name.to_string();
+
+        self.statuses.lock().unwrap().insert(name.clone(), JobStatus {
+            name: name.clone(),
+            interval_secs: every.as_secs(),
+            running: false,
+            run_count: 0,
+            last_started_at: None,
+            last_duration_ms: None,
+            last_success: None,
+            last_message: None,
+        });
+
+        info!("Registered job {} (every {:?})", name, every);
+
+        let statuses = self.statuses.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(every);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+            loop {
+                ticker.tick().await;
+
+                if let Some(status) = statuses.lock().unwrap().get_mut(&name) {
+                    status.running = true;
+                    status.last_started_at = DateTime::now().try_to_rfc3339_string().ok();
+                }
+
+                let started = Instant::now();
+                let result = job().await;
+                let elapsed = started.elapsed();
+
+                match &result {
+                    Ok(message) => info!("Job {} finished in {:?}: {}", name, elapsed, message),
+                    Err(message) => error!("Job {} failed after {:?}: {}", name, elapsed, message),
+                }
+
+                if let Some(status) = statuses.lock().unwrap().get_mut(&name) {
+                    status.running = false;
+                    status.run_count += 1;
+                    status.last_duration_ms = Some(elapsed.as_millis() as u64);
+                    status.last_success = Some(result.is_ok());
+                    status.last_message = Some(match result {
+                        Ok(message) | Err(message) => message,
+                    });
+                }
+            }
+        });
+    }
+
+    pub fn statuses(&self) -> Vec<JobStatus> {
+        self.statuses.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// Turns off sales whose end date has passed.
+pub async fn deactivate_expired_sales(db: &MongoConfig) -> Result<String, String> {
+    let collection: Collection<Product> = db.database.collection("products");
+    let mut filter = doc! { "has_active_sale": true, "sale_ends_at": { "$lte": DateTime::now() } };
+
+    // Pin down which products expire so each one gets its event
+    let expired: Vec<ObjectId> = if db.event_sourcing {
+        let ids = collection
+            .distinct("_id", filter.clone(), None)
+            .await
+            .map_err(|e| format!("Database error: {}", e))?;
+        let ids: Vec<ObjectId> = ids.iter().filter_map(|id| id.as_object_id()).collect();
+        filter.insert("_id", doc! { "$in": &ids });
+        ids
+    } else {
+        Vec::new()
+    };
+
+    let result = collection
+        .update_many(
+            filter,
+            doc! { "$set": { "has_active_sale": false }, "$unset": { "sale_ends_at": "" } },
+            None,
+        )
+        .await
+        .map_err(|e| format!("Database error: {}", e))?;
+
+    for product_id in expired {
+        event_store::record(db, product_id, vec![ProductEvent::SaleEnded]).await;
+    }
+
+    Ok(format!("Deactivated {} expired sales", result.modified_count))
+}
+
+/// Registers the built-in maintenance jobs.
+#[allow(clippy::too_many_arguments)]
+pub fn register_default_jobs(
+    scheduler: &Scheduler,
+    db: web::Data<MongoConfig>,
+    stats: web::Data<StatsCache>,
+    views: web::Data<ViewCounter>,
+    searches: web::Data<SearchLog>,
+    events: web::Data<EventHub>,
+    batching: web::Data<BatchLimits>,
+    fetcher: web::Data<UrlFetcher>,
+    limits: web::Data<LimitsConfig>,
+    webhook: Option<web::Data<ValidationWebhook>>,
+    trash_config: web::Data<TrashConfig>,
+    maintenance: web::Data<MaintenanceMode>,
+    export_storage: web::Data<dyn ExportStorage>,
+) {
+    let sales_db = db.clone();
+    scheduler.register("deactivate_expired_sales", Duration::from_secs(60), move || {
+        let db = sales_db.clone();
+        async move { deactivate_expired_sales(&db).await }
+    });
+
+    let views_db = db.clone();
+    scheduler.register("flush_product_views", Duration::from_secs(10), move || {
+        let db = views_db.clone();
+        let views = views.clone();
+        async move { views.flush(&db).await }
+    });
+
+    let searches_db = db.clone();
+    scheduler.register("flush_search_queries", Duration::from_secs(10), move || {
+        let db = searches_db.clone();
+        let searches = searches.clone();
+        async move { searches.flush(&db).await }
+    });
+
+    let imports_db = db.clone();
+    scheduler.register("run_import_sources", Duration::from_secs(60), move || {
+        let db = imports_db.clone();
+        let events = events.clone();
+        let batching = batching.clone();
+        let fetcher = fetcher.clone();
+        let limits = limits.clone();
+        let webhook = webhook.clone();
+        async move {
+            let webhook = webhook.as_ref().map(|webhook| webhook.get_ref());
+            import_sources::run_due_import_sources(&db, &events, &batching, &fetcher, &limits, webhook).await
+        }
+    });
+
+    let maintenance_db = db.clone();
+    scheduler.register("sync_maintenance_mode", Duration::from_secs(10), move || {
+        let db = maintenance_db.clone();
+        let maintenance = maintenance.clone();
+        async move {
+            match maintenance.refresh(&db).await {
+                Ok(true) => Ok("Maintenance mode is on".to_string()),
+                Ok(false) => Ok("Maintenance mode is off".to_string()),
+                Err(e) => Err(format!("Database error: {}", e)),
+            }
+        }
+    });
+
+    let exports_db = db.clone();
+    scheduler.register("purge_exports", Duration::from_secs(60 * 60), move || {
+        let db = exports_db.clone();
+        let export_storage = export_storage.clone();
+        async move { exports::purge_expired(&db, export_storage.as_ref()).await }
+    });
+
+    let trash_db = db.clone();
+    scheduler.register("purge_trash", Duration::from_secs(60 * 60), move || {
+        let db = trash_db.clone();
+        let trash_config = trash_config.clone();
+        async move { trash::purge_expired(&db, &trash_config).await }
+    });
+
+    scheduler.register("recompute_statistics", Duration::from_secs(5 * 60), move || {
+        let db = db.clone();
+        let stats = stats.clone();
+        async move {
+            let computed = stats.refresh(&db).await?;
+            Ok(format!("Computed statistics for {} products", computed.total_products))
+        }
+    });
+}
+
+pub async fn list_jobs(scheduler: web::Data<Scheduler>) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(scheduler.statuses()))
+}
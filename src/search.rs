@@ -0,0 +1,272 @@
+use std::{collections::BTreeSet, time::Duration};
+
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, Document},
+    error::ErrorKind,
+    options::{AggregateOptions, FindOptions},
+    Collection,
+};
+use regex::escape;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, warn};
+use validator::Validate;
+
+use crate::{
+    config::{MongoConfig, SearchConfig},
+    errors::ApiError,
+    models::Product,
+    money::{self, Decimal},
+    public_ids,
+    validation::ValidatedQuery,
+};
+
+const DEFAULT_SUGGEST_LIMIT: i64 = 10;
+const DEFAULT_RELATED_LIMIT: i64 = 5;
+// Share of the query's trigrams a name must contain to match a fuzzy search
+const FUZZY_MIN_SIMILARITY: f64 = 0.5;
+
+// Server error code for an operation that ran past its maxTimeMS
+const MAX_TIME_MS_EXPIRED: i32 = 50;
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct SuggestQuery {
+    #[validate(length(min = 1, max = 100, message = "q must be between 1 and 100 characters"))]
+    q: String,
+    #[validate(range(min = 1, message = "limit must be at least 1"))]
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SuggestResponse {
+    suggestions: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct RelatedQuery {
+    #[validate(range(min = 1, max = 50, message = "limit must be between 1 and 50"))]
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RelatedResponse {
+    products: Vec<Product>,
+}
+
+/// The trigrams of a product name, for typo-tolerant search. Each word is
+/// padded like "  word " so its start weighs more than its end, which keeps
+/// a one-letter typo within half of the trigrams: "iphoen" shares 4 of 7
+/// with "iphone".
+pub fn name_grams(name: &str) -> Vec<String> {
+    let mut grams = BTreeSet::new();
+    for word in name.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()) {
+        let padded: Vec<char> = format!("  {} ", word.to_lowercase()).chars().collect();
+        for window in padded.windows(3) {
+            grams.insert(window.iter().collect::<String>());
+        }
+    }
+    grams.into_iter().collect()
+}
+
+/// Filter for products whose name shares enough trigrams with `query`.
+/// None when the query has no letters or digits to match on.
+pub fn fuzzy_filter(query: &str) -> Option<Document> {
+    let grams = name_grams(query);
+    if grams.is_empty() {
+        return None;
+    }
+    let min_matches = ((grams.len() as f64) * FUZZY_MIN_SIMILARITY).ceil() as i32;
+    Some(doc! {
+        "search_grams": { "$in": &grams },
+        "$expr": { "$gte": [shared_grams(&grams), min_matches] },
+    })
+}
+
+/// Stage that scores fuzzy matches by how many of the query's trigrams
+/// they share, for sorting best match first.
+pub fn fuzzy_score_stage(query: &str) -> Document {
+    doc! { "$set": { "fuzzy_score": shared_grams(&name_grams(query)) } }
+}
+
+// How many of `grams` the product's name has; products written before fuzzy search have none
+fn shared_grams(grams: &[String]) -> Document {
+    doc! { "$size": { "$setIntersection": [{ "$ifNull": ["$search_grams", []] }, grams] } }
+}
+
+/// The fields to `$set` when a product is renamed, so fuzzy search finds it
+/// by its new name.
+pub fn rename_fields(new_name: &str) -> Document {
+    doc! { "search_grams": name_grams(new_name) }
+}
+
+/// Gives every product without search trigrams its own. Returns how many
+/// were updated.
+pub async fn backfill_grams(db: &MongoConfig) -> Result<usize, mongodb::error::Error> {
+    let products: Collection<Product> = db.database.collection("products");
+    let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+    let missing: Vec<Product> = products
+        .find(doc! { "search_grams": { "$exists": false } }, options)
+        .await?
+        .try_collect()
+        .await?;
+
+    let mut updated = 0;
+    for product in &missing {
+        let Some(product_id) = product.id else { continue };
+        products
+            .update_one(doc! { "_id": product_id }, doc! { "$set": rename_fields(&product.name) }, None)
+            .await?;
+        updated += 1;
+    }
+    Ok(updated)
+}
+
+// Distinct names only; a few extra candidates make up for duplicates
+fn dedup_stages(limit: i64) -> [Document; 4] {
+    [
+        doc! { "$limit": limit * 3 },
+        doc! { "$group": { "_id": "$name", "score": { "$max": "$score" } } },
+        doc! { "$sort": { "score": -1, "_id": 1 } },
+        doc! { "$limit": limit },
+    ]
+}
+
+fn suggest_pipeline(config: &SearchConfig, prefix: &str, limit: i64) -> Vec<Document> {
+    let active = doc! { "status": { "$in": ["active", null] } };
+
+    let mut pipeline = match &config.autocomplete_index {
+        Some(index) => vec![
+            doc! { "$search": {
+                "index": index,
+                "autocomplete": { "query": prefix, "path": "name" },
+            } },
+            doc! { "$match": active },
+            doc! { "$set": { "score": { "$meta": "searchScore" } } },
+        ],
+        None => {
+            // Anchored prefix; sorting by name lets the scan walk the name index in order
+            let mut filter = active;
+            filter.insert("name", doc! { "$regex": format!("^{}", escape(prefix)), "$options": "i" });
+            vec![
+                doc! { "$match": filter },
+                doc! { "$sort": { "name": 1 } },
+                doc! { "$set": { "score": 0 } },
+            ]
+        }
+    };
+    pipeline.extend(dedup_stages(limit));
+    pipeline
+}
+
+/// Product name suggestions for search-as-you-type. Runs under a strict time
+/// budget and answers with no suggestions rather than an error when it runs out.
+pub async fn suggest_products(
+    db: web::Data<MongoConfig>,
+    config: web::Data<SearchConfig>,
+    query: ValidatedQuery<SuggestQuery>,
+) -> Result<HttpResponse, Error> {
+    let collection: Collection<Product> = db.catalog_collection("products");
+
+    let limit = query.limit.unwrap_or(DEFAULT_SUGGEST_LIMIT).min(config.suggest_max_limit);
+    let prefix = query.q.trim();
+    let options = AggregateOptions::builder()
+        .max_time(Duration::from_millis(config.suggest_max_time_ms))
+        .build();
+
+    let pipeline = suggest_pipeline(&config, prefix, limit);
+    let results: Result<Vec<Document>, _> = match collection.aggregate(pipeline, options).await {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(e) => Err(e),
+    };
+
+    let suggestions = match results {
+        Ok(documents) => documents
+            .iter()
+            .filter_map(|d| d.get_str("_id").ok().map(str::to_string))
+            .collect(),
+        Err(e) if matches!(*e.kind, ErrorKind::Command(ref c) if c.code == MAX_TIME_MS_EXPIRED) => {
+            warn!("Suggestions for '{}' exceeded {}ms", prefix, config.suggest_max_time_ms);
+            Vec::new()
+        }
+        Err(e) => {
+            error!("Failed to fetch suggestions: {}", e);
+            return Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into());
+        }
+    };
+
+    debug!("Returning {} suggestions for '{}'", suggestions.len(), prefix);
+    Ok(HttpResponse::Ok().json(SuggestResponse { suggestions }))
+}
+
+/// Active products in the same category priced within the configured band of
+/// the given product, closest price first.
+pub async fn related_products(
+    db: web::Data<MongoConfig>,
+    config: web::Data<SearchConfig>,
+    id: web::Path<String>,
+    query: ValidatedQuery<RelatedQuery>,
+) -> Result<HttpResponse, Error> {
+    let collection: Collection<Product> = db.catalog_collection("products");
+
+    let object_id = public_ids::resolve_product_id(&db, &id).await?;
+
+    let product = collection.find_one(doc! { "_id": object_id }, None).await.map_err(|e| {
+        error!("Failed to fetch product {}: {}", id, e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+    })?;
+    let Some(product) = product else {
+        debug!("Product not found for related lookup: {}", id);
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    };
+
+    let limit = query.limit.unwrap_or(DEFAULT_RELATED_LIMIT);
+    let band_fraction = money::from_f64(config.related_price_band).unwrap_or_default();
+    let band = (product.price * band_fraction).max(Decimal::new(1, 2));
+    let (price, band_bson) = (money::to_bson(product.price), money::to_bson(band));
+
+    // Score 1.0 at the same price, falling linearly to 0.0 at the edge of the band
+    let pipeline = vec![
+        doc! { "$match": {
+            "_id": { "$ne": object_id },
+            "category": product.category.to_string(),
+            "status": { "$in": ["active", null] },
+            "price": { "$gte": money::to_bson(product.price - band), "$lte": money::to_bson(product.price + band) },
+        } },
+        doc! { "$set": { "score": {
+            "$subtract": [1.0, { "$divide": [{ "$abs": { "$subtract": ["$price", price] } }, band_bson] }]
+        } } },
+        doc! { "$sort": { "score": -1, "name": 1 } },
+        doc! { "$limit": limit },
+        doc! { "$unset": "score" },
+    ];
+
+    let documents: Vec<Document> = collection
+        .aggregate(pipeline, None)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch related products for {}: {}", id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?
+        .try_collect()
+        .await
+        .map_err(|e| {
+            error!("Error while iterating related products: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+
+    let products = documents
+        .into_iter()
+        .filter_map(|d| match mongodb::bson::from_document::<Product>(d) {
+            Ok(product) => Some(product),
+            Err(e) => {
+                warn!("Skipping undecodable related product: {}", e);
+                None
+            }
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(RelatedResponse { products }))
+}
@@ -0,0 +1,595 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::future::BoxFuture;
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime},
+    options::FindOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, error, info, warn};
+use validator::Validate;
+
+use crate::{
+    analytics::{Analytics, AnalyticsEvent},
+    config::{MongoConfig, SearchEngineConfig},
+    errors::ApiError,
+    events::{DomainEvent, EventHub},
+    models::{Category, Product, ProductStatus},
+    money::{self, Decimal},
+    scheduler::Scheduler,
+    search_queries::SearchLog,
+    validation::ValidatedQuery,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_PER_PAGE: u64 = 20;
+// Documents sent to or read from the engine per request during a sync
+const SYNC_BATCH_SIZE: usize = 1000;
+
+/// The fields of a product that are mirrored into the search engine.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SearchDocument {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_id: Option<String>,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slug: Option<String>,
+    #[serde(with = "money::price")]
+    pub price: Decimal,
+    pub category: Category,
+    pub status: ProductStatus,
+    pub has_active_sale: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stock_quantity: Option<i64>,
+}
+
+impl SearchDocument {
+    pub fn of(product: &Product) -> Option<Self> {
+        Some(SearchDocument {
+            id: product.id?.to_string(),
+            public_id: product.public_id.clone(),
+            name: product.name.clone(),
+            slug: product.slug.clone(),
+            price: product.price,
+            category: product.category.clone(),
+            status: product.status,
+            has_active_sale: product.has_active_sale,
+            stock_quantity: product.stock_quantity,
+        })
+    }
+}
+
+/// A search as the engine runs it. Only active products are returned.
+#[derive(Debug)]
+pub struct EngineQuery {
+    pub text: String,
+    pub category: Option<Category>,
+    pub on_sale: Option<bool>,
+    pub min_price: Option<Decimal>,
+    pub max_price: Option<Decimal>,
+    // Field and direction, e.g. ("price", "asc"); relevance when unset
+    pub sort: Option<(String, String)>,
+    pub page: u64,
+    pub per_page: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchPage {
+    pub products: Vec<SearchDocument>,
+    // Matching products per category and per has_active_sale value
+    pub facets: BTreeMap<String, BTreeMap<String, u64>>,
+    pub total: u64,
+    pub page: u64,
+    pub per_page: u64,
+}
+
+/// An external full-text search engine that mirrors the catalog. Each
+/// backend maps documents and queries to its own API.
+pub trait SearchEngine: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Creates the index if needed and declares which fields are
+    /// searchable, filterable and sortable.
+    fn configure(&self) -> BoxFuture<'_, Result<(), String>>;
+
+    fn upsert<'a>(&'a self, documents: &'a [SearchDocument]) -> BoxFuture<'a, Result<(), String>>;
+
+    // Unknown IDs are not an error
+    fn delete<'a>(&'a self, ids: &'a [String]) -> BoxFuture<'a, Result<(), String>>;
+
+    fn search<'a>(&'a self, query: &'a EngineQuery) -> BoxFuture<'a, Result<SearchPage, String>>;
+
+    // A page of every indexed document, in the engine's own order
+    fn documents(&self, offset: usize, limit: usize) -> BoxFuture<'_, Result<Vec<SearchDocument>, String>>;
+}
+
+/// A Meilisearch index, talked to over its HTTP API.
+pub struct Meilisearch {
+    url: String,
+    api_key: Option<String>,
+    index: String,
+    client: reqwest::Client,
+}
+
+impl Meilisearch {
+    pub fn new(url: &str, api_key: Option<String>, index: String) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Failed to build search engine HTTP client");
+        Meilisearch {
+            url: url.trim_end_matches('/').to_string(),
+            api_key,
+            index,
+            client,
+        }
+    }
+
+    async fn send(&self, method: reqwest::Method, path: &str, body: Option<serde_json::Value>) -> Result<serde_json::Value, String> {
+        let url = format!("{}/indexes/{}{}", self.url, self.index, path);
+        let mut request = self.client.request(method, &url);
+        if let Some(body) = &body {
+            request = request.json(body);
+        }
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        let status = response.status();
+        if !status.is_success() {
+            let detail = response.text().await.unwrap_or_default();
+            return Err(format!("{} answered {}: {}", url, status, detail));
+        }
+        response.json().await.map_err(|e| e.to_string())
+    }
+}
+
+// Meilisearch filter expression for `query`; strings are quoted, the rest are literals
+fn meilisearch_filter(query: &EngineQuery) -> Vec<String> {
+    let mut filter = vec![format!("status = \"{}\"", ProductStatus::Active)];
+    if let Some(category) = &query.category {
+        filter.push(format!("category = \"{}\"", category));
+    }
+    if let Some(on_sale) = query.on_sale {
+        filter.push(format!("has_active_sale = {}", on_sale));
+    }
+    if let Some(min_price) = query.min_price {
+        filter.push(format!("price >= {}", min_price));
+    }
+    if let Some(max_price) = query.max_price {
+        filter.push(format!("price <= {}", max_price));
+    }
+    filter
+}
+
+impl SearchEngine for Meilisearch {
+    fn name(&self) -> &'static str {
+        "Meilisearch"
+    }
+
+    fn configure(&self) -> BoxFuture<'_, Result<(), String>> {
+        Box::pin(async move {
+            let settings = json!({
+                "searchableAttributes": ["name", "slug", "public_id"],
+                "filterableAttributes": ["category", "status", "has_active_sale", "price"],
+                "sortableAttributes": ["name", "price"],
+            });
+            self.send(reqwest::Method::PATCH, "/settings", Some(settings)).await.map(|_| ())
+        })
+    }
+
+    fn upsert<'a>(&'a self, documents: &'a [SearchDocument]) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let body = serde_json::to_value(documents).map_err(|e| e.to_string())?;
+            self.send(reqwest::Method::POST, "/documents?primaryKey=id", Some(body)).await.map(|_| ())
+        })
+    }
+
+    fn delete<'a>(&'a self, ids: &'a [String]) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            self.send(reqwest::Method::POST, "/documents/delete-batch", Some(json!(ids))).await.map(|_| ())
+        })
+    }
+
+    fn search<'a>(&'a self, query: &'a EngineQuery) -> BoxFuture<'a, Result<SearchPage, String>> {
+        Box::pin(async move {
+            let mut body = json!({
+                "q": query.text,
+                "filter": meilisearch_filter(query),
+                "facets": ["category", "has_active_sale"],
+                "page": query.page,
+                "hitsPerPage": query.per_page,
+            });
+            if let Some((field, direction)) = &query.sort {
+                body["sort"] = json!([format!("{}:{}", field, direction)]);
+            }
+
+            let mut response = self.send(reqwest::Method::POST, "/search", Some(body)).await?;
+            let products = serde_json::from_value(response["hits"].take()).map_err(|e| e.to_string())?;
+            let facets = serde_json::from_value(response["facetDistribution"].take()).unwrap_or_default();
+            Ok(SearchPage {
+                products,
+                facets,
+                total: response["totalHits"].as_u64().unwrap_or_default(),
+                page: query.page,
+                per_page: query.per_page,
+            })
+        })
+    }
+
+    fn documents(&self, offset: usize, limit: usize) -> BoxFuture<'_, Result<Vec<SearchDocument>, String>> {
+        Box::pin(async move {
+            let path = format!("/documents?offset={}&limit={}", offset, limit);
+            let mut response = self.send(reqwest::Method::GET, &path, None).await?;
+            serde_json::from_value(response["results"].take()).map_err(|e| e.to_string())
+        })
+    }
+}
+
+/// The engine at SEARCH_ENGINE_URL, if one is set and supported.
+pub fn from_config(config: &SearchEngineConfig) -> Option<Arc<dyn SearchEngine>> {
+    let url = config.url.as_deref()?;
+    match config.kind.as_str() {
+        "meilisearch" => {
+            info!("Mirroring products into Meilisearch index {} at {}", config.index, url);
+            Some(Arc::new(Meilisearch::new(url, config.api_key.clone(), config.index.clone())))
+        }
+        other => {
+            warn!("Search engine '{}' is not supported, products are not mirrored", other);
+            None
+        }
+    }
+}
+
+/// Configures the index, then mirrors every product change published on
+/// `events` into `engine` until the hub closes. Failed updates are logged
+/// and dropped.
+pub fn spawn_indexer(events: &EventHub, db: web::Data<MongoConfig>, engine: Arc<dyn SearchEngine>) {
+    let mut receiver = events.subscribe();
+    tokio::spawn(async move {
+        if let Err(e) = engine.configure().await {
+            warn!("Failed to configure {} index: {}", engine.name(), e);
+        }
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("{} indexer lagged, dropped {} events", engine.name(), skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            let result = match &event {
+                DomainEvent::ProductCreated { product_id } | DomainEvent::ProductUpdated { product_id } => {
+                    mirror(&db, engine.as_ref(), product_id).await
+                }
+                DomainEvent::ProductDeleted { product_id } => engine.delete(std::slice::from_ref(product_id)).await,
+                _ => continue,
+            };
+            match result {
+                Ok(()) => debug!("Mirrored {} into {}", event.kind(), engine.name()),
+                Err(e) => warn!("Failed to mirror {} of {:?} into {}: {}", event.kind(), event.product_id(), engine.name(), e),
+            }
+        }
+    });
+}
+
+// Copies the product's current state, or its absence, into the engine
+async fn mirror(db: &MongoConfig, engine: &dyn SearchEngine, product_id: &str) -> Result<(), String> {
+    let object_id = ObjectId::parse_str(product_id).map_err(|e| e.to_string())?;
+    let products: Collection<Product> = db.database.collection("products");
+    let product = products.find_one(doc! { "_id": object_id }, None).await.map_err(|e| e.to_string())?;
+    match product.as_ref().and_then(SearchDocument::of) {
+        Some(document) => engine.upsert(&[document]).await,
+        None => engine.delete(&[product_id.to_string()]).await,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncMode {
+    // Only count the differences
+    Check,
+    // Fix the differences found
+    Repair,
+    // Send every product again, e.g. after changing the index settings
+    Rebuild,
+}
+
+impl SyncMode {
+    fn name(self) -> &'static str {
+        match self {
+            SyncMode::Check => "check",
+            SyncMode::Repair => "repair",
+            SyncMode::Rebuild => "rebuild",
+        }
+    }
+}
+
+/// What a sync between MongoDB and the search engine found and did.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncReport {
+    pub products: u64,
+    // In MongoDB but not in the engine
+    pub missing: u64,
+    // In both, but the engine has an older state
+    pub stale: u64,
+    // In the engine but no longer in MongoDB
+    pub orphaned: u64,
+    pub upserted: u64,
+    pub deleted: u64,
+}
+
+impl SyncReport {
+    pub fn summary(&self) -> String {
+        format!(
+            "{} products: {} missing, {} stale, {} orphaned; upserted {}, deleted {}",
+            self.products, self.missing, self.stale, self.orphaned, self.upserted, self.deleted
+        )
+    }
+}
+
+// Every document in the engine by ID
+async fn indexed_documents(engine: &dyn SearchEngine) -> Result<HashMap<String, SearchDocument>, String> {
+    let mut indexed = HashMap::new();
+    loop {
+        let page = engine.documents(indexed.len(), SYNC_BATCH_SIZE).await?;
+        let done = page.len() < SYNC_BATCH_SIZE;
+        indexed.extend(page.into_iter().map(|document| (document.id.clone(), document)));
+        if done {
+            return Ok(indexed);
+        }
+    }
+}
+
+/// Compares every product in MongoDB with its document in the engine and,
+/// unless only checking, brings the engine in line. Changes made while the
+/// sync runs may be reported as drift; the indexer catches up on them.
+pub async fn sync(db: &MongoConfig, engine: &dyn SearchEngine, mode: SyncMode) -> Result<SyncReport, String> {
+    if mode == SyncMode::Rebuild {
+        engine.configure().await?;
+    }
+    let mut indexed = indexed_documents(engine).await?;
+    let mut report = SyncReport::default();
+
+    let products: Collection<Product> = db.database.collection("products");
+    let options = FindOptions::builder().sort(doc! { "_id": 1 }).batch_size(SYNC_BATCH_SIZE as u32).build();
+    let mut cursor = products.find(None, options).await.map_err(|e| format!("Database error: {}", e))?;
+    let mut pending = Vec::new();
+    while let Some(product) = cursor.try_next().await.map_err(|e| format!("Database error: {}", e))? {
+        let Some(document) = SearchDocument::of(&product) else { continue };
+        report.products += 1;
+        let outdated = match indexed.remove(&document.id) {
+            None => {
+                report.missing += 1;
+                true
+            }
+            Some(current) if current != document => {
+                report.stale += 1;
+                true
+            }
+            Some(_) => false,
+        };
+        if mode == SyncMode::Rebuild || (mode == SyncMode::Repair && outdated) {
+            pending.push(document);
+        }
+        if pending.len() == SYNC_BATCH_SIZE {
+            engine.upsert(&pending).await?;
+            report.upserted += pending.len() as u64;
+            pending.clear();
+        }
+    }
+    if !pending.is_empty() {
+        engine.upsert(&pending).await?;
+        report.upserted += pending.len() as u64;
+    }
+
+    report.orphaned = indexed.len() as u64;
+    if mode != SyncMode::Check {
+        let orphaned: Vec<String> = indexed.into_keys().collect();
+        for ids in orphaned.chunks(SYNC_BATCH_SIZE) {
+            engine.delete(ids).await?;
+            report.deleted += ids.len() as u64;
+        }
+    }
+    Ok(report)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncRun {
+    pub mode: SyncMode,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    pub report: Option<SyncReport>,
+    pub error: Option<String>,
+}
+
+/// The search engine along with the state of its syncs, so that only one
+/// runs at a time and admins can see how the last one went.
+pub struct SearchSync {
+    engine: Arc<dyn SearchEngine>,
+    running: AtomicBool,
+    last_run: Mutex<Option<SyncRun>>,
+}
+
+impl SearchSync {
+    pub fn new(engine: Arc<dyn SearchEngine>) -> Self {
+        SearchSync { engine, running: AtomicBool::new(false), last_run: Mutex::new(None) }
+    }
+
+    /// Runs a sync unless one is already running.
+    pub async fn run(&self, db: &MongoConfig, mode: SyncMode) -> Result<SyncReport, String> {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err("A search index sync is already running".to_string());
+        }
+        let started_at = DateTime::now().try_to_rfc3339_string().unwrap_or_default();
+        *self.last_run.lock().unwrap() =
+            Some(SyncRun { mode, started_at: started_at.clone(), finished_at: None, report: None, error: None });
+
+        let result = sync(db, self.engine.as_ref(), mode).await;
+
+        *self.last_run.lock().unwrap() = Some(SyncRun {
+            mode,
+            started_at,
+            finished_at: DateTime::now().try_to_rfc3339_string().ok(),
+            report: result.as_ref().ok().cloned(),
+            error: result.as_ref().err().cloned(),
+        });
+        self.running.store(false, Ordering::SeqCst);
+        result
+    }
+
+    pub fn last_run(&self) -> Option<SyncRun> {
+        self.last_run.lock().unwrap().clone()
+    }
+}
+
+/// Repairs drift between MongoDB and the engine every
+/// SEARCH_ENGINE_CHECK_INTERVAL_SECS, e.g. changes the indexer dropped.
+pub fn schedule_repair(
+    scheduler: &Scheduler,
+    db: web::Data<MongoConfig>,
+    sync: web::Data<SearchSync>,
+    config: &SearchEngineConfig,
+) {
+    scheduler.register("repair_search_index", Duration::from_secs(config.check_interval_secs), move || {
+        let db = db.clone();
+        let sync = sync.clone();
+        async move { sync.run(&db, SyncMode::Repair).await.map(|report| report.summary()) }
+    });
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct StartSyncRequest {
+    mode: SyncMode,
+}
+
+fn search_engine_unavailable() -> ApiError {
+    ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "Search engine is not configured")
+}
+
+/// Starts a sync in the background. Its outcome is reported by
+/// `search_sync_status`.
+pub async fn start_search_sync(
+    db: web::Data<MongoConfig>,
+    sync: Option<web::Data<SearchSync>>,
+    request: web::Json<StartSyncRequest>,
+) -> Result<HttpResponse, Error> {
+    let Some(sync) = sync else {
+        return Err(search_engine_unavailable().into());
+    };
+    if sync.running.load(Ordering::SeqCst) {
+        return Err(ApiError::new(StatusCode::CONFLICT, "A search index sync is already running").into());
+    }
+
+    let mode = request.mode;
+    let sync = sync.into_inner();
+    tokio::spawn(async move {
+        match sync.run(&db, mode).await {
+            Ok(report) => info!("Search index {} finished: {}", mode.name(), report.summary()),
+            Err(e) => error!("Search index {} failed: {}", mode.name(), e),
+        }
+    });
+    Ok(HttpResponse::Accepted().json(doc! { "message": format!("Search index {} started", mode.name()) }))
+}
+
+/// The last or currently running sync, `null` when none ran since startup.
+pub async fn search_sync_status(sync: Option<web::Data<SearchSync>>) -> Result<HttpResponse, Error> {
+    let Some(sync) = sync else {
+        return Err(search_engine_unavailable().into());
+    };
+    Ok(HttpResponse::Ok().json(json!({
+        "running": sync.running.load(Ordering::SeqCst),
+        "last_run": sync.last_run(),
+    })))
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct SearchQuery {
+    #[validate(length(max = 200, message = "q must be at most 200 characters"))]
+    q: Option<String>,
+    category: Option<Category>,
+    on_sale: Option<bool>,
+    #[serde(default, with = "money::option_input_price")]
+    min_price: Option<Decimal>,
+    #[serde(default, with = "money::option_input_price")]
+    max_price: Option<Decimal>,
+    sort: Option<String>,
+    direction: Option<String>,
+    #[validate(range(min = 1, message = "page must be at least 1"))]
+    page: Option<u64>,
+    #[validate(range(min = 1, max = 100, message = "per_page must be between 1 and 100"))]
+    per_page: Option<u64>,
+}
+
+/// Typo-tolerant, faceted search over active products, answered by the
+/// external search engine.
+pub async fn search_products(
+    engine: Option<web::Data<dyn SearchEngine>>,
+    analytics: web::Data<Analytics>,
+    searches: web::Data<SearchLog>,
+    query: ValidatedQuery<SearchQuery>,
+) -> Result<HttpResponse, Error> {
+    let Some(engine) = engine else {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Search engine is not configured; use GET /api/products?filter=...&fuzzy=true",
+        ).into());
+    };
+
+    let sort = match query.sort.as_deref() {
+        None | Some("relevance") => None,
+        Some(field @ ("name" | "price")) => {
+            let direction = if query.direction.as_deref() == Some("desc") { "desc" } else { "asc" };
+            Some((field.to_string(), direction.to_string()))
+        }
+        Some(other) => {
+            let message = format!("Invalid sort '{}': expected relevance, name or price", other);
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, message).into());
+        }
+    };
+    let engine_query = EngineQuery {
+        text: query.q.as_deref().unwrap_or_default().trim().to_string(),
+        category: query.category.clone(),
+        on_sale: query.on_sale,
+        min_price: query.min_price,
+        max_price: query.max_price,
+        sort,
+        page: query.page.unwrap_or(1),
+        per_page: query.per_page.unwrap_or(DEFAULT_PER_PAGE),
+    };
+
+    match engine.search(&engine_query).await {
+        Ok(page) => {
+            debug!("{} found {} products for '{}'", engine.name(), page.total, engine_query.text);
+            if !engine_query.text.is_empty() {
+                searches.record(&engine_query.text, page.total);
+                analytics.record(AnalyticsEvent::SearchPerformed {
+                    query: engine_query.text.clone(),
+                    result_count: page.total,
+                    source: "search_engine",
+                });
+            }
+            Ok(HttpResponse::Ok().json(page))
+        }
+        Err(e) => {
+            error!("{} search failed: {}", engine.name(), e);
+            Err(ApiError::new(StatusCode::BAD_GATEWAY, format!("Search engine error: {}", e)).into())
+        }
+    }
+}
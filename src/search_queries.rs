@@ -0,0 +1,230 @@
+use std::{collections::HashMap, mem, sync::Mutex};
+
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use chrono::{Duration as ChronoDuration, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::UpdateOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error};
+use validator::Validate;
+
+use crate::{config::MongoConfig, errors::ApiError, validation::ValidatedQuery};
+
+const DEFAULT_DAYS: i64 = 30;
+const DEFAULT_LIMIT: i64 = 20;
+// Longer queries are cut, so pasted text can't bloat the collection
+const MAX_QUERY_CHARS: usize = 200;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct QueryCounts {
+    searches: i64,
+    zero_results: i64,
+    results: i64,
+}
+
+// Searches are counted in memory per query and written to daily buckets in
+// `search_queries` by the flush_search_queries job, like product views
+#[derive(Default)]
+pub struct SearchLog {
+    pending: Mutex<HashMap<String, QueryCounts>>,
+}
+
+// Queries differing only in case or spacing count as one
+fn normalize(query: &str) -> String {
+    let words: Vec<&str> = query.split_whitespace().collect();
+    words.join(" ").to_lowercase().chars().take(MAX_QUERY_CHARS).collect()
+}
+
+impl SearchLog {
+    pub fn record(&self, query: &str, result_count: u64) {
+        let query = normalize(query);
+        if query.is_empty() {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        let counts = pending.entry(query).or_default();
+        counts.searches += 1;
+        counts.results += result_count as i64;
+        if result_count == 0 {
+            counts.zero_results += 1;
+        }
+    }
+
+    fn take(&self) -> HashMap<String, QueryCounts> {
+        mem::take(&mut *self.pending.lock().unwrap())
+    }
+
+    // Puts counts back after a failed flush so they go out with the next one
+    fn restore(&self, counts: HashMap<String, QueryCounts>) {
+        let mut pending = self.pending.lock().unwrap();
+        for (query, counts) in counts {
+            let entry = pending.entry(query).or_default();
+            entry.searches += counts.searches;
+            entry.zero_results += counts.zero_results;
+            entry.results += counts.results;
+        }
+    }
+
+    /// Adds pending searches to each query's bucket for today.
+    pub async fn flush(&self, db: &MongoConfig) -> Result<String, String> {
+        let counts = self.take();
+        if counts.is_empty() {
+            return Ok("No searches to flush".to_string());
+        }
+
+        let daily: Collection<Document> = db.database.collection("search_queries");
+        let day = Utc::now().format("%Y-%m-%d").to_string();
+        let upsert = UpdateOptions::builder().upsert(true).build();
+
+        let mut flushed = 0;
+        let mut remaining = counts.clone();
+        for (query, counts) in counts {
+            let update = doc! {
+                "$inc": {
+                    "searches": counts.searches,
+                    "zero_results": counts.zero_results,
+                    "results": counts.results,
+                }
+            };
+            if let Err(e) = daily.update_one(doc! { "query": &query, "day": &day }, update, upsert.clone()).await {
+                self.restore(remaining);
+                return Err(format!("Database error: {}", e));
+            }
+            flushed += counts.searches;
+            remaining.remove(&query);
+        }
+
+        Ok(format!("Flushed {} searches", flushed))
+    }
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct SearchAnalyticsQuery {
+    #[validate(range(min = 1, max = 365, message = "days must be between 1 and 365"))]
+    days: Option<i64>,
+    #[validate(range(min = 1, max = 100, message = "limit must be between 1 and 100"))]
+    limit: Option<i64>,
+    // day or week
+    interval: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueryStats {
+    #[serde(rename(deserialize = "_id"))]
+    query: String,
+    searches: i64,
+    zero_results: i64,
+    // Average number of products found per search
+    average_results: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TrendPoint {
+    // The day (2026-10-17) or ISO week (2026-W42)
+    #[serde(rename(deserialize = "_id"))]
+    period: String,
+    searches: i64,
+    zero_results: i64,
+    distinct_queries: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchAnalytics {
+    #[serde(default)]
+    since: String,
+    top_queries: Vec<QueryStats>,
+    zero_result_queries: Vec<QueryStats>,
+    trend: Vec<TrendPoint>,
+}
+
+// Sums a query's buckets; `sort` picks the order
+fn query_stats(sort: Document, limit: i64) -> Vec<Document> {
+    vec![
+        doc! { "$group": {
+            "_id": "$query",
+            "searches": { "$sum": "$searches" },
+            "zero_results": { "$sum": "$zero_results" },
+            "results": { "$sum": "$results" },
+        } },
+        doc! { "$addFields": { "average_results": { "$divide": ["$results", { "$max": ["$searches", 1] }] } } },
+        doc! { "$match": { "searches": { "$gt": 0 } } },
+        doc! { "$sort": sort },
+        doc! { "$limit": limit },
+    ]
+}
+
+/// The most frequent queries, the queries that most often found nothing,
+/// and the number of searches per day or week, over the last `days` days.
+pub async fn search_analytics(
+    db: web::Data<MongoConfig>,
+    query: ValidatedQuery<SearchAnalyticsQuery>,
+) -> Result<HttpResponse, Error> {
+    let daily: Collection<Document> = db.catalog_collection("search_queries");
+
+    let days = query.days.unwrap_or(DEFAULT_DAYS);
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    let period = match query.interval.as_deref() {
+        None | Some("day") => Bson::String("$day".to_string()),
+        Some("week") => Bson::Document(doc! {
+            "$dateToString": { "format": "%G-W%V", "date": { "$dateFromString": { "dateString": "$day" } } }
+        }),
+        Some(other) => {
+            let message = format!("Invalid interval '{}': expected day or week", other);
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, message).into());
+        }
+    };
+    let since = (Utc::now() - ChronoDuration::days(days - 1)).format("%Y-%m-%d").to_string();
+
+    let mut zero_result_queries = vec![doc! { "$match": { "zero_results": { "$gt": 0 } } }];
+    zero_result_queries.extend(query_stats(doc! { "zero_results": -1, "searches": -1, "_id": 1 }, limit));
+    let pipeline = vec![
+        doc! { "$match": { "day": { "$gte": &since } } },
+        doc! { "$facet": {
+            "top_queries": query_stats(doc! { "searches": -1, "_id": 1 }, limit),
+            "zero_result_queries": zero_result_queries,
+            "trend": [
+                { "$group": {
+                    "_id": period,
+                    "searches": { "$sum": "$searches" },
+                    "zero_results": { "$sum": "$zero_results" },
+                    "queries": { "$addToSet": "$query" },
+                } },
+                { "$project": {
+                    "searches": 1,
+                    "zero_results": 1,
+                    "distinct_queries": { "$size": "$queries" },
+                } },
+                { "$sort": { "_id": 1 } },
+            ],
+        } },
+    ];
+
+    let documents: Vec<Document> = daily
+        .aggregate(pipeline, None)
+        .await
+        .map_err(|e| {
+            error!("Failed to compute search analytics: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?
+        .try_collect()
+        .await
+        .map_err(|e| {
+            error!("Error while reading search analytics: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+
+    let facets = documents.into_iter().next().unwrap_or_default();
+    let mut analytics: SearchAnalytics = mongodb::bson::from_document(facets).map_err(|e| {
+        error!("Failed to decode search analytics: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+    })?;
+    analytics.since = since;
+
+    debug!("Returning search analytics over {} days", days);
+    Ok(HttpResponse::Ok().json(analytics))
+}
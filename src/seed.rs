@@ -0,0 +1,136 @@
+
+use fake::{faker::company::en::Buzzword, Fake};
+use mongodb::{bson::doc, Collection};
+use rand::{seq::SliceRandom, Rng};
+use tracing::info;
+
+use crate::{
+    auth::{default_scopes, User},
+    config::MongoConfig,
+    event_store::{self, ProductEvent},
+    models::{Category, Product, ProductStatus, TaxClass, Unit},
+    money::Decimal,
+    password::hash_password,
+    pii,
+    public_ids,
+    search,
+    slugs,
+};
+
+const BATCH_SIZE: usize = 500;
+
+fn category_nouns(category: &Category) -> &'static [&'static str] {
+    match category {
+        Category::Electronics => &["Headphones", "Monitor", "Keyboard", "Smartwatch", "Speaker", "Charger", "Webcam"],
+        Category::Clothing => &["Jacket", "T-Shirt", "Sneakers", "Hoodie", "Scarf", "Jeans", "Dress"],
+        Category::Food => &["Coffee Beans", "Olive Oil", "Granola", "Dark Chocolate", "Green Tea", "Honey"],
+        Category::Books => &["Cookbook", "Novel", "Field Guide", "Atlas", "Biography", "Poetry Collection"],
+        Category::Other => &["Backpack", "Water Bottle", "Desk Lamp", "Notebook", "Candle", "Umbrella"],
+    }
+}
+
+fn fake_product(rng: &mut impl Rng) -> Product {
+    let categories = [Category::Electronics, Category::Clothing, Category::Food, Category::Books, Category::Other];
+    let category = categories.choose(rng).cloned().unwrap_or(Category::Other);
+    let noun = category_nouns(&category).choose(rng).copied().unwrap_or("Item");
+
+    let adjective: String = Buzzword().fake_with_rng(rng);
+    let name = format!("{} {}", capitalize(&adjective), noun);
+
+    // Mostly active, with a few drafts and archived products for realism
+    let status = match rng.gen_range(0..10) {
+        0 => ProductStatus::Draft,
+        1 => ProductStatus::Archived,
+        _ => ProductStatus::Active,
+    };
+
+    Product {
+        id: None,
+        public_id: Some(public_ids::new_public_id()),
+        search_grams: search::name_grams(&name),
+        sku: None,
+        description: None,
+        name,
+        // Given by slugs::backfill once the batch is in, since generated names can repeat
+        slug: None,
+        previous_slugs: Vec::new(),
+        price: Decimal::new(rng.gen_range(100..50_000), 2),
+        category,
+        status,
+        tax_class: TaxClass::default(),
+        unit: Unit::default(),
+        price_per_unit: None,
+        price_tiers: Vec::new(),
+        has_active_sale: rng.gen_bool(0.2),
+        sale_ends_at: None,
+        stock_quantity: Some(rng.gen_range(0..200)),
+        low_stock_threshold: Some(rng.gen_range(5..20)),
+        barcode: None,
+        attributes: None,
+        supplier_id: None,
+        supplier_sku: None,
+        cost_price: None,
+        import_id: None,
+        bundle: None,
+        age_restricted: false,
+        hazardous: false,
+        region_restrictions: Vec::new(),
+    }
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Inserts `count` generated products and makes sure the demo user exists.
+pub async fn seed(
+    db: &MongoConfig,
+    count: usize,
+    demo_email: &str,
+    demo_password: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let products: Collection<Product> = db.database.collection("products");
+    let mut rng = rand::thread_rng();
+
+    let mut inserted = 0;
+    while inserted < count {
+        let batch: Vec<Product> = (0..BATCH_SIZE.min(count - inserted))
+            .map(|_| fake_product(&mut rng))
+            .collect();
+        let result = products.insert_many(&batch, None).await?;
+        for (index, id) in &result.inserted_ids {
+            if let (Some(product_id), Ok(created)) = (id.as_object_id(), ProductEvent::created(&batch[*index])) {
+                event_store::record(db, product_id, vec![created]).await;
+            }
+        }
+        inserted += result.inserted_ids.len();
+    }
+    slugs::backfill(db).await?;
+    info!("Seeded {} products", inserted);
+
+    let users: Collection<User> = db.database.collection("users");
+    let demo_email = pii::normalize_email(demo_email);
+    if users.find_one(doc! { "email": pii::email_filter(&demo_email) }, None).await?.is_some() {
+        info!("Demo user {} already exists", demo_email);
+        return Ok(());
+    }
+
+    let user = User {
+        id: None,
+        email: demo_email.clone(),
+        first_name: "Demo".to_string(),
+        last_name: "User".to_string(),
+        password_hash: hash_password(demo_password).map_err(|e| e.to_string())?,
+        scopes: default_scopes(),
+        identities: Vec::new(),
+        two_factor: None,
+    };
+    users.insert_one(&user, None).await?;
+    info!("Created demo user {}", demo_email);
+
+    Ok(())
+}
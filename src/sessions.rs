@@ -0,0 +1,323 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{http::StatusCode, web, Error, HttpRequest, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime},
+    options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument},
+    Collection,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use crate::{auth::{generate_tokens, Claims}, config::MongoConfig, errors::ApiError};
+
+// Sessions idle for longer than the refresh token lifetime can no longer be used
+const SESSION_IDLE_DAYS: i64 = 7;
+
+// How long after logging in a session counts as freshly authenticated
+const RECENT_LOGIN_MINUTES: i64 = 10;
+
+// How long a session found live is trusted before it is looked up again; a
+// session revoked through another instance keeps working for at most this long
+const LIVE_CHECK_TTL: Duration = Duration::from_secs(30);
+
+// A login session; all refresh tokens rotated from one login share it
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Session {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_id: ObjectId,
+    pub refresh_jti: String,          // The only refresh token currently accepted
+    #[serde(default)]
+    pub consumed_jtis: Vec<String>,   // Refresh tokens already exchanged
+    #[serde(default)]
+    pub revoked: bool,
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    #[serde(default)]
+    pub ip: Option<String>,
+    pub created_at: DateTime,
+    pub last_used_at: DateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionResponse {
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: String,
+    pub last_used_at: String,
+    pub current: bool,
+}
+
+/// Sessions recently found live, so AuthMiddleware can refuse access tokens
+/// of revoked sessions without a database read on every request.
+#[derive(Default)]
+pub struct SessionCache {
+    // Session ID to its user and when it was last found live
+    live: Mutex<HashMap<ObjectId, (ObjectId, Instant)>>,
+}
+
+impl SessionCache {
+    /// Whether the session exists, belongs to the user and isn't revoked.
+    pub async fn is_live(
+        &self,
+        db: &MongoConfig,
+        session_id: ObjectId,
+        user_id: ObjectId,
+    ) -> Result<bool, mongodb::error::Error> {
+        if let Some((owner, checked_at)) = self.live.lock().unwrap().get(&session_id) {
+            if *owner == user_id && checked_at.elapsed() < LIVE_CHECK_TTL {
+                return Ok(true);
+            }
+        }
+
+        let session = sessions_collection(db)
+            .find_one(doc! { "_id": session_id, "user_id": user_id, "revoked": false }, None)
+            .await?;
+        let mut live = self.live.lock().unwrap();
+        live.retain(|_, (_, checked_at)| checked_at.elapsed() < LIVE_CHECK_TTL);
+        if session.is_some() {
+            live.insert(session_id, (user_id, Instant::now()));
+        } else {
+            live.remove(&session_id);
+        }
+        Ok(session.is_some())
+    }
+
+    /// Stops trusting a session this instance just revoked.
+    pub fn forget(&self, session_id: &ObjectId) {
+        self.live.lock().unwrap().remove(session_id);
+    }
+
+    /// Stops trusting every session of a user, e.g. a deleted account.
+    pub fn forget_user(&self, user_id: &ObjectId) {
+        self.live.lock().unwrap().retain(|_, (owner, _)| owner != user_id);
+    }
+}
+
+fn new_jti() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+fn sessions_collection(db: &MongoConfig) -> Collection<Session> {
+    db.database.collection("sessions")
+}
+
+/// Creates a session for a fresh login and returns its access/refresh token pair.
+pub async fn start_session(
+    db: &web::Data<MongoConfig>,
+    req: &HttpRequest,
+    user_id: &ObjectId,
+    scopes: &[String],
+) -> Result<(String, String), Error> {
+    let now = DateTime::now();
+    let jti = new_jti();
+
+    let session = Session {
+        id: None,
+        user_id: *user_id,
+        refresh_jti: jti.clone(),
+        consumed_jtis: Vec::new(),
+        revoked: false,
+        user_agent: req
+            .headers()
+            .get("User-Agent")
+            .and_then(|ua| ua.to_str().ok())
+            .map(str::to_string),
+        ip: req.connection_info().realip_remote_addr().map(str::to_string),
+        created_at: now,
+        last_used_at: now,
+    };
+
+    let result = sessions_collection(db).insert_one(&session, None).await.map_err(|e| {
+        error!("Failed to create session: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to create session")
+    })?;
+
+    let session_id = result.inserted_id.as_object_id().unwrap();
+    generate_tokens(user_id, scopes, &session_id, &jti).await
+}
+
+/// Marks `jti` consumed and returns the ID of its replacement. Returns `None`
+/// when the token is not the session's current one; presenting an already
+/// consumed token revokes the whole session.
+pub async fn rotate_refresh_token(
+    db: &web::Data<MongoConfig>,
+    session_id: &ObjectId,
+    user_id: &ObjectId,
+    jti: &str,
+) -> Result<Option<String>, Error> {
+    let collection = sessions_collection(db);
+    let next_jti = new_jti();
+
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+
+    let rotated = collection
+        .find_one_and_update(
+            doc! {
+                "_id": session_id,
+                "user_id": user_id,
+                "refresh_jti": jti,
+                "revoked": false,
+            },
+            doc! {
+                "$set": { "refresh_jti": &next_jti, "last_used_at": DateTime::now() },
+                "$push": { "consumed_jtis": jti },
+            },
+            options,
+        )
+        .await
+        .map_err(|e| {
+            error!("Failed to rotate refresh token: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+        })?;
+
+    if rotated.is_some() {
+        return Ok(Some(next_jti));
+    }
+
+    // A consumed token showing up again means it leaked; kill the session
+    let reuse = collection
+        .update_one(
+            doc! { "_id": session_id, "consumed_jtis": jti, "revoked": false },
+            doc! { "$set": { "revoked": true } },
+            None,
+        )
+        .await
+        .map_err(|e| {
+            error!("Failed to revoke session {}: {}", session_id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+        })?;
+
+    if reuse.modified_count > 0 {
+        warn!("Refresh token reuse detected, revoked session {} of user {}", session_id, user_id);
+    } else {
+        info!("Rejected refresh token for unknown or revoked session {}", session_id);
+    }
+
+    Ok(None)
+}
+
+/// Whether the token belongs to a session started by a login in the last
+/// few minutes; refreshing a token doesn't count. Confirms sensitive actions
+/// for accounts that have no password to ask for.
+pub async fn recently_logged_in(db: &MongoConfig, claims: &Claims) -> Result<bool, Error> {
+    let Some(session_id) = claims.sid.as_deref().and_then(|sid| ObjectId::parse_str(sid).ok()) else {
+        return Ok(false);
+    };
+    let cutoff = DateTime::from_millis(DateTime::now().timestamp_millis() - RECENT_LOGIN_MINUTES * 60 * 1000);
+    let session = sessions_collection(db)
+        .find_one(
+            doc! {
+                "_id": session_id,
+                "user_id": claims.user_id()?,
+                "revoked": false,
+                "created_at": { "$gte": cutoff },
+            },
+            None,
+        )
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch session {}: {}", session_id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+        })?;
+    Ok(session.is_some())
+}
+
+pub async fn list_sessions(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+) -> Result<HttpResponse, Error> {
+    let user_id = claims.user_id()?;
+    let idle_cutoff = DateTime::from_millis(
+        DateTime::now().timestamp_millis() - SESSION_IDLE_DAYS * 24 * 60 * 60 * 1000,
+    );
+
+    let options = FindOptions::builder()
+        .sort(doc! { "last_used_at": -1 })
+        .build();
+
+    let mut cursor = sessions_collection(&db)
+        .find(
+            doc! {
+                "user_id": user_id,
+                "revoked": false,
+                "last_used_at": { "$gte": idle_cutoff },
+            },
+            options,
+        )
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch sessions for user {}: {}", user_id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+        })?;
+
+    let mut sessions = Vec::new();
+    while let Some(session) = cursor.try_next().await.map_err(|e| {
+        error!("Error while iterating sessions: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+    })? {
+        let id = session.id.map(|id| id.to_string()).unwrap_or_default();
+        sessions.push(SessionResponse {
+            current: claims.sid.as_deref() == Some(id.as_str()),
+            id,
+            user_agent: session.user_agent,
+            ip: session.ip,
+            created_at: session.created_at.try_to_rfc3339_string().unwrap_or_default(),
+            last_used_at: session.last_used_at.try_to_rfc3339_string().unwrap_or_default(),
+        });
+    }
+
+    debug!("Found {} active sessions for user {}", sessions.len(), user_id);
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+pub async fn revoke_session(
+    db: web::Data<MongoConfig>,
+    cache: web::Data<SessionCache>,
+    claims: web::ReqData<Claims>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    claims.forbid_impersonation()?;
+    let user_id = claims.user_id()?;
+
+    let session_id = ObjectId::parse_str(id.as_str()).map_err(|_| {
+        error!("Invalid session ID format: {}", id);
+        ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format")
+    })?;
+
+    // Scoped to the caller so users can only revoke their own sessions
+    let result = sessions_collection(&db)
+        .update_one(
+            doc! { "_id": session_id, "user_id": user_id, "revoked": false },
+            doc! { "$set": { "revoked": true } },
+            None,
+        )
+        .await
+        .map_err(|e| {
+            error!("Failed to revoke session {}: {}", session_id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+        })?;
+
+    if result.matched_count == 0 {
+        debug!("Session not found for revocation: {}", session_id);
+        Err(ApiError::blank(StatusCode::NOT_FOUND).into())
+    } else {
+        cache.forget(&session_id);
+        info!("User {} revoked session {}", user_id, session_id);
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
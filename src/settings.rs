@@ -0,0 +1,270 @@
+use std::{
+    collections::BTreeMap,
+    env, fmt, io,
+    path::Path,
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+};
+
+use config::{Config, Environment, File};
+use dotenv::dotenv;
+use percent_encoding::percent_decode_str;
+use tracing::warn;
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+// Values of settings named like *_SECRET, *_KEY_ID or *_AUTH_TOKEN never reach
+// the log. PASSWORD_MIN_LENGTH and the like are not secret
+const SECRET_MARKERS: [&str; 4] = ["SECRET", "PASSWORD", "TOKEN", "KEY"];
+
+/// The kind of deployment, from APP_PROFILE. Profiles pick their own config
+/// file and built-in defaults; prod also refuses settings meant for testing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Profile {
+    Dev,
+    Test,
+    Prod,
+}
+
+impl Profile {
+    fn from_env() -> io::Result<Self> {
+        match env::var("APP_PROFILE").as_deref() {
+            Err(_) | Ok("" | "dev") => Ok(Profile::Dev),
+            Ok("test") => Ok(Profile::Test),
+            Ok("prod") => Ok(Profile::Prod),
+            Ok(other) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown APP_PROFILE '{}': expected dev, test or prod", other),
+            )),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Profile::Dev => "dev",
+            Profile::Test => "test",
+            Profile::Prod => "prod",
+        }
+    }
+
+    // Defaults that differ from the ones in code. Dev is what running without
+    // a profile has always been, so it changes nothing
+    fn defaults(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Profile::Dev | Profile::Prod => &[],
+            Profile::Test => &[
+                ("DATABASE_NAME", "products_test"),
+                ("OPEN_REGISTRATION", "true"),
+                ("ALLOW_SEED", "true"),
+                // Hashing at full strength only slows tests down
+                ("ARGON2_MEMORY_KIB", "1024"),
+                ("ARGON2_ITERATIONS", "1"),
+            ],
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// A setting as it was read, for the configuration dump
+struct Read {
+    value: String,
+    origin: String,
+}
+
+/// Every setting of the API, taken from layered sources where later ones
+/// win: the profile's defaults, config.toml, config.<profile>.toml, then .env
+/// and the environment. Settings are named like environment variables; the
+/// files use the same names in lower case.
+pub struct Settings {
+    profile: Profile,
+    // Lowest precedence first, each with where its values come from
+    layers: Vec<(String, Config)>,
+    read: Mutex<BTreeMap<String, Read>>,
+    invalid: Mutex<Vec<String>>,
+}
+
+fn load_error(e: config::ConfigError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl Settings {
+    fn load() -> io::Result<Self> {
+        dotenv().ok();
+        let profile = Profile::from_env()?;
+
+        let mut defaults = Config::builder();
+        for (key, value) in profile.defaults() {
+            defaults = defaults.set_default(key.to_lowercase(), *value).map_err(load_error)?;
+        }
+        let mut layers = vec![(format!("{} profile", profile), defaults.build().map_err(load_error)?)];
+
+        // Files are optional; CONFIG_DIR says where to look for them
+        let dir = env::var("CONFIG_DIR").unwrap_or_else(|_| ".".to_string());
+        for name in ["config.toml".to_string(), format!("config.{}.toml", profile)] {
+            let path = Path::new(&dir).join(&name);
+            let file = Config::builder()
+                .add_source(File::from(path.as_path()).required(false))
+                .build()
+                .map_err(load_error)?;
+            layers.push((path.display().to_string(), file));
+        }
+
+        let environment = Config::builder().add_source(Environment::default()).build().map_err(load_error)?;
+        layers.push(("environment".to_string(), environment));
+
+        Ok(Settings { profile, layers, read: Mutex::new(BTreeMap::new()), invalid: Mutex::new(Vec::new()) })
+    }
+
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+
+    fn record(&self, key: &str, value: String, origin: &str) {
+        let read = Read { value, origin: origin.to_string() };
+        self.read.lock().unwrap().insert(key.to_string(), read);
+    }
+
+    /// Reports `value` of `key` as invalid, for settings parsed by their section.
+    pub fn reject(&self, key: &str, value: &str, expected: &str) {
+        let problem = format!("{}: expected {}, got '{}'", key, expected, value);
+        let mut invalid = self.invalid.lock().unwrap();
+        if !invalid.contains(&problem) {
+            warn!("Invalid setting {}", problem);
+            invalid.push(problem);
+        }
+    }
+
+    /// The raw value of `key` from the layer with the highest precedence, as
+    /// env::var would have returned it.
+    pub fn var(&self, key: &str) -> Option<String> {
+        let lookup = key.to_lowercase();
+        let (origin, value) = self
+            .layers
+            .iter()
+            .rev()
+            .find_map(|(origin, layer)| layer.get_string(&lookup).ok().map(|value| (origin, value)))?;
+        self.record(key, value.clone(), origin);
+        Some(value)
+    }
+
+    /// `key` unless it is unset or empty.
+    pub fn text(&self, key: &str) -> Option<String> {
+        self.var(key).filter(|value| !value.is_empty())
+    }
+
+    /// `key`, or `default` when unset.
+    pub fn string(&self, key: &str, default: &str) -> String {
+        self.var(key).unwrap_or_else(|| {
+            self.record_default(key, &default);
+            default.to_string()
+        })
+    }
+
+    /// `key` parsed as a number, if set. A value that doesn't parse is reported
+    /// as invalid and treated as unset.
+    pub fn optional<T: FromStr>(&self, key: &str) -> Option<T> {
+        let value = self.text(key)?;
+        match value.trim().parse() {
+            Ok(parsed) => Some(parsed),
+            Err(_) => {
+                self.reject(key, &value, "a number");
+                None
+            }
+        }
+    }
+
+    /// `key` parsed as a number, or `default` when unset or invalid.
+    pub fn parse<T: FromStr + fmt::Display>(&self, key: &str, default: T) -> T {
+        self.optional(key).unwrap_or_else(|| {
+            self.record_default(key, &default);
+            default
+        })
+    }
+
+    /// A switch: true or 1 turn it on, false or 0 off.
+    pub fn flag(&self, key: &str, default: bool) -> bool {
+        match self.text(key).map(|value| value.to_lowercase()).as_deref() {
+            Some("true" | "1") => true,
+            Some("false" | "0") => false,
+            Some(other) => {
+                self.reject(key, other, "true, false, 1 or 0");
+                default
+            }
+            None => {
+                self.record_default(key, &default);
+                default
+            }
+        }
+    }
+
+    fn record_default(&self, key: &str, default: &dyn fmt::Display) {
+        let mut read = self.read.lock().unwrap();
+        read.entry(key.to_string())
+            .or_insert_with(|| Read { value: default.to_string(), origin: "default".to_string() });
+    }
+
+    /// Every value that failed to parse so far.
+    pub fn invalid(&self) -> Vec<String> {
+        self.invalid.lock().unwrap().clone()
+    }
+
+    /// The settings read so far with their values and where they came from,
+    /// one per line. Secrets and passwords in URLs are masked.
+    pub fn dump(&self) -> String {
+        let read = self.read.lock().unwrap();
+        read.iter()
+            .map(|(key, read)| format!("{} = {} ({})", key, redact(key, &read.value), read.origin))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+fn redact(key: &str, value: &str) -> String {
+    if value.is_empty() {
+        return String::new();
+    }
+    if key.split('_').skip(1).any(|segment| SECRET_MARKERS.contains(&segment)) {
+        return "<redacted>".to_string();
+    }
+    match reqwest::Url::parse(value) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(Some("redacted"));
+            url.to_string()
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// Loads the settings once; later calls return the same ones.
+pub fn init() -> io::Result<&'static Settings> {
+    if let Some(settings) = SETTINGS.get() {
+        return Ok(settings);
+    }
+    let settings = Settings::load()?;
+    Ok(SETTINGS.get_or_init(|| settings))
+}
+
+/// The settings of this process. Loaded on first use when AppConfig hasn't
+/// loaded them yet.
+pub fn settings() -> &'static Settings {
+    init().unwrap_or_else(|e| panic!("Failed to load settings: {}", e))
+}
+
+/// The user name and password of a service URL such as MAIL_URL, with the
+/// percent-encoding taken off, e.g. `%40` back to the `@` in a password.
+pub fn url_credentials(url: &reqwest::Url) -> Result<(Option<String>, Option<String>), String> {
+    let decode = |value: &str| {
+        percent_decode_str(value)
+            .decode_utf8()
+            .map(|value| value.into_owned())
+            .map_err(|e| format!("invalid credentials: {}", e))
+    };
+    let user = Some(url.username()).filter(|user| !user.is_empty()).map(decode).transpose()?;
+    let password = url.password().map(decode).transpose()?;
+    Ok((user, password))
+}
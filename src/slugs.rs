@@ -0,0 +1,204 @@
+use std::collections::{BTreeSet, HashSet};
+
+use actix_web::{http::{header, StatusCode}, web, Error, HttpRequest, HttpResponse, Responder};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document, Regex},
+    error::{ErrorKind, WriteFailure},
+    options::FindOptions,
+    Collection,
+};
+use tracing::{debug, error, info};
+
+use crate::{
+    barcode::is_duplicate_key,
+    config::MongoConfig,
+    errors::ApiError,
+    models::Product,
+    negotiation::Negotiated,
+};
+
+// Long names are cut to this many characters before suffixing
+const MAX_SLUG_LENGTH: usize = 80;
+
+fn products_collection(db: &MongoConfig) -> Collection<Product> {
+    db.database.collection("products")
+}
+
+/// A URL-friendly form of a product name: lowercase ASCII letters and digits
+/// separated by single dashes.
+pub fn slugify(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.truncate(MAX_SLUG_LENGTH);
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() { "product".to_string() } else { slug.to_string() }
+}
+
+// `base` itself or `base` followed by a collision suffix such as -2
+fn is_variant(slug: &str, base: &str) -> bool {
+    slug == base
+        || slug
+            .strip_prefix(base)
+            .and_then(|rest| rest.strip_prefix('-'))
+            .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// A slug for `name` that no other product uses, now or as a previous slug,
+/// so old links never start pointing at a different product. Collisions get
+/// a numeric suffix: coffee-mug, coffee-mug-2, coffee-mug-3...
+pub async fn unique_slug(
+    db: &MongoConfig,
+    name: &str,
+    product_id: Option<ObjectId>,
+) -> Result<String, mongodb::error::Error> {
+    let base = slugify(name);
+    let pattern = format!("^{}(-[0-9]+)?$", base);
+    let mut filter = doc! {
+        "$or": [
+            { "slug": { "$regex": &pattern } },
+            { "previous_slugs": { "$regex": &pattern } },
+        ]
+    };
+    if let Some(product_id) = product_id {
+        filter.insert("_id", doc! { "$ne": product_id });
+    }
+
+    let products: Vec<Product> = products_collection(db).find(filter, None).await?.try_collect().await?;
+    let taken: Vec<&str> = products
+        .iter()
+        .flat_map(|product| product.slug.iter().chain(&product.previous_slugs))
+        .map(String::as_str)
+        .filter(|slug| is_variant(slug, &base))
+        .collect();
+
+    if !taken.contains(&base.as_str()) {
+        return Ok(base);
+    }
+    let suffix = (2..).find(|n| !taken.contains(&format!("{}-{}", base, n).as_str())).unwrap_or(2);
+    Ok(format!("{}-{}", base, suffix))
+}
+
+/// Slugs for several new products at once, unique among themselves and in
+/// the catalog like [`unique_slug`], with one query for all of them.
+pub async fn unique_slugs(db: &MongoConfig, names: &[&str]) -> Result<Vec<String>, mongodb::error::Error> {
+    let bases: Vec<String> = names.iter().map(|name| slugify(name)).collect();
+    let patterns: BTreeSet<String> = bases.iter().map(|base| format!("^{}(-[0-9]+)?$", base)).collect();
+    let patterns: Vec<Regex> = patterns.into_iter().map(|pattern| Regex { pattern, options: String::new() }).collect();
+    let filter = doc! {
+        "$or": [
+            { "slug": { "$in": &patterns } },
+            { "previous_slugs": { "$in": &patterns } },
+        ]
+    };
+
+    let products: Vec<Product> = products_collection(db).find(filter, None).await?.try_collect().await?;
+    let mut taken: HashSet<String> = products
+        .into_iter()
+        .flat_map(|product| product.slug.into_iter().chain(product.previous_slugs))
+        .collect();
+
+    Ok(bases
+        .into_iter()
+        .map(|base| {
+            let slug = if taken.contains(&base) {
+                (2..).map(|n| format!("{}-{}", base, n)).find(|slug| !taken.contains(slug)).unwrap_or(base)
+            } else {
+                base
+            };
+            taken.insert(slug.clone());
+            slug
+        })
+        .collect())
+}
+
+/// The fields to `$set` when a product is renamed: a slug for the new name,
+/// with the current slug kept in previous_slugs so links to it redirect.
+pub async fn rename_fields(
+    db: &MongoConfig,
+    product: &Product,
+    new_name: &str,
+) -> Result<Document, mongodb::error::Error> {
+    let slug = unique_slug(db, new_name, product.id).await?;
+    if product.slug.as_deref() == Some(slug.as_str()) {
+        return Ok(Document::new());
+    }
+
+    // Renaming back to an earlier name takes that slug out of the history
+    let mut previous_slugs: Vec<String> = product.previous_slugs.iter().filter(|s| **s != slug).cloned().collect();
+    if let Some(current) = &product.slug {
+        previous_slugs.push(current.clone());
+    }
+    Ok(doc! { "slug": slug, "previous_slugs": previous_slugs })
+}
+
+/// Whether an insert or update failed because another product was given
+/// the same slug at the same time.
+pub fn is_duplicate_slug(e: &mongodb::error::Error) -> bool {
+    is_duplicate_key(e) && matches!(*e.kind, ErrorKind::Write(WriteFailure::WriteError(ref w)) if w.message.contains("slug"))
+}
+
+/// Gives every product without a slug one. Returns how many were updated.
+pub async fn backfill(db: &MongoConfig) -> Result<usize, mongodb::error::Error> {
+    let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+    let products: Vec<Product> = products_collection(db)
+        .find(doc! { "slug": { "$exists": false } }, options)
+        .await?
+        .try_collect()
+        .await?;
+
+    let mut updated = 0;
+    for product in &products {
+        let Some(product_id) = product.id else { continue };
+        let slug = unique_slug(db, &product.name, Some(product_id)).await?;
+        products_collection(db)
+            .update_one(doc! { "_id": product_id }, doc! { "$set": { "slug": slug } }, None)
+            .await?;
+        updated += 1;
+    }
+    Ok(updated)
+}
+
+/// Looks a product up by its slug. An old slug redirects to the product's
+/// current one with 301 Moved Permanently.
+pub async fn get_product_by_slug(
+    req: HttpRequest,
+    db: web::Data<MongoConfig>,
+    slug: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let collection = products_collection(&db);
+    let db_error = |e: mongodb::error::Error| {
+        error!("Failed to fetch product by slug {}: {}", slug, e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+    };
+
+    if let Some(product) = collection.find_one(doc! { "slug": slug.as_str() }, None).await.map_err(db_error)? {
+        info!("Product found for slug {}", slug);
+        return Ok(Negotiated(product).respond_to(&req));
+    }
+
+    let renamed = collection
+        .find_one(doc! { "previous_slugs": slug.as_str() }, None)
+        .await
+        .map_err(db_error)?;
+    match renamed.and_then(|product| product.slug) {
+        Some(current) => {
+            debug!("Redirecting old slug {} to {}", slug, current);
+            let path = req.path();
+            let prefix = path.strip_suffix(slug.as_str()).unwrap_or(path);
+            Ok(HttpResponse::MovedPermanently()
+                .insert_header((header::LOCATION, format!("{}{}", prefix, current)))
+                .finish())
+        }
+        None => {
+            debug!("No product with slug {}", slug);
+            Err(ApiError::blank(StatusCode::NOT_FOUND).into())
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, DateTime, Document},
+    Collection,
+};
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::RwLock};
+use tracing::{debug, error};
+
+use crate::{
+    config::MongoConfig,
+    errors::ApiError,
+    money::{self, Decimal},
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProductStats {
+    pub total_products: i64,
+    pub products_on_sale: i64,
+    #[serde(with = "money::price")]
+    pub average_price: Decimal,
+    pub total_stock_units: i64,
+    #[serde(with = "money::price")]
+    pub total_stock_value: Decimal,
+    pub by_category: BTreeMap<String, i64>,
+    pub margin: MarginStats,
+    pub computed_at: String,
+}
+
+/// Price against cost, over the products that have a cost_price.
+#[derive(Debug, Clone, Serialize)]
+pub struct MarginStats {
+    pub costed_products: i64,
+    // Mean of (price - cost) / price, in percent
+    #[serde(with = "money::price")]
+    pub average_margin_percent: Decimal,
+    #[serde(with = "money::price")]
+    pub total_stock_cost: Decimal,
+    #[serde(with = "money::price")]
+    pub total_stock_margin: Decimal,
+}
+
+// Catalog statistics, recomputed periodically by the scheduler
+#[derive(Default)]
+pub struct StatsCache {
+    current: RwLock<Option<ProductStats>>,
+}
+
+impl StatsCache {
+    pub fn get(&self) -> Option<ProductStats> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub async fn refresh(&self, db: &MongoConfig) -> Result<ProductStats, String> {
+        let stats = compute_stats(db).await?;
+        *self.current.write().unwrap() = Some(stats.clone());
+        Ok(stats)
+    }
+}
+
+async fn compute_stats(db: &MongoConfig) -> Result<ProductStats, String> {
+    let collection: Collection<Document> = db.catalog_collection("products");
+
+    let costed = doc! { "$ne": [{ "$ifNull": ["$cost_price", null] }, null] };
+    let stock = doc! { "$ifNull": ["$stock_quantity", 0] };
+
+    let pipeline = vec![doc! {
+        "$facet": {
+            "totals": [{
+                "$group": {
+                    "_id": null,
+                    "total_products": { "$sum": 1 },
+                    "products_on_sale": { "$sum": { "$cond": ["$has_active_sale", 1, 0] } },
+                    "average_price": { "$avg": "$price" },
+                    "total_stock_units": { "$sum": { "$ifNull": ["$stock_quantity", 0] } },
+                    "total_stock_value": {
+                        "$sum": { "$multiply": ["$price", &stock] }
+                    },
+                    "costed_products": { "$sum": { "$cond": [&costed, 1, 0] } },
+                    // $avg skips the nulls of products without a cost or a price
+                    "average_margin_percent": {
+                        "$avg": {
+                            "$cond": [
+                                { "$and": [&costed, { "$gt": ["$price", 0] }] },
+                                { "$multiply": [{ "$divide": [{ "$subtract": ["$price", "$cost_price"] }, "$price"] }, 100] },
+                                null,
+                            ]
+                        }
+                    },
+                    "total_stock_cost": {
+                        "$sum": { "$cond": [&costed, { "$multiply": ["$cost_price", &stock] }, 0] }
+                    },
+                    "total_stock_margin": {
+                        "$sum": {
+                            "$cond": [&costed, { "$multiply": [{ "$subtract": ["$price", "$cost_price"] }, &stock] }, 0]
+                        }
+                    },
+                }
+            }],
+            "by_category": [{ "$group": { "_id": "$category", "count": { "$sum": 1 } } }],
+        }
+    }];
+
+    let mut cursor = collection
+        .aggregate(pipeline, None)
+        .await
+        .map_err(|e| format!("Database error: {}", e))?;
+
+    let result = cursor
+        .try_next()
+        .await
+        .map_err(|e| format!("Database error: {}", e))?
+        .unwrap_or_default();
+
+    let totals = result
+        .get_array("totals")
+        .ok()
+        .and_then(|t| t.first())
+        .and_then(|t| t.as_document())
+        .cloned()
+        .unwrap_or_default();
+
+    let number = |doc: &Document, key: &str| -> f64 {
+        match doc.get(key) {
+            Some(mongodb::bson::Bson::Int32(v)) => *v as f64,
+            Some(mongodb::bson::Bson::Int64(v)) => *v as f64,
+            Some(mongodb::bson::Bson::Double(v)) => *v,
+            _ => 0.0,
+        }
+    };
+    let amount = |doc: &Document, key: &str| doc.get(key).and_then(money::from_bson).unwrap_or_default();
+
+    let mut by_category = BTreeMap::new();
+    if let Ok(categories) = result.get_array("by_category") {
+        for entry in categories.iter().filter_map(|c| c.as_document()) {
+            let name = entry.get_str("_id").unwrap_or("unknown").to_string();
+            by_category.insert(name, number(entry, "count") as i64);
+        }
+    }
+
+    Ok(ProductStats {
+        total_products: number(&totals, "total_products") as i64,
+        products_on_sale: number(&totals, "products_on_sale") as i64,
+        average_price: amount(&totals, "average_price").round_dp(2),
+        total_stock_units: number(&totals, "total_stock_units") as i64,
+        total_stock_value: amount(&totals, "total_stock_value"),
+        by_category,
+        margin: MarginStats {
+            costed_products: number(&totals, "costed_products") as i64,
+            average_margin_percent: amount(&totals, "average_margin_percent").round_dp(2),
+            total_stock_cost: amount(&totals, "total_stock_cost"),
+            total_stock_margin: amount(&totals, "total_stock_margin"),
+        },
+        computed_at: DateTime::now().try_to_rfc3339_string().unwrap_or_default(),
+    })
+}
+
+pub async fn get_stats(
+    db: web::Data<MongoConfig>,
+    stats: web::Data<StatsCache>,
+) -> Result<HttpResponse, Error> {
+    if let Some(cached) = stats.get() {
+        debug!("Serving cached statistics from {}", cached.computed_at);
+        return Ok(HttpResponse::Ok().json(cached));
+    }
+
+    // Nothing cached yet (e.g. right after startup)
+    let computed = stats.refresh(&db).await.map_err(|e| {
+        error!("Failed to compute statistics: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e)
+    })?;
+    Ok(HttpResponse::Ok().json(computed))
+}
@@ -0,0 +1,119 @@
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    options::FindOptions,
+    ClientSession, Collection,
+};
+use tracing::{error, info};
+
+use crate::{
+    config::MongoConfig,
+    errors::ApiError,
+    event_store::{self, ProductEvent},
+    events::{DomainEvent, EventHub},
+    models::Product,
+};
+
+// Tracked products whose stock is at or below their own threshold
+fn low_stock_filter() -> Document {
+    doc! {
+        "stock_quantity": { "$exists": true, "$ne": null },
+        "low_stock_threshold": { "$exists": true, "$ne": null },
+        "$expr": { "$lte": ["$stock_quantity", "$low_stock_threshold"] },
+    }
+}
+
+/// Adds `delta` to the stock of a stock-tracked product inside `session`'s
+/// transaction and records the adjustment. Returns false, changing nothing,
+/// when the product does not track stock.
+pub async fn adjust_stock_with_session(
+    db: &MongoConfig,
+    session: &mut ClientSession,
+    product_id: ObjectId,
+    delta: i64,
+) -> Result<bool, mongodb::error::Error> {
+    let products: Collection<Product> = db.database.collection("products");
+    let result = products
+        .update_one_with_session(
+            doc! { "_id": product_id, "stock_quantity": { "$exists": true } },
+            doc! { "$inc": { "stock_quantity": delta } },
+            None,
+            session,
+        )
+        .await?;
+    if result.modified_count == 0 {
+        return Ok(false);
+    }
+    event_store::record_with_session(db, session, product_id, vec![ProductEvent::StockAdjusted { delta }]).await?;
+    Ok(true)
+}
+
+/// Publishes a low-stock event for each of `product_ids` that is at or below
+/// its threshold. Called after stock mutations; failures are only logged.
+pub async fn check_low_stock(db: &MongoConfig, events: &EventHub, product_ids: &[ObjectId]) {
+    if product_ids.is_empty() {
+        return;
+    }
+
+    let collection: Collection<Product> = db.transactional_collection("products");
+    let mut filter = low_stock_filter();
+    filter.insert("_id", doc! { "$in": product_ids });
+
+    let mut cursor = match collection.find(filter, None).await {
+        Ok(cursor) => cursor,
+        Err(e) => {
+            error!("Failed to check low stock: {}", e);
+            return;
+        }
+    };
+
+    loop {
+        match cursor.try_next().await {
+            Ok(Some(product)) => {
+                let (Some(id), Some(stock_quantity), Some(threshold)) =
+                    (product.id, product.stock_quantity, product.low_stock_threshold)
+                else {
+                    continue;
+                };
+                info!("Product {} is low on stock ({} <= {})", id, stock_quantity, threshold);
+                events.publish(DomainEvent::LowStock {
+                    product_id: id.to_string(),
+                    name: product.name,
+                    stock_quantity,
+                    threshold,
+                });
+            }
+            Ok(None) => break,
+            Err(e) => {
+                error!("Error while checking low stock: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Replenishment report: every product at or below its low-stock threshold.
+pub async fn low_stock_report(db: web::Data<MongoConfig>) -> Result<HttpResponse, Error> {
+    let collection: Collection<Product> = db.catalog_collection("products");
+
+    let options = FindOptions::builder()
+        .sort(doc! { "stock_quantity": 1, "name": 1 })
+        .build();
+
+    let mut cursor = collection.find(low_stock_filter(), options).await.map_err(|e| {
+        error!("Failed to fetch low stock products: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+    })?;
+
+    let mut products = Vec::new();
+    while let Some(product) = cursor.try_next().await.map_err(|e| {
+        error!("Error while iterating products: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+    })? {
+        products.push(product);
+    }
+
+    info!("Low stock report: {} products", products.len());
+    Ok(HttpResponse::Ok().json(products))
+}
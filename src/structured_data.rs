@@ -0,0 +1,151 @@
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use mongodb::{bson::doc, Collection};
+use serde::Serialize;
+use tracing::{debug, error};
+
+use crate::{
+    bundles,
+    config::{MongoConfig, StorefrontConfig},
+    errors::ApiError,
+    models::{AttributeValue, Product, ProductStatus},
+    public_ids,
+};
+
+// Attribute shown as the brand rather than as a plain property
+const BRAND_ATTRIBUTE: &str = "brand";
+
+/// schema.org Product, for the storefront to embed in its product pages.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProductJsonLd {
+    #[serde(rename = "@context")]
+    context: &'static str,
+    #[serde(rename = "@type")]
+    kind: &'static str,
+    name: String,
+    sku: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gtin13: Option<String>,
+    category: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    brand: Option<Brand>,
+    offers: Offer,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    additional_property: Vec<PropertyValue>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Brand {
+    #[serde(rename = "@type")]
+    kind: &'static str,
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Offer {
+    #[serde(rename = "@type")]
+    kind: &'static str,
+    price: String,
+    price_currency: String,
+    availability: &'static str,
+    // The sale price only holds until the sale ends
+    #[serde(skip_serializing_if = "Option::is_none")]
+    price_valid_until: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PropertyValue {
+    #[serde(rename = "@type")]
+    kind: &'static str,
+    name: String,
+    value: AttributeValue,
+}
+
+fn availability(product: &Product, available_quantity: Option<i64>) -> &'static str {
+    if product.status == ProductStatus::Archived {
+        return "https://schema.org/Discontinued";
+    }
+    match available_quantity {
+        Some(quantity) if quantity <= 0 => "https://schema.org/OutOfStock",
+        _ => "https://schema.org/InStock",
+    }
+}
+
+impl ProductJsonLd {
+    fn new(product: Product, available_quantity: Option<i64>, config: &StorefrontConfig) -> Self {
+        let id = product.id.map(|id| id.to_hex()).unwrap_or_default();
+        let slug = product.slug.clone().unwrap_or_else(|| id.clone());
+        let fill = |template: &String| template.replace("{id}", &id).replace("{slug}", &slug);
+        let url = config.product_url_template.as_ref().map(fill);
+
+        let mut attributes = product.attributes.clone().unwrap_or_default();
+        let brand = match attributes.remove(BRAND_ATTRIBUTE) {
+            Some(AttributeValue::Text(name)) => Some(name),
+            _ => config.brand.clone(),
+        };
+
+        ProductJsonLd {
+            context: "https://schema.org",
+            kind: "Product",
+            sku: product.sku.clone().or_else(|| product.public_id.clone()).unwrap_or_else(|| id.clone()),
+            gtin13: product.barcode.clone(),
+            category: product.category.to_string(),
+            image: config.image_url_template.as_ref().map(fill),
+            brand: brand.map(|name| Brand { kind: "Brand", name }),
+            offers: Offer {
+                kind: "Offer",
+                price: format!("{:.2}", product.price),
+                price_currency: config.currency.clone(),
+                availability: availability(&product, available_quantity),
+                price_valid_until: product
+                    .sale_ends_at
+                    .filter(|_| product.has_active_sale)
+                    .and_then(|at| at.try_to_rfc3339_string().ok()),
+                url: url.clone(),
+            },
+            url,
+            additional_property: attributes
+                .into_iter()
+                .map(|(name, value)| PropertyValue { kind: "PropertyValue", name, value })
+                .collect(),
+            name: product.name,
+        }
+    }
+}
+
+/// A product as schema.org structured data. Drafts are not on the
+/// storefront and are not found; archived products are discontinued.
+pub async fn product_jsonld(
+    db: web::Data<MongoConfig>,
+    config: web::Data<StorefrontConfig>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let product_id = public_ids::resolve_product_id(&db, &id).await?;
+
+    let collection: Collection<Product> = db.catalog_collection("products");
+    let product = collection.find_one(doc! { "_id": product_id }, None).await.map_err(|e| {
+        error!("Failed to fetch product {}: {}", product_id, e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+    })?;
+    let Some(product) = product.filter(|product| product.status != ProductStatus::Draft) else {
+        debug!("No published product {} for structured data", product_id);
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    };
+
+    // A bundle is in stock as long as its components are
+    let available_quantity = match &product.bundle {
+        Some(bundle) => bundles::expand(&db, bundle).await?.available_quantity,
+        None => product.stock_quantity,
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/ld+json")
+        .json(ProductJsonLd::new(product, available_quantity, &config)))
+}
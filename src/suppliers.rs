@@ -0,0 +1,244 @@
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime},
+    options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+use validator::Validate;
+
+use crate::{config::MongoConfig, errors::ApiError, models::Product, validation::validation_error};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Supplier {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub name: String,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    // Days from ordering to delivery, for planning purchase orders
+    pub lead_time_days: Option<i64>,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct SupplierRequest {
+    #[validate(length(min = 1, max = 100, message = "name must be between 1 and 100 characters"))]
+    pub name: String,
+    #[validate(email)]
+    pub email: Option<String>,
+    #[validate(length(max = 30, message = "phone must be at most 30 characters"))]
+    pub phone: Option<String>,
+    #[validate(range(min = 0, message = "lead_time_days must be non-negative"))]
+    pub lead_time_days: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SupplierResponse {
+    pub id: String,
+    pub name: String,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub lead_time_days: Option<i64>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl From<&Supplier> for SupplierResponse {
+    fn from(supplier: &Supplier) -> Self {
+        SupplierResponse {
+            id: supplier.id.map(|id| id.to_string()).unwrap_or_default(),
+            name: supplier.name.clone(),
+            email: supplier.email.clone(),
+            phone: supplier.phone.clone(),
+            lead_time_days: supplier.lead_time_days,
+            created_at: supplier.created_at.try_to_rfc3339_string().unwrap_or_default(),
+            updated_at: supplier.updated_at.try_to_rfc3339_string().unwrap_or_default(),
+        }
+    }
+}
+
+pub fn suppliers_collection(db: &MongoConfig) -> Collection<Supplier> {
+    db.database.collection("suppliers")
+}
+
+pub fn parse_supplier_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| {
+        error!("Invalid supplier ID format: {}", id);
+        ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format").into()
+    })
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+/// Parses a supplier ID given on a product and checks the supplier exists.
+pub async fn check_supplier(db: &MongoConfig, id: &str) -> Result<ObjectId, Error> {
+    let supplier_id = parse_supplier_id(id)?;
+    let count = suppliers_collection(db)
+        .count_documents(doc! { "_id": supplier_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch supplier", e))?;
+    if count == 0 {
+        debug!("Rejected unknown supplier {}", supplier_id);
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, format!("Supplier not found: {}", supplier_id)).into());
+    }
+    Ok(supplier_id)
+}
+
+pub async fn create_supplier(
+    db: web::Data<MongoConfig>,
+    request: web::Json<SupplierRequest>,
+) -> Result<HttpResponse, Error> {
+    request.validate().map_err(validation_error)?;
+
+    let now = DateTime::now();
+    let request = request.into_inner();
+    let mut supplier = Supplier {
+        id: None,
+        name: request.name,
+        email: request.email,
+        phone: request.phone,
+        lead_time_days: request.lead_time_days,
+        created_at: now,
+        updated_at: now,
+    };
+
+    let result = suppliers_collection(&db)
+        .insert_one(&supplier, None)
+        .await
+        .map_err(|e| db_error("Failed to create supplier", e))?;
+    supplier.id = result.inserted_id.as_object_id();
+
+    info!("Supplier created with ID: {}", result.inserted_id);
+    Ok(HttpResponse::Created().json(SupplierResponse::from(&supplier)))
+}
+
+pub async fn list_suppliers(db: web::Data<MongoConfig>) -> Result<HttpResponse, Error> {
+    let options = FindOptions::builder().sort(doc! { "name": 1 }).build();
+    let suppliers: Vec<Supplier> = suppliers_collection(&db)
+        .find(None, options)
+        .await
+        .map_err(|e| db_error("Failed to fetch suppliers", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating suppliers", e))?;
+
+    let suppliers: Vec<SupplierResponse> = suppliers.iter().map(SupplierResponse::from).collect();
+    Ok(HttpResponse::Ok().json(suppliers))
+}
+
+pub async fn get_supplier(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let supplier_id = parse_supplier_id(&id)?;
+
+    let supplier = suppliers_collection(&db)
+        .find_one(doc! { "_id": supplier_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch supplier", e))?;
+
+    match supplier {
+        Some(supplier) => Ok(HttpResponse::Ok().json(SupplierResponse::from(&supplier))),
+        None => Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    }
+}
+
+pub async fn update_supplier(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+    request: web::Json<SupplierRequest>,
+) -> Result<HttpResponse, Error> {
+    let supplier_id = parse_supplier_id(&id)?;
+    request.validate().map_err(validation_error)?;
+
+    let request = request.into_inner();
+    let update = doc! {
+        "name": request.name,
+        "email": request.email,
+        "phone": request.phone,
+        "lead_time_days": request.lead_time_days,
+        "updated_at": DateTime::now(),
+    };
+
+    let supplier = suppliers_collection(&db)
+        .find_one_and_update(
+            doc! { "_id": supplier_id },
+            doc! { "$set": update },
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await
+        .map_err(|e| db_error("Failed to update supplier", e))?;
+
+    match supplier {
+        Some(supplier) => Ok(HttpResponse::Ok().json(SupplierResponse::from(&supplier))),
+        None => Err(ApiError::blank(StatusCode::NOT_FOUND).into()),
+    }
+}
+
+/// Deletes a supplier that no product is linked to anymore.
+pub async fn delete_supplier(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let supplier_id = parse_supplier_id(&id)?;
+
+    let products: Collection<Product> = db.database.collection("products");
+    let linked = products
+        .count_documents(doc! { "supplier_id": supplier_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to count supplier products", e))?;
+    if linked > 0 {
+        return Err(ApiError::new(StatusCode::CONFLICT, format!("Supplier is still linked to {} products", linked))
+            .with("linked_products", linked as i64)
+            .into());
+    }
+
+    let result = suppliers_collection(&db)
+        .delete_one(doc! { "_id": supplier_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to delete supplier", e))?;
+    if result.deleted_count == 0 {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    }
+
+    info!("Supplier deleted: {}", supplier_id);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Products linked to a supplier, by name.
+pub async fn list_supplier_products(
+    db: web::Data<MongoConfig>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let supplier_id = parse_supplier_id(&id)?;
+
+    let exists = suppliers_collection(&db)
+        .count_documents(doc! { "_id": supplier_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch supplier", e))?;
+    if exists == 0 {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    }
+
+    let products: Collection<Product> = db.catalog_collection("products");
+    let options = FindOptions::builder().sort(doc! { "name": 1 }).build();
+    let products: Vec<Product> = products
+        .find(doc! { "supplier_id": supplier_id }, options)
+        .await
+        .map_err(|e| db_error("Failed to fetch supplier products", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating supplier products", e))?;
+
+    Ok(HttpResponse::Ok().json(products))
+}
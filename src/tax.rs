@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime, Document},
+    options::{FindOptions, ReplaceOptions},
+    Collection,
+};
+use rust_decimal::RoundingStrategy;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{
+    config::{MongoConfig, TaxConfig},
+    errors::ApiError,
+    models::TaxClass,
+    money::{self, Decimal},
+};
+
+const MAX_REGION_LEN: usize = 10;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TaxRate {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub region: String,
+    pub tax_class: TaxClass,
+    // Percent, e.g. 19 for 19% VAT
+    #[serde(with = "money::price")]
+    pub rate: Decimal,
+    pub updated_at: DateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TaxRateResponse {
+    pub region: String,
+    pub tax_class: TaxClass,
+    #[serde(with = "money::price")]
+    pub rate: Decimal,
+    pub updated_at: String,
+}
+
+impl From<TaxRate> for TaxRateResponse {
+    fn from(rate: TaxRate) -> Self {
+        TaxRateResponse {
+            region: rate.region,
+            tax_class: rate.tax_class,
+            rate: rate.rate,
+            updated_at: rate.updated_at.try_to_rfc3339_string().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SetTaxRateRequest {
+    #[serde(with = "money::price")]
+    pub rate: Decimal,
+}
+
+/// `?region=` on responses that include a price breakdown.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TaxQuery {
+    pub region: Option<String>,
+}
+
+/// Net, tax and gross amounts of a price in one region.
+#[derive(Debug, Clone, Serialize)]
+pub struct PriceBreakdown {
+    pub region: String,
+    pub tax_class: TaxClass,
+    #[serde(with = "money::price")]
+    pub rate: Decimal,
+    #[serde(with = "money::price")]
+    pub net: Decimal,
+    #[serde(with = "money::price")]
+    pub tax: Decimal,
+    #[serde(with = "money::price")]
+    pub gross: Decimal,
+}
+
+/// Sum of several breakdowns, e.g. of the lines of a cart.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TaxTotals {
+    #[serde(with = "money::price")]
+    pub net: Decimal,
+    #[serde(with = "money::price")]
+    pub tax: Decimal,
+    #[serde(with = "money::price")]
+    pub gross: Decimal,
+}
+
+impl TaxTotals {
+    pub fn add(&mut self, breakdown: &PriceBreakdown) {
+        self.net += breakdown.net;
+        self.tax += breakdown.tax;
+        self.gross += breakdown.gross;
+    }
+}
+
+/// The tax rates of one region, loaded once per request.
+pub struct TaxTable {
+    region: String,
+    rates: HashMap<TaxClass, Decimal>,
+    prices_include_tax: bool,
+}
+
+fn round_cents(amount: Decimal) -> Decimal {
+    amount.round_dp_with_strategy(2, RoundingStrategy::MidpointAwayFromZero)
+}
+
+impl TaxTable {
+    /// Splits `price` into net, tax and gross. Prices are gross or net
+    /// depending on PRICES_INCLUDE_TAX; classes without a rate in the region
+    /// are not taxed.
+    pub fn breakdown(&self, price: Decimal, tax_class: TaxClass) -> PriceBreakdown {
+        let rate = self.rates.get(&tax_class).copied().unwrap_or_default();
+        let hundred = Decimal::ONE_HUNDRED;
+        let (net, tax, gross) = if self.prices_include_tax {
+            let net = round_cents(price * hundred / (hundred + rate));
+            (net, price - net, price)
+        } else {
+            let tax = round_cents(price * rate / hundred);
+            (price, tax, price + tax)
+        };
+        PriceBreakdown { region: self.region.clone(), tax_class, rate, net, tax, gross }
+    }
+}
+
+fn rates_collection(db: &MongoConfig) -> Collection<TaxRate> {
+    db.database.collection("tax_rates")
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+/// Region codes are upper-cased, e.g. "DE" or "US-CA".
+pub fn normalize_region(region: &str) -> Result<String, Error> {
+    let region = region.trim().to_uppercase();
+    let valid = !region.is_empty()
+        && region.len() <= MAX_REGION_LEN
+        && region.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if valid {
+        Ok(region)
+    } else {
+        Err(ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid region: {}", region)).into())
+    }
+}
+
+/// Rates for the requested region, or TAX_DEFAULT_REGION. None when neither
+/// is set, in which case responses carry no breakdown.
+pub async fn load_table(db: &MongoConfig, config: &TaxConfig, region: Option<&str>) -> Result<Option<TaxTable>, Error> {
+    let Some(region) = region.or(config.default_region.as_deref()) else {
+        return Ok(None);
+    };
+    let region = normalize_region(region)?;
+
+    let rates: Vec<TaxRate> = rates_collection(db)
+        .find(doc! { "region": &region }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch tax rates", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating tax rates", e))?;
+
+    Ok(Some(TaxTable {
+        region,
+        rates: rates.into_iter().map(|rate| (rate.tax_class, rate.rate)).collect(),
+        prices_include_tax: config.prices_include_tax,
+    }))
+}
+
+/// Configured rates, optionally of one region, by region then class.
+pub async fn list_tax_rates(db: web::Data<MongoConfig>, query: web::Query<TaxQuery>) -> Result<HttpResponse, Error> {
+    let filter = match &query.region {
+        Some(region) => doc! { "region": normalize_region(region)? },
+        None => Document::new(),
+    };
+    let options = FindOptions::builder().sort(doc! { "region": 1, "tax_class": 1 }).build();
+
+    let rates: Vec<TaxRate> = rates_collection(&db)
+        .find(filter, options)
+        .await
+        .map_err(|e| db_error("Failed to fetch tax rates", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating tax rates", e))?;
+
+    let rates: Vec<TaxRateResponse> = rates.into_iter().map(TaxRateResponse::from).collect();
+    Ok(HttpResponse::Ok().json(rates))
+}
+
+/// Creates or replaces the rate of a tax class in a region.
+pub async fn set_tax_rate(
+    db: web::Data<MongoConfig>,
+    path: web::Path<(String, String)>,
+    request: web::Json<SetTaxRateRequest>,
+) -> Result<HttpResponse, Error> {
+    let (region, tax_class) = path.into_inner();
+    let region = normalize_region(&region)?;
+    let tax_class: TaxClass = tax_class.parse().map_err(ApiError::bad_request)?;
+
+    if request.rate.is_sign_negative() || request.rate > Decimal::ONE_HUNDRED {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "rate must be a percentage between 0 and 100").into());
+    }
+
+    let rate = TaxRate { id: None, region, tax_class, rate: request.rate, updated_at: DateTime::now() };
+    let options = ReplaceOptions::builder().upsert(true).build();
+    rates_collection(&db)
+        .replace_one(doc! { "region": &rate.region, "tax_class": tax_class.to_string() }, &rate, options)
+        .await
+        .map_err(|e| db_error("Failed to save tax rate", e))?;
+
+    info!("Tax rate for {} in {} set to {}%", tax_class, rate.region, rate.rate);
+    Ok(HttpResponse::Ok().json(TaxRateResponse::from(rate)))
+}
+
+pub async fn delete_tax_rate(db: web::Data<MongoConfig>, path: web::Path<(String, String)>) -> Result<HttpResponse, Error> {
+    let (region, tax_class) = path.into_inner();
+    let region = normalize_region(&region)?;
+    let tax_class: TaxClass = tax_class.parse().map_err(ApiError::bad_request)?;
+
+    let result = rates_collection(&db)
+        .delete_one(doc! { "region": &region, "tax_class": tax_class.to_string() }, None)
+        .await
+        .map_err(|e| db_error("Failed to delete tax rate", e))?;
+
+    if result.deleted_count == 0 {
+        Err(ApiError::blank(StatusCode::NOT_FOUND).into())
+    } else {
+        info!("Tax rate for {} in {} removed", tax_class, region);
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
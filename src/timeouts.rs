@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    http::StatusCode,
+    web, Error, HttpMessage,
+};
+use tracing::warn;
+use tracing_actix_web::RequestId;
+
+use crate::{config::RequestTimeoutConfig, errors::ApiError};
+
+// Routes under /api that stream an upload or a download, or undo a whole
+// import and must not stop halfway, and may rightly run for minutes; they
+// are never aborted. A `{name}` segment matches any one segment
+const STREAMING_ROUTES: [&str; 9] = [
+    "/products/import",
+    "/products/relationships/import",
+    "/products/imports/{id}/rollback",
+    "/products/stream",
+    "/exports",
+    "/feeds",
+    "/events",
+    "/admin/backup",
+    "/admin/restore",
+];
+
+// The path relative to /api or /api/v{n}, for API routes
+fn api_route(path: &str) -> Option<&str> {
+    let rest = path.strip_prefix("/api")?;
+    let Some(versioned) = rest.strip_prefix("/v") else { return Some(rest) };
+    let digits = versioned.find('/').unwrap_or(versioned.len());
+    if digits > 0 && versioned[..digits].chars().all(|c| c.is_ascii_digit()) {
+        Some(&versioned[digits..])
+    } else {
+        Some(rest)
+    }
+}
+
+// Whether `route` is `prefix` or one of the routes below it
+fn is_under(route: &str, prefix: &str) -> bool {
+    let mut segments = route.split('/');
+    prefix.split('/').all(|expected| {
+        segments.next().is_some_and(|segment| segment == expected || expected.starts_with('{'))
+    })
+}
+
+// The timeout of a path: the longest matching override, else the default.
+// None when it is a streaming route or the timeout is 0
+fn timeout_for(config: &RequestTimeoutConfig, path: &str) -> Option<Duration> {
+    let route = api_route(path);
+    if route.is_some_and(|route| STREAMING_ROUTES.iter().any(|prefix| is_under(route, prefix))) {
+        return None;
+    }
+    let secs = route
+        .and_then(|route| {
+            config
+                .routes
+                .iter()
+                .filter(|(prefix, _)| is_under(route, prefix))
+                .max_by_key(|(prefix, _)| prefix.len())
+                .map(|(_, secs)| *secs)
+        })
+        .unwrap_or(config.default_secs);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Aborts requests still running after REQUEST_TIMEOUT_SECS, or the
+/// timeout REQUEST_TIMEOUT_ROUTES sets for the route, and answers 504 naming
+/// the request. The handler is dropped at its next await, so a hung query
+/// doesn't hold the worker. Import and export streaming routes are exempt.
+pub async fn abort_slow_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let config = req.app_data::<web::Data<RequestTimeoutConfig>>().cloned();
+    let Some(timeout) = config.and_then(|config| timeout_for(&config, req.path())) else {
+        return next.call(req).await;
+    };
+
+    // Kept for the 504, since the request moves into the handler. The request
+    // itself can't be kept: routing needs the only reference to it
+    let method = req.method().clone();
+    let path = req.path().to_string();
+    let request_id = req.extensions().get::<RequestId>().map(|id| id.to_string());
+    match tokio::time::timeout(timeout, next.call(req)).await {
+        Ok(res) => res,
+        Err(_) => {
+            warn!(
+                "Aborted {} {} after {}s (request {})",
+                method,
+                path,
+                timeout.as_secs(),
+                request_id.as_deref().unwrap_or("-")
+            );
+            let message = format!("The request took longer than {}s and was aborted", timeout.as_secs());
+            Err(ApiError::new(StatusCode::GATEWAY_TIMEOUT, message)
+                .with("code", "request_timeout")
+                .with("request_id", request_id)
+                .into())
+        }
+    }
+}
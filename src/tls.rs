@@ -0,0 +1,83 @@
+use std::{fs::File, io::{self, BufReader}, sync::Arc};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    error::InternalError,
+    http::header::{self, HeaderMap, HeaderValue},
+    middleware::Next,
+    web, Error, HttpRequest, HttpResponse,
+};
+use rustls::{crypto::ring, ServerConfig};
+use tracing::debug;
+
+use crate::config::TlsConfig;
+
+/// Loads the PEM certificate chain and private key into a rustls server config.
+pub fn load_server_config(tls: &TlsConfig) -> io::Result<ServerConfig> {
+    let mut cert_reader = BufReader::new(File::open(&tls.cert_path)?);
+    let certs = rustls_pemfile::certs(&mut cert_reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::other(format!("No certificates found in {}", tls.cert_path)));
+    }
+
+    let mut key_reader = BufReader::new(File::open(&tls.key_path)?);
+    let key = rustls_pemfile::private_key(&mut key_reader)?
+        .ok_or_else(|| io::Error::other(format!("No private key found in {}", tls.key_path)))?;
+
+    ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(io::Error::other)
+}
+
+fn insert_hsts(headers: &mut HeaderMap, tls: &TlsConfig) {
+    let value = format!("max-age={}; includeSubDomains", tls.hsts_max_age);
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.insert(header::STRICT_TRANSPORT_SECURITY, value);
+    }
+}
+
+/// Adds Strict-Transport-Security to every response, including errors raised
+/// by inner middleware, whenever a TlsConfig is registered as app data.
+pub async fn strict_transport_security(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let tls = req.app_data::<web::Data<TlsConfig>>().cloned();
+
+    match (next.call(req).await, tls) {
+        (Ok(mut res), Some(tls)) => {
+            insert_hsts(res.headers_mut(), &tls);
+            Ok(res)
+        }
+        (Err(e), Some(tls)) => {
+            let mut response = e.error_response();
+            insert_hsts(response.headers_mut(), &tls);
+            Err(InternalError::from_response(e, response).into())
+        }
+        (result, None) => result,
+    }
+}
+
+/// Answers every plain HTTP request with a permanent redirect to HTTPS.
+pub async fn redirect_to_https(req: HttpRequest, tls: web::Data<TlsConfig>) -> HttpResponse {
+    let connection = req.connection_info();
+    let host = connection.host();
+    // Strip the plain HTTP port; the HTTPS port is added back unless it's the default
+    let hostname = host.rsplit_once(':').map_or(host, |(name, _)| name);
+    let authority = if tls.https_port == 443 {
+        hostname.to_string()
+    } else {
+        format!("{}:{}", hostname, tls.https_port)
+    };
+
+    let location = format!("https://{}{}", authority, req.uri());
+    debug!("Redirecting to {}", location);
+
+    HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
@@ -0,0 +1,80 @@
+use futures::future::BoxFuture;
+use mongodb::{
+    error::{Error as MongoError, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT},
+    ClientSession,
+};
+use tracing::{debug, warn};
+
+use crate::config::MongoConfig;
+
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Why a transaction did not commit: the callback chose to abort, or the
+/// database failed in a way retrying could not fix.
+pub enum TransactionError<E> {
+    Aborted(E),
+    Database(MongoError),
+}
+
+impl<E> From<MongoError> for TransactionError<E> {
+    fn from(e: MongoError) -> Self {
+        TransactionError::Database(e)
+    }
+}
+
+/// Runs `callback` inside a transaction and commits it. The whole transaction
+/// is retried on TransientTransactionError and the commit on
+/// UnknownTransactionCommitResult, up to MAX_ATTEMPTS times. Returning
+/// `TransactionError::Aborted` from the callback aborts without retrying.
+///
+/// `context` is handed back to the callback on every attempt, which lets the
+/// returned future borrow data from the caller.
+pub async fn run_in_transaction<C, R, E, F>(
+    db: &MongoConfig,
+    mut context: C,
+    mut callback: F,
+) -> Result<R, TransactionError<E>>
+where
+    F: for<'a> FnMut(&'a mut ClientSession, &'a mut C) -> BoxFuture<'a, Result<R, TransactionError<E>>>,
+{
+    let mut session = db.client.start_session(None).await?;
+    let mut attempt = 0;
+
+    'transaction: loop {
+        attempt += 1;
+        session.start_transaction(db.transaction_options()).await?;
+
+        let value = match callback(&mut session, &mut context).await {
+            Ok(value) => value,
+            Err(err) => {
+                if let Err(e) = session.abort_transaction().await {
+                    warn!("Failed to abort transaction: {}", e);
+                }
+                match err {
+                    TransactionError::Database(e)
+                        if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_ATTEMPTS =>
+                    {
+                        debug!("Transient transaction error, retrying (attempt {}): {}", attempt, e);
+                        continue 'transaction;
+                    }
+                    err => return Err(err),
+                }
+            }
+        };
+
+        loop {
+            match session.commit_transaction().await {
+                Ok(()) => return Ok(value),
+                Err(e) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && attempt < MAX_ATTEMPTS => {
+                    attempt += 1;
+                    debug!("Unknown commit result, retrying commit (attempt {}): {}", attempt, e);
+                }
+                Err(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < MAX_ATTEMPTS => {
+                    debug!("Transient commit error, retrying transaction (attempt {}): {}", attempt, e);
+                    continue 'transaction;
+                }
+                Err(e) => return Err(TransactionError::Database(e)),
+            }
+        }
+    }
+}
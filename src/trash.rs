@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, DateTime},
+    options::FindOptions,
+    ClientSession, Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info};
+use validator::Validate;
+
+use crate::{
+    auth::User,
+    barcode::is_duplicate_key,
+    config::{MongoConfig, TrashConfig},
+    errors::ApiError,
+    event_store::{self, ProductEvent},
+    events::{DomainEvent, EventHub},
+    models::Product,
+    slugs,
+    validation::{page_offset, validation_error},
+};
+
+const DAY_MILLIS: i64 = 24 * 60 * 60 * 1000;
+
+// A deleted product, kept out of the products collection until it is purged
+// so no product query has to know about deletion
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TrashedProduct {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub product: Product,
+    pub deleted_at: DateTime,
+    // Unset for deletions made over gRPC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_by: Option<ObjectId>,
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct TrashQuery {
+    #[validate(range(min = 1, message = "page must be at least 1"))]
+    pub page: Option<i64>,
+    #[validate(range(min = 1, max = 100, message = "per_page must be between 1 and 100"))]
+    pub per_page: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrashedProductResponse {
+    pub product: Product,
+    pub deleted_at: String,
+    pub deleted_by: Option<String>,
+    pub deleted_by_email: Option<String>,
+    pub days_until_purge: i64,
+}
+
+fn trash_collection(db: &MongoConfig) -> Collection<TrashedProduct> {
+    db.database.collection("products_trash")
+}
+
+fn products_collection(db: &MongoConfig) -> Collection<Product> {
+    db.database.collection("products")
+}
+
+fn parse_product_id(id: &str) -> Result<ObjectId, Error> {
+    ObjectId::parse_str(id).map_err(|_| {
+        error!("Invalid product ID format: {}", id);
+        ApiError::new(StatusCode::BAD_REQUEST, "Invalid ID format").into()
+    })
+}
+
+fn db_error(context: &str, e: mongodb::error::Error) -> Error {
+    error!("{}: {}", context, e);
+    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into()
+}
+
+// Whole days left, rounded up, so an item is never shown as 0 days before its last day
+fn days_until_purge(deleted_at: DateTime, retention_days: i64) -> i64 {
+    let purge_at = deleted_at.timestamp_millis() + retention_days * DAY_MILLIS;
+    let remaining = purge_at - DateTime::now().timestamp_millis();
+    if remaining <= 0 { 0 } else { (remaining + DAY_MILLIS - 1) / DAY_MILLIS }
+}
+
+/// Moves a product to the trash. Returns false if there is no such product.
+pub async fn move_to_trash(
+    db: &MongoConfig,
+    product_id: ObjectId,
+    deleted_by: Option<ObjectId>,
+) -> Result<bool, mongodb::error::Error> {
+    let Some(product) = products_collection(db).find_one(doc! { "_id": product_id }, None).await? else {
+        return Ok(false);
+    };
+
+    // Copy first so a failure in between never loses the product
+    let trashed = TrashedProduct { id: product_id, product, deleted_at: DateTime::now(), deleted_by };
+    trash_collection(db).insert_one(&trashed, None).await?;
+
+    let result = products_collection(db).delete_one(doc! { "_id": product_id }, None).await?;
+    if result.deleted_count == 0 {
+        // Deleted concurrently by someone else, who already trashed it
+        trash_collection(db).delete_one(doc! { "_id": product_id, "deleted_at": trashed.deleted_at }, None).await?;
+        return Ok(false);
+    }
+    event_store::record(db, product_id, vec![ProductEvent::ProductDeleted { deleted_by }]).await;
+    Ok(true)
+}
+
+/// Moves a product to the trash as part of the transaction in `session`.
+/// Returns false if there is no such product.
+pub async fn move_to_trash_with_session(
+    db: &MongoConfig,
+    session: &mut ClientSession,
+    product_id: ObjectId,
+    deleted_by: Option<ObjectId>,
+) -> Result<bool, mongodb::error::Error> {
+    let Some(product) = products_collection(db)
+        .find_one_with_session(doc! { "_id": product_id }, None, session)
+        .await?
+    else {
+        return Ok(false);
+    };
+
+    let trashed = TrashedProduct { id: product_id, product, deleted_at: DateTime::now(), deleted_by };
+    trash_collection(db).insert_one_with_session(&trashed, None, session).await?;
+    products_collection(db).delete_one_with_session(doc! { "_id": product_id }, None, session).await?;
+    event_store::record_with_session(db, session, product_id, vec![ProductEvent::ProductDeleted { deleted_by }]).await?;
+    Ok(true)
+}
+
+/// Permanently removes trashed products older than the retention period.
+pub async fn purge_expired(db: &MongoConfig, config: &TrashConfig) -> Result<String, String> {
+    let cutoff = DateTime::from_millis(DateTime::now().timestamp_millis() - config.retention_days * DAY_MILLIS);
+    let result = trash_collection(db)
+        .delete_many(doc! { "deleted_at": { "$lte": cutoff } }, None)
+        .await
+        .map_err(|e| format!("Database error: {}", e))?;
+
+    Ok(format!("Purged {} products from the trash", result.deleted_count))
+}
+
+/// Deleted products, most recently deleted first.
+pub async fn list_trash(
+    db: web::Data<MongoConfig>,
+    config: web::Data<TrashConfig>,
+    query: web::Query<TrashQuery>,
+) -> Result<HttpResponse, Error> {
+    query.validate().map_err(validation_error)?;
+    let page = query.page.unwrap_or(1);
+    let per_page = query.per_page.unwrap_or(20);
+
+    let options = FindOptions::builder()
+        .sort(doc! { "deleted_at": -1 })
+        .skip(page_offset(page, per_page)? as u64)
+        .limit(per_page)
+        .build();
+    let trashed: Vec<TrashedProduct> = trash_collection(&db)
+        .find(doc! {}, options)
+        .await
+        .map_err(|e| db_error("Failed to fetch trashed products", e))?
+        .try_collect()
+        .await
+        .map_err(|e| db_error("Error while iterating trashed products", e))?;
+
+    // Resolve deleters in one query; deleted accounts simply show no email
+    let mut deleter_ids: Vec<ObjectId> = trashed.iter().filter_map(|t| t.deleted_by).collect();
+    deleter_ids.sort();
+    deleter_ids.dedup();
+    let emails: HashMap<ObjectId, String> = if deleter_ids.is_empty() {
+        HashMap::new()
+    } else {
+        let users: Collection<User> = db.database.collection("users");
+        users
+            .find(doc! { "_id": { "$in": &deleter_ids } }, None)
+            .await
+            .map_err(|e| db_error("Failed to fetch deleting users", e))?
+            .try_collect::<Vec<User>>()
+            .await
+            .map_err(|e| db_error("Error while iterating deleting users", e))?
+            .into_iter()
+            .filter_map(|user| user.id.map(|id| (id, user.email)))
+            .collect()
+    };
+
+    let items: Vec<TrashedProductResponse> = trashed
+        .into_iter()
+        .map(|t| TrashedProductResponse {
+            deleted_at: t.deleted_at.try_to_rfc3339_string().unwrap_or_default(),
+            deleted_by: t.deleted_by.map(|id| id.to_string()),
+            deleted_by_email: t.deleted_by.and_then(|id| emails.get(&id).cloned()),
+            days_until_purge: days_until_purge(t.deleted_at, config.retention_days),
+            product: t.product,
+        })
+        .collect();
+
+    debug!("Returning {} trashed products", items.len());
+    Ok(HttpResponse::Ok().json(items))
+}
+
+/// Puts a trashed product back into the catalog under its original ID.
+pub async fn restore_product(
+    db: web::Data<MongoConfig>,
+    events: web::Data<EventHub>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let product_id = parse_product_id(&id)?;
+
+    let Some(mut trashed) = trash_collection(&db)
+        .find_one(doc! { "_id": product_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to fetch trashed product", e))?
+    else {
+        return Err(ApiError::blank(StatusCode::NOT_FOUND).into());
+    };
+
+    // The slug may have gone to another product while this one was in the trash
+    if let Some(slug) = trashed.product.slug.clone() {
+        let taken = products_collection(&db)
+            .count_documents(doc! { "$or": [{ "slug": &slug }, { "previous_slugs": &slug }] }, None)
+            .await
+            .map_err(|e| db_error("Failed to check slug", e))?;
+        if taken > 0 {
+            let slug = slugs::unique_slug(&db, &trashed.product.name, Some(product_id))
+                .await
+                .map_err(|e| db_error("Failed to generate slug", e))?;
+            trashed.product.slug = Some(slug);
+        }
+    }
+
+    if let Err(e) = products_collection(&db).insert_one(&trashed.product, None).await {
+        if is_duplicate_key(&e) {
+            return Err(ApiError::new(StatusCode::CONFLICT, "Another product now uses this product's barcode").into());
+        }
+        return Err(db_error("Failed to restore product", e));
+    }
+    trash_collection(&db)
+        .delete_one(doc! { "_id": product_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to remove restored product from the trash", e))?;
+
+    info!("Product restored from the trash: {}", product_id);
+    if let Ok(product) = mongodb::bson::to_document(&trashed.product) {
+        event_store::record(&db, product_id, vec![ProductEvent::restored(product)]).await;
+    }
+    events.publish(DomainEvent::ProductCreated { product_id: product_id.to_string() });
+    Ok(HttpResponse::Ok().json(trashed.product))
+}
+
+/// Permanently deletes one trashed product without waiting for the retention period.
+pub async fn purge_product(db: web::Data<MongoConfig>, id: web::Path<String>) -> Result<HttpResponse, Error> {
+    let product_id = parse_product_id(&id)?;
+
+    let result = trash_collection(&db)
+        .delete_one(doc! { "_id": product_id }, None)
+        .await
+        .map_err(|e| db_error("Failed to purge product", e))?;
+
+    if result.deleted_count == 0 {
+        Err(ApiError::blank(StatusCode::NOT_FOUND).into())
+    } else {
+        info!("Product purged from the trash: {}", product_id);
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
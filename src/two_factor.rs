@@ -0,0 +1,494 @@
+use actix_web::{http::StatusCode, web, Error, HttpRequest, HttpResponse};
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use mongodb::{
+    bson::{doc, oid::ObjectId, to_bson, DateTime, Document},
+    options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument},
+    Collection, IndexModel,
+};
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+use tracing::{debug, error, info};
+
+use crate::{
+    auth::{two_factor_secret, AuthResponse, Claims, User, UserResponse},
+    auth_events::{AuthEvent, AuthEventKind},
+    config::{AuthConfig, MongoConfig},
+    crypto,
+    errors::ApiError,
+    sessions,
+};
+
+const TOTP_PERIOD: u64 = 30;
+const TOTP_DIGITS: u32 = 6;
+const TOTP_SKEW: i64 = 1; // Accept codes from adjacent time steps
+const RECOVERY_CODE_COUNT: usize = 10;
+const CHALLENGE_TTL_MINUTES: i64 = 5;
+// Codes tried with one challenge before it is revoked and the password is needed again
+const MAX_CHALLENGE_ATTEMPTS: i64 = 5;
+
+// Name authenticator apps show next to the account, from TOTP_ISSUER
+static ISSUER: OnceLock<String> = OnceLock::new();
+
+/// Sets the TOTP issuer from the loaded configuration. Runs once at startup.
+pub fn init(config: &AuthConfig) {
+    ISSUER.get_or_init(|| config.totp_issuer.clone());
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TwoFactor {
+    pub secret_encrypted: String,
+    pub enabled: bool,
+    #[serde(default)]
+    pub recovery_codes: Vec<String>, // SHA-256 hashes of unused recovery codes
+    // Time step of the last code used to log in; codes of it and earlier steps are refused
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_step: Option<i64>,
+}
+
+// Short-lived token proving the password step of a 2FA login succeeded.
+// Scopes are granted from the user record at the second step; the token
+// only carries the subset the client asked for, if any
+#[derive(Debug, Serialize, Deserialize)]
+struct ChallengeClaims {
+    sub: String,
+    exp: i64,
+    // Attempts on the challenge are counted under this ID in `two_factor_challenges`
+    jti: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    requested_scopes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TwoFactorLoginRequest {
+    pub challenge_token: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SetupResponse {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+fn totp_at(secret: &[u8], counter: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation (RFC 4226 section 5.3)
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]])
+        & 0x7fff_ffff;
+
+    format!("{:0width$}", binary % 10u32.pow(TOTP_DIGITS), width = TOTP_DIGITS as usize)
+}
+
+// The time step `code` belongs to, if it is valid now
+fn totp_step(secret: &[u8], code: &str) -> Option<i64> {
+    let code = code.trim();
+    let counter = Utc::now().timestamp() / TOTP_PERIOD as i64;
+    (-TOTP_SKEW..=TOTP_SKEW)
+        .map(|skew| counter + skew)
+        .find(|step| totp_at(secret, *step as u64) == code)
+}
+
+fn verify_totp(secret: &[u8], code: &str) -> bool {
+    totp_step(secret, code).is_some()
+}
+
+// Records `step` as used, unless it or a later one already was, so a code
+// seen by someone else can't be replayed within its validity window
+async fn consume_totp_step(collection: &Collection<User>, user_id: &ObjectId, step: i64) -> Result<bool, Error> {
+    let result = collection
+        .update_one(
+            doc! {
+                "_id": user_id,
+                "$or": [
+                    { "two_factor.last_used_step": { "$exists": false } },
+                    { "two_factor.last_used_step": { "$lt": step } },
+                ],
+            },
+            doc! { "$set": { "two_factor.last_used_step": step } },
+            None,
+        )
+        .await
+        .map_err(|e| {
+            error!("Failed to record used 2FA code: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+        })?;
+    Ok(result.modified_count > 0)
+}
+
+fn challenges_collection(db: &MongoConfig) -> Collection<Document> {
+    db.database.collection("two_factor_challenges")
+}
+
+/// Removes challenge attempt counters once their challenge has expired. The
+/// server ensures it at startup, as `migrate` does.
+pub async fn ensure_challenge_index(db: &MongoConfig) -> Result<String, mongodb::error::Error> {
+    let index = IndexModel::builder()
+        .keys(doc! { "expires_at": 1 })
+        .options(IndexOptions::builder().expire_after(std::time::Duration::from_secs(0)).build())
+        .build();
+    let result = challenges_collection(db).create_index(index, None).await?;
+    Ok(result.index_name)
+}
+
+// Counts an attempt on the challenge; false once it was used or had too many
+async fn count_attempt(db: &MongoConfig, jti: &str) -> Result<bool, Error> {
+    let expires_at = DateTime::from_millis((Utc::now() + Duration::minutes(CHALLENGE_TTL_MINUTES)).timestamp_millis());
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .build();
+    let challenge = challenges_collection(db)
+        .find_one_and_update(
+            doc! { "_id": jti },
+            doc! { "$inc": { "attempts": 1 }, "$setOnInsert": { "used": false, "expires_at": expires_at } },
+            options,
+        )
+        .await
+        .map_err(|e| {
+            error!("Failed to count 2FA attempt: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+        })?
+        .unwrap_or_default();
+    let attempts = challenge.get_i64("attempts").or_else(|_| challenge.get_i32("attempts").map(i64::from)).unwrap_or(0);
+    Ok(!challenge.get_bool("used").unwrap_or(false) && attempts <= MAX_CHALLENGE_ATTEMPTS)
+}
+
+// Marks the challenge used; false if a concurrent attempt already did
+async fn use_challenge(db: &MongoConfig, jti: &str) -> Result<bool, Error> {
+    let result = challenges_collection(db)
+        .update_one(doc! { "_id": jti, "used": false }, doc! { "$set": { "used": true } }, None)
+        .await
+        .map_err(|e| {
+            error!("Failed to mark 2FA challenge used: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+        })?;
+    Ok(result.modified_count > 0)
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    let normalized = code.trim().to_uppercase().replace('-', "");
+    format!("{:x}", Sha256::digest(normalized.as_bytes()))
+}
+
+fn decrypt_secret(two_factor: &TwoFactor) -> Result<Vec<u8>, Error> {
+    crypto::decrypt(&two_factor.secret_encrypted).map_err(|e| {
+        error!("Failed to decrypt 2FA secret: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Two-factor configuration error").into()
+    })
+}
+
+async fn find_user(collection: &Collection<User>, user_id: &ObjectId) -> Result<User, Error> {
+    collection
+        .find_one(doc! { "_id": user_id }, None)
+        .await
+        .map_err(|e| {
+            error!("Database error: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+        })?
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "User not found").into())
+}
+
+async fn save_two_factor(
+    collection: &Collection<User>,
+    user_id: &ObjectId,
+    two_factor: Option<&TwoFactor>,
+) -> Result<(), Error> {
+    let value = to_bson(&two_factor).map_err(|e| {
+        error!("Failed to serialize 2FA settings: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to update two-factor settings")
+    })?;
+
+    collection
+        .update_one(doc! { "_id": user_id }, doc! { "$set": { "two_factor": value } }, None)
+        .await
+        .map_err(|e| {
+            error!("Failed to update 2FA settings for user {}: {}", user_id, e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to update two-factor settings")
+        })?;
+    Ok(())
+}
+
+/// Checks a TOTP code, or else a recovery code, and uses it up so it can't
+/// be presented again.
+pub async fn verify_second_factor(
+    collection: &Collection<User>,
+    user_id: &ObjectId,
+    two_factor: &TwoFactor,
+    code: Option<&str>,
+    recovery_code: Option<&str>,
+) -> Result<bool, Error> {
+    match (code, recovery_code) {
+        (Some(code), _) => match totp_step(&decrypt_secret(two_factor)?, code) {
+            Some(step) => consume_totp_step(collection, user_id, step).await,
+            None => Ok(false),
+        },
+        (None, Some(recovery_code)) => {
+            // Recovery codes are single use
+            let hashed = hash_recovery_code(recovery_code);
+            let result = collection
+                .update_one(
+                    doc! { "_id": user_id, "two_factor.recovery_codes": &hashed },
+                    doc! { "$pull": { "two_factor.recovery_codes": &hashed } },
+                    None,
+                )
+                .await
+                .map_err(|e| {
+                    error!("Failed to consume recovery code: {}", e);
+                    ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+                })?;
+            if result.modified_count > 0 {
+                info!("User {} used a recovery code", user_id);
+            }
+            Ok(result.modified_count > 0)
+        }
+        (None, None) => Ok(false),
+    }
+}
+
+/// Issues the challenge token returned by login when the user has 2FA enabled.
+pub fn issue_challenge(user_id: &ObjectId, requested_scopes: Option<&[String]>) -> Result<String, Error> {
+    let claims = ChallengeClaims {
+        sub: user_id.to_string(),
+        exp: (Utc::now() + Duration::minutes(CHALLENGE_TTL_MINUTES)).timestamp(),
+        jti: uuid::Uuid::now_v7().to_string(),
+        requested_scopes: requested_scopes.map(<[String]>::to_vec),
+    };
+
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(two_factor_secret())).map_err(|e| {
+        error!("Challenge token generation error: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Token generation failed").into()
+    })
+}
+
+pub async fn setup_two_factor(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+) -> Result<HttpResponse, Error> {
+    claims.forbid_impersonation()?;
+    let user_id = claims.user_id()?;
+    let collection: Collection<User> = db.database.collection("users");
+    let user = find_user(&collection, &user_id).await?;
+
+    if user.two_factor.as_ref().map(|tf| tf.enabled).unwrap_or(false) {
+        return Err(ApiError::new(StatusCode::CONFLICT, "Two-factor authentication is already enabled").into());
+    }
+
+    let mut secret = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let encoded_secret = base32::encode(base32::Alphabet::Rfc4648 { padding: false }, &secret);
+
+    let secret_encrypted = crypto::encrypt(&secret).map_err(|e| {
+        error!("Failed to encrypt 2FA secret: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to set up two-factor authentication")
+    })?;
+
+    // Stored as pending until the user proves their authenticator works
+    save_two_factor(&collection, &user_id, Some(&TwoFactor {
+        secret_encrypted,
+        enabled: false,
+        recovery_codes: Vec::new(),
+        last_used_step: None,
+    })).await?;
+
+    let issuer = ISSUER.get().expect("two_factor::init runs at startup");
+    let mut uri = reqwest::Url::parse(&format!("otpauth://totp/{}:{}", issuer, user.email)).map_err(|e| {
+        error!("Failed to build otpauth URI: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Failed to set up two-factor authentication")
+    })?;
+    uri.query_pairs_mut()
+        .append_pair("secret", &encoded_secret)
+        .append_pair("issuer", issuer)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &TOTP_DIGITS.to_string())
+        .append_pair("period", &TOTP_PERIOD.to_string());
+
+    info!("Started 2FA setup for user {}", user_id);
+    Ok(HttpResponse::Ok().json(SetupResponse {
+        secret: encoded_secret,
+        otpauth_uri: uri.to_string(),
+    }))
+}
+
+pub async fn verify_two_factor_setup(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    body: web::Json<CodeRequest>,
+) -> Result<HttpResponse, Error> {
+    claims.forbid_impersonation()?;
+    let user_id = claims.user_id()?;
+    let collection: Collection<User> = db.database.collection("users");
+    let user = find_user(&collection, &user_id).await?;
+
+    let mut two_factor = match user.two_factor {
+        Some(tf) if !tf.enabled => tf,
+        Some(_) => {
+            return Err(ApiError::new(StatusCode::CONFLICT, "Two-factor authentication is already enabled").into())
+        }
+        None => return Err(ApiError::new(StatusCode::BAD_REQUEST, "Two-factor setup has not been started").into()),
+    };
+
+    if !verify_totp(&decrypt_secret(&two_factor)?, &body.code) {
+        debug!("Invalid 2FA setup code for user {}", user_id);
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Invalid verification code").into());
+    }
+
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(10)
+                .map(|c| char::from(c).to_ascii_uppercase())
+                .collect();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect();
+
+    two_factor.enabled = true;
+    two_factor.recovery_codes = recovery_codes.iter().map(|c| hash_recovery_code(c)).collect();
+    save_two_factor(&collection, &user_id, Some(&two_factor)).await?;
+
+    info!("Enabled 2FA for user {}", user_id);
+    Ok(HttpResponse::Ok().json(doc! {
+        "message": "Two-factor authentication enabled",
+        "recovery_codes": recovery_codes,
+    }))
+}
+
+pub async fn disable_two_factor(
+    db: web::Data<MongoConfig>,
+    claims: web::ReqData<Claims>,
+    body: web::Json<CodeRequest>,
+) -> Result<HttpResponse, Error> {
+    claims.forbid_impersonation()?;
+    let user_id = claims.user_id()?;
+    let collection: Collection<User> = db.database.collection("users");
+    let user = find_user(&collection, &user_id).await?;
+
+    let two_factor = match user.two_factor {
+        Some(tf) if tf.enabled => tf,
+        _ => return Err(ApiError::new(StatusCode::BAD_REQUEST, "Two-factor authentication is not enabled").into()),
+    };
+
+    // Used up like a login code, so one seen by someone else can't turn 2FA off
+    let verified = match totp_step(&decrypt_secret(&two_factor)?, &body.code) {
+        Some(step) => consume_totp_step(&collection, &user_id, step).await?,
+        None => false,
+    };
+    if !verified {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Invalid verification code").into());
+    }
+
+    save_two_factor(&collection, &user_id, None).await?;
+
+    info!("Disabled 2FA for user {}", user_id);
+    Ok(HttpResponse::Ok().json(doc! {
+        "message": "Two-factor authentication disabled"
+    }))
+}
+
+pub async fn login_two_factor(
+    req: HttpRequest,
+    db: web::Data<MongoConfig>,
+    body: web::Json<TwoFactorLoginRequest>,
+) -> Result<HttpResponse, Error> {
+    let challenge = match decode::<ChallengeClaims>(
+        &body.challenge_token,
+        &DecodingKey::from_secret(two_factor_secret()),
+        &Validation::default(),
+    ) {
+        Ok(data) => data.claims,
+        Err(e) => {
+            debug!("Invalid 2FA challenge token: {}", e);
+            return Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid or expired challenge").into());
+        }
+    };
+
+    let user_id = ObjectId::parse_str(&challenge.sub).map_err(|e| {
+        error!("Failed to parse ObjectId: {}", e);
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Invalid user ID format")
+    })?;
+
+    let collection: Collection<User> = db.database.collection("users");
+    let user = find_user(&collection, &user_id).await?;
+
+    let two_factor = match &user.two_factor {
+        Some(tf) if tf.enabled => tf,
+        _ => return Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid or expired challenge").into()),
+    };
+
+    // Each challenge allows a few guesses, then the password step has to be repeated
+    if !count_attempt(&db, &challenge.jti).await? {
+        AuthEvent::new(AuthEventKind::LoginFailed)
+            .user(user_id)
+            .email(&user.email)
+            .method("two_factor")
+            .reason("challenge_revoked")
+            .record(&db, &req)
+            .await;
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "Too many attempts or challenge already used; log in again",
+        ).into());
+    }
+
+    let verified =
+        verify_second_factor(&collection, &user_id, two_factor, body.code.as_deref(), body.recovery_code.as_deref())
+            .await?;
+
+    if !verified {
+        AuthEvent::new(AuthEventKind::LoginFailed)
+            .user(user_id)
+            .email(&user.email)
+            .method("two_factor")
+            .reason("invalid_code")
+            .record(&db, &req)
+            .await;
+        return Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid verification code").into());
+    }
+
+    if !use_challenge(&db, &challenge.jti).await? {
+        return Err(ApiError::new(StatusCode::UNAUTHORIZED, "Invalid or expired challenge").into());
+    }
+
+    // The user's current scopes, narrowed to those asked for at the password step
+    let scopes: Vec<String> = match &challenge.requested_scopes {
+        Some(requested) => user.scopes.iter().filter(|scope| requested.contains(scope)).cloned().collect(),
+        None => user.scopes.clone(),
+    };
+    let (token, refresh_token) = sessions::start_session(&db, &req, &user_id, &scopes).await?;
+    AuthEvent::new(AuthEventKind::LoginSucceeded)
+        .user(user_id)
+        .email(&user.email)
+        .method("two_factor")
+        .record(&db, &req)
+        .await;
+
+    Ok(HttpResponse::Ok().json(AuthResponse {
+        token,
+        refresh_token,
+        user: UserResponse {
+            id: user_id.to_string(),
+            email: user.email,
+            first_name: user.first_name,
+            last_name: user.last_name,
+            scopes,
+        },
+    }))
+}
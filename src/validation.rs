@@ -0,0 +1,80 @@
+use std::{future::Future, ops::Deref, pin::Pin};
+
+use actix_web::{
+    dev::Payload, error::QueryPayloadError, http::StatusCode, web, Error, FromRequest, HttpRequest,
+};
+use serde::de::DeserializeOwned;
+use tracing::debug;
+use validator::{Validate, ValidationErrors, ValidationErrorsKind};
+
+use crate::errors::ApiError;
+
+// Field messages of `errors`, including those of nested structs and lists
+fn collect_messages(errors: &ValidationErrors, messages: &mut Vec<String>) {
+    for (field, kind) in errors.errors() {
+        match kind {
+            ValidationErrorsKind::Field(errors) => {
+                messages.extend(errors.iter().map(|e| match &e.message {
+                    Some(message) => message.to_string(),
+                    None => format!("{} is invalid ({})", field, e.code),
+                }));
+            }
+            ValidationErrorsKind::Struct(errors) => collect_messages(errors, messages),
+            ValidationErrorsKind::List(items) => {
+                for errors in items.values() {
+                    collect_messages(errors, messages);
+                }
+            }
+        }
+    }
+}
+
+/// Joins every field message into one readable sentence, e.g.
+/// "per_page must be between 1 and 100".
+fn describe(errors: &ValidationErrors) -> String {
+    let mut messages = Vec::new();
+    collect_messages(errors, &mut messages);
+    messages.sort();
+    messages.join("; ")
+}
+
+pub fn validation_error(errors: ValidationErrors) -> Error {
+    ApiError::new(StatusCode::BAD_REQUEST, describe(&errors)).with("errors", &errors).into()
+}
+
+// Turns query string deserialization failures into a 400 with a JSON message
+pub fn query_error_handler(err: QueryPayloadError, req: &HttpRequest) -> Error {
+    debug!("Rejected query string on {}: {}", req.path(), err);
+    ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid query parameters: {}", err)).into()
+}
+
+/// How many items come before `page` (from 1) of a listing, or a 400 when
+/// the page is so far out that the offset overflows.
+pub fn page_offset(page: i64, per_page: i64) -> Result<i64, Error> {
+    (page - 1).checked_mul(per_page).ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "page is too large").into())
+}
+
+/// Query extractor that also runs the `Validate` rules of `T`.
+pub struct ValidatedQuery<T>(pub T);
+
+impl<T> Deref for ValidatedQuery<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + Validate + 'static> FromRequest for ValidatedQuery<T> {
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let query = web::Query::<T>::from_request(req, payload);
+        Box::pin(async move {
+            let query = query.await?.into_inner();
+            query.validate().map_err(validation_error)?;
+            Ok(ValidatedQuery(query))
+        })
+    }
+}
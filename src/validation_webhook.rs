@@ -0,0 +1,145 @@
+use std::{fmt, io, time::Duration};
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::ValidationWebhookConfig,
+    errors::ApiError,
+    models::{CreateProductRequest, UpdateProductRequest},
+};
+
+// What the service is asked to judge: a new product, or the changes to one
+#[derive(Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum Submission<'a> {
+    Create { product: &'a CreateProductRequest },
+    Update { product_id: &'a str, changes: &'a UpdateProductRequest },
+}
+
+#[derive(Deserialize)]
+struct Verdict {
+    allowed: bool,
+    #[serde(default)]
+    message: Option<String>,
+}
+
+/// Why a change was not allowed.
+#[derive(Debug)]
+pub enum Rejection {
+    /// The service said no, with its message
+    Denied(String),
+    /// The service could not be asked and PRODUCT_VALIDATION_WEBHOOK_FAIL_OPEN is off
+    Unavailable,
+}
+
+impl Rejection {
+    pub fn message(&self) -> &str {
+        match self {
+            Rejection::Denied(message) => message,
+            Rejection::Unavailable => "Product validation is unavailable; try again later",
+        }
+    }
+
+    /// 422 when the service said no, 503 when it could not be asked.
+    pub fn to_problem(&self) -> ApiError {
+        match self {
+            Rejection::Denied(message) => {
+                ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, message.clone()).with("code", "product_rejected")
+            }
+            Rejection::Unavailable => {
+                ApiError::new(StatusCode::SERVICE_UNAVAILABLE, self.message()).with("code", "validation_unavailable")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl ResponseError for Rejection {
+    fn status_code(&self) -> StatusCode {
+        self.to_problem().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.to_problem().error_response()
+    }
+}
+
+/// An external service consulted before products are created or updated,
+/// which may veto the change, e.g. a compliance check for restricted items.
+pub struct ValidationWebhook {
+    http: reqwest::Client,
+    url: String,
+    secret: Option<String>,
+    fail_open: bool,
+}
+
+impl ValidationWebhook {
+    /// None when PRODUCT_VALIDATION_WEBHOOK_URL is unset.
+    pub fn from_config(config: ValidationWebhookConfig) -> io::Result<Option<Self>> {
+        let Some(url) = config.url else { return Ok(None) };
+        reqwest::Url::parse(&url).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid PRODUCT_VALIDATION_WEBHOOK_URL: {}", e))
+        })?;
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_millis(config.timeout_ms.max(1)))
+            .build()
+            .expect("Failed to build HTTP client");
+        info!(
+            "Validating product changes with {}, {} when it fails",
+            url,
+            if config.fail_open { "allowing them" } else { "refusing them" }
+        );
+        Ok(Some(ValidationWebhook { http, url, secret: config.secret, fail_open: config.fail_open }))
+    }
+
+    async fn ask(&self, submission: &Submission<'_>) -> Result<Verdict, String> {
+        let mut request = self.http.post(&self.url).json(submission);
+        if let Some(secret) = &self.secret {
+            request = request.bearer_auth(secret);
+        }
+        request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn check(&self, submission: Submission<'_>) -> Result<(), Rejection> {
+        match self.ask(&submission).await {
+            Ok(Verdict { allowed: true, .. }) => Ok(()),
+            Ok(Verdict { allowed: false, message }) => {
+                let message = message.unwrap_or_else(|| "The product was rejected by validation".to_string());
+                debug!("Validation webhook rejected a product: {}", message);
+                Err(Rejection::Denied(message))
+            }
+            Err(e) if self.fail_open => {
+                warn!("Validation webhook failed, allowing the change: {}", e);
+                Ok(())
+            }
+            Err(e) => {
+                warn!("Validation webhook failed, refusing the change: {}", e);
+                Err(Rejection::Unavailable)
+            }
+        }
+    }
+
+    /// Asks the service whether `product` may be created.
+    pub async fn check_create(&self, product: &CreateProductRequest) -> Result<(), Rejection> {
+        self.check(Submission::Create { product }).await
+    }
+
+    /// Asks the service whether product `product_id` may change as `changes` says.
+    pub async fn check_update(&self, product_id: &str, changes: &UpdateProductRequest) -> Result<(), Rejection> {
+        self.check(Submission::Update { product_id, changes }).await
+    }
+}
@@ -0,0 +1,136 @@
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    error::InternalError,
+    http::{
+        header::{HeaderMap, HeaderName, HeaderValue},
+        StatusCode,
+    },
+    middleware::Next,
+    web, Error, HttpRequest, ResponseError,
+};
+use tracing::debug;
+
+use crate::{config::VersioningConfig, errors::ApiError};
+
+// Requested with Accept-Version on unversioned /api paths, answered with API-Version
+const ACCEPT_VERSION: &str = "accept-version";
+const API_VERSION: HeaderName = HeaderName::from_static("api-version");
+const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+const SUNSET: HeaderName = HeaderName::from_static("sunset");
+const LINK: HeaderName = HeaderName::from_static("link");
+
+/// Response shape generation of the HTTP API. Each version is mounted under
+/// `/api/v{n}`; responses only change shape in a new version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ApiVersion {
+    V1,
+    // Listings use the data/meta/links envelope
+    V2,
+}
+
+impl ApiVersion {
+    pub const ALL: [ApiVersion; 2] = [ApiVersion::V1, ApiVersion::V2];
+
+    pub fn number(self) -> u8 {
+        match self {
+            ApiVersion::V1 => 1,
+            ApiVersion::V2 => 2,
+        }
+    }
+
+    pub fn prefix(self) -> String {
+        format!("/api/v{}", self.number())
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let value = value.strip_prefix('v').unwrap_or(value);
+        ApiVersion::ALL.into_iter().find(|version| value == version.number().to_string())
+    }
+
+    /// The version a request is served with: the one of its `/api/v{n}` scope,
+    /// else the one asked for with Accept-Version, else v1.
+    pub fn of(req: &HttpRequest) -> Self {
+        if let Some(version) = req.app_data::<ApiVersion>() {
+            return *version;
+        }
+        requested_version(req.headers()).and_then(Result::ok).unwrap_or(ApiVersion::V1)
+    }
+}
+
+fn requested_version(headers: &HeaderMap) -> Option<Result<ApiVersion, String>> {
+    let value = headers.get(ACCEPT_VERSION)?;
+    let value = value.to_str().unwrap_or_default();
+    Some(ApiVersion::parse(value).ok_or_else(|| value.to_string()))
+}
+
+fn path_version(path: &str) -> Option<ApiVersion> {
+    ApiVersion::ALL.into_iter().find(|v| path.starts_with(&format!("{}/", v.prefix())))
+}
+
+// Unversioned /api paths predate versioning and serve v1 by default
+fn is_unversioned(path: &str) -> bool {
+    path.starts_with("/api/") && path_version(path).is_none()
+}
+
+fn insert_version_headers(headers: &mut HeaderMap, path: &str, version: ApiVersion, config: Option<&VersioningConfig>) {
+    headers.insert(API_VERSION, HeaderValue::from(u16::from(version.number())));
+
+    if is_unversioned(path) {
+        headers.insert(DEPRECATION, HeaderValue::from_static("true"));
+        let successor = format!("<{}{}>; rel=\"successor-version\"", version.prefix(), &path["/api".len()..]);
+        if let Ok(value) = HeaderValue::from_str(&successor) {
+            headers.insert(LINK, value);
+        }
+    }
+
+    if let Some(sunset) = config.and_then(|config| config.deprecations.get(&version)) {
+        headers.insert(DEPRECATION, HeaderValue::from_static("true"));
+        if let Some(value) = sunset.as_deref().and_then(|s| HeaderValue::from_str(s).ok()) {
+            headers.insert(SUNSET, value);
+        }
+    }
+}
+
+/// Negotiates the version of `/api` requests and labels responses with it.
+/// Unversioned paths are deprecated in favour of `/api/v{n}` and answer with
+/// a successor Link; versions deprecated in VersioningConfig also get
+/// Deprecation and Sunset headers. Unknown Accept-Version values get a 406.
+pub async fn negotiate_version(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let path = req.path().to_string();
+    if !path.starts_with("/api/") {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    }
+
+    if is_unversioned(&path) {
+        if let Some(Err(requested)) = requested_version(req.headers()) {
+            debug!("Rejected unsupported Accept-Version {} on {}", requested, path);
+            let supported: Vec<String> = ApiVersion::ALL.iter().map(|v| v.number().to_string()).collect();
+            let response = ApiError::new(StatusCode::NOT_ACCEPTABLE, format!("Unsupported API version '{}'", requested))
+                .with("supported_versions", supported)
+                .error_response();
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    }
+
+    // Scope app data is only attached once routing is done, so go by the path here
+    let version = path_version(&path).unwrap_or_else(|| ApiVersion::of(req.request()));
+    let config = req.app_data::<web::Data<VersioningConfig>>().cloned();
+    let config = config.as_ref().map(|config| config.get_ref());
+
+    match next.call(req).await {
+        Ok(mut res) => {
+            insert_version_headers(res.headers_mut(), &path, version, config);
+            Ok(res.map_into_left_body())
+        }
+        Err(e) => {
+            let mut response = e.error_response();
+            insert_version_headers(response.headers_mut(), &path, version, config);
+            Err(InternalError::from_response(e, response).into())
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::{collections::HashMap, mem, sync::Mutex};
+
+use actix_web::{http::StatusCode, web, Error, HttpResponse};
+use chrono::{Duration as ChronoDuration, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    options::UpdateOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, warn};
+use validator::Validate;
+
+use crate::{config::MongoConfig, errors::ApiError, models::Product, public_ids, validation::ValidatedQuery};
+
+const DEFAULT_TRENDING_LIMIT: i64 = 10;
+const DEFAULT_TRENDING_DAYS: i64 = 7;
+
+// Views are counted in memory and written to MongoDB in batches by the
+// flush_product_views job, so a page view never waits on a database write
+#[derive(Default)]
+pub struct ViewCounter {
+    pending: Mutex<HashMap<ObjectId, i64>>,
+}
+
+impl ViewCounter {
+    pub fn record(&self, product_id: ObjectId) {
+        *self.pending.lock().unwrap().entry(product_id).or_insert(0) += 1;
+    }
+
+    fn take(&self) -> HashMap<ObjectId, i64> {
+        mem::take(&mut *self.pending.lock().unwrap())
+    }
+
+    // Puts counts back after a failed flush so they go out with the next one
+    fn restore(&self, counts: HashMap<ObjectId, i64>) {
+        let mut pending = self.pending.lock().unwrap();
+        for (product_id, count) in counts {
+            *pending.entry(product_id).or_insert(0) += count;
+        }
+    }
+
+    /// Adds pending views to each product's `view_count` and to its daily
+    /// bucket in `product_views`, which backs the trending list.
+    pub async fn flush(&self, db: &MongoConfig) -> Result<String, String> {
+        let counts = self.take();
+        if counts.is_empty() {
+            return Ok("No views to flush".to_string());
+        }
+
+        let products: Collection<Product> = db.database.collection("products");
+        let daily: Collection<Document> = db.database.collection("product_views");
+        let day = Utc::now().format("%Y-%m-%d").to_string();
+        let upsert = UpdateOptions::builder().upsert(true).build();
+
+        let mut flushed = 0;
+        let mut remaining = counts.clone();
+        for (product_id, count) in counts {
+            let result = products
+                .update_one(doc! { "_id": product_id }, doc! { "$inc": { "view_count": count } }, None)
+                .await;
+            let matched = match result {
+                Ok(result) => result.matched_count > 0,
+                Err(e) => {
+                    self.restore(remaining);
+                    return Err(format!("Database error: {}", e));
+                }
+            };
+
+            // Views of products that no longer exist are dropped
+            if matched {
+                if let Err(e) = daily
+                    .update_one(
+                        doc! { "product_id": product_id, "day": &day },
+                        doc! { "$inc": { "count": count } },
+                        upsert.clone(),
+                    )
+                    .await
+                {
+                    // view_count already includes these; only the daily bucket misses them
+                    warn!("Failed to record daily views for {}: {}", product_id, e);
+                }
+                flushed += count;
+            }
+            remaining.remove(&product_id);
+        }
+
+        Ok(format!("Flushed {} views", flushed))
+    }
+}
+
+pub async fn record_view(
+    db: web::Data<MongoConfig>,
+    views: web::Data<ViewCounter>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let product_id = public_ids::resolve_product_id(&db, &id).await?;
+
+    views.record(product_id);
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct TrendingQuery {
+    #[validate(range(min = 1, max = 50, message = "limit must be between 1 and 50"))]
+    limit: Option<i64>,
+    #[validate(range(min = 1, max = 30, message = "days must be between 1 and 30"))]
+    days: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TrendingProduct {
+    #[serde(flatten)]
+    product: Product,
+    recent_views: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrendingResponse {
+    products: Vec<TrendingProduct>,
+}
+
+/// Active products with the most views over the last `days` days.
+pub async fn trending_products(
+    db: web::Data<MongoConfig>,
+    query: ValidatedQuery<TrendingQuery>,
+) -> Result<HttpResponse, Error> {
+    let daily: Collection<Document> = db.catalog_collection("product_views");
+
+    let limit = query.limit.unwrap_or(DEFAULT_TRENDING_LIMIT);
+    let days = query.days.unwrap_or(DEFAULT_TRENDING_DAYS);
+    let since = (Utc::now() - ChronoDuration::days(days - 1)).format("%Y-%m-%d").to_string();
+
+    let pipeline = vec![
+        doc! { "$match": { "day": { "$gte": since } } },
+        doc! { "$group": { "_id": "$product_id", "recent_views": { "$sum": "$count" } } },
+        doc! { "$sort": { "recent_views": -1 } },
+        // Leave room for products filtered out below
+        doc! { "$limit": limit * 2 },
+        doc! { "$lookup": { "from": "products", "localField": "_id", "foreignField": "_id", "as": "product" } },
+        doc! { "$unwind": "$product" },
+        doc! { "$match": { "product.status": { "$in": ["active", null] } } },
+        doc! { "$replaceRoot": { "newRoot": { "$mergeObjects": ["$product", { "recent_views": "$recent_views" }] } } },
+        doc! { "$sort": { "recent_views": -1 } },
+        doc! { "$limit": limit },
+    ];
+
+    let documents: Vec<Document> = daily
+        .aggregate(pipeline, None)
+        .await
+        .map_err(|e| {
+            error!("Failed to fetch trending products: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?
+        .try_collect()
+        .await
+        .map_err(|e| {
+            error!("Error while iterating trending products: {}", e);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e))
+        })?;
+
+    let products: Vec<TrendingProduct> = documents
+        .into_iter()
+        .filter_map(|d| match mongodb::bson::from_document(d) {
+            Ok(product) => Some(product),
+            Err(e) => {
+                warn!("Skipping undecodable trending product: {}", e);
+                None
+            }
+        })
+        .collect();
+
+    debug!("Returning {} trending products over {} days", products.len(), days);
+    Ok(HttpResponse::Ok().json(TrendingResponse { products }))
+}